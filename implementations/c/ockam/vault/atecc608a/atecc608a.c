@@ -1572,3 +1572,217 @@ exit:
   return error;
 }
 
+/**
+ ********************************************************************************************************
+ *                                   ockam_vault_atecc608a_ecdh_output()
+ ********************************************************************************************************
+ */
+
+ockam_error_t ockam_vault_atecc608a_ecdh_output(ockam_vault_t*        vault,
+                                                ockam_vault_secret_t* privatekey,
+                                                const uint8_t*        peer_publickey,
+                                                size_t                peer_publickey_length,
+                                                uint8_t*              shared_secret,
+                                                size_t                shared_secret_size)
+{
+  ockam_error_t                     error          = ockam_vault_atecc608a_error_none;
+  ockam_error_t                     exit_error     = ockam_vault_atecc608a_error_none;
+  ATCA_STATUS                       status         = ATCA_SUCCESS;
+  vault_atecc608a_context_t*        context        = 0;
+  vault_atecc608a_secret_context_t* privatekey_ctx = 0;
+
+  if ((vault == 0) || (vault->context == 0)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_CONTEXT;
+    goto exit;
+  }
+
+  context = (vault_atecc608a_context_t*) vault->context;
+
+  if((privatekey == 0) ||
+     (privatekey->context == 0) ||
+     (privatekey->attributes.type != OCKAM_VAULT_SECRET_TYPE_P256_PRIVATEKEY)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_SECRET_TYPE;
+    goto exit;
+  }
+
+  privatekey_ctx = (vault_atecc608a_secret_context_t*) privatekey->context;
+
+  if((peer_publickey == 0) || (shared_secret == 0)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_PARAM;
+    goto exit;
+  }
+
+  if((peer_publickey_length != OCKAM_VAULT_P256_PUBLICKEY_LENGTH) ||
+     (shared_secret_size < VAULT_ATECC608A_SS_SIZE)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_SIZE;
+    goto exit;
+  }
+
+  if(*peer_publickey != VAULT_ATECC608A_PUBLIC_KEY_PREFIX) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_ECDH_FAIL;
+    goto exit;
+  }
+
+  if(context->mutex) {
+    error = ockam_mutex_lock(context->mutex, context->lock);
+    if(ockam_error_has_error(&error)) {
+      goto exit;
+    }
+  }
+
+  /* Only x,y coordinates of public key go here, 64 bytes. The shared secret is encrypted on the bus */
+  status = atcab_ecdh_ioenc(privatekey_ctx->slot, peer_publickey + 1, shared_secret, context->io_protection.key);
+  if (status != ATCA_SUCCESS) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_ECDH_FAIL;
+    ockam_log_error("ECDH error: %d", status);
+  }
+
+exit:
+
+  if((context != 0) && (context->mutex)) {
+    exit_error = ockam_mutex_unlock(context->mutex, context->lock);
+    if(ockam_error_is_none(&error)) {
+      error = exit_error;
+    }
+  }
+
+  return error;
+}
+
+/**
+ ********************************************************************************************************
+ *                                       ockam_vault_atecc608a_sign()
+ ********************************************************************************************************
+ */
+
+ockam_error_t ockam_vault_atecc608a_sign(ockam_vault_t*        vault,
+                                         ockam_vault_secret_t* privatekey,
+                                         const uint8_t*        digest,
+                                         size_t                digest_length,
+                                         uint8_t*              signature,
+                                         size_t                signature_size)
+{
+  ockam_error_t                     error          = ockam_vault_atecc608a_error_none;
+  ockam_error_t                     exit_error     = ockam_vault_atecc608a_error_none;
+  ATCA_STATUS                       status         = ATCA_SUCCESS;
+  vault_atecc608a_context_t*        context        = 0;
+  vault_atecc608a_secret_context_t* privatekey_ctx = 0;
+
+  if ((vault == 0) || (vault->context == 0)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_CONTEXT;
+    goto exit;
+  }
+
+  context = (vault_atecc608a_context_t*) vault->context;
+
+  if((privatekey == 0) ||
+     (privatekey->context == 0) ||
+     (privatekey->attributes.type != OCKAM_VAULT_SECRET_TYPE_P256_PRIVATEKEY)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_SECRET_TYPE;
+    goto exit;
+  }
+
+  privatekey_ctx = (vault_atecc608a_secret_context_t*) privatekey->context;
+
+  if((digest == 0) || (signature == 0)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_PARAM;
+    goto exit;
+  }
+
+  if((digest_length != OCKAM_VAULT_SHA256_DIGEST_LENGTH) || (signature_size < VAULT_ATECC608A_PUB_KEY_SIZE)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_SIZE;
+    goto exit;
+  }
+
+  if(context->mutex) {
+    error = ockam_mutex_lock(context->mutex, context->lock);
+    if(ockam_error_has_error(&error)) {
+      goto exit;
+    }
+  }
+
+  status = atcab_sign(privatekey_ctx->slot, digest, signature);
+  if (status != ATCA_SUCCESS) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_SIGN_FAIL;
+    ockam_log_error("Sign error: %d", status);
+  }
+
+exit:
+
+  if((context != 0) && (context->mutex)) {
+    exit_error = ockam_mutex_unlock(context->mutex, context->lock);
+    if(ockam_error_is_none(&error)) {
+      error = exit_error;
+    }
+  }
+
+  return error;
+}
+
+/**
+ ********************************************************************************************************
+ *                                      ockam_vault_atecc608a_verify()
+ ********************************************************************************************************
+ */
+
+ockam_error_t ockam_vault_atecc608a_verify(ockam_vault_t* vault,
+                                           const uint8_t* publickey,
+                                           size_t         publickey_length,
+                                           const uint8_t* digest,
+                                           size_t         digest_length,
+                                           const uint8_t* signature,
+                                           size_t         signature_length)
+{
+  ockam_error_t              error       = ockam_vault_atecc608a_error_none;
+  ockam_error_t              exit_error  = ockam_vault_atecc608a_error_none;
+  ATCA_STATUS                status      = ATCA_SUCCESS;
+  vault_atecc608a_context_t* context     = 0;
+  bool                       is_verified = false;
+
+  if ((vault == 0) || (vault->context == 0)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_CONTEXT;
+    goto exit;
+  }
+
+  context = (vault_atecc608a_context_t*) vault->context;
+
+  if((publickey == 0) || (digest == 0) || (signature == 0)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_PARAM;
+    goto exit;
+  }
+
+  if((publickey_length != OCKAM_VAULT_P256_PUBLICKEY_LENGTH) ||
+     (digest_length != OCKAM_VAULT_SHA256_DIGEST_LENGTH) ||
+     (signature_length != VAULT_ATECC608A_PUB_KEY_SIZE)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_INVALID_SIZE;
+    goto exit;
+  }
+
+  if(*publickey != VAULT_ATECC608A_PUBLIC_KEY_PREFIX) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_VERIFY_FAIL;
+    goto exit;
+  }
+
+  if(context->mutex) {
+    error = ockam_mutex_lock(context->mutex, context->lock);
+    if(ockam_error_has_error(&error)) {
+      goto exit;
+    }
+  }
+
+  status = atcab_verify_extern(digest, signature, publickey + 1, &is_verified);
+  if ((status != ATCA_SUCCESS) || (!is_verified)) {
+    error.code = OCKAM_VAULT_ATECC608A_ERROR_VERIFY_FAIL;
+  }
+
+exit:
+
+  if((context != 0) && (context->mutex)) {
+    exit_error = ockam_mutex_unlock(context->mutex, context->lock);
+    if(ockam_error_is_none(&error)) {
+      error = exit_error;
+    }
+  }
+
+  return error;
+}
@@ -36,6 +36,8 @@ typedef enum {
   OCKAM_VAULT_ATECC608A_ERROR_INVALID_SECRET_TYPE       = 13,
   OCKAM_VAULT_ATECC608A_ERROR_HKDF_SHA256_FAIL          = 14,
   OCKAM_VAULT_ATECC608A_ERROR_AEAD_AES_GCM_FAIL         = 15,
+  OCKAM_VAULT_ATECC608A_ERROR_SIGN_FAIL                 = 16,
+  OCKAM_VAULT_ATECC608A_ERROR_VERIFY_FAIL               = 17,
 } ockam_error_code_vault_atecc608a_t;
 
 #define OCKAM_VAULT_ATECC608A_IO_PROTECTION_KEY_SIZE  32u
@@ -56,4 +58,59 @@ typedef struct
 
 ockam_error_t ockam_vault_atecc608a_init(ockam_vault_t* vault, ockam_vault_atecc608a_attributes_t* attributes);
 
+/**
+ * @brief   Perform ECDH with a private key in a slot, returning the shared secret to the host. The shared
+ *          secret is encrypted with the IO protection key on its way over the bus.
+ * @param   vault[in]                 Vault object to use for ECDH.
+ * @param   privatekey[in]            The P-256 private key in a slot.
+ * @param   peer_publickey[in]        Uncompressed P-256 public key of the peer.
+ * @param   peer_publickey_length[in] Length of the public key. Must be 65 bytes.
+ * @param   shared_secret[out]        Buffer to place the shared secret in.
+ * @param   shared_secret_size[in]    Size of the shared secret buffer. Must be at least 32 bytes.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+ockam_error_t ockam_vault_atecc608a_ecdh_output(ockam_vault_t*        vault,
+                                                ockam_vault_secret_t* privatekey,
+                                                const uint8_t*        peer_publickey,
+                                                size_t                peer_publickey_length,
+                                                uint8_t*              shared_secret,
+                                                size_t                shared_secret_size);
+
+/**
+ * @brief   Sign a SHA-256 digest with the ECDSA P-256 private key in a slot.
+ * @param   vault[in]           Vault object to use for signing.
+ * @param   privatekey[in]      The P-256 private key in a slot.
+ * @param   digest[in]          The SHA-256 digest to sign.
+ * @param   digest_length[in]   Length of the digest. Must be 32 bytes.
+ * @param   signature[out]      Buffer to place the signature, R followed by S, in.
+ * @param   signature_size[in]  Size of the signature buffer. Must be at least 64 bytes.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+ockam_error_t ockam_vault_atecc608a_sign(ockam_vault_t*        vault,
+                                         ockam_vault_secret_t* privatekey,
+                                         const uint8_t*        digest,
+                                         size_t                digest_length,
+                                         uint8_t*              signature,
+                                         size_t                signature_size);
+
+/**
+ * @brief   Verify an ECDSA P-256 signature of a SHA-256 digest with a public key given by the host.
+ * @param   vault[in]             Vault object to use for verifying.
+ * @param   publickey[in]         Uncompressed P-256 public key of the signer.
+ * @param   publickey_length[in]  Length of the public key. Must be 65 bytes.
+ * @param   digest[in]            The SHA-256 digest which was signed.
+ * @param   digest_length[in]     Length of the digest. Must be 32 bytes.
+ * @param   signature[in]         The signature, R followed by S.
+ * @param   signature_length[in]  Length of the signature. Must be 64 bytes.
+ * @return  OCKAM_ERROR_NONE if the signature is valid.
+ * @return  OCKAM_VAULT_ATECC608A_ERROR_VERIFY_FAIL if it is not.
+ */
+ockam_error_t ockam_vault_atecc608a_verify(ockam_vault_t* vault,
+                                           const uint8_t* publickey,
+                                           size_t         publickey_length,
+                                           const uint8_t* digest,
+                                           size_t         digest_length,
+                                           const uint8_t* signature,
+                                           size_t         signature_length);
+
 #endif
@@ -0,0 +1,130 @@
+defmodule Ockam.Wire.Native do
+  @moduledoc """
+  Encodes and decodes messages with the codec of the Rust implementation,
+  through the NIFs in `Ockam.Native`, so that they interoperate with Rust nodes.
+
+  The Rust wire format carries a message type before the payload. It is taken
+  from the message's `:message_type`, or is `:payload` if it has none, and is
+  returned as `:message_type` by `decode/1`.
+  """
+
+  @behaviour Ockam.Wire
+
+  alias Ockam.Message
+  alias Ockam.Wire.Binary.V1
+  alias Ockam.Wire.Binary.V1.Address
+  alias Ockam.Wire.DecodeError
+  alias Ockam.Wire.EncodeError
+
+  require DecodeError
+  require EncodeError
+
+  @message_types [
+    ping: 0,
+    pong: 1,
+    payload: 2,
+    key_agreement_m1: 3,
+    key_agreement_m2: 4,
+    key_agreement_m3: 5,
+    close: 6,
+    fragment: 7,
+    channel_error: 8,
+    ack: 9,
+    none: 255
+  ]
+
+  @doc """
+  Encodes a message into a binary.
+
+  Returns `{:ok, iodata}`, if it succeeds.
+  Returns `{:error, error}`, if it fails.
+  """
+  @spec encode(message :: Message.t()) ::
+          {:ok, encoded :: iodata} | {:error, error :: EncodeError.t()}
+
+  def encode(message) do
+    with {:ok, onward_route} <- encode_route(Message.onward_route(message)),
+         {:ok, return_route} <- encode_route(Message.return_route(message)),
+         {:ok, message_type} <- encode_message_type(message),
+         {:ok, payload} <- V1.encode_payload(Message.payload(message)) do
+      payload = IO.iodata_to_binary(payload)
+
+      case Ockam.Native.wire_encode(onward_route, return_route, message_type, payload) do
+        {:ok, encoded} -> {:ok, encoded}
+        {:error, reason} -> {:error, EncodeError.new(reason)}
+      end
+    end
+  end
+
+  defp encode_route(route) when is_list(route) do
+    Enum.reduce_while(route, {:ok, []}, fn address, {:ok, encoded} ->
+      case Address.encode(address) do
+        {:error, error} -> {:halt, {:error, error}}
+        encoded_address -> {:cont, {:ok, encoded ++ [IO.iodata_to_binary(encoded_address)]}}
+      end
+    end)
+  end
+
+  defp encode_route(input), do: {:error, EncodeError.new({:argument_is_not_a_route, input})}
+
+  defp encode_message_type(message) do
+    message_type =
+      case message do
+        %{message_type: message_type} -> message_type
+        _message -> :payload
+      end
+
+    case Keyword.fetch(@message_types, message_type) do
+      {:ok, encoded} -> {:ok, encoded}
+      :error -> {:error, EncodeError.new({:unknown_message_type, message_type})}
+    end
+  end
+
+  @doc """
+  Decodes a message from a binary.
+
+  Returns `{:ok, message}`, if it succeeds.
+  Returns `{:error, error}`, if it fails.
+  """
+  @spec decode(encoded :: binary()) ::
+          {:ok, message :: Message.t()} | {:error, error :: DecodeError.t()}
+
+  def decode(encoded) do
+    case Ockam.Native.wire_decode(encoded) do
+      {:ok, {onward_route, return_route, message_type, payload}} ->
+        with {:ok, message_type} <- decode_message_type(message_type) do
+          {:ok,
+           %{
+             onward_route: Enum.map(onward_route, &decode_address/1),
+             return_route: Enum.map(return_route, &decode_address/1),
+             message_type: message_type,
+             payload: payload
+           }}
+        end
+
+      {:error, reason} ->
+        {:error, DecodeError.new(reason)}
+    end
+  end
+
+  # addresses are decoded as by Ockam.Wire.Binary.V1
+  defp decode_address(<<address_type::unsigned-integer-8, _rest::binary>> = address),
+    do: {address_type, address}
+
+  defp decode_message_type(encoded) do
+    case List.keyfind(@message_types, encoded, 1) do
+      {message_type, ^encoded} -> {:ok, message_type}
+      nil -> {:error, DecodeError.new({:unknown_message_type, encoded})}
+    end
+  end
+
+  @doc """
+  Formats an error returned by `Ockam.Wire.encode/1` or `Ockam.Wire.decode/1`.
+
+  Returns a string.
+  """
+  @spec format_error(error :: EncodeError.t() | DecodeError.t()) ::
+          formatted_error_message :: String.t()
+
+  def format_error(error), do: "Unexpected error: #{inspect(error)}"
+end
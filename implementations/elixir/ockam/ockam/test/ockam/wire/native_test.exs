@@ -0,0 +1,37 @@
+defmodule Ockam.Wire.Native.Tests do
+  use ExUnit.Case, async: true
+
+  alias Ockam.Transport.UDPAddress
+  alias Ockam.Wire
+  alias Ockam.Wire.DecodeError
+  alias Ockam.Wire.Native
+
+  describe "Ockam.Wire.encode/2" do
+    test "encodes a message as the rust implementation does" do
+      message = %{
+        onward_route: [%UDPAddress{ip: {127, 0, 0, 1}, port: 4000}],
+        return_route: [],
+        payload: "hello"
+      }
+
+      assert {:ok, <<1, 1, 2, 7, 0, 127, 0, 0, 1, 160, 15, 0, 2, "hello">>} ==
+               Wire.encode(Native, message)
+    end
+  end
+
+  describe "Ockam.Wire.decode/2" do
+    test "decodes what it encodes" do
+      address = %UDPAddress{ip: {127, 0, 0, 1}, port: 4000}
+      message = %{onward_route: [], return_route: [address], message_type: :ping, payload: ""}
+      {:ok, encoded} = Wire.encode(Native, message)
+      {:ok, decoded} = Wire.decode(Native, encoded)
+
+      assert %{onward_route: [], return_route: [{2, serialized}], message_type: :ping} = decoded
+      assert address == UDPAddress.deserialize(serialized)
+    end
+
+    test "fails on an unknown message type" do
+      assert {:error, %DecodeError{}} = Wire.decode(Native, <<1, 0, 0, 10>>)
+    end
+  end
+end
@@ -0,0 +1,49 @@
+defmodule Ockam.Native do
+  @moduledoc """
+  The message codec and channel encryption of the Rust implementation, as NIFs,
+  so that the two implementations share one wire format.
+
+  Addresses are passed serialized, as in `Ockam.Wire.Binary.V1`, and message
+  types as the byte the Rust implementation puts on the wire.
+
+  A channel is a resource holding one end of an XX key exchange with a software
+  vault of its own. Once the exchange is complete it encrypts and decrypts
+  payloads as the Rust channel manager does, so either end can be the other
+  implementation. A payload is a message encoded by `wire_encode/4`, and
+  decrypting one gives a message for `wire_decode/1`.
+  """
+
+  use Rustler, otp_app: :ockam_native, crate: "ockam_native"
+
+  def wire_encode(_onward_route, _return_route, _message_type, _body) do
+    raise "natively implemented wire_encode/4 not loaded"
+  end
+
+  def wire_decode(_encoded) do
+    raise "natively implemented wire_decode/1 not loaded"
+  end
+
+  def channel_new(_role) do
+    raise "natively implemented channel_new/1 not loaded"
+  end
+
+  def channel_process(_channel, _data) do
+    raise "natively implemented channel_process/2 not loaded"
+  end
+
+  def channel_is_complete(_channel) do
+    raise "natively implemented channel_is_complete/1 not loaded"
+  end
+
+  def channel_remote_public_key(_channel) do
+    raise "natively implemented channel_remote_public_key/1 not loaded"
+  end
+
+  def channel_encrypt(_channel, _encoded) do
+    raise "natively implemented channel_encrypt/2 not loaded"
+  end
+
+  def channel_decrypt(_channel, _body) do
+    raise "natively implemented channel_decrypt/2 not loaded"
+  end
+end
@@ -0,0 +1,423 @@
+//! Elixir NIFs over the Rust message codec and channel encryption, so that the Elixir and Rust
+//! implementations share one wire format rather than each keeping its own in step.
+
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use ockam_channel::protocol::{self, Role};
+use ockam_channel::{compress, stream};
+use ockam_channel::{decrypt_payload, encrypt_payload, DEFAULT_MAX_MESSAGE_SIZE};
+use ockam_kex::xx::XXNewKeyExchanger;
+use ockam_kex::{CipherSuite, CompletedKeyExchange, KeyExchanger, NewKeyExchanger};
+use ockam_message::message::{Codec, Message, MessageType, Route, RouterAddress};
+use ockam_vault::software::DefaultVault;
+use ockam_vault::DynVault;
+use rustler::{Atom, Binary, Env, OwnedBinary, ResourceArc, Term};
+
+mod atoms {
+    rustler::atoms! {
+        initiator,
+        responder,
+    }
+}
+
+/// One end of a channel: the key exchange while it runs, then the keys it agreed and the nonce
+/// of the next payload, as the channel manager keeps them.
+struct ChannelState {
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+    role: Role,
+    cipher_suite_id: u8,
+    agreement: Box<dyn KeyExchanger + Send>,
+    // whether a responder has received M1, so the next message is M3
+    m1_received: bool,
+    completed_key_exchange: Option<CompletedKeyExchange>,
+    nonce: u64,
+    // the sequence number of the next payload, all sent on stream 0
+    seq: u64,
+}
+
+impl ChannelState {
+    fn new(role: Role) -> Self {
+        let vault: Arc<Mutex<dyn DynVault + Send>> = Arc::new(Mutex::new(DefaultVault::default()));
+        let exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        let agreement = match role {
+            Role::Initiator => exchanger.initiator(None),
+            Role::Responder => exchanger.responder(None),
+        };
+        ChannelState {
+            vault,
+            role,
+            cipher_suite_id: exchanger.cipher_suite_id(),
+            agreement,
+            m1_received: false,
+            completed_key_exchange: None,
+            nonce: 0,
+            seq: 0,
+        }
+    }
+
+    // Run the key exchange over the body of a message received, returning the body of the
+    // reply. As a channel manager frames them, M1 carries the cipher suite and the options
+    // offered before the key exchange's message, and M2 the options accepted; none are offered
+    // or accepted here.
+    fn process(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        if self.completed_key_exchange.is_some() {
+            return Err("key exchange is complete".into());
+        }
+        let ka_data = match self.role {
+            Role::Initiator if data.is_empty() => data,
+            Role::Initiator => protocol::split_m2(data)?.1,
+            Role::Responder if !self.m1_received => {
+                let (cipher_suite_id, _options, ka_m1) = protocol::split_m1(data)?;
+                if cipher_suite_id != self.cipher_suite_id {
+                    return Err("unsupported cipher suite".into());
+                }
+                ka_m1
+            }
+            Role::Responder => data,
+        };
+
+        let mut reply = self.agreement.process(ka_data)?;
+        if reply.is_empty() && !self.agreement.is_complete() {
+            reply = self.agreement.process(&[])?;
+        }
+        if self.agreement.is_complete() {
+            self.completed_key_exchange = Some(self.agreement.finalize()?);
+        }
+        Ok(match self.role {
+            Role::Initiator if data.is_empty() => {
+                protocol::m1_body(self.cipher_suite_id, 0, &reply)
+            }
+            Role::Responder if !self.m1_received => {
+                self.m1_received = true;
+                protocol::m2_body(0, &reply)
+            }
+            _ => reply,
+        })
+    }
+
+    // As a channel manager sends a payload: the encoded message after a byte telling that it
+    // isn't compressed, framed on its stream, then encrypted.
+    fn encrypt(&mut self, encoded: &[u8]) -> Result<Vec<u8>, String> {
+        let cke = self
+            .completed_key_exchange
+            .ok_or("key exchange is not complete")?;
+        let plaintext = stream::frame(0, self.seq, &compress::compress(encoded, None));
+        let body = {
+            let mut vault = self.vault.lock().unwrap();
+            encrypt_payload(&mut *vault, &cke, self.nonce, &plaintext)?
+        };
+        self.nonce += 1;
+        self.seq += 1;
+        Ok(body)
+    }
+
+    // The encoded message a channel manager sent, in whichever order the payloads arrive.
+    fn decrypt(&self, body: &[u8]) -> Result<Vec<u8>, String> {
+        let cke = self
+            .completed_key_exchange
+            .ok_or("key exchange is not complete")?;
+        // each direction has its own key, so only the nonces sent are counted
+        let (_nonce, plaintext) = {
+            let mut vault = self.vault.lock().unwrap();
+            decrypt_payload(&mut *vault, &cke, body)?
+        };
+        let (_stream, _seq, payload) =
+            stream::unframe(&plaintext).ok_or("payload has no stream header")?;
+        Ok(compress::decompress(payload, DEFAULT_MAX_MESSAGE_SIZE)?)
+    }
+}
+
+struct ChannelResource(Mutex<ChannelState>);
+
+fn to_binary<'a>(env: Env<'a>, bytes: &[u8]) -> Result<Binary<'a>, String> {
+    let mut b = OwnedBinary::new(bytes.len()).ok_or("failed to allocate binary")?;
+    b.as_mut_slice().copy_from_slice(bytes);
+    Ok(b.release(env))
+}
+
+fn to_route(addresses: Vec<Binary>) -> Result<Route, String> {
+    let mut route = Route { addresses: vec![] };
+    for a in addresses {
+        let (address, rest) = RouterAddress::decode(a.as_slice())?;
+        if !rest.is_empty() {
+            return Err("address has trailing bytes".into());
+        }
+        route.addresses.push(address);
+    }
+    Ok(route)
+}
+
+fn from_route<'a>(env: Env<'a>, route: &Route) -> Result<Vec<Binary<'a>>, String> {
+    let mut addresses = vec![];
+    for a in &route.addresses {
+        let mut v = vec![];
+        RouterAddress::encode(a, &mut v)?;
+        addresses.push(to_binary(env, &v)?);
+    }
+    Ok(addresses)
+}
+
+/// Encode a message whose addresses are already serialized, returning `{:ok, binary}`.
+#[rustler::nif]
+fn wire_encode<'a>(
+    env: Env<'a>,
+    onward_route: Vec<Binary>,
+    return_route: Vec<Binary>,
+    message_type: u8,
+    body: Binary,
+) -> Result<Binary<'a>, String> {
+    let m = Message {
+        onward_route: to_route(onward_route)?,
+        return_route: to_route(return_route)?,
+        message_type: MessageType::try_from(message_type)?,
+        message_body: body.as_slice().to_vec(),
+    };
+    let mut v = vec![];
+    Message::encode(&m, &mut v)?;
+    to_binary(env, &v)
+}
+
+/// Decode a message, returning `{:ok, {onward_route, return_route, message_type, body}}`.
+#[rustler::nif]
+#[allow(clippy::type_complexity)]
+fn wire_decode<'a>(
+    env: Env<'a>,
+    encoded: Binary,
+) -> Result<(Vec<Binary<'a>>, Vec<Binary<'a>>, u8, Binary<'a>), String> {
+    if encoded.is_empty() {
+        return Err("empty message".into());
+    }
+    let (m, _) = Message::decode(encoded.as_slice())?;
+    Ok((
+        from_route(env, &m.onward_route)?,
+        from_route(env, &m.return_route)?,
+        m.message_type as u8,
+        to_binary(env, &m.message_body)?,
+    ))
+}
+
+/// Start a channel as `:initiator` or `:responder`, with a software vault of its own.
+#[rustler::nif]
+fn channel_new(role: Atom) -> Result<ResourceArc<ChannelResource>, String> {
+    let role = if role == atoms::initiator() {
+        Role::Initiator
+    } else if role == atoms::responder() {
+        Role::Responder
+    } else {
+        return Err("role must be :initiator or :responder".into());
+    };
+    Ok(ResourceArc::new(ChannelResource(Mutex::new(
+        ChannelState::new(role),
+    ))))
+}
+
+/// Take the body of the key exchange message received from the other end, or `""` for the
+/// initiator's first, returning `{:ok, reply}` where the reply is the body of the message to
+/// send back, or `""` when there is nothing to send. The bodies of M1 and M2 are framed as a
+/// Rust channel manager frames them, so either end may be a Rust node.
+#[rustler::nif]
+fn channel_process<'a>(
+    env: Env<'a>,
+    channel: ResourceArc<ChannelResource>,
+    data: Binary,
+) -> Result<Binary<'a>, String> {
+    let reply = channel.0.lock().unwrap().process(data.as_slice())?;
+    to_binary(env, &reply)
+}
+
+#[rustler::nif]
+fn channel_is_complete(channel: ResourceArc<ChannelResource>) -> bool {
+    channel.0.lock().unwrap().completed_key_exchange.is_some()
+}
+
+/// The other end's static public key, once the key exchange is complete.
+#[rustler::nif]
+fn channel_remote_public_key<'a>(
+    env: Env<'a>,
+    channel: ResourceArc<ChannelResource>,
+) -> Result<Binary<'a>, String> {
+    let channel = channel.0.lock().unwrap();
+    match &channel.completed_key_exchange {
+        Some(cke) => to_binary(env, cke.remote_static_public_key.as_ref()),
+        None => Err("key exchange is not complete".into()),
+    }
+}
+
+/// Encrypt a message encoded by `wire_encode` into the body of a channel payload, returning
+/// `{:ok, body}`. The body is framed as a Rust channel manager frames it, and never compressed.
+#[rustler::nif]
+fn channel_encrypt<'a>(
+    env: Env<'a>,
+    channel: ResourceArc<ChannelResource>,
+    encoded: Binary,
+) -> Result<Binary<'a>, String> {
+    let body = channel.0.lock().unwrap().encrypt(encoded.as_slice())?;
+    to_binary(env, &body)
+}
+
+/// Decrypt the body of a channel payload, inflating it if the other end compressed it, and
+/// return `{:ok, encoded}` with the message it carries, for `wire_decode`.
+#[rustler::nif]
+fn channel_decrypt<'a>(
+    env: Env<'a>,
+    channel: ResourceArc<ChannelResource>,
+    body: Binary,
+) -> Result<Binary<'a>, String> {
+    let encoded = channel.0.lock().unwrap().decrypt(body.as_slice())?;
+    to_binary(env, &encoded)
+}
+
+fn load(env: Env, _info: Term) -> bool {
+    rustler::resource!(ChannelResource, env);
+    true
+}
+
+rustler::init!(
+    "Elixir.Ockam.Native",
+    [
+        wire_encode,
+        wire_decode,
+        channel_new,
+        channel_process,
+        channel_is_complete,
+        channel_remote_public_key,
+        channel_encrypt,
+        channel_decrypt
+    ],
+    load = load
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_channel::{ChannelManager, CHANNEL_ZERO};
+    use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    // A channel manager using the software vault, with the sender of its commands and the
+    // receiver of what it sends the router.
+    fn manager() -> (ChannelManager, Sender<OckamCommand>, Receiver<OckamCommand>) {
+        let (router_tx, router_rx) = channel();
+        let (channel_tx, channel_rx) = channel();
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let new_key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        let manager = ChannelManager::new(
+            channel_rx,
+            channel_tx.clone(),
+            router_tx,
+            vault,
+            Box::new(new_key_exchanger),
+            None,
+            None,
+        )
+        .unwrap();
+        (manager, channel_tx, router_rx)
+    }
+
+    // A message for the manager sent to `to` by the NIF's end, at a UDP address.
+    fn received(to: RouterAddress, message_type: MessageType, body: Vec<u8>) -> OckamCommand {
+        OckamCommand::Channel(ChannelCommand::ReceiveMessage(Message {
+            onward_route: Route {
+                addresses: vec![to],
+            },
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap()
+                ],
+            },
+            message_type,
+            message_body: body,
+        }))
+    }
+
+    fn payload(onward_route: Vec<RouterAddress>, body: &[u8]) -> Message {
+        Message {
+            onward_route: Route {
+                addresses: onward_route,
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: body.to_vec(),
+        }
+    }
+
+    // The first message of `message_type` the manager has passed to the router.
+    fn routed(router_rx: &Receiver<OckamCommand>, message_type: MessageType) -> Message {
+        router_rx
+            .try_iter()
+            .find_map(|cmd| match cmd {
+                OckamCommand::Router(RouterCommand::SendMessage(m))
+                | OckamCommand::Router(RouterCommand::ReceiveMessage(m))
+                    if m.message_type == message_type =>
+                {
+                    Some(m)
+                }
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    // needs the software vault's crypto
+    #[test]
+    fn key_exchange_with_channel_manager() {
+        let (mut manager, manager_tx, router_rx) = manager();
+        let mut initiator = ChannelState::new(Role::Initiator);
+
+        let m1 = initiator.process(&[]).unwrap();
+        let channel_zero = RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap();
+        manager_tx
+            .send(received(channel_zero, MessageType::KeyAgreementM1, m1))
+            .unwrap();
+        manager.poll().unwrap();
+        let m2 = routed(&router_rx, MessageType::KeyAgreementM2);
+
+        // M3 goes to the channel's ciphertext address, which M2 came from
+        let m3 = initiator.process(&m2.message_body).unwrap();
+        let channel = m2.return_route.addresses[0].clone();
+        manager_tx
+            .send(received(channel.clone(), MessageType::KeyAgreementM3, m3))
+            .unwrap();
+        manager.poll().unwrap();
+
+        // both ends are done, and the manager announces the channel to its workers
+        assert!(initiator.completed_key_exchange.is_some());
+        let announced = routed(&router_rx, MessageType::None);
+        assert_eq!(manager.list_channels().len(), 1);
+
+        // a payload each way, the manager's worker sending from the channel's cleartext address
+        let worker = RouterAddress::worker_router_address_from_str("00000002").unwrap();
+        let mut encoded = vec![];
+        Message::encode(&payload(vec![worker.clone()], b"hello"), &mut encoded).unwrap();
+        let body = initiator.encrypt(&encoded).unwrap();
+        manager_tx
+            .send(received(channel, MessageType::Payload, body))
+            .unwrap();
+        manager.poll().unwrap();
+        let delivered = routed(&router_rx, MessageType::Payload);
+        assert_eq!(delivered.onward_route.addresses, vec![worker.clone()]);
+        assert_eq!(delivered.message_body, b"hello".to_vec());
+
+        assert_eq!(
+            delivered.return_route.addresses,
+            announced.return_route.addresses
+        );
+        let mut reply = payload(delivered.return_route.addresses, b"hello back");
+        reply.return_route.addresses.push(worker);
+        manager_tx
+            .send(OckamCommand::Channel(ChannelCommand::SendMessage(reply)))
+            .unwrap();
+        manager.poll().unwrap();
+        let sent = routed(&router_rx, MessageType::Payload);
+        let (m, _) = Message::decode(&initiator.decrypt(&sent.message_body).unwrap()).unwrap();
+        assert_eq!(m.message_body, b"hello back".to_vec());
+    }
+}
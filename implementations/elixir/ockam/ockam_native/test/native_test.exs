@@ -0,0 +1,61 @@
+defmodule Ockam.Native.Tests do
+  use ExUnit.Case, async: true
+  doctest Ockam.Native
+  alias Ockam.Native
+
+  # 127.0.0.1:4000 as a udp address
+  @udp_address <<2, 7, 0, 127, 0, 0, 1, 160, 15>>
+
+  describe "Ockam.Native.wire_encode/4" do
+    test "encodes as the rust implementation does" do
+      {:ok, encoded} = Native.wire_encode([@udp_address], [], 2, "hello")
+      assert encoded == <<1, 1>> <> @udp_address <> <<0, 2>> <> "hello"
+    end
+
+    test "returns an error for an address which isn't serialized" do
+      assert {:error, _reason} = Native.wire_encode([<<9>>], [], 2, "hello")
+    end
+  end
+
+  describe "Ockam.Native.wire_decode/1" do
+    test "decodes what wire_encode/4 encodes" do
+      {:ok, encoded} = Native.wire_encode([@udp_address], [@udp_address], 0, "ping")
+      assert {:ok, {[@udp_address], [@udp_address], 0, "ping"}} == Native.wire_decode(encoded)
+    end
+
+    test "returns an error for an empty binary" do
+      assert {:error, _reason} = Native.wire_decode("")
+    end
+  end
+
+  describe "Ockam.Native.channel_process/2" do
+    test "completes a key exchange and encrypts between the two ends" do
+      {:ok, initiator} = Native.channel_new(:initiator)
+      {:ok, responder} = Native.channel_new(:responder)
+
+      {:ok, m1} = Native.channel_process(initiator, "")
+      {:ok, m2} = Native.channel_process(responder, m1)
+      {:ok, m3} = Native.channel_process(initiator, m2)
+      {:ok, ""} = Native.channel_process(responder, m3)
+
+      assert Native.channel_is_complete(initiator)
+      assert Native.channel_is_complete(responder)
+      {:ok, key} = Native.channel_remote_public_key(initiator)
+      assert byte_size(key) == 32
+
+      # payloads are encoded messages, as a Rust channel carries them
+      {:ok, hello} = Native.wire_encode([@udp_address], [], 2, "hello")
+      {:ok, body} = Native.channel_encrypt(initiator, hello)
+      assert {:ok, hello} == Native.channel_decrypt(responder, body)
+
+      {:ok, world} = Native.wire_encode([], [@udp_address], 2, "world")
+      {:ok, body} = Native.channel_encrypt(responder, world)
+      assert {:ok, world} == Native.channel_decrypt(initiator, body)
+    end
+
+    test "won't encrypt before the key exchange is complete" do
+      {:ok, initiator} = Native.channel_new(:initiator)
+      assert {:error, _reason} = Native.channel_encrypt(initiator, "hello")
+    end
+  end
+end
@@ -0,0 +1,4 @@
+Application.ensure_all_started(:logger)
+Application.ensure_all_started(:ockam_native)
+
+ExUnit.start(capture_log: true, trace: true)
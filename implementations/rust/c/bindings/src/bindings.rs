@@ -1013,6 +1013,8 @@ pub enum ockam_error_code_vault_atecc608a_t {
     OCKAM_VAULT_ATECC608A_ERROR_INVALID_SECRET_TYPE = 13,
     OCKAM_VAULT_ATECC608A_ERROR_HKDF_SHA256_FAIL = 14,
     OCKAM_VAULT_ATECC608A_ERROR_AEAD_AES_GCM_FAIL = 15,
+    OCKAM_VAULT_ATECC608A_ERROR_SIGN_FAIL = 16,
+    OCKAM_VAULT_ATECC608A_ERROR_VERIFY_FAIL = 17,
 }
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -1035,3 +1037,61 @@ extern "C" {
         attributes: *mut ockam_vault_atecc608a_attributes_t,
     ) -> ockam_error_t;
 }
+extern "C" {
+    #[doc = " @brief   Perform ECDH with a private key in a slot, returning the shared secret to the host. The shared"]
+    #[doc = "          secret is encrypted with the IO protection key on its way over the bus."]
+    #[doc = " @param   vault[in]                 Vault object to use for ECDH."]
+    #[doc = " @param   privatekey[in]            The P-256 private key in a slot."]
+    #[doc = " @param   peer_publickey[in]        Uncompressed P-256 public key of the peer."]
+    #[doc = " @param   peer_publickey_length[in] Length of the public key. Must be 65 bytes."]
+    #[doc = " @param   shared_secret[out]        Buffer to place the shared secret in."]
+    #[doc = " @param   shared_secret_size[in]    Size of the shared secret buffer. Must be at least 32 bytes."]
+    #[doc = " @return  OCKAM_ERROR_NONE on success."]
+    pub fn ockam_vault_atecc608a_ecdh_output(
+        vault: *mut ockam_vault_t,
+        privatekey: *mut ockam_vault_secret_t,
+        peer_publickey: *const u8,
+        peer_publickey_length: usize,
+        shared_secret: *mut u8,
+        shared_secret_size: usize,
+    ) -> ockam_error_t;
+}
+extern "C" {
+    #[doc = " @brief   Sign a SHA-256 digest with the ECDSA P-256 private key in a slot."]
+    #[doc = " @param   vault[in]           Vault object to use for signing."]
+    #[doc = " @param   privatekey[in]      The P-256 private key in a slot."]
+    #[doc = " @param   digest[in]          The SHA-256 digest to sign."]
+    #[doc = " @param   digest_length[in]   Length of the digest. Must be 32 bytes."]
+    #[doc = " @param   signature[out]      Buffer to place the signature, R followed by S, in."]
+    #[doc = " @param   signature_size[in]  Size of the signature buffer. Must be at least 64 bytes."]
+    #[doc = " @return  OCKAM_ERROR_NONE on success."]
+    pub fn ockam_vault_atecc608a_sign(
+        vault: *mut ockam_vault_t,
+        privatekey: *mut ockam_vault_secret_t,
+        digest: *const u8,
+        digest_length: usize,
+        signature: *mut u8,
+        signature_size: usize,
+    ) -> ockam_error_t;
+}
+extern "C" {
+    #[doc = " @brief   Verify an ECDSA P-256 signature of a SHA-256 digest with a public key given by the host."]
+    #[doc = " @param   vault[in]             Vault object to use for verifying."]
+    #[doc = " @param   publickey[in]         Uncompressed P-256 public key of the signer."]
+    #[doc = " @param   publickey_length[in]  Length of the public key. Must be 65 bytes."]
+    #[doc = " @param   digest[in]            The SHA-256 digest which was signed."]
+    #[doc = " @param   digest_length[in]     Length of the digest. Must be 32 bytes."]
+    #[doc = " @param   signature[in]         The signature, R followed by S."]
+    #[doc = " @param   signature_length[in]  Length of the signature. Must be 64 bytes."]
+    #[doc = " @return  OCKAM_ERROR_NONE if the signature is valid."]
+    #[doc = " @return  OCKAM_VAULT_ATECC608A_ERROR_VERIFY_FAIL if it is not."]
+    pub fn ockam_vault_atecc608a_verify(
+        vault: *mut ockam_vault_t,
+        publickey: *const u8,
+        publickey_length: usize,
+        digest: *const u8,
+        digest_length: usize,
+        signature: *const u8,
+        signature_length: usize,
+    ) -> ockam_error_t;
+}
@@ -0,0 +1,120 @@
+/**
+ * @file    node.h
+ * @brief   Node interface for the Ockam Library
+ */
+
+#ifndef OCKAM_NODE_H_
+#define OCKAM_NODE_H_
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef uint64_t ockam_node_t;
+
+#define OCKAM_NODE_ERROR_NONE             0
+#define OCKAM_NODE_ERROR_INVALID_PARAM    1
+#define OCKAM_NODE_ERROR_INVALID_HANDLE   2
+#define OCKAM_NODE_ERROR_CREATE           3
+#define OCKAM_NODE_ERROR_CHANNEL          4
+#define OCKAM_NODE_ERROR_TIMEOUT          5
+#define OCKAM_NODE_ERROR_STOPPED          6
+#define OCKAM_NODE_ERROR_BUFFER_TOO_SMALL 7
+#define OCKAM_NODE_ERROR_STOP             8
+#define OCKAM_NODE_ERROR_PANIC            9
+
+/**
+ * @brief   Room for the hex of any address, and a terminating NUL.
+ */
+#define OCKAM_NODE_ADDRESS_SIZE 512
+
+/**
+ * @enum    ockam_node_message_type_t
+ * @brief   Kinds of message received by the inbox.
+ */
+typedef enum {
+    OCKAM_NODE_MESSAGE_PAYLOAD = 0,
+    OCKAM_NODE_MESSAGE_CHANNEL = 1,
+    OCKAM_NODE_MESSAGE_CLOSED = 2,
+    OCKAM_NODE_MESSAGE_CHANNEL_ERROR = 3, // Key exchange failed; payload is the error code, LE u32
+} ockam_node_message_type_t;
+
+/**
+ * @struct  ockam_node_message_t
+ * @brief   A message received by the inbox, its payload apart.
+ */
+typedef struct {
+    uint32_t type;                             // ockam_node_message_type_t
+    char     channel[OCKAM_NODE_ADDRESS_SIZE]; // Channel it arrived over, or empty if from this node
+    char     worker[OCKAM_NODE_ADDRESS_SIZE];  // Worker which sent it, or empty for a channel
+    uint32_t length;                           // Length of the payload
+} ockam_node_message_t;
+
+/**
+ * @brief   Create a node and start polling it on a thread of its own.
+ * @param   node[out]          The node object to initialize.
+ * @param   options[in]        The command-line options of ockamd, e.g. "--role", "initiator".
+ * @param   options_length[in] Number of options.
+ * @param   inbox[in]          Worker address, in hex, of the messages received by the application.
+ * @return  OCKAM_NODE_ERROR_NONE on success.
+ */
+uint32_t ockam_node_create(ockam_node_t*      node,
+                           const char* const* options,
+                           uint32_t           options_length,
+                           const char*        inbox);
+
+/**
+ * @brief   Initiate a secure channel, which is announced to the inbox once established as a message
+ *          of type OCKAM_NODE_MESSAGE_CHANNEL, with the responder's public key as its payload.
+ * @param   node[in]  The node to initiate the channel from.
+ * @param   route[in] Route to the responder, as for ockamd --route, e.g. "udp://127.0.0.1:4050".
+ * @return  OCKAM_NODE_ERROR_NONE on success.
+ */
+uint32_t ockam_node_channel_initiate(ockam_node_t node, const char* route);
+
+/**
+ * @brief   Queue a message for a worker, whose replies arrive at the inbox.
+ * @param   node[in]           The node to send from.
+ * @param   channel[in]        Channel address, in hex, or NULL for a worker on this node.
+ * @param   worker[in]         Worker address, in hex.
+ * @param   payload[in]        Payload to send.
+ * @param   payload_length[in] Length of the payload.
+ * @return  OCKAM_NODE_ERROR_NONE on success.
+ */
+uint32_t ockam_node_send(ockam_node_t   node,
+                         const char*    channel,
+                         const char*    worker,
+                         const uint8_t* payload,
+                         uint32_t       payload_length);
+
+/**
+ * @brief   Receive the next message for the inbox. A message whose payload doesn't fit the buffer
+ *          is kept for the next call, and OCKAM_NODE_ERROR_BUFFER_TOO_SMALL returned with its length.
+ * @param   node[in]        The node to receive from.
+ * @param   timeout_ms[in]  Longest to wait for a message, or 0 not to wait.
+ * @param   message[out]    The message received.
+ * @param   buffer[out]     Buffer to place the payload in.
+ * @param   buffer_size[in] Size of the buffer.
+ * @return  OCKAM_NODE_ERROR_NONE on success, OCKAM_NODE_ERROR_TIMEOUT if no message arrived.
+ */
+uint32_t ockam_node_receive(ockam_node_t          node,
+                            uint32_t              timeout_ms,
+                            ockam_node_message_t* message,
+                            uint8_t*              buffer,
+                            uint32_t              buffer_size);
+
+/**
+ * @brief   Stop the node. The node object is invalid afterwards.
+ * @param   node[in]       The node to stop.
+ * @param   timeout_ms[in] Longest for the node's threads to finish.
+ * @return  OCKAM_NODE_ERROR_NONE on success.
+ */
+uint32_t ockam_node_stop(ockam_node_t node, uint32_t timeout_ms);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif
+
+#endif
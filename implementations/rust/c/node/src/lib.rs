@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::ffi::{CStr, OsString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use ockam_message::message::{
+    AddressType, Message as OckamMessage, MessageType, Route, RouterAddress,
+};
+use ockamd::cli::{Args, OutputKind};
+use ockamd::config::Config;
+use ockamd::{NodeBuilder, RemoteHandle, ThreadPolicy};
+
+/// Represents a node handle
+pub type OckamNode = u64;
+/// Represents a node error code
+pub type NodeError = u32;
+
+/// No error or success
+pub const ERROR_NONE: NodeError = 0;
+/// A null pointer, a string which isn't UTF-8, or an option, address or route which doesn't parse
+pub const ERROR_INVALID_PARAM: NodeError = 1;
+/// No node has the handle, or it has been stopped
+pub const ERROR_INVALID_HANDLE: NodeError = 2;
+/// The node failed to start, e.g. its socket is in use or its vault failed to open
+pub const ERROR_CREATE: NodeError = 3;
+/// The node can't initiate channels, e.g. because it is a relay
+pub const ERROR_CHANNEL: NodeError = 4;
+/// No message arrived within the timeout
+pub const ERROR_TIMEOUT: NodeError = 5;
+/// The node has stopped running
+pub const ERROR_STOPPED: NodeError = 6;
+/// The message's payload doesn't fit the buffer, and is kept for the next receive
+pub const ERROR_BUFFER_TOO_SMALL: NodeError = 7;
+/// The node's threads failed to stop, or didn't in time
+pub const ERROR_STOP: NodeError = 8;
+/// The call panicked
+pub const ERROR_PANIC: NodeError = 9;
+
+/// A message sent to the inbox
+pub const MESSAGE_PAYLOAD: u32 = 0;
+/// A channel announced to the inbox once established
+pub const MESSAGE_CHANNEL: u32 = 1;
+/// A channel announced to the inbox which has since closed, at either end
+pub const MESSAGE_CLOSED: u32 = 2;
+/// A channel initiated for the inbox whose key exchange failed, its payload the error code as a
+/// little-endian u32
+pub const MESSAGE_CHANNEL_ERROR: u32 = 3;
+
+/// Room for the hex of any address, and a terminating NUL
+pub const ADDRESS_SIZE: usize = 512;
+
+/// A message received by the inbox, its payload apart
+#[repr(C)]
+pub struct FfiMessage {
+    pub message_type: u32,
+    /// The channel the message arrived over, in hex, or empty if it came from this node
+    pub channel: [c_char; ADDRESS_SIZE],
+    /// The worker which sent the message, in hex, or empty for a channel announcement
+    pub worker: [c_char; ADDRESS_SIZE],
+    pub length: u32,
+}
+
+impl FfiMessage {
+    fn fill(&mut self, msg: &OckamMessage) {
+        self.message_type = match msg.message_type {
+            MessageType::None => MESSAGE_CHANNEL,
+            MessageType::Close => MESSAGE_CLOSED,
+            MessageType::ChannelError => MESSAGE_CHANNEL_ERROR,
+            _ => MESSAGE_PAYLOAD,
+        };
+        let find = |a_type| {
+            msg.return_route
+                .addresses
+                .iter()
+                .find(|a| a.a_type == a_type)
+                .map(|a| a.address.as_string())
+                .unwrap_or_default()
+        };
+        copy_address(&mut self.channel, &find(AddressType::Channel));
+        copy_address(&mut self.worker, &find(AddressType::Worker));
+        self.length = msg.message_body.len() as u32;
+    }
+}
+
+fn copy_address(dst: &mut [c_char; ADDRESS_SIZE], address: &str) {
+    let len = address.len().min(ADDRESS_SIZE - 1);
+    for (d, s) in dst.iter_mut().zip(address.bytes().take(len)) {
+        *d = s as c_char;
+    }
+    dst[len] = 0;
+}
+
+/// Wraps a node started for a C application
+struct FfiNode {
+    remote: RemoteHandle,
+    inbox: RouterAddress,
+    // a message whose payload didn't fit the last receive's buffer
+    pending: Mutex<Option<OckamMessage>>,
+}
+
+lazy_static! {
+    static ref NODES: Mutex<BTreeMap<OckamNode, Arc<FfiNode>>> = Mutex::new(BTreeMap::new());
+}
+static NEXT_NODE: AtomicU64 = AtomicU64::new(1);
+
+// Call `f`, reporting a panic as an error rather than unwinding into C.
+fn guard(f: impl FnOnce() -> NodeError) -> NodeError {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(ERROR_PANIC)
+}
+
+// Call `f` with the node behind `node`, which other threads may use meanwhile.
+fn with_node(node: OckamNode, f: impl FnOnce(&FfiNode) -> NodeError) -> NodeError {
+    guard(|| {
+        let node = match NODES.lock().unwrap().get(&node) {
+            Some(node) => node.clone(),
+            None => return ERROR_INVALID_HANDLE,
+        };
+        f(&node)
+    })
+}
+
+// The string at `s`, or None if it is null or not UTF-8.
+unsafe fn string<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// Create a node from `options`, the command-line options of `ockamd` such as `--role` and
+/// `--local-socket`, and start polling it on a thread of its own. The messages for the worker
+/// address `inbox`, in hex, and the channels the node initiates are received with
+/// `ockam_node_receive`.
+///
+/// # Safety
+/// `options` must point to `options_length` NUL-terminated strings, and `inbox` to another.
+#[no_mangle]
+pub unsafe extern "C" fn ockam_node_create(
+    node: *mut OckamNode,
+    options: *const *const c_char,
+    options_length: u32,
+    inbox: *const c_char,
+) -> NodeError {
+    if node.is_null() || (options.is_null() && options_length > 0) {
+        return ERROR_INVALID_PARAM;
+    }
+    let mut args = vec![OsString::from("ockamd")];
+    for i in 0..options_length as usize {
+        match string(*options.add(i)) {
+            Some(option) => args.push(option.into()),
+            None => return ERROR_INVALID_PARAM,
+        }
+    }
+    let inbox = match string(inbox).map(RouterAddress::worker_router_address_from_str) {
+        Some(Ok(inbox)) => inbox,
+        _ => return ERROR_INVALID_PARAM,
+    };
+
+    guard(|| {
+        let config: Config = match Args::load(args) {
+            Ok(args) => args.into(),
+            Err(_) => return ERROR_INVALID_PARAM,
+        };
+        let remote = NodeBuilder::new(config)
+            .inbox(inbox.clone())
+            .thread_policy(ThreadPolicy::Dedicated)
+            .start()
+            .and_then(|handle| handle.into_remote());
+        match remote {
+            Ok(remote) => {
+                let handle = NEXT_NODE.fetch_add(1, Ordering::SeqCst);
+                let ffi_node = FfiNode {
+                    remote,
+                    inbox,
+                    pending: Mutex::new(None),
+                };
+                NODES.lock().unwrap().insert(handle, Arc::new(ffi_node));
+                *node = handle;
+                ERROR_NONE
+            }
+            Err(_) => ERROR_CREATE,
+        }
+    })
+}
+
+/// Initiate a secure channel along `route`, given as for `ockamd --route`, e.g.
+/// "udp://127.0.0.1:4050". Once established the channel is announced to the inbox as a message
+/// of type `MESSAGE_CHANNEL` from the channel, with the responder's public key as its payload.
+///
+/// # Safety
+/// `route` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ockam_node_channel_initiate(
+    node: OckamNode,
+    route: *const c_char,
+) -> NodeError {
+    let route = match string(route).map(OutputKind::from_str) {
+        Some(Ok(OutputKind::Channel(route))) => route,
+        _ => return ERROR_INVALID_PARAM,
+    };
+    with_node(node, |node| {
+        match node.remote.initiate_channel(route, node.inbox.clone()) {
+            Ok(()) => ERROR_NONE,
+            Err(_) => ERROR_CHANNEL,
+        }
+    })
+}
+
+/// Send `payload` to the worker at `worker`, in hex, over the channel at `channel`, or to a
+/// worker on this node if `channel` is null. Replies arrive at the inbox. Returns once the
+/// message is queued, without waiting for it to be sent.
+///
+/// # Safety
+/// `channel`, unless null, and `worker` must be NUL-terminated strings, and `payload` must have
+/// `payload_length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ockam_node_send(
+    node: OckamNode,
+    channel: *const c_char,
+    worker: *const c_char,
+    payload: *const u8,
+    payload_length: u32,
+) -> NodeError {
+    if payload.is_null() && payload_length > 0 {
+        return ERROR_INVALID_PARAM;
+    }
+    let mut onward_route = vec![];
+    if !channel.is_null() {
+        match string(channel).map(RouterAddress::channel_router_address_from_str) {
+            Some(Ok(channel)) => onward_route.push(channel),
+            _ => return ERROR_INVALID_PARAM,
+        }
+    }
+    match string(worker).map(RouterAddress::worker_router_address_from_str) {
+        Some(Ok(worker)) => onward_route.push(worker),
+        _ => return ERROR_INVALID_PARAM,
+    }
+    let message_body = if payload_length > 0 {
+        slice::from_raw_parts(payload, payload_length as usize).to_vec()
+    } else {
+        vec![]
+    };
+
+    with_node(node, |node| {
+        let msg = OckamMessage {
+            onward_route: Route {
+                addresses: onward_route,
+            },
+            return_route: Route {
+                addresses: vec![node.inbox.clone()],
+            },
+            message_type: MessageType::Payload,
+            message_body,
+        };
+        match node.remote.send(msg) {
+            Ok(()) => ERROR_NONE,
+            Err(_) => ERROR_STOPPED,
+        }
+    })
+}
+
+/// Receive the next message for the inbox into `message`, and its payload into `buffer`, waiting
+/// at most `timeout_ms` for one, or not at all if it is 0. A message whose payload doesn't fit is
+/// kept for the next receive, with its length in `message`.
+///
+/// # Safety
+/// `message` must point to an `FfiMessage`, and `buffer` must have room for `buffer_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ockam_node_receive(
+    node: OckamNode,
+    timeout_ms: u32,
+    message: *mut FfiMessage,
+    buffer: *mut u8,
+    buffer_size: u32,
+) -> NodeError {
+    if message.is_null() || (buffer.is_null() && buffer_size > 0) {
+        return ERROR_INVALID_PARAM;
+    }
+    with_node(node, |node| {
+        // receivers on several threads take turns
+        let mut pending = node.pending.lock().unwrap();
+        let msg = match pending.take() {
+            Some(msg) => msg,
+            None => {
+                let timeout = Duration::from_millis(timeout_ms.into());
+                match node.remote.recv_timeout(timeout) {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => return ERROR_TIMEOUT,
+                    Err(_) => return ERROR_STOPPED,
+                }
+            }
+        };
+        (*message).fill(&msg);
+        let length = msg.message_body.len();
+        if length > buffer_size as usize {
+            *pending = Some(msg);
+            return ERROR_BUFFER_TOO_SMALL;
+        }
+        if length > 0 {
+            ptr::copy_nonoverlapping(msg.message_body.as_ptr(), buffer, length);
+        }
+        ERROR_NONE
+    })
+}
+
+/// Stop the node, giving its threads `timeout_ms` to finish. The handle is invalid afterwards, and
+/// a receive waiting meanwhile returns `ERROR_STOPPED`.
+#[no_mangle]
+pub extern "C" fn ockam_node_stop(node: OckamNode, timeout_ms: u32) -> NodeError {
+    guard(|| {
+        let node = match NODES.lock().unwrap().remove(&node) {
+            Some(node) => node,
+            None => return ERROR_INVALID_HANDLE,
+        };
+        match node.remote.stop(Duration::from_millis(timeout_ms.into())) {
+            Ok(()) => ERROR_NONE,
+            Err(_) => ERROR_STOP,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn create(options: &[&str], inbox: &str) -> Result<OckamNode, NodeError> {
+        let options: Vec<_> = options.iter().map(|o| CString::new(*o).unwrap()).collect();
+        let pointers: Vec<_> = options.iter().map(|o| o.as_ptr()).collect();
+        let inbox = CString::new(inbox).unwrap();
+        let mut node = 0;
+        match unsafe {
+            ockam_node_create(
+                &mut node,
+                pointers.as_ptr(),
+                pointers.len() as u32,
+                inbox.as_ptr(),
+            )
+        } {
+            ERROR_NONE => Ok(node),
+            e => Err(e),
+        }
+    }
+
+    fn empty_message() -> FfiMessage {
+        FfiMessage {
+            message_type: u32::MAX,
+            channel: [0; ADDRESS_SIZE],
+            worker: [0; ADDRESS_SIZE],
+            length: 0,
+        }
+    }
+
+    #[test]
+    fn node_send_receive() {
+        let relay = ["--role", "relay", "--local-socket", "127.0.0.1:0"];
+        assert_eq!(
+            create(&["--role", "dancer"], "00a44a00"),
+            Err(ERROR_INVALID_PARAM)
+        );
+        assert_eq!(create(&relay, "not hex"), Err(ERROR_INVALID_PARAM));
+        let node = create(&relay, "00a44a00").unwrap();
+
+        // nothing is waiting
+        let mut message = empty_message();
+        let mut buffer = [0u8; 16];
+        let receive = |message: &mut FfiMessage, buffer: &mut [u8], timeout_ms| unsafe {
+            ockam_node_receive(
+                node,
+                timeout_ms,
+                message,
+                buffer.as_mut_ptr(),
+                buffer.len() as u32,
+            )
+        };
+        assert_eq!(receive(&mut message, &mut buffer, 0), ERROR_TIMEOUT);
+
+        // the application sends to its own inbox, with a payload too big for the first buffer
+        let inbox = CString::new("00a44a00").unwrap();
+        let payload = b"hello, ockam node";
+        assert_eq!(
+            unsafe {
+                ockam_node_send(
+                    node,
+                    ptr::null(),
+                    inbox.as_ptr(),
+                    payload.as_ptr(),
+                    payload.len() as u32,
+                )
+            },
+            ERROR_NONE
+        );
+        assert_eq!(
+            receive(&mut message, &mut buffer, 5000),
+            ERROR_BUFFER_TOO_SMALL
+        );
+        assert_eq!(message.length as usize, payload.len());
+        let mut buffer = vec![0u8; message.length as usize];
+        assert_eq!(receive(&mut message, &mut buffer, 0), ERROR_NONE);
+        assert_eq!(buffer, payload);
+        assert_eq!(message.message_type, MESSAGE_PAYLOAD);
+        assert_eq!(message.channel[0], 0);
+        let worker = unsafe { CStr::from_ptr(message.worker.as_ptr()) };
+        assert_eq!(worker.to_str().unwrap(), "00a44a00");
+
+        // a relay initiates no channels
+        let route = CString::new("udp://127.0.0.1:4050").unwrap();
+        assert_eq!(
+            unsafe { ockam_node_channel_initiate(node, route.as_ptr()) },
+            ERROR_CHANNEL
+        );
+        let route = CString::new("stdout").unwrap();
+        assert_eq!(
+            unsafe { ockam_node_channel_initiate(node, route.as_ptr()) },
+            ERROR_INVALID_PARAM
+        );
+
+        // the handle is gone once the node stops
+        assert_eq!(ockam_node_stop(node, 5000), ERROR_NONE);
+        assert_eq!(ockam_node_stop(node, 5000), ERROR_INVALID_HANDLE);
+        assert_eq!(receive(&mut message, &mut buffer, 0), ERROR_INVALID_HANDLE);
+    }
+}
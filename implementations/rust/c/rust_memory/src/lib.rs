@@ -53,6 +53,12 @@ impl RustAlloc {
     }
 }
 
+/// The ockam memory object backed by the Rust allocator, for C modules which take one, e.g. the
+/// ATECC608A vault.
+pub fn ockam_memory() -> *mut ockam_memory_t {
+    RustAlloc::new().as_mut_ptr()
+}
+
 unsafe extern "C" fn deinit_impl(_: *mut ockam_memory_t) -> ockam_error_t {
     ERROR_NONE
 }
@@ -0,0 +1,135 @@
+//! Compressing payloads before they are encrypted, for channels carrying large payloads which
+//! compress well, such as telemetry. A channel manager with compression turned on offers deflate
+//! in M1, and one answering accepts in M2 if it has it turned on too; either end then deflates
+//! the payloads larger than its threshold. Every payload starts with a byte telling how the rest
+//! is encoded, so the other end decodes it whatever was negotiated. Without the `compression`
+//! feature, payloads are never deflated, and deflated ones received can't be decoded.
+
+use crate::error::{ChannelError, ChannelErrorKind};
+
+// The first byte of each payload: how the encoded message which follows is compressed
+const RAW: u8 = 0;
+const DEFLATE: u8 = 1;
+
+/// Whether this build can compress payloads
+pub fn supported() -> bool {
+    cfg!(feature = "compression")
+}
+
+/// The body of the payload whose encoding is `encoded`, deflated if it is larger than
+/// `threshold` and deflating makes it smaller. With `None`, it is sent as it is.
+pub fn compress(encoded: &[u8], threshold: Option<usize>) -> Vec<u8> {
+    match threshold {
+        Some(threshold) if encoded.len() > threshold => match deflate(encoded) {
+            Some(deflated) if deflated.len() < encoded.len() => {
+                let mut body = Vec::with_capacity(1 + deflated.len());
+                body.push(DEFLATE);
+                body.extend_from_slice(&deflated);
+                body
+            }
+            _ => raw(encoded),
+        },
+        _ => raw(encoded),
+    }
+}
+
+/// The encoding of the payload whose body is `body`, failing with `MessageTooLarge` if it is
+/// longer than `max` bytes
+pub fn decompress(body: &[u8], max: usize) -> Result<Vec<u8>, ChannelError> {
+    match body.split_first() {
+        Some((&RAW, encoded)) if encoded.len() > max => {
+            Err(ChannelErrorKind::MessageTooLarge.into())
+        }
+        Some((&RAW, encoded)) => Ok(encoded.to_vec()),
+        Some((&DEFLATE, deflated)) => inflate(deflated, max),
+        _ => Err(ChannelErrorKind::RecvError.into()),
+    }
+}
+
+fn raw(encoded: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(1 + encoded.len());
+    body.push(RAW);
+    body.extend_from_slice(encoded);
+    body
+}
+
+#[cfg(feature = "compression")]
+fn deflate(encoded: &[u8]) -> Option<Vec<u8>> {
+    use std::io::Write;
+    let mut encoder =
+        flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(encoded).ok()?;
+    encoder.finish().ok()
+}
+
+#[cfg(not(feature = "compression"))]
+fn deflate(_encoded: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression")]
+fn inflate(deflated: &[u8], max: usize) -> Result<Vec<u8>, ChannelError> {
+    use std::io::Read;
+    let mut encoded = vec![];
+    // inflated no further than to tell that it is too large
+    flate2::read::DeflateDecoder::new(deflated)
+        .take(max as u64 + 1)
+        .read_to_end(&mut encoded)?;
+    if encoded.len() > max {
+        return Err(ChannelErrorKind::MessageTooLarge.into());
+    }
+    Ok(encoded)
+}
+
+#[cfg(not(feature = "compression"))]
+fn inflate(_deflated: &[u8], _max: usize) -> Result<Vec<u8>, ChannelError> {
+    Err(ChannelErrorKind::NotImplemented.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let telemetry = b"temperature=21.5,".repeat(64);
+        assert_eq!(compress(&telemetry, None)[0], RAW);
+        assert_eq!(compress(&telemetry, Some(telemetry.len()))[0], RAW);
+        let body = compress(&telemetry, Some(64));
+        if supported() {
+            assert_eq!(body[0], DEFLATE);
+            assert!(body.len() < telemetry.len() / 4);
+        }
+        assert_eq!(decompress(&body, telemetry.len()).unwrap(), telemetry);
+
+        // what deflating doesn't shrink is sent as it is
+        let random: Vec<u8> = (0..64u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        assert_eq!(compress(&random, Some(0))[0], RAW);
+        assert_eq!(decompress(&compress(&random, Some(0)), 64).unwrap(), random);
+        let too_large = decompress(&compress(&random, None), 63).unwrap_err();
+        assert!(matches!(
+            too_large.kind(),
+            ChannelErrorKind::MessageTooLarge
+        ));
+
+        assert!(decompress(&[], 64).is_err());
+        assert!(decompress(&[7, 1, 2], 64).is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn inflation_is_bounded() {
+        let zeros = vec![0u8; 4097];
+        let mut body = vec![DEFLATE];
+        body.extend(deflate(&zeros).unwrap());
+        assert!(body.len() < 64);
+        let too_large = decompress(&body, 4096).unwrap_err();
+        assert!(matches!(
+            too_large.kind(),
+            ChannelErrorKind::MessageTooLarge
+        ));
+        assert_eq!(decompress(&body, 4097).unwrap(), zeros);
+    }
+}
@@ -1,15 +1,15 @@
-use failure::{Backtrace, Context, Fail};
+use failure::Fail;
+use ockam_common::error::{ErrorKind, ERROR_INTERFACE_CHANNEL};
 use ockam_kex::error::*;
+use ockam_message::error::{MessageError, MessageErrorKind};
 use ockam_system::commands::{ChannelCommand, OckamCommand};
 use ockam_vault::error::*;
-use std::{
-    fmt,
-    sync::mpsc::{SendError, TryRecvError},
-};
+use std::sync::mpsc::{SendError, TryRecvError};
 
 /// Represents the failures that can occur in
 /// an Ockam Channel
 #[derive(Clone, Fail, Debug)]
+#[non_exhaustive]
 pub enum ChannelErrorKind {
     /// An invalid parameter was supplied
     #[fail(display = "An invalid parameter was supplied: {}", 0)]
@@ -29,80 +29,88 @@ pub enum ChannelErrorKind {
     /// Couldn't receive message
     #[fail(display = "Couldn't receive message")]
     RecvError,
+    /// An error occurred in the vault
+    #[fail(display = "An error occurred in the vault: {}", 0)]
+    Vault(VaultFailErrorKind),
+    /// A message couldn't be encoded or decoded
+    #[fail(display = "A message couldn't be encoded or decoded: {}", 0)]
+    Message(MessageErrorKind),
+    /// The channel has used every nonce its keys allow
+    #[fail(display = "The channel's nonces are exhausted")]
+    NonceExhausted,
+    /// A channel's saved state couldn't be encoded, decoded or resumed
+    #[fail(display = "A channel's saved state couldn't be encoded, decoded or resumed")]
+    BadChannelState,
+    /// A channel's key exchange hasn't completed, and it holds as many payloads as it may
+    #[fail(
+        display = "The channel holds as many payloads as it may until its key exchange completes"
+    )]
+    HeldFull,
+    /// No key exchanger for the cipher suite was given to the channel manager
+    #[fail(display = "The channel manager has no key exchanger for the cipher suite")]
+    UnknownCipherSuite,
+    /// A key exchange message went unanswered however many times it was sent
+    #[fail(display = "The key exchange timed out")]
+    HandshakeTimeout,
+    /// Every pair of addresses drawn for a new channel was already in use
+    #[fail(display = "No unused channel addresses were found")]
+    AddressesExhausted,
+    /// A message received, or the payload it carries, is larger than the channel manager
+    /// accepts
+    #[fail(display = "The message is larger than the channel manager accepts")]
+    MessageTooLarge,
 }
 
-impl ChannelErrorKind {
-    pub(crate) const ERROR_INTERFACE_CHANNEL: usize = 8 << 24;
-    /// Convert to an integer
-    pub fn to_usize(&self) -> usize {
-        match *self {
-            ChannelErrorKind::InvalidParam(_) => Self::ERROR_INTERFACE_CHANNEL | 1,
-            ChannelErrorKind::NotImplemented => Self::ERROR_INTERFACE_CHANNEL | 2,
-            ChannelErrorKind::KeyAgreement(_) => Self::ERROR_INTERFACE_CHANNEL | 3,
-            ChannelErrorKind::State => Self::ERROR_INTERFACE_CHANNEL | 4,
-            ChannelErrorKind::CantSend => Self::ERROR_INTERFACE_CHANNEL | 5,
-            ChannelErrorKind::RecvError => Self::ERROR_INTERFACE_CHANNEL | 6,
-        }
-    }
-}
+impl ErrorKind for ChannelErrorKind {
+    const ERROR_INTERFACE: usize = ERROR_INTERFACE_CHANNEL;
 
-/// Wraps an error kind with context and backtrace logic
-#[derive(Debug)]
-pub struct ChannelError {
-    inner: Context<ChannelErrorKind>,
-}
-
-impl ChannelError {
-    /// Convert from an error kind and a static string
-    pub fn from_msg<D>(kind: ChannelErrorKind, msg: D) -> Self
-    where
-        D: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
-    {
-        Self {
-            inner: Context::new(msg).context(kind),
-        }
-    }
-
-    /// Convert to an integer, reused in From trait implementations
     fn to_usize(&self) -> usize {
-        self.inner.get_context().to_usize()
-    }
-}
-
-impl From<ChannelErrorKind> for ChannelError {
-    fn from(kind: ChannelErrorKind) -> Self {
-        Self {
-            inner: Context::new("").context(kind),
+        match *self {
+            ChannelErrorKind::InvalidParam(_) => Self::ERROR_INTERFACE | 1,
+            ChannelErrorKind::NotImplemented => Self::ERROR_INTERFACE | 2,
+            ChannelErrorKind::KeyAgreement(_) => Self::ERROR_INTERFACE | 3,
+            ChannelErrorKind::State => Self::ERROR_INTERFACE | 4,
+            ChannelErrorKind::CantSend => Self::ERROR_INTERFACE | 5,
+            ChannelErrorKind::RecvError => Self::ERROR_INTERFACE | 6,
+            ChannelErrorKind::Vault(_) => Self::ERROR_INTERFACE | 7,
+            ChannelErrorKind::Message(_) => Self::ERROR_INTERFACE | 8,
+            ChannelErrorKind::NonceExhausted => Self::ERROR_INTERFACE | 9,
+            ChannelErrorKind::BadChannelState => Self::ERROR_INTERFACE | 10,
+            ChannelErrorKind::HeldFull => Self::ERROR_INTERFACE | 11,
+            ChannelErrorKind::UnknownCipherSuite => Self::ERROR_INTERFACE | 12,
+            ChannelErrorKind::HandshakeTimeout => Self::ERROR_INTERFACE | 13,
+            ChannelErrorKind::AddressesExhausted => Self::ERROR_INTERFACE | 14,
+            ChannelErrorKind::MessageTooLarge => Self::ERROR_INTERFACE | 15,
         }
     }
 }
 
-impl From<ChannelError> for ChannelErrorKind {
-    fn from(err: ChannelError) -> Self {
-        err.inner.get_context().clone()
-    }
-}
+error_impl!(ChannelError, ChannelErrorKind);
 
 impl From<VaultFailError> for ChannelError {
     fn from(err: VaultFailError) -> Self {
-        let kind: VaultFailErrorKind = err.into();
-        match kind {
-            VaultFailErrorKind::InvalidParam(p) => ChannelErrorKind::InvalidParam(p).into(),
-            _ => ChannelErrorKind::NotImplemented.into(),
-        }
+        let kind = *err.kind();
+        Self::from_source(ChannelErrorKind::Vault(kind), err)
     }
 }
 
 impl From<KexExchangeFailError> for ChannelError {
     fn from(err: KexExchangeFailError) -> Self {
-        let kind: KeyExchangeFailErrorKind = err.into();
-        ChannelErrorKind::KeyAgreement(kind).into()
+        let kind = err.kind().clone();
+        Self::from_source(ChannelErrorKind::KeyAgreement(kind), err)
+    }
+}
+
+impl From<MessageError> for ChannelError {
+    fn from(err: MessageError) -> Self {
+        let kind = *err.kind();
+        Self::from_source(ChannelErrorKind::Message(kind), err)
     }
 }
 
 impl From<std::io::Error> for ChannelError {
-    fn from(_: std::io::Error) -> Self {
-        ChannelErrorKind::State.into()
+    fn from(err: std::io::Error) -> Self {
+        Self::from_source(ChannelErrorKind::State, err)
     }
 }
 
@@ -123,36 +131,3 @@ impl From<TryRecvError> for ChannelError {
         ChannelErrorKind::RecvError.into()
     }
 }
-
-from_int_impl!(ChannelError, u32);
-from_int_impl!(ChannelError, u64);
-from_int_impl!(ChannelError, u128);
-from_int_impl!(ChannelErrorKind, u32);
-from_int_impl!(ChannelErrorKind, u64);
-from_int_impl!(ChannelErrorKind, u128);
-
-impl Fail for ChannelError {
-    fn cause(&self) -> Option<&dyn Fail> {
-        self.inner.cause()
-    }
-
-    fn backtrace(&self) -> Option<&Backtrace> {
-        self.inner.backtrace()
-    }
-}
-
-impl fmt::Display for ChannelError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut first = true;
-
-        for cause in Fail::iter_chain(&self.inner) {
-            if first {
-                first = false;
-                writeln!(f, "Error: {}", cause)?;
-            } else {
-                writeln!(f, "Caused by: {}", cause)?;
-            }
-        }
-        Ok(())
-    }
-}
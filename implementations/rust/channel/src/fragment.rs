@@ -0,0 +1,152 @@
+//! Splitting payloads too large for one frame into fragments, and putting them back together.
+//! Each fragment is encrypted as a frame of its own and sent as a `MessageType::Fragment`, the
+//! fragments of a payload taking consecutive nonces on its stream, so that once the reorder
+//! buffer has put them back in order a payload is whole when its fragments' nonces follow on
+//! from each other, from its first fragment to its last. A payload missing any of its fragments
+//! is dropped. The nonces here are sequence numbers on a stream, see `stream`.
+
+// The first byte of each fragment's plaintext: whether it starts and whether it ends a payload
+const FIRST: u8 = 1;
+const LAST: u8 = 2;
+
+/// Split the encoded payload `plaintext` into the plaintexts of fragments of at most `mtu`
+/// bytes, each a flags byte followed by a piece of the payload. `mtu` must be at least 2.
+pub fn split(plaintext: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+    let pieces: Vec<&[u8]> = plaintext.chunks(mtu - 1).collect();
+    let last = pieces.len() - 1;
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(i, piece)| {
+            let mut flags = 0;
+            if i == 0 {
+                flags |= FIRST;
+            }
+            if i == last {
+                flags |= LAST;
+            }
+            let mut fragment = Vec::with_capacity(1 + piece.len());
+            fragment.push(flags);
+            fragment.extend_from_slice(piece);
+            fragment
+        })
+        .collect()
+}
+
+/// The fragments of a payload received so far on one stream of a channel
+#[derive(Debug, Default)]
+pub struct Reassembly {
+    payload: Vec<u8>,
+    // the nonce of the fragment which follows on from those received, if a payload is started
+    next: Option<u64>,
+    dropped: u64,
+}
+
+impl Reassembly {
+    /// Accept the fragment with `nonce`, fragments being accepted in nonce order, returning the
+    /// encoded payload once its last fragment is accepted. A fragment which doesn't follow on
+    /// from those before it drops the payload they started, and is itself dropped unless it
+    /// starts another. A payload which grows longer than `max` bytes is dropped as its
+    /// fragments arrive, so that it is never held whole.
+    pub fn receive(&mut self, nonce: u64, fragment: &[u8], max: usize) -> Option<Vec<u8>> {
+        let (flags, piece) = match fragment.split_first() {
+            Some((flags, piece)) => (*flags, piece),
+            None => return None,
+        };
+        if flags & FIRST != 0 {
+            self.give_up();
+        } else if self.next != Some(nonce) {
+            self.give_up();
+            return None;
+        }
+        if self.payload.len() + piece.len() > max {
+            self.give_up();
+            return None;
+        }
+        self.payload.extend_from_slice(piece);
+        if flags & LAST != 0 {
+            self.next = None;
+            return Some(std::mem::take(&mut self.payload));
+        }
+        self.next = nonce.checked_add(1);
+        None
+    }
+
+    /// Drop the payload started, as a channel does when it agrees new keys
+    pub fn reset(&mut self) {
+        self.give_up();
+    }
+
+    /// The payloads dropped because some of their fragments never arrived
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // Drop the payload started, if any.
+    fn give_up(&mut self) {
+        if self.next.take().is_some() {
+            self.dropped += 1;
+        }
+        self.payload.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_and_reassemble() {
+        let payload: Vec<u8> = (0..10).collect();
+        let fragments = split(&payload, 4);
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|f| f.len() <= 4));
+
+        let mut r = Reassembly::default();
+        for (nonce, f) in fragments[..3].iter().enumerate() {
+            assert_eq!(r.receive(nonce as u64 + 5, f, 16), None);
+        }
+        assert_eq!(r.receive(8, &fragments[3], 16), Some(payload));
+
+        // a payload which fits one fragment is both its first and last
+        assert_eq!(split(&[7], 4), vec![vec![FIRST | LAST, 7]]);
+        assert_eq!(r.receive(9, &[FIRST | LAST, 7], 16), Some(vec![7]));
+        assert_eq!(r.dropped(), 0);
+    }
+
+    #[test]
+    fn missing_fragment() {
+        let payload: Vec<u8> = (0..10).collect();
+        let fragments = split(&payload, 4);
+        let mut r = Reassembly::default();
+        r.receive(0, &fragments[0], 16);
+        // nonce 1 was lost
+        assert_eq!(r.receive(2, &fragments[2], 16), None);
+        assert_eq!(r.receive(3, &fragments[3], 16), None);
+        assert_eq!(r.dropped(), 1);
+
+        // a new payload starts over
+        r.receive(4, &fragments[0], 16);
+        r.reset();
+        assert_eq!(r.dropped(), 2);
+        for (nonce, f) in fragments.iter().enumerate() {
+            r.receive(nonce as u64 + 10, f, 16);
+        }
+        assert_eq!(r.dropped(), 2);
+    }
+
+    #[test]
+    fn payload_too_large() {
+        let payload: Vec<u8> = (0..10).collect();
+        let fragments = split(&payload, 4);
+        let mut r = Reassembly::default();
+        for (nonce, f) in fragments.iter().enumerate() {
+            assert_eq!(r.receive(nonce as u64, f, 9), None);
+        }
+        assert_eq!(r.dropped(), 1);
+        for (nonce, f) in fragments.iter().enumerate() {
+            r.receive(nonce as u64 + 4, f, 10);
+        }
+        assert_eq!(r.dropped(), 1);
+    }
+}
@@ -0,0 +1,94 @@
+//! Where a channel manager takes its commands from. Polled, it takes those already sent on the
+//! channel it was created with. Run as a future, with the `async` feature, it waits on a tokio
+//! channel instead, which a thread of its own fills from the first as commands arrive, so that
+//! the router and workers send to it as they always do.
+
+#[cfg(feature = "async")]
+use crate::{error::ChannelError, ChannelManager};
+use ockam_system::commands::OckamCommand;
+use std::sync::mpsc::Receiver;
+#[cfg(feature = "async")]
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+pub(crate) enum Inbox {
+    Polled(Receiver<OckamCommand>),
+    #[cfg(feature = "async")]
+    Awaited(UnboundedReceiver<OckamCommand>),
+}
+
+impl Inbox {
+    // The next command already sent, if any
+    pub(crate) fn try_recv(&mut self) -> Option<OckamCommand> {
+        match self {
+            Inbox::Polled(rx) => rx.try_recv().ok(),
+            #[cfg(feature = "async")]
+            Inbox::Awaited(rx) => rx.try_recv().ok(),
+        }
+    }
+
+    // The tokio channel the commands arrive on, forwarded to it from then on if they were
+    // polled for until now
+    #[cfg(feature = "async")]
+    fn awaited(&mut self) -> Result<&mut UnboundedReceiver<OckamCommand>, ChannelError> {
+        if let Inbox::Polled(rx) = self {
+            let (tx, awaited) = unbounded_channel();
+            let rx = std::mem::replace(rx, std::sync::mpsc::channel().1);
+            // ends once every sender has gone, or at the first command after the manager has
+            std::thread::Builder::new()
+                .name("channel-inbox".into())
+                .spawn(move || {
+                    for c in rx {
+                        if tx.send(c).is_err() {
+                            break;
+                        }
+                    }
+                })?;
+            *self = Inbox::Awaited(awaited);
+        }
+        match self {
+            Inbox::Awaited(rx) => Ok(rx),
+            Inbox::Polled(_) => unreachable!(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl ChannelManager {
+    /// Run the channel manager until it is sent a Stop, as `poll` would be run in a loop, but
+    /// sleeping until it is sent a command or the next of its channels' timers is due rather
+    /// than being polled for them. Needs a tokio runtime with its timers enabled. Commands are
+    /// sent on the channel the manager was created with, as they are to a polled one.
+    pub async fn run(&mut self) -> Result<(), ChannelError> {
+        loop {
+            while let Some(c) = self.rx.try_recv() {
+                if !self.handle_command(c)? {
+                    return Ok(());
+                }
+            }
+            self.tick()?;
+
+            let due = self.next_expiry();
+            let rx = self.rx.awaited()?;
+            let c = match due {
+                Some(at) => {
+                    let at = tokio::time::Instant::from_std(at);
+                    match tokio::time::timeout_at(at, rx.recv()).await {
+                        Ok(c) => c,
+                        // a timer is due
+                        Err(_) => continue,
+                    }
+                }
+                None => rx.recv().await,
+            };
+            match c {
+                Some(c) => {
+                    if !self.handle_command(c)? {
+                        return Ok(());
+                    }
+                }
+                // no command can be sent any more
+                None => return Ok(()),
+            }
+        }
+    }
+}
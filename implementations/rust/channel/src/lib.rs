@@ -19,62 +19,200 @@
 //! a C FFI version.
 //!
 //! Channels are where parties can send messages securely
+//!
+//! The channel manager never blocks or spawns threads, taking its commands with `try_recv`
+//! as it is polled, so it also runs on a single thread compiled to WebAssembly. With the
+//! `async` feature, it can instead be run as a future on a tokio runtime, which sleeps until it
+//! is sent a command or one of its timers is due.
 
 #![cfg_attr(feature = "nightly", feature(doc_cfg))]
 
 #[macro_use]
 extern crate ockam_common;
 
-use core::marker::PhantomData;
 use error::*;
+use fragment::Reassembly;
+use inbox::Inbox;
+use ockam_common::error::ErrorKind;
+use ockam_identity::Identity;
+#[cfg(test)]
+use ockam_kex::Aead;
 use ockam_kex::{CompletedKeyExchange, KeyExchanger, NewKeyExchanger};
 use ockam_message::message::{
     Address, AddressType, Codec, Message, MessageType, Route, RouterAddress,
 };
 use ockam_system::commands::OckamCommand::Router;
 use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand};
-use ockam_vault::types::{PublicKey, SecretKeyContext};
+use ockam_vault::types::{PublicKey, SecretKeyContext, SecretPersistenceType};
 use ockam_vault::DynVault;
+use protocol::{Phase, ReceiveStep, Role, SendStep};
 use rand::{thread_rng, Rng};
+use reorder::{Reorder, ReorderStats};
+use state::ChannelState;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     sync::{
         mpsc::{Receiver, Sender},
         Arc, Mutex,
     },
+    time::{Duration, Instant, SystemTime},
 };
+use stream::Sending;
 
 /// A channel address of zero indicates to the channel manager that
 /// a new channel is being initiated
 pub static CHANNEL_ZERO: &str = "00000000";
 
-enum ExchangerRole {
-    Initiator,
-    Responder,
+/// A summary of one channel, as reported by [`ChannelManager::list_channels`]
+#[derive(Debug, Clone)]
+pub struct ChannelInfo {
+    /// The cleartext address that workers send payloads to
+    pub address: String,
+    /// The end of the key exchange this node was
+    pub role: Role,
+    /// Whether the key exchange has completed
+    pub established: bool,
+    /// When the channel's keys were agreed, or it was resumed with them, if they have been
+    pub established_at: Option<SystemTime>,
+    /// The route to the remote end of the channel
+    pub route: Route,
+    /// The remote party's static public key, once the key exchange has completed
+    pub remote_public_key: Option<Vec<u8>>,
+    /// The number of payloads held until the key exchange completes
+    pub held: usize,
+    /// Whether both ends agreed to deflate payloads
+    pub compress: bool,
+    /// Whether both ends agreed to acknowledge frames and send those unacknowledged again
+    pub reliable: bool,
+    /// The frames sent which the other end hasn't acknowledged yet
+    pub unacked: usize,
+    /// The counts of received payloads which weren't delivered as they arrived
+    pub reorder: ReorderStats,
+    /// Payloads dropped because some of their fragments never arrived
+    pub incomplete: u64,
+    /// The nonce of the next payload or control message sent
+    pub send_nonce: u64,
+    /// One past the highest nonce received from the other end, which counts its own
+    pub recv_nonce: u64,
+    /// The lowest sequence number which may still be received, on each stream received on
+    pub next_received: BTreeMap<u32, u64>,
+}
+
+/// What a channel has carried since it was created, as reported by `ChannelManager::stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Bytes of payload encrypted and sent, as encoded before encryption
+    pub bytes_encrypted: u64,
+    /// Bytes of payload received and decrypted
+    pub bytes_decrypted: u64,
+    /// Payloads sent
+    pub messages_sent: u64,
+    /// Payloads received
+    pub messages_received: u64,
+    /// The times the key exchange was re-run to agree new keys
+    pub rekeys: u64,
+    /// How long the last key exchange took, once it has completed
+    pub handshake_duration: Option<Duration>,
+    /// Payloads dropped because the channel already held as many as it may until its key
+    /// exchange completed
+    pub held_dropped: u64,
+    /// Frames sent again because the other end of a reliable channel didn't acknowledge them
+    pub retransmitted: u64,
+}
+
+/// The payloads a channel holds until its key exchange completes, by default
+pub const DEFAULT_MAX_HELD: usize = 64;
+
+/// The key exchanges a channel manager responds to at a time, by default
+pub const DEFAULT_MAX_HALF_OPEN: usize = 256;
+
+/// The largest message a channel manager accepts, and the largest payload it delivers, in
+/// bytes, by default
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long a key exchange a channel manager responds to may take, by default
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The pairs of random addresses drawn for a new channel before giving up, should each collide
+// with a channel's
+const ADDRESS_ATTEMPTS: usize = 8;
+
+/// How long a key exchange message goes unanswered before it is sent again, by default
+pub const DEFAULT_RETRANSMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The times a key exchange message is sent again before the key exchange fails, by default
+pub const DEFAULT_RETRANSMITS: u32 = 5;
+
+/// Decides which remote parties may complete a channel with this node. Any
+/// `Fn(Role, &[u8]) -> bool` is a trust policy.
+pub trait TrustPolicy: Send {
+    /// Whether to complete a channel with the party holding this static public key, `role`
+    /// being the end of the key exchange this node is
+    fn is_trusted(&self, role: Role, remote_static_public_key: &[u8]) -> bool;
+}
+
+impl<F: Fn(Role, &[u8]) -> bool + Send> TrustPolicy for F {
+    fn is_trusted(&self, role: Role, remote_static_public_key: &[u8]) -> bool {
+        self(role, remote_static_public_key)
+    }
+}
+
+/// Decides which key exchanges a node responds to, before any work is done for them: an M1
+/// that isn't accepted is dropped without creating a channel or touching the vault. Any
+/// `Fn(&Route) -> bool` is a listener.
+pub trait ChannelListener: Send {
+    /// Whether to respond to the M1 which came back along `route`, its return route, the first
+    /// address of which is the transport address it arrived from
+    fn accept(&self, route: &Route) -> bool;
+}
+
+impl<F: Fn(&Route) -> bool + Send> ChannelListener for F {
+    fn accept(&self, route: &Route) -> bool {
+        self(route)
+    }
 }
 
-/// A Channel Manager creates secure channels on demand using the specified key exchange
-/// generic. All keys will be created in the associated vault object
-pub struct ChannelManager<
-    I: KeyExchanger + 'static,
-    R: KeyExchanger + 'static,
-    E: NewKeyExchanger<I, R>,
-> {
+/// A Channel Manager creates secure channels on demand using the key exchangers it is given,
+/// picking the one for each M1 it receives by the cipher suite id the M1 carries. All keys will
+/// be created in the associated vault object
+pub struct ChannelManager {
     channels: BTreeMap<String, Arc<Mutex<Channel>>>,
-    rx: Receiver<OckamCommand>,
+    rx: Inbox,
     tx: Sender<OckamCommand>,
     router_tx: Sender<OckamCommand>,
     vault: Arc<Mutex<dyn DynVault + Send>>,
-    new_key_exchanger: E,
-    phantom_i: PhantomData<I>,
-    phantom_r: PhantomData<R>,
-    resp_key_ctx: Option<SecretKeyContext>,
+    // the key exchangers channels may use, by cipher suite id
+    key_exchangers: BTreeMap<u8, Box<dyn NewKeyExchanger + Send>>,
+    // the cipher suite of the channels this node initiates
+    cipher_suite_id: u8,
+    resp_identity: Option<Identity>,
+    init_identity: Option<Identity>,
+    // the static key of the channels being initiated, the initiator identity's unless the
+    // last Initiate command named another
     init_key_ctx: Option<SecretKeyContext>,
+    trust_policy: Option<Box<dyn TrustPolicy>>,
+    // the static public keys of the initiators channels this node responds to may be completed
+    // with, if they are restricted
+    allowed_initiators: Option<BTreeSet<Vec<u8>>>,
+    listener: Option<Box<dyn ChannelListener>>,
+    idle_timeout: Option<Duration>,
+    reorder_window: u64,
+    reorder_delay: Duration,
+    heartbeat: Option<Duration>,
+    missed_heartbeats: u32,
+    max_held: usize,
+    mtu: Option<usize>,
+    compression: Option<usize>,
+    reliable: bool,
+    max_message_size: usize,
+    max_half_open: usize,
+    handshake_timeout: Duration,
+    rejected_handshakes: u64,
+    retransmit_interval: Duration,
+    max_retransmits: u32,
 }
 
-impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> std::fmt::Debug
-    for ChannelManager<I, R, E>
-{
+impl std::fmt::Debug for ChannelManager {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
@@ -84,16 +222,19 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> std::fmt::Debug
     }
 }
 
-impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<I, R, E> {
-    /// Create a new Channel Manager
+impl ChannelManager {
+    /// Create a new Channel Manager, which initiates channels with `new_key_exchanger` and
+    /// responds to them with it or any added by `add_key_exchanger`. Channels it responds to are
+    /// established with the static key of `resp_identity`, and those it initiates with that of
+    /// `init_identity`; without an identity, each channel gets a new static key.
     pub fn new(
         rx: Receiver<OckamCommand>,
         tx: Sender<OckamCommand>,
         router_tx: Sender<OckamCommand>,
         vault: Arc<Mutex<dyn DynVault + Send>>,
-        new_key_exchanger: E,
-        resp_key_ctx: Option<SecretKeyContext>,
-        init_key_ctx: Option<SecretKeyContext>,
+        new_key_exchanger: Box<dyn NewKeyExchanger + Send>,
+        resp_identity: Option<Identity>,
+        init_identity: Option<Identity>,
     ) -> Result<Self, ChannelError> {
         // register ChannelManager with the router as the handler for all Channel address types
         if let Err(_error) = router_tx.send(Router(RouterCommand::Register(
@@ -104,149 +245,1269 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             return Err(ChannelErrorKind::CantSend.into());
         }
 
+        let cipher_suite_id = new_key_exchanger.cipher_suite_id();
+        let mut key_exchangers = BTreeMap::new();
+        key_exchangers.insert(cipher_suite_id, new_key_exchanger);
+
         Ok(Self {
             channels: BTreeMap::new(),
             tx,
-            rx,
+            rx: Inbox::Polled(rx),
             router_tx,
             vault,
-            new_key_exchanger,
-            phantom_i: PhantomData,
-            phantom_r: PhantomData,
-            resp_key_ctx,
-            init_key_ctx,
+            key_exchangers,
+            cipher_suite_id,
+            init_key_ctx: init_identity.as_ref().map(Identity::key),
+            resp_identity,
+            init_identity,
+            trust_policy: None,
+            allowed_initiators: None,
+            listener: None,
+            idle_timeout: None,
+            reorder_window: reorder::DEFAULT_WINDOW,
+            reorder_delay: reorder::DEFAULT_MAX_DELAY,
+            heartbeat: None,
+            missed_heartbeats: 3,
+            max_held: DEFAULT_MAX_HELD,
+            mtu: None,
+            compression: None,
+            reliable: false,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_half_open: DEFAULT_MAX_HALF_OPEN,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            rejected_handshakes: 0,
+            retransmit_interval: DEFAULT_RETRANSMIT_INTERVAL,
+            max_retransmits: DEFAULT_RETRANSMITS,
+        })
+    }
+
+    /// Also respond to key exchanges with the cipher suite of `new_key_exchanger`, replacing the
+    /// key exchanger for that cipher suite if there was one. Channels this node initiates keep
+    /// the cipher suite of the key exchanger given to `new`.
+    pub fn add_key_exchanger(&mut self, new_key_exchanger: Box<dyn NewKeyExchanger + Send>) {
+        self.key_exchangers
+            .insert(new_key_exchanger.cipher_suite_id(), new_key_exchanger);
+    }
+
+    /// Only complete channels with remote parties that `policy` trusts, whichever end of the
+    /// key exchange this node is. Channels with any other party are closed once the key
+    /// exchange reveals its static public key, before they are established: a responder tells
+    /// the initiator, and an initiator doesn't send its last message but tells its worker.
+    pub fn set_trust_policy(&mut self, policy: Box<dyn TrustPolicy>) {
+        self.trust_policy = Some(policy);
+    }
+
+    /// Only complete the channels this node responds to with initiators holding one of `keys`,
+    /// as well as the trust policy trusts. An initiator with any other static public key is
+    /// refused once M3 reveals it, as the trust policy refuses it: its channel is closed and
+    /// the keys the key exchange derived are destroyed. With `None`, the default, any initiator
+    /// the trust policy trusts completes its channel.
+    pub fn set_allowed_initiators(&mut self, keys: Option<BTreeSet<Vec<u8>>>) {
+        self.allowed_initiators = keys;
+    }
+
+    /// Only respond to the key exchanges that `listener` accepts. Without one, every M1 sent to
+    /// channel address zero opens a channel.
+    pub fn set_listener(&mut self, listener: Box<dyn ChannelListener>) {
+        self.listener = Some(listener);
+    }
+
+    /// Close channels which have neither sent nor received anything for `timeout`, as
+    /// `close_channel` does; channels still in their key exchange are dropped. Heartbeats
+    /// count as activity. With `None`, channels are kept until closed.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Deliver the payloads each channel receives in the order they were sent, holding those
+    /// which arrive up to `window` nonces early for at most `max_delay`. Payloads which arrive
+    /// later than that are dropped. Each worker sending into a channel does so on a stream of
+    /// its own, ordered on its own, so a payload lost on one holds back only those which follow
+    /// it from the same worker. Defaults to a window of 64 and 200ms.
+    pub fn set_reorder_window(&mut self, window: u64, max_delay: Duration) {
+        self.reorder_window = window;
+        self.reorder_delay = max_delay;
+    }
+
+    /// Hold at most `limit` payloads sent into each channel before its key exchange completes,
+    /// first or re-run, sending them once it does. Sending another fails with `HeldFull`, and
+    /// the payload is dropped. Defaults to 64.
+    pub fn set_max_held(&mut self, limit: usize) {
+        self.max_held = limit;
+    }
+
+    /// Acknowledge the frames received on channels whose other end turns reliability on too,
+    /// which is offered in M1 and accepted in M2, and send the frames the other end doesn't
+    /// acknowledge again, with a new nonce, at the interval and up to the attempts set by
+    /// `set_retransmit`; a channel with a frame unacknowledged after that many is closed. The
+    /// receiving end waits for missing frames rather than giving them up as lost, unless they
+    /// fall behind its reorder window. Frames not acknowledged when the channel agrees new keys
+    /// or is suspended are lost. Defaults to off.
+    pub fn set_reliable(&mut self, reliable: bool) {
+        self.reliable = reliable;
+    }
+
+    /// Accept messages of at most `max` bytes, and deliver payloads of at most `max` bytes as
+    /// encoded, for devices which can't hold more. A larger message is dropped before it is
+    /// decrypted, a fragmented payload as soon as its fragments add up to more, and a deflated
+    /// one as soon as it inflates to more; the channel carries on. The other end isn't told,
+    /// so it should be given an MTU below `max`. Defaults to 16 MiB.
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max;
+    }
+
+    /// Respond to at most `limit` key exchanges at a time, dropping those which haven't
+    /// completed within `timeout` to make room for others. An M1 which arrives while `limit`
+    /// are under way is dropped before any work is done for it, and counted by
+    /// `rejected_handshakes`, so that a party sending M1s can't make the manager hold more.
+    /// Defaults to 256 and 10 seconds.
+    pub fn set_half_open_limit(&mut self, limit: usize, timeout: Duration) {
+        self.max_half_open = limit;
+        self.handshake_timeout = timeout;
+    }
+
+    /// The M1s dropped because as many key exchanges as the manager responds to at a time were
+    /// under way
+    pub fn rejected_handshakes(&self) -> u64 {
+        self.rejected_handshakes
+    }
+
+    /// Send a key exchange message again each `interval` it goes unanswered, as UDP may lose
+    /// it, up to `attempts` times, after which the key exchange fails and the channel is
+    /// dropped. The worker which initiated it is sent a `MessageType::ChannelError` whose body
+    /// is the code of `ChannelErrorKind::HandshakeTimeout`, a little-endian u32. Defaults to 1
+    /// second and 5 attempts.
+    pub fn set_retransmit(&mut self, interval: Duration, attempts: u32) {
+        self.retransmit_interval = interval;
+        self.max_retransmits = attempts;
+    }
+
+    /// Split payloads whose encoding is larger than `mtu` bytes into fragments of at most that
+    /// many, each encrypted and sent on its own, for transports that can't carry large
+    /// messages; the other end puts them back together before delivering the payload. With
+    /// `None`, the default, every payload is sent whole. An `mtu` below 14 is taken as 14, the
+    /// stream header and flags byte each fragment starts with, and one byte of payload.
+    pub fn set_mtu(&mut self, mtu: Option<usize>) {
+        self.mtu = mtu.map(|mtu| mtu.max(stream::HEADER_LEN + 2));
+    }
+
+    /// Deflate payloads whose encoding is larger than `threshold` bytes before encrypting them,
+    /// on channels whose other end turns compression on too, which is offered in M1 and
+    /// accepted in M2. A payload which deflating doesn't shrink is sent as it is. With `None`,
+    /// the default, or without the `compression` feature, compression isn't offered or
+    /// accepted; compressed payloads received are decoded either way, if the feature is on.
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.compression = threshold;
+    }
+
+    /// Send an encrypted Ping on each established channel which hasn't heard from the other end
+    /// for `interval`, answered with a Pong by the remote channel manager for as long as it holds
+    /// the other end. A channel which hears nothing for `missed` intervals is closed, as
+    /// `close_channel` does, so its worker is sent a `MessageType::Close`. With `None`, the
+    /// default, only the Pings that workers send are sent.
+    pub fn set_heartbeat(&mut self, interval: Option<Duration>, missed: u32) {
+        self.heartbeat = interval;
+        self.missed_heartbeats = missed.max(1);
+    }
+
+    /// When `poll` next has work of its own to do: a channel which will have been idle for the
+    /// idle timeout, held payloads to give up waiting for, a heartbeat due, or a key exchange
+    /// message to send again or give up on
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.channels
+            .values()
+            .filter_map(|channel| {
+                let channel = channel.lock().unwrap();
+                let idle = self
+                    .idle_timeout
+                    .map(|timeout| channel.last_active + timeout);
+                let held = channel
+                    .streams
+                    .values()
+                    .filter_map(|s| s.reorder.next_expiry(self.reorder_delay))
+                    .min()
+                    .filter(|_| !channel.reliable);
+                let unacked = channel.unacked.values().map(|u| u.retransmit_at).min();
+                let heartbeat = match self.heartbeat {
+                    Some(interval) if channel.completed_key_exchange.is_some() => Some(
+                        (channel.last_heard + interval * self.missed_heartbeats)
+                            .min(channel.last_heard.max(channel.last_ping) + interval),
+                    ),
+                    _ => None,
+                };
+                let handshake = Some(channel.handshake_started + self.handshake_timeout)
+                    .filter(|_| channel.is_half_open());
+                let retransmit = Some(channel.retransmit_at).filter(|_| {
+                    channel.phase != Phase::Established && channel.last_key_exchange.is_some()
+                });
+                idle.into_iter()
+                    .chain(held)
+                    .chain(heartbeat)
+                    .chain(handshake)
+                    .chain(retransmit)
+                    .chain(unacked)
+                    .min()
+            })
+            .min()
+    }
+
+    /// What the channel at either of its addresses has carried, or `None` if there is no such
+    /// channel. Heartbeats and other control messages aren't counted.
+    pub fn stats(&self, address: &str) -> Option<ChannelStats> {
+        self.channels
+            .get(address)
+            .map(|channel| channel.lock().unwrap().stats)
+    }
+
+    /// List the channels this manager holds
+    pub fn list_channels(&self) -> Vec<ChannelInfo> {
+        self.channels
+            .iter()
+            .filter_map(|(address, channel)| {
+                let channel = channel.lock().unwrap();
+                // every channel is stored under both of its addresses, only report it once
+                if *address != channel.as_cleartext_address().as_string() {
+                    return None;
+                }
+                Some(channel.info())
+            })
+            .collect()
+    }
+
+    /// The channel at either of its addresses, or `None` if there is no such channel
+    pub fn channel_info(&self, address: &str) -> Option<ChannelInfo> {
+        self.channels
+            .get(address)
+            .map(|channel| channel.lock().unwrap().info())
+    }
+
+    /// Close a channel, given either of its addresses: the other end is sent an encrypted
+    /// close, so that it closes its end too, the keys the key exchange derived are destroyed in
+    /// the vault, and the worker which owns the channel is sent a `MessageType::Close` from its
+    /// cleartext address. Any held payloads are dropped. Returns false if there is no such
+    /// channel.
+    pub fn close_channel(&mut self, address: &str) -> bool {
+        self.close(address, true)
+    }
+
+    /// Close every channel, as `close_channel` does, once the payloads each holds back waiting
+    /// for those sent before them have been delivered, giving up on the missing ones. Channels
+    /// still in their key exchange are dropped. This is what `ChannelCommand::Stop` does; the
+    /// closes are sent through the router, so it must still be running to route them.
+    pub fn stop(&mut self) {
+        let addresses: Vec<String> = self
+            .each_channel()
+            .map(|channel| channel.lock().unwrap().as_cleartext_address().as_string())
+            .collect();
+        for address in addresses {
+            if let Some(channel) = self.channels.get(&address) {
+                let mut channel = channel.lock().unwrap();
+                if let Err(e) = self.flush(&mut channel, Duration::from_secs(0)) {
+                    tracing::debug!(error = ?e, "failed to deliver held payloads");
+                }
+            }
+            self.close(&address, true);
+        }
+    }
+
+    /// Take every established channel out of the manager, returning their state for `resume`
+    /// once the node restarts. Their keys are made persistent in the vault, so that a vault
+    /// which persists keys, as the filesystem vault does, still holds them then. Channels still
+    /// in their key exchange, and those whose keys can't be made persistent, are dropped. The
+    /// other ends aren't told, and keep their channels open for the suspended ones to resume.
+    pub fn suspend(&mut self) -> Vec<ChannelState> {
+        let channels: Vec<Arc<Mutex<Channel>>> = self.each_channel().cloned().collect();
+        self.channels.clear();
+
+        let mut vault = self.vault.lock().unwrap();
+        let mut states = vec![];
+        for channel in channels {
+            let channel = channel.lock().unwrap();
+            let mut cke = match channel.completed_key_exchange {
+                Some(cke) => cke,
+                None => continue,
+            };
+            let persisted = set_persistence(
+                &mut *vault,
+                &mut cke.encrypt_key,
+                SecretPersistenceType::Persistent,
+            )
+            .and_then(|_| {
+                set_persistence(
+                    &mut *vault,
+                    &mut cke.decrypt_key,
+                    SecretPersistenceType::Persistent,
+                )
+            });
+            if let Err(e) = persisted {
+                tracing::debug!(error = ?e, "failed to persist channel keys, dropping channel");
+                continue;
+            }
+            states.push(ChannelState {
+                cleartext_address: channel.cleartext_address,
+                ciphertext_address: channel.ciphertext_address,
+                role: channel.role,
+                cipher_suite_id: channel.cipher_suite_id,
+                route: channel.route.clone(),
+                initiate_route: channel.initiate_route.clone(),
+                owner: channel.pending.as_ref().map(|p| p.onward_route.clone()),
+                send_nonce: channel.send_nonce,
+                recv_nonce: channel.recv_nonce,
+                compress: channel.compress,
+                reliable: channel.reliable,
+                sending: channel.sending.clone(),
+                received: channel
+                    .streams
+                    .iter()
+                    .map(|(id, s)| (*id, s.reorder.next()))
+                    .collect(),
+                completed_key_exchange: cke,
+            });
+        }
+        states
+    }
+
+    /// Resume a channel suspended before the node restarted, with the keys its state names in
+    /// the vault, which are made ephemeral again. The worker which owns the channel is told of
+    /// it as if its key exchange had just completed: an initiator's worker from the channel's
+    /// cleartext address, and a responder's those listening for new channels. Fails if either
+    /// address is already in use, the vault doesn't hold the keys, or the manager has no key
+    /// exchanger for the channel's cipher suite to re-run its key exchange with.
+    pub fn resume(&mut self, state: ChannelState) -> Result<(), ChannelError> {
+        let clear_address = Address::ChannelAddress(state.cleartext_address.to_le_bytes().to_vec());
+        let cipher_address =
+            Address::ChannelAddress(state.ciphertext_address.to_le_bytes().to_vec());
+        if self.channels.contains_key(&clear_address.as_string())
+            || self.channels.contains_key(&cipher_address.as_string())
+        {
+            return Err(ChannelErrorKind::BadChannelState.into());
+        }
+        if !self.key_exchangers.contains_key(&state.cipher_suite_id) {
+            return Err(ChannelErrorKind::UnknownCipherSuite.into());
+        }
+
+        let mut cke = state.completed_key_exchange;
+        {
+            let mut vault = self.vault.lock().unwrap();
+            set_persistence(
+                &mut *vault,
+                &mut cke.encrypt_key,
+                SecretPersistenceType::Ephemeral,
+            )?;
+            set_persistence(
+                &mut *vault,
+                &mut cke.decrypt_key,
+                SecretPersistenceType::Ephemeral,
+            )?;
+        }
+
+        let agreement = self.new_agreement(state.role, state.cipher_suite_id);
+        let mut channel = Channel::new(
+            state.cleartext_address,
+            state.ciphertext_address,
+            state.role,
+            state.cipher_suite_id,
+            agreement,
+        );
+        channel.phase = Phase::Established;
+        channel.completed_key_exchange = Some(cke);
+        channel.established_at = Some(SystemTime::now());
+        channel.route = state.route;
+        channel.initiate_route = state.initiate_route;
+        channel.send_nonce = state.send_nonce;
+        channel.recv_nonce = state.recv_nonce;
+        channel.compress = state.compress;
+        channel.reliable = state.reliable;
+        channel.sending = state.sending;
+        channel.streams = state
+            .received
+            .into_iter()
+            .map(|(id, next)| {
+                let reorder = Reorder::starting_at(next);
+                let reassembly = Reassembly::default();
+                (
+                    id,
+                    Stream {
+                        reorder,
+                        reassembly,
+                    },
+                )
+            })
+            .collect();
+        channel.pending = state.owner.map(|owner| Message {
+            onward_route: owner,
+            return_route: Route {
+                addresses: vec![RouterAddress::from_address(clear_address.clone()).unwrap()],
+            },
+            message_type: MessageType::None,
+            message_body: vec![],
+        });
+        tracing::debug!(channel = %cipher_address.as_string(), "resumed channel");
+
+        // announce the channel as a completed key exchange does
+        let remote_public_key = cke.remote_static_public_key.as_ref().to_vec();
+        let announcement = match &channel.pending {
+            Some(pending) => Message {
+                message_body: remote_public_key,
+                ..pending.clone()
+            },
+            None => {
+                let mut return_route = channel.route.clone();
+                return_route.addresses.insert(
+                    0,
+                    RouterAddress::from_address(clear_address.clone()).unwrap(),
+                );
+                Message {
+                    onward_route: Route {
+                        addresses: vec![RouterAddress::worker_router_address_from_str(
+                            CHANNEL_ZERO,
+                        )
+                        .unwrap()],
+                    },
+                    return_route,
+                    message_type: MessageType::None,
+                    message_body: remote_public_key,
+                }
+            }
+        };
+        self.insert(channel);
+        self.router_tx
+            .send(Router(RouterCommand::ReceiveMessage(announcement)))?;
+        Ok(())
+    }
+
+    // Close a channel, telling the other end unless it is the one which closed it.
+    fn close(&mut self, address: &str, notify_peer: bool) -> bool {
+        let channel = match self.channels.get(address) {
+            Some(channel) => channel.clone(),
+            None => return false,
+        };
+        let channel = channel.lock().unwrap();
+        self.channels
+            .remove(&channel.as_cleartext_address().as_string());
+        self.channels
+            .remove(&channel.as_ciphertext_address().as_string());
+
+        // a channel still in its key exchange has no keys, and its worker doesn't know of it
+        let cke = match channel.completed_key_exchange {
+            Some(cke) => cke,
+            None => return true,
+        };
+        self.destroy_keys(&channel, &cke, notify_peer);
+        self.notify_owner(&channel);
+        true
+    }
+
+    // Close a channel whose key exchange has just completed with a party the trust policy
+    // doesn't trust. Only an initiator's worker knows of the channel, and only a responder's
+    // peer has keys to hear the close with.
+    fn refuse(&mut self, channel: &Channel, cke: &CompletedKeyExchange) {
+        tracing::debug!(role = ?channel.role, "remote party is not trusted, closing channel");
+        self.channels
+            .remove(&channel.as_cleartext_address().as_string());
+        self.channels
+            .remove(&channel.as_ciphertext_address().as_string());
+        self.destroy_keys(channel, cke, channel.role == Role::Responder);
+        if channel.role == Role::Initiator {
+            self.notify_owner(channel);
+        }
+    }
+
+    // Drop a channel whose key exchange failed, sending the worker which initiated it a
+    // `MessageType::ChannelError` with the code of `kind`.
+    fn fail_key_exchange(&mut self, address: &str, kind: ChannelErrorKind) {
+        let channel = match self.channels.get(address) {
+            Some(channel) => channel.clone(),
+            None => return,
+        };
+        let channel = channel.lock().unwrap();
+        tracing::debug!(channel = %address, error = %kind, "key exchange failed, dropping channel");
+        self.channels
+            .remove(&channel.as_cleartext_address().as_string());
+        self.channels
+            .remove(&channel.as_ciphertext_address().as_string());
+        // only an initiator's worker is waiting for the channel
+        if let Some(pending) = &channel.pending {
+            let worker = pending.onward_route.clone();
+            self.send_channel_error(channel.as_cleartext_address(), worker, &kind);
+        }
+    }
+
+    // Send `worker` a `MessageType::ChannelError` from `from` with the code of `kind`.
+    fn send_channel_error(&self, from: Address, worker: Route, kind: &ChannelErrorKind) {
+        let failed = protocol::message_from(
+            from,
+            worker,
+            MessageType::ChannelError,
+            kind.code().to_le_bytes().to_vec(),
+        );
+        let _ = self
+            .router_tx
+            .send(Router(RouterCommand::ReceiveMessage(failed)));
+    }
+
+    // Every channel once, as each is stored under both its ciphertext and cleartext addresses.
+    fn each_channel(&self) -> impl Iterator<Item = &Arc<Mutex<Channel>>> {
+        self.channels.iter().filter_map(|(address, channel)| {
+            let ciphertext = channel.lock().unwrap().as_ciphertext_address().as_string();
+            if *address == ciphertext {
+                Some(channel)
+            } else {
+                None
+            }
         })
     }
 
+    // Destroy the keys of a channel being closed, first telling the other end with them if
+    // `notify_peer`.
+    fn destroy_keys(&self, channel: &Channel, cke: &CompletedKeyExchange, notify_peer: bool) {
+        let mut vault = self.vault.lock().unwrap();
+        if notify_peer {
+            let close = stream::frame(0, channel.sending.peek(0), &[]);
+            match encrypt_payload(&mut *vault, cke, channel.send_nonce, &close) {
+                Ok(body) => {
+                    let m = protocol::message_from(
+                        channel.as_ciphertext_address(),
+                        channel.route.clone(),
+                        MessageType::Close,
+                        body,
+                    );
+                    let _ = self.router_tx.send(Router(RouterCommand::SendMessage(m)));
+                }
+                Err(e) => tracing::debug!(error = ?e, "failed to encrypt close"),
+            }
+        }
+        for key in &[cke.encrypt_key, cke.decrypt_key] {
+            if let Err(e) = vault.secret_destroy(*key) {
+                tracing::debug!(error = ?e, "failed to destroy channel key");
+            }
+        }
+    }
+
+    // Tell the worker which owns a channel that it has closed.
+    fn notify_owner(&self, channel: &Channel) {
+        // an initiator's worker is the one it announced the channel to, a responder's those
+        // listening for new channels
+        let owner = match &channel.pending {
+            Some(pending) => pending.onward_route.clone(),
+            None => Route {
+                addresses: vec![
+                    RouterAddress::worker_router_address_from_str(CHANNEL_ZERO).unwrap()
+                ],
+            },
+        };
+        let closed = protocol::message_from(
+            channel.as_cleartext_address(),
+            owner,
+            MessageType::Close,
+            vec![],
+        );
+        let _ = self
+            .router_tx
+            .send(Router(RouterCommand::ReceiveMessage(closed)));
+    }
+
     /// Check for work to be done and do it
     pub fn poll(&mut self) -> Result<bool, ChannelError> {
         let keep_going = true;
-        let mut got_message = true;
-        while got_message {
-            match self.rx.try_recv() {
-                Ok(c) => match c {
-                    OckamCommand::Channel(ChannelCommand::Initiate(
-                        mut route,
-                        return_address,
-                        key,
-                    )) => {
-                        if let AddressType::Channel = route.addresses[0].a_type {
-                            if route.addresses[0].address.as_string() == *CHANNEL_ZERO {
-                                route.addresses.remove(0);
-                            }
-                        }
-                        self.init_key_ctx = key;
-                        self.initiate_new_channel(route, return_address)?;
+        while let Some(c) = self.rx.try_recv() {
+            if !self.handle_command(c)? {
+                break;
+            }
+        }
+        self.tick()?;
+        Ok(keep_going)
+    }
+
+    // Carry out a command, returning false if it stopped the channel manager.
+    fn handle_command(&mut self, c: OckamCommand) -> Result<bool, ChannelError> {
+        match c {
+            OckamCommand::Channel(ChannelCommand::Initiate(
+                mut route,
+                return_address,
+                key,
+                payload,
+            )) => {
+                if let AddressType::Channel = route.addresses[0].a_type {
+                    if route.addresses[0].address.as_string() == *CHANNEL_ZERO {
+                        route.addresses.remove(0);
                     }
-                    OckamCommand::Channel(ChannelCommand::Stop) => {
-                        self.channels.clear();
-                        break;
+                }
+                self.init_key_ctx = key.or_else(|| self.init_identity.as_ref().map(Identity::key));
+                match self.initiate_new_channel(route, return_address, payload) {
+                    // the worker has been told, and other channels carry on
+                    Err(e) if matches!(e.kind(), ChannelErrorKind::AddressesExhausted) => {
+                        tracing::debug!("no unused channel addresses, not initiating");
                     }
-                    OckamCommand::Channel(ChannelCommand::SendMessage(m)) => {
-                        self.handle_send(m)?;
+                    result => {
+                        result?;
                     }
-                    OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)) => {
-                        self.handle_recv(m)?;
+                }
+            }
+            OckamCommand::Channel(ChannelCommand::Stop) => {
+                self.stop();
+                return Ok(false);
+            }
+            OckamCommand::Channel(ChannelCommand::TransportReconnected(peer)) => {
+                self.handle_transport_reconnected(peer)?;
+            }
+            OckamCommand::Channel(ChannelCommand::Close(address)) => {
+                self.close_channel(&address.as_string());
+            }
+            OckamCommand::Channel(ChannelCommand::SendMessage(m)) => {
+                match self.handle_send(m) {
+                    // only the payload is lost, the channel carries on
+                    Err(e) if matches!(e.kind(), ChannelErrorKind::HeldFull) => {
+                        tracing::debug!("channel holds too many payloads, dropped one");
                     }
-                    _ => return Err(ChannelErrorKind::InvalidParam(0).into()),
-                },
-                Err(_) => {
-                    got_message = false;
+                    result => result?,
+                }
+            }
+            OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)) => {
+                match self.handle_recv(m) {
+                    // only the message is lost, the channel carries on
+                    Err(e) if matches!(e.kind(), ChannelErrorKind::MessageTooLarge) => {
+                        tracing::debug!("message larger than the channel accepts, dropped");
+                    }
+                    result => result?,
                 }
             }
+            _ => return Err(ChannelErrorKind::InvalidParam(0).into()),
         }
-        Ok(keep_going)
+        Ok(true)
+    }
+
+    // Do the work which falls due with time rather than commands.
+    fn tick(&mut self) -> Result<(), ChannelError> {
+        self.expire_held()?;
+        self.heartbeat();
+        self.expire_idle();
+        self.expire_half_open();
+        self.retransmit();
+        self.retransmit_frames();
+        Ok(())
+    }
+
+    // Ping the channels which haven't heard from the other end for a heartbeat interval, and
+    // close those which haven't for the number of intervals that may be missed.
+    fn heartbeat(&mut self) {
+        let interval = match self.heartbeat {
+            Some(interval) => interval,
+            None => return,
+        };
+        let mut dead = vec![];
+        for channel in self.each_channel() {
+            let mut channel = channel.lock().unwrap();
+            if channel.completed_key_exchange.is_none() {
+                continue;
+            }
+            if channel.last_heard.elapsed() > interval * self.missed_heartbeats {
+                dead.push(channel.as_cleartext_address().as_string());
+            } else if channel.last_heard.elapsed() >= interval
+                && channel.last_ping.elapsed() >= interval
+            {
+                channel.last_ping = Instant::now();
+                let route = channel.route.clone();
+                if let Err(e) = self.send_control(&mut channel, route, MessageType::Ping) {
+                    tracing::debug!(error = ?e, "failed to send heartbeat");
+                }
+            }
+        }
+        for address in dead {
+            tracing::debug!(channel = %address, "other end stopped answering heartbeats");
+            self.close_channel(&address);
+        }
+    }
+
+    // Deliver the payloads which have waited too long for those sent before them.
+    fn expire_held(&mut self) -> Result<(), ChannelError> {
+        for channel in self.each_channel() {
+            let mut channel = channel.lock().unwrap();
+            // a reliable channel waits for what is missing to be sent again
+            if channel.reliable {
+                continue;
+            }
+            self.flush(&mut channel, self.reorder_delay)?;
+        }
+        Ok(())
+    }
+
+    // Deliver the payloads on each of the channel's streams which have waited for longer than
+    // `max_delay` for those sent before them.
+    fn flush(&self, channel: &mut Channel, max_delay: Duration) -> Result<(), ChannelError> {
+        let ids: Vec<u32> = channel.streams.keys().copied().collect();
+        for id in ids {
+            let due = channel
+                .streams
+                .get_mut(&id)
+                .unwrap()
+                .reorder
+                .expire(max_delay);
+            self.release(channel, id, due)?;
+        }
+        Ok(())
+    }
+
+    // Close the channels which have been idle for longer than the idle timeout.
+    fn expire_idle(&mut self) {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let idle: Vec<String> = self
+            .channels
+            .iter()
+            .filter_map(|(address, channel)| {
+                let channel = channel.lock().unwrap();
+                // every channel is stored under both of its addresses, only close it once
+                if *address != channel.as_cleartext_address().as_string()
+                    || channel.last_active.elapsed() < timeout
+                {
+                    return None;
+                }
+                Some(address.clone())
+            })
+            .collect();
+        for address in idle {
+            tracing::debug!(channel = %address, "closing idle channel");
+            self.close_channel(&address);
+        }
+    }
+
+    // Send the key exchange messages which have gone unanswered for the retransmit interval
+    // again, and fail the key exchanges whose messages have been sent as often as they may be.
+    fn retransmit(&mut self) {
+        let mut failed = vec![];
+        for channel in self.each_channel() {
+            let mut channel = channel.lock().unwrap();
+            if channel.phase == Phase::Established || Instant::now() < channel.retransmit_at {
+                continue;
+            }
+            let address = channel.as_cleartext_address().as_string();
+            let m = match &channel.last_key_exchange {
+                Some(m) => m.clone(),
+                None => continue,
+            };
+            if channel.retransmits >= self.max_retransmits {
+                failed.push(address.clone());
+                continue;
+            }
+            channel.retransmits += 1;
+            channel.retransmit_at = Instant::now() + self.retransmit_interval;
+            tracing::debug!(
+                channel = %address,
+                attempt = channel.retransmits,
+                "key exchange message unanswered, sending it again"
+            );
+            if let Err(e) = self.router_tx.send(Router(RouterCommand::SendMessage(m))) {
+                tracing::debug!(error = ?e, "failed to retransmit key exchange message");
+            }
+        }
+        for address in failed {
+            self.fail_key_exchange(&address, ChannelErrorKind::HandshakeTimeout);
+        }
+    }
+
+    // Send a key exchange message, keeping it to send again should it go unanswered.
+    fn send_key_exchange(&self, channel: &mut Channel, m: Message) -> Result<(), ChannelError> {
+        channel.last_key_exchange = Some(m.clone());
+        channel.retransmits = 0;
+        channel.retransmit_at = Instant::now() + self.retransmit_interval;
+        self.router_tx.send(Router(RouterCommand::SendMessage(m)))?;
+        Ok(())
+    }
+
+    // Drop the key exchanges this node responded to which haven't completed in time.
+    fn expire_half_open(&mut self) {
+        let expired: Vec<String> = self
+            .channels
+            .iter()
+            .filter_map(|(address, channel)| {
+                let channel = channel.lock().unwrap();
+                // every channel is stored under both of its addresses, only drop it once
+                if *address != channel.as_cleartext_address().as_string()
+                    || !channel.is_half_open()
+                    || channel.handshake_started.elapsed() < self.handshake_timeout
+                {
+                    return None;
+                }
+                Some(address.clone())
+            })
+            .collect();
+        for address in expired {
+            tracing::debug!(channel = %address, "key exchange timed out, dropping channel");
+            self.close(&address, false);
+        }
+    }
+
+    // The key exchanges this node is responding to.
+    fn half_open(&self) -> usize {
+        self.channels
+            .values()
+            .filter(|channel| channel.lock().unwrap().is_half_open())
+            .count()
+            // every channel is stored under both of its addresses
+            / 2
     }
 
     fn handle_send(&mut self, mut m: Message) -> Result<(), ChannelError> {
         if m.onward_route.addresses.is_empty() {
             return Err(ChannelErrorKind::CantSend.into());
         }
-        match m.message_type {
-            MessageType::Payload => {
-                let address = m.onward_route.addresses[0].address.as_string();
-                match self.channels.get_mut(&address) {
-                    Some(channel) => {
-                        let mut channel = channel.lock().unwrap();
+        let address = m.onward_route.addresses[0].address.as_string();
+        let channel = match self.channels.get(&address).cloned() {
+            Some(channel) => channel,
+            // a keepalive for a channel which has since been closed
+            None if m.message_type == MessageType::Ping => return Ok(()),
+            None => return Err(ChannelErrorKind::NotImplemented.into()),
+        };
+        let mut channel = channel.lock().unwrap();
+        channel.last_active = Instant::now();
+        let to_channel = m.onward_route.addresses.len() == 1;
+        match protocol::send(channel.phase, m.message_type, to_channel)? {
+            SendStep::Encrypt => {
+                // remove this channel's address
+                m.onward_route.addresses.remove(0);
+                self.encrypt_and_send(&mut channel, m)
+            }
+            SendStep::Hold => {
+                if channel.held.len() >= self.max_held {
+                    channel.stats.held_dropped += 1;
+                    return Err(ChannelErrorKind::HeldFull.into());
+                }
+                m.onward_route.addresses.remove(0);
+                channel.held.push(m);
+                Ok(())
+            }
+            SendStep::Ping => {
+                // a heartbeat asked for by the worker, which is sent a Pong once it is answered
+                channel.last_ping = Instant::now();
+                let route = channel.route.clone();
+                self.send_control(&mut channel, route, MessageType::Ping)
+            }
+            SendStep::Drop => Ok(()),
+        }
+    }
 
-                        if !channel.agreement.is_complete() {
-                            debug_assert!(channel.completed_key_exchange.is_none());
-                            return Ok(());
-                        }
+    // Send the payloads held while the channel's key exchange ran, now that it has completed.
+    fn send_held(&self, channel: &mut Channel) -> Result<(), ChannelError> {
+        let held: Vec<Message> = channel.held.drain(..).collect();
+        for h in held {
+            self.encrypt_and_send(channel, h)?;
+        }
+        Ok(())
+    }
 
-                        // remove this channel's address, encode message
-                        m.onward_route.addresses.remove(0);
-                        let mut m_encoded: Vec<u8> = vec![];
+    fn encrypt_and_send(&self, channel: &mut Channel, m: Message) -> Result<(), ChannelError> {
+        let span = tracing::debug_span!(
+            "channel_encrypt",
+            channel = %channel.as_ciphertext_address().as_string(),
+            id = %m.trace_id()
+        );
+        let _enter = span.enter();
+        let mut m_encoded: Vec<u8> = vec![];
+        Message::encode(&m, &mut m_encoded)?;
+        let threshold = self.compression.filter(|_| channel.compress);
+        let m_encoded = compress::compress(&m_encoded, threshold);
 
-                        if Message::encode(&m, &mut m_encoded).is_err() {
-                            return Err(ChannelErrorKind::CantSend.into());
-                        }
+        debug_assert!(channel.completed_key_exchange.is_some());
 
-                        debug_assert!(channel.completed_key_exchange.is_some());
-                        let cke = channel.completed_key_exchange.as_ref().unwrap();
-                        let mut vault = self.vault.lock().unwrap();
+        // each worker sends on a stream of its own
+        let sender = m.return_route.addresses.first();
+        let stream_id = channel
+            .sending
+            .stream_of(sender.map(|a| a.address.as_string()));
+        let (message_type, frames) = match self.mtu {
+            Some(mtu) if stream::HEADER_LEN + m_encoded.len() > mtu => (
+                MessageType::Fragment,
+                fragment::split(&m_encoded, mtu - stream::HEADER_LEN),
+            ),
+            _ => (MessageType::Payload, vec![m_encoded]),
+        };
+        // an exhausted channel refuses to send rather than reuse a nonce with the same keys, and
+        // doesn't start a payload it can't finish
+        if channel.send_nonce.saturating_add(frames.len() as u64 - 1) == u64::MAX {
+            return Err(ChannelErrorKind::NonceExhausted.into());
+        }
+        channel.stats.messages_sent += 1;
+        for frame in frames {
+            let seq = channel.sending.take(stream_id);
+            let route = channel.route.clone();
+            channel.stats.bytes_encrypted += frame.len() as u64;
+            self.send_frame(channel, route, message_type, stream_id, seq, frame)?;
+        }
+        Ok(())
+    }
 
-                        let mut new_message_body: Vec<u8> = vec![];
-                        if let Err(e) = u16::encode(&channel.nonce, &mut new_message_body)
-                            .map_err(|e| ChannelError::from_msg(ChannelErrorKind::CantSend, e))
-                        {
-                            return Err(e);
+    // Send an encrypted control message with an empty body, e.g. a heartbeat, along `route`, on
+    // stream 0.
+    fn send_control(
+        &self,
+        channel: &mut Channel,
+        route: Route,
+        message_type: MessageType,
+    ) -> Result<(), ChannelError> {
+        let seq = channel.sending.take(0);
+        self.send_frame(channel, route, message_type, 0, seq, vec![])
+    }
+
+    // Send frame `seq` of stream `stream_id`, carrying `body`, along `route` as a message of
+    // `message_type`. A reliable channel keeps the frame until the other end acknowledges it.
+    fn send_frame(
+        &self,
+        channel: &mut Channel,
+        route: Route,
+        message_type: MessageType,
+        stream_id: u32,
+        seq: u64,
+        body: Vec<u8>,
+    ) -> Result<(), ChannelError> {
+        let plaintext = stream::frame(stream_id, seq, &body);
+        self.seal_and_send(channel, route, message_type, &plaintext)?;
+        tracing::debug!(stream = stream_id, seq, "encrypted");
+        if channel.reliable {
+            let unacked = Unacked {
+                message_type,
+                body,
+                retransmits: 0,
+                retransmit_at: Instant::now() + self.retransmit_interval,
+            };
+            channel.unacked.insert((stream_id, seq), unacked);
+        }
+        Ok(())
+    }
+
+    // Encrypt `plaintext` with the channel's next nonce and send it along `route` as a message
+    // of `message_type`.
+    fn seal_and_send(
+        &self,
+        channel: &mut Channel,
+        route: Route,
+        message_type: MessageType,
+        plaintext: &[u8],
+    ) -> Result<(), ChannelError> {
+        if channel.send_nonce == u64::MAX {
+            return Err(ChannelErrorKind::NonceExhausted.into());
+        }
+        let cke = channel.completed_key_exchange.unwrap();
+        let body = encrypt_payload(
+            &mut *self.vault.lock().unwrap(),
+            &cke,
+            channel.send_nonce,
+            plaintext,
+        )?;
+        channel.send_nonce += 1;
+        let m = protocol::message_from(channel.as_ciphertext_address(), route, message_type, body);
+        tracing::debug!(ciphertext_id = %m.trace_id(), nonce = channel.send_nonce - 1, "sealed");
+        self.router_tx.send(Router(RouterCommand::SendMessage(m)))?;
+        Ok(())
+    }
+
+    // Tell the other end of a reliable channel which frames of a stream have been received: all
+    // those before the next due, whose sequence number the ack carries in a frame header.
+    fn send_ack(&self, channel: &mut Channel, stream_id: u32) -> Result<(), ChannelError> {
+        let next = channel
+            .streams
+            .get(&stream_id)
+            .map_or(0, |s| s.reorder.next());
+        let route = channel.route.clone();
+        let ack = stream::frame(stream_id, next, &[]);
+        self.seal_and_send(channel, route, MessageType::Ack, &ack)
+    }
+
+    // Send the frames of reliable channels which the other end hasn't acknowledged within the
+    // retransmit interval again, with new nonces, and close the channels with a frame sent as
+    // often as it may be.
+    fn retransmit_frames(&mut self) {
+        let mut dead = vec![];
+        for channel in self.each_channel() {
+            let mut channel = channel.lock().unwrap();
+            if channel.completed_key_exchange.is_none() {
+                continue;
+            }
+            let address = channel.as_cleartext_address().as_string();
+            let now = Instant::now();
+            let due: Vec<(u32, u64)> = channel
+                .unacked
+                .iter()
+                .filter(|(_, unacked)| unacked.retransmit_at <= now)
+                .map(|(frame, _)| *frame)
+                .collect();
+            for (stream_id, seq) in due {
+                let unacked = channel.unacked.get_mut(&(stream_id, seq)).unwrap();
+                if unacked.retransmits >= self.max_retransmits {
+                    dead.push(address.clone());
+                    break;
+                }
+                unacked.retransmits += 1;
+                unacked.retransmit_at = now + self.retransmit_interval;
+                let attempt = unacked.retransmits;
+                let message_type = unacked.message_type;
+                let plaintext = stream::frame(stream_id, seq, &unacked.body);
+                tracing::debug!(
+                    channel = %address,
+                    stream = stream_id,
+                    seq,
+                    attempt,
+                    "frame unacknowledged, sending it again"
+                );
+                let route = channel.route.clone();
+                if let Err(e) = self.seal_and_send(&mut channel, route, message_type, &plaintext) {
+                    tracing::debug!(error = ?e, "failed to retransmit frame");
+                }
+                channel.stats.retransmitted += 1;
+            }
+        }
+        for address in dead {
+            tracing::debug!(channel = %address, "other end stopped acknowledging frames");
+            self.close_channel(&address);
+        }
+    }
+
+    // Decrypt the body of a control message, returning false if it wasn't sent by the other end.
+    fn open_control(&self, channel: &mut Channel, m: &Message) -> Result<bool, ChannelError> {
+        let cke = channel.completed_key_exchange.unwrap();
+        let decrypted = decrypt_payload(&mut *self.vault.lock().unwrap(), &cke, &m.message_body);
+        match decrypted {
+            Ok((nonce, plaintext)) => match stream::unframe(&plaintext) {
+                Some((stream_id, seq, _)) => {
+                    channel.received(nonce);
+                    self.deliver(channel, stream_id, seq, Received::Control)?;
+                    Ok(true)
+                }
+                None => {
+                    tracing::debug!("control message has no stream header, dropping");
+                    Ok(false)
+                }
+            },
+            Err(_) => {
+                tracing::debug!("control message doesn't decrypt, dropping");
+                Ok(false)
+            }
+        }
+    }
+
+    // Pass what the other end sent as frame `seq` of a stream through the stream's reorder
+    // window, delivering the payloads then due; a control message only takes up its sequence
+    // number. A frame which would open a stream beyond the most received on is dropped.
+    fn deliver(
+        &self,
+        channel: &mut Channel,
+        stream_id: u32,
+        seq: u64,
+        received: Received,
+    ) -> Result<(), ChannelError> {
+        channel.last_heard = Instant::now();
+        if !channel.streams.contains_key(&stream_id) && channel.streams.len() >= stream::MAX_STREAMS
+        {
+            tracing::debug!(stream = stream_id, "too many streams, dropping");
+            return Ok(());
+        }
+        let due = channel
+            .streams
+            .entry(stream_id)
+            .or_default()
+            .reorder
+            .receive(seq, received, self.reorder_window);
+        // acknowledged even if it was already received, as the last ack may have been lost
+        if channel.reliable {
+            if let Err(e) = self.send_ack(channel, stream_id) {
+                tracing::debug!(error = ?e, "failed to acknowledge frame");
+            }
+        }
+        self.release(channel, stream_id, due)
+    }
+
+    // Deliver what a stream's reorder window has put back in order, reassembling fragmented
+    // payloads.
+    fn release(
+        &self,
+        channel: &mut Channel,
+        stream_id: u32,
+        due: Vec<Received>,
+    ) -> Result<(), ChannelError> {
+        for received in due {
+            let m = match received {
+                Received::Payload(m) => m,
+                Received::Fragment(seq, fragment) => {
+                    let stream = channel.streams.get_mut(&stream_id);
+                    let max = self.max_message_size;
+                    let reassembled =
+                        stream.and_then(|s| s.reassembly.receive(seq, &fragment, max));
+                    let plaintext = match reassembled {
+                        Some(plaintext) => plaintext,
+                        None => continue,
+                    };
+                    let address = channel.as_cleartext_address();
+                    match protocol::delivered(&plaintext, address, self.max_message_size) {
+                        Ok(m) => {
+                            channel.stats.messages_received += 1;
+                            m
+                        }
+                        Err(e) => {
+                            tracing::debug!(error = ?e, "reassembled payload doesn't decode");
+                            continue;
                         }
+                    }
+                }
+                Received::Control => continue,
+            };
+            self.router_tx
+                .send(Router(RouterCommand::ReceiveMessage(m)))?;
+        }
+        Ok(())
+    }
+
+    /// A transport regained connectivity to `peer`. Channels this node initiated over
+    /// that hop re-run the key exchange on the new connection, keeping their cleartext
+    /// address so the owning worker keeps sending to the same place. The other end is sent
+    /// a Close for the old channel under its old keys first, so it doesn't linger there.
+    /// Responder channels are re-established by their initiator.
+    fn handle_transport_reconnected(&mut self, peer: RouterAddress) -> Result<(), ChannelError> {
+        let affected: Vec<Arc<Mutex<Channel>>> = self
+            .each_channel()
+            .filter(|channel| {
+                let channel = channel.lock().unwrap();
+                matches!(channel.role, Role::Initiator)
+                    && channel.completed_key_exchange.is_some()
+                    && channel.route.addresses.first() == Some(&peer)
+            })
+            .cloned()
+            .collect();
+
+        for channel in affected {
+            let mut channel = channel.lock().unwrap();
+            let address = channel.as_ciphertext_address().as_string();
+            channel.agreement = self.new_agreement(Role::Initiator, channel.cipher_suite_id);
+            // the keys of the old connection are replaced by those the exchange agrees
+            if let Some(cke) = channel.completed_key_exchange.take() {
+                self.destroy_keys(&channel, &cke, true);
+            }
+            channel.established_at = None;
+            channel.phase = Phase::start(Role::Initiator);
+            channel.send_nonce = 0;
+            channel.recv_nonce = 0;
+            for stream in channel.streams.values_mut() {
+                stream.reorder.reset();
+                stream.reassembly.reset();
+            }
+            channel.sending.reset();
+            // what the other end hadn't acknowledged is sent again under the new keys, ahead of
+            // anything sent meanwhile
+            let unacked = std::mem::take(&mut channel.unacked);
+            let mut held = self.unacked_payloads(unacked);
+            held.append(&mut channel.held);
+            channel.held = held;
+            channel.stats.rekeys += 1;
+            channel.handshake_started = Instant::now();
+            tracing::debug!(
+                channel = %address,
+                peer = %peer.address.as_string(),
+                "transport reconnected, re-running key exchange"
+            );
+
+            let sent = self.send_m1(&mut channel);
+            drop(channel);
+            if let Err(e) = sent {
+                self.fail_key_exchange(&address, e.kind().clone());
+            }
+        }
+        Ok(())
+    }
 
-                        let nonce = Channel::nonce_16_to_96(channel.nonce);
-                        let mut ciphertext_and_tag = vault.aead_aes_gcm_encrypt(
-                            cke.encrypt_key,
-                            &m_encoded,
-                            &nonce,
-                            &cke.h,
-                        )?;
-                        channel.nonce += 1;
-                        //TODO: check if key rotation needs to happen
-
-                        new_message_body.append(&mut ciphertext_and_tag);
-                        let new_m = Message {
-                            onward_route: channel.route.clone(),
-                            return_route: Route {
-                                addresses: vec![RouterAddress::from_address(
-                                    channel.as_ciphertext_address(),
-                                )
-                                .unwrap()],
-                            },
-                            message_type: MessageType::Payload,
-                            message_body: new_message_body,
-                        };
-                        self.router_tx
-                            .send(Router(RouterCommand::SendMessage(new_m)))?;
-                        Ok(())
+    // The payloads carried by the frames of a reliable channel which the other end hasn't
+    // acknowledged, in the order they were sent. A fragmented payload whose first fragments
+    // were acknowledged can't be put back together, and is lost.
+    fn unacked_payloads(&self, unacked: BTreeMap<(u32, u64), Unacked>) -> Vec<Message> {
+        let mut payloads = vec![];
+        let mut reassembly: Option<(u32, Reassembly)> = None;
+        for ((stream_id, seq), frame) in unacked {
+            let body = match frame.message_type {
+                MessageType::Payload => frame.body,
+                MessageType::Fragment => {
+                    if reassembly
+                        .as_ref()
+                        .map(|(s, _)| *s != stream_id)
+                        .unwrap_or(true)
+                    {
+                        reassembly = Some((stream_id, Reassembly::default()));
+                    }
+                    let (_, r) = reassembly.as_mut().unwrap();
+                    match r.receive(seq, &frame.body, self.max_message_size) {
+                        Some(body) => body,
+                        None => continue,
                     }
-                    None => Err(ChannelErrorKind::NotImplemented.into()),
                 }
+                _ => continue,
+            };
+            let decoded = compress::decompress(&body, self.max_message_size)
+                .and_then(|encoded| Ok(Message::decode(&encoded)?.0));
+            match decoded {
+                Ok(m) => payloads.push(m),
+                Err(e) => tracing::debug!(error = ?e, "unacknowledged payload doesn't decode"),
             }
-            _ => Err(ChannelErrorKind::NotImplemented.into()),
         }
+        payloads
+    }
+
+    // The options this manager offers in M1, and accepts in M2
+    fn options(&self) -> u8 {
+        let mut options = 0;
+        if compress::supported() && self.compression.is_some() {
+            options |= protocol::OPTION_DEFLATE;
+        }
+        if self.reliable {
+            options |= protocol::OPTION_RELIABLE;
+        }
+        options
+    }
+
+    // Start the channel's key exchange by sending M1 along the route it was initiated over.
+    fn send_m1(&self, channel: &mut Channel) -> Result<(), ChannelError> {
+        let ka_m1 = channel.agreement.process(&[])?;
+        let m = protocol::message_from(
+            channel.as_ciphertext_address(),
+            channel.initiate_route.clone(),
+            MessageType::KeyAgreementM1,
+            protocol::m1_body(channel.cipher_suite_id, self.options(), &ka_m1),
+        );
+        tracing::debug!(id = %m.trace_id(), "sending key exchange M1");
+        self.send_key_exchange(channel, m)
     }
 
     /// Initiates key exchange to create new secure channel over supplied route.
     /// Upon completion of key exchange, a message is sent to return_address with
-    /// MessageType::None and the channel address in the return route.
+    /// MessageType::None and the channel address in the return route, or with
+    /// MessageType::ChannelError should it fail. A `payload`, whose
+    /// onward route is that beyond the channel, is encrypted and sent right after M3, before
+    /// the worker hears that the channel is established.
     fn initiate_new_channel(
         &mut self,
         mut route: Route,
         return_address: Address,
+        payload: Option<Message>,
     ) -> Result<Address, ChannelError> {
         // Remember who to notify when the channel is secure
         let pending_return = RouterAddress::from_address(return_address).unwrap();
 
         // Generate 2 channel addresses, one each for clear and cipher text
-        let mut clear_address = String::from(CHANNEL_ZERO);
-        let mut cipher_address = String::from(CHANNEL_ZERO);
-        if let Some((clear, cipher)) = self.create_channel(ExchangerRole::Initiator) {
-            clear_address = clear;
-            cipher_address = cipher;
-        }
+        let (clear_address, cipher_address) =
+            match self.create_channel(Role::Initiator, self.cipher_suite_id) {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    let zero = Address::channel_address_from_string(CHANNEL_ZERO).unwrap();
+                    let worker = Route {
+                        addresses: vec![pending_return],
+                    };
+                    self.send_channel_error(zero, worker, e.kind());
+                    return Err(e);
+                }
+            };
 
-        let channel = self.channels.get_mut(&cipher_address).unwrap();
-        let mut channel = &mut *channel.lock().unwrap();
+        let span = tracing::debug_span!("channel_initiate", channel = %cipher_address);
+        let _enter = span.enter();
+        let channel = self.channels[&cipher_address].clone();
+        let mut channel = channel.lock().unwrap();
         channel.pending = Some(Message {
             onward_route: Route {
                 addresses: vec![pending_return],
@@ -259,74 +1520,125 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             message_type: MessageType::None,
             message_body: vec![],
         });
-        let ka_m1 = channel.agreement.process(&[])?;
+        // held like a payload sent during the key exchange, but not counted against the limit
+        channel.held.extend(payload);
         route
             .addresses
             .push(RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap());
-        let m = Message {
-            onward_route: route,
-            return_route: Route {
-                addresses: vec![
-                    RouterAddress::channel_router_address_from_str(&cipher_address).unwrap(),
-                ],
-            },
-            message_type: MessageType::KeyAgreementM1,
-            message_body: ka_m1,
-        };
-        self.router_tx.send(Router(RouterCommand::SendMessage(m)))?;
+        channel.initiate_route = route;
+        let sent = self.send_m1(&mut channel);
+        drop(channel);
+        if let Err(e) = sent {
+            self.fail_key_exchange(&cipher_address, e.kind().clone());
+        }
         Ok(Address::channel_address_from_string(&clear_address).unwrap())
     }
 
     fn handle_recv(&mut self, m: Message) -> Result<(), ChannelError> {
-        if m.onward_route.addresses.is_empty() {
-            // no onward route, how to determine which channel to decrypt message?
-            // can't so drop
-            return Err(ChannelErrorKind::RecvError.into());
-        }
-        // Pop the first onward address off to get the channel id.
-        // If it's 0, we expect the message to be M1 of a key exchange
-        // Respond accordingly
-        let mut cipher_address = m.onward_route.addresses[0].address.as_string();
-        if cipher_address == CHANNEL_ZERO {
-            if let Some((_clear, cipher)) = self.create_channel(ExchangerRole::Responder) {
-                cipher_address = cipher;
-            } else {
-                return Err(ChannelErrorKind::State.into());
-            }
-        }
-        match self.channels.get_mut(&cipher_address) {
-            Some(channel) => {
-                let channel = channel.clone();
-                return match m.message_type {
-                    MessageType::KeyAgreementM1 => {
-                        self.handle_m1_recv(channel, m)?;
-                        Ok(())
-                    }
-                    MessageType::KeyAgreementM2 => {
-                        self.handle_m2_recv(channel, m)?;
-                        Ok(())
-                    }
-                    MessageType::KeyAgreementM3 => {
-                        self.handle_m3_recv(channel, m)?;
-                        Ok(())
-                    }
-                    MessageType::Payload => {
-                        self.handle_payload_recv(channel, m)?;
-                        Ok(())
-                    }
-                    _ => {
-                        debug_assert!(false);
-                        Err(ChannelErrorKind::NotImplemented.into())
+        // refused before any work is done for it, however much its sender claims it holds
+        if m.message_body.len() > self.max_message_size {
+            return Err(ChannelErrorKind::MessageTooLarge.into());
+        }
+        // The first onward address is the channel's. If it's 0, we expect the message to be
+        // M1 of a key exchange, and respond accordingly
+        let cipher_address = match protocol::addressee(&m) {
+            Ok(Some(address)) => address,
+            Err(_) => {
+                tracing::debug!(id = %m.trace_id(), "no channel address, dropping");
+                return Ok(());
+            }
+            Ok(None) if matches!(&self.listener, Some(l) if !l.accept(&m.return_route)) => {
+                tracing::debug!(id = %m.trace_id(), "key exchange not accepted, dropping");
+                return Ok(());
+            }
+            Ok(None) => {
+                let cipher_suite_id = match protocol::split_m1(&m.message_body) {
+                    Ok((cipher_suite_id, _, _)) => cipher_suite_id,
+                    Err(_) => {
+                        tracing::debug!(id = %m.trace_id(), "M1 too short to open a channel, dropping");
+                        return Ok(());
                     }
                 };
+                if !self.key_exchangers.contains_key(&cipher_suite_id) {
+                    tracing::debug!(
+                        id = %m.trace_id(),
+                        cipher_suite_id,
+                        "no key exchanger for the cipher suite, dropping"
+                    );
+                    return Ok(());
+                }
+                self.expire_half_open();
+                if self.half_open() >= self.max_half_open {
+                    self.rejected_handshakes += 1;
+                    tracing::debug!(id = %m.trace_id(), "too many key exchanges under way, dropping");
+                    return Ok(());
+                }
+                match self.create_channel(Role::Responder, cipher_suite_id) {
+                    Ok((_clear, cipher)) => cipher,
+                    Err(_) => {
+                        tracing::debug!(id = %m.trace_id(), "no unused channel addresses, dropping");
+                        return Ok(());
+                    }
+                }
             }
+        };
+        let span = tracing::debug_span!(
+            "channel_recv",
+            channel = %cipher_address,
+            id = %m.trace_id(),
+            message_type = ?m.message_type
+        );
+        let _enter = span.enter();
+        let channel = match self.channels.get(&cipher_address) {
+            Some(channel) => channel.clone(),
             None => {
-                debug_assert!(false, "unknown channel address");
-                //Ok(())
-                // Do nothing and drop message
+                // the channel may have been closed, or refused by the trust policy; drop the
+                // message
+                tracing::debug!("no such channel, dropping");
+                return Ok(());
+            }
+        };
+        let step = {
+            let mut channel = channel.lock().unwrap();
+            channel.last_active = Instant::now();
+            let (phase, step) = protocol::receive(channel.role, channel.phase, m.message_type);
+            channel.phase = phase;
+            step
+        };
+        match step {
+            ReceiveStep::KeyExchange(_) => {
+                let result = match m.message_type {
+                    MessageType::KeyAgreementM1 => self.handle_m1_recv(channel, m),
+                    MessageType::KeyAgreementM2 => self.handle_m2_recv(channel, m),
+                    _ => self.handle_m3_recv(channel, m),
+                };
+                // a bad key exchange message, or the vault failing, ends the channel rather
+                // than leaving it half-open
+                if let Err(e) = result {
+                    self.fail_key_exchange(&cipher_address, e.kind().clone());
+                }
+                Ok(())
+            }
+            ReceiveStep::Decrypt => self.handle_payload_recv(channel, m),
+            ReceiveStep::Reassemble => self.handle_fragment_recv(channel, m),
+            ReceiveStep::Pong => self.handle_ping_recv(channel, m),
+            ReceiveStep::NotifyAlive => self.handle_pong_recv(channel, m),
+            ReceiveStep::Close => self.handle_close_recv(channel, m),
+            ReceiveStep::Acknowledged => self.handle_ack_recv(channel, m),
+            ReceiveStep::Resend => {
+                let channel = channel.lock().unwrap();
+                if let Some(last) = channel.last_key_exchange.clone() {
+                    tracing::debug!("key exchange message retransmitted, answering it again");
+                    self.router_tx
+                        .send(Router(RouterCommand::SendMessage(last)))?;
+                }
+                Ok(())
+            }
+            ReceiveStep::Drop => {
+                tracing::debug!("message out of turn, dropping");
+                Ok(())
             }
         }
-        Ok(())
     }
 
     fn handle_payload_recv(
@@ -336,65 +1648,210 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
     ) -> Result<(), ChannelError> {
         // Decrypt, put address on onward route at 0 and send
         let mut channel = channel.lock().unwrap();
-        if m.message_body.len() < 2 {
-            return Err(ChannelErrorKind::RecvError.into());
-        }
         let kex = channel.completed_key_exchange.as_ref().unwrap();
 
-        return match u16::decode(&m.message_body) {
-            Ok((nonce, cipher_text)) => {
-                let nonce_96 = Channel::nonce_16_to_96(nonce);
-                let mut vault = self.vault.lock().unwrap();
-                let new_m_encoded =
-                    vault.aead_aes_gcm_decrypt(kex.decrypt_key, cipher_text, &nonce_96, &kex.h)?;
-                let (new_m, _) = Message::decode(&new_m_encoded).unwrap();
-                channel.nonce += 1;
-                self.router_tx
-                    .send(Router(RouterCommand::ReceiveMessage(new_m)))?;
-                Ok(())
+        let mut vault = self.vault.lock().unwrap();
+        let decrypted = decrypt_payload(&mut *vault, kex, &m.message_body);
+        drop(vault);
+        // one which doesn't decrypt wasn't sent by the other end
+        let (nonce, plaintext) = match decrypted {
+            Ok(decrypted) => decrypted,
+            Err(_) => {
+                tracing::debug!("payload doesn't decrypt, dropping");
+                return Ok(());
+            }
+        };
+        channel.received(nonce);
+        let (stream_id, seq, new_m_encoded) = match stream::unframe(&plaintext) {
+            Some(frame) => frame,
+            None => {
+                tracing::debug!("payload has no stream header, dropping");
+                return Ok(());
             }
-            _ => Err(ChannelErrorKind::InvalidParam(0).into()),
         };
+        channel.stats.messages_received += 1;
+        channel.stats.bytes_decrypted += new_m_encoded.len() as u64;
+        let new_m = protocol::delivered(
+            new_m_encoded,
+            channel.as_cleartext_address(),
+            self.max_message_size,
+        )?;
+        tracing::debug!(plaintext_id = %new_m.trace_id(), nonce, stream = stream_id, seq, "decrypted");
+        self.deliver(&mut channel, stream_id, seq, Received::Payload(new_m))
     }
 
-    fn handle_m1_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> Result<(), ChannelError> {
-        let channel = &mut *channel.lock().unwrap();
-        channel.agreement.process(&m.message_body)?;
-        let m2 = channel.agreement.process(&[])?;
-        let m = Message {
-            onward_route: m.return_route,
-            return_route: Route {
-                addresses: vec![
-                    RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
-                ],
+    fn handle_fragment_recv(
+        &self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
+        // the payload is decoded once all its fragments are in
+        let mut channel = channel.lock().unwrap();
+        let kex = channel.completed_key_exchange.as_ref().unwrap();
+
+        let mut vault = self.vault.lock().unwrap();
+        let decrypted = decrypt_payload(&mut *vault, kex, &m.message_body);
+        drop(vault);
+        // one which doesn't decrypt wasn't sent by the other end
+        let (nonce, plaintext) = match decrypted {
+            Ok(decrypted) => decrypted,
+            Err(_) => {
+                tracing::debug!("fragment doesn't decrypt, dropping");
+                return Ok(());
+            }
+        };
+        channel.received(nonce);
+        let (stream_id, seq, fragment) = match stream::unframe(&plaintext) {
+            Some(frame) => frame,
+            None => {
+                tracing::debug!("fragment has no stream header, dropping");
+                return Ok(());
+            }
+        };
+        channel.stats.bytes_decrypted += fragment.len() as u64;
+        tracing::debug!(nonce, stream = stream_id, seq, "decrypted fragment");
+        let fragment = Received::Fragment(seq, fragment.to_vec());
+        self.deliver(&mut channel, stream_id, seq, fragment)
+    }
+
+    fn handle_ping_recv(
+        &self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
+        let mut channel = channel.lock().unwrap();
+        if !self.open_control(&mut channel, &m)? {
+            return Ok(());
+        }
+        self.send_control(&mut channel, m.return_route, MessageType::Pong)
+    }
+
+    // Tell the worker that initiated the channel that the remote end is still there
+    fn handle_pong_recv(
+        &self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
+        let mut channel = channel.lock().unwrap();
+        if !self.open_control(&mut channel, &m)? {
+            return Ok(());
+        }
+        if let Some(pending) = &channel.pending {
+            let pong = protocol::message_from(
+                channel.as_cleartext_address(),
+                pending.onward_route.clone(),
+                MessageType::Pong,
+                vec![],
+            );
+            self.router_tx
+                .send(Router(RouterCommand::ReceiveMessage(pong)))?;
+        }
+        Ok(())
+    }
+
+    fn handle_ack_recv(
+        &self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
+        let mut channel = channel.lock().unwrap();
+        let cke = channel.completed_key_exchange.unwrap();
+        let decrypted = decrypt_payload(&mut *self.vault.lock().unwrap(), &cke, &m.message_body);
+        let (stream_id, next) = match decrypted {
+            Ok((nonce, plaintext)) => match stream::unframe(&plaintext) {
+                Some((stream_id, next, _)) => {
+                    channel.received(nonce);
+                    (stream_id, next)
+                }
+                None => {
+                    tracing::debug!("ack has no stream header, dropping");
+                    return Ok(());
+                }
             },
-            message_type: MessageType::KeyAgreementM2,
-            message_body: m2,
+            Err(_) => {
+                tracing::debug!("ack doesn't decrypt, dropping");
+                return Ok(());
+            }
         };
-        self.router_tx
-            .send(Router(RouterCommand::SendMessage(m)))
-            .unwrap();
+        channel.last_heard = Instant::now();
+        channel.acknowledged(stream_id, next);
+        tracing::debug!(stream = stream_id, next, "acknowledged");
+        Ok(())
+    }
+
+    fn handle_close_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
+        let address = {
+            let mut channel = channel.lock().unwrap();
+            // a close which doesn't decrypt wasn't sent by the other end
+            if !self.open_control(&mut channel, &m)? {
+                return Ok(());
+            }
+            channel.as_ciphertext_address().as_string()
+        };
+        tracing::debug!("closed by the other end");
+        self.close(&address, false);
         Ok(())
     }
 
-    fn handle_m2_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> Result<(), ChannelError> {
+    fn handle_m1_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> Result<(), ChannelError> {
+        let channel = &mut *channel.lock().unwrap();
+        let (_, offered, ka_m1) = protocol::split_m1(&m.message_body)?;
+        channel.agreement.process(ka_m1)?;
+        let m2 = channel.agreement.process(&[])?;
+        let accepted = offered & self.options();
+        channel.compress = accepted & protocol::OPTION_DEFLATE != 0;
+        channel.reliable = accepted & protocol::OPTION_RELIABLE != 0;
+        let m = protocol::message_from(
+            channel.as_ciphertext_address(),
+            m.return_route,
+            MessageType::KeyAgreementM2,
+            protocol::m2_body(accepted, &m2),
+        );
+        tracing::debug!(reply_id = %m.trace_id(), "sending key exchange M2");
+        self.send_key_exchange(channel, m)
+    }
+
+    fn handle_m2_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
         let mut channel = &mut *channel.lock().unwrap();
         let return_route = m.return_route.clone();
-        channel.agreement.process(&m.message_body)?;
+        let (accepted, ka_m2) = protocol::split_m2(&m.message_body)?;
+        channel.agreement.process(ka_m2)?;
+        let accepted = accepted & self.options();
+        channel.compress = accepted & protocol::OPTION_DEFLATE != 0;
+        channel.reliable = accepted & protocol::OPTION_RELIABLE != 0;
         let m3 = channel.agreement.process(&[])?;
-        let m = Message {
-            onward_route: return_route.clone(),
-            return_route: Route {
-                addresses: vec![m.onward_route.addresses[0].clone()],
-            },
-            message_type: MessageType::KeyAgreementM3,
-            message_body: m3,
-        };
-        self.router_tx
-            .send(Router(RouterCommand::SendMessage(m)))
-            .unwrap();
-        channel.completed_key_exchange = Some(channel.agreement.finalize()?);
+        let completed_key_exchange = channel.agreement.finalize()?;
+        // the responder never gets M3 from a party this node doesn't trust
+        if !self.is_trusted(channel, &completed_key_exchange) {
+            self.refuse(channel, &completed_key_exchange);
+            return Ok(());
+        }
+        let m = protocol::message_from(
+            channel.as_ciphertext_address(),
+            return_route.clone(),
+            MessageType::KeyAgreementM3,
+            m3,
+        );
+        tracing::debug!(reply_id = %m.trace_id(), "sending key exchange M3");
+        // kept to answer M2 again should the responder not get it, but not sent again unasked
+        self.send_key_exchange(channel, m)?;
+        channel.completed_key_exchange = Some(completed_key_exchange);
+        channel.established_at = Some(SystemTime::now());
         channel.route = return_route;
+        channel.last_heard = Instant::now();
+        channel.stats.handshake_duration = Some(channel.handshake_started.elapsed());
+        tracing::debug!("key exchange complete");
+
+        // payloads sent during the key exchange go out under the new keys
+        self.send_held(channel)?;
 
         // let the worker know the key exchange is done
         let pending = channel.pending.clone();
@@ -417,17 +1874,34 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
         Ok(())
     }
 
-    fn handle_m3_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> Result<(), ChannelError> {
+    fn handle_m3_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
         let mut channel = channel.lock().unwrap();
         let return_route = m.return_route.clone();
         // For now ignore anything returned from M3
         let _ = channel.agreement.process(&m.message_body)?;
         debug_assert!(channel.agreement.is_complete());
         if channel.completed_key_exchange.is_none() {
+            let completed_key_exchange = channel.agreement.finalize()?;
+            if !self.is_trusted(&channel, &completed_key_exchange) {
+                channel.route = return_route;
+                self.refuse(&channel, &completed_key_exchange);
+                return Ok(());
+            }
+
             // key agreement has finished, now can process any pending messages
             let pending = channel.pending.clone();
-            channel.completed_key_exchange = Some(channel.agreement.finalize()?);
+            channel.completed_key_exchange = Some(completed_key_exchange);
+            channel.established_at = Some(SystemTime::now());
+            channel.last_key_exchange = None;
             channel.route = return_route;
+            channel.last_heard = Instant::now();
+            channel.stats.handshake_duration = Some(channel.handshake_started.elapsed());
+            tracing::debug!("key exchange complete");
+            self.send_held(&mut channel)?;
             match pending {
                 Some(mut p) => {
                     p.return_route = channel.route.clone();
@@ -463,7 +1937,13 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                         },
                         return_route,
                         message_type: MessageType::None,
-                        message_body: vec![],
+                        // as for an initiator's worker, the remote public key is the body
+                        message_body: channel
+                            .completed_key_exchange
+                            .unwrap()
+                            .remote_static_public_key
+                            .as_ref()
+                            .to_vec(),
                     };
                     self.router_tx
                         .send(Router(RouterCommand::ReceiveMessage(new_m)))
@@ -474,48 +1954,171 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
         Ok(())
     }
 
-    fn create_channel(&mut self, role: ExchangerRole) -> Option<(String, String)> {
+    // Whether the allowed initiators and the trust policy, if any, trust the remote party of a
+    // completed key exchange.
+    fn is_trusted(&self, channel: &Channel, cke: &CompletedKeyExchange) -> bool {
+        let key = cke.remote_static_public_key.as_ref();
+        match &self.allowed_initiators {
+            Some(keys) if channel.role == Role::Responder && !keys.contains(key) => {
+                tracing::debug!(key = %hex::encode(key), "initiator is not allowed");
+                return false;
+            }
+            _ => {}
+        }
+        match &self.trust_policy {
+            Some(policy) => policy.is_trusted(channel.role, key),
+            None => true,
+        }
+    }
+
+    // A key exchanger for a new channel, with the static key of the identity for `role`. The
+    // manager must have a key exchanger for the cipher suite.
+    fn new_agreement(&self, role: Role, cipher_suite_id: u8) -> Box<dyn KeyExchanger + Send> {
+        let new_key_exchanger = &self.key_exchangers[&cipher_suite_id];
+        match role {
+            Role::Initiator => new_key_exchanger.initiator(self.init_key_ctx),
+            Role::Responder => {
+                new_key_exchanger.responder(self.resp_identity.as_ref().map(Identity::key))
+            }
+        }
+    }
+
+    // Store a channel under both of its addresses.
+    fn insert(&mut self, channel: Channel) {
+        let clear_address = channel.as_cleartext_address().as_string();
+        let cipher_address = channel.as_ciphertext_address().as_string();
+        let channel = Arc::new(Mutex::new(channel));
+        self.channels.insert(clear_address, channel.clone());
+        self.channels.insert(cipher_address, channel);
+    }
+
+    // Create a channel at a random pair of addresses, returning its cleartext and ciphertext
+    // addresses.
+    fn create_channel(
+        &mut self,
+        role: Role,
+        cipher_suite_id: u8,
+    ) -> Result<(String, String), ChannelError> {
         let mut rng = thread_rng();
-        let clear_u32 = rng.gen::<u32>();
-        let cipher_u32 = rng.gen::<u32>();
-        let channel = match role {
-            ExchangerRole::Initiator => Arc::new(Mutex::new(Channel::new(
-                clear_u32,
-                cipher_u32,
-                Box::new(self.new_key_exchanger.initiator(self.init_key_ctx)),
-            ))),
-            ExchangerRole::Responder => Arc::new(Mutex::new(Channel::new(
-                clear_u32,
-                cipher_u32,
-                Box::new(self.new_key_exchanger.responder(self.resp_key_ctx)),
-            ))),
+        self.create_channel_at(role, cipher_suite_id, || rng.gen::<u32>())
+    }
+
+    // Create a channel at a pair of addresses from `draw`, drawing again while either is zero,
+    // in use, or the same as the other, and failing with `AddressesExhausted` once
+    // `ADDRESS_ATTEMPTS` pairs have been.
+    fn create_channel_at(
+        &mut self,
+        role: Role,
+        cipher_suite_id: u8,
+        mut draw: impl FnMut() -> u32,
+    ) -> Result<(String, String), ChannelError> {
+        let in_use = |channels: &BTreeMap<String, _>, address: u32| {
+            address == 0 || channels.contains_key(&hex::encode(address.to_le_bytes()))
         };
-        let clear_address = Address::ChannelAddress(clear_u32.to_le_bytes().to_vec());
-        let cipher_address = Address::ChannelAddress(cipher_u32.to_le_bytes().to_vec());
-        self.channels
-            .insert(clear_address.as_string(), channel.clone());
-        self.channels.insert(cipher_address.as_string(), channel);
-        Some((clear_address.as_string(), cipher_address.as_string()))
+        for _ in 0..ADDRESS_ATTEMPTS {
+            let clear_u32 = draw();
+            let cipher_u32 = draw();
+            if clear_u32 == cipher_u32
+                || in_use(&self.channels, clear_u32)
+                || in_use(&self.channels, cipher_u32)
+            {
+                tracing::debug!(
+                    clear_u32,
+                    cipher_u32,
+                    "channel address in use, drawing again"
+                );
+                continue;
+            }
+            let agreement = self.new_agreement(role, cipher_suite_id);
+            let channel = Channel::new(clear_u32, cipher_u32, role, cipher_suite_id, agreement);
+            let clear_address = channel.as_cleartext_address();
+            let cipher_address = channel.as_ciphertext_address();
+            self.insert(channel);
+            return Ok((clear_address.as_string(), cipher_address.as_string()));
+        }
+        Err(ChannelErrorKind::AddressesExhausted.into())
     }
 }
 
+// What the other end sent as one frame of a stream, held until what it sent before on the
+// stream is delivered
+#[derive(Debug)]
+enum Received {
+    Payload(Message),
+    // a fragment of a payload, with its sequence number
+    Fragment(u64, Vec<u8>),
+    // a control message, which only takes up its nonce
+    Control,
+}
+
+// One stream a channel receives on
+#[derive(Debug, Default)]
+struct Stream {
+    // decrypted payloads waiting for those sent before them, and the sequence numbers of
+    // control messages
+    reorder: Reorder<Received>,
+    // the fragments of a payload received so far
+    reassembly: Reassembly,
+}
+
 struct Channel {
     completed_key_exchange: Option<CompletedKeyExchange>,
     remote_public_key: Option<PublicKey>,
     cleartext_address: u32,
     ciphertext_address: u32,
-    agreement: Box<dyn KeyExchanger>,
-    nonce: u16,
+    role: Role,
+    phase: Phase,
+    // the cipher suite of the key exchange, run again with the same
+    cipher_suite_id: u8,
+    agreement: Box<dyn KeyExchanger + Send>,
+    // the nonce of the next frame sent, on whichever stream, and one past the highest received;
+    // each direction has its own key and counts its own nonces
+    send_nonce: u64,
+    recv_nonce: u64,
     route: Route,
+    // the route M1 was sent over, reused when the key exchange is re-run
+    initiate_route: Route,
     pending: Option<Message>,
+    held: Vec<Message>,
+    // when a message was last sent or received on the channel
+    last_active: Instant,
+    // the streams received on, each put back in order on its own, and those sent on
+    streams: BTreeMap<u32, Stream>,
+    sending: Sending,
+    // when the other end was last heard from, and a heartbeat last sent to it
+    last_heard: Instant,
+    last_ping: Instant,
+    // when the key exchange was last started, and last completed
+    handshake_started: Instant,
+    established_at: Option<SystemTime>,
+    // the last key exchange message sent, the times it has been sent again, and when it is next
+    last_key_exchange: Option<Message>,
+    retransmits: u32,
+    retransmit_at: Instant,
+    // whether both ends agreed to deflate payloads
+    compress: bool,
+    // whether both ends agreed to acknowledge frames, and the frames sent which the other end
+    // hasn't yet, by stream and sequence number
+    reliable: bool,
+    unacked: BTreeMap<(u32, u64), Unacked>,
+    stats: ChannelStats,
+}
+
+// A frame sent on a reliable channel which the other end hasn't acknowledged, the times it has
+// been sent again, and when it is next
+struct Unacked {
+    message_type: MessageType,
+    body: Vec<u8>,
+    retransmits: u32,
+    retransmit_at: Instant,
 }
 
 impl std::fmt::Debug for Channel {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Channel {{ completed_key_exchange: {:?}, id: {:?}, nonce: {:?}, agreement }}",
-            self.completed_key_exchange, self.cleartext_address, self.nonce
+            "Channel {{ completed_key_exchange: {:?}, id: {:?}, send_nonce: {:?}, recv_nonce: {:?}, agreement }}",
+            self.completed_key_exchange, self.cleartext_address, self.send_nonce, self.recv_nonce
         )
     }
 }
@@ -526,20 +2129,98 @@ impl Channel {
     pub fn new(
         cleartext_address: u32,
         ciphertext_address: u32,
-        agreement: Box<dyn KeyExchanger>,
+        role: Role,
+        cipher_suite_id: u8,
+        agreement: Box<dyn KeyExchanger + Send>,
     ) -> Self {
         Self {
             cleartext_address,
             ciphertext_address,
+            role,
+            phase: Phase::start(role),
+            cipher_suite_id,
             agreement,
             completed_key_exchange: None,
-            nonce: 0,
+            send_nonce: 0,
+            recv_nonce: 0,
+            compress: false,
+            reliable: false,
+            unacked: BTreeMap::new(),
             route: Route { addresses: vec![] },
+            initiate_route: Route { addresses: vec![] },
             pending: None,
             remote_public_key: None,
+            held: vec![],
+            last_active: Instant::now(),
+            streams: BTreeMap::new(),
+            sending: Sending::default(),
+            last_heard: Instant::now(),
+            last_ping: Instant::now(),
+            handshake_started: Instant::now(),
+            established_at: None,
+            last_key_exchange: None,
+            retransmits: 0,
+            retransmit_at: Instant::now(),
+            stats: ChannelStats::default(),
+        }
+    }
+
+    // Stop sending again the frames of a stream before `next`, which the other end has received
+    fn acknowledged(&mut self, stream_id: u32, next: u64) {
+        let acked: Vec<(u32, u64)> = self
+            .unacked
+            .range((stream_id, 0)..(stream_id, next))
+            .map(|(frame, _)| *frame)
+            .collect();
+        for frame in acked {
+            self.unacked.remove(&frame);
+        }
+    }
+
+    // The summary of the channel reported by `ChannelManager::list_channels`
+    pub fn info(&self) -> ChannelInfo {
+        let mut reorder = ReorderStats::default();
+        let mut incomplete = 0;
+        for stream in self.streams.values() {
+            reorder += stream.reorder.stats();
+            incomplete += stream.reassembly.dropped();
+        }
+        ChannelInfo {
+            address: self.as_cleartext_address().as_string(),
+            role: self.role,
+            established: self.completed_key_exchange.is_some(),
+            established_at: self.established_at,
+            route: self.route.clone(),
+            remote_public_key: self
+                .completed_key_exchange
+                .as_ref()
+                .map(|cke| cke.remote_static_public_key.as_ref().to_vec()),
+            held: self.held.len(),
+            compress: self.compress,
+            reliable: self.reliable,
+            unacked: self.unacked.len(),
+            reorder,
+            incomplete,
+            send_nonce: self.send_nonce,
+            recv_nonce: self.recv_nonce,
+            next_received: self
+                .streams
+                .iter()
+                .map(|(id, s)| (*id, s.reorder.next()))
+                .collect(),
         }
     }
 
+    // Count the frame received with `nonce`, which may arrive after those with higher nonces.
+    fn received(&mut self, nonce: u64) {
+        self.recv_nonce = self.recv_nonce.max(nonce.saturating_add(1));
+    }
+
+    // A channel responding to a key exchange which hasn't completed
+    pub fn is_half_open(&self) -> bool {
+        self.role == Role::Responder && self.completed_key_exchange.is_none()
+    }
+
     pub fn as_cleartext_address(&self) -> Address {
         Address::ChannelAddress(self.cleartext_address.to_le_bytes().to_vec())
     }
@@ -548,25 +2229,619 @@ impl Channel {
         Address::ChannelAddress(self.ciphertext_address.to_le_bytes().to_vec())
     }
 
-    pub fn nonce_16_to_96(n16: u16) -> [u8; 12] {
-        // the nonce value is an le u16, whereas the nonce
-        // byte array is 10 bytes of 0's follow by the be
+    pub fn nonce_64_to_96(n64: u64) -> [u8; 12] {
+        // the nonce value is an le u64, whereas the nonce
+        // byte array is 4 bytes of 0's follow by the be
         // representation of the nonce
         let mut n: [u8; 12] = [0; 12];
-        let b = n16.to_be_bytes();
-        n[10] = b[0];
-        n[11] = b[1];
+        n[4..].copy_from_slice(&n64.to_be_bytes());
         n
     }
 
-    pub fn nonce_from_96(n: &[u8; 12]) -> u16 {
-        let bytes: [u8; 2] = [n[10], n[11]];
-        u16::from_be_bytes(bytes)
+    pub fn nonce_from_96(n: &[u8; 12]) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&n[4..]);
+        u64::from_be_bytes(bytes)
+    }
+}
+
+// Copy a channel key in the vault to one with `persistence`, destroying the original, so that
+// the filesystem vault writes a key which is to outlive the node to disk, and deletes it again.
+fn set_persistence(
+    vault: &mut dyn DynVault,
+    key: &mut SecretKeyContext,
+    persistence: SecretPersistenceType,
+) -> Result<(), ChannelError> {
+    let mut attributes = vault.secret_attributes_get(*key)?;
+    attributes.persistence = persistence;
+    let secret = vault.secret_export(*key)?;
+    let copy = vault.secret_import(&secret, attributes)?;
+    vault.secret_destroy(*key)?;
+    *key = copy;
+    Ok(())
+}
+
+/// Bytes of the nonce at the start of the body of each encrypted channel message.
+pub const NONCE_LEN: usize = 8;
+
+/// Encrypt the payload of a channel message with the keys of a completed key exchange, using the
+/// AEAD its cipher suite agreed them for: the body sent is the nonce, a little-endian u64,
+/// followed by the ciphertext and tag. This is the
+/// channel's wire format, shared with the other implementations through their native bindings.
+/// The last nonce, `u64::MAX`, is never used, so that a counter of the nonces sent can't wrap;
+/// encrypting with it fails with `NonceExhausted`, and the channel must be re-established to
+/// agree new keys.
+pub fn encrypt_payload(
+    vault: &mut dyn DynVault,
+    cke: &CompletedKeyExchange,
+    nonce: u64,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, ChannelError> {
+    if nonce == u64::MAX {
+        return Err(ChannelErrorKind::NonceExhausted.into());
     }
+    let mut body = nonce.to_le_bytes().to_vec();
+    let mut ciphertext_and_tag = cke.aead.encrypt(
+        vault,
+        cke.encrypt_key,
+        plaintext,
+        &cke.aead.salted_nonce(nonce, cke.encrypt_salt),
+        &cke.h,
+    )?;
+    body.append(&mut ciphertext_and_tag);
+    Ok(body)
 }
 
+/// Decrypt the body of a channel message made by `encrypt_payload` on the other end of the
+/// channel, returning its nonce and plaintext.
+pub fn decrypt_payload(
+    vault: &mut dyn DynVault,
+    cke: &CompletedKeyExchange,
+    body: &[u8],
+) -> Result<(u64, Vec<u8>), ChannelError> {
+    if body.len() < NONCE_LEN {
+        return Err(ChannelErrorKind::RecvError.into());
+    }
+    let (nonce, cipher_text) = body.split_at(NONCE_LEN);
+    let mut bytes = [0; NONCE_LEN];
+    bytes.copy_from_slice(nonce);
+    let nonce = u64::from_le_bytes(bytes);
+    let plaintext = cke.aead.decrypt(
+        vault,
+        cke.decrypt_key,
+        cipher_text,
+        &cke.aead.salted_nonce(nonce, cke.decrypt_salt),
+        &cke.h,
+    )?;
+    Ok((nonce, plaintext))
+}
+
+pub mod compress;
 /// Represents the errors that occur within a channel
 pub mod error;
+pub mod fragment;
+mod inbox;
+pub mod protocol;
+pub mod reorder;
+pub mod state;
+pub mod stream;
+#[cfg(test)]
+mod nonce_tests {
+    use super::*;
+    use ockam_common::error::ErrorKind;
+    use ockam_vault::software::DefaultVault;
+
+    #[test]
+    fn nonce_96_round_trip() {
+        for n in &[0, 1, 0xffff, 0x1_0000, u64::MAX - 1] {
+            let n96 = Channel::nonce_64_to_96(*n);
+            assert_eq!(n96[..4], [0; 4]);
+            assert_eq!(Channel::nonce_from_96(&n96), *n);
+        }
+        // small nonces keep the layout of the 16-bit counter
+        assert_eq!(Channel::nonce_64_to_96(0x0102)[10..], [1, 2]);
+        // which is the one Noise gives AES-GCM
+        assert_eq!(Channel::nonce_64_to_96(0x0102), Aead::AesGcm.nonce(0x0102));
+        // and payloads salt
+        let salted = Aead::AesGcm.salted_nonce(0x0102, [9, 8, 7, 6]);
+        assert_eq!(salted[..4], [9, 8, 7, 6]);
+        assert_eq!(Channel::nonce_from_96(&salted), 0x0102);
+    }
+
+    #[test]
+    fn exhausted_nonce_is_refused() {
+        let mut vault = DefaultVault::default();
+        let cke = CompletedKeyExchange {
+            h: [0; 32],
+            encrypt_key: SecretKeyContext::Memory(0),
+            decrypt_key: SecretKeyContext::Memory(0),
+            local_static_secret: SecretKeyContext::Memory(0),
+            remote_static_public_key: PublicKey::Curve25519([0; 32]),
+            aead: Aead::AesGcm,
+            encrypt_salt: [0; 4],
+            decrypt_salt: [0; 4],
+        };
+        let err = encrypt_payload(&mut vault, &cke, u64::MAX, b"hello").unwrap_err();
+        assert_eq!(
+            err.kind().to_usize(),
+            ChannelErrorKind::NonceExhausted.to_usize()
+        );
+        assert!(decrypt_payload(&mut vault, &cke, &[0; NONCE_LEN - 1]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod manager_tests {
+    use super::*;
+    use ockam_kex::xx::XXNewKeyExchanger;
+    use ockam_kex::CipherSuite;
+    use ockam_vault::software::DefaultVault;
+    use ockam_vault::types::{SecretKey, SecretKeyAttributes, SecretKeyType, SecretPurposeType};
+    use std::sync::mpsc::channel;
+
+    fn new_manager() -> ChannelManager {
+        let vault: Arc<Mutex<dyn DynVault + Send>> = Arc::new(Mutex::new(DefaultVault::default()));
+        let (tx, rx) = channel();
+        let (router_tx, _router_rx) = channel();
+        let new_key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        ChannelManager::new(
+            rx,
+            tx,
+            router_tx,
+            vault,
+            Box::new(new_key_exchanger),
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    // An established channel at 1 and 2 whose keys are one key, so that it can open what it
+    // sends.
+    fn established(manager: &ChannelManager, role: Role) -> Channel {
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Aes256,
+            persistence: SecretPersistenceType::Ephemeral,
+            purpose: SecretPurposeType::KeyAgreement,
+        };
+        let mut vault = manager.vault.lock().unwrap();
+        let mut key = || {
+            vault
+                .secret_import(&SecretKey::Aes256([7; 32]), attributes)
+                .unwrap()
+        };
+        let cke = CompletedKeyExchange {
+            h: [0; 32],
+            encrypt_key: key(),
+            decrypt_key: key(),
+            local_static_secret: SecretKeyContext::Memory(0),
+            remote_static_public_key: PublicKey::Curve25519([0; 32]),
+            aead: Aead::AesGcm,
+            encrypt_salt: [0; 4],
+            decrypt_salt: [0; 4],
+        };
+        let agreement = manager.new_agreement(role, 1);
+        let mut channel = Channel::new(1, 2, role, 1, agreement);
+        channel.completed_key_exchange = Some(cke);
+        channel.phase = Phase::Established;
+        channel
+    }
+
+    fn m1(cipher_suite_id: u8) -> Message {
+        Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap()
+                ],
+            },
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap()
+                ],
+            },
+            message_type: MessageType::KeyAgreementM1,
+            message_body: protocol::m1_body(cipher_suite_id, 0, &[0; 32]),
+        }
+    }
+
+    #[test]
+    fn m1_refused_before_key_exchange() {
+        let mut manager = new_manager();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let listener_seen = seen.clone();
+        manager.set_listener(Box::new(move |route: &Route| {
+            listener_seen.lock().unwrap().push(route.addresses.len());
+            false
+        }));
+        manager.handle_recv(m1(1)).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![1]);
+        assert!(manager.list_channels().is_empty());
+        assert!(manager.channel_info(CHANNEL_ZERO).is_none());
+
+        // a cipher suite with no key exchanger, or one too many half-open channels
+        let mut manager = new_manager();
+        manager.handle_recv(m1(3)).unwrap();
+        manager.set_half_open_limit(0, DEFAULT_HANDSHAKE_TIMEOUT);
+        manager.handle_recv(m1(1)).unwrap();
+        manager.handle_recv(m1(1)).unwrap();
+        assert!(manager.list_channels().is_empty());
+        assert_eq!(manager.rejected_handshakes(), 2);
+
+        // nor does an M1 too short to hold a cipher suite and options fail the manager
+        let mut manager = new_manager();
+        let mut short = m1(1);
+        short.message_body.truncate(1);
+        manager.handle_recv(short).unwrap();
+        assert!(manager.list_channels().is_empty());
+    }
+
+    #[test]
+    fn allowed_initiators() {
+        let mut manager = new_manager();
+        let cke = |key| CompletedKeyExchange {
+            h: [0; 32],
+            encrypt_key: SecretKeyContext::Memory(1),
+            decrypt_key: SecretKeyContext::Memory(2),
+            local_static_secret: SecretKeyContext::Memory(3),
+            remote_static_public_key: PublicKey::Curve25519([key; 32]),
+            aead: Aead::AesGcm,
+            encrypt_salt: [0; 4],
+            decrypt_salt: [0; 4],
+        };
+        let responder = Channel::new(
+            1,
+            2,
+            Role::Responder,
+            1,
+            manager.new_agreement(Role::Responder, 1),
+        );
+        let initiator = Channel::new(
+            3,
+            4,
+            Role::Initiator,
+            1,
+            manager.new_agreement(Role::Initiator, 1),
+        );
+        assert!(manager.is_trusted(&responder, &cke(7)));
+
+        manager.set_allowed_initiators(Some(vec![vec![7; 32]].into_iter().collect()));
+        assert!(manager.is_trusted(&responder, &cke(7)));
+        assert!(!manager.is_trusted(&responder, &cke(8)));
+        // the responders of channels this node initiates aren't restricted
+        assert!(manager.is_trusted(&initiator, &cke(8)));
+
+        // an allowed initiator must still satisfy the trust policy
+        manager.set_trust_policy(Box::new(|_: Role, key: &[u8]| key[0] != 7));
+        assert!(!manager.is_trusted(&responder, &cke(7)));
+        assert!(manager.is_trusted(&initiator, &cke(8)));
+    }
+
+    #[test]
+    fn nonces_counted_by_direction() {
+        let manager = new_manager();
+        let agreement = manager.new_agreement(Role::Initiator, 1);
+        let mut channel = Channel::new(1, 2, Role::Initiator, 1, agreement);
+        channel.send_nonce = 9;
+        channel.received(4);
+        channel.received(2);
+        let info = channel.info();
+        assert_eq!((info.send_nonce, info.recv_nonce), (9, 5));
+    }
+
+    #[test]
+    fn unacked_frames() {
+        let mut manager = new_manager();
+        assert_eq!(manager.options(), 0);
+        manager.set_reliable(true);
+        assert_eq!(manager.options(), protocol::OPTION_RELIABLE);
+
+        let agreement = manager.new_agreement(Role::Initiator, 1);
+        let mut channel = Channel::new(1, 2, Role::Initiator, 1, agreement);
+        channel.reliable = true;
+        let due = Instant::now() + Duration::from_secs(1);
+        for frame in &[(0, 0), (0, 1), (1, 0), (1, 1), (1, 2)] {
+            let unacked = Unacked {
+                message_type: MessageType::Payload,
+                body: vec![],
+                retransmits: 0,
+                retransmit_at: due,
+            };
+            channel.unacked.insert(*frame, unacked);
+        }
+        // acks are cumulative, and per stream
+        channel.acknowledged(1, 2);
+        channel.acknowledged(0, 1);
+        channel.acknowledged(0, 0);
+        let unacked: Vec<_> = channel.unacked.keys().copied().collect();
+        assert_eq!(unacked, vec![(0, 1), (1, 2)]);
+        assert!(channel.info().reliable);
+        assert_eq!(channel.info().unacked, 2);
+
+        // the manager wakes to send them again
+        manager.insert(channel);
+        assert_eq!(manager.next_expiry(), Some(due));
+    }
+
+    #[test]
+    fn undecryptable_payloads_dropped() {
+        let mut manager = new_manager();
+        manager.insert(established(&manager, Role::Responder));
+        for message_type in &[MessageType::Payload, MessageType::Fragment] {
+            let junk = Message {
+                onward_route: Route {
+                    addresses: vec![
+                        RouterAddress::channel_router_address_from_str("02000000").unwrap()
+                    ],
+                },
+                return_route: Route {
+                    addresses: vec![
+                        RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap()
+                    ],
+                },
+                message_type: *message_type,
+                message_body: vec![0; 40],
+            };
+            manager.handle_recv(junk).unwrap();
+        }
+        assert_eq!(manager.list_channels().len(), 1);
+    }
+
+    #[test]
+    fn out_of_turn_messages_dropped() {
+        let mut manager = new_manager();
+        manager.insert(established(&manager, Role::Responder));
+        for message_type in &[
+            MessageType::KeyAgreementM1,
+            MessageType::KeyAgreementM2,
+            MessageType::None,
+        ] {
+            let m = Message {
+                onward_route: Route {
+                    addresses: vec![
+                        RouterAddress::channel_router_address_from_str("02000000").unwrap()
+                    ],
+                },
+                return_route: Route {
+                    addresses: vec![
+                        RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap()
+                    ],
+                },
+                message_type: *message_type,
+                message_body: vec![0; 40],
+            };
+            manager.handle_recv(m).unwrap();
+        }
+
+        // nor is one with no channel to address an error
+        let mut m = m1(1);
+        m.onward_route.addresses.clear();
+        manager.handle_recv(m).unwrap();
+        assert_eq!(manager.list_channels().len(), 1);
+    }
+
+    #[test]
+    fn unacked_sent_again_after_reconnect() {
+        let peer = RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap();
+        let mut initiator = new_manager();
+        let (router_tx, router_rx) = channel();
+        initiator.router_tx = router_tx;
+        let mut initiated = established(&initiator, Role::Initiator);
+        initiated.reliable = true;
+        initiated.route = Route {
+            addresses: vec![peer.clone()],
+        };
+        let payload = Message {
+            onward_route: Route { addresses: vec![] },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: b"lost with the connection".to_vec(),
+        };
+        // sent, but the connection drops before the other end acknowledges it
+        initiator
+            .encrypt_and_send(&mut initiated, payload.clone())
+            .unwrap();
+        router_rx.recv().unwrap();
+        initiator.insert(initiated);
+
+        initiator.handle_transport_reconnected(peer).unwrap();
+        // the other end closes the old channel before the key exchange makes it a new one
+        let sent: Vec<MessageType> = router_rx
+            .try_iter()
+            .map(|command| match command {
+                Router(RouterCommand::SendMessage(m)) => m.message_type,
+                other => panic!("expected a message to be sent, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(sent, vec![MessageType::Close, MessageType::KeyAgreementM1]);
+        let initiated = initiator.channels["01000000"].clone();
+        let mut initiated = initiated.lock().unwrap();
+        assert!(initiated.unacked.is_empty());
+        assert_eq!(initiated.held.len(), 1);
+
+        // once the key exchange completes, the payload reaches the other end
+        let completed = established(&initiator, Role::Initiator);
+        initiated.completed_key_exchange = completed.completed_key_exchange;
+        initiated.phase = Phase::Established;
+        initiator.send_held(&mut initiated).unwrap();
+        let mut sent = match router_rx.try_iter().last() {
+            Some(Router(RouterCommand::SendMessage(m))) => m,
+            other => panic!("expected the payload to be sent, got {:?}", other),
+        };
+        assert_eq!(sent.message_type, MessageType::Payload);
+        sent.onward_route = Route {
+            addresses: vec![RouterAddress::channel_router_address_from_str("02000000").unwrap()],
+        };
+
+        let mut responder = new_manager();
+        let (router_tx, router_rx) = channel();
+        responder.router_tx = router_tx;
+        responder.insert(established(&responder, Role::Responder));
+        responder.handle_recv(sent).unwrap();
+        match router_rx.try_recv() {
+            Ok(Router(RouterCommand::ReceiveMessage(m))) => {
+                assert_eq!(m.message_body, payload.message_body)
+            }
+            other => panic!("expected the payload to be delivered, got {:?}", other),
+        }
+    }
+
+    // needs the software vault's crypto
+    #[test]
+    fn payload_sent_with_initiate() {
+        let peer = RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap();
+        let mut initiator = new_manager();
+        let (router_tx, initiator_rx) = channel();
+        initiator.router_tx = router_tx;
+        let mut responder = new_manager();
+        let (router_tx, responder_rx) = channel();
+        responder.router_tx = router_tx;
+        // the messages each manager passes to its router, the transport taking off the peer
+        let routed = |rx: &Receiver<OckamCommand>| -> Vec<(bool, Message)> {
+            rx.try_iter()
+                .map(|command| match command {
+                    Router(RouterCommand::SendMessage(mut m)) => {
+                        if m.onward_route.addresses.first() == Some(&peer) {
+                            m.onward_route.addresses.remove(0);
+                        }
+                        (true, m)
+                    }
+                    Router(RouterCommand::ReceiveMessage(m)) => (false, m),
+                    other => panic!("expected a message, got {:?}", other),
+                })
+                .collect()
+        };
+
+        let payload = Message {
+            onward_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str("00000002").unwrap()],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: b"sent with M3".to_vec(),
+        };
+        let initiate = ChannelCommand::Initiate(
+            Route {
+                addresses: vec![peer.clone()],
+            },
+            Address::worker_address_from_string("00000001").unwrap(),
+            None,
+            Some(payload.clone()),
+        );
+        assert!(initiator
+            .handle_command(OckamCommand::Channel(initiate))
+            .unwrap());
+        for (_, m1) in routed(&initiator_rx) {
+            responder.handle_recv(m1).unwrap();
+        }
+        for (_, m2) in routed(&responder_rx) {
+            initiator.handle_recv(m2).unwrap();
+        }
+
+        // the payload follows M3, and the worker hears that the channel is established after
+        let completed = routed(&initiator_rx);
+        let steps: Vec<_> = completed
+            .iter()
+            .map(|(sent, m)| (*sent, m.message_type))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (true, MessageType::KeyAgreementM3),
+                (true, MessageType::Payload),
+                (false, MessageType::None),
+            ]
+        );
+        for (_, m) in completed.into_iter().take(2) {
+            responder.handle_recv(m).unwrap();
+        }
+        let delivered = routed(&responder_rx);
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(delivered[1].1.message_body, payload.message_body);
+    }
+
+    #[test]
+    fn address_collisions() {
+        let mut manager = new_manager();
+        let mut draws = vec![1, 2, 1, 5, 6, 6, 0, 7, 3, 4].into_iter();
+        let mut draw = || draws.next().unwrap();
+        manager
+            .create_channel_at(Role::Responder, 1, &mut draw)
+            .unwrap();
+        // the first pair collides with the channel made from it, and the next two are refused
+        let (clear, cipher) = manager
+            .create_channel_at(Role::Responder, 1, &mut draw)
+            .unwrap();
+        assert_eq!((clear.as_str(), cipher.as_str()), ("03000000", "04000000"));
+        assert_eq!(manager.list_channels().len(), 2);
+
+        let err = manager
+            .create_channel_at(Role::Responder, 1, || 2)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ChannelErrorKind::AddressesExhausted));
+        assert_eq!(manager.list_channels().len(), 2);
+    }
+
+    #[test]
+    fn oversized_messages_dropped() {
+        let mut manager = new_manager();
+        manager.set_max_message_size(32);
+        let err = manager.handle_recv(m1(1)).unwrap_err();
+        assert!(matches!(err.kind(), ChannelErrorKind::MessageTooLarge));
+        let received = OckamCommand::Channel(ChannelCommand::ReceiveMessage(m1(1)));
+        assert!(manager.handle_command(received).unwrap());
+        assert!(manager.channels.is_empty());
+    }
+
+    #[test]
+    fn stop_drops_key_exchanges() {
+        let mut manager = new_manager();
+        for (clear, cipher) in &[(1, 2), (3, 4)] {
+            let agreement = manager.new_agreement(Role::Responder, 1);
+            manager.insert(Channel::new(*clear, *cipher, Role::Responder, 1, agreement));
+        }
+        assert_eq!(manager.list_channels().len(), 2);
+        manager.stop();
+        assert!(manager.list_channels().is_empty());
+        assert!(manager.channels.is_empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn run_wakes_for_timers_and_commands() {
+        fn is_send<T: Send>(_: &T) {}
+        let mut manager = new_manager();
+        is_send(&manager.run());
+        manager.set_idle_timeout(Some(Duration::from_millis(20)));
+        for (clear, cipher) in &[(1, 2), (3, 4)] {
+            let agreement = manager.new_agreement(Role::Responder, 1);
+            manager.insert(Channel::new(*clear, *cipher, Role::Responder, 1, agreement));
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        // the idle channels are closed as their timers fall due, with no command to wake for
+        let ran = runtime.block_on(async {
+            tokio::time::timeout(Duration::from_millis(500), manager.run()).await
+        });
+        assert!(ran.is_err());
+        assert!(manager.channels.is_empty());
+
+        let tx = manager.tx.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            tx.send(OckamCommand::Channel(ChannelCommand::Stop))
+                .unwrap();
+        });
+        runtime.block_on(manager.run()).unwrap();
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
@@ -0,0 +1,487 @@
+//! The channel protocol as pure functions: given a channel's state and a message, they decide
+//! the channel's next state and what is to be done with the message, without doing any of it.
+//! Nothing here touches the router, the vault or the key exchange, so fuzzers and model
+//! checkers can drive the protocol directly, and executors other than the channel manager's
+//! poll loop can run it.
+
+use crate::compress;
+use crate::error::{ChannelError, ChannelErrorKind};
+use crate::CHANNEL_ZERO;
+use ockam_message::message::{Address, Codec, Message, MessageType, Route, RouterAddress};
+
+/// Which end of its key exchange a channel is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Sends M1 and M3
+    Initiator,
+    /// Sends M2
+    Responder,
+}
+
+/// Where a channel is in its key exchange
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// A responder waiting for M1
+    AwaitingM1,
+    /// An initiator which has sent M1, waiting for M2
+    AwaitingM2,
+    /// A responder which has sent M2, waiting for M3
+    AwaitingM3,
+    /// The key exchange is complete, and payloads can be exchanged
+    Established,
+}
+
+impl Phase {
+    /// The phase of a new channel, an initiator having sent M1
+    pub fn start(role: Role) -> Phase {
+        match role {
+            Role::Initiator => Phase::AwaitingM2,
+            Role::Responder => Phase::AwaitingM1,
+        }
+    }
+}
+
+/// What is done with a message a channel receives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReceiveStep {
+    /// Run the key exchange over the body, then send its next message, of this type, back
+    /// along the return route, if there is one. The exchange is complete once the channel is
+    /// `Established`.
+    KeyExchange(Option<MessageType>),
+    /// Decrypt the body and deliver the payload it holds
+    Decrypt,
+    /// Decrypt the body, a fragment of a payload, and deliver the payload once it is whole
+    Reassemble,
+    /// Decrypt the body of a heartbeat Ping, proving the other end sent it, and answer it with
+    /// a Pong
+    Pong,
+    /// Decrypt the body of a Pong, then tell the worker which initiated the channel that the
+    /// other end is there
+    NotifyAlive,
+    /// Decrypt the body, proving the other end sent it, then close the channel
+    Close,
+    /// Decrypt the body, the frames of a stream the other end has received, and stop sending
+    /// them again
+    Acknowledged,
+    /// Send the last key exchange message again, its answer having been retransmitted because
+    /// it was lost
+    Resend,
+    /// Drop the message
+    Drop,
+}
+
+/// What is done with a message sent into a channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendStep {
+    /// Encrypt the message and send it to the other end
+    Encrypt,
+    /// Hold the message until the key exchange completes
+    Hold,
+    /// Send an encrypted heartbeat Ping to the other end
+    Ping,
+    /// Drop the message
+    Drop,
+}
+
+/// The ciphertext address of the channel a received message is for, or `None` for an M1
+/// opening a new channel.
+pub fn addressee(m: &Message) -> Result<Option<String>, ChannelError> {
+    match m.onward_route.addresses.first() {
+        Some(a) if a.address.as_string() == CHANNEL_ZERO => Ok(None),
+        Some(a) => Ok(Some(a.address.as_string())),
+        // no onward route, so no way of telling which channel is to decrypt it
+        None => Err(ChannelErrorKind::RecvError.into()),
+    }
+}
+
+/// The option bit of an M1 offering to deflate payloads, and of an M2 accepting
+pub const OPTION_DEFLATE: u8 = 1;
+
+/// The option bit of an M1 offering to acknowledge frames and send those unacknowledged
+/// again, and of an M2 accepting
+pub const OPTION_RELIABLE: u8 = 2;
+
+/// The body of an M1: the id of the cipher suite the key exchange uses, so that the responder
+/// can answer with the same, and the options the initiator offers, followed by the key
+/// exchange's first message
+pub fn m1_body(cipher_suite_id: u8, options: u8, ka_m1: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + ka_m1.len());
+    body.push(cipher_suite_id);
+    body.push(options);
+    body.extend_from_slice(ka_m1);
+    body
+}
+
+/// The cipher suite id, options and key exchange message of an M1's body
+pub fn split_m1(body: &[u8]) -> Result<(u8, u8, &[u8]), ChannelError> {
+    match body {
+        [cipher_suite_id, options, ka_m1 @ ..] => Ok((*cipher_suite_id, *options, ka_m1)),
+        _ => Err(ChannelErrorKind::RecvError.into()),
+    }
+}
+
+/// The body of an M2: the options the responder accepts of those the initiator offered, then
+/// the key exchange message
+pub fn m2_body(options: u8, ka_m2: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(1 + ka_m2.len());
+    body.push(options);
+    body.extend_from_slice(ka_m2);
+    body
+}
+
+/// The options and key exchange message of an M2's body
+pub fn split_m2(body: &[u8]) -> Result<(u8, &[u8]), ChannelError> {
+    match body.split_first() {
+        Some((options, ka_m2)) => Ok((*options, ka_m2)),
+        None => Err(ChannelErrorKind::RecvError.into()),
+    }
+}
+
+/// The next phase of a channel receiving a message of this type, and what is done with it.
+/// The other end retransmits key exchange messages after the key exchange completed here: an M2
+/// is answered with M3 again, and an M3 dropped. Any other message out of turn, e.g. a payload,
+/// heartbeat or close on a channel without keys, is dropped, as it came from the network.
+pub fn receive(role: Role, phase: Phase, message_type: MessageType) -> (Phase, ReceiveStep) {
+    match (message_type, role, phase) {
+        (MessageType::KeyAgreementM1, Role::Responder, Phase::AwaitingM1) => (
+            Phase::AwaitingM3,
+            ReceiveStep::KeyExchange(Some(MessageType::KeyAgreementM2)),
+        ),
+        (MessageType::KeyAgreementM2, Role::Initiator, Phase::AwaitingM2) => (
+            Phase::Established,
+            ReceiveStep::KeyExchange(Some(MessageType::KeyAgreementM3)),
+        ),
+        (MessageType::KeyAgreementM3, Role::Responder, Phase::AwaitingM3) => {
+            (Phase::Established, ReceiveStep::KeyExchange(None))
+        }
+        (MessageType::KeyAgreementM2, Role::Initiator, Phase::Established) => {
+            (phase, ReceiveStep::Resend)
+        }
+        (MessageType::Payload, _, Phase::Established) => (phase, ReceiveStep::Decrypt),
+        (MessageType::Ping, _, Phase::Established) => (phase, ReceiveStep::Pong),
+        (MessageType::Pong, _, Phase::Established) => (phase, ReceiveStep::NotifyAlive),
+        (MessageType::Close, _, Phase::Established) => (phase, ReceiveStep::Close),
+        (MessageType::Fragment, _, Phase::Established) => (phase, ReceiveStep::Reassemble),
+        (MessageType::Ack, _, Phase::Established) => (phase, ReceiveStep::Acknowledged),
+        // a ChannelError or None is only ever passed to a worker on this node
+        _ => (phase, ReceiveStep::Drop),
+    }
+}
+
+/// What is done with a message of this type sent into a channel, `to_channel` if the channel is
+/// the last address of its onward route. A channel running its key exchange, for the first time
+/// or again, holds on to payloads until it completes. A message routed on past the channel is
+/// carried as a payload whatever its type, so that channels can be layered, a key exchange
+/// running through another channel; one for the channel itself must be a payload, or a Ping
+/// asking for a heartbeat.
+pub fn send(
+    phase: Phase,
+    message_type: MessageType,
+    to_channel: bool,
+) -> Result<SendStep, ChannelError> {
+    let carry = if phase == Phase::Established {
+        SendStep::Encrypt
+    } else {
+        SendStep::Hold
+    };
+    match message_type {
+        MessageType::None => Err(ChannelErrorKind::NotImplemented.into()),
+        MessageType::Ping if to_channel && phase == Phase::Established => Ok(SendStep::Ping),
+        MessageType::Ping if to_channel => Ok(SendStep::Drop),
+        MessageType::Payload => Ok(carry),
+        _ if to_channel => Err(ChannelErrorKind::NotImplemented.into()),
+        _ => Ok(carry),
+    }
+}
+
+/// The code of the `ChannelErrorKind` a `MessageType::ChannelError` carries, if it is one
+pub fn channel_error_code(m: &Message) -> Option<u32> {
+    if m.message_type != MessageType::ChannelError || m.message_body.len() != 4 {
+        return None;
+    }
+    let mut code = [0u8; 4];
+    code.copy_from_slice(&m.message_body);
+    Some(u32::from_le_bytes(code))
+}
+
+/// A message from the channel at `from` along `onward_route`, so that replies come back to it
+pub fn message_from(
+    from: Address,
+    onward_route: Route,
+    message_type: MessageType,
+    message_body: Vec<u8>,
+) -> Message {
+    Message {
+        onward_route,
+        return_route: Route {
+            addresses: vec![RouterAddress::from_address(from).unwrap()],
+        },
+        message_type,
+        message_body,
+    }
+}
+
+/// The message a decrypted payload holds, with the channel's cleartext address at the front
+/// of its return route so that replies go back through the channel. Fails with
+/// `MessageTooLarge` if its encoding is longer than `max` bytes.
+pub fn delivered(
+    plaintext: &[u8],
+    cleartext_address: Address,
+    max: usize,
+) -> Result<Message, ChannelError> {
+    let encoded = compress::decompress(plaintext, max)?;
+    let (mut m, _) = Message::decode(&encoded)?;
+    m.return_route
+        .addresses
+        .insert(0, RouterAddress::from_address(cleartext_address).unwrap());
+    Ok(m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_common::error::ErrorKind;
+
+    fn route(address: &str) -> Route {
+        Route {
+            addresses: vec![RouterAddress::channel_router_address_from_str(address).unwrap()],
+        }
+    }
+
+    #[test]
+    fn key_exchange() {
+        let mut initiator = Phase::start(Role::Initiator);
+        let mut responder = Phase::start(Role::Responder);
+
+        let (next, step) = receive(Role::Responder, responder, MessageType::KeyAgreementM1);
+        assert_eq!(
+            step,
+            ReceiveStep::KeyExchange(Some(MessageType::KeyAgreementM2))
+        );
+        responder = next;
+
+        let (next, step) = receive(Role::Initiator, initiator, MessageType::KeyAgreementM2);
+        assert_eq!(
+            step,
+            ReceiveStep::KeyExchange(Some(MessageType::KeyAgreementM3))
+        );
+        initiator = next;
+        assert_eq!(initiator, Phase::Established);
+
+        // payloads are dropped until the responder has M3
+        assert_eq!(
+            receive(Role::Responder, responder, MessageType::Payload),
+            (Phase::AwaitingM3, ReceiveStep::Drop)
+        );
+        let (next, step) = receive(Role::Responder, responder, MessageType::KeyAgreementM3);
+        assert_eq!(step, ReceiveStep::KeyExchange(None));
+        responder = next;
+        assert_eq!(responder, Phase::Established);
+
+        assert_eq!(
+            receive(Role::Responder, responder, MessageType::Payload),
+            (Phase::Established, ReceiveStep::Decrypt)
+        );
+        assert_eq!(
+            receive(Role::Initiator, initiator, MessageType::Ping),
+            (Phase::Established, ReceiveStep::Pong)
+        );
+        assert_eq!(
+            receive(Role::Responder, responder, MessageType::Pong),
+            (Phase::Established, ReceiveStep::NotifyAlive)
+        );
+        // heartbeats are encrypted, so a channel without keys can't answer them
+        assert_eq!(
+            receive(Role::Initiator, Phase::AwaitingM2, MessageType::Pong),
+            (Phase::AwaitingM2, ReceiveStep::Drop)
+        );
+
+        // only an established channel can be closed by the other end, which holds its keys
+        assert_eq!(
+            receive(Role::Initiator, initiator, MessageType::Close),
+            (Phase::Established, ReceiveStep::Close)
+        );
+        assert_eq!(
+            receive(Role::Responder, Phase::AwaitingM3, MessageType::Close),
+            (Phase::AwaitingM3, ReceiveStep::Drop)
+        );
+
+        // fragments are payloads, only received on an established channel
+        assert_eq!(
+            receive(Role::Responder, responder, MessageType::Fragment),
+            (Phase::Established, ReceiveStep::Reassemble)
+        );
+        assert_eq!(
+            receive(Role::Initiator, Phase::AwaitingM2, MessageType::Fragment),
+            (Phase::AwaitingM2, ReceiveStep::Drop)
+        );
+
+        // acks are encrypted too
+        assert_eq!(
+            receive(Role::Initiator, initiator, MessageType::Ack),
+            (Phase::Established, ReceiveStep::Acknowledged)
+        );
+        assert_eq!(
+            receive(Role::Responder, Phase::AwaitingM3, MessageType::Ack),
+            (Phase::AwaitingM3, ReceiveStep::Drop)
+        );
+
+        // a channel error is only for workers
+        assert_eq!(
+            receive(Role::Responder, responder, MessageType::ChannelError),
+            (Phase::Established, ReceiveStep::Drop)
+        );
+    }
+
+    #[test]
+    fn key_exchange_out_of_turn() {
+        for &(role, phase, message_type) in [
+            (
+                Role::Initiator,
+                Phase::AwaitingM2,
+                MessageType::KeyAgreementM1,
+            ),
+            (
+                Role::Initiator,
+                Phase::AwaitingM2,
+                MessageType::KeyAgreementM3,
+            ),
+            (
+                Role::Responder,
+                Phase::AwaitingM1,
+                MessageType::KeyAgreementM3,
+            ),
+            (
+                Role::Responder,
+                Phase::AwaitingM3,
+                MessageType::KeyAgreementM1,
+            ),
+            (
+                Role::Responder,
+                Phase::Established,
+                MessageType::KeyAgreementM1,
+            ),
+            (Role::Initiator, Phase::Established, MessageType::None),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                receive(role, phase, message_type),
+                (phase, ReceiveStep::Drop)
+            );
+        }
+
+        // an M2 retransmitted because M3 was lost is answered again, and a late M3 dropped
+        assert_eq!(
+            receive(
+                Role::Initiator,
+                Phase::Established,
+                MessageType::KeyAgreementM2
+            ),
+            (Phase::Established, ReceiveStep::Resend)
+        );
+        assert_eq!(
+            receive(
+                Role::Responder,
+                Phase::Established,
+                MessageType::KeyAgreementM3
+            ),
+            (Phase::Established, ReceiveStep::Drop)
+        );
+    }
+
+    #[test]
+    fn send_steps() {
+        assert_eq!(
+            send(Phase::Established, MessageType::Payload, false).unwrap(),
+            SendStep::Encrypt
+        );
+        assert_eq!(
+            send(Phase::AwaitingM2, MessageType::Payload, false).unwrap(),
+            SendStep::Hold
+        );
+        assert_eq!(
+            send(Phase::AwaitingM3, MessageType::Payload, true).unwrap(),
+            SendStep::Hold
+        );
+        assert_eq!(
+            send(Phase::Established, MessageType::Ping, true).unwrap(),
+            SendStep::Ping
+        );
+        assert_eq!(
+            send(Phase::AwaitingM2, MessageType::Ping, true).unwrap(),
+            SendStep::Drop
+        );
+        assert!(send(Phase::Established, MessageType::KeyAgreementM1, true).is_err());
+        assert!(send(Phase::Established, MessageType::None, false).is_err());
+
+        // another channel's messages are carried through this one
+        for message_type in &[
+            MessageType::KeyAgreementM1,
+            MessageType::Ping,
+            MessageType::Close,
+        ] {
+            assert_eq!(
+                send(Phase::Established, *message_type, false).unwrap(),
+                SendStep::Encrypt
+            );
+            assert_eq!(
+                send(Phase::AwaitingM2, *message_type, false).unwrap(),
+                SendStep::Hold
+            );
+        }
+    }
+
+    #[test]
+    fn addressing() {
+        let mut m = message_from(
+            Address::channel_address_from_string("01020304").unwrap(),
+            route(CHANNEL_ZERO),
+            MessageType::KeyAgreementM1,
+            m1_body(3, OPTION_DEFLATE, &[9, 9]),
+        );
+        assert_eq!(addressee(&m).unwrap(), None);
+        assert_eq!(
+            split_m1(&m.message_body).unwrap(),
+            (3, OPTION_DEFLATE, &[9, 9][..])
+        );
+        assert!(split_m1(&[3]).is_err());
+        assert_eq!(split_m2(&m2_body(0, &[8])).unwrap(), (0, &[8][..]));
+        assert!(split_m2(&[]).is_err());
+        m.onward_route = route("05060708");
+        assert_eq!(addressee(&m).unwrap(), Some("05060708".to_string()));
+        m.onward_route.addresses.clear();
+        assert!(addressee(&m).is_err());
+
+        let mut plaintext = vec![];
+        message_from(
+            Address::channel_address_from_string("01020304").unwrap(),
+            route("05060708"),
+            MessageType::Payload,
+            vec![1, 2, 3],
+        )
+        .encode(&mut plaintext)
+        .unwrap();
+        let m = delivered(
+            &compress::compress(&plaintext, None),
+            Address::channel_address_from_string("0a0b0c0d").unwrap(),
+            plaintext.len(),
+        )
+        .unwrap();
+        assert_eq!(m.return_route.addresses.len(), 2);
+        assert_eq!(m.return_route.addresses[0].address.as_string(), "0a0b0c0d");
+        assert_eq!(m.message_body, vec![1, 2, 3]);
+        assert_eq!(channel_error_code(&m), None);
+
+        let failed = message_from(
+            Address::channel_address_from_string("01020304").unwrap(),
+            route("05060708"),
+            MessageType::ChannelError,
+            ChannelErrorKind::HandshakeTimeout
+                .code()
+                .to_le_bytes()
+                .to_vec(),
+        );
+        assert_eq!(channel_error_code(&failed), Some(0x0800_000d));
+    }
+}
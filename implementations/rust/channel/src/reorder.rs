@@ -0,0 +1,232 @@
+//! Putting the payloads a channel receives on each of its streams back in the order they were
+//! sent. Transports like UDP may deliver them out of order, so each payload is held until those
+//! with lower nonces have arrived, or until the window or the longest delay has passed them by,
+//! in which case the missing ones are given up as lost. Payloads whose nonce has already been
+//! delivered or passed over are dropped, which also refuses replays. The nonces here are the
+//! sequence numbers frames carry on their stream, see `stream`, rather than the AEAD's.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// The nonces a channel may receive ahead of the next one due, by default
+pub const DEFAULT_WINDOW: u64 = 64;
+
+/// The longest a payload is held waiting for those before it, by default
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_millis(200);
+
+/// The counts of a channel's payloads which weren't delivered as they arrived
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    /// Payloads held until those sent before them arrived
+    pub reordered: u64,
+    /// Payloads dropped because they arrived after their nonce was delivered or passed over
+    pub late: u64,
+    /// Payloads dropped because one with the same nonce was already held
+    pub duplicate: u64,
+    /// Nonces passed over, their payloads never having arrived in time
+    pub lost: u64,
+}
+
+impl std::ops::AddAssign for ReorderStats {
+    fn add_assign(&mut self, other: ReorderStats) {
+        self.reordered += other.reordered;
+        self.late += other.late;
+        self.duplicate += other.duplicate;
+        self.lost += other.lost;
+    }
+}
+
+/// The reordering buffer of one stream of a channel, holding payloads of type `T` by nonce
+#[derive(Debug)]
+pub struct Reorder<T> {
+    // the lowest nonce not yet delivered or passed over
+    next: u64,
+    held: BTreeMap<u64, (T, Instant)>,
+    stats: ReorderStats,
+}
+
+impl<T> Default for Reorder<T> {
+    fn default() -> Self {
+        Reorder {
+            next: 0,
+            held: BTreeMap::new(),
+            stats: ReorderStats::default(),
+        }
+    }
+}
+
+impl<T> Reorder<T> {
+    /// Accept the payload with `nonce`, returning those now due for delivery, in order. A nonce
+    /// `window` or more ahead of the next one due moves the window on, giving up the nonces it
+    /// passes; a window of 1 delivers each payload as it arrives, dropping only those which are
+    /// late.
+    pub fn receive(&mut self, nonce: u64, payload: T, window: u64) -> Vec<T> {
+        if nonce < self.next {
+            self.stats.late += 1;
+            return vec![];
+        }
+        if self.held.contains_key(&nonce) {
+            self.stats.duplicate += 1;
+            return vec![];
+        }
+        self.held.insert(nonce, (payload, Instant::now()));
+        let mut due = vec![];
+        let floor = nonce.saturating_sub(window.max(1) - 1);
+        if floor > self.next {
+            self.skip_to(floor, &mut due);
+        }
+        self.release(&mut due);
+        if self.held.contains_key(&nonce) {
+            self.stats.reordered += 1;
+        }
+        due
+    }
+
+    /// Give up the nonces which have held a payload back for longer than `max_delay`, returning
+    /// the payloads then due, in order.
+    pub fn expire(&mut self, max_delay: Duration) -> Vec<T> {
+        let mut due = vec![];
+        while let Some((&nonce, (_, since))) = self.held.iter().next() {
+            if since.elapsed() < max_delay {
+                break;
+            }
+            self.skip_to(nonce, &mut due);
+            self.release(&mut due);
+        }
+        due
+    }
+
+    /// When the oldest held payload will have waited for `max_delay`, if any is held
+    pub fn next_expiry(&self, max_delay: Duration) -> Option<Instant> {
+        self.held
+            .values()
+            .map(|(_, since)| *since + max_delay)
+            .min()
+    }
+
+    /// Start again from nonce 0, as a channel does when it agrees new keys; anything held is
+    /// dropped. The counts are kept.
+    pub fn reset(&mut self) {
+        self.next = 0;
+        self.held.clear();
+    }
+
+    /// Start from `next`, the nonce due next when a channel's state was saved, so that none of
+    /// the nonces before it is delivered again.
+    pub fn starting_at(next: u64) -> Self {
+        Reorder {
+            next,
+            ..Reorder::default()
+        }
+    }
+
+    /// The lowest nonce not yet delivered or passed over
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+
+    /// The payloads held waiting for those before them
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    /// The counts of payloads which weren't delivered as they arrived
+    pub fn stats(&self) -> ReorderStats {
+        self.stats
+    }
+
+    // Deliver the held payloads below `nonce`, passing over the missing ones.
+    fn skip_to(&mut self, nonce: u64, due: &mut Vec<T>) {
+        let kept = self.held.split_off(&nonce);
+        let passed = std::mem::replace(&mut self.held, kept);
+        self.stats.lost += nonce - self.next - passed.len() as u64;
+        due.extend(passed.into_iter().map(|(_, (payload, _))| payload));
+        self.next = nonce;
+    }
+
+    // Deliver the held payloads which follow on from those delivered.
+    fn release(&mut self, due: &mut Vec<T>) {
+        while let Some((payload, _)) = self.held.remove(&self.next) {
+            due.push(payload);
+            self.next = self.next.saturating_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut r = Reorder::default();
+        for n in 0..5 {
+            assert_eq!(r.receive(n, n, DEFAULT_WINDOW), vec![n]);
+        }
+        assert_eq!(r.stats(), ReorderStats::default());
+    }
+
+    #[test]
+    fn reordered_within_window() {
+        let mut r = Reorder::default();
+        assert_eq!(r.receive(2, 2, 4), vec![]);
+        assert_eq!(r.receive(1, 1, 4), vec![]);
+        assert_eq!(r.receive(0, 0, 4), vec![0, 1, 2]);
+        assert_eq!(r.receive(3, 3, 4), vec![3]);
+        assert_eq!(r.held(), 0);
+        assert_eq!(r.stats().reordered, 2);
+    }
+
+    #[test]
+    fn late_and_duplicate() {
+        let mut r = Reorder::default();
+        assert_eq!(r.receive(0, 0, 4), vec![0]);
+        assert_eq!(r.receive(0, 0, 4), vec![]);
+        assert_eq!(r.receive(2, 2, 4), vec![]);
+        assert_eq!(r.receive(2, 2, 4), vec![]);
+        let stats = r.stats();
+        assert_eq!((stats.late, stats.duplicate), (1, 1));
+    }
+
+    #[test]
+    fn window_passes_missing() {
+        let mut r = Reorder::default();
+        assert_eq!(r.receive(1, 1, 4), vec![]);
+        // 0 never arrives: 5 moves the window on past it
+        assert_eq!(r.receive(5, 5, 4), vec![1]);
+        assert_eq!(r.receive(2, 2, 4), vec![2]);
+        assert_eq!(r.receive(0, 0, 4), vec![]);
+        let stats = r.stats();
+        assert_eq!((stats.lost, stats.late), (1, 1));
+        assert_eq!(r.held(), 1);
+
+        // a window of 1 delivers as payloads arrive
+        let mut r = Reorder::default();
+        assert_eq!(r.receive(3, 3, 1), vec![3]);
+        assert_eq!(r.receive(2, 2, 1), vec![]);
+        assert_eq!(r.stats().lost, 3);
+        assert_eq!(r.stats().reordered, 0);
+    }
+
+    #[test]
+    fn expire_and_reset() {
+        let mut r = Reorder::default();
+        r.receive(2, 2, 8);
+        r.receive(4, 4, 8);
+        assert_eq!(r.expire(Duration::from_secs(60)), vec![]);
+        assert!(r.next_expiry(Duration::from_secs(60)).is_some());
+        assert_eq!(r.expire(Duration::from_secs(0)), vec![2, 4]);
+        assert_eq!(r.stats().lost, 3);
+        assert_eq!(r.next_expiry(Duration::from_secs(0)), None);
+
+        r.receive(7, 7, 8);
+        r.reset();
+        assert_eq!(r.held(), 0);
+        assert_eq!(r.receive(0, 0, 8), vec![0]);
+
+        // a resumed channel doesn't deliver what it delivered before
+        let mut r = Reorder::starting_at(r.next());
+        assert_eq!(r.receive(0, 0, 8), vec![]);
+        assert_eq!(r.receive(1, 1, 8), vec![1]);
+    }
+}
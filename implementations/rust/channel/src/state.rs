@@ -0,0 +1,358 @@
+//! The saved state of established channels, so that a node which restarts can resume them
+//! without new key exchanges. A channel's state holds the handles of its keys in the vault, not
+//! the keys, so it can only be resumed with a vault that persists them, as the filesystem
+//! vault does once `ChannelManager::suspend` has made them persistent.
+//!
+//! A channel must not send once its state is saved, since resuming it sends from the saved
+//! nonce again; the state of a channel is saved as its node stops, and should be resumed once.
+
+use crate::error::{ChannelError, ChannelErrorKind};
+use crate::protocol::Role;
+use crate::stream::Sending;
+use ockam_kex::{nonce_salts, Aead, CompletedKeyExchange};
+use ockam_message::message::{Codec, Route};
+use ockam_vault::types::{PublicKey, SecretKeyContext};
+
+// Changed whenever the encoding does, so that a node doesn't resume state it can't read.
+const VERSION: u8 = 6;
+
+/// The state of an established channel, as saved by `ChannelManager::suspend`
+#[derive(Clone, Debug)]
+pub struct ChannelState {
+    pub(crate) cleartext_address: u32,
+    pub(crate) ciphertext_address: u32,
+    pub(crate) role: Role,
+    pub(crate) cipher_suite_id: u8,
+    pub(crate) route: Route,
+    pub(crate) initiate_route: Route,
+    // the worker the channel was announced to, if this node initiated it
+    pub(crate) owner: Option<Route>,
+    // the nonce of the next frame sent, and one past the highest received
+    pub(crate) send_nonce: u64,
+    pub(crate) recv_nonce: u64,
+    // whether both ends agreed to deflate payloads
+    pub(crate) compress: bool,
+    // whether both ends agreed to acknowledge frames; those unacknowledged aren't saved
+    pub(crate) reliable: bool,
+    // the streams sent on, and the lowest sequence number which may still be received on each
+    // stream received on
+    pub(crate) sending: Sending,
+    pub(crate) received: Vec<(u32, u64)>,
+    pub(crate) completed_key_exchange: CompletedKeyExchange,
+}
+
+impl ChannelState {
+    /// The channel's cleartext address, which workers send payloads to
+    pub fn address(&self) -> String {
+        hex::encode(self.cleartext_address.to_le_bytes())
+    }
+
+    /// The end of the key exchange this node was
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The route the channel was initiated over, as given to `ChannelCommand::Initiate`
+    pub fn initiate_route(&self) -> Route {
+        let mut route = self.initiate_route.clone();
+        // M1 was sent on to channel address zero at the end of the route
+        route.addresses.pop();
+        route
+    }
+
+    /// The route to the worker the channel was announced to, if this node initiated it
+    pub fn owner(&self) -> Option<&Route> {
+        self.owner.as_ref()
+    }
+
+    /// Append the encoded state to `v`
+    pub fn encode(&self, v: &mut Vec<u8>) -> Result<(), ChannelError> {
+        let cke = &self.completed_key_exchange;
+        v.push(VERSION);
+        v.extend_from_slice(&self.cleartext_address.to_le_bytes());
+        v.extend_from_slice(&self.ciphertext_address.to_le_bytes());
+        v.push(match self.role {
+            Role::Initiator => 0,
+            Role::Responder => 1,
+        });
+        v.push(self.cipher_suite_id);
+        self.route.encode(v)?;
+        self.initiate_route.encode(v)?;
+        match &self.owner {
+            Some(owner) => {
+                v.push(1);
+                owner.encode(v)?;
+            }
+            None => v.push(0),
+        }
+        v.extend_from_slice(&self.send_nonce.to_le_bytes());
+        v.extend_from_slice(&self.recv_nonce.to_le_bytes());
+        v.push(self.compress as u8);
+        v.push(self.reliable as u8);
+        v.extend_from_slice(&(self.sending.senders.len() as u32).to_le_bytes());
+        for (sender, stream) in &self.sending.senders {
+            v.extend_from_slice(&(sender.len() as u32).to_le_bytes());
+            v.extend_from_slice(sender.as_bytes());
+            v.extend_from_slice(&stream.to_le_bytes());
+        }
+        v.extend_from_slice(&(self.sending.next.len() as u32).to_le_bytes());
+        for (stream, next) in &self.sending.next {
+            v.extend_from_slice(&stream.to_le_bytes());
+            v.extend_from_slice(&next.to_le_bytes());
+        }
+        v.extend_from_slice(&(self.received.len() as u32).to_le_bytes());
+        for (stream, next) in &self.received {
+            v.extend_from_slice(&stream.to_le_bytes());
+            v.extend_from_slice(&next.to_le_bytes());
+        }
+        v.push(match cke.aead {
+            Aead::AesGcm => 0,
+            Aead::ChaCha20Poly1305 => 1,
+        });
+        v.extend_from_slice(&cke.h);
+        for key in &[cke.encrypt_key, cke.decrypt_key, cke.local_static_secret] {
+            match key {
+                SecretKeyContext::Memory(id) => v.extend_from_slice(&(*id as u64).to_le_bytes()),
+                _ => return Err(ChannelErrorKind::BadChannelState.into()),
+            }
+        }
+        match cke.remote_static_public_key {
+            PublicKey::Curve25519(key) => {
+                v.push(0);
+                v.extend_from_slice(&key);
+            }
+            PublicKey::P256(key) => {
+                v.push(1);
+                v.extend_from_slice(&key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode a state, returning it and the bytes which follow it
+    pub fn decode(s: &[u8]) -> Result<(ChannelState, &[u8]), ChannelError> {
+        let (version, s) = take(s, 1)?;
+        if version[0] != VERSION {
+            return Err(ChannelErrorKind::BadChannelState.into());
+        }
+        let (cleartext_address, s) = take_u32(s)?;
+        let (ciphertext_address, s) = take_u32(s)?;
+        let (role, s) = take(s, 1)?;
+        let role = match role[0] {
+            0 => Role::Initiator,
+            1 => Role::Responder,
+            _ => return Err(ChannelErrorKind::BadChannelState.into()),
+        };
+        let (cipher_suite_id, s) = take(s, 1)?;
+        let cipher_suite_id = cipher_suite_id[0];
+        let (route, s) = Route::decode(s)?;
+        let (initiate_route, s) = Route::decode(s)?;
+        let (has_owner, s) = take(s, 1)?;
+        let (owner, s) = match has_owner[0] {
+            0 => (None, s),
+            1 => {
+                let (owner, s) = Route::decode(s)?;
+                (Some(owner), s)
+            }
+            _ => return Err(ChannelErrorKind::BadChannelState.into()),
+        };
+        let (send_nonce, s) = take_u64(s)?;
+        let (recv_nonce, s) = take_u64(s)?;
+        let (compress, s) = take_bool(s)?;
+        let (reliable, s) = take_bool(s)?;
+        let mut sending = Sending::default();
+        let (senders, mut s) = take_u32(s)?;
+        for _ in 0..senders {
+            let (len, rest) = take_u32(s)?;
+            let (sender, rest) = take(rest, len as usize)?;
+            let sender = String::from_utf8(sender.to_vec())
+                .map_err(|_| ChannelError::from(ChannelErrorKind::BadChannelState))?;
+            let (stream, rest) = take_u32(rest)?;
+            sending.senders.insert(sender, stream);
+            s = rest;
+        }
+        sending.next = take_streams(&mut s)?.into_iter().collect();
+        let received = take_streams(&mut s)?;
+        let (aead, s) = take(s, 1)?;
+        let aead = match aead[0] {
+            0 => Aead::AesGcm,
+            1 => Aead::ChaCha20Poly1305,
+            _ => return Err(ChannelErrorKind::BadChannelState.into()),
+        };
+        let (h, s) = take(s, 32)?;
+        let (encrypt_key, s) = take_u64(s)?;
+        let (decrypt_key, s) = take_u64(s)?;
+        let (local_static_secret, s) = take_u64(s)?;
+        let (key_type, s) = take(s, 1)?;
+        let (remote_static_public_key, s) = match key_type[0] {
+            0 => {
+                let (key, s) = take(s, 32)?;
+                let mut k = [0u8; 32];
+                k.copy_from_slice(key);
+                (PublicKey::Curve25519(k), s)
+            }
+            1 => {
+                let (key, s) = take(s, 65)?;
+                let mut k = [0u8; 65];
+                k.copy_from_slice(key);
+                (PublicKey::P256(k), s)
+            }
+            _ => return Err(ChannelErrorKind::BadChannelState.into()),
+        };
+
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(h);
+        // the salts follow from the state hash, so aren't saved
+        let (encrypt_salt, decrypt_salt) = nonce_salts(&hash, role == Role::Initiator);
+        let state = ChannelState {
+            cleartext_address,
+            ciphertext_address,
+            role,
+            cipher_suite_id,
+            route,
+            initiate_route,
+            owner,
+            send_nonce,
+            recv_nonce,
+            compress,
+            reliable,
+            sending,
+            received,
+            completed_key_exchange: CompletedKeyExchange {
+                h: hash,
+                encrypt_key: SecretKeyContext::Memory(encrypt_key as usize),
+                decrypt_key: SecretKeyContext::Memory(decrypt_key as usize),
+                local_static_secret: SecretKeyContext::Memory(local_static_secret as usize),
+                remote_static_public_key,
+                aead,
+                encrypt_salt,
+                decrypt_salt,
+            },
+        };
+        Ok((state, s))
+    }
+}
+
+fn take(s: &[u8], n: usize) -> Result<(&[u8], &[u8]), ChannelError> {
+    if s.len() < n {
+        return Err(ChannelErrorKind::BadChannelState.into());
+    }
+    Ok(s.split_at(n))
+}
+
+fn take_u32(s: &[u8]) -> Result<(u32, &[u8]), ChannelError> {
+    let (bytes, s) = take(s, 4)?;
+    let mut b = [0u8; 4];
+    b.copy_from_slice(bytes);
+    Ok((u32::from_le_bytes(b), s))
+}
+
+fn take_u64(s: &[u8]) -> Result<(u64, &[u8]), ChannelError> {
+    let (bytes, s) = take(s, 8)?;
+    let mut b = [0u8; 8];
+    b.copy_from_slice(bytes);
+    Ok((u64::from_le_bytes(b), s))
+}
+
+fn take_bool(s: &[u8]) -> Result<(bool, &[u8]), ChannelError> {
+    let (byte, s) = take(s, 1)?;
+    match byte[0] {
+        0 => Ok((false, s)),
+        1 => Ok((true, s)),
+        _ => Err(ChannelErrorKind::BadChannelState.into()),
+    }
+}
+
+// A count of streams and the next sequence number on each, moving `s` on past them
+fn take_streams(s: &mut &[u8]) -> Result<Vec<(u32, u64)>, ChannelError> {
+    let (n, mut rest) = take_u32(s)?;
+    let mut streams = vec![];
+    for _ in 0..n {
+        let (stream, r) = take_u32(rest)?;
+        let (next, r) = take_u64(r)?;
+        streams.push((stream, next));
+        rest = r;
+    }
+    *s = rest;
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_message::message::RouterAddress;
+
+    #[test]
+    fn encode_decode() {
+        let state = ChannelState {
+            cleartext_address: 0x0102_0304,
+            ciphertext_address: 7,
+            role: Role::Initiator,
+            cipher_suite_id: 3,
+            route: Route {
+                addresses: vec![
+                    RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap()
+                ],
+            },
+            initiate_route: Route { addresses: vec![] },
+            owner: Some(Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str("00000001").unwrap()],
+            }),
+            send_nonce: 42,
+            recv_nonce: 17,
+            compress: true,
+            reliable: true,
+            sending: Sending::default(),
+            received: vec![(0, 9), (2, 4)],
+            completed_key_exchange: CompletedKeyExchange {
+                h: [3; 32],
+                encrypt_key: SecretKeyContext::Memory(5),
+                decrypt_key: SecretKeyContext::Memory(6),
+                local_static_secret: SecretKeyContext::Memory(1),
+                remote_static_public_key: PublicKey::Curve25519([8; 32]),
+                aead: Aead::ChaCha20Poly1305,
+                encrypt_salt: [3; 4],
+                decrypt_salt: [3; 4],
+            },
+        };
+        let mut state = state;
+        let stream = state.sending.stream_of(Some("00000001".into()));
+        state.sending.take(stream);
+        let mut v = vec![];
+        state.encode(&mut v).unwrap();
+        state.encode(&mut v).unwrap();
+
+        // states are self-delimiting, so several can be saved one after another
+        let (decoded, rest) = ChannelState::decode(&v).unwrap();
+        let (_, rest) = ChannelState::decode(rest).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.address(), "04030201");
+        assert_eq!(decoded.ciphertext_address, 7);
+        assert_eq!(decoded.role(), Role::Initiator);
+        assert_eq!(decoded.cipher_suite_id, 3);
+        assert_eq!(decoded.route.addresses, state.route.addresses);
+        assert!(decoded.initiate_route().addresses.is_empty());
+        assert_eq!(
+            decoded.owner().unwrap().addresses,
+            state.owner.as_ref().unwrap().addresses
+        );
+        assert_eq!((decoded.send_nonce, decoded.recv_nonce), (42, 17));
+        assert!(decoded.compress && decoded.reliable);
+        assert_eq!(decoded.received, vec![(0, 9), (2, 4)]);
+        let mut sending = decoded.sending.clone();
+        assert_eq!(sending.stream_of(Some("00000001".into())), 1);
+        assert_eq!(sending.peek(1), 1);
+        let cke = decoded.completed_key_exchange;
+        assert_eq!(cke.h, [3; 32]);
+        assert_eq!(cke.encrypt_key, SecretKeyContext::Memory(5));
+        assert_eq!(cke.decrypt_key, SecretKeyContext::Memory(6));
+        assert_eq!(cke.remote_static_public_key.as_ref(), &[8; 32][..]);
+        assert_eq!(cke.aead, Aead::ChaCha20Poly1305);
+        assert_eq!((cke.encrypt_salt, cke.decrypt_salt), ([3; 4], [3; 4]));
+
+        // a truncated or unknown state isn't resumed
+        assert!(ChannelState::decode(&v[..v.len() / 2 - 1]).is_err());
+        v[0] = 0;
+        assert!(ChannelState::decode(&v).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+//! Logical streams multiplexed over one channel, so that the workers sharing a channel don't
+//! wait on each other's lost payloads. Each frame a channel encrypts starts with the id of its
+//! stream and its sequence number within the stream, and the other end puts each stream's
+//! frames back in order on their own: a stream carries on while another waits for a frame which
+//! went missing. The sending channel manager opens a stream for each worker which sends into
+//! the channel; stream 0 carries control messages, and payloads with no return address.
+
+use std::collections::BTreeMap;
+
+/// The bytes of the header before each frame's body: its stream id and sequence number
+pub const HEADER_LEN: usize = 12;
+
+/// The streams a channel receives on at most; frames on any more are dropped
+pub const MAX_STREAMS: usize = 256;
+
+/// The plaintext of the frame `seq` of `stream`, carrying `body`
+pub fn frame(stream: u32, seq: u64, body: &[u8]) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(HEADER_LEN + body.len());
+    plaintext.extend_from_slice(&stream.to_le_bytes());
+    plaintext.extend_from_slice(&seq.to_le_bytes());
+    plaintext.extend_from_slice(body);
+    plaintext
+}
+
+/// The stream, sequence number and body of a frame's plaintext, or `None` if it is too short
+/// to have a header
+pub fn unframe(plaintext: &[u8]) -> Option<(u32, u64, &[u8])> {
+    if plaintext.len() < HEADER_LEN {
+        return None;
+    }
+    let mut stream = [0u8; 4];
+    stream.copy_from_slice(&plaintext[..4]);
+    let mut seq = [0u8; 8];
+    seq.copy_from_slice(&plaintext[4..HEADER_LEN]);
+    Some((
+        u32::from_le_bytes(stream),
+        u64::from_le_bytes(seq),
+        &plaintext[HEADER_LEN..],
+    ))
+}
+
+/// The streams a channel sends on: the stream of each worker which has sent into it, and the
+/// sequence number of the next frame on each
+#[derive(Clone, Debug, Default)]
+pub struct Sending {
+    // stream ids by the address of their worker, never reused while the channel's keys last
+    pub(crate) senders: BTreeMap<String, u32>,
+    pub(crate) next: BTreeMap<u32, u64>,
+}
+
+impl Sending {
+    /// The stream of the worker at `sender`, opening one if it hasn't sent before; stream 0
+    /// without one, or once as many streams are open as the other end receives on
+    pub fn stream_of(&mut self, sender: Option<String>) -> u32 {
+        let sender = match sender {
+            Some(sender) => sender,
+            None => return 0,
+        };
+        if let Some(stream) = self.senders.get(&sender) {
+            return *stream;
+        }
+        if self.senders.len() + 1 >= MAX_STREAMS {
+            return 0;
+        }
+        let opened = self.senders.len() as u32 + 1;
+        self.senders.insert(sender, opened);
+        opened
+    }
+
+    /// The sequence number of the next frame on `stream`
+    pub fn peek(&self, stream: u32) -> u64 {
+        self.next.get(&stream).copied().unwrap_or(0)
+    }
+
+    /// Take the sequence number of the next frame on `stream`
+    pub fn take(&mut self, stream: u32) -> u64 {
+        let next = self.next.entry(stream).or_insert(0);
+        let seq = *next;
+        *next += 1;
+        seq
+    }
+
+    /// Start every stream again from sequence number 0, as a channel does when it agrees new
+    /// keys. Workers keep their streams.
+    pub fn reset(&mut self) {
+        self.next.clear();
+    }
+
+    /// The streams opened
+    pub fn streams(&self) -> usize {
+        self.senders.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_unframe() {
+        let plaintext = frame(3, 9, &[1, 2]);
+        assert_eq!(plaintext.len(), HEADER_LEN + 2);
+        assert_eq!(unframe(&plaintext), Some((3, 9, &[1, 2][..])));
+        assert_eq!(unframe(&frame(0, 0, &[])), Some((0, 0, &[][..])));
+        assert_eq!(unframe(&plaintext[..HEADER_LEN - 1]), None);
+    }
+
+    #[test]
+    fn streams_by_sender() {
+        let mut s = Sending::default();
+        assert_eq!(s.stream_of(None), 0);
+        assert_eq!(s.stream_of(Some("0a".into())), 1);
+        assert_eq!(s.stream_of(Some("0b".into())), 2);
+        assert_eq!(s.stream_of(Some("0a".into())), 1);
+        assert_eq!(s.streams(), 2);
+
+        assert_eq!((s.take(1), s.take(1), s.take(2)), (0, 1, 0));
+        assert_eq!((s.peek(1), s.peek(0)), (2, 0));
+        s.reset();
+        assert_eq!(s.take(1), 0);
+        assert_eq!(s.stream_of(Some("0b".into())), 2);
+
+        for i in 0..MAX_STREAMS {
+            s.stream_of(Some(i.to_string()));
+        }
+        assert_eq!(s.streams(), MAX_STREAMS - 1);
+        assert_eq!(s.stream_of(Some("0c".into())), 0);
+    }
+}
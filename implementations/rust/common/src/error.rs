@@ -1,4 +1,157 @@
+//! The error model shared by the crates.
+//!
+//! Each crate has an error kind enum, marked `#[non_exhaustive]` so kinds can be added without
+//! breaking its users, and an error, declared with `error_impl!`, which wraps a kind together
+//! with the message or the error from another crate that caused it. Every kind has a code that
+//! never changes once released: its crate's interface in the top byte, and its own number below.
+//! These are the codes the FFI layers return.
+
+/// The interface of the vault's error codes
+pub const ERROR_INTERFACE_VAULT: usize = 3 << 24;
+/// The interface of the key exchange's error codes
+pub const ERROR_INTERFACE_KEX: usize = 5 << 24;
+/// The interface of the channel's error codes
+pub const ERROR_INTERFACE_CHANNEL: usize = 8 << 24;
+/// The interface of the message codec's error codes
+pub const ERROR_INTERFACE_MESSAGE: usize = 9 << 24;
+/// The interface of the router's error codes
+pub const ERROR_INTERFACE_ROUTER: usize = 10 << 24;
+/// The interface of the credentials' error codes
+pub const ERROR_INTERFACE_CREDENTIAL: usize = 11 << 24;
+/// The interface of the identities' error codes
+pub const ERROR_INTERFACE_IDENTITY: usize = 12 << 24;
+
+/// A kind of error, with its code
 pub trait ErrorKind {
     const ERROR_INTERFACE: usize;
     fn to_usize(&self) -> usize;
+
+    /// The stable code of the kind, as returned across the FFI
+    fn code(&self) -> u32 {
+        self.to_usize() as u32
+    }
+}
+
+/// Declare the error wrapping an error kind, with the conversions, chaining and display every
+/// crate's error has. The crate must depend on `failure` and the kind implement `ErrorKind`,
+/// `Fail` and `Clone`.
+#[macro_export]
+macro_rules! error_impl {
+    ($err:ident, $kind:ident) => {
+        /// Wraps an error kind with context and backtrace logic
+        #[derive(Debug)]
+        pub struct $err {
+            inner: failure::Context<$kind>,
+        }
+
+        impl $err {
+            /// Convert from an error kind and a static string
+            pub fn from_msg<D>(kind: $kind, msg: D) -> Self
+            where
+                D: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
+            {
+                Self {
+                    inner: failure::Fail::context(failure::Context::new(msg), kind),
+                }
+            }
+
+            /// Convert from an error kind and the error which caused it, kept as its cause
+            pub fn from_source<F: failure::Fail>(kind: $kind, source: F) -> Self {
+                Self {
+                    inner: failure::Fail::context(source, kind),
+                }
+            }
+
+            /// The kind of the error
+            pub fn kind(&self) -> &$kind {
+                self.inner.get_context()
+            }
+
+            /// The stable code of the error, as returned across the FFI
+            pub fn code(&self) -> u32 {
+                $crate::error::ErrorKind::code(self.kind())
+            }
+
+            /// Convert to an integer, reused in From trait implementations
+            pub fn to_usize(&self) -> usize {
+                $crate::error::ErrorKind::to_usize(self.kind())
+            }
+        }
+
+        impl From<$kind> for $err {
+            fn from(kind: $kind) -> Self {
+                Self {
+                    inner: failure::Fail::context(failure::Context::new(""), kind),
+                }
+            }
+        }
+
+        impl From<$err> for $kind {
+            fn from(err: $err) -> Self {
+                err.inner.get_context().clone()
+            }
+        }
+
+        impl From<failure::Context<$kind>> for $err {
+            fn from(inner: failure::Context<$kind>) -> Self {
+                Self { inner }
+            }
+        }
+
+        impl failure::Fail for $err {
+            fn cause(&self) -> Option<&dyn failure::Fail> {
+                failure::Fail::cause(&self.inner)
+            }
+
+            fn backtrace(&self) -> Option<&failure::Backtrace> {
+                failure::Fail::backtrace(&self.inner)
+            }
+        }
+
+        impl std::fmt::Display for $err {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                let mut first = true;
+
+                for cause in failure::Fail::iter_chain(&self.inner) {
+                    if first {
+                        first = false;
+                        writeln!(f, "Error: {}", cause)?;
+                    } else {
+                        writeln!(f, "Caused by: {}", cause)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        // for the code which still reports errors as strings: the kind, and the message it was
+        // made with, if any; the kind of an error from another crate is in its own kind
+        impl From<$err> for String {
+            fn from(err: $err) -> Self {
+                let mut s = err.kind().to_string();
+                let msg = failure::Fail::cause(&err).and_then(|cause| {
+                    cause
+                        .downcast_ref::<failure::Context<&'static str>>()
+                        .map(|c| c.get_context().to_string())
+                        .or_else(|| {
+                            cause
+                                .downcast_ref::<failure::Context<String>>()
+                                .map(|c| c.get_context().clone())
+                        })
+                });
+                if let Some(msg) = msg.filter(|msg| !msg.is_empty()) {
+                    s.push_str(": ");
+                    s.push_str(&msg);
+                }
+                s
+            }
+        }
+
+        $crate::from_int_impl!($err, u32);
+        $crate::from_int_impl!($err, u64);
+        $crate::from_int_impl!($err, u128);
+        $crate::from_int_impl!($kind, u32);
+        $crate::from_int_impl!($kind, u64);
+        $crate::from_int_impl!($kind, u128);
+    };
 }
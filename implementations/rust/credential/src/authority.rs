@@ -0,0 +1,44 @@
+use crate::error::CredentialError;
+use crate::{Attributes, Credential, Schema};
+use ockam_identity::Identity;
+
+/// Issues credentials, signing them with the current key of its identity. Any node can be an
+/// authority; peers decide which authorities' public keys they trust.
+#[derive(Debug)]
+pub struct Authority {
+    identity: Identity,
+}
+
+impl Authority {
+    pub fn new(identity: Identity) -> Authority {
+        Authority { identity }
+    }
+
+    /// The public key peers trust to accept the authority's credentials
+    pub fn public_key(&self) -> &[u8] {
+        self.identity.public_key()
+    }
+
+    /// Attest that the party holding the static public key `subject` has `attributes`, which
+    /// must be those of `schema`, until `expires`, in seconds since the Unix epoch
+    pub fn issue(
+        &self,
+        schema: &Schema,
+        subject: &[u8],
+        attributes: Attributes,
+        expires: u64,
+    ) -> Result<Credential, CredentialError> {
+        schema.check(&attributes)?;
+        let mut credential = Credential {
+            schema: schema.id.clone(),
+            issuer: self.public_key().to_vec(),
+            subject: subject.to_vec(),
+            expires,
+            attributes,
+            signature: vec![],
+        };
+        let signature = self.identity.sign(&credential.signed_data()?)?;
+        credential.signature = signature.to_vec();
+        Ok(credential)
+    }
+}
@@ -0,0 +1,430 @@
+use crate::error::{CredentialError, CredentialErrorKind};
+use crate::{decode_bytes, encode_bytes, Attributes, Authority, Credential, Schema};
+use ockam_message::message::{AddressType, Message, MessageType, Receiver, Route, RouterAddress};
+use ockam_vault::DynVault;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the version of the enrollment encodings, their first byte
+const ENROLLMENT_VERSION: u8 = 1;
+const TOKEN_LEN: usize = 16;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// A one-time secret the registrar hands out of band, e.g. printed on a device's label, which
+/// enrolls the first party to present it
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Token([u8; TOKEN_LEN]);
+
+impl Token {
+    /// A new random token
+    pub fn generate(vault: &mut dyn DynVault) -> Result<Token, CredentialError> {
+        let mut token = [0; TOKEN_LEN];
+        vault.random(&mut token)?;
+        Ok(Token(token))
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::str::FromStr for Token {
+    type Err = CredentialError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| CredentialErrorKind::Malformed)?;
+        if bytes.len() != TOKEN_LEN {
+            return Err(CredentialErrorKind::Malformed.into());
+        }
+        let mut token = [0; TOKEN_LEN];
+        token.copy_from_slice(&bytes);
+        Ok(Token(token))
+    }
+}
+
+/// What a device receives for its token: a credential for the key of the channel it enrolled
+/// over, and the public key of the authority which issued it, for the device to trust in turn
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Enrollment {
+    pub authority: Vec<u8>,
+    pub credential: Credential,
+}
+
+impl Enrollment {
+    pub fn encode(&self) -> Result<Vec<u8>, CredentialError> {
+        let mut v = vec![ENROLLMENT_VERSION];
+        encode_bytes(&self.authority, &mut v)?;
+        v.extend_from_slice(&self.credential.encode()?);
+        Ok(v)
+    }
+
+    pub fn decode(u: &[u8]) -> Result<Enrollment, CredentialError> {
+        if u.first() != Some(&ENROLLMENT_VERSION) {
+            return Err(CredentialErrorKind::Malformed.into());
+        }
+        let (authority, u) = decode_bytes(&u[1..])?;
+        Ok(Enrollment {
+            authority: authority.to_vec(),
+            credential: Credential::decode(u)?,
+        })
+    }
+}
+
+/// A payload presenting `token` to the registrar at `registrar`, over the channel at `channel`.
+/// The enrollment is sent back to the worker at `reply_to`.
+pub fn enrollment_request(
+    channel: RouterAddress,
+    registrar: RouterAddress,
+    reply_to: RouterAddress,
+    token: &Token,
+) -> Message {
+    let mut body = vec![ENROLLMENT_VERSION];
+    body.extend_from_slice(&token.0);
+    Message {
+        onward_route: Route {
+            addresses: vec![channel, registrar],
+        },
+        return_route: Route {
+            addresses: vec![reply_to],
+        },
+        message_type: MessageType::Payload,
+        message_body: body,
+    }
+}
+
+fn decode_request(u: &[u8]) -> Result<Token, CredentialError> {
+    if u.len() != 1 + TOKEN_LEN || u[0] != ENROLLMENT_VERSION {
+        return Err(CredentialErrorKind::Malformed.into());
+    }
+    let mut token = [0; TOKEN_LEN];
+    token.copy_from_slice(&u[1..]);
+    Ok(Token(token))
+}
+
+// what an outstanding token enrolls its holder with
+#[derive(Clone, Debug)]
+struct Grant {
+    schema: Schema,
+    attributes: Attributes,
+    // when the token can no longer be used, in seconds since the Unix epoch
+    valid_until: u64,
+    // when the credential issued for it expires
+    expires: u64,
+}
+
+/// The authority's side of enrollment: it hands out tokens, each granting some attributes, and
+/// is the worker devices present them to over channels. Like a [`Verifier`], it must be
+/// registered where the channel manager announces channels, so that it knows the static public
+/// key to issue each credential to. A token is used up by the first request carrying it.
+///
+/// [`Verifier`]: crate::Verifier
+pub struct Registrar {
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+    authority: Authority,
+    tokens: BTreeMap<Token, Grant>,
+    // the remote static public key of each channel announced, by the channel's address
+    channels: BTreeMap<String, Vec<u8>>,
+}
+
+impl std::fmt::Debug for Registrar {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Registrar {{ authority: {:?}, tokens: {}, vault }}",
+            self.authority,
+            self.tokens.len()
+        )
+    }
+}
+
+impl Registrar {
+    pub fn new(vault: Arc<Mutex<dyn DynVault + Send>>, authority: Authority) -> Registrar {
+        Registrar {
+            vault,
+            authority,
+            tokens: BTreeMap::new(),
+            channels: BTreeMap::new(),
+        }
+    }
+
+    /// A new token, usable until `valid_until`, which enrolls its holder with `attributes`
+    /// under `schema` until `expires`; both are in seconds since the Unix epoch
+    pub fn issue_token(
+        &mut self,
+        schema: &Schema,
+        attributes: Attributes,
+        valid_until: u64,
+        expires: u64,
+    ) -> Result<Token, CredentialError> {
+        schema.check(&attributes)?;
+        let token = Token::generate(&mut *self.vault.lock().unwrap())?;
+        self.tokens.insert(
+            token,
+            Grant {
+                schema: schema.clone(),
+                attributes,
+                valid_until,
+                expires,
+            },
+        );
+        Ok(token)
+    }
+
+    /// Withdraw a token which hasn't been used yet. Returns whether it was outstanding.
+    pub fn revoke_token(&mut self, token: &Token) -> bool {
+        self.tokens.remove(token).is_some()
+    }
+
+    /// The tokens which haven't been used or revoked
+    pub fn outstanding(&self) -> impl Iterator<Item = &Token> {
+        self.tokens.keys()
+    }
+
+    /// Use up `token` to enroll the party at the other end of the channel at `channel`
+    pub fn enroll(&mut self, channel: &str, token: &Token) -> Result<Enrollment, CredentialError> {
+        let subject = self
+            .channels
+            .get(channel)
+            .ok_or(CredentialErrorKind::NoChannel)?;
+        let grant = self
+            .tokens
+            .remove(token)
+            .ok_or(CredentialErrorKind::Token)?;
+        if now() > grant.valid_until {
+            return Err(CredentialErrorKind::Token.into());
+        }
+        let credential =
+            self.authority
+                .issue(&grant.schema, subject, grant.attributes, grant.expires)?;
+        Ok(Enrollment {
+            authority: self.authority.public_key().to_vec(),
+            credential,
+        })
+    }
+}
+
+impl Receiver for Registrar {
+    fn recv(&mut self, m: Message) -> Result<Option<Message>, String> {
+        let channel = match m.return_route.addresses.first() {
+            Some(a) if a.a_type == AddressType::Channel => a.address.as_string(),
+            _ => {
+                return Err(String::from(CredentialError::from(
+                    CredentialErrorKind::NoChannel,
+                )))
+            }
+        };
+        match m.message_type {
+            // a channel is up, with the remote static public key as the body
+            MessageType::None => {
+                self.channels.insert(channel, m.message_body);
+                Ok(None)
+            }
+            MessageType::Payload => {
+                let token = decode_request(&m.message_body)?;
+                let enrollment = self.enroll(&channel, &token)?;
+                Ok(Some(Message {
+                    onward_route: m.return_route,
+                    return_route: Route { addresses: vec![] },
+                    message_type: MessageType::Payload,
+                    message_body: enrollment.encode()?,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The device's side of enrollment: the worker the registrar's reply is sent to. It keeps the
+/// enrollment once it has checked that the credential was issued to `subject`, the device's
+/// static public key, and signed by the authority it names.
+pub struct Enrollee {
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+    subject: Vec<u8>,
+    enrollment: Option<Enrollment>,
+}
+
+impl std::fmt::Debug for Enrollee {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Enrollee {{ enrolled: {}, vault }}",
+            self.enrollment.is_some()
+        )
+    }
+}
+
+impl Enrollee {
+    pub fn new(vault: Arc<Mutex<dyn DynVault + Send>>, subject: &[u8]) -> Enrollee {
+        Enrollee {
+            vault,
+            subject: subject.to_vec(),
+            enrollment: None,
+        }
+    }
+
+    /// The enrollment received, once the registrar has replied
+    pub fn enrollment(&self) -> Option<&Enrollment> {
+        self.enrollment.as_ref()
+    }
+
+    /// Check and keep the enrollment a registrar sent
+    pub fn accept(&mut self, enrollment: Enrollment) -> Result<(), CredentialError> {
+        if enrollment.credential.issuer != enrollment.authority {
+            return Err(CredentialErrorKind::UntrustedIssuer.into());
+        }
+        enrollment
+            .credential
+            .verify(&mut *self.vault.lock().unwrap(), &self.subject, now())?;
+        self.enrollment = Some(enrollment);
+        Ok(())
+    }
+}
+
+impl Receiver for Enrollee {
+    fn recv(&mut self, m: Message) -> Result<Option<Message>, String> {
+        if m.message_type == MessageType::Payload {
+            self.accept(Enrollment::decode(&m.message_body)?)?;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_channel::CHANNEL_ZERO;
+    use ockam_identity::Identity;
+    use ockam_test_support::{route, run_until, Network};
+    use ockam_vault::software::DefaultVault;
+
+    fn worker(address: &str) -> RouterAddress {
+        RouterAddress::worker_router_address_from_str(address).unwrap()
+    }
+
+    #[test]
+    fn token_text() {
+        let token = Token([7; TOKEN_LEN]);
+        assert_eq!(token.to_string().parse::<Token>().unwrap(), token);
+        assert!("07".parse::<Token>().is_err());
+        assert!("not hex".parse::<Token>().is_err());
+        assert_eq!(
+            decode_request(
+                &enrollment_request(
+                    worker("00000001"),
+                    worker("00000002"),
+                    worker("00000003"),
+                    &token
+                )
+                .message_body
+            )
+            .unwrap(),
+            token
+        );
+    }
+
+    #[test]
+    fn enrollment_encoding() {
+        let enrollment = Enrollment {
+            authority: vec![1; 32],
+            credential: Credential {
+                schema: "device".to_string(),
+                issuer: vec![1; 32],
+                subject: vec![2; 32],
+                expires: 1_700_000_000,
+                attributes: [("role".to_string(), "sensor".to_string())]
+                    .iter()
+                    .cloned()
+                    .collect(),
+                signature: vec![3; 64],
+            },
+        };
+        let encoded = enrollment.encode().unwrap();
+        assert_eq!(Enrollment::decode(&encoded).unwrap(), enrollment);
+        assert!(Enrollment::decode(&encoded[1..]).is_err());
+    }
+
+    // needs the software vault's crypto
+    #[test]
+    fn enroll_over_channel() {
+        let mut network = Network::new();
+        let mut nodes = network.nodes(2).unwrap();
+        let holder = nodes[0].inbox("00000001").unwrap();
+
+        let vault: Arc<Mutex<dyn DynVault + Send>> = Arc::new(Mutex::new(DefaultVault::default()));
+        let authority = Authority::new(Identity::create(vault.clone()).unwrap());
+        let authority_key = authority.public_key().to_vec();
+        let schema = Schema::new("device", &["role"]);
+        let attributes: Attributes = [("role".to_string(), "sensor".to_string())]
+            .iter()
+            .cloned()
+            .collect();
+
+        let registrar = Arc::new(Mutex::new(Registrar::new(vault.clone(), authority)));
+        let token = registrar
+            .lock()
+            .unwrap()
+            .issue_token(&schema, attributes.clone(), u64::MAX, u64::MAX)
+            .unwrap();
+        let revoked = registrar
+            .lock()
+            .unwrap()
+            .issue_token(&schema, attributes, u64::MAX, u64::MAX)
+            .unwrap();
+        assert!(registrar.lock().unwrap().revoke_token(&revoked));
+        assert_eq!(registrar.lock().unwrap().outstanding().count(), 1);
+        nodes[1]
+            .register_worker(CHANNEL_ZERO, registrar.clone())
+            .unwrap();
+
+        let channel_zero = RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap();
+        nodes[0]
+            .initiate_channel(route(&[&nodes[1]], channel_zero), "00000001")
+            .unwrap();
+        run_until(&mut nodes, 50, || holder.channel().is_some()).unwrap();
+
+        // the device's static public key, as the registrar learned it
+        let channels = registrar.lock().unwrap().channels.clone();
+        let (responder_channel, subject) = channels.iter().next().unwrap();
+        let enrollee = Arc::new(Mutex::new(Enrollee::new(vault, subject)));
+        nodes[0]
+            .register_worker("00000002", enrollee.clone())
+            .unwrap();
+
+        let m = enrollment_request(
+            holder.channel().unwrap(),
+            worker(CHANNEL_ZERO),
+            worker("00000002"),
+            &token,
+        );
+        nodes[0].send(m).unwrap();
+        run_until(&mut nodes, 50, || {
+            enrollee.lock().unwrap().enrollment().is_some()
+        })
+        .unwrap();
+
+        let enrollment = enrollee.lock().unwrap().enrollment().cloned().unwrap();
+        assert_eq!(enrollment.authority, authority_key);
+        assert_eq!(
+            enrollment
+                .credential
+                .attributes
+                .get("role")
+                .map(String::as_str),
+            Some("sensor")
+        );
+
+        // the token is used up, and the revoked one was never valid
+        let mut registrar = registrar.lock().unwrap();
+        assert_eq!(registrar.outstanding().count(), 0);
+        assert!(registrar.enroll(responder_channel, &token).is_err());
+        assert!(registrar.enroll(responder_channel, &revoked).is_err());
+    }
+}
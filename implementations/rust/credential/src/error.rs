@@ -0,0 +1,100 @@
+use failure::Fail;
+use ockam_common::error::{ErrorKind, ERROR_INTERFACE_CREDENTIAL};
+use ockam_identity::error::{IdentityError, IdentityErrorKind};
+use ockam_message::error::{MessageError, MessageErrorKind};
+use ockam_vault::error::{VaultFailError, VaultFailErrorKind};
+
+/// Represents the failures that can occur
+/// issuing, presenting or verifying a credential, enrolling, or enforcing a policy
+#[derive(Clone, Copy, Fail, Debug)]
+#[non_exhaustive]
+pub enum CredentialErrorKind {
+    /// The credential couldn't be decoded
+    #[fail(display = "The credential is malformed")]
+    Malformed,
+    /// The attributes don't match the credential's schema
+    #[fail(display = "The attributes don't match the schema")]
+    Schema,
+    /// The verifier doesn't know the credential's schema
+    #[fail(display = "The schema is unknown")]
+    UnknownSchema,
+    /// The issuer isn't an authority the verifier trusts
+    #[fail(display = "The issuer is not a trusted authority")]
+    UntrustedIssuer,
+    /// The issuer's signature doesn't verify
+    #[fail(display = "The signature is invalid")]
+    Signature,
+    /// The credential has expired
+    #[fail(display = "The credential has expired")]
+    Expired,
+    /// The credential was issued to another party than the one presenting it
+    #[fail(display = "The credential was not issued to the presenter")]
+    Subject,
+    /// The credential didn't arrive over an established channel
+    #[fail(display = "The credential was not presented over a channel")]
+    NoChannel,
+    /// An error occurred in the vault
+    #[fail(display = "An error occurred in the vault: {}", 0)]
+    Vault(VaultFailErrorKind),
+    /// A message couldn't be encoded or decoded
+    #[fail(display = "A message couldn't be encoded or decoded: {}", 0)]
+    Message(MessageErrorKind),
+    /// An error occurred with the authority's identity
+    #[fail(display = "An error occurred with the identity: {}", 0)]
+    Identity(IdentityErrorKind),
+    /// The enrollment token is unknown, used, revoked or expired
+    #[fail(display = "The enrollment token is not valid")]
+    Token,
+    /// The policy couldn't be parsed
+    #[fail(display = "The policy is malformed")]
+    Policy,
+    /// The peer's attributes don't satisfy the policy protecting a worker
+    #[fail(display = "The policy denies access")]
+    Denied,
+}
+
+impl ErrorKind for CredentialErrorKind {
+    const ERROR_INTERFACE: usize = ERROR_INTERFACE_CREDENTIAL;
+
+    fn to_usize(&self) -> usize {
+        match *self {
+            CredentialErrorKind::Malformed => Self::ERROR_INTERFACE | 1,
+            CredentialErrorKind::Schema => Self::ERROR_INTERFACE | 2,
+            CredentialErrorKind::UnknownSchema => Self::ERROR_INTERFACE | 3,
+            CredentialErrorKind::UntrustedIssuer => Self::ERROR_INTERFACE | 4,
+            CredentialErrorKind::Signature => Self::ERROR_INTERFACE | 5,
+            CredentialErrorKind::Expired => Self::ERROR_INTERFACE | 6,
+            CredentialErrorKind::Subject => Self::ERROR_INTERFACE | 7,
+            CredentialErrorKind::NoChannel => Self::ERROR_INTERFACE | 8,
+            CredentialErrorKind::Vault(_) => Self::ERROR_INTERFACE | 9,
+            CredentialErrorKind::Message(_) => Self::ERROR_INTERFACE | 10,
+            CredentialErrorKind::Identity(_) => Self::ERROR_INTERFACE | 11,
+            CredentialErrorKind::Token => Self::ERROR_INTERFACE | 12,
+            CredentialErrorKind::Policy => Self::ERROR_INTERFACE | 13,
+            CredentialErrorKind::Denied => Self::ERROR_INTERFACE | 14,
+        }
+    }
+}
+
+error_impl!(CredentialError, CredentialErrorKind);
+
+impl From<VaultFailError> for CredentialError {
+    fn from(err: VaultFailError) -> Self {
+        let kind = *err.kind();
+        Self::from_source(CredentialErrorKind::Vault(kind), err)
+    }
+}
+
+impl From<MessageError> for CredentialError {
+    fn from(err: MessageError) -> Self {
+        let kind = *err.kind();
+        Self::from_source(CredentialErrorKind::Message(kind), err)
+    }
+}
+
+impl From<IdentityError> for CredentialError {
+    fn from(err: IdentityError) -> Self {
+        let kind = *err.kind();
+        Self::from_source(CredentialErrorKind::Identity(kind), err)
+    }
+}
@@ -0,0 +1,237 @@
+//! Attribute credentials: an authority attests that the party holding a static public key has
+//! some attributes, e.g. `role=sensor`, by signing them with its own key in its vault. The party
+//! presents the credential to a peer over a secure channel, and the peer accepts the attributes
+//! if an authority it trusts signed them for the key the channel was established with. Peers can
+//! then authorize on what a party is attested to be rather than on its raw public key.
+//!
+//! A party gets its credential by enrolling: the authority's registrar hands out a one-time
+//! token, and the party presents it to the registrar over a channel, receiving in return a
+//! credential for the channel's key and the public key of the authority which issued it.
+//!
+//! A service is protected by a policy over the attributes, e.g. `role = sensor and site =
+//! dublin`: a guard registered at the service's worker address refuses the payloads of peers
+//! whose credentials don't satisfy it, so reaching the service takes more than its address.
+
+#[macro_use]
+extern crate ockam_common;
+
+/// Represents the errors that occur issuing, presenting and verifying credentials
+pub mod error;
+
+mod authority;
+mod enrollment;
+mod policy;
+mod verifier;
+
+pub use authority::Authority;
+pub use enrollment::{enrollment_request, Enrollee, Enrollment, Registrar, Token};
+pub use policy::{Guard, Policy};
+pub use verifier::{presentation, Verifier};
+
+use crate::error::{CredentialError, CredentialErrorKind};
+use ockam_identity::public_key;
+use ockam_message::message::Codec;
+use ockam_vault::DynVault;
+use std::collections::BTreeMap;
+
+/// The attributes a credential attests, by name
+pub type Attributes = BTreeMap<String, String>;
+
+// the version of the credential encoding, its first byte
+const CREDENTIAL_VERSION: u8 = 1;
+const SIGNATURE_LEN: usize = 64;
+
+/// The attributes the credentials of one kind carry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    /// Names the schema in the credentials issued under it
+    pub id: String,
+    /// The names of the attributes, each of which a credential must have
+    pub attributes: Vec<String>,
+}
+
+impl Schema {
+    pub fn new(id: &str, attributes: &[&str]) -> Schema {
+        Schema {
+            id: id.to_string(),
+            attributes: attributes.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    /// Check that `attributes` are exactly those of the schema
+    pub fn check(&self, attributes: &Attributes) -> Result<(), CredentialError> {
+        if attributes.len() != self.attributes.len()
+            || !self.attributes.iter().all(|a| attributes.contains_key(a))
+        {
+            return Err(CredentialErrorKind::Schema.into());
+        }
+        Ok(())
+    }
+}
+
+/// Attributes attested by an issuer for a subject, each named by its static public key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credential {
+    /// The id of the schema the credential was issued under
+    pub schema: String,
+    /// The public key of the authority which signed the credential
+    pub issuer: Vec<u8>,
+    /// The static public key of the party the credential was issued to
+    pub subject: Vec<u8>,
+    /// Seconds since the Unix epoch after which the credential is no longer accepted
+    pub expires: u64,
+    pub attributes: Attributes,
+    /// The issuer's signature over all of the above
+    pub signature: Vec<u8>,
+}
+
+impl Credential {
+    /// The encoding of everything the issuer signs
+    fn signed_data(&self) -> Result<Vec<u8>, CredentialError> {
+        let mut v = vec![CREDENTIAL_VERSION];
+        encode_bytes(self.schema.as_bytes(), &mut v)?;
+        encode_bytes(&self.issuer, &mut v)?;
+        encode_bytes(&self.subject, &mut v)?;
+        v.extend_from_slice(&self.expires.to_be_bytes());
+        (self.attributes.len() as u16).encode(&mut v)?;
+        for (name, value) in &self.attributes {
+            encode_bytes(name.as_bytes(), &mut v)?;
+            encode_bytes(value.as_bytes(), &mut v)?;
+        }
+        Ok(v)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, CredentialError> {
+        let mut v = self.signed_data()?;
+        v.extend_from_slice(&self.signature);
+        Ok(v)
+    }
+
+    pub fn decode(u: &[u8]) -> Result<Credential, CredentialError> {
+        if u.first() != Some(&CREDENTIAL_VERSION) {
+            return Err(CredentialErrorKind::Malformed.into());
+        }
+        let (schema, u) = decode_string(&u[1..])?;
+        let (issuer, u) = decode_bytes(u)?;
+        let (subject, u) = decode_bytes(u)?;
+        if u.len() < 8 {
+            return Err(CredentialErrorKind::Malformed.into());
+        }
+        let mut expires = [0; 8];
+        expires.copy_from_slice(&u[..8]);
+        let (count, mut u) = u16::decode(&u[8..])?;
+        let mut attributes = Attributes::new();
+        for _ in 0..count {
+            let (name, w) = decode_string(u)?;
+            let (value, w) = decode_string(w)?;
+            attributes.insert(name, value);
+            u = w;
+        }
+        if u.len() != SIGNATURE_LEN {
+            return Err(CredentialErrorKind::Malformed.into());
+        }
+        Ok(Credential {
+            schema,
+            issuer: issuer.to_vec(),
+            subject: subject.to_vec(),
+            expires: u64::from_be_bytes(expires),
+            attributes,
+            signature: u.to_vec(),
+        })
+    }
+
+    /// Check that the credential was signed by its issuer for the party holding `subject`, and
+    /// hasn't expired by `now`, in seconds since the Unix epoch. Whether the issuer is trusted
+    /// is for the caller to decide.
+    pub fn verify(
+        &self,
+        vault: &mut dyn DynVault,
+        subject: &[u8],
+        now: u64,
+    ) -> Result<(), CredentialError> {
+        if self.subject != subject {
+            return Err(CredentialErrorKind::Subject.into());
+        }
+        if now > self.expires {
+            return Err(CredentialErrorKind::Expired.into());
+        }
+        if self.signature.len() != SIGNATURE_LEN {
+            return Err(CredentialErrorKind::Malformed.into());
+        }
+        let mut signature = [0; SIGNATURE_LEN];
+        signature.copy_from_slice(&self.signature);
+        vault
+            .verify(signature, public_key(&self.issuer)?, &self.signed_data()?)
+            .map_err(|e| CredentialError::from_source(CredentialErrorKind::Signature, e))
+    }
+}
+
+fn encode_bytes(b: &[u8], v: &mut Vec<u8>) -> Result<(), CredentialError> {
+    (b.len() as u16).encode(v)?;
+    v.extend_from_slice(b);
+    Ok(())
+}
+
+fn decode_bytes(u: &[u8]) -> Result<(&[u8], &[u8]), CredentialError> {
+    let (len, u) = u16::decode(u)?;
+    let len = len as usize;
+    if u.len() < len {
+        return Err(CredentialErrorKind::Malformed.into());
+    }
+    Ok((&u[..len], &u[len..]))
+}
+
+fn decode_string(u: &[u8]) -> Result<(String, &[u8]), CredentialError> {
+    let (b, u) = decode_bytes(u)?;
+    let s = String::from_utf8(b.to_vec()).map_err(|_| CredentialErrorKind::Malformed)?;
+    Ok((s, u))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(pairs: &[(&str, &str)]) -> Attributes {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn schema_check() {
+        let schema = Schema::new("device", &["role", "site"]);
+        assert!(schema
+            .check(&attributes(&[("role", "sensor"), ("site", "dublin")]))
+            .is_ok());
+        assert!(schema.check(&attributes(&[("role", "sensor")])).is_err());
+        assert!(schema
+            .check(&attributes(&[("role", "sensor"), ("floor", "2")]))
+            .is_err());
+        assert!(schema
+            .check(&attributes(&[
+                ("role", "sensor"),
+                ("site", "dublin"),
+                ("floor", "2")
+            ]))
+            .is_err());
+    }
+
+    #[test]
+    fn encoding() {
+        let credential = Credential {
+            schema: "device".to_string(),
+            issuer: vec![1; 32],
+            subject: vec![2; 32],
+            expires: 1_700_000_000,
+            attributes: attributes(&[("role", "sensor"), ("site", "dublin")]),
+            signature: vec![3; 64],
+        };
+        let encoded = credential.encode().unwrap();
+        assert_eq!(Credential::decode(&encoded).unwrap(), credential);
+
+        assert!(Credential::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(Credential::decode(&encoded[1..]).is_err());
+        assert!(Credential::decode(&[]).is_err());
+    }
+}
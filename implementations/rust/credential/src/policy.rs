@@ -0,0 +1,398 @@
+use crate::error::{CredentialError, CredentialErrorKind};
+use crate::{Attributes, Verifier};
+use ockam_message::message::{AddressType, Message, MessageType, Receiver};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// A boolean expression over the attributes attested for a peer, e.g.
+/// `role = sensor and (site = dublin or site = "new york")`. A name or value which isn't a
+/// plain word is quoted; `has(name)` tests that an attribute is present at all, and `not`,
+/// `and` and `or` bind in that order, most tightly first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Always or never satisfied, from `true` or `false`
+    Constant(bool),
+    /// The attribute is present, whatever its value
+    Has(String),
+    /// The attribute is present with the value
+    Eq(String, String),
+    /// The attribute is present with another value
+    Ne(String, String),
+    Not(Box<Policy>),
+    And(Box<Policy>, Box<Policy>),
+    Or(Box<Policy>, Box<Policy>),
+}
+
+impl Policy {
+    /// Whether the policy is satisfied by `attributes`
+    pub fn evaluate(&self, attributes: &Attributes) -> bool {
+        match self {
+            Policy::Constant(b) => *b,
+            Policy::Has(name) => attributes.contains_key(name),
+            Policy::Eq(name, value) => attributes.get(name) == Some(value),
+            Policy::Ne(name, value) => attributes.get(name).map(|v| v != value).unwrap_or(false),
+            Policy::Not(p) => !p.evaluate(attributes),
+            Policy::And(p, q) => p.evaluate(attributes) && q.evaluate(attributes),
+            Policy::Or(p, q) => p.evaluate(attributes) || q.evaluate(attributes),
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = CredentialError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, next: 0 };
+        let policy = parser.or()?;
+        if parser.next != parser.tokens.len() {
+            return Err(CredentialErrorKind::Policy.into());
+        }
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Policy::Constant(b) => write!(f, "{}", b),
+            Policy::Has(name) => write!(f, "has({})", Quoted(name)),
+            Policy::Eq(name, value) => write!(f, "{} = {}", Quoted(name), Quoted(value)),
+            Policy::Ne(name, value) => write!(f, "{} != {}", Quoted(name), Quoted(value)),
+            Policy::Not(p) => write!(f, "not ({})", p),
+            Policy::And(p, q) => write!(f, "({}) and ({})", p, q),
+            Policy::Or(p, q) => write!(f, "({}) or ({})", p, q),
+        }
+    }
+}
+
+// A name or value, quoted unless it would read back as the same word.
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keyword = KEYWORDS.contains(&self.0);
+        if !keyword && !self.0.is_empty() && self.0.chars().all(is_word) {
+            write!(f, "{}", self.0)
+        } else {
+            write!(
+                f,
+                "\"{}\"",
+                self.0.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        }
+    }
+}
+
+const KEYWORDS: &[&str] = &["and", "or", "not", "has", "true", "false"];
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || "_-.:/@".contains(c)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Open,
+    Close,
+    Eq,
+    Ne,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, CredentialError> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '=' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Eq,
+                });
+            }
+            '!' => {
+                chars.next();
+                if chars.next() != Some('=') {
+                    return Err(CredentialErrorKind::Policy.into());
+                }
+                tokens.push(Token::Ne);
+            }
+            '"' => {
+                chars.next();
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // a backslash escapes the character after it
+                        Some('\\') => match chars.next() {
+                            Some(c) => quoted.push(c),
+                            None => return Err(CredentialErrorKind::Policy.into()),
+                        },
+                        Some(c) => quoted.push(c),
+                        None => return Err(CredentialErrorKind::Policy.into()),
+                    }
+                }
+                tokens.push(Token::Quoted(quoted));
+            }
+            c if is_word(c) => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek().filter(|&&c| is_word(c)) {
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+            _ => return Err(CredentialErrorKind::Policy.into()),
+        }
+    }
+    Ok(tokens)
+}
+
+// A recursive descent over the tokens, one method for each level of precedence.
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn take(&mut self) -> Result<Token, CredentialError> {
+        let token = self
+            .tokens
+            .get(self.next)
+            .cloned()
+            .ok_or(CredentialErrorKind::Policy)?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), CredentialError> {
+        if self.take()? != token {
+            return Err(CredentialErrorKind::Policy.into());
+        }
+        Ok(())
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek() == Some(&Token::Word(keyword.to_string()));
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Policy, CredentialError> {
+        let mut policy = self.and()?;
+        while self.keyword("or") {
+            policy = Policy::Or(Box::new(policy), Box::new(self.and()?));
+        }
+        Ok(policy)
+    }
+
+    fn and(&mut self) -> Result<Policy, CredentialError> {
+        let mut policy = self.unary()?;
+        while self.keyword("and") {
+            policy = Policy::And(Box::new(policy), Box::new(self.unary()?));
+        }
+        Ok(policy)
+    }
+
+    fn unary(&mut self) -> Result<Policy, CredentialError> {
+        if self.keyword("not") {
+            return Ok(Policy::Not(Box::new(self.unary()?)));
+        }
+        if self.keyword("true") {
+            return Ok(Policy::Constant(true));
+        }
+        if self.keyword("false") {
+            return Ok(Policy::Constant(false));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.next += 1;
+            let policy = self.or()?;
+            self.expect(Token::Close)?;
+            return Ok(policy);
+        }
+        if self.peek() == Some(&Token::Word("has".into()))
+            && self.tokens.get(self.next + 1) == Some(&Token::Open)
+        {
+            self.next += 2;
+            let name = self.name()?;
+            self.expect(Token::Close)?;
+            return Ok(Policy::Has(name));
+        }
+        let name = self.name()?;
+        match self.take()? {
+            Token::Eq => Ok(Policy::Eq(name, self.name()?)),
+            Token::Ne => Ok(Policy::Ne(name, self.name()?)),
+            _ => Err(CredentialErrorKind::Policy.into()),
+        }
+    }
+
+    // A name or value, which is a quoted string or any word other than a keyword.
+    fn name(&mut self) -> Result<String, CredentialError> {
+        match self.take()? {
+            Token::Quoted(s) => Ok(s),
+            Token::Word(w) if !KEYWORDS.contains(&w.as_str()) => Ok(w),
+            _ => Err(CredentialErrorKind::Policy.into()),
+        }
+    }
+}
+
+/// A worker delivering only the payloads from peers whose attested attributes satisfy a policy
+/// to the protected worker it stands in front of; register the guard at the protected worker's
+/// address instead. A payload's peer is the party at the other end of the channel it arrived
+/// on, and its attributes are those the verifier accepted from the peer's credential, so a
+/// payload sent without a channel, or before a credential was presented, is refused. The
+/// announcements that channels are up are passed on as they are.
+pub struct Guard {
+    policy: Policy,
+    verifier: Arc<Mutex<Verifier>>,
+    worker: Arc<Mutex<dyn Receiver + Send>>,
+}
+
+impl Guard {
+    pub fn new(
+        policy: Policy,
+        verifier: Arc<Mutex<Verifier>>,
+        worker: Arc<Mutex<dyn Receiver + Send>>,
+    ) -> Guard {
+        Guard {
+            policy,
+            verifier,
+            worker,
+        }
+    }
+
+    /// Whether the policy admits the message, by the credential presented on its channel
+    pub fn admits(&self, m: &Message) -> Result<(), CredentialError> {
+        let channel = match m.return_route.addresses.first() {
+            Some(a) if a.a_type == AddressType::Channel => a.address.as_string(),
+            _ => return Err(CredentialErrorKind::NoChannel.into()),
+        };
+        let verifier = self.verifier.lock().unwrap();
+        match verifier.attributes(&channel) {
+            Some(attributes) if self.policy.evaluate(attributes) => Ok(()),
+            _ => Err(CredentialErrorKind::Denied.into()),
+        }
+    }
+}
+
+impl Receiver for Guard {
+    fn recv(&mut self, m: Message) -> Result<Option<Message>, String> {
+        if m.message_type != MessageType::None {
+            self.admits(&m)?;
+        }
+        self.worker.lock().unwrap().recv(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_message::message::{Route, RouterAddress};
+    use ockam_test_support::Inbox;
+    use ockam_vault::software::DefaultVault;
+    use ockam_vault::DynVault;
+
+    fn attributes(pairs: &[(&str, &str)]) -> Attributes {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn policy_evaluate() {
+        let policy: Policy = "role = sensor and (site = dublin or site = \"new york\")"
+            .parse()
+            .unwrap();
+        assert!(policy.evaluate(&attributes(&[("role", "sensor"), ("site", "dublin")])));
+        assert!(policy.evaluate(&attributes(&[("role", "sensor"), ("site", "new york")])));
+        assert!(!policy.evaluate(&attributes(&[("role", "sensor"), ("site", "paris")])));
+        assert!(!policy.evaluate(&attributes(&[("role", "gateway"), ("site", "dublin")])));
+        assert!(!policy.evaluate(&attributes(&[("site", "dublin")])));
+
+        // `not` binds more tightly than `and`, which binds more tightly than `or`
+        let policy: Policy = "not has(revoked) and role != admin or true"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            policy,
+            Policy::Or(
+                Box::new(Policy::And(
+                    Box::new(Policy::Not(Box::new(Policy::Has("revoked".into())))),
+                    Box::new(Policy::Ne("role".into(), "admin".into())),
+                )),
+                Box::new(Policy::Constant(true)),
+            )
+        );
+        let ne: Policy = "role != admin".parse().unwrap();
+        assert!(ne.evaluate(&attributes(&[("role", "sensor")])));
+        assert!(!ne.evaluate(&attributes(&[])));
+    }
+
+    #[test]
+    fn policy_text() {
+        for text in &[
+            "role = sensor and (site = dublin or site = \"new york\")",
+            "not has(\"and\") or \"quote\\\"d\" != x",
+            "false",
+        ] {
+            let policy: Policy = text.parse().unwrap();
+            assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+        }
+        for bad in &[
+            "",
+            "role",
+            "role =",
+            "role = and",
+            "(role = sensor",
+            "role = sensor)",
+            "role = sensor site = dublin",
+            "role ! sensor",
+            "role = \"sensor",
+            "has role",
+        ] {
+            assert!(bad.parse::<Policy>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn guard_refuses() {
+        let vault: Arc<Mutex<dyn DynVault + Send>> = Arc::new(Mutex::new(DefaultVault::default()));
+        let verifier = Arc::new(Mutex::new(Verifier::new(vault)));
+        let inbox = Inbox::default();
+        let mut guard = Guard::new(
+            "role = sensor".parse().unwrap(),
+            verifier,
+            Arc::new(Mutex::new(inbox.clone())),
+        );
+        let channel = RouterAddress::channel_router_address_from_str("0a0b0c0d").unwrap();
+        let from = |addresses| Message {
+            onward_route: Route { addresses: vec![] },
+            return_route: Route { addresses },
+            message_type: MessageType::Payload,
+            message_body: vec![1],
+        };
+
+        // the announcement of a channel is passed on, but no payloads without a credential
+        let mut announcement = from(vec![channel.clone()]);
+        announcement.message_type = MessageType::None;
+        assert!(guard.recv(announcement).is_ok());
+        assert!(guard.recv(from(vec![channel])).is_err());
+        assert!(guard.recv(from(vec![])).is_err());
+        assert_eq!(inbox.messages().len(), 1);
+        assert!(inbox.payloads().is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::error::{CredentialError, CredentialErrorKind};
+use crate::{Attributes, Credential, Schema};
+use ockam_message::message::{AddressType, Message, MessageType, Receiver, Route, RouterAddress};
+use ockam_vault::DynVault;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A payload presenting `credential` to the verifier at `verifier`, over the channel at
+/// `channel`
+pub fn presentation(
+    channel: RouterAddress,
+    verifier: RouterAddress,
+    credential: &Credential,
+) -> Result<Message, CredentialError> {
+    Ok(Message {
+        onward_route: Route {
+            addresses: vec![channel, verifier],
+        },
+        return_route: Route { addresses: vec![] },
+        message_type: MessageType::Payload,
+        message_body: credential.encode()?,
+    })
+}
+
+/// A worker accepting the credentials peers present over channels. It learns each channel's
+/// remote static public key from the channel manager's announcement that the channel is up, so
+/// it must be registered where those are sent: the zero worker address on a responder, or the
+/// address given when initiating a channel. A credential is accepted if a trusted authority
+/// issued it under a known schema to the key the channel it arrived on was established with.
+pub struct Verifier {
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+    authorities: BTreeSet<Vec<u8>>,
+    schemas: BTreeMap<String, Schema>,
+    // the remote static public key of each channel announced, by the channel's address
+    channels: BTreeMap<String, Vec<u8>>,
+    // the credential accepted on each channel, by the channel's address
+    accepted: BTreeMap<String, Credential>,
+}
+
+impl std::fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Verifier {{ authorities: {}, schemas: {:?}, accepted: {:?}, vault }}",
+            self.authorities.len(),
+            self.schemas.keys(),
+            self.accepted.keys()
+        )
+    }
+}
+
+impl Verifier {
+    pub fn new(vault: Arc<Mutex<dyn DynVault + Send>>) -> Verifier {
+        Verifier {
+            vault,
+            authorities: BTreeSet::new(),
+            schemas: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            accepted: BTreeMap::new(),
+        }
+    }
+
+    /// Accept credentials signed by the authority with this public key
+    pub fn trust_authority(&mut self, public_key: &[u8]) {
+        self.authorities.insert(public_key.to_vec());
+    }
+
+    /// Accept credentials issued under `schema`
+    pub fn add_schema(&mut self, schema: Schema) {
+        self.schemas.insert(schema.id.clone(), schema);
+    }
+
+    /// Accept `credential` for the party at the other end of the channel at `channel`, if it
+    /// was presented by the key that channel was established with
+    pub fn verify(&mut self, channel: &str, credential: Credential) -> Result<(), CredentialError> {
+        let remote_public_key = self
+            .channels
+            .get(channel)
+            .ok_or(CredentialErrorKind::NoChannel)?;
+        if !self.authorities.contains(&credential.issuer) {
+            return Err(CredentialErrorKind::UntrustedIssuer.into());
+        }
+        self.schemas
+            .get(&credential.schema)
+            .ok_or(CredentialErrorKind::UnknownSchema)?
+            .check(&credential.attributes)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        credential.verify(&mut *self.vault.lock().unwrap(), remote_public_key, now)?;
+        self.accepted.insert(channel.to_string(), credential);
+        Ok(())
+    }
+
+    /// The attributes attested for the party at the other end of the channel at `channel`, if
+    /// it has presented a credential which was accepted
+    pub fn attributes(&self, channel: &str) -> Option<&Attributes> {
+        self.accepted.get(channel).map(|c| &c.attributes)
+    }
+
+    /// Whether the party at the other end of the channel at `channel` is attested to have the
+    /// attribute `name` with `value`
+    pub fn is_attested(&self, channel: &str, name: &str, value: &str) -> bool {
+        self.attributes(channel)
+            .and_then(|a| a.get(name))
+            .map(String::as_str)
+            == Some(value)
+    }
+}
+
+impl Receiver for Verifier {
+    fn recv(&mut self, m: Message) -> Result<Option<Message>, String> {
+        let channel = match m.return_route.addresses.first() {
+            Some(a) if a.a_type == AddressType::Channel => a.address.as_string(),
+            _ => {
+                return Err(String::from(CredentialError::from(
+                    CredentialErrorKind::NoChannel,
+                )))
+            }
+        };
+        match m.message_type {
+            // a channel is up, with the remote static public key as the body
+            MessageType::None => {
+                self.accepted.remove(&channel);
+                self.channels.insert(channel, m.message_body);
+                Ok(None)
+            }
+            MessageType::Payload => {
+                let credential = Credential::decode(&m.message_body)?;
+                self.verify(&channel, credential)?;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Authority, Guard};
+    use ockam_channel::CHANNEL_ZERO;
+    use ockam_identity::Identity;
+    use ockam_test_support::{route, run_until, Network};
+    use ockam_vault::software::DefaultVault;
+
+    fn worker(address: &str) -> RouterAddress {
+        RouterAddress::worker_router_address_from_str(address).unwrap()
+    }
+
+    // needs the software vault's crypto
+    #[test]
+    fn present_over_channel() {
+        let mut network = Network::new();
+        let mut nodes = network.nodes(2).unwrap();
+        let holder = nodes[0].inbox("00000001").unwrap();
+
+        let vault: Arc<Mutex<dyn DynVault + Send>> = Arc::new(Mutex::new(DefaultVault::default()));
+        let authority = Authority::new(Identity::create(vault.clone()).unwrap());
+        let schema = Schema::new("device", &["role"]);
+
+        let verifier = Arc::new(Mutex::new(Verifier::new(vault)));
+        verifier
+            .lock()
+            .unwrap()
+            .trust_authority(authority.public_key());
+        verifier.lock().unwrap().add_schema(schema.clone());
+        nodes[1]
+            .register_worker(CHANNEL_ZERO, verifier.clone())
+            .unwrap();
+
+        let channel_zero = RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap();
+        nodes[0]
+            .initiate_channel(route(&[&nodes[1]], channel_zero), "00000001")
+            .unwrap();
+        run_until(&mut nodes, 50, || holder.channel().is_some()).unwrap();
+
+        // the holder's static public key, as the responder learned it
+        let channel = verifier.lock().unwrap().channels.clone();
+        let (responder_channel, subject) = channel.iter().next().unwrap();
+        let attributes = [("role".to_string(), "sensor".to_string())]
+            .iter()
+            .cloned()
+            .collect();
+        let credential = authority
+            .issue(&schema, subject, attributes, u64::MAX)
+            .unwrap();
+
+        let m = presentation(holder.channel().unwrap(), worker(CHANNEL_ZERO), &credential).unwrap();
+        nodes[0].send(m).unwrap();
+        run_until(&mut nodes, 50, || {
+            verifier
+                .lock()
+                .unwrap()
+                .attributes(responder_channel)
+                .is_some()
+        })
+        .unwrap();
+        assert!(verifier
+            .lock()
+            .unwrap()
+            .is_attested(responder_channel, "role", "sensor"));
+
+        // a guard admits the holder's payloads to a worker for sensors, but not one for gateways
+        let m = presentation(holder.channel().unwrap(), worker("00000002"), &credential).unwrap();
+        let mut arrived = m.clone();
+        arrived.return_route.addresses =
+            vec![RouterAddress::channel_router_address_from_str(responder_channel).unwrap()];
+        let inbox = ockam_test_support::Inbox::default();
+        let guard = |policy: &str| {
+            Guard::new(
+                policy.parse().unwrap(),
+                verifier.clone(),
+                Arc::new(Mutex::new(inbox.clone())),
+            )
+        };
+        assert!(guard("role = sensor").admits(&arrived).is_ok());
+        assert!(guard("role = gateway").admits(&arrived).is_err());
+
+        // a credential for another key is refused
+        let other = authority
+            .issue(&schema, &[9; 32], credential.attributes.clone(), u64::MAX)
+            .unwrap();
+        assert!(verifier
+            .lock()
+            .unwrap()
+            .verify(responder_channel, other)
+            .is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use crate::addon::Addon;
+use crate::output::base64;
+
+use ockam_message::message::Message as OckamMessage;
+
+use flate2::{write::GzEncoder, Compression};
+use url::Url;
+
+const DEFAULT_BATCH_LINES: usize = 5000;
+const DEFAULT_FLUSH_MS: u64 = 1000;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF_MS: u64 = 500;
+// a batch which keeps failing keeps at most this many batches of lines, dropping the oldest
+const MAX_KEPT_BATCHES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Auth {
+    /// InfluxDB 2.x API token.
+    Token(String),
+    /// InfluxDB 1.x username and password.
+    Basic(String, String),
+}
+
+#[derive(Default)]
+struct Batch {
+    data: Vec<u8>,
+    lines: usize,
+    // the oldest lines dropped to bound a batch which keeps failing
+    dropped: u64,
+    // the last write failed, so the batch waits for the flush interval to be tried again
+    failing: bool,
+}
+
+impl Batch {
+    // Add the lines of `payload`, dropping the oldest beyond `max_lines`.
+    fn push(&mut self, payload: &str, max_lines: usize) {
+        for line in payload.lines().filter(|l| !l.trim().is_empty()) {
+            self.data.extend_from_slice(line.as_bytes());
+            self.data.push(b'\n');
+            self.lines += 1;
+        }
+        if self.lines <= max_lines {
+            return;
+        }
+        let excess = self.lines - max_lines;
+        let end = self
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .nth(excess - 1)
+            .map(|(i, _)| i + 1)
+            .unwrap_or_else(|| self.data.len());
+        self.data.drain(..end);
+        self.lines = max_lines;
+        self.dropped += excess as u64;
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.lines = 0;
+    }
+}
+
+/// Writes received payloads, as line protocol, to an InfluxDB database. Lines are batched and
+/// written once a batch holds `batch_lines` lines, or every flush interval otherwise.
+pub struct InfluxDb {
+    url: Url,
+    database: String,
+    auth: Option<Auth>,
+    batch_lines: usize,
+    flush_interval: Duration,
+    gzip: bool,
+    retries: u32,
+    backoff: Duration,
+    batch: Batch,
+}
+
+/// Create the addon from `--addon influxdb,DATABASE,URL[,option=value...]`.
+pub fn init(options: &[&str]) -> Result<Box<dyn Addon>, String> {
+    match options {
+        [database, url, options @ ..] => Ok(Box::new(InfluxDb::parse(database, url, options)?)),
+        _ => Err("bad configuration: influx addon needs db and url".into()),
+    }
+}
+
+impl InfluxDb {
+    /// Parse the options following the database and URL: `token=TOKEN`, or `username=NAME` and
+    /// `password=PASSWORD`, `batch_lines=N`, `flush_ms=N`, `compression=gzip|none`, `retries=N`
+    /// and `backoff_ms=N`.
+    pub fn parse(database: &str, url: &str, options: &[&str]) -> Result<InfluxDb, String> {
+        let mut influx = InfluxDb {
+            url: Url::parse(url).map_err(|_| "expected valid URL".to_string())?,
+            database: database.into(),
+            auth: None,
+            batch_lines: DEFAULT_BATCH_LINES,
+            flush_interval: Duration::from_millis(DEFAULT_FLUSH_MS),
+            gzip: false,
+            retries: DEFAULT_RETRIES,
+            backoff: Duration::from_millis(DEFAULT_BACKOFF_MS),
+            batch: Batch::default(),
+        };
+
+        let mut username = None;
+        let mut password = None;
+        for option in options {
+            match option.splitn(2, '=').collect::<Vec<&str>>().as_slice() {
+                ["token", t] => influx.auth = Some(Auth::Token(t.to_string())),
+                ["username", u] => username = Some(u.to_string()),
+                ["password", p] => password = Some(p.to_string()),
+                ["batch_lines", n] => {
+                    influx.batch_lines = n.parse().map_err(|_| "batch_lines must be a number")?
+                }
+                ["flush_ms", n] => {
+                    influx.flush_interval =
+                        Duration::from_millis(n.parse().map_err(|_| "flush_ms must be a number")?)
+                }
+                ["compression", "gzip"] => influx.gzip = true,
+                ["compression", "none"] => influx.gzip = false,
+                ["retries", n] => {
+                    influx.retries = n.parse().map_err(|_| "retries must be a number")?
+                }
+                ["backoff_ms", n] => {
+                    influx.backoff =
+                        Duration::from_millis(n.parse().map_err(|_| "backoff_ms must be a number")?)
+                }
+                _ => return Err(format!("unknown influxdb option: {}", option)),
+            }
+        }
+
+        match (username, password, &influx.auth) {
+            (Some(u), Some(p), None) => influx.auth = Some(Auth::Basic(u, p)),
+            (None, None, _) => {}
+            (Some(_), Some(_), Some(_)) => {
+                return Err("influxdb takes either a token or a username and password".into())
+            }
+            _ => return Err("influxdb username and password must be given together".into()),
+        }
+
+        Ok(influx)
+    }
+
+    /// Write the current batch, retrying with exponential backoff on connection failures, 429
+    /// and 5xx responses. A batch which still fails is kept for the next flush, which tries it
+    /// once more, its oldest lines dropped as it grows; a batch rejected outright, e.g. for bad
+    /// line protocol, is dropped.
+    fn write(&mut self) -> Result<(), String> {
+        let batch = &mut self.batch;
+        if batch.data.is_empty() {
+            return Ok(());
+        }
+
+        let body = if self.gzip {
+            let mut gz = GzEncoder::new(vec![], Compression::default());
+            gz.write_all(&batch.data).map_err(|e| e.to_string())?;
+            gz.finish().map_err(|e| e.to_string())?
+        } else {
+            batch.data.clone()
+        };
+
+        let url = format!("{}write?db={}", self.url, self.database);
+        let retries = if batch.failing { 0 } else { self.retries };
+        let mut delay = self.backoff;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+
+            let mut request = attohttpc::post(&url);
+            if self.gzip {
+                request = request.header("content-encoding", "gzip");
+            }
+            let request = match &self.auth {
+                Some(auth) => request
+                    .try_header("authorization", authorization(auth))
+                    .map_err(|e| format!("invalid influxdb credentials: {}", e))?,
+                None => request,
+            };
+
+            match request.bytes(&body).send() {
+                Ok(resp) => {
+                    let status = resp.status();
+                    if status.is_success() {
+                        batch.clear();
+                        batch.failing = false;
+                        return Ok(());
+                    }
+                    if !(status.is_server_error() || status.as_u16() == 429) {
+                        batch.clear();
+                        batch.failing = false;
+                        return Err(format!("bad influx HTTP response: {}", status));
+                    }
+                    eprintln!("influxdb write attempt {} failed: {}", attempt + 1, status);
+                }
+                Err(e) => eprintln!("influxdb write attempt {} failed: {}", attempt + 1, e),
+            }
+        }
+
+        batch.failing = true;
+        Err(format!(
+            "failed to send to influxdb after {} attempts, keeping {} lines ({} older dropped)",
+            retries + 1,
+            batch.lines,
+            batch.dropped
+        ))
+    }
+}
+
+impl Addon for InfluxDb {
+    fn handle(&mut self, msg: &OckamMessage) -> Result<(), String> {
+        let payload = std::str::from_utf8(&msg.message_body)
+            .map_err(|_| "invalid message body for influx".to_string())?;
+
+        let batch = &mut self.batch;
+        batch.push(payload, self.batch_lines.saturating_mul(MAX_KEPT_BATCHES));
+
+        // a failing batch is only tried again on the flush interval, not as each message fills it
+        if batch.lines >= self.batch_lines && !batch.failing {
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.write()
+    }
+
+    // a batch that hasn't filled up is written on the flush interval
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.flush_interval)
+    }
+}
+
+fn authorization(auth: &Auth) -> String {
+    match auth {
+        Auth::Token(token) => format!("Token {}", token),
+        Auth::Basic(username, password) => format!(
+            "Basic {}",
+            base64(format!("{}:{}", username, password).as_bytes())
+        ),
+    }
+}
+
+#[test]
+fn test_influxdb_parse() {
+    let influx = InfluxDb::parse(
+        "telemetry",
+        "http://localhost:8086/",
+        &[
+            "username=Aladdin",
+            "password=open sesame",
+            "batch_lines=100",
+        ],
+    )
+    .unwrap();
+    assert_eq!(influx.batch_lines, 100);
+    assert_eq!(
+        authorization(influx.auth.as_ref().unwrap()),
+        "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+    );
+
+    assert!(InfluxDb::parse("telemetry", "http://localhost:8086/", &["username=a"]).is_err());
+    assert!(InfluxDb::parse(
+        "telemetry",
+        "http://localhost:8086/",
+        &["token=t", "username=a", "password=b"]
+    )
+    .is_err());
+}
+
+#[test]
+fn test_influxdb_failing_batch() {
+    use ockam_message::message::{MessageType, Route};
+
+    let mut influx =
+        InfluxDb::parse("telemetry", "http://localhost:8086/", &["batch_lines=2"]).unwrap();
+    influx.batch.failing = true;
+
+    // a failing batch isn't written as messages fill it, and keeps only the newest lines
+    for i in 0..25 {
+        let msg = OckamMessage {
+            onward_route: Route { addresses: vec![] },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: format!("temp value={}\n", i).into_bytes(),
+        };
+        influx.handle(&msg).unwrap();
+    }
+    assert_eq!(influx.batch.lines, 20);
+    assert_eq!(influx.batch.dropped, 5);
+    assert!(influx.batch.data.starts_with(b"temp value=5\n"));
+    assert!(influx.batch.data.ends_with(b"temp value=24\n"));
+}
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use ockam_message::message::Message as OckamMessage;
+
+pub mod influxdb;
+pub mod prometheus;
+pub mod s3;
+pub mod syslog;
+pub mod webhook;
+
+/// A sink for the messages received by a responder. Implement this in your own crate and
+/// register it with [`crate::Builder::addon`] to add a custom destination to `ockamd`.
+pub trait Addon {
+    /// Deliver one received message.
+    fn handle(&mut self, msg: &OckamMessage) -> Result<(), String>;
+
+    /// Deliver anything buffered so far.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// How often `tick` is called while the addon runs, if at all.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called every `tick_interval` by the addon's worker, e.g. to deliver a batch that hasn't
+    /// filled up. Flushes by default.
+    fn tick(&mut self) -> Result<(), String> {
+        self.flush()
+    }
+
+    /// Called before the addon is replaced or the daemon stops. Flushes by default.
+    fn shutdown(&mut self) -> Result<(), String> {
+        self.flush()
+    }
+}
+
+/// Creates an addon from the options that follow its name in `--addon NAME,OPTION,...`.
+pub type AddonInit = fn(options: &[&str]) -> Result<Box<dyn Addon>, String>;
+
+/// The `--addon` setting, kept as given so it can be resolved against the registry at runtime.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddonSpec {
+    name: String,
+    options: Vec<String>,
+}
+
+impl AddonSpec {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn options(&self) -> Vec<&str> {
+        self.options.iter().map(String::as_str).collect()
+    }
+}
+
+impl FromStr for AddonSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        match parts.next() {
+            Some(name) if !name.is_empty() => Ok(AddonSpec {
+                name: name.into(),
+                options: parts.map(String::from).collect(),
+            }),
+            _ => Err("addon name is missing".into()),
+        }
+    }
+}
+
+/// Maps addon names to the functions that create them.
+#[derive(Clone)]
+pub struct AddonRegistry {
+    addons: BTreeMap<String, AddonInit>,
+}
+
+impl AddonRegistry {
+    /// A registry with no addons, not even the built-in ones.
+    pub fn empty() -> Self {
+        AddonRegistry {
+            addons: BTreeMap::new(),
+        }
+    }
+
+    /// Register an addon under `name`, replacing any addon already registered with that name.
+    pub fn register(&mut self, name: &str, init: AddonInit) {
+        self.addons.insert(name.into(), init);
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.addons.keys().map(String::as_str).collect()
+    }
+
+    pub fn create(&self, spec: &AddonSpec) -> Result<Box<dyn Addon>, String> {
+        match self.addons.get(spec.name()) {
+            Some(init) => init(&spec.options()),
+            None => Err(format!(
+                "unknown addon: {} (available: {})",
+                spec.name(),
+                self.names().join(", ")
+            )),
+        }
+    }
+}
+
+impl Default for AddonRegistry {
+    /// A registry holding the built-in addons.
+    fn default() -> Self {
+        let mut registry = AddonRegistry::empty();
+        registry.register("influxdb", influxdb::init);
+        registry.register("webhook", webhook::init);
+        registry.register("prometheus", prometheus::init);
+        registry.register("syslog", syslog::init);
+        registry.register("s3", s3::init);
+        registry
+    }
+}
+
+#[test]
+fn test_addon_registry() {
+    struct Counter(usize);
+
+    impl Addon for Counter {
+        fn handle(&mut self, _msg: &OckamMessage) -> Result<(), String> {
+            self.0 += 1;
+            Ok(())
+        }
+    }
+
+    fn counter(options: &[&str]) -> Result<Box<dyn Addon>, String> {
+        match options {
+            [] => Ok(Box::new(Counter(0))),
+            _ => Err("counter takes no options".into()),
+        }
+    }
+
+    let mut registry = AddonRegistry::default();
+    registry.register("counter", counter);
+
+    let spec: AddonSpec = "counter".parse().unwrap();
+    let mut addon = registry.create(&spec).unwrap();
+    assert!(addon.handle(&OckamMessage::default()).is_ok());
+    assert!(addon.shutdown().is_ok());
+
+    assert!(registry.create(&"counter,x".parse().unwrap()).is_err());
+    assert!(registry.create(&"bogus".parse().unwrap()).is_err());
+    assert!(registry
+        .create(&"webhook,http://localhost:8080/ingest".parse().unwrap())
+        .is_ok());
+    assert!("".parse::<AddonSpec>().is_err());
+}
@@ -0,0 +1,325 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::addon::Addon;
+
+use ockam_message::message::Message as OckamMessage;
+
+use url::Url;
+
+/// Pushes metrics parsed from received payloads to a Prometheus remote_write endpoint.
+/// Payloads may hold InfluxDB line protocol or Prometheus/OpenMetrics text exposition lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteWrite {
+    url: Url,
+}
+
+// label names and values
+type Labels = Vec<(String, String)>;
+
+/// One sample of a time series, with its labels sorted by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub timestamp_ms: i64,
+}
+
+impl RemoteWrite {
+    pub fn new(url: &str) -> Result<RemoteWrite, String> {
+        Ok(RemoteWrite {
+            url: Url::parse(url).map_err(|_| "expected valid URL".to_string())?,
+        })
+    }
+
+    pub fn push(&self, payload: &[u8]) -> Result<(), String> {
+        let text = std::str::from_utf8(payload).map_err(|_| "payload is not UTF-8")?;
+        let series = parse(text)?;
+        if series.is_empty() {
+            return Ok(());
+        }
+
+        let body = snappy_literal(&encode_write_request(&series));
+        let resp = attohttpc::post(self.url.as_str())
+            .header("content-encoding", "snappy")
+            .header("content-type", "application/x-protobuf")
+            .header("x-prometheus-remote-write-version", "0.1.0")
+            .bytes(body)
+            .send()
+            .map_err(|e| format!("remote write failed: {}", e))?;
+        resp.error_for_status()
+            .map(|_| ())
+            .map_err(|e| format!("bad remote write response: {}", e))
+    }
+}
+
+/// Create the addon from `--addon prometheus,URL`.
+pub fn init(options: &[&str]) -> Result<Box<dyn Addon>, String> {
+    match options {
+        [url] => Ok(Box::new(RemoteWrite::new(url)?)),
+        _ => Err("bad configuration: prometheus addon needs a remote write URL".into()),
+    }
+}
+
+impl Addon for RemoteWrite {
+    fn handle(&mut self, msg: &OckamMessage) -> Result<(), String> {
+        self.push(&msg.message_body)
+            .map_err(|e| format!("failed to send to prometheus: {}", e))
+    }
+}
+
+/// Parse every metric line in a payload. Comment and blank lines are skipped.
+pub fn parse(text: &str) -> Result<Vec<Series>, String> {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+
+    let mut series = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if !line.contains('{') && tokens.len() > 1 && tokens[1].contains('=') {
+            series.extend(parse_line_protocol(&tokens, now_ms)?);
+        } else {
+            series.push(parse_exposition(line, now_ms)?);
+        }
+    }
+    Ok(series)
+}
+
+// `measurement[,tag=value...] field=value[,field=value...] [timestamp_ns]`, producing one
+// series per numeric field, named `measurement_field`. String fields are skipped.
+fn parse_line_protocol(tokens: &[&str], now_ms: i64) -> Result<Vec<Series>, String> {
+    let mut tags = tokens[0].split(',');
+    let measurement = tags.next().unwrap_or_default();
+    let mut labels = vec![];
+    for tag in tags {
+        match tag.splitn(2, '=').collect::<Vec<&str>>().as_slice() {
+            [k, v] => labels.push((sanitize(k), v.to_string())),
+            _ => return Err(format!("bad tag: {}", tag)),
+        }
+    }
+
+    let timestamp_ms = match tokens.get(2) {
+        Some(ts) => ts.parse::<i64>().map_err(|_| "bad timestamp")? / 1_000_000,
+        None => now_ms,
+    };
+
+    let mut series = vec![];
+    for field in tokens[1].split(',') {
+        let (key, value) = match field.splitn(2, '=').collect::<Vec<&str>>().as_slice() {
+            [k, v] => (k.to_string(), v.to_string()),
+            _ => return Err(format!("bad field: {}", field)),
+        };
+        let value = match value.as_str() {
+            "t" | "T" | "true" | "True" | "TRUE" => 1.0,
+            "f" | "F" | "false" | "False" | "FALSE" => 0.0,
+            v if v.starts_with('"') => continue,
+            v => v
+                .trim_end_matches(['i', 'u'])
+                .parse()
+                .map_err(|_| format!("bad field value: {}", field))?,
+        };
+
+        let mut labels = labels.clone();
+        labels.push((
+            "__name__".into(),
+            sanitize(&format!("{}_{}", measurement, key)),
+        ));
+        labels.sort();
+        series.push(Series {
+            labels,
+            value,
+            timestamp_ms,
+        });
+    }
+    Ok(series)
+}
+
+// `name[{label="value",...}] value [timestamp_ms]`
+fn parse_exposition(line: &str, now_ms: i64) -> Result<Series, String> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .ok_or_else(|| format!("bad metric line: {}", line))?;
+    let mut labels = vec![("__name__".to_string(), sanitize(&line[..name_end]))];
+
+    let mut rest = &line[name_end..];
+    if rest.starts_with('{') {
+        let (parsed, after) = parse_labels(&rest[1..])?;
+        labels.extend(parsed);
+        rest = after;
+    }
+
+    let mut tokens = rest.split_whitespace();
+    let value = match tokens.next() {
+        Some("+Inf") => f64::INFINITY,
+        Some("-Inf") => f64::NEG_INFINITY,
+        Some(v) => v.parse().map_err(|_| format!("bad value: {}", v))?,
+        None => return Err(format!("missing value: {}", line)),
+    };
+    let timestamp_ms = match tokens.next() {
+        Some(ts) => ts.parse().map_err(|_| "bad timestamp")?,
+        None => now_ms,
+    };
+
+    labels.sort();
+    Ok(Series {
+        labels,
+        value,
+        timestamp_ms,
+    })
+}
+
+// Parse `name="value",...}`, returning the labels and the text after the closing brace.
+fn parse_labels(s: &str) -> Result<(Labels, &str), String> {
+    let mut labels = vec![];
+    let mut chars = s.char_indices().peekable();
+
+    loop {
+        while let Some((_, c)) = chars.peek() {
+            if *c == ',' || c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let (start, c) = chars.next().ok_or("unterminated labels")?;
+        if c == '}' {
+            return Ok((labels, &s[start + 1..]));
+        }
+
+        let eq = s[start..].find('=').ok_or("label without value")? + start;
+        let name = s[start..eq].trim().to_string();
+        while let Some((i, _)) = chars.peek() {
+            if *i > eq {
+                break;
+            }
+            chars.next();
+        }
+        if chars.next().map(|(_, c)| c) != Some('"') {
+            return Err("label value must be quoted".into());
+        }
+
+        let mut value = String::new();
+        loop {
+            match chars.next().ok_or("unterminated label value")? {
+                (_, '"') => break,
+                (_, '\\') => match chars.next().ok_or("unterminated label value")?.1 {
+                    'n' => value.push('\n'),
+                    c => value.push(c),
+                },
+                (_, c) => value.push(c),
+            }
+        }
+        labels.push((name, value));
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'a'..='z' | 'A'..='Z' | '_' | ':' => c,
+            '0'..='9' if i > 0 => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+// prometheus.WriteRequest { repeated TimeSeries timeseries = 1; }
+// TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }
+// Label { string name = 1; string value = 2; }
+// Sample { double value = 1; int64 timestamp = 2; }
+pub fn encode_write_request(series: &[Series]) -> Vec<u8> {
+    let mut request = vec![];
+    for s in series {
+        let mut ts = vec![];
+        for (name, value) in &s.labels {
+            let mut label = vec![];
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut ts, 1, &label);
+        }
+
+        let mut sample = vec![0x09];
+        sample.extend_from_slice(&s.value.to_le_bytes());
+        sample.push(0x10);
+        put_varint(&mut sample, s.timestamp_ms as u64);
+        put_bytes(&mut ts, 2, &sample);
+
+        put_bytes(&mut request, 1, &ts);
+    }
+    request
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    buf.push(field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Wrap data in a snappy block made of literals only. This is valid snappy that any decoder
+/// accepts; remote_write payloads are small enough that skipping compression costs little.
+pub fn snappy_literal(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    put_varint(&mut out, data.len() as u64);
+    for chunk in data.chunks(65536) {
+        let n = chunk.len() - 1;
+        if n < 60 {
+            out.push((n as u8) << 2);
+        } else if n < 256 {
+            out.push(60 << 2);
+            out.push(n as u8);
+        } else {
+            out.push(61 << 2);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+#[test]
+fn test_prometheus_parse() {
+    let series = parse(
+        "# TYPE temp gauge\n\
+         temp{room=\"lab \\\"a\\\"\",floor=\"2\"} 21.5 1600000000000\n\
+         weather,station=north temp=3.5,ok=t,note=\"x\" 1600000000000000000\n",
+    )
+    .unwrap();
+
+    assert_eq!(series.len(), 3);
+    assert_eq!(
+        series[0].labels,
+        vec![
+            ("__name__".to_string(), "temp".to_string()),
+            ("floor".to_string(), "2".to_string()),
+            ("room".to_string(), "lab \"a\"".to_string()),
+        ]
+    );
+    assert_eq!(series[0].value, 21.5);
+    assert_eq!(series[1].labels[0].1, "weather_temp");
+    assert_eq!(series[1].timestamp_ms, 1_600_000_000_000);
+    assert_eq!(series[2].value, 1.0);
+}
+
+#[test]
+fn test_prometheus_snappy_literal() {
+    let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    let block = snappy_literal(&data);
+    assert_eq!(&block[..2], &[0xac, 0x02]);
+    assert_eq!(block[2], 61 << 2);
+    assert_eq!(&block[5..], data.as_slice());
+}
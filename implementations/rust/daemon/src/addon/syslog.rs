@@ -0,0 +1,224 @@
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+
+use crate::addon::Addon;
+use crate::clock::Utc;
+
+use ockam_message::message::Message as OckamMessage;
+
+use native_tls::{TlsConnector, TlsStream};
+
+const SEVERITY_INFO: u8 = 6;
+const DEFAULT_FACILITY: u8 = 1; // user-level messages
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// Forwards each line of a received payload to a syslog collector as an RFC 5424 message.
+/// Stream transports use octet-counting framing (RFC 6587) and stay connected between payloads.
+pub struct Syslog {
+    transport: Transport,
+    host: String,
+    port: u16,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    connection: Option<Connection>,
+}
+
+impl Syslog {
+    /// Parse `--addon syslog,udp|tcp|tls://host[:port][,facility=NAME][,hostname=NAME]
+    /// [,app_name=NAME]`. The port defaults to 514, or 6514 for TLS.
+    pub fn parse(endpoint: &str, options: &[&str]) -> Result<Syslog, String> {
+        let (transport, rest) = match endpoint.splitn(2, "://").collect::<Vec<&str>>().as_slice() {
+            ["udp", rest] => (Transport::Udp, *rest),
+            ["tcp", rest] => (Transport::Tcp, *rest),
+            ["tls", rest] => (Transport::Tls, *rest),
+            _ => return Err("syslog endpoint must start with udp://, tcp:// or tls://".into()),
+        };
+        let default_port = match transport {
+            Transport::Tls => 6514,
+            _ => 514,
+        };
+        let (host, port) = match rest.rsplitn(2, ':').collect::<Vec<&str>>().as_slice() {
+            [port, host] => (
+                host.to_string(),
+                port.parse().map_err(|_| "bad syslog port")?,
+            ),
+            _ => (rest.to_string(), default_port),
+        };
+
+        let mut syslog = Syslog {
+            transport,
+            host,
+            port,
+            facility: DEFAULT_FACILITY,
+            hostname: local_hostname(),
+            app_name: "ockamd".into(),
+            connection: None,
+        };
+
+        for option in options {
+            match option.splitn(2, '=').collect::<Vec<&str>>().as_slice() {
+                ["facility", f] => syslog.facility = facility(f)?,
+                ["hostname", h] => syslog.hostname = h.to_string(),
+                ["app_name", a] => syslog.app_name = a.to_string(),
+                _ => return Err(format!("unknown syslog option: {}", option)),
+            }
+        }
+
+        Ok(syslog)
+    }
+
+    /// Format one RFC 5424 message with informational severity.
+    pub fn format(&self, msg: &str) -> String {
+        format!(
+            "<{}>1 {} {} {} - - - {}",
+            self.facility * 8 + SEVERITY_INFO,
+            Utc::now().rfc3339(),
+            self.hostname,
+            self.app_name,
+            msg
+        )
+    }
+
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), String> {
+        let text = String::from_utf8_lossy(payload);
+
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            if self.connection.is_none() {
+                self.connection = Some(self.connect()?);
+            }
+
+            let msg = self.format(line);
+            let result = match self.connection.as_mut().unwrap() {
+                Connection::Udp(s) => s.send(msg.as_bytes()).map(|_| ()),
+                Connection::Tcp(s) => write!(s, "{} {}", msg.len(), msg),
+                Connection::Tls(s) => write!(s, "{} {}", msg.len(), msg),
+            };
+            if let Err(e) = result {
+                // reconnect on the next message
+                self.connection = None;
+                return Err(format!("failed to send to syslog: {}", e));
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&self) -> Result<Connection, String> {
+        let addr = format!("{}:{}", self.host, self.port);
+        match self.transport {
+            Transport::Udp => {
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+                socket.connect(&addr).map_err(|e| e.to_string())?;
+                Ok(Connection::Udp(socket))
+            }
+            Transport::Tcp => TcpStream::connect(&addr)
+                .map(Connection::Tcp)
+                .map_err(|e| format!("failed to connect to syslog {}: {}", addr, e)),
+            Transport::Tls => {
+                let tcp = TcpStream::connect(&addr)
+                    .map_err(|e| format!("failed to connect to syslog {}: {}", addr, e))?;
+                let connector = TlsConnector::new().map_err(|e| e.to_string())?;
+                connector
+                    .connect(&self.host, tcp)
+                    .map(|s| Connection::Tls(Box::new(s)))
+                    .map_err(|e| format!("syslog TLS handshake failed: {}", e))
+            }
+        }
+    }
+}
+
+/// Create the addon from `--addon syslog,ENDPOINT[,option=value...]`.
+pub fn init(options: &[&str]) -> Result<Box<dyn Addon>, String> {
+    match options {
+        [endpoint, options @ ..] => Ok(Box::new(Syslog::parse(endpoint, options)?)),
+        [] => Err("bad configuration: syslog addon needs an endpoint".into()),
+    }
+}
+
+impl Addon for Syslog {
+    fn handle(&mut self, msg: &OckamMessage) -> Result<(), String> {
+        self.send(&msg.message_body)
+    }
+
+    fn shutdown(&mut self) -> Result<(), String> {
+        self.connection = None;
+        Ok(())
+    }
+}
+
+fn facility(name: &str) -> Result<u8, String> {
+    let names = [
+        "kern",
+        "user",
+        "mail",
+        "daemon",
+        "auth",
+        "syslog",
+        "lpr",
+        "news",
+        "uucp",
+        "cron",
+        "authpriv",
+        "ftp",
+        "ntp",
+        "security",
+        "console",
+        "solaris-cron",
+        "local0",
+        "local1",
+        "local2",
+        "local3",
+        "local4",
+        "local5",
+        "local6",
+        "local7",
+    ];
+    names
+        .iter()
+        .position(|n| *n == name)
+        .map(|i| i as u8)
+        .or_else(|| name.parse().ok().filter(|f| *f < 24))
+        .ok_or_else(|| format!("unknown syslog facility: {}", name))
+}
+
+fn local_hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "-".into())
+}
+
+#[test]
+fn test_syslog_parse_and_format() {
+    let syslog = Syslog::parse(
+        "tcp://logs.example.com",
+        &["facility=local4", "hostname=gateway-7"],
+    )
+    .unwrap();
+    assert_eq!(syslog.port, 514);
+    assert_eq!(syslog.facility, 20);
+
+    let msg = syslog.format("door opened");
+    assert!(msg.starts_with("<166>1 "));
+    assert!(msg.ends_with(" gateway-7 ockamd - - - door opened"));
+
+    assert_eq!(
+        Syslog::parse("tls://logs.example.com", &[]).unwrap().port,
+        6514
+    );
+    assert!(Syslog::parse("http://logs.example.com", &[]).is_err());
+}
@@ -0,0 +1,242 @@
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::addon::Addon;
+
+use ockam_message::message::Message as OckamMessage;
+
+use attohttpc::header::HeaderName;
+use url::Url;
+
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF_MS: u64 = 500;
+
+/// POSTs each received payload to an HTTP endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    url: Url,
+    headers: Vec<(String, String)>,
+    content_type: String,
+    template: Option<String>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Webhook {
+    /// Parse the options following the URL in `--addon webhook,URL[,option=value...]`:
+    /// `header=Name:Value` (repeatable), `content_type=TYPE`, `template=FILE`, `retries=N` and
+    /// `backoff_ms=N`. A template file is read once here, and may use the `{{payload}}` and
+    /// `{{timestamp}}` (seconds since the Unix epoch) placeholders.
+    pub fn parse(url: &str, options: &[&str]) -> Result<Webhook, String> {
+        let mut webhook = Webhook {
+            url: Url::parse(url).map_err(|_| "expected valid URL".to_string())?,
+            headers: vec![],
+            content_type: DEFAULT_CONTENT_TYPE.into(),
+            template: None,
+            retries: DEFAULT_RETRIES,
+            backoff: Duration::from_millis(DEFAULT_BACKOFF_MS),
+        };
+
+        for option in options {
+            match option.splitn(2, '=').collect::<Vec<&str>>().as_slice() {
+                ["header", header] => match header.splitn(2, ':').collect::<Vec<&str>>().as_slice()
+                {
+                    [name, value] => {
+                        HeaderName::from_bytes(name.trim().as_bytes())
+                            .map_err(|_| format!("invalid header name: {}", name))?;
+                        webhook
+                            .headers
+                            .push((name.trim().into(), value.trim().into()));
+                    }
+                    _ => return Err("webhook header must be given as Name:Value".into()),
+                },
+                ["content_type", t] => webhook.content_type = t.to_string(),
+                ["template", path] => {
+                    webhook.template = Some(
+                        std::fs::read_to_string(path)
+                            .map_err(|e| format!("failed to read template {}: {}", path, e))?,
+                    )
+                }
+                ["retries", n] => {
+                    webhook.retries = n.parse().map_err(|_| "retries must be a number")?
+                }
+                ["backoff_ms", n] => {
+                    webhook.backoff =
+                        Duration::from_millis(n.parse().map_err(|_| "backoff_ms must be a number")?)
+                }
+                _ => return Err(format!("unknown webhook option: {}", option)),
+            }
+        }
+
+        Ok(webhook)
+    }
+
+    /// Build the request body for a payload, applying the template if one is configured. With a
+    /// JSON content type the payload is escaped, to go between quotes in the template.
+    pub fn render(&self, payload: &[u8]) -> Vec<u8> {
+        let template = match &self.template {
+            Some(t) => t,
+            None => return payload.to_vec(),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+            .to_string();
+        let payload = String::from_utf8_lossy(payload);
+        let payload = if self.is_json() {
+            json_escape(&payload)
+        } else {
+            payload.into_owned()
+        };
+
+        // one pass over the template, so placeholders in the payload are left alone
+        let mut body = String::with_capacity(template.len() + payload.len());
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find("{{") {
+            body.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if let Some(after) = rest.strip_prefix("{{payload}}") {
+                body.push_str(&payload);
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("{{timestamp}}") {
+                body.push_str(&timestamp);
+                rest = after;
+            } else {
+                body.push_str("{{");
+                rest = &rest[2..];
+            }
+        }
+        body.push_str(rest);
+        body.into_bytes()
+    }
+
+    // Whether the content type is JSON, e.g. `application/json; charset=utf-8` or
+    // `application/vnd.api+json`.
+    fn is_json(&self) -> bool {
+        let media_type = self.content_type.split(';').next().unwrap_or("");
+        let media_type = media_type.trim().to_ascii_lowercase();
+        media_type == "application/json" || media_type.ends_with("+json")
+    }
+
+    /// Deliver a payload, retrying with exponential backoff on connection failures, 429 and 5xx
+    /// responses. Other error responses are not retried.
+    pub fn post(&self, payload: &[u8]) -> Result<(), String> {
+        let body = self.render(payload);
+        let mut delay = self.backoff;
+
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+
+            let mut request = attohttpc::post(self.url.as_str());
+            for (name, value) in &self.headers {
+                let name = HeaderName::from_bytes(name.as_bytes()).unwrap();
+                request = request
+                    .try_header(name, value.as_str())
+                    .map_err(|e| format!("invalid webhook header: {}", e))?;
+            }
+            let request = request
+                .try_header("content-type", self.content_type.as_str())
+                .map_err(|e| format!("invalid webhook content type: {}", e))?;
+
+            match request.bytes(&body).send() {
+                Ok(resp) => {
+                    let status = resp.status();
+                    if status.is_success() {
+                        return Ok(());
+                    }
+                    if !(status.is_server_error() || status.as_u16() == 429) {
+                        return Err(format!("webhook rejected payload: {}", status));
+                    }
+                    eprintln!("webhook attempt {} failed: {}", attempt + 1, status);
+                }
+                Err(e) => eprintln!("webhook attempt {} failed: {}", attempt + 1, e),
+            }
+        }
+
+        Err(format!(
+            "webhook gave up after {} attempts",
+            self.retries + 1
+        ))
+    }
+}
+
+/// Create the addon from `--addon webhook,URL[,option=value...]`.
+pub fn init(options: &[&str]) -> Result<Box<dyn Addon>, String> {
+    match options {
+        [url, options @ ..] => Ok(Box::new(Webhook::parse(url, options)?)),
+        [] => Err("bad configuration: webhook addon needs a URL".into()),
+    }
+}
+
+// `s` escaped as the inside of a JSON string.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Addon for Webhook {
+    fn handle(&mut self, msg: &OckamMessage) -> Result<(), String> {
+        self.post(&msg.message_body)
+            .map_err(|e| format!("failed to send to webhook: {}", e))
+    }
+}
+
+#[test]
+fn test_webhook_parse_and_render() {
+    let path = std::env::temp_dir().join("ockamd_test_webhook_template");
+    std::fs::write(&path, r#"{"at":{{timestamp}},"reading":"{{payload}}"}"#).unwrap();
+    let template = format!("template={}", path.display());
+
+    let webhook = Webhook::parse(
+        "http://localhost:8080/ingest",
+        &["header=X-Api-Key:secret", "retries=1", template.as_str()],
+    )
+    .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(webhook.retries, 1);
+    assert_eq!(
+        webhook.headers,
+        vec![("X-Api-Key".to_string(), "secret".to_string())]
+    );
+    let body = String::from_utf8(webhook.render(b"21.5")).unwrap();
+    assert!(body.starts_with(r#"{"at":"#));
+    assert!(body.ends_with(r#","reading":"21.5"}"#));
+
+    assert!(Webhook::parse("http://localhost:8080", &["bogus=1"]).is_err());
+}
+
+#[test]
+fn test_webhook_render_json() {
+    let mut webhook = Webhook::parse("http://localhost:8080/ingest", &[]).unwrap();
+    webhook.template = Some(r#"{"at":{{timestamp}},"reading":"{{payload}}"}"#.into());
+
+    // placeholders in the payload aren't substituted
+    let body = String::from_utf8(webhook.render(b"{{timestamp}}")).unwrap();
+    assert!(body.ends_with(r#","reading":"{{timestamp}}"}"#));
+
+    // nor is it escaped unless the content type is JSON
+    let payload = b"say \"hi\"\\\n\x01";
+    let body = String::from_utf8(webhook.render(payload)).unwrap();
+    assert!(body.ends_with("\"reading\":\"say \"hi\"\\\n\x01\"}"));
+
+    webhook.content_type = "application/json; charset=utf-8".into();
+    let body = String::from_utf8(webhook.render(payload)).unwrap();
+    assert!(body.ends_with(r#","reading":"say \"hi\"\\\n\u0001"}"#));
+}
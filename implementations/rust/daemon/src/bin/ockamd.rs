@@ -1,22 +1,74 @@
 use ockamd::{
     cli::{
         Args,
-        ChannelRole::{Initiator, Responder},
         Mode::{Control, Server},
     },
-    initiator, responder,
+    Builder,
 };
 
 fn main() {
+    // `ockamd key ...` manages the identity keys in the vault
+    let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    if args.get(1).map(|a| a == "key").unwrap_or(false) {
+        if let Err(e) = ockamd::key::main(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `ockamd ping ROUTE` checks a route and channel to a responder's echo service
+    if args.get(1).map(|a| a == "ping").unwrap_or(false) {
+        if let Err(e) = ockamd::ping::main(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `ockamd discover ROUTE NAME` looks up the route to a service through a node's neighbors
+    if args.get(1).map(|a| a == "discover").unwrap_or(false) {
+        if let Err(e) = ockamd::discovery::main(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `ockamd send ROUTE FILE` sends a file to a responder's file-transfer service
+    if args.get(1).map(|a| a == "send").unwrap_or(false) {
+        if let Err(e) = ockamd::transfer::main(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `ockamd browse` lists the nodes advertised with mDNS on the local network
+    if args.get(1).map(|a| a == "browse").unwrap_or(false) {
+        if let Err(e) = ockamd::mdns::main(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `ockamd service ...` installs, removes or runs ockamd as a Windows service
+    #[cfg(windows)]
+    {
+        if args.get(1).map(|a| a == "service").unwrap_or(false) {
+            if let Err(e) = ockamd::service::main(&args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
     let args = Args::parse();
-    let role = args.role();
-    let mode = args.exec_mode();
-    let cfg = args.into();
-
-    match mode {
-        Server if matches!(role, Initiator) => initiator::run(cfg),
-        Server if matches!(role, Responder) => responder::run(cfg),
-        Server => eprintln!("server mode must be executed with a role"),
+
+    match args.exec_mode() {
+        Server => Builder::new(args.into()).run(),
         Control => unimplemented!(),
     }
 }
@@ -0,0 +1,48 @@
+use crate::addon::{AddonInit, AddonRegistry};
+use crate::config::{Config, Role};
+use crate::{daemonize, gateway, initiator, relay, responder};
+
+/// Runs `ockamd` from a [`Config`]. Crates embedding the daemon can register their own addons
+/// here, which are then selected with `--addon NAME,...` like the built-in ones.
+pub struct Builder {
+    config: Config,
+    addons: AddonRegistry,
+}
+
+impl Builder {
+    pub fn new(config: Config) -> Self {
+        Builder {
+            config,
+            addons: AddonRegistry::default(),
+        }
+    }
+
+    /// Register an addon under `name`. Registering a built-in addon's name replaces it.
+    pub fn addon(mut self, name: &str, init: AddonInit) -> Self {
+        self.addons.register(name, init);
+        self
+    }
+
+    pub fn run(self) {
+        if let Err(e) = daemonize::start(&self.config) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        // once detached, so that stderr is the log file
+        if self.config.trace() {
+            let _ = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::DEBUG)
+                .with_writer(std::io::stderr)
+                .with_ansi(false)
+                .try_init();
+        }
+
+        match self.config.role() {
+            Role::Initiator => initiator::run(self.config),
+            Role::Responder => responder::run(self.config, self.addons),
+            Role::Both => gateway::run(self.config, self.addons),
+            Role::Relay => relay::run(self.config),
+        }
+    }
+}
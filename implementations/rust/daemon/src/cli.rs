@@ -1,10 +1,17 @@
+use std::ffi::OsString;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use crate::addon::AddonSpec;
+use crate::config::ServiceSpec;
+
 use ockam_message::message::{Route, RouterAddress};
 
-use structopt::{clap::ArgSettings::Hidden, StructOpt};
+use structopt::{
+    clap::{AppSettings::AllArgsOverrideSelf, ArgSettings::Hidden},
+    StructOpt,
+};
 use url::Url;
 
 /// The port on which the config updater runs and accepts Config messages.
@@ -12,6 +19,7 @@ pub const DEFAULT_CONFIG_PORT: u16 = 11199;
 
 pub const FILENAME_KEY_SUFFIX: &str = ".key";
 pub const FILENAME_KEY_DEFAULT: &str = "1.key";
+pub const DEFAULT_VAULT_PATH: &str = "ockamd_vault";
 
 const DEFAULT_LOCAL_SOCKET: &str = "127.0.0.1:0";
 
@@ -20,24 +28,59 @@ const DEFAULT_LOCAL_SOCKET: &str = "127.0.0.1:0";
 #[derive(StructOpt)]
 #[structopt(
     author = "Ockam Developers (ockam.io)",
-    about = "Encrypt, route, and decrypt messages using the Ockam daemon."
+    about = "Encrypt, route, and decrypt messages using the Ockam daemon.",
+    after_help = "Each option may also be set by an OCKAMD_* environment variable, e.g. OCKAMD_LOCAL_SOCKET for --local-socket, which overrides the --config file and is overridden by the command line. Flags take \"true\" or \"false\", and repeatable options take several values separated by \";\".",
+    global_settings = &[AllArgsOverrideSelf]
 )]
 pub struct Args {
     /// Defines the kind of input from which a message should be read.
     #[structopt(
         long,
         default_value = "stdin",
-        help = "Data source providing input to `ockamd`"
+        help = r#"Data source providing input to `ockamd`: "stdin" or a local listener, e.g. tcp://127.0.0.1:4100 or unix:///run/ockamd.sock or pipe://ockamd (Windows), or a file to follow, e.g. file:///var/log/sensor.log"#
     )]
     input: InputKind,
 
-    /// Defines the route where a message should be sent.
+    /// Defines how records are delimited within the input, whichever input kind is used.
+    #[structopt(
+        long,
+        default_value = "newline",
+        help = r#"How input records are delimited: "newline", "length" (4-byte big-endian length prefix) or "chunk:N" (raw N-byte chunks)"#
+    )]
+    framing: FramingKind,
+
+    /// Defines how a responder writes received payloads to stdout, when no addon is set.
+    #[structopt(
+        long,
+        default_value = "raw",
+        help = r#"How received payloads are written to stdout: "raw", "hex" or "base64" (one payload per line), or "json" (one object per line, with the time, service, channel and size)"#
+    )]
+    output_encoding: EncodingKind,
+
+    /// Defines the routes where a message should be sent; repeat to mirror input to several
+    /// channel responders. Routes from a `--config` file are combined with those given here.
     #[structopt(
         long,
         default_value = "stdout",
-        help = r#"Route to channel responder, e.g. udp://host:port[,udp://host:port] (note comma-separation) or "stdout""#
+        number_of_values = 1,
+        help = r#"Route to channel responder, e.g. udp://host:port[,udp://host:port] (note comma-separation), ending with worker://ALIAS for a responder registered with a relay's hub, or "stdout"; repeat for each additional output"#
+    )]
+    route: Vec<OutputKind>,
+
+    /// Make a responder reachable through a relay when it can't accept inbound connections.
+    #[structopt(
+        long,
+        help = "Route to a relay whose hub service gives this responder an alias, e.g. udp://relay:4050; initiators reach the responder through the relay at the alias printed once registered"
+    )]
+    hub: Option<OutputKind>,
+
+    /// Nodes this one advertises its services, and those it has learned of, to.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Route to a neighboring relay or responder whose discovery service is told the routes to this node's services, e.g. udp://relay:4050; repeat for each neighbor"
     )]
-    route: OutputKind,
+    neighbor: Vec<OutputKind>,
 
     #[structopt(
         long,
@@ -50,7 +93,7 @@ pub struct Args {
     #[structopt(
         long,
         default_value = "FILESYSTEM",
-        help = "Specify which type of Ockam vault to use for this instance of `ockamd`"
+        help = r#"Vault holding the identity and channel keys: "FILESYSTEM" (keys persist in --vault-path) or "MEMORY" (keys, including a responder's identity, are lost when `ockamd` stops)"#
     )]
     vault: VaultKind,
 
@@ -58,33 +101,50 @@ pub struct Args {
     #[structopt(
         parse(from_os_str),
         long,
-        default_value = "ockamd_vault",
+        default_value = DEFAULT_VAULT_PATH,
         required_if("vault", "FILESYSTEM"),
         help = "Filepath on disk to pre-existing private keys to be used by the filesystem vault"
     )]
     vault_path: PathBuf,
 
+    /// Seal the filesystem vault with the passphrase in the given file.
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "File holding the passphrase the filesystem vault's keys are encrypted with on disk; without it, they are stored in the clear"
+    )]
+    vault_passphrase_file: Option<PathBuf>,
+
     /// Start the `ockamd` process as the initiator or responder of a secure channel.
     #[structopt(
         long,
         default_value = "initiator",
-        help = r#"Start `ockamd` as an "initiator" or a "responder" of a secure channel"#
+        help = r#"Start `ockamd` as an "initiator" or a "responder" of a secure channel, "both" to accept channels while initiating its own, or "relay" to only forward messages between hops"#
     )]
     role: ChannelRole,
 
-    /// Define which private key to use as the initiator's identity.
+    /// Define which private key to use as the node's identity.
     #[structopt(
         long,
         default_value = FILENAME_KEY_DEFAULT,
-        help = "Name of the private key to use for the identity of the channel initiator"
+        help = "Name of the private key in the vault to use as this node's identity; a responder generates it on first use and reuses it after"
     )]
     identity_name: String,
 
+    /// Write the identity's public key, hex-encoded, so it can be shared with peers.
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "File to write the hex-encoded identity public key to"
+    )]
+    public_key_file: Option<PathBuf>,
+
     /// Define the public key provided by the remote service.
     #[structopt(
         long,
         required_if("role", "initiator"),
         required_if("role", "init"),
+        required_if("role", "both"),
         help = "The public key provided by the remote service"
     )]
     service_public_key: Option<String>,
@@ -93,15 +153,261 @@ pub struct Args {
         long,
         required_if("role", "initiator"),
         required_if("role", "init"),
-        help = "Address used to reach the service on remote machine"
+        required_if("role", "both"),
+        help = "Address used to reach the service on remote machine, or its name as service:NAME, which the responder resolves"
     )]
     service_address: Option<String>,
 
+    /// Detect channels whose other end has gone away, e.g. restarted, so they can be closed,
+    /// and those this node initiated re-established.
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "Seconds a channel may go without hearing from the other end before it sends an encrypted keepalive; a channel missing three is closed, and initiated ones re-established with backoff. 0 disables keepalives"
+    )]
+    keepalive_secs: u64,
+
+    #[structopt(
+        long,
+        default_value = "600",
+        help = "Seconds a channel may go without sending or receiving anything before it is closed and its keys destroyed; keepalives count. 0 keeps idle channels open"
+    )]
+    channel_idle_timeout_secs: u64,
+
+    /// Defines the cipher suite of the key exchange, and so the AEAD channels encrypt with.
+    #[structopt(
+        long,
+        default_value = "aes-gcm",
+        help = r#"Cipher suite channels agree keys with: "aes-gcm" (Curve25519, AES-256-GCM, SHA-256) or "chacha-poly" (Curve25519, ChaCha20-Poly1305, SHA-256), faster on devices without AES instructions. Both ends must use the same suite"#
+    )]
+    cipher_suite: CipherSuiteKind,
+
+    #[structopt(
+        long,
+        default_value = "64",
+        help = "Payloads a channel holds when they arrive ahead of those sent before them, e.g. over UDP, so they are delivered in order; 1 delivers them as they arrive"
+    )]
+    reorder_window: u64,
+
+    #[structopt(
+        long,
+        default_value = "200",
+        help = "Milliseconds a payload is held waiting for those sent before it, which are then given up as lost"
+    )]
+    reorder_delay_ms: u64,
+
+    #[structopt(
+        long,
+        help = "Largest payload, in bytes as encoded, a channel sends in one message; larger ones are split into fragments which the other end puts back together, for transports that can't carry them. Without it, payloads are sent whole"
+    )]
+    channel_mtu: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Deflate payloads larger than this many bytes as encoded before encrypting them, on channels whose other end is given this option too. Without it, payloads are sent as they are"
+    )]
+    channel_compress_over: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Acknowledge the frames channels receive, and send those the other end doesn't acknowledge again, on channels whose other end is given this option too, so that payloads lost by UDP are recovered"
+    )]
+    channel_reliable: bool,
+
+    #[structopt(
+        long,
+        default_value = "16777216",
+        help = "Largest message, in bytes, a channel accepts, and largest payload it delivers as encoded; larger ones are dropped before they are decrypted or held whole"
+    )]
+    channel_max_message_size: usize,
+
+    #[structopt(
+        long,
+        default_value = "256",
+        help = "Key exchanges a responder answers at a time; M1s which arrive while as many are under way are dropped, and those which don't complete in 10 seconds give way to others"
+    )]
+    max_half_open_channels: usize,
+
+    #[structopt(
+        long,
+        default_value = "60",
+        help = "Longest wait, in seconds, between attempts to re-establish a channel or restart a failed worker"
+    )]
+    max_backoff_secs: u64,
+
+    #[structopt(
+        long,
+        default_value = "10000",
+        help = "Input records held for each route while its channel is down; the oldest are dropped beyond this"
+    )]
+    buffer_limit: usize,
+
+    /// Restart a service worker whose addon fails or panics, rather than the whole daemon.
+    #[structopt(
+        long,
+        default_value = "5",
+        help = "Times a failed service worker is restarted, with backoff, before it is left stopped; a handled message resets the count"
+    )]
+    worker_max_restarts: u32,
+
+    /// Bound the messages waiting for each worker, so that a slow addon can't exhaust memory.
+    #[structopt(
+        long,
+        default_value = "1000",
+        help = "Messages queued for each worker before --worker-overflow applies"
+    )]
+    worker_mailbox_size: usize,
+
+    #[structopt(
+        long,
+        default_value = "drop-oldest",
+        help = r#"What a full worker mailbox does with new messages: "block" pauses the node's network traffic until the worker catches up, "drop-oldest" or "drop-newest" discards a message"#
+    )]
+    worker_overflow: OverflowKind,
+
+    /// Restrict the initiators a responder completes channels with to an enrolled set.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Hex-encoded public key of an initiator allowed to open channels to this responder; repeat for each key. Once a key or key file is given, all other initiators are refused"
+    )]
+    allowed_initiator: Vec<String>,
+
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "File of allowed initiator public keys, one hex-encoded key per line; lines starting with `#` are ignored"
+    )]
+    allowed_initiators_file: Option<PathBuf>,
+
     #[structopt(
         long,
-        help = r#"Pre-defined configuration for an official Ockam Add-on, e.g. "influxdb,database_name,http://localhost:8086""#
+        help = r#"Add-on that receives messages, as its name followed by its options, e.g. "influxdb,database_name,http://localhost:8086", "webhook,http://localhost:8080/ingest,header=Name:Value,retries=3", "prometheus,http://localhost:9090/api/v1/write", "syslog,tls://siem.local,facility=local0" or "s3,https://s3.amazonaws.com/bucket,prefix=raw/""#
     )]
-    addon: Option<Addon>,
+    addon: Option<AddonSpec>,
+
+    /// Host several services on a responder, each with its own address and addon.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = r#"Service hosted by a responder, as its name, worker address and optionally an add-on, e.g. "telemetry=01242020,influxdb,db,http://localhost:8086" or "commands=0a0b0c0d" (written to stdout); repeat for each service. Replaces the default service at 01242020 that uses --addon"#
+    )]
+    service: Vec<ServiceSpec>,
+
+    /// Keep the state of each device apart, e.g. a connection of its own to the addon.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Name of a service which starts a worker, with its own addon, for each sender; repeat for each service. The first message from a sender starts its session, and replies come from the session's address"
+    )]
+    session_service: Vec<String>,
+
+    #[structopt(
+        long,
+        help = "Host an echo service, which returns each payload to its sender, for `ockamd ping`"
+    )]
+    echo: bool,
+
+    #[structopt(
+        long,
+        help = "Host a pub/sub broker, which sends each publication to a topic on to the routes subscribed to it"
+    )]
+    broker: bool,
+
+    /// Publish the input to a broker rather than send it to a service.
+    #[structopt(
+        long,
+        help = "Topic an initiator publishes each input record to, at a broker given by --service-address 70756273"
+    )]
+    topic: Option<String>,
+
+    /// Hold messages for devices which are rarely online, until they next connect.
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "Directory where a mailbox service keeps the messages deposited for identities which have completed a channel to this node, to deliver over the next channel each completes"
+    )]
+    mailbox_dir: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        default_value = "1000",
+        help = "Messages the mailbox holds for each identity; deposits beyond this are refused"
+    )]
+    mailbox_limit: usize,
+
+    #[structopt(
+        long,
+        default_value = "604800",
+        help = "Seconds the mailbox holds each message before discarding it undelivered"
+    )]
+    mailbox_ttl_secs: u64,
+
+    /// Receive files, e.g. firmware images, sent with `ockamd send`.
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "Directory a file-transfer service receives files into, keeping partial files there so that interrupted transfers resume"
+    )]
+    transfer_dir: Option<PathBuf>,
+
+    /// Find peers on the local network without configuring routes, e.g. in a lab.
+    #[structopt(
+        long,
+        help = "Advertise this node and its services on the local network with mDNS; an initiator also browses for the nodes hosting its --service-address and opens a channel to each as if given by --route"
+    )]
+    mdns: bool,
+
+    /// File of `key = value` lines, one per long option, re-read on SIGHUP.
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "Configuration file of `option = value` lines; command-line arguments take precedence"
+    )]
+    config: Option<PathBuf>,
+
+    /// Fork into the background, for init systems which don't manage foreground processes.
+    #[structopt(
+        long,
+        help = "Run `ockamd` in the background, detached from the terminal; relative paths are still resolved against the starting directory"
+    )]
+    daemonize: bool,
+
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "File to write the process ID to; startup fails if it names a running process"
+    )]
+    pid_file: Option<PathBuf>,
+
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "File to append output to once daemonized [default: output is discarded]"
+    )]
+    log_file: Option<PathBuf>,
+
+    /// Follow a message across the nodes of a route: every node logs it under the same ID.
+    #[structopt(
+        long,
+        help = "Log each message's handling by the channels, router and transport to stderr, or to the log file once daemonized, tagged with an ID which is the same at every node of its route"
+    )]
+    trace: bool,
+
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "Unix socket accepting control commands: list-channels, show-channel ADDRESS, show-identity, close-channel ADDRESS, reload-config, stats, list-workers, list-dead-letters, start-worker, stop-worker or restart-worker ADDRESS, and resolve-service NAME"
+    )]
+    control_socket: Option<PathBuf>,
+
+    /// Keep secure channels across restarts, instead of running new key exchanges.
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "File the established channels are saved to as ockamd stops, and resumed from once as it starts, removing the file; their keys stay in the vault meanwhile, so the filesystem vault is needed"
+    )]
+    channel_state_file: Option<PathBuf>,
 
     // TODO: expose `control` and `control_port` once runtime configuration is needed.
     #[structopt(
@@ -127,16 +433,56 @@ impl Default for Args {
             control: false,
             control_port: DEFAULT_CONFIG_PORT,
             input: InputKind::Stdin,
-            route: OutputKind::Stdout,
+            framing: FramingKind::Newline,
+            output_encoding: EncodingKind::Raw,
+            route: vec![OutputKind::Stdout],
+            hub: None,
+            neighbor: vec![],
             local_socket: SocketAddr::from_str(DEFAULT_LOCAL_SOCKET)
                 .expect("bad default set for local socket"),
             vault: VaultKind::Filesystem,
-            vault_path: PathBuf::from("ockamd_vault"),
+            vault_path: PathBuf::from(DEFAULT_VAULT_PATH),
+            vault_passphrase_file: None,
             role: ChannelRole::Responder,
             service_address: None,
             identity_name: format!("1{}", FILENAME_KEY_SUFFIX),
             service_public_key: None,
             addon: None,
+            service: vec![],
+            session_service: vec![],
+            echo: false,
+            broker: false,
+            topic: None,
+            mailbox_dir: None,
+            mailbox_limit: 1000,
+            mailbox_ttl_secs: 604800,
+            transfer_dir: None,
+            mdns: false,
+            config: None,
+            daemonize: false,
+            pid_file: None,
+            log_file: None,
+            trace: false,
+            control_socket: None,
+            channel_state_file: None,
+            public_key_file: None,
+            keepalive_secs: 10,
+            channel_idle_timeout_secs: 600,
+            cipher_suite: CipherSuiteKind::AesGcm,
+            reorder_window: 64,
+            reorder_delay_ms: 200,
+            channel_mtu: None,
+            channel_compress_over: None,
+            channel_reliable: false,
+            channel_max_message_size: 16 * 1024 * 1024,
+            max_half_open_channels: 256,
+            max_backoff_secs: 60,
+            buffer_limit: 10000,
+            worker_max_restarts: 5,
+            worker_mailbox_size: 1000,
+            worker_overflow: OverflowKind::DropOldest,
+            allowed_initiator: vec![],
+            allowed_initiators_file: None,
         }
     }
 }
@@ -146,7 +492,83 @@ impl Args {
     pub fn parse() -> Args {
         // validate provided arguments & override possibly fallible options
         // TODO: what should be disallowed that the CLI validation wont handle?
-        Args::from_args()
+        match Args::load(std::env::args_os()) {
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Parse the given command line, merging in the options from a `--config` file if one is
+    /// named and from `OCKAMD_*` environment variables. Options from the file come first, then
+    /// those from the environment, then the command-line arguments, so that each overrides the
+    /// ones before it.
+    pub fn load<I: IntoIterator<Item = OsString>>(cli: I) -> Result<Args, String> {
+        Args::load_with_env(cli, std::env::vars_os())
+    }
+
+    fn load_with_env<I, E>(cli: I, env: E) -> Result<Args, String>
+    where
+        I: IntoIterator<Item = OsString>,
+        E: IntoIterator<Item = (OsString, OsString)>,
+    {
+        let cli: Vec<OsString> = cli.into_iter().collect();
+        let env = env_args(env)?;
+
+        // the file has to be located before parsing, since it may supply required options
+        let mut config = None;
+        for args in [&env[..], &cli[1..]].iter() {
+            for (i, arg) in args.iter().enumerate() {
+                let arg = arg.to_string_lossy();
+                if arg == "--config" {
+                    config = args.get(i + 1).map(PathBuf::from);
+                } else if let Some(path) = arg.strip_prefix("--config=") {
+                    config = Some(PathBuf::from(path));
+                }
+            }
+        }
+
+        let mut merged = cli.iter().take(1).cloned().collect::<Vec<OsString>>();
+        if let Some(path) = config {
+            merged.extend(config_file_args(&path)?);
+        }
+        merged.extend(env);
+        merged.extend(cli.iter().skip(1).cloned());
+        Args::from_iter_safe(&merged).map_err(|e| e.message)
+    }
+
+    /// An initiator with a single route to the service at `service_address`, for commands
+    /// which open one channel, e.g. `ockamd ping`.
+    pub fn initiator(
+        route: Route,
+        service_address: &str,
+        service_public_key: Option<String>,
+        local_socket: SocketAddr,
+        vault_path: PathBuf,
+        identity_name: String,
+    ) -> Args {
+        Args {
+            role: ChannelRole::Initiator,
+            route: vec![OutputKind::Channel(route)],
+            service_address: Some(service_address.into()),
+            service_public_key,
+            local_socket,
+            vault_path,
+            identity_name,
+            ..Args::default()
+        }
+    }
+
+    /// A relay on `local_socket`, for commands which send plain messages rather than over a
+    /// channel, e.g. `ockamd discover`.
+    pub fn relay(local_socket: SocketAddr) -> Args {
+        Args {
+            role: ChannelRole::Relay,
+            local_socket,
+            ..Args::default()
+        }
     }
 
     /// Checks which mode the executable was run in: Control or Server.
@@ -161,22 +583,46 @@ impl Args {
         self.role
     }
 
-    pub fn output_kind(&self) -> OutputKind {
+    pub fn output_kinds(&self) -> Vec<OutputKind> {
         self.route.clone()
     }
 
+    pub fn hub(&self) -> Option<OutputKind> {
+        self.hub.clone()
+    }
+
+    pub fn neighbors(&self) -> Vec<OutputKind> {
+        self.neighbor.clone()
+    }
+
     pub fn input_kind(&self) -> InputKind {
         self.input.clone()
     }
 
+    pub fn framing(&self) -> FramingKind {
+        self.framing
+    }
+
+    pub fn output_encoding(&self) -> EncodingKind {
+        self.output_encoding
+    }
+
     pub fn local_socket(&self) -> SocketAddr {
         self.local_socket
     }
 
+    pub fn vault(&self) -> VaultKind {
+        self.vault
+    }
+
     pub fn vault_path(&self) -> PathBuf {
         self.vault_path.clone()
     }
 
+    pub fn vault_passphrase_file(&self) -> Option<PathBuf> {
+        self.vault_passphrase_file.clone()
+    }
+
     pub fn service_public_key(&self) -> Option<String> {
         self.service_public_key.clone()
     }
@@ -189,40 +635,241 @@ impl Args {
         self.identity_name.clone()
     }
 
-    pub fn addon(&self) -> Option<Addon> {
+    pub fn public_key_file(&self) -> Option<PathBuf> {
+        self.public_key_file.clone()
+    }
+
+    pub fn keepalive_secs(&self) -> u64 {
+        self.keepalive_secs
+    }
+
+    pub fn channel_idle_timeout_secs(&self) -> u64 {
+        self.channel_idle_timeout_secs
+    }
+
+    pub fn cipher_suite(&self) -> CipherSuiteKind {
+        self.cipher_suite
+    }
+
+    pub fn reorder_window(&self) -> u64 {
+        self.reorder_window
+    }
+
+    pub fn reorder_delay_ms(&self) -> u64 {
+        self.reorder_delay_ms
+    }
+
+    pub fn channel_mtu(&self) -> Option<usize> {
+        self.channel_mtu
+    }
+
+    pub fn channel_compress_over(&self) -> Option<usize> {
+        self.channel_compress_over
+    }
+
+    pub fn channel_reliable(&self) -> bool {
+        self.channel_reliable
+    }
+
+    pub fn channel_max_message_size(&self) -> usize {
+        self.channel_max_message_size
+    }
+
+    pub fn max_half_open_channels(&self) -> usize {
+        self.max_half_open_channels
+    }
+
+    pub fn max_backoff_secs(&self) -> u64 {
+        self.max_backoff_secs
+    }
+
+    pub fn buffer_limit(&self) -> usize {
+        self.buffer_limit
+    }
+
+    pub fn worker_max_restarts(&self) -> u32 {
+        self.worker_max_restarts
+    }
+
+    pub fn worker_mailbox_size(&self) -> usize {
+        self.worker_mailbox_size
+    }
+
+    pub fn worker_overflow(&self) -> OverflowKind {
+        self.worker_overflow
+    }
+
+    pub fn allowed_initiators(&self) -> Vec<String> {
+        self.allowed_initiator.clone()
+    }
+
+    pub fn allowed_initiators_file(&self) -> Option<PathBuf> {
+        self.allowed_initiators_file.clone()
+    }
+
+    pub fn addon(&self) -> Option<AddonSpec> {
         self.addon.clone()
     }
+
+    pub fn services(&self) -> Vec<ServiceSpec> {
+        self.service.clone()
+    }
+
+    pub fn session_services(&self) -> Vec<String> {
+        self.session_service.clone()
+    }
+
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    pub fn broker(&self) -> bool {
+        self.broker
+    }
+
+    pub fn topic(&self) -> Option<String> {
+        self.topic.clone()
+    }
+
+    pub fn transfer_dir(&self) -> Option<PathBuf> {
+        self.transfer_dir.clone()
+    }
+
+    pub fn mdns(&self) -> bool {
+        self.mdns
+    }
+
+    pub fn mailbox_dir(&self) -> Option<PathBuf> {
+        self.mailbox_dir.clone()
+    }
+
+    pub fn mailbox_limit(&self) -> usize {
+        self.mailbox_limit
+    }
+
+    pub fn mailbox_ttl_secs(&self) -> u64 {
+        self.mailbox_ttl_secs
+    }
+
+    pub fn config_file(&self) -> Option<PathBuf> {
+        self.config.clone()
+    }
+
+    pub fn daemonize(&self) -> bool {
+        self.daemonize
+    }
+
+    pub fn pid_file(&self) -> Option<PathBuf> {
+        self.pid_file.clone()
+    }
+
+    pub fn log_file(&self) -> Option<PathBuf> {
+        self.log_file.clone()
+    }
+
+    pub fn trace(&self) -> bool {
+        self.trace
+    }
+
+    pub fn control_socket(&self) -> Option<PathBuf> {
+        self.control_socket.clone()
+    }
+
+    pub fn channel_state_file(&self) -> Option<PathBuf> {
+        self.channel_state_file.clone()
+    }
 }
 
-#[derive(Debug, Clone)]
-pub enum Addon {
-    InfluxDb(Url, String),
+/// Read a configuration file into the equivalent long options, e.g. `route = udp://host:port`
+/// becomes `--route udp://host:port`. Underscores in keys are accepted in place of dashes, and
+/// flags are set with `true` or `false`. Blank lines and lines starting with `#` are ignored.
+fn config_file_args(path: &Path) -> Result<Vec<OsString>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
+
+    let mut args = vec![];
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line
+            .splitn(2, '=')
+            .map(str::trim)
+            .collect::<Vec<&str>>()
+            .as_slice()
+        {
+            [key, "false"] if !key.is_empty() => {}
+            [key, "true"] if !key.is_empty() => {
+                args.push(format!("--{}", key.replace('_', "-")).into());
+            }
+            [key, value] if !key.is_empty() => {
+                args.push(format!("--{}", key.replace('_', "-")).into());
+                args.push(value.trim_matches('"').into());
+            }
+            _ => return Err(format!("bad config file line {}: {}", n + 1, line)),
+        }
+    }
+
+    Ok(args)
 }
 
-impl FromStr for Addon {
-    type Err = String;
+/// The prefix of environment variables which set options, e.g. `OCKAMD_LOCAL_SOCKET` for
+/// `--local-socket`.
+const ENV_PREFIX: &str = "OCKAMD_";
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.split(',').collect::<Vec<&str>>().as_slice() {
-            ["influxdb", dbname_url @ ..] => {
-                if dbname_url.len() != 2 {
-                    return Err("bad configuration: influx addon needs db and url".into());
-                }
+/// Options which may be repeated; their environment variables hold `;`-separated values.
+const REPEATABLE_OPTIONS: &[&str] = &[
+    "route",
+    "service",
+    "session-service",
+    "allowed-initiator",
+    "neighbor",
+];
+
+// Read the `OCKAMD_*` variables as command-line arguments, taking "true" and "false" values of
+// flags the same way as the config file.
+fn env_args<E: IntoIterator<Item = (OsString, OsString)>>(env: E) -> Result<Vec<OsString>, String> {
+    let mut vars: Vec<(String, String)> = vec![];
+    for (key, value) in env {
+        let key = key.to_string_lossy();
+        if let Some(name) = key.strip_prefix(ENV_PREFIX) {
+            let value = value
+                .into_string()
+                .map_err(|_| format!("environment variable {} is not valid unicode", key))?;
+            vars.push((name.to_lowercase().replace('_', "-"), value));
+        }
+    }
+    // the environment's order is unspecified, so keep the arguments stable
+    vars.sort();
 
-                if let Ok(u) = Url::parse(dbname_url[1]) {
-                    Ok(Addon::InfluxDb(u, dbname_url[0].into()))
-                } else {
-                    Err("expected valid URL".into())
+    let mut args = vec![];
+    for (name, value) in vars {
+        match value.trim() {
+            "false" => {}
+            "true" => args.push(format!("--{}", name).into()),
+            value if REPEATABLE_OPTIONS.contains(&name.as_str()) => {
+                for value in value.split(';').map(str::trim).filter(|v| !v.is_empty()) {
+                    args.push(format!("--{}", name).into());
+                    args.push(value.into());
                 }
             }
-            _ => Err(format!("unknown configuration: {}", s)),
+            value => {
+                args.push(format!("--{}", name).into());
+                args.push(value.into());
+            }
         }
     }
+
+    Ok(args)
 }
 
 /// Specifies the implementation of a Ockam vault to be used.
+#[derive(Clone, Copy)]
 pub enum VaultKind {
     Filesystem,
+    Memory,
 }
 
 impl FromStr for VaultKind {
@@ -231,7 +878,29 @@ impl FromStr for VaultKind {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "FILESYSTEM" => Ok(VaultKind::Filesystem),
-            _ => Err("currently, 'FILESYSTEM' is the only supported vault option".into()),
+            "MEMORY" => Ok(VaultKind::Memory),
+            _ => Err("vault must be 'FILESYSTEM' or 'MEMORY'".into()),
+        }
+    }
+}
+
+/// Specifies what a full worker mailbox does with the next message.
+#[derive(Clone, Copy)]
+pub enum OverflowKind {
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+impl FromStr for OverflowKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowKind::Block),
+            "drop-oldest" => Ok(OverflowKind::DropOldest),
+            "drop-newest" => Ok(OverflowKind::DropNewest),
+            _ => Err("worker overflow must be 'block', 'drop-oldest' or 'drop-newest'".into()),
         }
     }
 }
@@ -245,6 +914,12 @@ pub enum ChannelRole {
     /// The Responder role will create a channel responder, and will instruct the program to print
     /// the responder's channel responder address and the public key it's advertising.
     Responder,
+    /// Both roles at once: responding to channels from other nodes, while also initiating
+    /// channels to the given routes, e.g. for a gateway relaying device traffic upstream.
+    Both,
+    /// Neither role: only forward messages between hops, e.g. on an intermediate cloud node
+    /// that needs no identity of its own.
+    Relay,
 }
 
 impl FromStr for ChannelRole {
@@ -254,7 +929,9 @@ impl FromStr for ChannelRole {
         match s {
             "initiator" | "init" => Ok(ChannelRole::Initiator),
             "responder" | "resp" => Ok(ChannelRole::Responder),
-            _ => Err("role must be set to either 'initiator' or 'responder'".into()),
+            "both" => Ok(ChannelRole::Both),
+            "relay" => Ok(ChannelRole::Relay),
+            _ => Err("role must be set to 'initiator', 'responder', 'both' or 'relay'".into()),
         }
     }
 }
@@ -274,6 +951,12 @@ pub enum Mode {
 #[derive(Clone)]
 pub enum InputKind {
     Stdin,
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(windows)]
+    Pipe(String),
+    File(PathBuf),
 }
 
 impl FromStr for InputKind {
@@ -282,7 +965,103 @@ impl FromStr for InputKind {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "stdin" => Ok(InputKind::Stdin),
-            _ => Err("currently, only 'stdin' is a supported input type".into()),
+            _ => {
+                if let Some(addr) = s.strip_prefix("tcp://") {
+                    return SocketAddr::from_str(addr)
+                        .map(InputKind::Tcp)
+                        .map_err(|e| format!("invalid tcp input address: {}", e));
+                }
+                if let Some(path) = s.strip_prefix("file://") {
+                    return Ok(InputKind::File(PathBuf::from(path)));
+                }
+                #[cfg(unix)]
+                {
+                    if let Some(path) = s.strip_prefix("unix://") {
+                        return Ok(InputKind::Unix(PathBuf::from(path)));
+                    }
+                }
+                #[cfg(windows)]
+                {
+                    if let Some(name) = s.strip_prefix("pipe://") {
+                        return Ok(InputKind::Pipe(name.into()));
+                    }
+                }
+                Err(
+                    "input must be 'stdin', 'tcp://host:port', 'unix:///path' or 'file:///path'"
+                        .into(),
+                )
+            }
+        }
+    }
+}
+
+/// Specifies how records are delimited within the input.
+#[derive(Clone, Copy)]
+pub enum FramingKind {
+    Newline,
+    LengthPrefixed,
+    Chunk(usize),
+}
+
+impl FromStr for FramingKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newline" => Ok(FramingKind::Newline),
+            "length" => Ok(FramingKind::LengthPrefixed),
+            _ => match s.strip_prefix("chunk:").map(usize::from_str) {
+                Some(Ok(n)) if n > 0 && n <= crate::input::MAX_RECORD_SIZE => {
+                    Ok(FramingKind::Chunk(n))
+                }
+                Some(_) => Err(format!(
+                    "chunk size must be between 1 and {} bytes",
+                    crate::input::MAX_RECORD_SIZE
+                )),
+                None => Err("framing must be 'newline', 'length' or 'chunk:N'".into()),
+            },
+        }
+    }
+}
+
+/// Specifies how received payloads are written to stdout.
+#[derive(Clone, Copy)]
+pub enum EncodingKind {
+    Raw,
+    Hex,
+    Base64,
+    Json,
+}
+
+impl FromStr for EncodingKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(EncodingKind::Raw),
+            "hex" => Ok(EncodingKind::Hex),
+            "base64" => Ok(EncodingKind::Base64),
+            "json" => Ok(EncodingKind::Json),
+            _ => Err("output encoding must be 'raw', 'hex', 'base64' or 'json'".into()),
+        }
+    }
+}
+
+/// Specifies the cipher suite channels agree keys with.
+#[derive(Clone, Copy)]
+pub enum CipherSuiteKind {
+    AesGcm,
+    ChaChaPoly,
+}
+
+impl FromStr for CipherSuiteKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-gcm" => Ok(CipherSuiteKind::AesGcm),
+            "chacha-poly" => Ok(CipherSuiteKind::ChaChaPoly),
+            _ => Err("cipher suite must be 'aes-gcm' or 'chacha-poly'".into()),
         }
     }
 }
@@ -307,6 +1086,14 @@ impl FromStr for OutputKind {
         let mut route = Route { addresses: vec![] };
 
         s.split(',').for_each(|part| {
+            // a worker on the previous hop, e.g. an alias on a relay's hub
+            if let Some(address) = part.strip_prefix("worker://") {
+                match RouterAddress::worker_router_address_from_str(address) {
+                    Ok(router_addr) if !address.is_empty() => route.addresses.push(router_addr),
+                    _ => ret = Err(format!("invalid worker address: {}", part)),
+                }
+                return;
+            }
             match Url::parse(part) {
                 Ok(u) => {
                     if !u.has_host() {
@@ -333,17 +1120,117 @@ impl FromStr for OutputKind {
     }
 }
 
+#[test]
+fn test_cli_args_config_file() {
+    let path = std::env::temp_dir().join("ockamd_test_cli_args_config_file.conf");
+    std::fs::write(
+        &path,
+        "# responder settings\nrole = responder\nlocal_socket = 127.0.0.1:4051\ndaemonize = true\n",
+    )
+    .unwrap();
+
+    let cli = vec![
+        "ockamd".into(),
+        "--config".into(),
+        path.clone().into_os_string(),
+        "--local-socket".into(),
+        "127.0.0.1:4052".into(),
+    ];
+    let args = Args::load(cli).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(args.role(), ChannelRole::Responder));
+    assert_eq!(args.local_socket().port(), 4052);
+    assert!(args.daemonize());
+}
+
+#[test]
+fn test_cli_args_env() {
+    let path = std::env::temp_dir().join("ockamd_test_cli_args_env.conf");
+    std::fs::write(
+        &path,
+        "role = responder\nlocal_socket = 127.0.0.1:4051\nidentity_name = file.key\n",
+    )
+    .unwrap();
+
+    let env = vec![
+        ("OCKAMD_CONFIG", path.to_str().unwrap()),
+        ("OCKAMD_LOCAL_SOCKET", "127.0.0.1:4052"),
+        ("OCKAMD_IDENTITY_NAME", "env.key"),
+        ("OCKAMD_DAEMONIZE", "true"),
+        ("OCKAMD_ALLOWED_INITIATOR", "aa; bb"),
+        ("PATH", "/usr/bin"),
+    ];
+    let cli = vec!["ockamd", "--identity-name", "cli.key"];
+    let args = Args::load_with_env(
+        cli.into_iter().map(OsString::from),
+        env.into_iter().map(|(k, v)| (k.into(), v.into())),
+    )
+    .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(args.role(), ChannelRole::Responder));
+    assert_eq!(args.local_socket().port(), 4052);
+    assert_eq!(args.identity_name(), "cli.key");
+    assert!(args.daemonize());
+    assert_eq!(args.allowed_initiators(), vec!["aa", "bb"]);
+}
+
+#[test]
+fn test_cli_args_multiple_routes() {
+    let cli = vec![
+        "ockamd",
+        "--role",
+        "responder",
+        "--route",
+        "udp://127.0.0.1:4051",
+        "--route",
+        "udp://127.0.0.1:4052,udp://127.0.0.1:4053",
+    ];
+    let args = Args::load(cli.into_iter().map(OsString::from)).unwrap();
+
+    let hops: Vec<usize> = args
+        .output_kinds()
+        .iter()
+        .map(|output| match output {
+            OutputKind::Channel(route) => route.addresses.len(),
+            OutputKind::Stdout => 0,
+        })
+        .collect();
+    assert_eq!(hops, vec![1, 2]);
+}
+
+#[test]
+fn test_cli_args_hub_alias() {
+    use ockam_message::message::AddressType;
+
+    let route = match OutputKind::from_str("udp://127.0.0.1:4050,worker://6875627300000000") {
+        Ok(OutputKind::Channel(route)) => route,
+        _ => panic!("route to an alias failed to parse"),
+    };
+    assert_eq!(route.addresses.len(), 2);
+    assert_eq!(route.addresses[1].a_type, AddressType::Worker);
+    assert_eq!(route.addresses[1].address.as_string(), "6875627300000000");
+    assert!(OutputKind::from_str("udp://127.0.0.1:4050,worker://").is_err());
+    assert!(OutputKind::from_str("udp://127.0.0.1:4050,worker://xyz").is_err());
+
+    let cli = vec![
+        "ockamd",
+        "--role",
+        "responder",
+        "--hub",
+        "udp://127.0.0.1:4050",
+    ];
+    let args = Args::load(cli.into_iter().map(OsString::from)).unwrap();
+    assert!(matches!(args.hub(), Some(OutputKind::Channel(_))));
+}
+
 #[test]
 fn test_cli_args_output() {
     use ockam_message::message::AddressType;
 
-    if let Ok(output_kind) = OutputKind::from_str("udp://127.0.0.1:12345".into()) {
-        match output_kind {
-            OutputKind::Channel(route) => {
-                assert_eq!(route.addresses.len(), 1);
-            }
-            _ => {}
-        }
+    if let Ok(OutputKind::Channel(route)) = OutputKind::from_str("udp://127.0.0.1:12345") {
+        assert_eq!(route.addresses.len(), 1);
     }
 
     let test_cases = [
@@ -357,16 +1244,61 @@ fn test_cli_args_output() {
     ];
 
     test_cases.windows(2).for_each(|route_hop| {
-        if let Ok(output_kind) = OutputKind::from_str(route_hop[0]) {
-            match output_kind {
-                OutputKind::Channel(route) => {
-                    assert_eq!(route.addresses.len(), route_hop[1].parse().unwrap());
-                    route.addresses.iter().for_each(|addr| {
-                        assert_eq!(addr.a_type, AddressType::Udp);
-                    })
-                }
-                _ => {}
-            }
+        if let Ok(OutputKind::Channel(route)) = OutputKind::from_str(route_hop[0]) {
+            assert_eq!(route.addresses.len(), route_hop[1].parse().unwrap());
+            route.addresses.iter().for_each(|addr| {
+                assert_eq!(addr.a_type, AddressType::Udp);
+            })
         }
     });
 }
+
+#[test]
+fn test_cli_args_cipher_suite() {
+    let cli: Vec<std::ffi::OsString> = vec!["ockamd".into(), "--role".into(), "responder".into()];
+    let args = Args::load(cli.clone()).unwrap();
+    assert!(matches!(args.cipher_suite(), CipherSuiteKind::AesGcm));
+
+    let mut cli = cli;
+    cli.extend(vec!["--cipher-suite".into(), "chacha-poly".into()]);
+    let args = Args::load(cli).unwrap();
+    assert!(matches!(args.cipher_suite(), CipherSuiteKind::ChaChaPoly));
+
+    assert!(CipherSuiteKind::from_str("aes-ccm").is_err());
+}
+
+#[test]
+fn test_cli_args_channel_mtu() {
+    let cli: Vec<std::ffi::OsString> = vec!["ockamd".into(), "--role".into(), "responder".into()];
+    let args = Args::load(cli.clone()).unwrap();
+    assert_eq!(args.channel_mtu(), None);
+
+    let mut cli = cli;
+    cli.extend(vec!["--channel-mtu".into(), "1200".into()]);
+    let args = Args::load(cli).unwrap();
+    assert_eq!(args.channel_mtu(), Some(1200));
+}
+
+#[test]
+fn test_cli_args_channel_compress_over() {
+    let cli: Vec<std::ffi::OsString> = vec!["ockamd".into(), "--role".into(), "responder".into()];
+    let args = Args::load(cli.clone()).unwrap();
+    assert_eq!(args.channel_compress_over(), None);
+
+    let mut cli = cli;
+    cli.extend(vec!["--channel-compress-over".into(), "512".into()]);
+    let args = Args::load(cli).unwrap();
+    assert_eq!(args.channel_compress_over(), Some(512));
+}
+
+#[test]
+fn test_cli_args_channel_max_message_size() {
+    let cli: Vec<std::ffi::OsString> = vec!["ockamd".into(), "--role".into(), "responder".into()];
+    let args = Args::load(cli.clone()).unwrap();
+    assert_eq!(args.channel_max_message_size(), 16 * 1024 * 1024);
+
+    let mut cli = cli;
+    cli.extend(vec!["--channel-max-message-size".into(), "4096".into()]);
+    let args = Args::load(cli).unwrap();
+    assert_eq!(args.channel_max_message_size(), 4096);
+}
@@ -0,0 +1,73 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A UTC calendar time, to the second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Utc {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: i64,
+    pub minute: i64,
+    pub second: i64,
+}
+
+impl Utc {
+    pub fn now() -> Utc {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Utc::from_unix(secs)
+    }
+
+    pub fn from_unix(secs: i64) -> Utc {
+        let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+        // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Utc {
+            year,
+            month,
+            day,
+            hour: rem / 3600,
+            minute: rem % 3600 / 60,
+            second: rem % 60,
+        }
+    }
+
+    /// RFC 3339, e.g. 2020-09-13T12:26:40Z
+    pub fn rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+
+    /// ISO 8601 basic format, e.g. 20200913T122640Z
+    pub fn basic(&self) -> String {
+        format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[test]
+fn test_clock_utc() {
+    let t = Utc::from_unix(1_600_000_000);
+    assert_eq!(t.rfc3339(), "2020-09-13T12:26:40Z");
+    assert_eq!(t.basic(), "20200913T122640Z");
+    assert_eq!(
+        Utc::from_unix(951_782_400).rfc3339(),
+        "2000-02-29T00:00:00Z"
+    );
+}
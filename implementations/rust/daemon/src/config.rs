@@ -1,38 +1,170 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
+use crate::addon::AddonSpec;
 use crate::cli;
+use crate::names;
 
-use ockam_message::message::Route;
+use ockam_message::message::{Route, RouterAddress};
 
-#[derive(Debug, Clone, Copy)]
+/// The worker address of the service a responder hosts when no `--service` is given.
+pub const DEFAULT_SERVICE_ADDRESS: &str = "01242020";
+
+/// The worker address of the echo service, "echo" in ASCII.
+pub const ECHO_SERVICE_ADDRESS: &str = "6563686f";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Role {
     Initiator,
     Responder,
+    Both,
+    Relay,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Input {
     Stdin,
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(windows)]
+    Pipe(String),
+    File(PathBuf),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    Newline,
+    LengthPrefixed,
+    Chunk(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cipher {
+    AesGcm,
+    ChaChaPoly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VaultBackend {
+    Filesystem,
+    Memory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Raw,
+    Hex,
+    Base64,
+    Json,
+}
+
+/// A named service hosted by a responder: a worker address that initiators send to, and the
+/// addon its payloads go to, or stdout if none, e.g. `telemetry=01242020,influxdb,db,URL`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceSpec {
+    pub name: String,
+    pub address: String,
+    pub addon: Option<AddonSpec>,
+}
+
+impl FromStr for ServiceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = match s.find('=') {
+            Some(i) if i > 0 => (&s[..i], &s[i + 1..]),
+            _ => return Err("service must be given as NAME=ADDRESS[,ADDON,OPTIONS...]".into()),
+        };
+        let (address, addon) = match rest.find(',') {
+            Some(i) => (&rest[..i], Some(rest[i + 1..].parse()?)),
+            None => (rest, None),
+        };
+        match RouterAddress::worker_router_address_from_str(address) {
+            Ok(_) if !address.is_empty() => Ok(ServiceSpec {
+                name: name.into(),
+                address: address.into(),
+                addon,
+            }),
+            _ => Err(format!(
+                "service address must be hex-encoded, e.g. {}: {}",
+                DEFAULT_SERVICE_ADDRESS, address
+            )),
+        }
+    }
+}
+
+/// A configuration change that running components apply in place, without being recreated.
 #[derive(Debug, Clone)]
-pub enum AddonKind {
-    InfluxDb(url::Url, String),
+pub enum ConfigUpdate {
+    OnwardRoutes(Vec<Route>),
+    Addon(Option<AddonSpec>),
+    OutputEncoding(Encoding),
+    Services(Vec<ServiceSpec>),
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    onward_route: Option<Route>,
+    onward_routes: Vec<Route>,
     output_to_stdout: bool,
+    hub_route: Option<Route>,
+    neighbors: Vec<Route>,
     local_host: SocketAddr,
     role: Role,
+    vault: VaultBackend,
     vault_path: PathBuf,
+    vault_passphrase_file: Option<PathBuf>,
     input_kind: Input,
+    framing: Framing,
+    output_encoding: Encoding,
     remote_public_key: Option<String>,
     service_address: Option<String>,
+    service_name: Option<String>,
     identity_name: String,
-    addon: Option<AddonKind>,
+    public_key_file: Option<PathBuf>,
+    keepalive: Option<Duration>,
+    channel_idle_timeout: Option<Duration>,
+    cipher: Cipher,
+    reorder_window: u64,
+    reorder_delay: Duration,
+    channel_mtu: Option<usize>,
+    channel_compress_over: Option<usize>,
+    channel_reliable: bool,
+    channel_max_message_size: usize,
+    max_half_open_channels: usize,
+    max_backoff: Duration,
+    buffer_limit: usize,
+    worker_max_restarts: u32,
+    worker_mailbox_size: usize,
+    worker_overflow: Overflow,
+    allowed_initiators: Vec<String>,
+    allowed_initiators_file: Option<PathBuf>,
+    addon: Option<AddonSpec>,
+    services: Vec<ServiceSpec>,
+    session_services: Vec<String>,
+    echo: bool,
+    broker: bool,
+    topic: Option<String>,
+    mailbox_dir: Option<PathBuf>,
+    mailbox_limit: usize,
+    mailbox_ttl: Duration,
+    transfer_dir: Option<PathBuf>,
+    mdns: bool,
+    daemonize: bool,
+    pid_file: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    trace: bool,
+    control_socket: Option<PathBuf>,
+    channel_state_file: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -42,16 +174,44 @@ impl Default for Config {
 }
 
 impl Config {
+    pub fn vault(&self) -> VaultBackend {
+        self.vault
+    }
+
     pub fn vault_path(&self) -> PathBuf {
         self.vault_path.clone()
     }
 
-    pub fn onward_route(&self) -> Option<Route> {
-        self.onward_route.clone()
+    /// The file holding the passphrase the filesystem vault is sealed with, if it is.
+    pub fn vault_passphrase_file(&self) -> Option<PathBuf> {
+        self.vault_passphrase_file.clone()
+    }
+
+    pub fn onward_routes(&self) -> Vec<Route> {
+        self.onward_routes.clone()
+    }
+
+    /// The route to the relay whose hub a responder registers with, see `--hub`.
+    pub fn hub_route(&self) -> Option<Route> {
+        self.hub_route.clone()
+    }
+
+    /// The routes to the nodes whose discovery services this node advertises to, see
+    /// `--neighbor`.
+    pub fn neighbors(&self) -> Vec<Route> {
+        self.neighbors.clone()
     }
 
     pub fn input_kind(&self) -> Input {
-        self.input_kind
+        self.input_kind.clone()
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn output_encoding(&self) -> Encoding {
+        self.output_encoding
     }
 
     pub fn local_host(&self) -> SocketAddr {
@@ -70,54 +230,503 @@ impl Config {
         self.service_address.clone()
     }
 
+    /// The name of the service an initiator sends to, when given as `service:NAME` rather than
+    /// an address, which each responder resolves for itself.
+    pub fn service_name(&self) -> Option<String> {
+        self.service_name.clone()
+    }
+
     pub fn identity_name(&self) -> String {
         self.identity_name.clone()
     }
 
-    pub fn addon(&self) -> Option<AddonKind> {
+    pub fn public_key_file(&self) -> Option<PathBuf> {
+        self.public_key_file.clone()
+    }
+
+    /// How often to ping initiated channels, if at all.
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    /// How long a channel may be idle before it is closed, if ever.
+    pub fn channel_idle_timeout(&self) -> Option<Duration> {
+        self.channel_idle_timeout
+    }
+
+    /// The cipher suite channels agree keys with, and so encrypt with.
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// How many payloads ahead of the next one due channels hold to deliver them in order.
+    pub fn reorder_window(&self) -> u64 {
+        self.reorder_window
+    }
+
+    /// How long channels hold a payload waiting for those sent before it.
+    pub fn reorder_delay(&self) -> Duration {
+        self.reorder_delay
+    }
+
+    /// The largest payload channels send whole, larger ones being fragmented, if any is.
+    pub fn channel_mtu(&self) -> Option<usize> {
+        self.channel_mtu
+    }
+
+    /// The size over which channels deflate payloads, if they do.
+    pub fn channel_compress_over(&self) -> Option<usize> {
+        self.channel_compress_over
+    }
+
+    /// Whether channels acknowledge frames and send unacknowledged ones again.
+    pub fn channel_reliable(&self) -> bool {
+        self.channel_reliable
+    }
+
+    /// The largest message channels accept, and payload they deliver.
+    pub fn channel_max_message_size(&self) -> usize {
+        self.channel_max_message_size
+    }
+
+    /// How many key exchanges a responder answers at a time.
+    pub fn max_half_open_channels(&self) -> usize {
+        self.max_half_open_channels
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    pub fn buffer_limit(&self) -> usize {
+        self.buffer_limit
+    }
+
+    pub fn worker_max_restarts(&self) -> u32 {
+        self.worker_max_restarts
+    }
+
+    pub fn worker_mailbox_size(&self) -> usize {
+        self.worker_mailbox_size
+    }
+
+    pub fn worker_overflow(&self) -> Overflow {
+        self.worker_overflow
+    }
+
+    pub fn allowed_initiators(&self) -> Vec<String> {
+        self.allowed_initiators.clone()
+    }
+
+    pub fn allowed_initiators_file(&self) -> Option<PathBuf> {
+        self.allowed_initiators_file.clone()
+    }
+
+    pub fn addon(&self) -> Option<AddonSpec> {
         self.addon.clone()
     }
+
+    /// The services a responder hosts: those given with `--service`, or else a single service
+    /// at the default address using `--addon`.
+    pub fn services(&self) -> Vec<ServiceSpec> {
+        if !self.services.is_empty() {
+            return self.services.clone();
+        }
+        vec![ServiceSpec {
+            name: "default".into(),
+            address: DEFAULT_SERVICE_ADDRESS.into(),
+            addon: self.addon.clone(),
+        }]
+    }
+
+    pub fn service(&self, name: &str) -> Option<ServiceSpec> {
+        self.services().into_iter().find(|s| s.name == name)
+    }
+
+    /// The names of the services which spawn a worker for each sender.
+    pub fn session_services(&self) -> Vec<String> {
+        self.session_services.clone()
+    }
+
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    pub fn broker(&self) -> bool {
+        self.broker
+    }
+
+    /// The topic an initiator publishes its input to, see `--topic`.
+    pub fn topic(&self) -> Option<String> {
+        self.topic.clone()
+    }
+
+    /// The directory of the mailbox service, which is only hosted when one is given.
+    pub fn mailbox_dir(&self) -> Option<PathBuf> {
+        self.mailbox_dir.clone()
+    }
+
+    pub fn mailbox_limit(&self) -> usize {
+        self.mailbox_limit
+    }
+
+    pub fn mailbox_ttl(&self) -> Duration {
+        self.mailbox_ttl
+    }
+
+    /// The directory of the file-transfer service, which is only hosted when one is given.
+    pub fn transfer_dir(&self) -> Option<PathBuf> {
+        self.transfer_dir.clone()
+    }
+
+    /// Whether the node advertises itself, and browses for peers, with mDNS.
+    pub fn mdns(&self) -> bool {
+        self.mdns
+    }
+
+    pub fn daemonize(&self) -> bool {
+        self.daemonize
+    }
+
+    pub fn pid_file(&self) -> Option<PathBuf> {
+        self.pid_file.clone()
+    }
+
+    pub fn log_file(&self) -> Option<PathBuf> {
+        self.log_file.clone()
+    }
+
+    /// Whether to log the spans messages pass through, see `--trace`.
+    pub fn trace(&self) -> bool {
+        self.trace
+    }
+
+    pub fn control_socket(&self) -> Option<PathBuf> {
+        self.control_socket.clone()
+    }
+
+    /// Where established channels are saved as the node stops, to be resumed as it starts.
+    pub fn channel_state_file(&self) -> Option<PathBuf> {
+        self.channel_state_file.clone()
+    }
+
+    pub fn apply(&mut self, update: &ConfigUpdate) {
+        match update {
+            ConfigUpdate::OnwardRoutes(routes) => self.onward_routes = routes.clone(),
+            ConfigUpdate::Addon(addon) => self.addon = addon.clone(),
+            ConfigUpdate::OutputEncoding(encoding) => self.output_encoding = *encoding,
+            ConfigUpdate::Services(services) => self.services = services.clone(),
+        }
+    }
+
+    /// Compare against a newly loaded configuration, returning the updates that can be applied
+    /// at runtime. Settings which need a restart to take effect are reported and left unchanged.
+    pub fn changes(&self, new: &Config) -> Vec<ConfigUpdate> {
+        // a service's addon can change at runtime, but not the set of services
+        let same_services = {
+            let addresses = |c: &Config| {
+                c.services()
+                    .into_iter()
+                    .map(|s| (s.name, s.address))
+                    .collect::<Vec<_>>()
+            };
+            addresses(self) == addresses(new)
+        };
+        let restart_only = [
+            ("service", !same_services),
+            ("local_socket", self.local_host != new.local_host),
+            (
+                "hub",
+                self.hub_route.as_ref().map(|r| &r.addresses)
+                    != new.hub_route.as_ref().map(|r| &r.addresses),
+            ),
+            (
+                "neighbor",
+                self.neighbors
+                    .iter()
+                    .map(|r| &r.addresses)
+                    .ne(new.neighbors.iter().map(|r| &r.addresses)),
+            ),
+            ("role", self.role != new.role),
+            ("vault", self.vault != new.vault),
+            ("vault_path", self.vault_path != new.vault_path),
+            (
+                "vault_passphrase_file",
+                self.vault_passphrase_file != new.vault_passphrase_file,
+            ),
+            ("input", self.input_kind != new.input_kind),
+            ("framing", self.framing != new.framing),
+            ("identity_name", self.identity_name != new.identity_name),
+            (
+                "public_key_file",
+                self.public_key_file != new.public_key_file,
+            ),
+            ("keepalive_secs", self.keepalive != new.keepalive),
+            (
+                "channel_idle_timeout_secs",
+                self.channel_idle_timeout != new.channel_idle_timeout,
+            ),
+            ("cipher_suite", self.cipher != new.cipher),
+            ("reorder_window", self.reorder_window != new.reorder_window),
+            ("reorder_delay_ms", self.reorder_delay != new.reorder_delay),
+            ("channel_mtu", self.channel_mtu != new.channel_mtu),
+            (
+                "channel_compress_over",
+                self.channel_compress_over != new.channel_compress_over,
+            ),
+            (
+                "channel_reliable",
+                self.channel_reliable != new.channel_reliable,
+            ),
+            (
+                "channel_max_message_size",
+                self.channel_max_message_size != new.channel_max_message_size,
+            ),
+            (
+                "max_half_open_channels",
+                self.max_half_open_channels != new.max_half_open_channels,
+            ),
+            ("max_backoff_secs", self.max_backoff != new.max_backoff),
+            ("buffer_limit", self.buffer_limit != new.buffer_limit),
+            (
+                "worker_max_restarts",
+                self.worker_max_restarts != new.worker_max_restarts,
+            ),
+            (
+                "worker_mailbox_size",
+                self.worker_mailbox_size != new.worker_mailbox_size,
+            ),
+            (
+                "worker_overflow",
+                self.worker_overflow != new.worker_overflow,
+            ),
+            (
+                "allowed_initiator",
+                self.allowed_initiators != new.allowed_initiators,
+            ),
+            (
+                "allowed_initiators_file",
+                self.allowed_initiators_file != new.allowed_initiators_file,
+            ),
+            (
+                "session_service",
+                self.session_services != new.session_services,
+            ),
+            ("echo", self.echo != new.echo),
+            ("broker", self.broker != new.broker),
+            ("topic", self.topic != new.topic),
+            ("mailbox_dir", self.mailbox_dir != new.mailbox_dir),
+            ("mailbox_limit", self.mailbox_limit != new.mailbox_limit),
+            ("mailbox_ttl_secs", self.mailbox_ttl != new.mailbox_ttl),
+            ("transfer_dir", self.transfer_dir != new.transfer_dir),
+            ("mdns", self.mdns != new.mdns),
+            ("daemonize", self.daemonize != new.daemonize),
+            ("pid_file", self.pid_file != new.pid_file),
+            ("log_file", self.log_file != new.log_file),
+            ("control_socket", self.control_socket != new.control_socket),
+            (
+                "channel_state_file",
+                self.channel_state_file != new.channel_state_file,
+            ),
+        ];
+        for (name, _) in restart_only.iter().filter(|(_, changed)| *changed) {
+            eprintln!("ignoring change to {}: restart ockamd to apply it", name);
+        }
+
+        let mut updates = vec![];
+        let routes_changed = self.onward_routes.len() != new.onward_routes.len()
+            || self
+                .onward_routes
+                .iter()
+                .zip(new.onward_routes.iter())
+                .any(|(old, new)| old.addresses != new.addresses);
+        if routes_changed {
+            updates.push(ConfigUpdate::OnwardRoutes(new.onward_routes.clone()));
+        }
+        if self.addon != new.addon {
+            updates.push(ConfigUpdate::Addon(new.addon.clone()));
+        }
+        if same_services && self.services != new.services {
+            updates.push(ConfigUpdate::Services(new.services.clone()));
+        }
+        if self.output_encoding != new.output_encoding {
+            updates.push(ConfigUpdate::OutputEncoding(new.output_encoding));
+        }
+        updates
+    }
 }
 
 impl From<cli::Args> for Config {
     fn from(args: cli::Args) -> Self {
         let mut cfg = Config {
-            onward_route: None,
+            onward_routes: vec![],
             output_to_stdout: false,
+            hub_route: match args.hub() {
+                Some(cli::OutputKind::Channel(route)) => Some(route),
+                _ => None,
+            },
+            neighbors: args
+                .neighbors()
+                .into_iter()
+                .filter_map(|n| match n {
+                    cli::OutputKind::Channel(route) => Some(route),
+                    cli::OutputKind::Stdout => None,
+                })
+                .collect(),
             local_host: args.local_socket(),
             role: Role::Initiator,
+            vault: match args.vault() {
+                cli::VaultKind::Filesystem => VaultBackend::Filesystem,
+                cli::VaultKind::Memory => VaultBackend::Memory,
+            },
             vault_path: args.vault_path(),
+            vault_passphrase_file: args.vault_passphrase_file(),
             input_kind: Input::Stdin,
+            framing: match args.framing() {
+                cli::FramingKind::Newline => Framing::Newline,
+                cli::FramingKind::LengthPrefixed => Framing::LengthPrefixed,
+                cli::FramingKind::Chunk(n) => Framing::Chunk(n),
+            },
+            output_encoding: match args.output_encoding() {
+                cli::EncodingKind::Raw => Encoding::Raw,
+                cli::EncodingKind::Hex => Encoding::Hex,
+                cli::EncodingKind::Base64 => Encoding::Base64,
+                cli::EncodingKind::Json => Encoding::Json,
+            },
             remote_public_key: args.service_public_key(),
-            service_address: args.service_address(),
+            service_address: args
+                .service_address()
+                .filter(|a| !a.starts_with(names::NAME_PREFIX)),
+            service_name: args
+                .service_address()
+                .and_then(|a| a.strip_prefix(names::NAME_PREFIX).map(String::from)),
             identity_name: args.identity_name(),
-            addon: if let Some(a) = args.addon() {
-                match a {
-                    cli::Addon::InfluxDb(u, db) => Some(AddonKind::InfluxDb(u, db)),
-                }
-            } else {
-                None
+            public_key_file: args.public_key_file(),
+            keepalive: Some(args.keepalive_secs())
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            channel_idle_timeout: Some(args.channel_idle_timeout_secs())
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            cipher: match args.cipher_suite() {
+                cli::CipherSuiteKind::AesGcm => Cipher::AesGcm,
+                cli::CipherSuiteKind::ChaChaPoly => Cipher::ChaChaPoly,
+            },
+            reorder_window: args.reorder_window(),
+            reorder_delay: Duration::from_millis(args.reorder_delay_ms()),
+            channel_mtu: args.channel_mtu(),
+            channel_compress_over: args.channel_compress_over(),
+            channel_reliable: args.channel_reliable(),
+            channel_max_message_size: args.channel_max_message_size(),
+            max_half_open_channels: args.max_half_open_channels(),
+            max_backoff: Duration::from_secs(args.max_backoff_secs()),
+            buffer_limit: args.buffer_limit(),
+            worker_max_restarts: args.worker_max_restarts(),
+            worker_mailbox_size: args.worker_mailbox_size(),
+            worker_overflow: match args.worker_overflow() {
+                cli::OverflowKind::Block => Overflow::Block,
+                cli::OverflowKind::DropOldest => Overflow::DropOldest,
+                cli::OverflowKind::DropNewest => Overflow::DropNewest,
             },
+            allowed_initiators: args.allowed_initiators(),
+            allowed_initiators_file: args.allowed_initiators_file(),
+            addon: args.addon(),
+            services: args.services(),
+            session_services: args.session_services(),
+            echo: args.echo(),
+            broker: args.broker(),
+            topic: args.topic(),
+            mailbox_dir: args.mailbox_dir(),
+            mailbox_limit: args.mailbox_limit(),
+            mailbox_ttl: Duration::from_secs(args.mailbox_ttl_secs()),
+            transfer_dir: args.transfer_dir(),
+            mdns: args.mdns(),
+            daemonize: args.daemonize(),
+            pid_file: args.pid_file(),
+            log_file: args.log_file(),
+            trace: args.trace(),
+            control_socket: args.control_socket(),
+            channel_state_file: args.channel_state_file(),
         };
 
-        match args.output_kind() {
-            cli::OutputKind::Channel(route) => {
-                cfg.onward_route = Some(route);
-            }
-            cli::OutputKind::Stdout => {
-                cfg.output_to_stdout = true;
+        for output in args.output_kinds() {
+            match output {
+                cli::OutputKind::Channel(route) => {
+                    cfg.onward_routes.push(route);
+                }
+                cli::OutputKind::Stdout => {
+                    cfg.output_to_stdout = true;
+                }
             }
         }
 
         cfg.role = match args.role() {
             cli::ChannelRole::Initiator => Role::Initiator,
             cli::ChannelRole::Responder => Role::Responder,
+            cli::ChannelRole::Both => Role::Both,
+            cli::ChannelRole::Relay => Role::Relay,
         };
 
         cfg.input_kind = match args.input_kind() {
             cli::InputKind::Stdin => Input::Stdin,
+            cli::InputKind::Tcp(addr) => Input::Tcp(addr),
+            #[cfg(unix)]
+            cli::InputKind::Unix(path) => Input::Unix(path),
+            #[cfg(windows)]
+            cli::InputKind::Pipe(name) => Input::Pipe(name),
+            cli::InputKind::File(path) => Input::File(path),
         };
 
         cfg
     }
 }
+#[test]
+fn test_config_services() {
+    let args = |extra: &[&str]| {
+        let mut cli = vec![
+            "ockamd",
+            "--role",
+            "responder",
+            "--addon",
+            "webhook,http://a",
+        ];
+        cli.extend_from_slice(extra);
+        Config::from(cli::Args::load(cli.into_iter().map(std::ffi::OsString::from)).unwrap())
+    };
+
+    let default = args(&[]);
+    assert_eq!(default.services().len(), 1);
+    assert_eq!(default.services()[0].address, DEFAULT_SERVICE_ADDRESS);
+    assert_eq!(default.services()[0].addon, default.addon());
+
+    let two = args(&[
+        "--service",
+        "telemetry=01242020,influxdb,db,http://localhost:8086",
+        "--service",
+        "commands=0a0b0c0d",
+    ]);
+    let services = two.services();
+    assert_eq!(services.len(), 2);
+    assert_eq!(
+        services[0].addon.as_ref().unwrap().options(),
+        vec!["db", "http://localhost:8086"]
+    );
+    assert_eq!(two.service("commands").unwrap().address, "0a0b0c0d");
+    assert!(two.service("commands").unwrap().addon.is_none());
+
+    // an addon change is applied in place, a new service needs a restart
+    let changed = args(&[
+        "--service",
+        "telemetry=01242020,webhook,http://b",
+        "--service",
+        "commands=0a0b0c0d",
+    ]);
+    assert!(matches!(
+        two.changes(&changed).as_slice(),
+        [ConfigUpdate::Services(_)]
+    ));
+    assert!(two.changes(&default).is_empty());
+
+    assert!("telemetry".parse::<ServiceSpec>().is_err());
+    assert!("telemetry=xyz".parse::<ServiceSpec>().is_err());
+}
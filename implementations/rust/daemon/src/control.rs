@@ -0,0 +1,237 @@
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+#[cfg(unix)]
+use crate::input::bind_private_socket;
+use crate::reload::ReloadRequest;
+use crate::wake::Waker;
+
+/// A command read from the control socket, one per line, e.g. `close-channel 0a1b2c3d`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlCommand {
+    ListChannels,
+    ShowChannel(String),
+    ShowIdentity,
+    CloseChannel(String),
+    ReloadConfig,
+    Stats,
+    ListWorkers,
+    ListDeadLetters,
+    StartWorker(String),
+    StopWorker(String),
+    RestartWorker(String),
+    ResolveService(String),
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            ["list-channels"] => Ok(ControlCommand::ListChannels),
+            ["show-channel", address] => Ok(ControlCommand::ShowChannel(address.to_string())),
+            ["show-identity"] => Ok(ControlCommand::ShowIdentity),
+            ["close-channel", address] => Ok(ControlCommand::CloseChannel(address.to_string())),
+            ["reload-config"] => Ok(ControlCommand::ReloadConfig),
+            ["stats"] => Ok(ControlCommand::Stats),
+            ["list-workers"] => Ok(ControlCommand::ListWorkers),
+            ["list-dead-letters"] => Ok(ControlCommand::ListDeadLetters),
+            ["start-worker", address] => Ok(ControlCommand::StartWorker(address.to_string())),
+            ["stop-worker", address] => Ok(ControlCommand::StopWorker(address.to_string())),
+            ["restart-worker", address] => Ok(ControlCommand::RestartWorker(address.to_string())),
+            ["resolve-service", name] => Ok(ControlCommand::ResolveService(name.to_string())),
+            _ => Err(format!(
+                "unknown command: {} (expected list-channels, show-channel ADDRESS, \
+                 show-identity, close-channel ADDRESS, reload-config, stats, list-workers, list-dead-letters, \
+                 start-worker ADDRESS, stop-worker ADDRESS, restart-worker ADDRESS or \
+                 resolve-service NAME)",
+                s
+            )),
+        }
+    }
+}
+
+/// A command for the node, which sends its one-line JSON reply back on `reply`.
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply: Sender<String>,
+}
+
+/// Serve the control socket at `path`. Each connection sends commands, one per line, and gets
+/// a JSON object back for each, always with an `ok` field. Configuration reloads are requested
+/// here; every other command is passed to the node through the returned receiver.
+#[cfg(unix)]
+pub fn listen(
+    path: &Path,
+    reload_tx: Sender<ReloadRequest>,
+    waker: Waker,
+) -> Result<Receiver<ControlRequest>, String> {
+    let listener = bind_private_socket(path)?;
+    println!("Listening for control commands on {}", path.display());
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    let reload_tx = reload_tx.clone();
+                    let waker = waker.clone();
+                    std::thread::spawn(move || {
+                        let reader = match stream.try_clone() {
+                            Ok(s) => std::io::BufReader::new(s),
+                            Err(e) => return eprintln!("failed to read control command: {}", e),
+                        };
+                        serve(reader, stream, tx, reload_tx, waker)
+                    });
+                }
+                Err(e) => eprintln!("failed to accept control connection: {}", e),
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+#[cfg(not(unix))]
+pub fn listen(
+    _path: &Path,
+    _reload_tx: Sender<ReloadRequest>,
+    _waker: Waker,
+) -> Result<Receiver<ControlRequest>, String> {
+    Err("the control socket is only supported on Unix".into())
+}
+
+fn serve<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    tx: Sender<ControlRequest>,
+    reload_tx: Sender<ReloadRequest>,
+    waker: Waker,
+) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        let reply = match line.parse() {
+            Ok(ControlCommand::ReloadConfig) => reload(&reload_tx),
+            Ok(command) => {
+                let (reply_tx, reply_rx) = mpsc::channel();
+                let request = ControlRequest {
+                    command,
+                    reply: reply_tx,
+                };
+                match tx.send(request).map(|_| {
+                    waker.wake();
+                    reply_rx.recv()
+                }) {
+                    Ok(Ok(reply)) => reply,
+                    _ => error("the node has stopped"),
+                }
+            }
+            Err(e) => error(&e),
+        };
+
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn reload(reload_tx: &Sender<ReloadRequest>) -> String {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    if reload_tx.send(Some(reply_tx)).is_err() {
+        return error("configuration reload is not available");
+    }
+    match reply_rx.recv() {
+        Ok(Ok(n)) => ok(&[("updates", n.to_string())]),
+        Ok(Err(e)) => error(&e),
+        Err(_) => error("configuration reload is not available"),
+    }
+}
+
+/// A successful reply, with fields whose values are already JSON.
+pub fn ok(fields: &[(&str, String)]) -> String {
+    let mut reply = String::from(r#"{"ok":true"#);
+    for (name, value) in fields {
+        reply.push_str(&format!(",{}:{}", string(name), value));
+    }
+    reply.push('}');
+    reply
+}
+
+pub fn error(msg: &str) -> String {
+    format!(r#"{{"ok":false,"error":{}}}"#, string(msg))
+}
+
+/// Encode a JSON string.
+pub fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[test]
+fn test_control_serve() {
+    let (tx, rx) = mpsc::channel::<ControlRequest>();
+    let (reload_tx, reload_rx) = mpsc::channel::<ReloadRequest>();
+    let (router_tx, router_rx) = mpsc::channel();
+
+    // stand in for the node and the reload thread
+    std::thread::spawn(move || {
+        for request in rx.iter() {
+            let reply = match request.command {
+                ControlCommand::ShowIdentity => ok(&[("public_key", string("ab\"cd"))]),
+                _ => error("unsupported"),
+            };
+            request.reply.send(reply).unwrap();
+        }
+    });
+    std::thread::spawn(move || {
+        for reply in reload_rx.iter() {
+            reply.unwrap().send(Ok(2)).unwrap();
+        }
+    });
+
+    let input = "show-identity\n\nreload-config\nclose-channel\nstats\n";
+    let mut output = vec![];
+    serve(
+        input.as_bytes(),
+        &mut output,
+        tx,
+        reload_tx,
+        Waker::router(router_tx),
+    );
+
+    assert_eq!(
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .collect::<Vec<&str>>(),
+        vec![
+            r#"{"ok":true,"public_key":"ab\"cd"}"#,
+            r#"{"ok":true,"updates":2}"#,
+            r#"{"ok":false,"error":"unknown command: close-channel (expected list-channels, show-channel ADDRESS, show-identity, close-channel ADDRESS, reload-config, stats, list-workers, list-dead-letters, start-worker ADDRESS, stop-worker ADDRESS, restart-worker ADDRESS or resolve-service NAME)"}"#,
+            r#"{"ok":false,"error":"unsupported"}"#,
+        ]
+    );
+
+    // the node is woken for each command it answers
+    assert_eq!(router_rx.try_iter().count(), 2);
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::Path;
+
+use crate::config::{Config, Input, Role};
+
+/// Prepare the process as configured: detach it from the terminal if `--daemonize` was given,
+/// then write the PID file. Must be called before any threads are started.
+pub fn start(config: &Config) -> Result<(), String> {
+    if let Some(path) = config.pid_file() {
+        check_pid_file(&path)?;
+    }
+
+    if config.daemonize() {
+        if matches!(config.role(), Role::Initiator | Role::Both)
+            && config.input_kind() == Input::Stdin
+        {
+            return Err("--daemonize needs an --input other than stdin".into());
+        }
+        detach(config.log_file().as_deref())?;
+    }
+
+    if let Some(path) = config.pid_file() {
+        fs::write(&path, format!("{}\n", std::process::id()))
+            .map_err(|e| format!("failed to write pid file {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Fail if the PID file names a process which is still running.
+fn check_pid_file(path: &Path) -> Result<(), String> {
+    let pid = match fs::read_to_string(path) {
+        Ok(contents) => contents.trim().parse::<u32>().ok(),
+        Err(_) => None,
+    };
+    match pid {
+        Some(pid) if pid != std::process::id() && is_running(pid) => Err(format!(
+            "ockamd is already running with pid {} (from {})",
+            pid,
+            path.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // signal 0 only checks that the process exists; EPERM means it belongs to another user
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+/// Fork twice, with a new session in between, so the daemon is re-parented to init and can't
+/// reacquire a controlling terminal. Standard input is then read from `/dev/null`, and standard
+/// output and error are appended to the log file or discarded.
+#[cfg(unix)]
+fn detach(log_file: Option<&Path>) -> Result<(), String> {
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::AsRawFd;
+
+    // open everything first, so that errors are still reported on the terminal
+    let null = File::open("/dev/null").map_err(|e| e.to_string())?;
+    let log = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("failed to open log file {}: {}", path.display(), e))?,
+        None => OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .map_err(|e| e.to_string())?,
+    };
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(format!(
+            "failed to start a new session: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    fork_and_exit_parent()?;
+
+    unsafe {
+        libc::umask(0o027);
+        if libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) < 0
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) < 0
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) < 0
+        {
+            return Err(format!(
+                "failed to redirect output: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn fork_and_exit_parent() -> Result<(), String> {
+    match unsafe { libc::fork() } {
+        -1 => Err(format!(
+            "failed to fork: {}",
+            std::io::Error::last_os_error()
+        )),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+#[cfg(not(unix))]
+fn detach(_log_file: Option<&Path>) -> Result<(), String> {
+    Err("--daemonize is not supported on this platform; run ockamd as a service instead".into())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemonize_pid_file() {
+    let path = std::env::temp_dir().join("ockamd_test_daemonize.pid");
+
+    // init is always running
+    fs::write(&path, "1\n").unwrap();
+    assert!(check_pid_file(&path).is_err());
+
+    fs::write(&path, "stale\n").unwrap();
+    assert!(check_pid_file(&path).is_ok());
+
+    fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+    assert!(check_pid_file(&path).is_ok());
+    fs::remove_file(&path).unwrap();
+}
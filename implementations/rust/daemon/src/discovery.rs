@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cli::{Args, OutputKind};
+use crate::config::{Config, Role, ECHO_SERVICE_ADDRESS};
+use crate::node::{self, Node};
+use crate::request::{self, Requester};
+use crate::worker::{Replier, Worker, WorkerHandler};
+
+use ockam_message::message::{
+    Address, AddressType, Codec, Message as OckamMessage, MessageType, Route, RouterAddress,
+};
+use ockam_system::commands::{OckamCommand, RouterCommand};
+use structopt::StructOpt;
+
+/// The worker address of the discovery service, "disc" in ASCII, which relays and responders
+/// with `--neighbor` host so that initiators can look up a route to a service by name.
+pub const DISCOVERY_SERVICE_ADDRESS: &str = "64697363";
+
+/// The worker address the answers to `ockamd discover` are sent to, "look" in ASCII.
+const LOOKUP_ADDRESS: &str = "6c6f6f6b";
+
+// the kinds of request the discovery service handles, the first byte of each payload
+const ADVERTISEMENT: u8 = 1;
+const QUERY: u8 = 2;
+
+// the most hops a learned route may have, which also ends routes going round in a loop
+const MAX_HOPS: usize = 8;
+
+// learned routes are forgotten once their neighbor misses this many advertisements
+const EXPIRY_INTERVALS: u32 = 3;
+
+// how often routes are advertised when channels have no keepalive to follow
+const DEFAULT_ADVERTISE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The discovery service: it knows a route to each service of its own node, and learns routes
+/// to the services its neighbors advertise, each through the neighbor it heard it from. At each
+/// keepalive interval it advertises all of them to the nodes given by `--neighbor`, except for
+/// the routes learned from that same neighbor, and it answers queries for a service by name
+/// with the route to it from this node. Advertisements and queries are plain messages rather
+/// than over channels: a false route leads nowhere, since the channel opened along it still
+/// authenticates the responder.
+pub fn discovery_worker(config: &Config, router_tx: Sender<OckamCommand>) -> Worker {
+    let worker_addr =
+        RouterAddress::worker_router_address_from_str(DISCOVERY_SERVICE_ADDRESS).unwrap();
+    let replier = Replier::new(router_tx.clone(), worker_addr.clone());
+    let tx = router_tx.clone();
+    let make_handler = Box::new(
+        move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            Ok(Box::new(Discovery {
+                replier: replier.clone(),
+                router_tx: tx.clone(),
+                learned: HashMap::new(),
+            }))
+        },
+    );
+    Worker::new(worker_addr, router_tx, config.clone(), make_handler)
+        .expect("failed to start discovery worker")
+}
+
+// a route learned from a neighbor's advertisement
+struct Learned {
+    route: Route,
+    heard: Instant,
+}
+
+struct Discovery {
+    replier: Replier,
+    router_tx: Sender<OckamCommand>,
+    // the route to each service learned, by name
+    learned: HashMap<String, Learned>,
+}
+
+impl Discovery {
+    // The routes to this node's own services, each only the service's worker address.
+    fn local(config: &Config) -> Vec<(String, Route)> {
+        let mut services: Vec<(String, String)> = config
+            .services()
+            .into_iter()
+            .filter(|_| config.role() != Role::Relay)
+            .map(|s| (s.name, s.address))
+            .collect();
+        if config.echo() {
+            services.push(("echo".into(), ECHO_SERVICE_ADDRESS.into()));
+        }
+        services
+            .into_iter()
+            .filter_map(|(name, address)| {
+                let a = RouterAddress::worker_router_address_from_str(&address).ok()?;
+                Some((name, Route { addresses: vec![a] }))
+            })
+            .collect()
+    }
+
+    fn lookup(&self, config: &Config, name: &str) -> Option<Route> {
+        Self::local(config)
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, route)| route)
+            .or_else(|| self.learned.get(name).map(|l| l.route.clone()))
+    }
+
+    // Take the routes a neighbor advertised, through `neighbor`, the route to it, unless a
+    // shorter one is known through another neighbor.
+    fn learn(&mut self, config: &Config, neighbor: &Route, advertised: Vec<(String, Route)>) {
+        let local = Self::local(config);
+        for (name, route) in advertised {
+            if local.iter().any(|(n, _)| *n == name) {
+                continue;
+            }
+            let mut full = neighbor.clone();
+            full.addresses.extend(route.addresses);
+            if full.addresses.len() > MAX_HOPS {
+                continue;
+            }
+            let better = self
+                .learned
+                .get(&name)
+                .map(|known| {
+                    full.addresses.len() <= known.route.addresses.len()
+                        || known.route.addresses.first() == neighbor.addresses.first()
+                })
+                .unwrap_or(true);
+            if better {
+                self.learned.insert(
+                    name,
+                    Learned {
+                        route: full,
+                        heard: Instant::now(),
+                    },
+                );
+            }
+        }
+    }
+
+    fn advertise(&self, config: &Config) -> Result<(), String> {
+        let discovery =
+            RouterAddress::worker_router_address_from_str(DISCOVERY_SERVICE_ADDRESS).unwrap();
+        for neighbor in config.neighbors() {
+            // a neighbor isn't told the routes it gave, which would lead back through itself
+            let mut routes = Self::local(config);
+            routes.extend(
+                self.learned
+                    .iter()
+                    .filter(|(_, l)| l.route.addresses.first() != neighbor.addresses.first())
+                    .map(|(name, l)| (name.clone(), l.route.clone())),
+            );
+            let mut onward_route = neighbor;
+            onward_route.addresses.push(discovery.clone());
+            let msg = OckamMessage {
+                onward_route,
+                return_route: Route {
+                    addresses: vec![discovery.clone()],
+                },
+                message_type: MessageType::Payload,
+                message_body: request::encode(0, &encode_advertisement(&routes)?),
+            };
+            self.router_tx
+                .send(OckamCommand::Router(RouterCommand::SendMessage(msg)))
+                .map_err(|_| "failed to send advertisement to router".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl WorkerHandler for Discovery {
+    fn handle_message(&mut self, config: &Config, msg: OckamMessage) -> Result<(), String> {
+        let (_, payload) =
+            request::decode(&msg.message_body).ok_or("discovery message has no correlation ID")?;
+        match payload.split_first() {
+            Some((&ADVERTISEMENT, advertised)) => {
+                // the return route leads to the neighbor's node, then its discovery service
+                let mut neighbor = msg.return_route.clone();
+                neighbor.addresses.pop();
+                if neighbor.addresses.is_empty() {
+                    return Err("advertisement has no route back to its node".into());
+                }
+                let advertised = decode_advertisement(advertised)?;
+                self.learn(config, &neighbor, advertised);
+                Ok(())
+            }
+            Some((&QUERY, name)) => {
+                let mut answer = vec![];
+                if let Some(route) = self.lookup(config, &String::from_utf8_lossy(name)) {
+                    route.encode(&mut answer)?;
+                }
+                self.replier.respond(&msg, &answer)
+            }
+            _ => Err("unknown discovery request".into()),
+        }
+    }
+
+    fn tick_interval(&self, config: &Config) -> Option<Duration> {
+        Some(config.keepalive().unwrap_or(DEFAULT_ADVERTISE_INTERVAL))
+    }
+
+    fn on_tick(&mut self, config: &Config) -> Result<(), String> {
+        let interval = config.keepalive().unwrap_or(DEFAULT_ADVERTISE_INTERVAL);
+        self.learned
+            .retain(|_, l| l.heard.elapsed() < interval * EXPIRY_INTERVALS);
+        self.advertise(config)
+    }
+}
+
+fn encode_advertisement(routes: &[(String, Route)]) -> Result<Vec<u8>, String> {
+    if routes.len() > u8::MAX as usize {
+        return Err("too many routes to advertise".into());
+    }
+    let mut v = vec![ADVERTISEMENT, routes.len() as u8];
+    for (name, route) in routes {
+        if name.len() > u8::MAX as usize {
+            return Err(format!("service name too long to advertise: {}", name));
+        }
+        v.push(name.len() as u8);
+        v.extend_from_slice(name.as_bytes());
+        route.encode(&mut v)?;
+    }
+    Ok(v)
+}
+
+// The routes in an advertisement, after its kind.
+fn decode_advertisement(u: &[u8]) -> Result<Vec<(String, Route)>, String> {
+    let (&count, mut u) = u.split_first().ok_or("advertisement is truncated")?;
+    let mut routes = vec![];
+    for _ in 0..count {
+        let (&len, rest) = u.split_first().ok_or("advertisement is truncated")?;
+        let name = rest
+            .get(..len as usize)
+            .ok_or("advertisement is truncated")?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| "service name is not UTF-8")?;
+        let (route, rest) = Route::decode(&rest[len as usize..])?;
+        routes.push((name, route));
+        u = rest;
+    }
+    Ok(routes)
+}
+
+/// A query for the route to the service called `name`, from the node the query is sent to.
+pub fn query(name: &str) -> Vec<u8> {
+    let mut payload = vec![QUERY];
+    payload.extend_from_slice(name.as_bytes());
+    payload
+}
+
+/// The route in the discovery service's answer to a query, or None if the name is unknown.
+pub fn query_response(payload: &[u8]) -> Result<Option<Route>, String> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    let (route, _) = Route::decode(payload)?;
+    Ok(Some(route))
+}
+
+/// A route as given to `--route`, e.g. `udp://10.0.0.1:4050,worker://6875627300000000`.
+pub fn route_text(route: &Route) -> String {
+    route
+        .addresses
+        .iter()
+        .map(|a| match &a.address {
+            Address::UdpAddress(addr) => format!("udp://{}", addr),
+            address => format!("worker://{}", address.as_string()),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "ockamd discover",
+    about = "Ask the discovery service of a relay or responder for the route to a service, and print the options to open a channel to it."
+)]
+pub struct DiscoverArgs {
+    #[structopt(help = "Route to the node to ask, e.g. udp://relay:4050")]
+    route: OutputKind,
+
+    #[structopt(help = "Name of the service to look up")]
+    name: String,
+
+    #[structopt(
+        long,
+        default_value = "5000",
+        help = "Milliseconds to wait for the answer"
+    )]
+    timeout_ms: u64,
+
+    #[structopt(
+        long,
+        default_value = "0.0.0.0:0",
+        help = "Local node address and port to bind"
+    )]
+    local_socket: SocketAddr,
+}
+
+/// Handle `ockamd discover ROUTE NAME [OPTIONS]`.
+pub fn main(args: &[OsString]) -> Result<(), String> {
+    // parse as if `discover` were the program name, so that usage reads `ockamd discover ...`
+    let args = DiscoverArgs::from_iter(&args[1..]);
+    let mut route = match args.route.clone() {
+        OutputKind::Channel(route) => route,
+        OutputKind::Stdout => return Err("discover needs a route to a node".into()),
+    };
+
+    // the lookup needs no channel, and so no identity
+    let config: Config = Args::relay(args.local_socket).into();
+    let (node, router_tx) = Node::new(&config);
+    let (tx, rx) = mpsc::channel();
+    router_tx
+        .send(OckamCommand::Router(RouterCommand::Register(
+            AddressType::Worker,
+            tx,
+        )))
+        .map_err(|e| format!("failed to register discovery worker: {}", e))?;
+
+    let lookup_addr = RouterAddress::worker_router_address_from_str(LOOKUP_ADDRESS).unwrap();
+    let name = args.name.clone();
+    let timeout = Duration::from_millis(args.timeout_ms);
+    let mut to = route.clone();
+    let looker = thread::spawn(move || {
+        let mut requester = Requester::new(router_tx, lookup_addr, rx);
+        to.addresses.push(
+            RouterAddress::worker_router_address_from_str(DISCOVERY_SERVICE_ADDRESS).unwrap(),
+        );
+        let result = requester.send_request(to, &query(&name), timeout);
+        node::stop();
+        result
+    });
+    node.run();
+
+    let answer = looker
+        .join()
+        .map_err(|_| "discovery thread panicked".to_string())??;
+    let mut found = query_response(&answer)?.ok_or(format!("no route to {}", args.name))?;
+    let service = found
+        .addresses
+        .pop()
+        .filter(|a| a.a_type == AddressType::Worker)
+        .ok_or("the route found doesn't end at a service")?;
+    route.addresses.extend(found.addresses);
+    println!(
+        "--route {} --service-address {}",
+        route_text(&route),
+        service.address.as_string()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_discovery_advertisement() {
+    let responder = RouterAddress::udp_router_address_from_str("10.0.0.5:4051").unwrap();
+    let service = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let routes = vec![
+        (
+            "telemetry".to_string(),
+            Route {
+                addresses: vec![service.clone()],
+            },
+        ),
+        (
+            "echo".to_string(),
+            Route {
+                addresses: vec![responder.clone(), service.clone()],
+            },
+        ),
+    ];
+    let encoded = encode_advertisement(&routes).unwrap();
+    assert_eq!(encoded[0], ADVERTISEMENT);
+    let decoded = decode_advertisement(&encoded[1..]).unwrap();
+    assert_eq!(decoded.len(), 2);
+    assert_eq!(decoded[1].0, "echo");
+    assert_eq!(decoded[1].1.addresses, routes[1].1.addresses);
+    assert!(decode_advertisement(&encoded[1..encoded.len() - 1]).is_err());
+
+    assert_eq!(
+        route_text(&Route {
+            addresses: vec![responder, service],
+        }),
+        "udp://10.0.0.5:4051,worker://01242020"
+    );
+    assert!(query_response(&[]).unwrap().is_none());
+}
+
+#[test]
+fn test_discovery_learn() {
+    let (router_tx, _router_rx) = mpsc::channel();
+    let addr = RouterAddress::worker_router_address_from_str(DISCOVERY_SERVICE_ADDRESS).unwrap();
+    let mut discovery = Discovery {
+        replier: Replier::new(router_tx.clone(), addr),
+        router_tx,
+        learned: HashMap::new(),
+    };
+    let config = Config::default();
+    let near = Route {
+        addresses: vec![RouterAddress::udp_router_address_from_str("10.0.0.5:4051").unwrap()],
+    };
+    let far = Route {
+        addresses: vec![RouterAddress::udp_router_address_from_str("10.0.0.9:4050").unwrap()],
+    };
+    let service = RouterAddress::worker_router_address_from_str("0a0b0c0d").unwrap();
+    let at = |hops: &[&Route]| Route {
+        addresses: hops
+            .iter()
+            .flat_map(|r| r.addresses.clone())
+            .chain(Some(service.clone()))
+            .collect(),
+    };
+
+    // a longer route is learned when it is the only one, and replaced by a shorter one
+    discovery.learn(&config, &far, vec![("sensor".into(), at(&[&near]))]);
+    assert_eq!(
+        discovery.lookup(&config, "sensor").unwrap().addresses,
+        at(&[&far, &near]).addresses
+    );
+    discovery.learn(
+        &config,
+        &near,
+        vec![(
+            "sensor".into(),
+            Route {
+                addresses: vec![service.clone()],
+            },
+        )],
+    );
+    assert_eq!(
+        discovery.lookup(&config, "sensor").unwrap().addresses,
+        at(&[&near]).addresses
+    );
+    discovery.learn(&config, &far, vec![("sensor".into(), at(&[&near]))]);
+    assert_eq!(
+        discovery.lookup(&config, "sensor").unwrap().addresses.len(),
+        2
+    );
+
+    // this node's own services are never replaced
+    discovery.learn(&config, &far, vec![("default".into(), at(&[]))]);
+    assert_eq!(
+        discovery.lookup(&config, "default").unwrap().addresses,
+        vec![RouterAddress::worker_router_address_from_str("01242020").unwrap()]
+    );
+
+    // routes which are too long are dropped
+    let long: Vec<&Route> = vec![&far; MAX_HOPS];
+    discovery.learn(&config, &near, vec![("distant".into(), at(&long))]);
+    assert!(discovery.lookup(&config, "distant").is_none());
+}
@@ -0,0 +1,65 @@
+use std::sync::mpsc;
+
+use crate::addon::AddonRegistry;
+use crate::config::Config;
+use crate::control;
+use crate::initiator;
+use crate::mdns;
+use crate::node::Node;
+use crate::reload;
+use crate::responder;
+use crate::transfer;
+use crate::wake::Waker;
+use crate::worker::{self, Spawner};
+
+use ockam_channel::CHANNEL_ZERO;
+use ockam_message::message::RouterAddress;
+
+/// Run both roles in one node: channels from other nodes are accepted and their payloads passed
+/// to the addon or stdout, as a responder does, while the input is mirrored over channels this
+/// node initiates to its onward routes, as an initiator does.
+pub fn run(config: Config, addons: AddonRegistry) {
+    let (mut node, router_tx) = Node::new(&config);
+
+    let (route_tx, route_rx) = mpsc::channel();
+    let spawner = Spawner::new(node.worker_sender(), route_tx);
+    let mut outputs = responder::output_workers(&config, &addons, &router_tx, &spawner);
+    if let Some(worker) = responder::hub_registrant(&config, &node, &router_tx) {
+        outputs.push(worker);
+    }
+    outputs.extend(transfer::transfer_worker(&config, &node, router_tx.clone()));
+    let (input_tx, input_update_tx) =
+        initiator::spawn_input_worker(&config, &mut node, router_tx.clone());
+    if config.mdns() {
+        let routes = (input_update_tx.clone(), Waker::worker(input_tx.clone()));
+        mdns::spawn(&config, &node, Some(routes)).expect("failed to start mDNS");
+    }
+    let mut update_txs: Vec<_> = outputs.iter().map(|w| w.config_sender()).collect();
+    update_txs.push(input_update_tx);
+    let reload_tx = reload::watch(
+        config.clone(),
+        update_txs,
+        vec![node.waker(), Waker::worker(input_tx.clone())],
+    );
+    if let Some(path) = config.control_socket() {
+        node.add_control(
+            control::listen(&path, reload_tx, node.waker()).expect("failed to open control socket"),
+        );
+    }
+
+    // the router has a single handler for worker addresses, so messages for the services and
+    // the announcements of channels this node responds to are picked out, and the channel
+    // notifications for the input worker passed on to it
+    let announcements = RouterAddress::worker_router_address_from_str(CHANNEL_ZERO).unwrap();
+    let mut output_routes: Vec<_> = outputs.iter().map(|w| (w.address(), w.sender())).collect();
+    output_routes.extend(outputs.iter().map(|w| (announcements.clone(), w.sender())));
+    for output in outputs {
+        node.add_worker(output);
+    }
+    node.add_thread(
+        "worker dispatch",
+        worker::dispatch(&router_tx, output_routes, input_tx, route_rx),
+    );
+
+    node.run();
+}
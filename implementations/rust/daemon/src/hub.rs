@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::worker::{Worker, WorkerHandler};
+
+use ockam_message::message::{
+    AddressType, Message as OckamMessage, MessageType, Route, RouterAddress,
+};
+use ockam_system::commands::{OckamCommand, RouterCommand, WorkerCommand};
+
+/// The worker address of a relay's hub service, "hubs" in ASCII, where nodes which can't accept
+/// inbound connections, e.g. behind NAT, register for an alias on the relay.
+pub const HUB_SERVICE_ADDRESS: &str = "68756273";
+
+/// The worker address on a registered node, "regi" in ASCII, which the hub's answers go to.
+pub const REGISTRANT_ADDRESS: &str = "72656769";
+
+// how often a registrant registers again when channels have no keepalive to follow
+const DEFAULT_REGISTER_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A relay's hub service: a node registers under a name and is given an alias, a worker
+/// address on the relay, and any message sent to the alias there is forwarded along the route
+/// the registration arrived by. Since that is the route through the registrant's own NAT
+/// mapping, an initiator can open a channel to a responder which accepts no inbound connections
+/// with a route such as `udp://relay:4050,worker://ALIAS`.
+///
+/// A name keeps its alias when registered again, e.g. from a new NAT mapping. Names aren't
+/// authenticated, so a registration can take over another node's alias; the channels opened
+/// through it still authenticate the responder, so this denies service but reveals nothing.
+pub struct Hub {
+    tx: Sender<OckamCommand>,
+    rx: Receiver<OckamCommand>,
+    router_tx: Sender<OckamCommand>,
+    addr: RouterAddress,
+    // the alias of each name registered
+    aliases: HashMap<Vec<u8>, String>,
+    // the route to each alias's registrant
+    routes: HashMap<String, Route>,
+    next: u32,
+}
+
+impl Hub {
+    /// Register with the router as the handler of the relay's worker addresses.
+    pub fn new(router_tx: &Sender<OckamCommand>) -> Self {
+        let hub = Hub::detached(router_tx);
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Worker,
+                hub.sender(),
+            )))
+            .expect("failed to register hub");
+        hub
+    }
+
+    /// A hub which doesn't register with the router, for a dispatcher to pass messages on to.
+    pub fn detached(router_tx: &Sender<OckamCommand>) -> Self {
+        let (tx, rx) = mpsc::channel::<OckamCommand>();
+        Hub {
+            tx,
+            rx,
+            router_tx: router_tx.clone(),
+            addr: RouterAddress::worker_router_address_from_str(HUB_SERVICE_ADDRESS).unwrap(),
+            aliases: HashMap::new(),
+            routes: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    pub fn sender(&self) -> Sender<OckamCommand> {
+        self.tx.clone()
+    }
+
+    /// Handle the messages the router passes on, on a thread of its own, until the node stops.
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            while let Ok(cmd) = self.rx.recv() {
+                if !self.handle(cmd) {
+                    break;
+                }
+            }
+        })
+    }
+
+    // Handle one command from the router, returning false once told to stop.
+    fn handle(&mut self, cmd: OckamCommand) -> bool {
+        let result = match cmd {
+            OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg))
+            | OckamCommand::Worker(WorkerCommand::SendMessage(msg)) => self.receive(msg),
+            OckamCommand::Worker(WorkerCommand::Stop) => return false,
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("hub: {}", e);
+        }
+        true
+    }
+
+    fn receive(&mut self, msg: OckamMessage) -> Result<(), String> {
+        let to = match msg.onward_route.addresses.first() {
+            Some(to) => to.address.as_string(),
+            None => return Err("message has no onward address".into()),
+        };
+        if to == HUB_SERVICE_ADDRESS {
+            return self.register(msg);
+        }
+        let route = self
+            .routes
+            .get(&to)
+            .ok_or_else(|| format!("no node registered at {}", to))?;
+
+        // any message for the alias goes on, whether a payload or a key exchange
+        let mut forwarded = msg;
+        let rest = forwarded.onward_route.addresses.split_off(1);
+        forwarded.onward_route.addresses = route.addresses.clone();
+        forwarded.onward_route.addresses.extend(rest);
+        self.send(forwarded)
+    }
+
+    // Give the registrant its alias, which now leads along the route the registration came by.
+    fn register(&mut self, msg: OckamMessage) -> Result<(), String> {
+        if msg.message_type != MessageType::Payload || msg.message_body.is_empty() {
+            return Err("registration has no name".into());
+        }
+        // the return route ends at the registrant's worker, and leads to its node before that
+        let mut route = msg.return_route.clone();
+        route.addresses.pop();
+        if route.addresses.is_empty() {
+            return Err("registration has no route back to its node".into());
+        }
+
+        let alias = match self.aliases.get(&msg.message_body) {
+            Some(alias) => alias.clone(),
+            None => {
+                let mut alias = HUB_SERVICE_ADDRESS.to_string();
+                alias.push_str(&hex::encode(self.next.to_be_bytes()));
+                self.next += 1;
+                self.aliases.insert(msg.message_body.clone(), alias.clone());
+                alias
+            }
+        };
+        let moved = self
+            .routes
+            .get(&alias)
+            .map(|r| r.addresses != route.addresses)
+            .unwrap_or(true);
+        if moved {
+            println!(
+                "Registered {} at {}",
+                String::from_utf8_lossy(&msg.message_body),
+                alias
+            );
+        }
+        self.routes.insert(alias.clone(), route);
+
+        let answer = OckamMessage {
+            onward_route: msg.return_route,
+            return_route: Route {
+                addresses: vec![self.addr.clone()],
+            },
+            message_type: MessageType::Payload,
+            message_body: hex::decode(&alias).unwrap(),
+        };
+        self.send(answer)
+    }
+
+    fn send(&self, msg: OckamMessage) -> Result<(), String> {
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(msg)))
+            .map_err(|_| "failed to send to router".to_string())
+    }
+}
+
+/// A registration for `name` with the hub at the end of `hub_route`, answered to `reply_to`.
+pub fn register_request(hub_route: &Route, reply_to: &RouterAddress, name: &str) -> OckamMessage {
+    let mut onward_route = hub_route.clone();
+    onward_route
+        .addresses
+        .push(RouterAddress::worker_router_address_from_str(HUB_SERVICE_ADDRESS).unwrap());
+    OckamMessage {
+        onward_route,
+        return_route: Route {
+            addresses: vec![reply_to.clone()],
+        },
+        message_type: MessageType::Payload,
+        message_body: name.as_bytes().to_vec(),
+    }
+}
+
+/// The alias in the hub's answer to a registration.
+pub fn register_response(msg: &OckamMessage) -> Result<RouterAddress, String> {
+    if msg.message_body.is_empty() {
+        return Err("hub answered without an alias".into());
+    }
+    let alias = RouterAddress::worker_router_address_from_str(&hex::encode(&msg.message_body))?;
+    Ok(alias)
+}
+
+/// Register this node as `name` with the hub at `--hub`, as soon as the worker starts and then
+/// at each keepalive interval, which also keeps the NAT's mapping for the relay open. The alias
+/// is printed whenever the hub gives a new one.
+pub fn registrant_worker(config: &Config, router_tx: Sender<OckamCommand>, name: String) -> Worker {
+    let worker_addr = RouterAddress::worker_router_address_from_str(REGISTRANT_ADDRESS).unwrap();
+    let reply_to = worker_addr.clone();
+    let tx = router_tx.clone();
+    let make_handler = Box::new(
+        move |config: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            let registrant = Registrant {
+                router_tx: tx.clone(),
+                reply_to: reply_to.clone(),
+                name: name.clone(),
+                alias: None,
+            };
+            registrant.register(config)?;
+            Ok(Box::new(registrant))
+        },
+    );
+    Worker::new(worker_addr, router_tx, config.clone(), make_handler)
+        .expect("failed to start hub registrant worker")
+}
+
+struct Registrant {
+    router_tx: Sender<OckamCommand>,
+    reply_to: RouterAddress,
+    name: String,
+    alias: Option<RouterAddress>,
+}
+
+impl Registrant {
+    fn register(&self, config: &Config) -> Result<(), String> {
+        let hub_route = config.hub_route().ok_or("no hub to register with")?;
+        let msg = register_request(&hub_route, &self.reply_to, &self.name);
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(msg)))
+            .map_err(|_| "failed to send registration to router".to_string())
+    }
+}
+
+impl WorkerHandler for Registrant {
+    fn handle_message(&mut self, _config: &Config, msg: OckamMessage) -> Result<(), String> {
+        let alias = register_response(&msg)?;
+        if self.alias.as_ref() != Some(&alias) {
+            println!("Reachable through the hub at {}", alias.address.as_string());
+            self.alias = Some(alias);
+        }
+        Ok(())
+    }
+
+    fn tick_interval(&self, config: &Config) -> Option<Duration> {
+        Some(config.keepalive().unwrap_or(DEFAULT_REGISTER_INTERVAL))
+    }
+
+    fn on_tick(&mut self, config: &Config) -> Result<(), String> {
+        self.register(config)
+    }
+}
+
+#[test]
+fn test_hub_register_and_forward() {
+    let (router_tx, router_rx) = mpsc::channel();
+    let mut hub = Hub::new(&router_tx);
+    assert!(matches!(
+        router_rx.try_recv(),
+        Ok(OckamCommand::Router(RouterCommand::Register(
+            AddressType::Worker,
+            _
+        )))
+    ));
+    let sent = || match router_rx.try_recv() {
+        Ok(OckamCommand::Router(RouterCommand::SendMessage(msg))) => msg,
+        other => panic!("expected a message, got {:?}", other.is_ok()),
+    };
+
+    // the registration arrives at the relay from the device's NAT mapping
+    let device = RouterAddress::udp_router_address_from_str("203.0.113.7:61002").unwrap();
+    let registrant = RouterAddress::worker_router_address_from_str(REGISTRANT_ADDRESS).unwrap();
+    let mut registration = register_request(&Route { addresses: vec![] }, &registrant, "dev-1");
+    registration
+        .return_route
+        .addresses
+        .insert(0, device.clone());
+    assert!(
+        hub.handle(OckamCommand::Worker(WorkerCommand::ReceiveMessage(
+            registration.clone()
+        )))
+    );
+    let answer = sent();
+    assert_eq!(
+        answer.onward_route.addresses,
+        vec![device.clone(), registrant.clone()]
+    );
+    let alias = register_response(&answer).unwrap();
+    assert_eq!(alias.address.as_string(), "6875627300000000");
+
+    // a key exchange sent to the alias goes on to the device
+    let initiator = RouterAddress::udp_router_address_from_str("198.51.100.2:4052").unwrap();
+    let zero = RouterAddress::channel_router_address_from_str("00000000").unwrap();
+    let m1 = OckamMessage {
+        onward_route: Route {
+            addresses: vec![alias.clone(), zero.clone()],
+        },
+        return_route: Route {
+            addresses: vec![initiator.clone()],
+        },
+        message_type: MessageType::KeyAgreementM1,
+        message_body: vec![1, 2, 3],
+    };
+    hub.handle(OckamCommand::Worker(WorkerCommand::ReceiveMessage(m1)));
+    let forwarded = sent();
+    assert_eq!(forwarded.onward_route.addresses, vec![device, zero.clone()]);
+    assert_eq!(forwarded.return_route.addresses, vec![initiator]);
+
+    // registering again from a new mapping keeps the alias, which follows it
+    let moved = RouterAddress::udp_router_address_from_str("203.0.113.7:61500").unwrap();
+    registration.return_route.addresses[0] = moved.clone();
+    hub.handle(OckamCommand::Worker(WorkerCommand::ReceiveMessage(
+        registration,
+    )));
+    assert_eq!(register_response(&sent()).unwrap(), alias);
+    let payload = OckamMessage {
+        onward_route: Route {
+            addresses: vec![alias, zero.clone()],
+        },
+        ..Default::default()
+    };
+    hub.handle(OckamCommand::Worker(WorkerCommand::ReceiveMessage(payload)));
+    assert_eq!(sent().onward_route.addresses, vec![moved, zero]);
+
+    // nothing is registered at another alias
+    let unknown = OckamMessage {
+        onward_route: Route {
+            addresses: vec![
+                RouterAddress::worker_router_address_from_str("6875627300000001").unwrap(),
+            ],
+        },
+        ..Default::default()
+    };
+    hub.handle(OckamCommand::Worker(WorkerCommand::ReceiveMessage(unknown)));
+    assert!(router_rx.try_recv().is_err());
+
+    assert!(!hub.handle(OckamCommand::Worker(WorkerCommand::Stop)));
+}
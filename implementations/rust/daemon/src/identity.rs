@@ -0,0 +1,293 @@
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cli;
+
+use ockam_identity::{History, Identity};
+use ockam_vault::software::DefaultVault;
+use ockam_vault::types::*;
+use ockam_vault::DynVault;
+
+// Replacement keys wait in this directory of the vault until their rotation takes effect. The
+// filesystem vault ignores directories, so staged keys aren't loaded alongside the current ones.
+const ROTATE_DIR: &str = "rotate";
+// The key history of each identity, by the name of its current key, is kept in this directory
+// of the vault.
+const HISTORY_DIR: &str = "history";
+const ATTRS_LEN: usize = 6;
+
+/// A replacement identity key, waiting for the end of its overlap window.
+pub struct StagedKey {
+    pub public_key: String,
+    /// Seconds since the Unix epoch after which the key replaces the current identity.
+    pub after: u64,
+}
+
+/// The vault context of a key file name, e.g. `1.key`.
+pub fn key_context(key_name: &str) -> Result<SecretKeyContext, String> {
+    if let Some(id) = key_name.strip_suffix(cli::FILENAME_KEY_SUFFIX) {
+        return Ok(SecretKeyContext::Memory(
+            id.parse().map_err(|_| "bad key name".to_string())?,
+        ));
+    }
+
+    Err("invalid key name format".into())
+}
+
+pub fn contains_key(v: &mut dyn DynVault, key_name: &str) -> bool {
+    if let Ok(ctx) = key_context(key_name) {
+        return v.secret_export(ctx).is_ok();
+    }
+
+    false
+}
+
+/// Load the identity key `key_name` from the vault at `vault_path`, generating and persisting
+/// it first if the vault doesn't hold it yet, so that the same identity is used on every run.
+pub fn load_or_generate(
+    vault: &mut dyn DynVault,
+    vault_path: &Path,
+    key_name: &str,
+) -> Result<SecretKeyContext, String> {
+    if contains_key(vault, key_name) {
+        return key_context(key_name);
+    }
+    key_context(key_name)?;
+
+    let ctx = vault
+        .secret_generate(identity_attributes())
+        .map_err(|e| format!("failed to generate identity key: {}", e))?;
+
+    // the vault names the key file after the next free id, which only matches the identity
+    // name in an empty vault; rename it so that the key is found by name on the next run
+    if let SecretKeyContext::Memory(id) = ctx {
+        let generated = format!("{}{}", id, cli::FILENAME_KEY_SUFFIX);
+        if generated != key_name {
+            std::fs::rename(vault_path.join(&generated), vault_path.join(key_name))
+                .map_err(|e| format!("failed to store identity key {}: {}", key_name, e))?;
+        }
+    }
+    println!(
+        "Generated identity key {} in {}",
+        key_name,
+        vault_path.display()
+    );
+    Ok(ctx)
+}
+
+/// The identity whose current key is `key_name`, with the key history kept in the vault at
+/// `vault_path`. A key without a history, e.g. one generated before histories were kept, starts
+/// one as the identity's first key.
+pub fn load(
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+    vault_path: &Path,
+    key_name: &str,
+) -> Result<Identity, String> {
+    let ctx = key_context(key_name)?;
+    let path = history_path(vault_path, key_name);
+    match std::fs::read(&path) {
+        Ok(data) => History::decode(&data)
+            .and_then(|history| Identity::load(vault, ctx, history))
+            .map_err(|e| format!("bad key history {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let identity = Identity::from_key(vault, ctx)
+                .map_err(|e| format!("failed to create identity {}: {}", key_name, e))?;
+            write_history(&path, identity.history())?;
+            Ok(identity)
+        }
+        Err(e) => Err(format!(
+            "failed to read key history {}: {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+pub fn public_key_hex(vault: &mut dyn DynVault, ctx: SecretKeyContext) -> Result<String, String> {
+    vault
+        .secret_public_key_get(ctx)
+        .map(hex::encode)
+        .map_err(|e| format!("failed to get public key: {}", e))
+}
+
+/// Generate a replacement for the current key of `identity`, named `key_name`, which the node
+/// starts using on the first start after `overlap` has passed, so that peers can be given the
+/// new public key while the current one is still in use. The change is signed by the current key
+/// now, and joins the identity's key history along with the new key. Returns the new public key.
+pub fn stage_rotation(
+    vault_path: &Path,
+    identity: &Identity,
+    key_name: &str,
+    overlap: std::time::Duration,
+) -> Result<String, String> {
+    key_context(key_name)?;
+
+    let mut v = DefaultVault::default();
+    let ctx = v
+        .secret_generate(identity_attributes())
+        .map_err(|e| format!("failed to generate identity key: {}", e))?;
+    let secret = v
+        .secret_export(ctx)
+        .map_err(|e| format!("failed to generate identity key: {}", e))?;
+
+    let public_key = v
+        .secret_public_key_get(ctx)
+        .map_err(|e| format!("failed to get public key: {}", e))?;
+    let mut history = identity.history().clone();
+    history.push(
+        identity
+            .change_to(public_key.as_ref())
+            .map_err(|e| format!("failed to sign replacement key: {}", e))?,
+    );
+    let history = history
+        .encode()
+        .map_err(|e| format!("failed to encode key history: {}", e))?;
+
+    let mut bytes = identity_attributes().to_bytes().to_vec();
+    bytes.extend_from_slice(secret.as_ref());
+    let after = unix_time() + overlap.as_secs();
+
+    let dir = vault_path.join(ROTATE_DIR);
+    std::fs::create_dir_all(&dir)
+        .and_then(|_| std::fs::write(dir.join(key_name), bytes))
+        .and_then(|_| std::fs::write(staged_history_path(vault_path, key_name), history))
+        .and_then(|_| std::fs::write(after_path(vault_path, key_name), after.to_string()))
+        .map_err(|e| format!("failed to store replacement key {}: {}", key_name, e))?;
+
+    Ok(hex::encode(public_key))
+}
+
+/// The staged replacement for the identity key `key_name`, if a rotation is pending.
+pub fn staged(vault_path: &Path, key_name: &str) -> Result<Option<StagedKey>, String> {
+    let path = vault_path.join(ROTATE_DIR).join(key_name);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let bad_key = |e: String| format!("bad replacement key {}: {}", path.display(), e);
+    let data = std::fs::read(&path).map_err(|e| bad_key(e.to_string()))?;
+    if data.len() < ATTRS_LEN {
+        return Err(bad_key("too short".into()));
+    }
+    let mut attrs = [0u8; ATTRS_LEN];
+    attrs.copy_from_slice(&data[..ATTRS_LEN]);
+    let attributes = SecretKeyAttributes::try_from(attrs).map_err(|e| bad_key(e.to_string()))?;
+
+    let mut v = DefaultVault::default();
+    let ctx = v
+        .secret_import(
+            &SecretKey::new(&data[ATTRS_LEN..], attributes.xtype),
+            attributes,
+        )
+        .map_err(|e| bad_key(e.to_string()))?;
+
+    Ok(Some(StagedKey {
+        public_key: public_key_hex(&mut v, ctx)?,
+        after: read_after(vault_path, key_name)?,
+    }))
+}
+
+/// Replace the identity key `key_name` with its staged replacement once the overlap window has
+/// passed. This has to happen before the vault is opened. Returns whether the key was replaced.
+pub fn promote_staged(vault_path: &Path, key_name: &str) -> Result<bool, String> {
+    let staged = vault_path.join(ROTATE_DIR).join(key_name);
+    if !staged.is_file() || read_after(vault_path, key_name)? > unix_time() {
+        return Ok(false);
+    }
+
+    // the history staged with the key, if any, records the change to it
+    let staged_history = staged_history_path(vault_path, key_name);
+    if staged_history.is_file() {
+        std::fs::create_dir_all(vault_path.join(HISTORY_DIR))
+            .and_then(|_| std::fs::rename(&staged_history, history_path(vault_path, key_name)))
+            .map_err(|e| format!("failed to rotate key history {}: {}", key_name, e))?;
+    }
+    std::fs::rename(&staged, vault_path.join(key_name))
+        .and_then(|_| std::fs::remove_file(after_path(vault_path, key_name)))
+        .map_err(|e| format!("failed to rotate identity key {}: {}", key_name, e))?;
+    println!("Rotated identity key {}", key_name);
+    Ok(true)
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The attributes of an identity key: a persistent Curve25519 key agreement key.
+pub fn identity_attributes() -> SecretKeyAttributes {
+    SecretKeyAttributes {
+        xtype: SecretKeyType::Curve25519,
+        purpose: SecretPurposeType::KeyAgreement,
+        persistence: SecretPersistenceType::Persistent,
+    }
+}
+
+fn history_path(vault_path: &Path, key_name: &str) -> PathBuf {
+    vault_path.join(HISTORY_DIR).join(key_name)
+}
+
+fn staged_history_path(vault_path: &Path, key_name: &str) -> PathBuf {
+    vault_path
+        .join(ROTATE_DIR)
+        .join(format!("{}.history", key_name))
+}
+
+fn write_history(path: &Path, history: &History) -> Result<(), String> {
+    let bytes = history
+        .encode()
+        .map_err(|e| format!("failed to encode key history: {}", e))?;
+    path.parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(path, bytes))
+        .map_err(|e| format!("failed to write key history {}: {}", path.display(), e))
+}
+
+fn after_path(vault_path: &Path, key_name: &str) -> PathBuf {
+    vault_path
+        .join(ROTATE_DIR)
+        .join(format!("{}.after", key_name))
+}
+
+fn read_after(vault_path: &Path, key_name: &str) -> Result<u64, String> {
+    let path = after_path(vault_path, key_name);
+    std::fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| s.trim().parse().map_err(|_| "not a timestamp".to_string()))
+        .map_err(|e| format!("bad rotation time {}: {}", path.display(), e))
+}
+
+#[test]
+fn test_identity_promote_staged() {
+    let vault_path = std::env::temp_dir().join("ockamd_test_identity_promote_staged");
+    let _ = std::fs::remove_dir_all(&vault_path);
+    std::fs::create_dir_all(vault_path.join(ROTATE_DIR)).unwrap();
+    std::fs::write(vault_path.join("1.key"), b"old").unwrap();
+    std::fs::write(vault_path.join(ROTATE_DIR).join("1.key"), b"new").unwrap();
+
+    // still within the overlap window
+    std::fs::write(
+        after_path(&vault_path, "1.key"),
+        (unix_time() + 60).to_string(),
+    )
+    .unwrap();
+    assert_eq!(promote_staged(&vault_path, "1.key"), Ok(false));
+    assert_eq!(std::fs::read(vault_path.join("1.key")).unwrap(), b"old");
+
+    std::fs::write(after_path(&vault_path, "1.key"), unix_time().to_string()).unwrap();
+    std::fs::write(staged_history_path(&vault_path, "1.key"), b"history").unwrap();
+    assert_eq!(promote_staged(&vault_path, "1.key"), Ok(true));
+    assert_eq!(std::fs::read(vault_path.join("1.key")).unwrap(), b"new");
+    assert_eq!(
+        std::fs::read(history_path(&vault_path, "1.key")).unwrap(),
+        b"history"
+    );
+    assert!(!staged_history_path(&vault_path, "1.key").exists());
+    assert!(!after_path(&vault_path, "1.key").exists());
+    assert_eq!(promote_staged(&vault_path, "1.key"), Ok(false));
+
+    std::fs::remove_dir_all(&vault_path).unwrap();
+}
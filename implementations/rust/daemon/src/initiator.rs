@@ -1,10 +1,21 @@
+use std::collections::VecDeque;
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
+use crate::config::{Config, ConfigUpdate};
+use crate::control;
+use crate::input;
+use crate::mdns;
+use crate::names;
 use crate::node::Node;
+use crate::pubsub;
+use crate::reload;
+use crate::stats;
+use crate::wake::Waker;
 
 use hex::encode;
+use ockam_channel::protocol::{channel_error_code, Role as ChannelRole};
 use ockam_message::message::{
     Address, AddressType, Message as OckamMessage, Message, MessageType, Route, RouterAddress,
 };
@@ -13,140 +24,593 @@ use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand, Worker
 pub fn run(config: Config) {
     // configure a node
     let node_config = config.clone();
-    let (node, router_tx) = Node::new(&node_config);
+    let (mut node, router_tx) = Node::new(&node_config);
 
-    let mut worker = StdinWorker::new(
-        RouterAddress::worker_router_address_from_str(&config.service_address().unwrap())
-            .expect("failed to create worker address for kex"),
-        router_tx,
+    let (input_tx, update_tx) = spawn_input_worker(&config, &mut node, router_tx);
+    if config.mdns() {
+        let routes = (update_tx.clone(), Waker::worker(input_tx.clone()));
+        mdns::spawn(&config, &node, Some(routes)).expect("failed to start mDNS");
+    }
+    let reload_tx = reload::watch(
         config.clone(),
+        vec![update_tx],
+        vec![Waker::worker(input_tx)],
     );
+    if let Some(path) = config.control_socket() {
+        node.add_control(
+            control::listen(&path, reload_tx, node.waker()).expect("failed to open control socket"),
+        );
+    }
 
-    // kick off the key exchange process. The result will be that the worker is notified
-    // when the secure channel is created.
-    node.channel_tx
-        .send(OckamCommand::Channel(ChannelCommand::Initiate(
-            config.onward_route().unwrap(),
-            Address::WorkerAddress(hex::decode(config.service_address().unwrap()).unwrap()),
-            None,
-        )))
-        .unwrap();
+    // run the node to poll its various internal components
+    node.run();
+}
+
+/// Start the worker which reads the configured input and mirrors each record over a secure
+/// channel to every onward route. Returns the senders for the worker's commands and for
+/// configuration updates.
+pub fn spawn_input_worker(
+    config: &Config,
+    node: &mut Node,
+    router_tx: Sender<OckamCommand>,
+) -> (Sender<OckamCommand>, Sender<ConfigUpdate>) {
+    if config.service_address().is_none() && config.service_name().is_none() {
+        panic!("an initiator needs a service address");
+    }
+    let mut worker = InputWorker::new(
+        router_tx,
+        node.channel_tx.clone(),
+        node.waker(),
+        config.clone(),
+    )
+    .expect("failed to open input");
+    let senders = (worker.tx.clone(), worker.config_sender());
 
-    thread::spawn(move || {
+    // kick off the key exchange process for each output. The result will be that the worker
+    // is notified when each secure channel is created. An output whose channel was saved as
+    // the node last stopped is told of it once the node resumes it instead.
+    for index in 0..worker.outputs.len() {
+        let resumed = node.suspended().iter().find_map(|state| {
+            let owner = match state.owner().map(|o| o.addresses.as_slice()) {
+                Some([owner]) => output_of(&owner.address),
+                _ => None,
+            };
+            match owner {
+                Some((owner, generation))
+                    if owner == index
+                        && state.role() == ChannelRole::Initiator
+                        && state.initiate_route().addresses
+                            == worker.outputs[index].route.addresses =>
+                {
+                    Some(generation)
+                }
+                _ => None,
+            }
+        });
+        match resumed {
+            Some(generation) => worker.outputs[index].generation = generation,
+            None => worker.initiate(index).unwrap(),
+        }
+    }
+
+    let thread = thread::spawn(move || {
         while worker.poll() {
-            std::thread::sleep(std::time::Duration::from_millis(1));
+            worker.wait();
         }
     });
+    node.add_thread("input worker", thread);
 
-    // run the node to poll its various internal components
-    node.run();
+    senders
 }
 
-struct StdinWorker {
+// The first wait before re-initiating a failed channel, doubled on each further failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+// A channel is given up on after this many keepalive intervals without an answer, and a key
+// exchange after as long.
+pub(crate) const MISSED_KEEPALIVES: u32 = 3;
+
+// One secure channel the input is mirrored to.
+struct Output {
+    route: Route,
+    // bumped for each key exchange initiated, so that what is sent about an earlier one is told
+    // apart
+    generation: u16,
     channel: Option<RouterAddress>,
-    worker_addr: RouterAddress,
+    // the service behind the channel, given by address or resolved by name once the channel is up
+    service: Option<RouterAddress>,
+    // when the channel's key exchange was last started
+    initiated: Instant,
+    // when to re-initiate the channel after a failure
+    retry_at: Option<Instant>,
+    backoff: Duration,
+    // records held while the output has no channel, oldest first
+    held: VecDeque<Vec<u8>>,
+    dropped: usize,
+}
+
+impl Output {
+    fn new(route: Route, service: Option<RouterAddress>) -> Self {
+        Output {
+            route,
+            generation: 0,
+            channel: None,
+            service,
+            initiated: Instant::now(),
+            retry_at: None,
+            backoff: INITIAL_BACKOFF,
+            held: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    // Hold a record until the channel is up and the service known, dropping the oldest beyond `limit`
+    fn hold(&mut self, record: Vec<u8>, limit: usize) {
+        if limit == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.held.len() >= limit {
+            self.held.pop_front();
+            self.dropped += 1;
+        }
+        self.held.push_back(record);
+    }
+}
+
+// Each output is initiated with its own return address, so that the channel manager's
+// notification tells the worker which output a newly secured channel belongs to. Addresses start
+// at 1, since the channel manager announces channels it responds to at worker address zero. The
+// output's generation is in the upper half, so that a channel initiated for a route since
+// replaced, or by an attempt since retried, is told apart from the one last initiated.
+fn output_address(index: usize, generation: u16) -> Address {
+    let address = u32::from(generation) << 16 | (index as u32 + 1);
+    Address::WorkerAddress(address.to_be_bytes().to_vec())
+}
+
+// The index and generation of the output whose address is `address`, if it is one
+fn output_of(address: &Address) -> Option<(usize, u16)> {
+    match address {
+        Address::WorkerAddress(bytes) if bytes.len() == 4 => {
+            let address = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let index = (address & 0xFFFF) as usize;
+            if index == 0 {
+                return None;
+            }
+            Some((index - 1, (address >> 16) as u16))
+        }
+        _ => None,
+    }
+}
+
+// The address of the service records are sent to, unless it is given by name.
+fn service_address(config: &Config) -> Option<RouterAddress> {
+    config.service_address().map(|a| {
+        RouterAddress::worker_router_address_from_str(&a)
+            .expect("failed to create worker address for kex")
+    })
+}
+
+struct InputWorker {
+    outputs: Vec<Output>,
     router_tx: Sender<OckamCommand>,
+    channel_tx: Sender<OckamCommand>,
+    // wakes the node once the channel manager is sent a command
+    node_waker: Waker,
     rx: Receiver<OckamCommand>,
-    stdin: std::io::Stdin,
-    buf: String,
+    tx: Sender<OckamCommand>,
+    // a command received by `wait`, handled at the next poll
+    pending: Option<OckamCommand>,
+    records: Receiver<Vec<u8>>,
     config: Config,
+    update_rx: Receiver<ConfigUpdate>,
+    update_tx: Sender<ConfigUpdate>,
 }
 
-impl StdinWorker {
-    fn new(worker_addr: RouterAddress, router_tx: Sender<OckamCommand>, config: Config) -> Self {
+impl InputWorker {
+    fn new(
+        router_tx: Sender<OckamCommand>,
+        channel_tx: Sender<OckamCommand>,
+        node_waker: Waker,
+        config: Config,
+    ) -> Result<Self, String> {
         let (tx, rx) = mpsc::channel();
+        let (update_tx, update_rx) = mpsc::channel();
+        let records = input::spawn(
+            config.input_kind(),
+            config.framing(),
+            Waker::worker(tx.clone()),
+        )?;
 
         // register the worker with the router
         router_tx
             .send(OckamCommand::Router(RouterCommand::Register(
                 AddressType::Worker,
-                tx,
+                tx.clone(),
             )))
-            .expect("Stdin worker registration failed");
+            .expect("input worker registration failed");
 
-        Self {
-            channel: None,
-            worker_addr,
+        let outputs = config
+            .onward_routes()
+            .into_iter()
+            .map(|route| Output::new(route, service_address(&config)))
+            .collect();
+
+        Ok(Self {
+            outputs,
             router_tx,
+            channel_tx,
+            node_waker,
             rx,
-            stdin: std::io::stdin(),
-            buf: String::new(),
+            tx,
+            pending: None,
+            records,
             config,
+            update_rx,
+            update_tx,
+        })
+    }
+
+    fn config_sender(&self) -> Sender<ConfigUpdate> {
+        self.update_tx.clone()
+    }
+
+    // The address output `index` was last initiated with
+    fn address(&self, index: usize) -> Address {
+        output_address(index, self.outputs[index].generation)
+    }
+
+    fn initiate(&mut self, index: usize) -> Result<(), String> {
+        let output = &mut self.outputs[index];
+        output.initiated = Instant::now();
+        output.generation = output.generation.wrapping_add(1);
+        self.channel_tx
+            .send(OckamCommand::Channel(ChannelCommand::Initiate(
+                self.outputs[index].route.clone(),
+                self.address(index),
+                None,
+                None,
+            )))
+            .map_err(|e| format!("failed to initiate channel: {}", e))?;
+        self.node_waker.wake();
+        Ok(())
+    }
+
+    // Close a channel the worker no longer sends over, and the other end with it.
+    fn close_channel(&self, channel: &RouterAddress) {
+        let close = ChannelCommand::Close(channel.address.clone());
+        if let Err(e) = self.channel_tx.send(OckamCommand::Channel(close)) {
+            eprintln!("failed to close channel: {}", e);
+        }
+        self.node_waker.wake();
+    }
+
+    // A changed route gets a new channel. Input keeps flowing over the output's current channel
+    // until the new one completes its key exchange and replaces it in `receive_channel`. The
+    // channels of removed routes are closed.
+    fn apply_update(&mut self, update: ConfigUpdate) {
+        if let ConfigUpdate::OnwardRoutes(routes) = &update {
+            let removed: Vec<Output> = self
+                .outputs
+                .drain(routes.len().min(self.outputs.len())..)
+                .collect();
+            for channel in removed.iter().filter_map(|output| output.channel.as_ref()) {
+                self.close_channel(channel);
+            }
+            for (index, route) in routes.iter().enumerate() {
+                match self.outputs.get_mut(index) {
+                    Some(output) if output.route.addresses == route.addresses => continue,
+                    Some(output) => output.route = route.clone(),
+                    None => self
+                        .outputs
+                        .push(Output::new(route.clone(), service_address(&self.config))),
+                }
+                if let Err(e) = self.initiate(index) {
+                    eprintln!("{}", e);
+                }
+            }
         }
+        self.config.apply(&update);
+    }
+
+    // The output a message was sent to, unless it was sent to the address of one removed or of
+    // a key exchange it initiated before its last
+    fn output_index(&self, m: &Message) -> Option<usize> {
+        let address = &m.onward_route.addresses.first()?.address;
+        match output_of(address) {
+            Some((index, generation))
+                if self.outputs.get(index).map(|o| o.generation) == Some(generation) =>
+            {
+                Some(index)
+            }
+            _ => None,
+        }
+    }
+
+    // The output whose channel sent a message
+    fn channel_output(&self, m: &Message) -> Option<usize> {
+        let channel = m.return_route.addresses.first()?;
+        self.outputs
+            .iter()
+            .position(|output| output.channel.as_ref() == Some(channel))
     }
 
     pub fn receive_channel(&mut self, m: Message) -> Result<(), String> {
         let channel = m.return_route.addresses[0].clone();
-        self.channel = Some(channel);
-        let resp_public_key = encode(&m.message_body);
-        println!("Remote static public key: {}", resp_public_key);
-        if let Some(rpk) = self.config.remote_public_key() {
-            if rpk == encode(&m.message_body) {
-                println!("keys agree");
+        // a key exchange completing late, for a route removed or since replaced, or after it was
+        // given up on and initiated again
+        let index = match self.output_index(&m) {
+            Some(index) => index,
+            None => {
+                self.close_channel(&channel);
                 return Ok(());
-            } else {
-                println!("keys conflict");
-                return Err("remote public key doesn't match expected, possible spoofing".into());
+            }
+        };
+        // the channel manager's trust policy has already checked the key, if one is expected
+        println!("Remote static public key: {}", encode(&m.message_body));
+
+        // the channel of the output's previous route is replaced
+        if let Some(previous) = self.outputs[index].channel.replace(channel.clone()) {
+            if previous != channel {
+                self.close_channel(&previous);
             }
         }
+        let output = &mut self.outputs[index];
+        output.retry_at = None;
+        output.backoff = INITIAL_BACKOFF;
+        if output.dropped > 0 {
+            eprintln!(
+                "dropped {} records for output {} while it had no channel",
+                output.dropped, index
+            );
+            output.dropped = 0;
+        }
+
+        // a service given by name is resolved afresh over each channel, since the responder
+        // may have been reconfigured meanwhile
+        if let Some(name) = self.config.service_name() {
+            output.service = None;
+            let reply_to =
+                RouterAddress::worker_router_address_from_str(&self.address(index).as_string())?;
+            let request = names::resolve_request(&channel, &reply_to, index as u64, &name);
+            return self
+                .router_tx
+                .send(OckamCommand::Router(RouterCommand::SendMessage(request)))
+                .map_err(|e| format!("failed to resolve service {}: {}", name, e));
+        }
+        self.flush(index);
         Ok(())
     }
 
+    // The responder's answer to the request in `receive_channel` for the service's address.
+    fn receive_resolution(&mut self, m: Message) -> Result<(), String> {
+        let index = self
+            .output_index(&m)
+            .ok_or("service address resolved for unknown output")?;
+        let name = self.config.service_name().unwrap_or_default();
+        match names::resolve_response(&m)? {
+            (_, Some(service)) => {
+                println!(
+                    "Service {} is at {} for output {}",
+                    name,
+                    service.address.as_string(),
+                    index
+                );
+                self.outputs[index].service = Some(service);
+                self.flush(index);
+                Ok(())
+            }
+            (_, None) => Err(format!(
+                "responder for output {} has no service named {}",
+                index, name
+            )),
+        }
+    }
+
+    // Send what was held while the output had no channel or service, in order.
+    fn flush(&mut self, index: usize) {
+        let output = &mut self.outputs[index];
+        if let (Some(channel), Some(service)) = (&output.channel, &output.service) {
+            for record in output.held.drain(..) {
+                send_record(&self.router_tx, service, channel, record);
+            }
+        }
+    }
+
+    // The output's channel was closed by the remote end, or here, or by the channel manager
+    // once it stopped answering keepalives or its key exchange failed; open another after
+    // the backoff.
+    fn channel_closed(&mut self, index: usize) {
+        let max_backoff = self.config.max_backoff();
+        let output = &mut self.outputs[index];
+        match output.channel.take() {
+            Some(_) => eprintln!(
+                "channel for output {} was closed; reconnecting in {:?}",
+                index, output.backoff
+            ),
+            // a channel still in its key exchange is closed when the trust policy refuses the
+            // responder, and fails when the responder doesn't answer or its messages are bad
+            None if output.retry_at.is_none() => eprintln!(
+                "key exchange for output {} failed; retrying in {:?}",
+                index, output.backoff
+            ),
+            None => return,
+        }
+        output.retry_at = Some(Instant::now() + output.backoff);
+        output.backoff = (output.backoff * 2).min(max_backoff);
+    }
+
+    // Re-initiate channels whose key exchange doesn't complete, with exponential backoff. Once a
+    // channel is up, the channel manager's heartbeats watch it, and it is closed if the other
+    // end stops answering them.
+    fn check_outputs(&mut self) {
+        let keepalive = self.config.keepalive();
+        let max_backoff = self.config.max_backoff();
+        let now = Instant::now();
+
+        for index in 0..self.outputs.len() {
+            let output = &mut self.outputs[index];
+            if let Some(retry_at) = output.retry_at {
+                if now >= retry_at {
+                    output.retry_at = None;
+                    if let Err(e) = self.initiate(index) {
+                        eprintln!("{}", e);
+                    }
+                }
+                continue;
+            }
+            let keepalive = match keepalive {
+                Some(keepalive) => keepalive,
+                None => continue,
+            };
+
+            if output.channel.is_none()
+                && now.duration_since(output.initiated) > keepalive * MISSED_KEEPALIVES
+            {
+                eprintln!(
+                    "key exchange for output {} timed out; retrying in {:?}",
+                    index, output.backoff
+                );
+                output.retry_at = Some(now + output.backoff);
+                output.backoff = (output.backoff * 2).min(max_backoff);
+            }
+        }
+    }
+
+    // When the next key exchange times out or retry of a channel is due, if any.
+    fn next_deadline(&self) -> Option<Instant> {
+        let keepalive = self.config.keepalive();
+        self.outputs
+            .iter()
+            .filter_map(|output| match (output.retry_at, keepalive) {
+                (Some(retry_at), _) => Some(retry_at),
+                (None, Some(keepalive)) if output.channel.is_none() => {
+                    Some(output.initiated + keepalive * MISSED_KEEPALIVES)
+                }
+                (None, _) => None,
+            })
+            .min()
+    }
+
+    // Block until the worker is sent a command, or woken for an input record or configuration
+    // update, or until a key exchange times out or a retry is due.
+    fn wait(&mut self) {
+        self.pending = match self.next_deadline() {
+            Some(at) => self
+                .rx
+                .recv_timeout(at.saturating_duration_since(Instant::now()))
+                .ok(),
+            None => self.rx.recv().ok(),
+        };
+    }
+
     fn poll(&mut self) -> bool {
+        while let Ok(update) = self.update_rx.try_recv() {
+            self.apply_update(update);
+        }
+
         // await key exchange finalization
         // match self.rx.try_recv() {
         //     Ok(cmd) => match cmd {
-        if let Ok(cmd) = self.rx.try_recv() {
+        if let Some(cmd) = self.pending.take().or_else(|| self.rx.try_recv().ok()) {
             match cmd {
                 OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)) => {
                     match msg.message_type {
-                        MessageType::None => {
-                            // validate the public key matches the one in our config
-                            // TODO: revert this comment to validate the responder public
-                            // key if let Some(key) =
-                            // config.remote_public_key() {
-                            //     validate_public_key(&key, msg.message_body)
-                            //         .expect("failed to prove responder identity");
-                            // }
-                            match self.receive_channel(msg) {
-                                Ok(()) => {}
-                                Err(s) => panic!(s),
+                        MessageType::None => match self.receive_channel(msg) {
+                            Ok(()) => {}
+                            Err(s) => panic!("{}", s),
+                        },
+                        MessageType::Payload => {
+                            if let Err(e) = self.receive_resolution(msg) {
+                                eprintln!("{}", e);
+                            }
+                        }
+                        // a heartbeat was answered, the channel manager keeps track of those
+                        MessageType::Pong => {}
+                        MessageType::Close => {
+                            let index = self
+                                .channel_output(&msg)
+                                .or_else(|| self.output_index(&msg));
+                            if let Some(index) = index {
+                                self.channel_closed(index);
                             }
                         }
-                        _ => unimplemented!(),
+                        MessageType::ChannelError => {
+                            if let Some(index) = self.output_index(&msg) {
+                                eprintln!(
+                                    "key exchange for output {} failed with error {:#010x}",
+                                    index,
+                                    channel_error_code(&msg).unwrap_or_default()
+                                );
+                                self.channel_closed(index);
+                            }
+                        }
+                        message_type => eprintln!(
+                            "input worker rejected a message: unexpected message type {:?}",
+                            message_type
+                        ),
                     }
                 }
-                _ => unimplemented!(),
+                OckamCommand::Worker(WorkerCommand::Stop) => return false,
+                OckamCommand::Worker(WorkerCommand::Wake) => {}
+                cmd => eprintln!(
+                    "input worker rejected a message: unrecognized command {:?}",
+                    cmd
+                ),
             }
         }
 
-        // read from stdin, pass each line to the router within the node
-        if let Some(channel) = &self.channel {
-            return if self.stdin.read_line(&mut self.buf).is_ok() {
-                self.router_tx
-                    .send(OckamCommand::Router(RouterCommand::SendMessage(
-                        OckamMessage {
-                            //onward_route: self.onward_route.clone(),
-                            onward_route: Route {
-                                addresses: vec![channel.clone(), self.worker_addr.clone()],
-                            },
-                            return_route: Route { addresses: vec![] },
-                            message_type: MessageType::Payload,
-                            message_body: self.buf.as_bytes().to_vec(),
-                        },
-                    )))
-                    .expect("failed to send input data to node");
-                self.buf.clear();
-                true
-            } else {
-                println!("failed to read stdin");
-                false
+        self.check_outputs();
+
+        // pass each input record to the router within the node for every output with a secure
+        // channel to its service, holding it for the others until their channel is up
+        let limit = self.config.buffer_limit();
+        while let Ok(record) = self.records.try_recv() {
+            stats::record_message(record.len());
+            // with `--topic`, the service is a broker, and each record a publication to it
+            let record = match self.config.topic() {
+                Some(topic) => match pubsub::publish(&topic, &record) {
+                    Ok(publication) => publication,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                },
+                None => record,
             };
+            for output in &mut self.outputs {
+                match (&output.channel, &output.service) {
+                    (Some(channel), Some(service)) => {
+                        send_record(&self.router_tx, service, channel, record.clone())
+                    }
+                    _ => output.hold(record.clone(), limit),
+                }
+            }
         }
         true
     }
 }
 
+fn send_record(
+    router_tx: &Sender<OckamCommand>,
+    worker_addr: &RouterAddress,
+    channel: &RouterAddress,
+    record: Vec<u8>,
+) {
+    router_tx
+        .send(OckamCommand::Router(RouterCommand::SendMessage(
+            OckamMessage {
+                onward_route: Route {
+                    addresses: vec![channel.clone(), worker_addr.clone()],
+                },
+                return_route: Route { addresses: vec![] },
+                message_type: MessageType::Payload,
+                message_body: record,
+            },
+        )))
+        .expect("failed to send input data to node");
+}
+
 #[allow(dead_code)]
 fn validate_public_key(known: &str, remote: Vec<u8>) -> Result<(), String> {
     if known.as_bytes().to_vec() == remote {
@@ -155,3 +619,33 @@ fn validate_public_key(known: &str, remote: Vec<u8>) -> Result<(), String> {
         Err("remote public key mismatch".into())
     }
 }
+
+#[test]
+fn test_initiator_output_hold() {
+    let mut output = Output::new(Route { addresses: vec![] }, None);
+    for record in 0..5u8 {
+        output.hold(vec![record], 3);
+    }
+    assert_eq!(output.held, vec![vec![2], vec![3], vec![4]]);
+    assert_eq!(output.dropped, 2);
+
+    output.hold(vec![5], 0);
+    assert_eq!(output.held.len(), 3);
+    assert_eq!(output.dropped, 3);
+}
+
+#[test]
+fn test_initiator_output_address() {
+    // the generation in the upper half, and the index from 1 in the lower
+    assert_eq!(
+        output_address(2, 1),
+        Address::WorkerAddress(vec![0, 1, 0, 3])
+    );
+    for &(index, generation) in [(0, 0), (2, 1), (0xFFFE, 0xFFFF)].iter() {
+        assert_eq!(
+            output_of(&output_address(index, generation)),
+            Some((index, generation))
+        );
+    }
+    assert_eq!(output_of(&Address::WorkerAddress(vec![0; 4])), None);
+}
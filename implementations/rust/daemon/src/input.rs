@@ -0,0 +1,446 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{Framing, Input};
+use crate::wake::Waker;
+
+/// Largest record accepted from a length-prefixed input.
+pub const MAX_RECORD_SIZE: usize = 1 << 20;
+
+/// How often a tailed file is checked for new data once its end has been reached.
+const TAIL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Start reading records from the configured input on background threads. Each complete record
+/// is delivered on the returned receiver, in the order it was read, and `waker` woken for it.
+pub fn spawn(input: Input, framing: Framing, waker: Waker) -> Result<Receiver<Vec<u8>>, String> {
+    let (tx, rx) = mpsc::channel();
+    let tx = Records(tx, waker);
+
+    match input {
+        Input::Stdin => {
+            thread::spawn(move || {
+                let stdin = io::stdin();
+                forward(stdin.lock(), framing, tx);
+            });
+        }
+        Input::Tcp(addr) => listen_tcp(addr, framing, tx)?,
+        #[cfg(windows)]
+        Input::Pipe(name) => listen_pipe(&name, framing, tx)?,
+        Input::File(path) => {
+            thread::spawn(move || tail_file(path, framing, tx));
+        }
+        #[cfg(unix)]
+        Input::Unix(path) => listen_unix(&path, framing, tx)?,
+    }
+
+    Ok(rx)
+}
+
+// Delivers records to the receiver returned by `spawn`, waking its reader for each.
+#[derive(Clone)]
+struct Records(Sender<Vec<u8>>, Waker);
+
+impl Records {
+    fn send(&self, record: Vec<u8>) -> Result<(), ()> {
+        self.0.send(record).map_err(|_| ())?;
+        self.1.wake();
+        Ok(())
+    }
+}
+
+// Accept local connections, forwarding records from each until it closes.
+fn listen_tcp(addr: SocketAddr, framing: Framing, tx: Records) -> Result<(), String> {
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("failed to listen on {}: {}", addr, e))?;
+    println!("Listening for input on tcp://{}", addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || forward(BufReader::new(stream), framing, tx));
+                }
+                Err(e) => eprintln!("failed to accept input connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Accept connections on a Unix-domain socket which only the daemon's user may connect to,
+// forwarding records from each until it closes.
+#[cfg(unix)]
+fn listen_unix(path: &std::path::Path, framing: Framing, tx: Records) -> Result<(), String> {
+    let listener = bind_private_socket(path)?;
+    println!("Listening for input on unix://{}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || forward(BufReader::new(stream), framing, tx));
+                }
+                Err(e) => eprintln!("failed to accept input connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Listen on a Unix-domain socket which only the daemon's user may connect to, replacing a
+/// socket left behind by a previous run.
+#[cfg(unix)]
+pub fn bind_private_socket(
+    path: &std::path::Path,
+) -> Result<std::os::unix::net::UnixListener, String> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+
+    // a socket left behind by a previous run would make bind fail
+    if let Ok(true) = std::fs::metadata(path).map(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)
+            .map_err(|e| format!("failed to remove stale socket {}: {}", path.display(), e))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| format!("failed to listen on {}: {}", path.display(), e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("failed to restrict {}: {}", path.display(), e))?;
+    Ok(listener)
+}
+
+// Serve a local-only, inbound named pipe, forwarding records from each client until it
+// disconnects. A new pipe instance is created for every client so several may write at once.
+#[cfg(windows)]
+fn listen_pipe(name: &str, framing: Framing, tx: Records) -> Result<(), String> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
+    use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+    use winapi::um::errhandlingapi::GetLastError;
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW};
+    use winapi::um::winbase::{
+        PIPE_ACCESS_INBOUND, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
+        PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+
+    let path = format!(r"\\.\pipe\{}", name);
+    let wide: Vec<u16> = std::ffi::OsStr::new(&path)
+        .encode_wide()
+        .chain(Some(0))
+        .collect();
+
+    let create = move || -> Result<File, String> {
+        let handle = unsafe {
+            CreateNamedPipeW(
+                wide.as_ptr(),
+                PIPE_ACCESS_INBOUND,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                0,
+                65536,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(format!(
+                "failed to create named pipe: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(unsafe { File::from_raw_handle(handle as RawHandle) })
+    };
+
+    // create the first instance up front so a bad pipe name is reported at startup
+    let mut next = create()?;
+    println!("Listening for input on {}", path);
+
+    thread::spawn(move || loop {
+        let pipe = next;
+        let connected =
+            unsafe { ConnectNamedPipe(pipe.as_raw_handle() as _, std::ptr::null_mut()) };
+        if connected != 0 || unsafe { GetLastError() } == ERROR_PIPE_CONNECTED {
+            let tx = tx.clone();
+            thread::spawn(move || forward(BufReader::new(pipe), framing, tx));
+        }
+
+        next = match create() {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+    });
+
+    Ok(())
+}
+
+// Follow a file like `tail -F`: start at its current end, forward records as they are appended,
+// and start over from the beginning of the file once it has been rotated or truncated.
+fn tail_file(path: PathBuf, framing: Framing, tx: Records) {
+    let mut file: Option<File> = None;
+    let mut from_start = false;
+    let mut pending = vec![];
+    let mut chunk = [0u8; 8192];
+
+    loop {
+        if file.is_none() {
+            match File::open(&path) {
+                Ok(mut f) => {
+                    if !from_start {
+                        if let Err(e) = f.seek(SeekFrom::End(0)) {
+                            eprintln!("failed to seek {}: {}", path.display(), e);
+                        }
+                    }
+                    file = Some(f);
+                }
+                Err(_) => {
+                    // not created yet, or mid-rotation; everything written once it exists is new
+                    from_start = true;
+                    thread::sleep(TAIL_INTERVAL);
+                    continue;
+                }
+            }
+        }
+
+        let f = file.as_mut().unwrap();
+        match f.read(&mut chunk) {
+            Ok(0) => {
+                if rotated(f, &path) {
+                    // a partial record at the end of the old file will never be completed
+                    pending.clear();
+                    file = None;
+                    from_start = true;
+                } else {
+                    thread::sleep(TAIL_INTERVAL);
+                }
+            }
+            Ok(n) => {
+                pending.extend_from_slice(&chunk[..n]);
+                match take_records(&mut pending, framing) {
+                    Ok(records) => {
+                        for record in records {
+                            if tx.send(record).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("dropping input from {}: {}", path.display(), e);
+                        pending.clear();
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("failed to read {}: {}", path.display(), e);
+                file = None;
+                thread::sleep(TAIL_INTERVAL);
+            }
+        }
+    }
+}
+
+// The file at `path` has been replaced, or truncated below what was already read.
+fn rotated(file: &mut File, path: &Path) -> bool {
+    let current = match std::fs::metadata(path) {
+        Ok(m) => m,
+        // keep draining the old file until its replacement appears
+        Err(_) => return false,
+    };
+
+    // rather than `stream_position`, which needs Rust 1.51
+    if let Ok(pos) = Seek::seek(file, SeekFrom::Current(0)) {
+        if current.len() < pos {
+            return true;
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Ok(open) = file.metadata() {
+            return open.ino() != current.ino() || open.dev() != current.dev();
+        }
+    }
+
+    false
+}
+
+/// Remove every complete record from the front of `pending`, leaving a trailing partial record
+/// in place until the rest of it arrives.
+pub fn take_records(pending: &mut Vec<u8>, framing: Framing) -> Result<Vec<Vec<u8>>, String> {
+    let mut records = vec![];
+    let mut start = 0;
+
+    loop {
+        let rest = &pending[start..];
+        let end = match framing {
+            Framing::Newline => match rest.iter().position(|b| *b == b'\n') {
+                Some(i) => i + 1,
+                None => break,
+            },
+            Framing::LengthPrefixed => {
+                if rest.len() < 4 {
+                    break;
+                }
+                let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                if len > MAX_RECORD_SIZE {
+                    return Err(format!("record of {} bytes exceeds limit", len));
+                }
+                if rest.len() < 4 + len {
+                    break;
+                }
+                4 + len
+            }
+            Framing::Chunk(n) => {
+                if rest.len() < n {
+                    break;
+                }
+                n
+            }
+        };
+
+        let record = match framing {
+            Framing::LengthPrefixed => rest[4..end].to_vec(),
+            _ => rest[..end].to_vec(),
+        };
+        records.push(record);
+        start += end;
+    }
+
+    pending.drain(..start);
+    Ok(records)
+}
+
+fn forward<R: BufRead>(mut reader: R, framing: Framing, tx: Records) {
+    loop {
+        match read_record(&mut reader, framing) {
+            Ok(Some(record)) => {
+                if tx.send(record).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("failed to read input: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Read one record, returning `None` once the input is exhausted. Newline-framed records keep
+/// their trailing newline; length-prefixed records start with a 4-byte big-endian length; raw
+/// chunks are always full-sized, except for the last one read before the input closes.
+pub fn read_record<R: BufRead>(reader: &mut R, framing: Framing) -> io::Result<Option<Vec<u8>>> {
+    match framing {
+        Framing::Newline => {
+            let mut record = vec![];
+            match reader.read_until(b'\n', &mut record)? {
+                0 => Ok(None),
+                _ => Ok(Some(record)),
+            }
+        }
+        Framing::LengthPrefixed => {
+            let mut len = [0u8; 4];
+            if reader.fill_buf()?.is_empty() {
+                return Ok(None);
+            }
+            reader.read_exact(&mut len)?;
+
+            let len = u32::from_be_bytes(len) as usize;
+            if len > MAX_RECORD_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("record of {} bytes exceeds limit", len),
+                ));
+            }
+
+            let mut record = vec![0; len];
+            reader.read_exact(&mut record)?;
+            Ok(Some(record))
+        }
+        Framing::Chunk(n) => {
+            let mut record = Vec::with_capacity(n);
+            match reader.by_ref().take(n as u64).read_to_end(&mut record)? {
+                0 => Ok(None),
+                _ => Ok(Some(record)),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_input_take_records() {
+    let mut pending = b"one\ntw".to_vec();
+    assert_eq!(
+        take_records(&mut pending, Framing::Newline).unwrap(),
+        vec![b"one\n".to_vec()]
+    );
+    pending.extend_from_slice(b"o\n");
+    assert_eq!(
+        take_records(&mut pending, Framing::Newline).unwrap(),
+        vec![b"two\n".to_vec()]
+    );
+    assert!(pending.is_empty());
+
+    let mut pending = vec![0, 0, 0, 2, 0xaa, 0xbb, 0, 0, 0, 1];
+    assert_eq!(
+        take_records(&mut pending, Framing::LengthPrefixed).unwrap(),
+        vec![vec![0xaa, 0xbb]]
+    );
+    assert_eq!(pending, vec![0, 0, 0, 1]);
+
+    let mut pending = vec![1, 2, 3, 4, 5];
+    assert_eq!(
+        take_records(&mut pending, Framing::Chunk(2)).unwrap(),
+        vec![vec![1, 2], vec![3, 4]]
+    );
+    assert_eq!(pending, vec![5]);
+}
+
+#[test]
+fn test_input_read_record() {
+    let mut newline = io::Cursor::new(b"first\nsecond".to_vec());
+    assert_eq!(
+        read_record(&mut newline, Framing::Newline).unwrap(),
+        Some(b"first\n".to_vec())
+    );
+    assert_eq!(
+        read_record(&mut newline, Framing::Newline).unwrap(),
+        Some(b"second".to_vec())
+    );
+    assert_eq!(read_record(&mut newline, Framing::Newline).unwrap(), None);
+
+    let mut prefixed = io::Cursor::new(vec![0, 0, 0, 3, 0xff, 0x00, 0x0a]);
+    assert_eq!(
+        read_record(&mut prefixed, Framing::LengthPrefixed).unwrap(),
+        Some(vec![0xff, 0x00, 0x0a])
+    );
+    assert_eq!(
+        read_record(&mut prefixed, Framing::LengthPrefixed).unwrap(),
+        None
+    );
+
+    let mut raw = io::Cursor::new(vec![1, 2, 3]);
+    assert_eq!(
+        read_record(&mut raw, Framing::Chunk(2)).unwrap(),
+        Some(vec![1, 2])
+    );
+    assert_eq!(
+        read_record(&mut raw, Framing::Chunk(2)).unwrap(),
+        Some(vec![3])
+    );
+    assert_eq!(read_record(&mut raw, Framing::Chunk(2)).unwrap(), None);
+}
@@ -0,0 +1,176 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cli::{DEFAULT_VAULT_PATH, FILENAME_KEY_DEFAULT};
+use crate::{identity, vault};
+
+use ockam_identity::Identity;
+use ockam_vault::file::FilesystemVault;
+use structopt::StructOpt;
+
+/// Subcommands of `ockamd key`, which manage identity keys in the filesystem vault.
+#[derive(StructOpt)]
+#[structopt(
+    name = "ockamd key",
+    about = "Manage the identity keys in the filesystem vault used by `ockamd`."
+)]
+pub enum KeyCommand {
+    /// Generate a new identity key, refusing to replace one that already exists.
+    Generate(KeyOptions),
+
+    /// Show the identity's identifier and public key, and the replacement key if a rotation is
+    /// pending.
+    Show(KeyOptions),
+
+    /// Generate a replacement identity key, which takes effect at the first start of `ockamd`
+    /// after the overlap window, leaving time to share its public key with peers.
+    Rotate {
+        #[structopt(flatten)]
+        key: KeyOptions,
+
+        #[structopt(
+            long,
+            default_value = "86400",
+            help = "Seconds to keep using the current key before switching to the new one"
+        )]
+        overlap_secs: u64,
+    },
+
+    /// Print the identity's hex-encoded public key, or write it to a file.
+    ExportPublic {
+        #[structopt(flatten)]
+        key: KeyOptions,
+
+        #[structopt(
+            parse(from_os_str),
+            long,
+            help = "File to write the public key to, instead of printing it"
+        )]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt)]
+pub struct KeyOptions {
+    #[structopt(
+        parse(from_os_str),
+        long,
+        default_value = DEFAULT_VAULT_PATH,
+        help = "Filepath on disk of the filesystem vault"
+    )]
+    vault_path: PathBuf,
+
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "File holding the passphrase the filesystem vault is sealed with"
+    )]
+    vault_passphrase_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        default_value = FILENAME_KEY_DEFAULT,
+        help = "Name of the identity key in the vault"
+    )]
+    identity_name: String,
+}
+
+/// Handle `ockamd key generate|show|rotate|export-public [OPTIONS]`.
+pub fn main(args: &[OsString]) -> Result<(), String> {
+    // parse as if `key` were the program name, so that usage reads `ockamd key ...`
+    match KeyCommand::from_iter(&args[1..]) {
+        KeyCommand::Generate(key) => {
+            let mut vault = open(&key)?;
+            if identity::contains_key(&mut vault, &key.identity_name) {
+                return Err(format!(
+                    "identity key {} already exists; use `ockamd key rotate` to replace it",
+                    key.identity_name
+                ));
+            }
+            identity::load_or_generate(&mut vault, &key.vault_path, &key.identity_name)?;
+            let identity = identity::load(
+                Arc::new(Mutex::new(vault)),
+                &key.vault_path,
+                &key.identity_name,
+            )?;
+            println!("{}", hex::encode(identity.public_key()));
+        }
+        KeyCommand::Show(key) => {
+            let identity = load(&key)?;
+            println!("Identity: {}", identity.id());
+            println!(
+                "Identity key {}: {}",
+                key.identity_name,
+                hex::encode(identity.public_key())
+            );
+            if let Some(staged) = identity::staged(&key.vault_path, &key.identity_name)? {
+                println!(
+                    "Replacement key: {} ({})",
+                    staged.public_key,
+                    takes_effect(staged.after)
+                );
+            }
+        }
+        KeyCommand::Rotate { key, overlap_secs } => {
+            // the current key signs its replacement
+            let current = load(&key)?;
+            if identity::staged(&key.vault_path, &key.identity_name)?.is_some() {
+                return Err(format!(
+                    "a rotation of {} is already pending; see `ockamd key show`",
+                    key.identity_name
+                ));
+            }
+            let public_key = identity::stage_rotation(
+                &key.vault_path,
+                &current,
+                &key.identity_name,
+                Duration::from_secs(overlap_secs),
+            )?;
+            println!(
+                "Replacement key: {} ({})",
+                public_key,
+                takes_effect(identity::unix_time() + overlap_secs)
+            );
+        }
+        KeyCommand::ExportPublic { key, output } => {
+            let public_key = hex::encode(load(&key)?.public_key());
+            match output {
+                Some(path) => std::fs::write(&path, format!("{}\n", public_key))
+                    .map_err(|e| format!("failed to write {}: {}", path.display(), e))?,
+                None => println!("{}", public_key),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn open(key: &KeyOptions) -> Result<FilesystemVault, String> {
+    // apply a rotation that is due, as the node would on its next start
+    identity::promote_staged(&key.vault_path, &key.identity_name)?;
+    vault::open_filesystem(&key.vault_path, key.vault_passphrase_file.as_deref())
+}
+
+fn load(key: &KeyOptions) -> Result<Identity, String> {
+    let mut vault = open(key)?;
+    if !identity::contains_key(&mut vault, &key.identity_name) {
+        return Err(format!(
+            "no identity key {} in {}; use `ockamd key generate` to create it",
+            key.identity_name,
+            key.vault_path.display()
+        ));
+    }
+    identity::load(
+        Arc::new(Mutex::new(vault)),
+        &key.vault_path,
+        &key.identity_name,
+    )
+}
+
+fn takes_effect(after: u64) -> String {
+    match after.checked_sub(identity::unix_time()) {
+        Some(secs) if secs > 0 => format!("takes effect at the first start in {}s", secs),
+        _ => "takes effect at the next start".into(),
+    }
+}
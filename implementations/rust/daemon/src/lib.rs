@@ -1,6 +1,40 @@
+pub mod addon;
+pub mod builder;
 pub mod cli;
+pub mod clock;
 pub mod config;
+pub mod control;
+pub mod daemonize;
+pub mod discovery;
+pub mod gateway;
+pub mod hub;
+pub mod identity;
 pub mod initiator;
+pub mod input;
+pub mod key;
+pub mod mailbox;
+pub mod mdns;
+pub mod names;
 pub mod node;
+pub mod output;
+pub mod ping;
+pub mod post;
+pub mod pubsub;
+pub mod relay;
+pub mod reload;
+pub mod request;
 pub mod responder;
+#[cfg(windows)]
+pub mod service;
+pub mod stats;
+pub mod suspend;
+#[cfg(feature = "systemd")]
+pub mod systemd;
+pub mod transfer;
+pub mod trust;
+pub mod vault;
+pub mod wake;
 pub mod worker;
+
+pub use builder::Builder;
+pub use node::{NodeBuilder, NodeHandle, RemoteHandle, ThreadPolicy};
@@ -0,0 +1,336 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::worker::{Worker, WorkerHandler};
+
+use ockam_message::message::{
+    AddressType, Codec, Message as OckamMessage, MessageType, Route, RouterAddress,
+};
+use ockam_system::commands::{OckamCommand, RouterCommand};
+use sha2::{Digest, Sha256};
+
+/// The worker address of the mailbox service, "mail" in ASCII.
+pub const MAILBOX_SERVICE_ADDRESS: &str = "6d61696c";
+
+// the kind of request the mailbox handles, the first byte of each payload
+const DEPOSIT: u8 = 1;
+
+// the length of an identity's identifier
+const ID_LEN: usize = 32;
+
+// how often expired messages are removed, unless the time they are kept is shorter
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A deposit of `payload` for the worker at `to` on the node of the identity `recipient`, as
+/// given to `--mailbox-dir`'s service at `MAILBOX_SERVICE_ADDRESS`, over a channel or not.
+pub fn deposit(recipient: &str, to: &RouterAddress, payload: &[u8]) -> Result<Vec<u8>, String> {
+    let id = hex::decode(recipient)
+        .ok()
+        .filter(|id| id.len() == ID_LEN)
+        .ok_or_else(|| format!("bad identity identifier: {}", recipient))?;
+    let mut v = vec![DEPOSIT];
+    v.extend_from_slice(&id);
+    to.encode(&mut v)?;
+    v.extend_from_slice(payload);
+    Ok(v)
+}
+
+/// The mailbox service, which holds messages for identities which are rarely online. An
+/// identity registers by completing a channel to the node; from then on, messages deposited
+/// for it are kept on disk under `--mailbox-dir`, up to `--mailbox-limit` for each identity
+/// and for `--mailbox-ttl-secs`, and all of them are delivered over the next channel it
+/// completes, each to the worker it was deposited for. A message is removed once sent, so one
+/// lost on the way isn't sent again.
+///
+/// The identity of a channel's initiator is taken to be the SHA-256 of its static public key,
+/// which is its identifier until it first rotates its key.
+pub fn mailbox_worker(config: &Config, router_tx: Sender<OckamCommand>) -> Option<Worker> {
+    let dir = config.mailbox_dir()?;
+    let worker_addr =
+        RouterAddress::worker_router_address_from_str(MAILBOX_SERVICE_ADDRESS).unwrap();
+    let addr = worker_addr.clone();
+    let tx = router_tx.clone();
+    let make_handler = Box::new(
+        move |config: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            let store = Store::open(&dir, config.mailbox_limit(), config.mailbox_ttl())?;
+            Ok(Box::new(Mailbox {
+                store,
+                router_tx: tx.clone(),
+                addr: addr.clone(),
+            }))
+        },
+    );
+    let worker = Worker::new(worker_addr, router_tx, config.clone(), make_handler)
+        .expect("failed to start mailbox worker");
+    Some(worker)
+}
+
+struct Mailbox {
+    store: Store,
+    router_tx: Sender<OckamCommand>,
+    addr: RouterAddress,
+}
+
+impl WorkerHandler for Mailbox {
+    fn handle_message(&mut self, _config: &Config, msg: OckamMessage) -> Result<(), String> {
+        let body = match msg.message_body.split_first() {
+            Some((&DEPOSIT, body)) if body.len() > ID_LEN => body,
+            _ => {
+                eprintln!("mailbox: refused a message which isn't a deposit");
+                return Ok(());
+            }
+        };
+        let recipient = hex::encode(&body[..ID_LEN]);
+        let (to, payload) = RouterAddress::decode(&body[ID_LEN..])?;
+        if to.a_type != AddressType::Worker {
+            eprintln!(
+                "mailbox: refused a deposit for {}: not for a worker",
+                recipient
+            );
+            return Ok(());
+        }
+        // a failure to store is the depositor's to see, not a reason to restart the mailbox
+        if let Err(e) = self.store.deposit(&recipient, &to, payload) {
+            eprintln!("mailbox: refused a deposit for {}: {}", recipient, e);
+        }
+        Ok(())
+    }
+
+    fn on_channel_established(
+        &mut self,
+        _config: &Config,
+        channel: &RouterAddress,
+        remote_public_key: &[u8],
+    ) -> Result<(), String> {
+        let recipient = hex::encode(Sha256::digest(remote_public_key));
+        self.store.register(&recipient)?;
+
+        let stored = self.store.take(&recipient)?;
+        if !stored.is_empty() {
+            println!("Delivering {} messages to {}", stored.len(), recipient);
+        }
+        for (to, payload) in stored {
+            let msg = OckamMessage {
+                onward_route: Route {
+                    addresses: vec![channel.clone(), to],
+                },
+                return_route: Route {
+                    addresses: vec![self.addr.clone()],
+                },
+                message_type: MessageType::Payload,
+                message_body: payload,
+            };
+            self.router_tx
+                .send(OckamCommand::Router(RouterCommand::SendMessage(msg)))
+                .map_err(|_| "failed to send to router".to_string())?;
+        }
+        Ok(())
+    }
+
+    fn tick_interval(&self, config: &Config) -> Option<Duration> {
+        Some(config.mailbox_ttl().min(EXPIRY_INTERVAL))
+    }
+
+    fn on_tick(&mut self, _config: &Config) -> Result<(), String> {
+        self.store.expire()
+    }
+}
+
+/// The messages held for each registered identity: a directory of its own under the mailbox
+/// directory, named by its identifier, with a file for each message, named by the time it was
+/// deposited in nanoseconds since the Unix epoch, of the address it is for then the payload.
+struct Store {
+    dir: PathBuf,
+    limit: usize,
+    ttl: Duration,
+}
+
+impl Store {
+    fn open(dir: &Path, limit: usize, ttl: Duration) -> Result<Store, String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("failed to create mailbox {}: {}", dir.display(), e))?;
+        Ok(Store {
+            dir: dir.to_path_buf(),
+            limit,
+            ttl,
+        })
+    }
+
+    fn register(&self, recipient: &str) -> Result<(), String> {
+        std::fs::create_dir_all(self.dir.join(recipient))
+            .map_err(|e| format!("failed to register {}: {}", recipient, e))
+    }
+
+    fn deposit(&self, recipient: &str, to: &RouterAddress, payload: &[u8]) -> Result<(), String> {
+        let dir = self.dir.join(recipient);
+        if !dir.is_dir() {
+            return Err("not a registered identity".into());
+        }
+        if self.messages(recipient)?.len() >= self.limit {
+            return Err(format!("the mailbox is full, at {} messages", self.limit));
+        }
+        let mut v = vec![];
+        to.encode(&mut v)?;
+        v.extend_from_slice(payload);
+
+        // deposits within the same nanosecond are ordered as they arrived
+        let mut at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        while dir.join(format!("{:024}", at)).exists() {
+            at += 1;
+        }
+        std::fs::write(dir.join(format!("{:024}", at)), v)
+            .map_err(|e| format!("failed to store message: {}", e))
+    }
+
+    // The messages held for `recipient`, oldest first, each with the time it was deposited.
+    fn messages(&self, recipient: &str) -> Result<Vec<(u128, PathBuf)>, String> {
+        let entries = match std::fs::read_dir(self.dir.join(recipient)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(format!("failed to read mailbox of {}: {}", recipient, e)),
+        };
+        let mut messages: Vec<(u128, PathBuf)> = entries
+            .filter_map(Result::ok)
+            .filter_map(|e| {
+                let at = e.file_name().to_str()?.parse().ok()?;
+                Some((at, e.path()))
+            })
+            .collect();
+        messages.sort();
+        Ok(messages)
+    }
+
+    // Remove and return the messages held for `recipient` which haven't expired.
+    fn take(&self, recipient: &str) -> Result<Vec<(RouterAddress, Vec<u8>)>, String> {
+        let oldest = self.oldest_kept();
+        let mut taken = vec![];
+        for (at, path) in self.messages(recipient)? {
+            let data = std::fs::read(&path);
+            std::fs::remove_file(&path).map_err(|e| format!("failed to remove message: {}", e))?;
+            match data {
+                Ok(data) if at >= oldest => {
+                    let (to, payload) = RouterAddress::decode(&data)?;
+                    taken.push((to, payload.to_vec()));
+                }
+                _ => {}
+            }
+        }
+        Ok(taken)
+    }
+
+    fn expire(&self) -> Result<(), String> {
+        let oldest = self.oldest_kept();
+        let recipients =
+            std::fs::read_dir(&self.dir).map_err(|e| format!("failed to read mailbox: {}", e))?;
+        for recipient in recipients.filter_map(Result::ok) {
+            let recipient = recipient.file_name().to_string_lossy().into_owned();
+            for (at, path) in self.messages(&recipient)? {
+                if at < oldest {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The time the oldest message which hasn't expired was deposited, in nanoseconds.
+    fn oldest_kept(&self) -> u128 {
+        SystemTime::now()
+            .checked_sub(self.ttl)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos())
+    }
+}
+
+#[test]
+fn test_mailbox_store() {
+    let dir = std::env::temp_dir().join("ockamd_test_mailbox_store");
+    let _ = std::fs::remove_dir_all(&dir);
+    let store = Store::open(&dir, 2, Duration::from_secs(60)).unwrap();
+    let recipient = hex::encode([7; ID_LEN]);
+    let to = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+
+    // only registered identities have mailboxes, which hold up to the limit
+    assert!(store.deposit(&recipient, &to, b"early").is_err());
+    store.register(&recipient).unwrap();
+    store.deposit(&recipient, &to, b"first").unwrap();
+    store.deposit(&recipient, &to, b"second").unwrap();
+    assert!(store.deposit(&recipient, &to, b"third").is_err());
+
+    let taken = store.take(&recipient).unwrap();
+    assert_eq!(
+        taken,
+        vec![
+            (to.clone(), b"first".to_vec()),
+            (to.clone(), b"second".to_vec())
+        ]
+    );
+    assert!(store.take(&recipient).unwrap().is_empty());
+
+    // expired messages are neither kept nor delivered
+    let expiring = Store::open(&dir, 2, Duration::from_secs(0)).unwrap();
+    expiring.deposit(&recipient, &to, b"stale").unwrap();
+    assert!(expiring.take(&recipient).unwrap().is_empty());
+    expiring.deposit(&recipient, &to, b"stale").unwrap();
+    expiring.expire().unwrap();
+    assert!(store.messages(&recipient).unwrap().is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_mailbox_deliver_on_channel() {
+    let dir = std::env::temp_dir().join("ockamd_test_mailbox_deliver");
+    let _ = std::fs::remove_dir_all(&dir);
+    let (router_tx, router_rx) = std::sync::mpsc::channel();
+    let mut mailbox = Mailbox {
+        store: Store::open(&dir, 10, Duration::from_secs(60)).unwrap(),
+        router_tx,
+        addr: RouterAddress::worker_router_address_from_str(MAILBOX_SERVICE_ADDRESS).unwrap(),
+    };
+    let config = Config::default();
+    let key = [3; 32];
+    let recipient = hex::encode(Sha256::digest(&key));
+    let to = RouterAddress::worker_router_address_from_str("0a0b0c0d").unwrap();
+    let channel = RouterAddress::channel_router_address_from_str("00000001").unwrap();
+    let deposited = |body| OckamMessage {
+        message_body: body,
+        ..Default::default()
+    };
+
+    // a deposit for an identity which hasn't registered is refused
+    mailbox
+        .handle_message(&config, deposited(deposit(&recipient, &to, b"hi").unwrap()))
+        .unwrap();
+    mailbox
+        .on_channel_established(&config, &channel, &key)
+        .unwrap();
+    assert!(router_rx.try_recv().is_err());
+
+    // once registered, deposits wait for the recipient's next channel
+    mailbox
+        .handle_message(&config, deposited(deposit(&recipient, &to, b"hi").unwrap()))
+        .unwrap();
+    assert!(router_rx.try_recv().is_err());
+    mailbox
+        .on_channel_established(&config, &channel, &key)
+        .unwrap();
+    match router_rx.try_recv() {
+        Ok(OckamCommand::Router(RouterCommand::SendMessage(msg))) => {
+            assert_eq!(msg.onward_route.addresses, vec![channel, to]);
+            assert_eq!(msg.message_body, b"hi");
+        }
+        _ => panic!("deposit wasn't delivered"),
+    }
+    assert!(router_rx.try_recv().is_err());
+    assert!(deposit(
+        "zz",
+        &RouterAddress::worker_router_address_from_str("01").unwrap(),
+        b""
+    )
+    .is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,703 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigUpdate, Role, ECHO_SERVICE_ADDRESS};
+use crate::discovery;
+use crate::node::Node;
+use crate::wake::Waker;
+
+use ockam_message::message::{Address, Route, RouterAddress};
+use structopt::StructOpt;
+
+/// The DNS-SD service type `ockamd` nodes are advertised under.
+pub const SERVICE_TYPE: &str = "_ockam._udp.local";
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+// the record types and class used, and the bit of a unique record's class which tells caches
+// to replace what they hold for its name
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+
+// the flags of a response, which is authoritative
+const RESPONSE: u16 = 0x8400;
+
+// peers are forgotten once they miss this many announcements
+const EXPIRY_INTERVALS: u32 = 3;
+
+// how often a node announces itself, and browses for peers
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+
+// the prefix of the TXT entries naming a service, e.g. `s.telemetry=01242020`
+const SERVICE_ENTRY: &str = "s.";
+
+/// A node as advertised on the local network: its transport address, its role and the services
+/// it hosts, by name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advert {
+    pub instance: String,
+    pub addr: SocketAddr,
+    pub role: String,
+    pub services: Vec<(String, String)>,
+}
+
+impl Advert {
+    /// What the node running with `config` advertises, as `instance`.
+    pub fn of(config: &Config, instance: String) -> Advert {
+        let mut services: Vec<(String, String)> = config
+            .services()
+            .into_iter()
+            .map(|s| (s.name, s.address))
+            .collect();
+        if config.echo() {
+            services.push(("echo".into(), ECHO_SERVICE_ADDRESS.into()));
+        }
+        let mut addr = config.local_host();
+        if addr.ip().is_unspecified() {
+            if let Some(ip) = outbound_ip() {
+                addr.set_ip(IpAddr::V4(ip));
+            }
+        }
+        Advert {
+            instance,
+            addr,
+            role: format!("{:?}", config.role()).to_lowercase(),
+            services,
+        }
+    }
+
+    /// The address of the service called `name` on the node, if it hosts one.
+    pub fn service(&self, name: &str) -> Option<&str> {
+        self.services
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, a)| a.as_str())
+    }
+
+    fn name(&self) -> String {
+        format!("{}.{}", self.instance, SERVICE_TYPE)
+    }
+
+    fn host(&self) -> String {
+        format!("{}.local", self.instance)
+    }
+}
+
+/// Advertise the node on the local network with mDNS, answering the queries for `ockamd` nodes
+/// and announcing itself at intervals, unless it is an initiator, which has nothing to offer.
+/// An initiator, or a node running both roles, also browses for the nodes hosting the service
+/// it sends its input to, by its name or address, and opens a channel to each one found as if
+/// given by `--route`, sending the routes found to `routes` and waking the worker with `waker`.
+/// Runs on a thread of its own until the worker has gone.
+pub fn spawn(
+    config: &Config,
+    node: &Node,
+    routes: Option<(Sender<ConfigUpdate>, Waker)>,
+) -> Result<(), String> {
+    // named by its identity, which is unique to it, as the host name may not be
+    let id = node
+        .identity()
+        .map_or_else(|| config.identity_name(), |i| i.id().to_string());
+    let id: String = id
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .take(16)
+        .collect();
+    let instance = format!("ockamd-{}", id);
+
+    let socket = bind_shared(MDNS_PORT).map_err(|e| format!("failed to bind mDNS port: {}", e))?;
+    socket
+        .join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)
+        .map_err(|e| format!("failed to join mDNS group: {}", e))?;
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .map_err(|e| e.to_string())?;
+
+    let mut browser = routes.map(|(tx, waker)| Browser {
+        instance: instance.clone(),
+        config: config.clone(),
+        tx,
+        waker,
+        peers: HashMap::new(),
+        routes: vec![],
+    });
+    let advert = match config.role() {
+        Role::Initiator => None,
+        _ => Some(Advert::of(config, instance)),
+    };
+    if let Some(advert) = &advert {
+        println!(
+            "Advertising {} on {} with mDNS",
+            advert.instance, advert.addr
+        );
+    }
+
+    thread::spawn(move || {
+        let group = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+        let mut announced = None::<Instant>;
+        let mut buf = [0; 9000];
+        loop {
+            if announced
+                .map(|at| at.elapsed() >= ANNOUNCE_INTERVAL)
+                .unwrap_or(true)
+            {
+                announced = Some(Instant::now());
+                if let Some(advert) = &advert {
+                    let _ = socket.send_to(&advert_packet(advert), group);
+                }
+                if let Some(browser) = &mut browser {
+                    let _ = socket.send_to(&query_packet(), group);
+                    if !browser.expire() {
+                        return;
+                    }
+                }
+            }
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    eprintln!("mDNS: {}", e);
+                    return;
+                }
+            };
+            let packet = &buf[..len];
+            if let Some(advert) = advert.as_ref().filter(|_| is_query(packet)) {
+                // a query from another port than mDNS's wants its answer directly
+                let to = if from.port() == MDNS_PORT {
+                    group
+                } else {
+                    from
+                };
+                let _ = socket.send_to(&advert_packet(advert), to);
+            } else if let Some(browser) = &mut browser {
+                if !browser.found(parse_adverts(packet, from)) {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// The nodes found hosting the service an initiator sends to, and the routes it was given.
+struct Browser {
+    // the node's own instance, which a node running both roles mustn't send to
+    instance: String,
+    config: Config,
+    tx: Sender<ConfigUpdate>,
+    waker: Waker,
+    peers: HashMap<String, (Advert, Instant)>,
+    routes: Vec<Route>,
+}
+
+impl Browser {
+    fn offers(&self, advert: &Advert) -> bool {
+        if advert.instance == self.instance {
+            return false;
+        }
+        match (self.config.service_name(), self.config.service_address()) {
+            (Some(name), _) => advert.service(&name).is_some(),
+            (None, Some(address)) => advert.services.iter().any(|(_, a)| *a == address),
+            (None, None) => false,
+        }
+    }
+
+    // Keep the peers which offer the service, returning false once the worker has gone.
+    fn found(&mut self, adverts: Vec<Advert>) -> bool {
+        for advert in adverts {
+            if !self.offers(&advert) {
+                continue;
+            }
+            if !self.peers.contains_key(&advert.instance) {
+                println!("Found {} at {} with mDNS", advert.instance, advert.addr);
+            }
+            self.peers
+                .insert(advert.instance.clone(), (advert, Instant::now()));
+        }
+        self.update()
+    }
+
+    fn expire(&mut self) -> bool {
+        self.peers
+            .retain(|_, (_, seen)| seen.elapsed() < ANNOUNCE_INTERVAL * EXPIRY_INTERVALS);
+        self.update()
+    }
+
+    // Send the routes to the peers on, with those configured, whenever they change.
+    fn update(&mut self) -> bool {
+        let mut peers: Vec<&Advert> = self.peers.values().map(|(a, _)| a).collect();
+        peers.sort_by(|a, b| a.instance.cmp(&b.instance));
+        let mut routes = self.config.onward_routes();
+        routes.extend(peers.into_iter().filter_map(|a| {
+            let hop = RouterAddress::from_address(Address::UdpAddress(a.addr))?;
+            Some(Route {
+                addresses: vec![hop],
+            })
+        }));
+        let same = routes.len() == self.routes.len()
+            && routes
+                .iter()
+                .zip(&self.routes)
+                .all(|(a, b)| a.addresses == b.addresses);
+        if same {
+            return true;
+        }
+        self.routes = routes.clone();
+        if self.tx.send(ConfigUpdate::OnwardRoutes(routes)).is_err() {
+            return false;
+        }
+        self.waker.wake();
+        true
+    }
+}
+
+// The address of the interface multicasts go out on, to advertise when bound to all of them.
+fn outbound_ip() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+// Bind `port` on all interfaces alongside any other mDNS responder on the host, e.g. Avahi.
+#[cfg(unix)]
+fn bind_shared(port: u16) -> io::Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // the socket closes the descriptor if binding fails
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let on: libc::c_int = 1;
+    for option in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let set = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                *option,
+                &on as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if set < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = port.to_be();
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    {
+        addr.sin_len = std::mem::size_of::<libc::sockaddr_in>() as u8;
+    }
+    let bound = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if bound < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn bind_shared(port: u16) -> io::Result<UdpSocket> {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+}
+
+fn put_u16(v: &mut Vec<u8>, n: u16) {
+    v.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_name(v: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        v.push(label.len() as u8);
+        v.extend_from_slice(label);
+    }
+    v.push(0);
+}
+
+fn put_record(v: &mut Vec<u8>, name: &str, rtype: u16, class: u16, rdata: &[u8]) {
+    put_name(v, name);
+    put_u16(v, rtype);
+    put_u16(v, class);
+    v.extend_from_slice(
+        &(ANNOUNCE_INTERVAL * EXPIRY_INTERVALS)
+            .as_secs()
+            .to_be_bytes()[4..],
+    );
+    put_u16(v, rdata.len() as u16);
+    v.extend_from_slice(rdata);
+}
+
+fn query_packet() -> Vec<u8> {
+    let mut v = vec![0; 4];
+    for count in &[1, 0, 0, 0] {
+        put_u16(&mut v, *count);
+    }
+    put_name(&mut v, SERVICE_TYPE);
+    put_u16(&mut v, TYPE_PTR);
+    put_u16(&mut v, CLASS_IN);
+    v
+}
+
+fn advert_packet(advert: &Advert) -> Vec<u8> {
+    let ip = match advert.addr.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    };
+    let mut v = vec![0, 0];
+    put_u16(&mut v, RESPONSE);
+    for count in &[0, 3 + ip.iter().count() as u16, 0, 0] {
+        put_u16(&mut v, *count);
+    }
+
+    let mut ptr = vec![];
+    put_name(&mut ptr, &advert.name());
+    put_record(&mut v, SERVICE_TYPE, TYPE_PTR, CLASS_IN, &ptr);
+
+    let mut srv = vec![0, 0, 0, 0];
+    put_u16(&mut srv, advert.addr.port());
+    put_name(&mut srv, &advert.host());
+    put_record(
+        &mut v,
+        &advert.name(),
+        TYPE_SRV,
+        CLASS_IN | CACHE_FLUSH,
+        &srv,
+    );
+
+    let mut txt = vec![];
+    let entries = Some(format!("role={}", advert.role)).into_iter().chain(
+        advert
+            .services
+            .iter()
+            .map(|(name, address)| format!("{}{}={}", SERVICE_ENTRY, name, address)),
+    );
+    for entry in entries.filter(|e| e.len() <= u8::MAX as usize) {
+        txt.push(entry.len() as u8);
+        txt.extend_from_slice(entry.as_bytes());
+    }
+    put_record(
+        &mut v,
+        &advert.name(),
+        TYPE_TXT,
+        CLASS_IN | CACHE_FLUSH,
+        &txt,
+    );
+
+    if let Some(ip) = ip {
+        put_record(
+            &mut v,
+            &advert.host(),
+            TYPE_A,
+            CLASS_IN | CACHE_FLUSH,
+            &ip.octets(),
+        );
+    }
+    v
+}
+
+// Reads the names and records of a DNS message, following the pointers which compress names.
+struct Reader<'a> {
+    packet: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.packet.get(self.at..self.at + 2)?;
+        self.at += 2;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let b = self.packet.get(self.at..self.at + len)?;
+        self.at += len;
+        Some(b)
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let mut labels = vec![];
+        let mut at = self.at;
+        let mut end = None;
+        // a pointer only leads backwards, so following a few is enough for any real name
+        for _ in 0..128 {
+            let len = *self.packet.get(at)? as usize;
+            if len == 0 {
+                self.at = end.unwrap_or(at + 1);
+                return Some(labels.join("."));
+            }
+            if len & 0xc0 == 0xc0 {
+                let low = *self.packet.get(at + 1)? as usize;
+                end.get_or_insert(at + 2);
+                at = (len & 0x3f) << 8 | low;
+                continue;
+            }
+            let label = self.packet.get(at + 1..at + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            at += 1 + len;
+        }
+        None
+    }
+}
+
+// Whether a message is a query for `ockamd` nodes.
+fn is_query(packet: &[u8]) -> bool {
+    let mut r = Reader { packet, at: 2 };
+    let questions = match (r.u16(), r.u16()) {
+        (Some(flags), Some(questions)) if flags & 0x8000 == 0 => questions,
+        _ => return false,
+    };
+    r.at = 12;
+    for _ in 0..questions {
+        let (name, qtype) = match (r.name(), r.u16(), r.u16()) {
+            (Some(name), Some(qtype), Some(_)) => (name, qtype),
+            _ => return false,
+        };
+        if name.eq_ignore_ascii_case(SERVICE_TYPE) && (qtype == TYPE_PTR || qtype == TYPE_ANY) {
+            return true;
+        }
+    }
+    false
+}
+
+// The nodes a response advertises; a node without an address record is at the one it came from.
+fn parse_adverts(packet: &[u8], from: SocketAddr) -> Vec<Advert> {
+    let mut r = Reader { packet, at: 2 };
+    let counts = match r.u16().filter(|flags| flags & 0x8000 != 0) {
+        Some(_) => (0..4).map(|_| r.u16()).collect::<Option<Vec<u16>>>(),
+        None => None,
+    };
+    let counts = match counts {
+        Some(counts) => counts,
+        None => return vec![],
+    };
+    for _ in 0..counts[0] {
+        if r.name().and(r.u16()).and(r.u16()).is_none() {
+            return vec![];
+        }
+    }
+
+    let mut instances = vec![];
+    let mut srv = HashMap::new();
+    let mut txt = HashMap::new();
+    let mut a = HashMap::new();
+    for _ in 0..counts[1] as usize + counts[2] as usize + counts[3] as usize {
+        let (name, rtype, end) = match (r.name(), r.u16(), r.u16(), r.bytes(4), r.u16()) {
+            (Some(name), Some(rtype), Some(_), Some(_), Some(len)) => {
+                (name, rtype, r.at + len as usize)
+            }
+            _ => break,
+        };
+        if end > packet.len() {
+            break;
+        }
+        match rtype {
+            TYPE_PTR if name.eq_ignore_ascii_case(SERVICE_TYPE) => {
+                if let Some(instance) = r.name() {
+                    instances.push(instance);
+                }
+            }
+            TYPE_SRV => {
+                if let (Some(_), Some(_), Some(port), Some(target)) =
+                    (r.u16(), r.u16(), r.u16(), r.name())
+                {
+                    srv.insert(name.to_lowercase(), (port, target.to_lowercase()));
+                }
+            }
+            TYPE_TXT => {
+                let mut entries = vec![];
+                while r.at < end {
+                    let len = match r.bytes(1) {
+                        Some(len) => len[0] as usize,
+                        None => break,
+                    };
+                    match r.bytes(len) {
+                        Some(entry) => entries.push(String::from_utf8_lossy(entry).into_owned()),
+                        None => break,
+                    }
+                }
+                txt.insert(name.to_lowercase(), entries);
+            }
+            TYPE_A => {
+                if let Some(ip) = r.bytes(4) {
+                    a.insert(
+                        name.to_lowercase(),
+                        Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]),
+                    );
+                }
+            }
+            _ => {}
+        }
+        r.at = end;
+    }
+
+    instances
+        .into_iter()
+        .filter_map(|name| {
+            let (port, target) = srv.get(&name.to_lowercase())?;
+            let suffix = format!(".{}", SERVICE_TYPE);
+            let instance = name
+                .get(..name.len().checked_sub(suffix.len())?)?
+                .to_string();
+            let ip = a.get(target).map_or(from.ip(), |ip| IpAddr::V4(*ip));
+            let mut advert = Advert {
+                instance,
+                addr: SocketAddr::new(ip, *port),
+                role: String::new(),
+                services: vec![],
+            };
+            for entry in txt.get(&name.to_lowercase()).into_iter().flatten() {
+                let (key, value) = match entry.find('=') {
+                    Some(i) => (&entry[..i], &entry[i + 1..]),
+                    None => continue,
+                };
+                match key.strip_prefix(SERVICE_ENTRY) {
+                    Some(service) => advert.services.push((service.into(), value.into())),
+                    None if key == "role" => advert.role = value.into(),
+                    None => {}
+                }
+            }
+            Some(advert)
+        })
+        .collect()
+}
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "ockamd browse",
+    about = "List the `ockamd` nodes advertised with mDNS on the local network, and the services they host."
+)]
+pub struct BrowseArgs {
+    #[structopt(
+        long,
+        default_value = "2000",
+        help = "Milliseconds to wait for nodes to answer"
+    )]
+    timeout_ms: u64,
+}
+
+/// Handle `ockamd browse [OPTIONS]`.
+pub fn main(args: &[OsString]) -> Result<(), String> {
+    // parse as if `browse` were the program name, so that usage reads `ockamd browse ...`
+    let args = BrowseArgs::from_iter(&args[1..]);
+
+    // the query comes from a port of its own, so that the answers come straight back to it
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+    socket
+        .send_to(&query_packet(), (MDNS_GROUP, MDNS_PORT))
+        .map_err(|e| format!("failed to send mDNS query: {}", e))?;
+
+    let deadline = Instant::now() + Duration::from_millis(args.timeout_ms);
+    let mut found: Vec<Advert> = vec![];
+    let mut buf = [0; 9000];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining == Duration::from_secs(0) {
+            break;
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|e| e.to_string())?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => break,
+        };
+        for advert in parse_adverts(&buf[..len], from) {
+            if found.iter().any(|f| f.instance == advert.instance) {
+                continue;
+            }
+            let hop = RouterAddress::from_address(Address::UdpAddress(advert.addr));
+            let route = discovery::route_text(&Route {
+                addresses: hop.into_iter().collect(),
+            });
+            println!("{} ({}) --route {}", advert.instance, advert.role, route);
+            for (name, address) in &advert.services {
+                println!("  {} --service-address {}", name, address);
+            }
+            found.push(advert);
+        }
+    }
+    if found.is_empty() {
+        return Err("no nodes found".into());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_mdns_advert() {
+    let advert = Advert {
+        instance: "ockamd-0a0b0c0d".into(),
+        addr: "192.168.1.20:4051".parse().unwrap(),
+        role: "responder".into(),
+        services: vec![
+            ("telemetry".into(), "01242020".into()),
+            ("echo".into(), ECHO_SERVICE_ADDRESS.into()),
+        ],
+    };
+    let from: SocketAddr = "192.168.1.20:5353".parse().unwrap();
+    let packet = advert_packet(&advert);
+    assert!(!is_query(&packet));
+    assert_eq!(parse_adverts(&packet, from), vec![advert.clone()]);
+    assert_eq!(advert.service("telemetry"), Some("01242020"));
+
+    // without an address record, the node is at the address the answer came from
+    let mut unbound = advert.clone();
+    unbound.addr = "0.0.0.0:4051".parse().unwrap();
+    let parsed = parse_adverts(&advert_packet(&unbound), from);
+    assert_eq!(parsed[0].addr, "192.168.1.20:4051".parse().unwrap());
+
+    assert!(is_query(&query_packet()));
+    assert!(parse_adverts(&query_packet(), from).is_empty());
+    assert!(parse_adverts(&packet[..packet.len() - 3], from).len() <= 1);
+}
+
+#[test]
+fn test_mdns_compressed_name() {
+    // a PTR answer whose instance name points back at the service type in the question
+    let mut packet = vec![0, 0, 0x84, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+    put_name(&mut packet, SERVICE_TYPE);
+    put_u16(&mut packet, TYPE_PTR);
+    put_u16(&mut packet, CLASS_IN);
+    packet.extend_from_slice(&[0xc0, 12]);
+    put_u16(&mut packet, TYPE_PTR);
+    put_u16(&mut packet, CLASS_IN);
+    packet.extend_from_slice(&[0, 0, 0, 120, 0, 6, 4]);
+    packet.extend_from_slice(b"node");
+    packet.extend_from_slice(&[0xc0, 12]);
+
+    let mut r = Reader {
+        packet: &packet,
+        at: 12,
+    };
+    assert_eq!(r.name().unwrap(), SERVICE_TYPE);
+    r.at += 4;
+    assert_eq!(r.name().unwrap(), SERVICE_TYPE);
+    r.at += 10;
+    assert_eq!(r.name().unwrap(), format!("node.{}", SERVICE_TYPE));
+    assert_eq!(r.at, packet.len());
+}
@@ -0,0 +1,147 @@
+use std::sync::mpsc::Sender;
+
+use crate::config::{Config, ECHO_SERVICE_ADDRESS};
+use crate::request;
+use crate::worker::{Replier, Worker, WorkerHandler};
+
+use ockam_message::message::{Message as OckamMessage, MessageType, Route, RouterAddress};
+use ockam_system::commands::OckamCommand;
+
+/// The worker address of the name service, "name" in ASCII, which a responder hosts so that
+/// initiators can reach its services by name.
+pub const NAME_SERVICE_ADDRESS: &str = "6e616d65";
+
+/// The prefix of a service given by name rather than worker address, e.g.
+/// `--service-address service:influx-sink`.
+pub const NAME_PREFIX: &str = "service:";
+
+/// The worker address of the service called `name` on a node with `config`.
+pub fn resolve(config: &Config, name: &str) -> Option<String> {
+    match config.service(name) {
+        Some(service) => Some(service.address),
+        None if name == "echo" && config.echo() => Some(ECHO_SERVICE_ADDRESS.into()),
+        None => None,
+    }
+}
+
+/// Answer each request for a name with the worker address of the service, or with an empty
+/// payload if there is no service of that name, from the configuration as last reloaded.
+pub fn name_worker(config: &Config, router_tx: Sender<OckamCommand>) -> Worker {
+    let worker_addr = RouterAddress::worker_router_address_from_str(NAME_SERVICE_ADDRESS).unwrap();
+    let replier = Replier::new(router_tx.clone(), worker_addr.clone());
+    let make_handler = Box::new(
+        move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            let replier = replier.clone();
+            Ok(Box::new(move |config: &Config, msg: OckamMessage| {
+                let (_, name) =
+                    request::decode(&msg.message_body).ok_or("request has no correlation ID")?;
+                let address = resolve(config, &String::from_utf8_lossy(name))
+                    .map(|address| hex::decode(address).unwrap_or_default())
+                    .unwrap_or_default();
+                replier.respond(&msg, &address)
+            }))
+        },
+    );
+    Worker::new(worker_addr, router_tx, config.clone(), make_handler)
+        .expect("failed to start name service worker")
+}
+
+/// A request to the name service behind `channel` for the service called `name`, answered to
+/// `reply_to` with the same `id`.
+pub fn resolve_request(
+    channel: &RouterAddress,
+    reply_to: &RouterAddress,
+    id: u64,
+    name: &str,
+) -> OckamMessage {
+    OckamMessage {
+        onward_route: Route {
+            addresses: vec![
+                channel.clone(),
+                RouterAddress::worker_router_address_from_str(NAME_SERVICE_ADDRESS).unwrap(),
+            ],
+        },
+        return_route: Route {
+            addresses: vec![reply_to.clone()],
+        },
+        message_type: MessageType::Payload,
+        message_body: request::encode(id, name.as_bytes()),
+    }
+}
+
+/// The address in the name service's answer to a request, or None if the name is unknown.
+pub fn resolve_response(msg: &OckamMessage) -> Result<(u64, Option<RouterAddress>), String> {
+    let (id, address) =
+        request::decode(&msg.message_body).ok_or("name service response has no correlation ID")?;
+    if address.is_empty() {
+        return Ok((id, None));
+    }
+    let address = RouterAddress::worker_router_address_from_str(&hex::encode(address))?;
+    Ok((id, Some(address)))
+}
+
+#[test]
+fn test_names_resolve() {
+    use ockam_system::commands::{RouterCommand, WorkerCommand};
+    use std::sync::mpsc;
+
+    let config: Config = crate::cli::Args::load(
+        vec![
+            "ockamd",
+            "--role",
+            "responder",
+            "--service",
+            "influx-sink=0a0b0c0d",
+            "--echo",
+        ]
+        .into_iter()
+        .map(std::ffi::OsString::from),
+    )
+    .unwrap()
+    .into();
+    assert_eq!(resolve(&config, "influx-sink").unwrap(), "0a0b0c0d");
+    assert_eq!(resolve(&config, "echo").unwrap(), ECHO_SERVICE_ADDRESS);
+    assert!(resolve(&config, "default").is_none());
+
+    let (fake_router_tx, fake_router_rx) = mpsc::channel();
+    let mut worker = name_worker(&config, fake_router_tx);
+    fake_router_rx.try_recv().unwrap();
+
+    let channel = RouterAddress::channel_router_address_from_str("01020304").unwrap();
+    let initiator = RouterAddress::worker_router_address_from_str("00000001").unwrap();
+    for &(id, name) in [(7, "influx-sink"), (8, "missing")].iter() {
+        // the request arrives with the channel in front of the initiator's return route
+        let mut msg = resolve_request(&channel, &initiator, id, name);
+        msg.onward_route.addresses.remove(0);
+        msg.return_route.addresses.insert(0, channel.clone());
+        worker
+            .sender()
+            .send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)))
+            .unwrap();
+        assert!(worker.poll());
+    }
+
+    let responses: Vec<_> = fake_router_rx
+        .try_iter()
+        .map(|cmd| match cmd {
+            OckamCommand::Router(RouterCommand::SendMessage(msg)) => {
+                assert_eq!(
+                    msg.onward_route.addresses,
+                    vec![channel.clone(), initiator.clone()]
+                );
+                resolve_response(&msg).unwrap()
+            }
+            _ => panic!("expected a response to the router"),
+        })
+        .collect();
+    assert_eq!(
+        responses,
+        vec![
+            (
+                7,
+                Some(RouterAddress::worker_router_address_from_str("0a0b0c0d").unwrap())
+            ),
+            (8, None)
+        ]
+    );
+}
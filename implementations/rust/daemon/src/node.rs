@@ -1,126 +1,274 @@
-use std::sync::mpsc::{self, Sender};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use crate::cli;
-use crate::config::{Config, Role};
-use crate::worker::Worker;
+use crate::config::{Cipher, Config, Role};
+use crate::control::{self, ControlCommand, ControlRequest};
+use crate::initiator;
+use crate::names;
+use crate::stats;
+use crate::suspend;
+#[cfg(feature = "systemd")]
+use crate::systemd;
+use crate::trust::{AllowList, Policy};
+use crate::vault;
+use crate::wake::Waker;
+use crate::worker::{self, DeadLetter, Dispatch, MakeHandler, Worker, WorkerHandler};
 
+use lazy_static::lazy_static;
 use ockam_channel::*;
-use ockam_kex::{
-    xx::{XXInitiator, XXNewKeyExchanger, XXResponder},
-    CipherSuite,
+use ockam_identity::Identity;
+use ockam_kex::{xx::XXNewKeyExchanger, CipherSuite};
+use ockam_message::message::{
+    AddressType, Message as OckamMessage, MessageType, Route, RouterAddress,
 };
-use ockam_message::message::AddressType;
 use ockam_router::router::Router;
-use ockam_system::commands::{OckamCommand, RouterCommand};
+use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand};
+use ockam_transport::profile::TransportProfile;
 use ockam_transport::transport::UdpTransport;
-use ockam_vault::types::*;
-use ockam_vault::{file::FilesystemVault, DynVault};
+use ockam_vault::DynVault;
+
+// the most recent messages rejected by workers, kept for the control socket
+const DEAD_LETTERS: usize = 100;
+
+// set to stop the running node, e.g. when its service is stopped
+static STOP: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // the running nodes, woken by `stop` so that they see it
+    static ref RUNNING: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+}
+
+// the longest a running node waits for work before polling its parts regardless
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+// how long a node's component threads have to finish once it stops, unless a `StopHandle` says
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Make a running node return from `run` once its current poll completes.
+pub fn stop() {
+    STOP.store(true, Ordering::SeqCst);
+    for waker in RUNNING.lock().unwrap().drain(..) {
+        waker.wake();
+    }
+}
 
 #[allow(dead_code)]
-pub struct Node<'a> {
-    config: &'a Config,
-    chan_manager: ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>,
-    worker: Option<Worker>,
+pub struct Node {
+    config: Config,
+    chan_manager: Option<ChannelManager>,
+    workers: Vec<Worker>,
+    // shares the router's worker handler between the workers, when polled by the node itself
+    dispatch: Option<Dispatch>,
+    spawned_rx: Option<Receiver<Worker>>,
+    dead_letters: VecDeque<DeadLetter>,
+    dead_letter_tx: Sender<DeadLetter>,
+    dead_letter_rx: Receiver<DeadLetter>,
     router: Router,
     router_tx: Sender<OckamCommand>,
     transport: UdpTransport,
     transport_tx: Sender<OckamCommand>,
     pub channel_tx: Sender<OckamCommand>,
+    identity: Option<Identity>,
+    vault: Option<Arc<Mutex<dyn DynVault + Send>>>,
+    control_rx: Option<Receiver<ControlRequest>>,
+    started: Instant,
+    // the threads of the node's components, by name, joined when it stops
+    threads: Vec<(String, JoinHandle<()>)>,
+    // polled on one thread with nothing of its own to wake it, see `ThreadPolicy::Cooperative`
+    cooperative: bool,
+    // set by a `StopHandle`, which is sent the outcome of stopping
+    stop_timeout: Arc<Mutex<Option<Duration>>>,
+    stop_reports: Vec<Sender<Result<(), String>>>,
+    // channels saved as the node last stopped, resumed at its first poll, once its workers
+    // are registered to be told of them
+    suspended: Vec<state::ChannelState>,
 }
 
-impl<'a> Node<'a> {
-    pub fn new(config: &'a Config) -> (Self, Sender<OckamCommand>) {
+impl Node {
+    pub fn new(config: &Config) -> (Self, Sender<OckamCommand>) {
         // TODO: temporarily passed into the node, need to re-work
-        let (router_tx, router_rx) = std::sync::mpsc::channel();
+        let router = std::sync::mpsc::channel();
+        Self::build(
+            config,
+            router,
+            None,
+            &config.local_host().to_string(),
+            false,
+        )
+        .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Create a node around the router channel `router`, with `vault`, or the one `config`
+    // selects, and a UDP transport bound to `local_host`, which reads its socket inline if the
+    // node is `cooperative`.
+    fn build(
+        config: &Config,
+        (router_tx, router_rx): (Sender<OckamCommand>, Receiver<OckamCommand>),
+        vault: Option<Arc<Mutex<dyn DynVault + Send>>>,
+        local_host: &str,
+        cooperative: bool,
+    ) -> Result<(Self, Sender<OckamCommand>), String> {
         let router = Router::new(router_rx);
 
-        // create the vault, using the FILESYSTEM implementation
-        let mut vault =
-            FilesystemVault::new(config.vault_path()).expect("failed to initialize vault");
-
-        let mut resp_key_ctx = None;
-        // check for re-use of provided identity name from CLI args, if not in on-disk in vault
-        // generate a new one to be used
-        if !contains_key(&mut vault, &config.identity_name()) {
-            // if responder, generate keypair and display static public key
-            if matches!(config.role(), Role::Responder) {
-                let attributes = SecretKeyAttributes {
-                    xtype: SecretKeyType::Curve25519,
-                    purpose: SecretPurposeType::KeyAgreement,
-                    persistence: SecretPersistenceType::Persistent,
+        // a relay only forwards messages between hops, so it has no identity and terminates no
+        // channels; messages addressed to a channel on the relay are dropped by the router
+        let (channel_tx, channel_rx) = mpsc::channel();
+        let (chan_manager, identity, vault) = match config.role() {
+            Role::Relay => (None, None, None),
+            _ => {
+                let vault = match vault {
+                    Some(vault) => vault,
+                    None => vault::open(config)
+                        .map_err(|e| format!("failed to initialize vault: {}", e))?,
                 };
-                resp_key_ctx = Some(
-                    vault
-                        .secret_generate(attributes)
-                        .expect("failed to generate secret"),
-                );
-            }
-        } else {
-            resp_key_ctx =
-                Some(as_key_ctx(&config.identity_name()).expect("invalid identity name provided"));
-        }
-
-        if matches!(config.role(), Role::Responder) && resp_key_ctx.is_some() {
-            if let Ok(resp_key) = vault.secret_public_key_get(resp_key_ctx.unwrap()) {
-                println!("Responder public key: {}", hex::encode(resp_key));
+                let (chan_manager, identity) = Self::channel_manager(
+                    config,
+                    vault.clone(),
+                    channel_rx,
+                    channel_tx.clone(),
+                    router_tx.clone(),
+                )?;
+                (Some(chan_manager), identity, Some(vault))
             }
-        }
-
-        // prepare the vault for use in key exchanger and channel manager
-        let vault = Arc::new(Mutex::new(vault));
-
-        // create the channel manager
-        type XXChannelManager = ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
-        let (channel_tx, channel_rx) = mpsc::channel();
-        let new_key_exchanger = XXNewKeyExchanger::new(
-            CipherSuite::Curve25519AesGcmSha256,
-            vault.clone(),
-            vault.clone(),
-        );
-
-        let chan_manager = XXChannelManager::new(
-            channel_rx,
-            channel_tx.clone(),
-            router_tx.clone(),
-            vault,
-            new_key_exchanger,
-            resp_key_ctx,
-            None,
-        )
-        .unwrap();
+        };
+        let suspended = match (&chan_manager, config.channel_state_file()) {
+            (Some(_), Some(path)) => suspend::load(&path).unwrap_or_else(|e| {
+                eprintln!("{}; starting without saved channels", e);
+                vec![]
+            }),
+            _ => vec![],
+        };
 
         // create the transport, currently UDP-only
         let transport_router_tx = router_tx.clone();
         let (transport_tx, transport_rx) = mpsc::channel();
         let self_transport_tx = transport_tx.clone();
-        let transport = UdpTransport::new(
-            transport_rx,
-            transport_tx,
-            transport_router_tx,
-            config.local_host().to_string().as_str(),
-        )
-        .expect("failed to create udp transport");
+        let transport = if cooperative {
+            UdpTransport::new_inline(
+                transport_rx,
+                transport_tx,
+                transport_router_tx,
+                local_host,
+                TransportProfile::default(),
+            )
+        } else {
+            UdpTransport::new(transport_rx, transport_tx, transport_router_tx, local_host)
+        }
+        .map_err(|e| format!("failed to create udp transport: {}", e))?;
 
         let node_router_tx = router_tx.clone();
-        (
+        let (dead_letter_tx, dead_letter_rx) = mpsc::channel();
+        Ok((
             Self {
-                config,
-                worker: None,
+                config: config.clone(),
+                workers: vec![],
+                dispatch: None,
+                spawned_rx: None,
+                dead_letters: VecDeque::new(),
+                dead_letter_tx,
+                dead_letter_rx,
                 router,
                 router_tx,
                 chan_manager,
                 transport_tx: self_transport_tx,
                 transport,
                 channel_tx,
+                identity,
+                vault,
+                control_rx: None,
+                started: Instant::now(),
+                threads: vec![],
+                cooperative,
+                stop_timeout: Arc::new(Mutex::new(None)),
+                stop_reports: vec![],
+                suspended,
             },
             node_router_tx,
+        ))
+    }
+
+    // Load the node's identity and create the channel manager, which terminates the secure
+    // channels this node initiates or responds to.
+    fn channel_manager(
+        config: &Config,
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        channel_rx: Receiver<OckamCommand>,
+        channel_tx: Sender<OckamCommand>,
+        router_tx: Sender<OckamCommand>,
+    ) -> Result<(ChannelManager, Option<Identity>), String> {
+        let identity = vault::identity(config, vault.clone())
+            .map_err(|e| format!("failed to load identity: {}", e))?;
+        if let Some(identity) = &identity {
+            let public_key = hex::encode(identity.public_key());
+            if matches!(config.role(), Role::Responder | Role::Both) {
+                println!("Responder identity: {}", identity.id());
+                println!("Responder public key: {}", public_key);
+            }
+            if let Some(path) = config.public_key_file() {
+                std::fs::write(&path, format!("{}\n", public_key))
+                    .map_err(|e| format!("failed to write public key file: {}", e))?;
+            }
+        }
+
+        // create the channel manager
+        let cipher_suite = match config.cipher() {
+            Cipher::AesGcm => CipherSuite::Curve25519AesGcmSha256,
+            Cipher::ChaChaPoly => CipherSuite::Curve25519ChaChaPolySha256,
+        };
+        let new_key_exchanger = XXNewKeyExchanger::new(cipher_suite, vault.clone(), vault.clone());
+
+        let mut chan_manager = ChannelManager::new(
+            channel_rx,
+            channel_tx,
+            router_tx,
+            vault,
+            Box::new(new_key_exchanger),
+            identity.clone(),
+            None,
+        )
+        .map_err(|e| format!("failed to create channel manager: {:?}", e))?;
+
+        // only complete channels with the expected responder and enrolled initiators, if any
+        // are configured
+        let mut policy = Policy::default();
+        if let Some(key) = config.remote_public_key() {
+            policy = policy.with_responder_key(&key)?;
+        }
+        let allow_list = AllowList::load(
+            &config.allowed_initiators(),
+            config.allowed_initiators_file().as_deref(),
         )
+        .map_err(|e| format!("failed to load allowed initiators: {}", e))?;
+        if let Some(allow_list) = allow_list {
+            println!(
+                "Accepting channels from {} allowed initiators",
+                allow_list.len()
+            );
+            policy = policy.with_initiators(allow_list);
+        }
+        if !policy.is_open() {
+            chan_manager.set_trust_policy(Box::new(policy));
+        }
+        chan_manager.set_idle_timeout(config.channel_idle_timeout());
+        chan_manager.set_reorder_window(config.reorder_window(), config.reorder_delay());
+        chan_manager.set_mtu(config.channel_mtu());
+        chan_manager.set_compression(config.channel_compress_over());
+        chan_manager.set_reliable(config.channel_reliable());
+        chan_manager.set_max_message_size(config.channel_max_message_size());
+        chan_manager
+            .set_half_open_limit(config.max_half_open_channels(), DEFAULT_HANDSHAKE_TIMEOUT);
+        chan_manager.set_heartbeat(config.keepalive(), initiator::MISSED_KEEPALIVES);
+
+        Ok((chan_manager, identity))
     }
 
-    pub fn add_worker(&mut self, worker: Worker) {
+    /// Poll `worker` while the node runs, and register it as the router's worker handler. Nodes
+    /// with several workers share the handler through `worker::dispatch`.
+    pub fn add_worker(&mut self, mut worker: Worker) {
         self.router_tx
             .send(OckamCommand::Router(RouterCommand::Register(
                 AddressType::Worker,
@@ -128,52 +276,1275 @@ impl<'a> Node<'a> {
             )))
             .expect("failed to register worker with router");
 
-        self.worker = Some(worker);
+        worker.set_dead_letters(self.dead_letter_tx.clone());
+        self.workers.push(worker);
+    }
+
+    /// Poll `dispatch` on the node's thread, rather than on a thread of its own as
+    /// `worker::dispatch` does.
+    pub fn add_dispatch(&mut self, dispatch: Dispatch) {
+        self.dispatch = Some(dispatch);
+    }
+
+    /// Workers sent here are polled too from then on, e.g. the sessions a `Factory` spawns.
+    pub fn worker_sender(&mut self) -> Sender<Worker> {
+        let (tx, rx) = mpsc::channel();
+        self.spawned_rx = Some(rx);
+        tx
+    }
+
+    /// Join `thread`, e.g. of a worker polled apart from the node, when the node stops. It
+    /// should end once its component is sent a stop by the router.
+    pub fn add_thread(&mut self, name: &str, thread: JoinHandle<()>) {
+        self.threads.push((name.to_string(), thread));
+    }
+
+    /// Wakes the node while it waits for work, for a thread which hands its parts work other
+    /// than through the router, e.g. a configuration update for one of its workers.
+    pub fn waker(&self) -> Waker {
+        Waker::router(self.router_tx.clone())
+    }
+
+    /// The node's identity, unless it is a relay.
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// The vault holding the node's keys, unless it is a relay.
+    pub fn vault(&self) -> Option<Arc<Mutex<dyn DynVault + Send>>> {
+        self.vault.clone()
+    }
+
+    // The sender for the channel manager's commands, if the node terminates channels.
+    fn channel_sender(&self) -> Option<Sender<OckamCommand>> {
+        self.chan_manager.as_ref().map(|_| self.channel_tx.clone())
+    }
+
+    /// A handle to stop the node from another thread while it runs.
+    pub fn stop_handle(&mut self) -> StopHandle {
+        let (done_tx, done) = mpsc::channel();
+        self.stop_reports.push(done_tx);
+        StopHandle {
+            router_tx: self.router_tx.clone(),
+            timeout: self.stop_timeout.clone(),
+            done,
+        }
+    }
+
+    /// Answer the commands received by a control socket while the node runs.
+    pub fn add_control(&mut self, control_rx: Receiver<ControlRequest>) {
+        self.control_rx = Some(control_rx);
     }
 
     pub fn run(mut self) {
-        match self.worker {
-            Some(worker) => {
-                while self.router.poll()
-                    && self.transport.poll()
-                    && worker.poll()
-                    && self
-                        .chan_manager
-                        .poll()
-                        .expect("channel manager poll failure")
-                {
-                    thread::sleep(time::Duration::from_millis(1));
+        // the transport is bound and the identity loaded by now, so the node is ready
+        #[cfg(feature = "systemd")]
+        let mut notifier = {
+            let mut notifier = systemd::Notifier::from_env();
+            notifier.ready();
+            notifier
+        };
+
+        RUNNING.lock().unwrap().push(self.waker());
+        while self.poll() {
+            #[cfg(feature = "systemd")]
+            let idle = {
+                notifier.watchdog();
+                notifier.next_watchdog().map_or(IDLE_WAIT, |at| {
+                    at.saturating_duration_since(Instant::now()).min(IDLE_WAIT)
+                })
+            };
+            #[cfg(not(feature = "systemd"))]
+            let idle = IDLE_WAIT;
+
+            self.wait(idle);
+        }
+
+        #[cfg(feature = "systemd")]
+        notifier.stopping();
+
+        if let Err(e) = self.shutdown() {
+            eprintln!("failed to stop cleanly: {}", e);
+        }
+    }
+
+    /// The channels saved as the node last stopped, which it resumes at its first poll.
+    pub fn suspended(&self) -> &[state::ChannelState] {
+        &self.suspended
+    }
+
+    /// Poll each of the node's parts once, returning false once the node has stopped.
+    pub fn poll(&mut self) -> bool {
+        if let Some(chan_manager) = self.chan_manager.as_mut() {
+            for state in self.suspended.drain(..) {
+                let address = state.address();
+                match chan_manager.resume(state) {
+                    Ok(()) => println!("Resumed channel {}", address),
+                    Err(e) => eprintln!("failed to resume channel {}: {:?}", address, e),
+                }
+            }
+        }
+        let running = !STOP.load(Ordering::SeqCst)
+            && self.router.poll()
+            && self.dispatch.as_mut().map(Dispatch::poll).unwrap_or(true)
+            // a full mailbox set to block holds back the network until its worker catches up
+            && (self.workers.iter().any(Worker::blocking) || self.transport.poll())
+            && self.workers.iter_mut().all(Worker::poll)
+            && self
+                .chan_manager
+                .as_mut()
+                .map(|chan_manager| chan_manager.poll().expect("channel manager poll failure"))
+                .unwrap_or(true);
+        if !running {
+            return false;
+        }
+
+        // a stopped worker, e.g. a session its factory stopped, is done with
+        self.workers.retain(|w| !w.stopped());
+        if let Some(rx) = &self.spawned_rx {
+            for mut worker in rx.try_iter() {
+                worker.set_dead_letters(self.dead_letter_tx.clone());
+                self.workers.push(worker);
+            }
+        }
+        for letter in self.dead_letter_rx.try_iter() {
+            if self.dead_letters.len() >= DEAD_LETTERS {
+                self.dead_letters.pop_front();
+            }
+            self.dead_letters.push_back(letter);
+        }
+        self.poll_control();
+        true
+    }
+
+    /// Block until the node has work, i.e. its router is sent a command or woken by another
+    /// thread, or one of its workers' ticks or restarts or the transport's paced sends is due,
+    /// waiting at most `timeout`. A cooperative node isn't woken as datagrams arrive, so it
+    /// returns at once, and the application idles between polls as its platform allows.
+    pub fn wait(&mut self, timeout: Duration) {
+        if self.cooperative {
+            return;
+        }
+        let now = Instant::now();
+        let timeout = match self.next_deadline() {
+            Some(at) => at.saturating_duration_since(now).min(timeout),
+            None => timeout,
+        };
+        if timeout > Duration::from_secs(0) {
+            self.router.wait(timeout);
+        }
+    }
+
+    // When the node next has work it won't be woken for, if ever.
+    fn next_deadline(&mut self) -> Option<Instant> {
+        self.workers
+            .iter_mut()
+            .filter_map(Worker::next_deadline)
+            .chain(self.transport.next_deadline())
+            .chain(self.chan_manager.as_ref().and_then(|c| c.next_expiry()))
+            .min()
+    }
+
+    /// Once the node has stopped polling, stop its parts, let its workers deliver anything they
+    /// have buffered, and join its component threads, giving them the timeout of a `StopHandle`
+    /// or 5 seconds. Returns the errors of those which failed to stop.
+    pub fn shutdown(&mut self) -> Result<(), String> {
+        let timeout = self.stop_timeout.lock().unwrap().unwrap_or(STOP_TIMEOUT);
+        let result = self.shutdown_within(timeout);
+        for tx in self.stop_reports.drain(..) {
+            let _ = tx.send(result.clone());
+        }
+        result
+    }
+
+    fn shutdown_within(&mut self, timeout: Duration) -> Result<(), String> {
+        let deadline = Instant::now() + timeout;
+        let mut errors = vec![];
+
+        // save the established channels before the stop reaches the channel manager
+        if let (Some(chan_manager), Some(path)) =
+            (self.chan_manager.as_mut(), self.config.channel_state_file())
+        {
+            let states = chan_manager.suspend();
+            match suspend::save(&path, &states) {
+                Ok(()) => println!("Saved {} channels", states.len()),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        // close the channels left while the router still runs, so that the closes sent to their
+        // other ends and workers are routed ahead of the stop
+        if let Some(chan_manager) = self.chan_manager.as_mut() {
+            let _ = self
+                .channel_tx
+                .send(OckamCommand::Channel(ChannelCommand::Stop));
+            if let Err(e) = chan_manager.poll() {
+                errors.push(format!("channel manager failed to stop: {:?}", e));
+            }
+        }
+
+        // the router passes the stop on to the transport, the channel manager and the workers'
+        // handler; a node stopped through its router has them stopped twice, which is harmless
+        let _ = self
+            .router_tx
+            .send(OckamCommand::Router(RouterCommand::Stop));
+        self.router.poll();
+        if let Some(dispatch) = self.dispatch.as_mut() {
+            dispatch.poll();
+        }
+        self.transport.poll();
+        if let Some(chan_manager) = self.chan_manager.as_mut() {
+            if let Err(e) = chan_manager.poll() {
+                errors.push(format!("channel manager failed to stop: {:?}", e));
+            }
+        }
+        for worker in self.workers.iter_mut() {
+            if let Err(e) = worker.shutdown() {
+                errors.push(e);
+            }
+        }
+
+        // a thread can't be joined with a timeout, so each is joined on a thread of its own which
+        // reports back, and those which haven't by the deadline are left to finish by themselves
+        let (joined_tx, joined_rx) = mpsc::channel();
+        let names: Vec<String> = self
+            .threads
+            .drain(..)
+            .enumerate()
+            .map(|(i, (name, thread))| {
+                let joined_tx = joined_tx.clone();
+                thread::spawn(move || {
+                    let _ = joined_tx.send((i, thread.join().is_ok()));
+                });
+                name
+            })
+            .collect();
+        let mut joined = vec![None; names.len()];
+        for _ in 0..names.len() {
+            match joined_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((i, ok)) => joined[i] = Some(ok),
+                Err(_) => break,
+            }
+        }
+        for (name, joined) in names.iter().zip(joined) {
+            match joined {
+                Some(true) => {}
+                Some(false) => errors.push(format!("{} panicked", name)),
+                None => errors.push(format!("{} didn't stop within {:?}", name, timeout)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    fn poll_control(&mut self) {
+        let requests: Vec<ControlRequest> = match &self.control_rx {
+            Some(rx) => rx.try_iter().collect(),
+            None => return,
+        };
+        for request in requests {
+            let reply = self.handle_control(request.command);
+            // the client may have gone away already
+            let _ = request.reply.send(reply);
+        }
+    }
+
+    fn channels(&self) -> Vec<ChannelInfo> {
+        self.chan_manager
+            .as_ref()
+            .map_or(vec![], |chan_manager| chan_manager.list_channels())
+    }
+
+    // A channel as the JSON object of `list-channels` and `show-channel`.
+    fn channel_json(&self, c: &ChannelInfo) -> String {
+        let route: Vec<String> = c
+            .route
+            .addresses
+            .iter()
+            .map(|a| a.address.as_string())
+            .collect();
+        let role = match c.role {
+            ockam_channel::protocol::Role::Initiator => "initiator",
+            ockam_channel::protocol::Role::Responder => "responder",
+        };
+        let stats = self
+            .chan_manager
+            .as_ref()
+            .and_then(|chan_manager| chan_manager.stats(&c.address))
+            .unwrap_or_default();
+        // the next sequence number due on each stream, by stream id
+        let next_received: Vec<String> = c
+            .next_received
+            .iter()
+            .map(|(stream, next)| format!(r#""{}":{}"#, stream, next))
+            .collect();
+        let next_received = format!("{{{}}}", next_received.join(","));
+        format!(
+            r#"{{"address":{},"role":{},"established":{},"established_at_ms":{},"route":{},"remote_public_key":{},"send_nonce":{},"recv_nonce":{},"next_received":{},"held":{},"compress":{},"reliable":{},"unacked":{},"reordered":{},"late":{},"duplicate":{},"lost":{},"incomplete":{},"stats":{}}}"#,
+            control::string(&c.address),
+            control::string(role),
+            c.established,
+            c.established_at
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or("null".into(), |d| d.as_millis().to_string()),
+            control::string(&route.join(",")),
+            c.remote_public_key
+                .as_ref()
+                .map(|k| control::string(&hex::encode(k)))
+                .unwrap_or_else(|| "null".into()),
+            c.send_nonce,
+            c.recv_nonce,
+            next_received,
+            c.held,
+            c.compress,
+            c.reliable,
+            c.unacked,
+            c.reorder.reordered,
+            c.reorder.late,
+            c.reorder.duplicate,
+            c.reorder.lost,
+            c.incomplete,
+            channel_stats(&stats)
+        )
+    }
+
+    fn control_worker(
+        &mut self,
+        address: &str,
+        f: fn(&mut Worker) -> Result<(), String>,
+    ) -> String {
+        let worker = self
+            .workers
+            .iter_mut()
+            .find(|w| w.address().address.as_string() == address);
+        match worker.map(f) {
+            Some(Ok(())) => control::ok(&[]),
+            Some(Err(e)) => control::error(&e),
+            None => control::error(&format!("no worker with address {}", address)),
+        }
+    }
+
+    fn handle_control(&mut self, command: ControlCommand) -> String {
+        match command {
+            ControlCommand::ListChannels => {
+                let channels: Vec<String> = self
+                    .channels()
+                    .iter()
+                    .map(|c| self.channel_json(c))
+                    .collect();
+                control::ok(&[("channels", format!("[{}]", channels.join(",")))])
+            }
+            ControlCommand::ShowChannel(address) => {
+                let info = self
+                    .chan_manager
+                    .as_ref()
+                    .and_then(|chan_manager| chan_manager.channel_info(&address));
+                match info {
+                    Some(c) => control::ok(&[("channel", self.channel_json(&c))]),
+                    None => control::error(&format!("no channel with address {}", address)),
                 }
             }
-            None => {
-                while self.router.poll()
-                    && self.transport.poll()
-                    && self
-                        .chan_manager
-                        .poll()
-                        .expect("channel manager poll failure")
-                {
-                    thread::sleep(time::Duration::from_millis(1));
+            ControlCommand::ShowIdentity => match &self.identity {
+                Some(identity) => control::ok(&[
+                    ("identity", control::string(&identity.id().to_string())),
+                    (
+                        "identity_name",
+                        control::string(&self.config.identity_name()),
+                    ),
+                    (
+                        "public_key",
+                        control::string(&hex::encode(identity.public_key())),
+                    ),
+                ]),
+                None => control::error("this node has no static identity key"),
+            },
+            ControlCommand::CloseChannel(address) => {
+                let closed = self
+                    .chan_manager
+                    .as_mut()
+                    .map(|chan_manager| chan_manager.close_channel(&address))
+                    .unwrap_or(false);
+                if closed {
+                    control::ok(&[])
+                } else {
+                    control::error(&format!("no channel with address {}", address))
                 }
             }
+            ControlCommand::Stats => {
+                let channels = self.channels();
+                let established = channels.iter().filter(|c| c.established).count();
+                control::ok(&[
+                    ("uptime_secs", self.started.elapsed().as_secs().to_string()),
+                    ("messages", stats::messages().to_string()),
+                    ("bytes", stats::bytes().to_string()),
+                    ("channels", channels.len().to_string()),
+                    ("established_channels", established.to_string()),
+                    (
+                        "rejected_handshakes",
+                        self.chan_manager
+                            .as_ref()
+                            .map_or(0, ChannelManager::rejected_handshakes)
+                            .to_string(),
+                    ),
+                ])
+            }
+            ControlCommand::ListWorkers => {
+                let workers: Vec<String> = self
+                    .workers
+                    .iter()
+                    .map(|w| {
+                        format!(
+                            r#"{{"address":{},"status":{},"failures":{},"processed":{},"dropped":{},"rejected":{},"queued":{},"last_error":{}}}"#,
+                            control::string(&w.address().address.as_string()),
+                            control::string(w.status()),
+                            w.failures(),
+                            w.processed(),
+                            w.dropped(),
+                            w.rejected(),
+                            w.depth(),
+                            w.last_error().map_or("null".into(), control::string)
+                        )
+                    })
+                    .collect();
+                control::ok(&[("workers", format!("[{}]", workers.join(",")))])
+            }
+            ControlCommand::ListDeadLetters => {
+                let letters: Vec<String> = self
+                    .dead_letters
+                    .iter()
+                    .map(|l| {
+                        let (message_type, size) = match &l.message {
+                            Some(m) => (
+                                control::string(&format!("{:?}", m.message_type)),
+                                m.message_body.len().to_string(),
+                            ),
+                            None => ("null".into(), "null".into()),
+                        };
+                        format!(
+                            r#"{{"worker":{},"reason":{},"message_type":{},"size":{}}}"#,
+                            control::string(&l.worker),
+                            control::string(&l.reason),
+                            message_type,
+                            size
+                        )
+                    })
+                    .collect();
+                control::ok(&[("dead_letters", format!("[{}]", letters.join(",")))])
+            }
+            ControlCommand::StartWorker(address) => self.control_worker(&address, Worker::start),
+            ControlCommand::StopWorker(address) => self.control_worker(&address, Worker::stop),
+            ControlCommand::RestartWorker(address) => {
+                self.control_worker(&address, Worker::restart)
+            }
+            ControlCommand::ResolveService(name) => match names::resolve(&self.config, &name) {
+                Some(address) => control::ok(&[("address", control::string(&address))]),
+                None => control::error(&format!("no service named {}", name)),
+            },
+            ControlCommand::ReloadConfig => {
+                control::error("configuration reloads are handled by the control socket")
+            }
         }
     }
 }
 
-fn as_key_ctx(key_name: &str) -> Result<SecretKeyContext, String> {
-    if let Some(id) = key_name.strip_suffix(cli::FILENAME_KEY_SUFFIX) {
-        return Ok(SecretKeyContext::Memory(
-            id.parse().map_err(|_| format!("bad key name"))?,
-        ));
+/// Where a node built by a `NodeBuilder` is polled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThreadPolicy {
+    /// On a thread of its own, started by `NodeBuilder::start`.
+    Dedicated,
+    /// On the application's thread, which calls `NodeHandle::poll` in its own loop.
+    Caller,
+    /// On the application's thread, as with `Caller`, with no threads of the node's own: the
+    /// transport reads its socket and the workers are dispatched to as the node is polled, and
+    /// its channels are only used as queues, never waited on. A step towards running the node
+    /// where there are no threads, e.g. on an RTOS.
+    Cooperative,
+}
+
+type SendMakeHandler =
+    Box<dyn FnMut(&Config) -> Result<Box<dyn WorkerHandler>, String> + Send + 'static>;
+
+/// Composes a node from parts, for applications embedding Ockam rather than running `ockamd`:
+/// its vault, its transport, its workers, the channels it initiates and where it is polled.
+/// Anything not chosen here is taken from the `Config`, as for `Node::new`.
+pub struct NodeBuilder {
+    config: Config,
+    router: (Sender<OckamCommand>, Receiver<OckamCommand>),
+    vault: Option<Arc<Mutex<dyn DynVault + Send>>>,
+    local_host: Option<String>,
+    workers: Vec<(RouterAddress, SendMakeHandler)>,
+    channels: Vec<(Route, RouterAddress)>,
+    inbox: Option<RouterAddress>,
+    thread_policy: ThreadPolicy,
+}
+
+impl NodeBuilder {
+    pub fn new(config: Config) -> Self {
+        NodeBuilder {
+            config,
+            router: mpsc::channel(),
+            vault: None,
+            local_host: None,
+            workers: vec![],
+            channels: vec![],
+            inbox: None,
+            thread_policy: ThreadPolicy::Dedicated,
+        }
+    }
+
+    /// The sender for the router's commands, for the workers' handlers to send with, e.g. in a
+    /// `Replier`.
+    pub fn router_sender(&self) -> Sender<OckamCommand> {
+        self.router.0.clone()
+    }
+
+    /// Keep the node's identity and channel keys in `vault` rather than the one `--vault`
+    /// selects.
+    pub fn vault(mut self, vault: Arc<Mutex<dyn DynVault + Send>>) -> Self {
+        self.vault = Some(vault);
+        self
+    }
+
+    /// Bind the node's UDP transport to `local_host`, e.g. "127.0.0.1:4050", rather than
+    /// `--local-socket`.
+    pub fn udp_transport(mut self, local_host: &str) -> Self {
+        self.local_host = Some(local_host.to_string());
+        self
+    }
+
+    /// Run a worker at `addr`, with a handler from `make_handler`, called again on each restart.
+    pub fn worker(
+        mut self,
+        addr: RouterAddress,
+        make_handler: impl FnMut(&Config) -> Result<Box<dyn WorkerHandler>, String> + Send + 'static,
+    ) -> Self {
+        self.workers.push((addr, Box::new(make_handler)));
+        self
+    }
+
+    /// Initiate a secure channel along `route` once the node starts, announcing it to the
+    /// worker at `notify` as a message of type `None` from the channel's address.
+    pub fn channel(mut self, route: Route, notify: RouterAddress) -> Self {
+        self.channels.push((route, notify));
+        self
+    }
+
+    /// Pass the messages for the worker address `addr`, and the channels announced to it, to the
+    /// application through `NodeHandle::recv_timeout`.
+    pub fn inbox(mut self, addr: RouterAddress) -> Self {
+        self.inbox = Some(addr);
+        self
+    }
+
+    pub fn thread_policy(mut self, thread_policy: ThreadPolicy) -> Self {
+        self.thread_policy = thread_policy;
+        self
+    }
+
+    /// Create the node, and with `ThreadPolicy::Dedicated` start polling it, returning once it is
+    /// ready.
+    pub fn start(self) -> Result<NodeHandle, String> {
+        let (inbox_tx, inbox_rx) = mpsc::channel();
+        let inbox = self.inbox.as_ref().map(|_| inbox_rx);
+        match self.thread_policy {
+            ThreadPolicy::Caller | ThreadPolicy::Cooperative => {
+                let (node, router_tx) = self.assemble(inbox_tx)?;
+                Ok(NodeHandle {
+                    router_tx,
+                    channel_tx: node.channel_sender(),
+                    inbox,
+                    identity: node.identity.clone(),
+                    node: Some(node),
+                    stop_handle: None,
+                    thread: None,
+                    thread_alive: None,
+                })
+            }
+            ThreadPolicy::Dedicated => {
+                let (ready_tx, ready_rx) = mpsc::channel();
+                let (alive_tx, alive_rx) = mpsc::channel::<()>();
+                let thread = thread::Builder::new()
+                    .name("ockam-node".into())
+                    .spawn(move || {
+                        let _alive = alive_tx;
+                        match self.assemble(inbox_tx) {
+                            Ok((mut node, router_tx)) => {
+                                let ready = (
+                                    router_tx,
+                                    node.channel_sender(),
+                                    node.identity.clone(),
+                                    node.stop_handle(),
+                                );
+                                let _ = ready_tx.send(Ok(ready));
+                                node.run();
+                            }
+                            Err(e) => {
+                                let _ = ready_tx.send(Err(e));
+                            }
+                        }
+                    })
+                    .map_err(|e| format!("failed to start node thread: {}", e))?;
+                let (router_tx, channel_tx, identity, stop_handle) = ready_rx
+                    .recv()
+                    .map_err(|_| "node thread panicked".to_string())??;
+                Ok(NodeHandle {
+                    router_tx,
+                    channel_tx,
+                    inbox,
+                    identity,
+                    node: None,
+                    stop_handle: Some(stop_handle),
+                    thread: Some(thread),
+                    thread_alive: Some(alive_rx),
+                })
+            }
+        }
+    }
+
+    // Create the node on the thread which will poll it, with its workers and channels.
+    fn assemble(
+        self,
+        inbox_tx: Sender<OckamMessage>,
+    ) -> Result<(Node, Sender<OckamCommand>), String> {
+        let config = &self.config;
+        let local_host = self
+            .local_host
+            .unwrap_or_else(|| config.local_host().to_string());
+        let cooperative = self.thread_policy == ThreadPolicy::Cooperative;
+        let (mut node, router_tx) = Node::build(
+            &self.config,
+            self.router,
+            self.vault,
+            &local_host,
+            cooperative,
+        )?;
+
+        let mut workers = vec![];
+        for (addr, make_handler) in self.workers {
+            let make_handler: MakeHandler = make_handler;
+            workers.push(Worker::detached(
+                addr,
+                router_tx.clone(),
+                self.config.clone(),
+                make_handler,
+            )?);
+        }
+        if let Some(addr) = self.inbox {
+            let inbox = addr.clone();
+            let make_handler: MakeHandler = Box::new(
+                move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                    Ok(Box::new(Inbox(inbox.clone(), inbox_tx.clone())))
+                },
+            );
+            workers.push(Worker::detached(
+                addr,
+                router_tx.clone(),
+                self.config.clone(),
+                make_handler,
+            )?);
+        }
+
+        // as for a responder, the workers share the router's single worker handler, and the
+        // announcements of new channels go to all of them
+        if let Some(first) = workers.first().map(Worker::sender) {
+            let announcements =
+                RouterAddress::worker_router_address_from_str(CHANNEL_ZERO).unwrap();
+            let mut routes: Vec<_> = workers.iter().map(|w| (w.address(), w.sender())).collect();
+            routes.extend(workers.iter().map(|w| (announcements.clone(), w.sender())));
+            for worker in workers {
+                node.add_worker(worker);
+            }
+            let (_, added) = mpsc::channel();
+            if cooperative {
+                node.add_dispatch(Dispatch::new(&router_tx, routes, first, added));
+            } else {
+                node.add_thread(
+                    "worker dispatch",
+                    worker::dispatch(&router_tx, routes, first, added),
+                );
+            }
+        }
+
+        for (route, notify) in self.channels {
+            if node.chan_manager.is_none() {
+                return Err("a relay initiates no channels".into());
+            }
+            node.channel_tx
+                .send(OckamCommand::Channel(ChannelCommand::Initiate(
+                    route,
+                    notify.address,
+                    None,
+                    None,
+                )))
+                .map_err(|e| format!("failed to initiate channel: {}", e))?;
+        }
+        Ok((node, router_tx))
+    }
+}
+
+// Passes an embedding application's messages, and the channels announced to it and their
+// closing, to its handle.
+struct Inbox(RouterAddress, Sender<OckamMessage>);
+
+impl WorkerHandler for Inbox {
+    fn handle_message(&mut self, _config: &Config, msg: OckamMessage) -> Result<(), String> {
+        self.1
+            .send(msg)
+            .map_err(|_| "the application's node handle has gone".to_string())
+    }
+
+    fn on_channel_established(
+        &mut self,
+        config: &Config,
+        channel: &RouterAddress,
+        remote_public_key: &[u8],
+    ) -> Result<(), String> {
+        let announcement = OckamMessage {
+            onward_route: Route {
+                addresses: vec![self.0.clone()],
+            },
+            return_route: Route {
+                addresses: vec![channel.clone()],
+            },
+            message_type: MessageType::None,
+            message_body: remote_public_key.to_vec(),
+        };
+        self.handle_message(config, announcement)
+    }
+
+    fn on_channel_closed(
+        &mut self,
+        config: &Config,
+        channel: &RouterAddress,
+    ) -> Result<(), String> {
+        let closed = OckamMessage {
+            onward_route: Route {
+                addresses: vec![self.0.clone()],
+            },
+            return_route: Route {
+                addresses: vec![channel.clone()],
+            },
+            message_type: MessageType::Close,
+            message_body: vec![],
+        };
+        self.handle_message(config, closed)
     }
+}
 
-    Err("invalid key name format".into())
+/// The application's side of a node started by a `NodeBuilder`. Dropping it stops the node.
+pub struct NodeHandle {
+    router_tx: Sender<OckamCommand>,
+    // the channel manager's commands, unless the node is a relay
+    channel_tx: Option<Sender<OckamCommand>>,
+    inbox: Option<Receiver<OckamMessage>>,
+    identity: Option<Identity>,
+    // the node, when the application polls it
+    node: Option<Node>,
+    // stops the node on its own thread, when it has one
+    stop_handle: Option<StopHandle>,
+    // the node's thread, when it has one
+    thread: Option<JoinHandle<()>>,
+    // disconnected once the node's thread ends, as the thread holds its sender
+    thread_alive: Option<Receiver<()>>,
 }
 
-fn contains_key(v: &mut dyn DynVault, key_name: &str) -> bool {
-    if let Ok(ctx) = as_key_ctx(key_name) {
-        return v.secret_export(ctx).is_ok();
+impl NodeHandle {
+    /// Send `msg` along its onward route, e.g. over a channel or to one of the node's workers.
+    pub fn send(&self, msg: OckamMessage) -> Result<(), String> {
+        send_message(&self.router_tx, msg)
+    }
+
+    /// Initiate a secure channel along `route` while the node runs, announced to the worker at
+    /// `notify` as for `NodeBuilder::channel`.
+    pub fn initiate_channel(&self, route: Route, notify: RouterAddress) -> Result<(), String> {
+        initiate_channel(&self.router_tx, self.channel_tx.as_ref(), route, notify)
+    }
+
+    /// The sender for the router's commands, e.g. for a `Requester` or `Poster`.
+    pub fn router_sender(&self) -> Sender<OckamCommand> {
+        self.router_tx.clone()
+    }
+
+    /// The next message for the inbox, waiting at most `timeout` for one.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<OckamMessage, String> {
+        let inbox = self.inbox.as_ref().ok_or("the node has no inbox")?;
+        inbox.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => "timed out waiting for a message".to_string(),
+            mpsc::RecvTimeoutError::Disconnected => "the node has stopped".to_string(),
+        })
+    }
+
+    /// The node's identity, whose current key is the static key of its channels, if it has one.
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// Poll a node with `ThreadPolicy::Caller` or `Cooperative` once, returning false once it has
+    /// stopped. A node with a thread of its own is polled there, so this only tells whether it
+    /// is still running.
+    pub fn poll(&mut self) -> bool {
+        match (&mut self.node, &self.thread_alive) {
+            (Some(node), _) => node.poll(),
+            (None, Some(alive)) => alive.try_recv() != Err(TryRecvError::Disconnected),
+            (None, None) => false,
+        }
+    }
+
+    /// Block until a node with `ThreadPolicy::Caller` has work to poll, as `Node::wait` does,
+    /// waiting at most `timeout`. A node with a thread of its own waits there.
+    pub fn wait(&mut self, timeout: Duration) {
+        if let Some(node) = &mut self.node {
+            node.wait(timeout);
+        }
+    }
+
+    /// Stop the node as `StopHandle::stop` does, giving its threads `timeout` to finish.
+    pub fn stop(mut self, timeout: Duration) -> Result<(), String> {
+        self.stop_within(timeout)
+    }
+
+    /// A handle to a node with `ThreadPolicy::Dedicated` which can be shared between threads. A
+    /// node the application polls stays with its handle on the application's thread.
+    pub fn into_remote(mut self) -> Result<RemoteHandle, String> {
+        let (stop_handle, thread) = match (self.stop_handle.take(), self.thread.take()) {
+            (Some(stop_handle), Some(thread)) => (stop_handle, thread),
+            _ => return Err("the node is polled by the application".into()),
+        };
+        Ok(RemoteHandle {
+            router_tx: Mutex::new(self.router_tx.clone()),
+            channel_tx: Mutex::new(self.channel_tx.take()),
+            inbox: Mutex::new(self.inbox.take()),
+            identity: self.identity.take(),
+            running: Mutex::new(Some((stop_handle, thread))),
+        })
+    }
+
+    fn stop_within(&mut self, timeout: Duration) -> Result<(), String> {
+        let mut result = Ok(());
+        if let Some(mut node) = self.node.take() {
+            result = node.shutdown_within(timeout);
+        }
+        if let Some(stop_handle) = self.stop_handle.take() {
+            result = stop_handle.stop(timeout);
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                result = result.and(Err("node thread panicked".into()));
+            }
+        }
+        result
     }
+}
+
+impl Drop for NodeHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_within(STOP_TIMEOUT) {
+            eprintln!("failed to stop cleanly: {}", e);
+        }
+    }
+}
+
+/// The application's side of a node polled on a thread of its own, which unlike a `NodeHandle`
+/// can be shared between threads, e.g. to receive on one while sending from others. See
+/// `NodeHandle::into_remote`. Dropping it stops the node.
+pub struct RemoteHandle {
+    router_tx: Mutex<Sender<OckamCommand>>,
+    channel_tx: Mutex<Option<Sender<OckamCommand>>>,
+    inbox: Mutex<Option<Receiver<OckamMessage>>>,
+    identity: Option<Identity>,
+    // taken by the first stop
+    running: Mutex<Option<(StopHandle, JoinHandle<()>)>>,
+}
+
+impl RemoteHandle {
+    /// Send `msg` as `NodeHandle::send` does.
+    pub fn send(&self, msg: OckamMessage) -> Result<(), String> {
+        send_message(&self.router_tx.lock().unwrap(), msg)
+    }
+
+    /// Initiate a secure channel as `NodeHandle::initiate_channel` does.
+    pub fn initiate_channel(&self, route: Route, notify: RouterAddress) -> Result<(), String> {
+        let router_tx = self.router_tx.lock().unwrap().clone();
+        initiate_channel(
+            &router_tx,
+            self.channel_tx.lock().unwrap().as_ref(),
+            route,
+            notify,
+        )
+    }
+
+    /// The next message for the inbox, or None if none arrives within `timeout`. Receivers on
+    /// several threads take turns.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<OckamMessage>, String> {
+        let inbox = self.inbox.lock().unwrap();
+        let inbox = inbox.as_ref().ok_or("the node has no inbox")?;
+        match inbox.recv_timeout(timeout) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err("the node has stopped".into()),
+        }
+    }
+
+    /// The node's identity, whose current key is the static key of its channels, if it has one.
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// Stop the node as `NodeHandle::stop` does. A receiver waiting meanwhile is told the node
+    /// has stopped, and the node is only stopped once.
+    pub fn stop(&self, timeout: Duration) -> Result<(), String> {
+        let (stop_handle, thread) = self
+            .running
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or("the node has stopped")?;
+        let result = stop_handle.stop(timeout);
+        match thread.join() {
+            Ok(()) => result,
+            Err(_) => result.and(Err("node thread panicked".into())),
+        }
+    }
+}
+
+impl Drop for RemoteHandle {
+    fn drop(&mut self) {
+        if self.running.lock().unwrap().is_none() {
+            return;
+        }
+        if let Err(e) = self.stop(STOP_TIMEOUT) {
+            eprintln!("failed to stop cleanly: {}", e);
+        }
+    }
+}
+
+// A channel's statistics as the JSON object of `list-channels`.
+fn channel_stats(stats: &ChannelStats) -> String {
+    format!(
+        r#"{{"messages_sent":{},"messages_received":{},"bytes_encrypted":{},"bytes_decrypted":{},"rekeys":{},"handshake_ms":{},"held_dropped":{},"retransmitted":{}}}"#,
+        stats.messages_sent,
+        stats.messages_received,
+        stats.bytes_encrypted,
+        stats.bytes_decrypted,
+        stats.rekeys,
+        stats
+            .handshake_duration
+            .map_or("null".into(), |d| d.as_millis().to_string()),
+        stats.held_dropped,
+        stats.retransmitted
+    )
+}
+
+fn send_message(router_tx: &Sender<OckamCommand>, msg: OckamMessage) -> Result<(), String> {
+    router_tx
+        .send(OckamCommand::Router(RouterCommand::SendMessage(msg)))
+        .map_err(|_| "the node has stopped".to_string())
+}
+
+// The channel manager is polled by the node, so it is woken to start the handshake.
+fn initiate_channel(
+    router_tx: &Sender<OckamCommand>,
+    channel_tx: Option<&Sender<OckamCommand>>,
+    route: Route,
+    notify: RouterAddress,
+) -> Result<(), String> {
+    channel_tx
+        .ok_or("a relay initiates no channels")?
+        .send(OckamCommand::Channel(ChannelCommand::Initiate(
+            route,
+            notify.address,
+            None,
+            None,
+        )))
+        .map_err(|_| "the node has stopped".to_string())?;
+    Waker::router(router_tx.clone()).wake();
+    Ok(())
+}
+
+/// Stops a running node from another thread. See `Node::stop_handle`.
+pub struct StopHandle {
+    router_tx: Sender<OckamCommand>,
+    timeout: Arc<Mutex<Option<Duration>>>,
+    done: Receiver<Result<(), String>>,
+}
+
+impl StopHandle {
+    /// Stop the node through its router, which passes the stop on to the channel manager, the
+    /// transport and the workers, and wait until the node has shut its workers down and joined
+    /// its component threads, giving the threads `timeout` to finish. Returns the errors of
+    /// those which failed to stop, or didn't in time.
+    pub fn stop(self, timeout: Duration) -> Result<(), String> {
+        *self.timeout.lock().unwrap() = Some(timeout);
+        let _ = self
+            .router_tx
+            .send(OckamCommand::Router(RouterCommand::Stop));
+        self.done
+            .recv()
+            .map_err(|_| "the node stopped without reporting".to_string())?
+    }
+}
+
+#[test]
+fn test_node_builder() {
+    use crate::worker::Replier;
+
+    let config: Config = crate::cli::Args::load(
+        vec!["ockamd", "--role", "relay"]
+            .into_iter()
+            .map(std::ffi::OsString::from),
+    )
+    .unwrap()
+    .into();
+    let echo = RouterAddress::worker_router_address_from_str("0ec40ec4").unwrap();
+    let app = RouterAddress::worker_router_address_from_str("00a44a00").unwrap();
+    let builder = |thread_policy| {
+        let builder = NodeBuilder::new(config.clone()).udp_transport("127.0.0.1:0");
+        let replier = Replier::new(builder.router_sender(), echo.clone());
+        builder
+            .worker(echo.clone(), move |_: &Config| {
+                let replier = replier.clone();
+                Ok(Box::new(move |_: &Config, msg: OckamMessage| {
+                    replier.reply(&msg, msg.message_body.to_ascii_uppercase())
+                }))
+            })
+            .inbox(app.clone())
+            .thread_policy(thread_policy)
+    };
+    let request = OckamMessage {
+        onward_route: Route {
+            addresses: vec![echo.clone()],
+        },
+        return_route: Route {
+            addresses: vec![app.clone()],
+        },
+        message_type: MessageType::Payload,
+        message_body: b"hello".to_vec(),
+    };
+
+    // the application polls the node itself, which is woken as the message is dispatched to
+    // each worker rather than waiting out the timeout
+    let mut handle = builder(ThreadPolicy::Caller).start().unwrap();
+    handle.send(request.clone()).unwrap();
+    let started = Instant::now();
+    let mut reply = None;
+    for _ in 0..1000 {
+        assert!(handle.poll());
+        if let Ok(msg) = handle.recv_timeout(Duration::from_millis(0)) {
+            reply = Some(msg);
+            break;
+        }
+        handle.wait(Duration::from_secs(5));
+    }
+    assert_eq!(reply.unwrap().message_body, b"HELLO");
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(handle.identity().is_none());
+    handle.stop(Duration::from_secs(1)).unwrap();
+
+    // the node polls on its own thread
+    let mut handle = builder(ThreadPolicy::Dedicated).start().unwrap();
+    handle.send(request).unwrap();
+    let reply = handle.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(reply.message_body, b"HELLO");
+    assert_eq!(reply.return_route.addresses, vec![echo.clone()]);
+    assert!(handle.poll());
+    handle.stop(Duration::from_secs(1)).unwrap();
+
+    // a relay has no channels to initiate
+    let route = Route {
+        addresses: vec![RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap()],
+    };
+    assert!(builder(ThreadPolicy::Caller)
+        .channel(route, app)
+        .start()
+        .is_err());
+}
+
+#[test]
+fn test_node_remote() {
+    let config: Config = crate::cli::Args::load(
+        vec!["ockamd", "--role", "relay"]
+            .into_iter()
+            .map(std::ffi::OsString::from),
+    )
+    .unwrap()
+    .into();
+    let app = RouterAddress::worker_router_address_from_str("00a44a00").unwrap();
+    let builder = |thread_policy| {
+        NodeBuilder::new(config.clone())
+            .udp_transport("127.0.0.1:0")
+            .inbox(app.clone())
+            .thread_policy(thread_policy)
+    };
+
+    // a node the application polls can't be shared
+    let handle = builder(ThreadPolicy::Caller).start().unwrap();
+    assert!(handle.into_remote().is_err());
+
+    // one thread waits for the message another sends to the inbox
+    let remote = Arc::new(
+        builder(ThreadPolicy::Dedicated)
+            .start()
+            .unwrap()
+            .into_remote()
+            .unwrap(),
+    );
+    assert!(remote
+        .recv_timeout(Duration::from_millis(0))
+        .unwrap()
+        .is_none());
+    let receiver = {
+        let remote = remote.clone();
+        thread::spawn(move || remote.recv_timeout(Duration::from_secs(5)))
+    };
+    let msg = OckamMessage {
+        onward_route: Route {
+            addresses: vec![app.clone()],
+        },
+        return_route: Route {
+            addresses: vec![app.clone()],
+        },
+        message_type: MessageType::Payload,
+        message_body: b"hello".to_vec(),
+    };
+    remote.send(msg).unwrap();
+    let received = receiver.join().unwrap().unwrap().unwrap();
+    assert_eq!(received.message_body, b"hello");
+
+    // a relay has no channels to initiate
+    let route = Route {
+        addresses: vec![RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap()],
+    };
+    assert!(remote.initiate_channel(route, app).is_err());
+
+    // the node stops once, after which receivers are told so
+    remote.stop(Duration::from_secs(5)).unwrap();
+    assert!(remote.stop(Duration::from_secs(5)).is_err());
+    assert!(remote.recv_timeout(Duration::from_millis(0)).is_err());
+}
+
+#[test]
+fn test_node_stop() {
+    let config: Config = crate::cli::Args::load(
+        vec!["ockamd", "--role", "relay"]
+            .into_iter()
+            .map(std::ffi::OsString::from),
+    )
+    .unwrap()
+    .into();
+    let worker = RouterAddress::worker_router_address_from_str("00000001").unwrap();
+    let builder = |thread_policy| {
+        NodeBuilder::new(config.clone())
+            .udp_transport("127.0.0.1:0")
+            .worker(worker.clone(), |_: &Config| {
+                Ok(Box::new(|_: &Config, _: OckamMessage| Ok(())))
+            })
+            .thread_policy(thread_policy)
+    };
+
+    // the stop reaches the worker dispatch through the router, which ends its thread
+    let handle = builder(ThreadPolicy::Dedicated).start().unwrap();
+    handle.stop(Duration::from_secs(5)).unwrap();
+
+    // a thread which doesn't stop in time is reported, as is one which panicked
+    let mut handle = builder(ThreadPolicy::Caller).start().unwrap();
+    assert!(handle.poll());
+    let node = handle.node.as_mut().unwrap();
+    node.add_thread(
+        "stuck",
+        thread::spawn(|| thread::sleep(Duration::from_secs(2))),
+    );
+    node.add_thread("broken", thread::spawn(|| panic!("broken")));
+    // long enough for the panic to unwind, even with a backtrace to print
+    let errors = handle.stop(Duration::from_millis(500)).unwrap_err();
+    assert_eq!(errors, "stuck didn't stop within 500ms; broken panicked");
+}
+
+#[test]
+fn test_node_cooperative() {
+    use crate::worker::Replier;
+
+    let config: Config = crate::cli::Args::load(
+        vec!["ockamd", "--role", "relay"]
+            .into_iter()
+            .map(std::ffi::OsString::from),
+    )
+    .unwrap()
+    .into();
+    let echo = RouterAddress::worker_router_address_from_str("0ec40ec4").unwrap();
+    let app = RouterAddress::worker_router_address_from_str("00a44a00").unwrap();
+
+    // a node with an echo service, and another whose application sends to it over UDP
+    let service = NodeBuilder::new(config.clone()).udp_transport("127.0.0.1:47061");
+    let replier = Replier::new(service.router_sender(), echo.clone());
+    let mut service = service
+        .worker(echo.clone(), move |_: &Config| {
+            let replier = replier.clone();
+            Ok(Box::new(move |_: &Config, msg: OckamMessage| {
+                replier.reply(&msg, msg.message_body.to_ascii_uppercase())
+            }))
+        })
+        .thread_policy(ThreadPolicy::Cooperative)
+        .start()
+        .unwrap();
+    let mut client = NodeBuilder::new(config)
+        .udp_transport("127.0.0.1:47062")
+        .inbox(app.clone())
+        .thread_policy(ThreadPolicy::Cooperative)
+        .start()
+        .unwrap();
+    for handle in [&service, &client].iter() {
+        let node = handle.node.as_ref().unwrap();
+        assert!(node.threads.is_empty());
+        assert!(node.dispatch.is_some());
+    }
+
+    // both are polled on this thread, which is all that moves the message along
+    client
+        .send(OckamMessage {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::udp_router_address_from_str("127.0.0.1:47061").unwrap(),
+                    echo,
+                ],
+            },
+            return_route: Route {
+                addresses: vec![app],
+            },
+            message_type: MessageType::Payload,
+            message_body: b"hello".to_vec(),
+        })
+        .unwrap();
+    let mut reply = None;
+    for _ in 0..5000 {
+        assert!(service.poll() && client.poll());
+        if let Ok(msg) = client.recv_timeout(Duration::from_millis(0)) {
+            reply = Some(msg);
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(reply.unwrap().message_body, b"HELLO");
+    service.stop(Duration::from_secs(1)).unwrap();
+    client.stop(Duration::from_secs(1)).unwrap();
+}
 
-    false
+#[test]
+fn test_channel_stats_json() {
+    let mut stats = ChannelStats::default();
+    assert_eq!(
+        channel_stats(&stats),
+        r#"{"messages_sent":0,"messages_received":0,"bytes_encrypted":0,"bytes_decrypted":0,"rekeys":0,"handshake_ms":null,"held_dropped":0,"retransmitted":0}"#
+    );
+    stats.messages_sent = 2;
+    stats.bytes_encrypted = 120;
+    stats.rekeys = 1;
+    stats.handshake_duration = Some(Duration::from_millis(35));
+    assert_eq!(
+        channel_stats(&stats),
+        r#"{"messages_sent":2,"messages_received":0,"bytes_encrypted":120,"bytes_decrypted":0,"rekeys":1,"handshake_ms":35,"held_dropped":0,"retransmitted":0}"#
+    );
 }
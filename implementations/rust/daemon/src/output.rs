@@ -0,0 +1,83 @@
+use crate::clock::Utc;
+use crate::config::Encoding;
+use crate::control;
+
+use ockam_message::message::Message as OckamMessage;
+
+/// Encode a received payload for writing to stdout. Every encoding but `Raw` ends each payload
+/// with a newline, so binary data can't garble a terminal or run into the next payload.
+pub fn encode(encoding: Encoding, msg: &OckamMessage) -> Vec<u8> {
+    let body = &msg.message_body;
+    let line = match encoding {
+        Encoding::Raw => return body.clone(),
+        Encoding::Hex => hex::encode(body),
+        Encoding::Base64 => base64(body),
+        Encoding::Json => {
+            let address = |route: &ockam_message::message::Route| {
+                route
+                    .addresses
+                    .first()
+                    .map(|a| control::string(&a.address.as_string()))
+                    .unwrap_or_else(|| "null".into())
+            };
+            format!(
+                r#"{{"time":{},"service":{},"channel":{},"bytes":{},"data":{}}}"#,
+                control::string(&Utc::now().rfc3339()),
+                address(&msg.onward_route),
+                address(&msg.return_route),
+                body.len(),
+                control::string(&base64(body))
+            )
+        }
+    };
+    let mut out = line.into_bytes();
+    out.push(b'\n');
+    out
+}
+
+/// Standard base64, with padding.
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_output_encode() {
+    use ockam_message::message::{MessageType, Route, RouterAddress};
+
+    let msg = OckamMessage {
+        onward_route: Route {
+            addresses: vec![RouterAddress::worker_router_address_from_str("01242020").unwrap()],
+        },
+        return_route: Route { addresses: vec![] },
+        message_type: MessageType::Payload,
+        message_body: b"\x00ab".to_vec(),
+    };
+
+    assert_eq!(encode(Encoding::Raw, &msg), b"\x00ab");
+    assert_eq!(encode(Encoding::Hex, &msg), b"006162\n");
+    assert_eq!(encode(Encoding::Base64, &msg), b"AGFi\n");
+    assert_eq!(base64(b"ab"), "YWI=");
+
+    let json = String::from_utf8(encode(Encoding::Json, &msg)).unwrap();
+    assert!(json.starts_with(r#"{"time":""#));
+    assert!(json.ends_with(
+        r#"","service":"01242020","channel":null,"bytes":3,"data":"AGFi"}
+"#
+    ));
+}
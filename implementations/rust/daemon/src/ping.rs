@@ -0,0 +1,262 @@
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cli::{Args, OutputKind, DEFAULT_VAULT_PATH, FILENAME_KEY_DEFAULT};
+use crate::config::{Config, ECHO_SERVICE_ADDRESS};
+use crate::node::{self, Node};
+use crate::request::Requester;
+
+use ockam_channel::protocol::channel_error_code;
+use ockam_message::message::{AddressType, MessageType, Route, RouterAddress};
+use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand, WorkerCommand};
+use structopt::StructOpt;
+
+/// The worker address echoes are returned to, "ping" in ASCII.
+const PING_ADDRESS: &str = "70696e67";
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "ockamd ping",
+    about = "Open a secure channel to a responder running with `--echo`, and measure the round-trip time of payloads sent over it."
+)]
+pub struct PingArgs {
+    #[structopt(
+        help = "Route to the responder, e.g. udp://127.0.0.1:4050 or several comma-separated hops"
+    )]
+    route: OutputKind,
+
+    #[structopt(short, long, default_value = "4", help = "Number of requests to send")]
+    count: u32,
+
+    #[structopt(long, default_value = "1000", help = "Milliseconds between payloads")]
+    interval_ms: u64,
+
+    #[structopt(
+        long,
+        default_value = "5000",
+        help = "Milliseconds to wait for the channel, or for each echo"
+    )]
+    timeout_ms: u64,
+
+    #[structopt(long, help = "Expected public key of the responder's identity")]
+    service_public_key: Option<String>,
+
+    #[structopt(
+        long,
+        default_value = "0.0.0.0:0",
+        help = "Local node address and port to bind"
+    )]
+    local_socket: SocketAddr,
+
+    #[structopt(
+        parse(from_os_str),
+        long,
+        default_value = DEFAULT_VAULT_PATH,
+        help = "Filepath on disk of the filesystem vault, for an existing identity key"
+    )]
+    vault_path: PathBuf,
+
+    #[structopt(
+        long,
+        default_value = FILENAME_KEY_DEFAULT,
+        help = "Name of the identity key in the vault"
+    )]
+    identity_name: String,
+}
+
+/// Handle `ockamd ping ROUTE [OPTIONS]`.
+pub fn main(args: &[OsString]) -> Result<(), String> {
+    // parse as if `ping` were the program name, so that usage reads `ockamd ping ...`
+    let args = PingArgs::from_iter(&args[1..]);
+    let route = match args.route.clone() {
+        OutputKind::Channel(route) => route,
+        OutputKind::Stdout => return Err("ping needs a route to a responder".into()),
+    };
+
+    let config: Config = Args::initiator(
+        route.clone(),
+        ECHO_SERVICE_ADDRESS,
+        args.service_public_key.clone(),
+        args.local_socket,
+        args.vault_path.clone(),
+        args.identity_name.clone(),
+    )
+    .into();
+    let (node, router_tx) = Node::new(&config);
+
+    // this node has no other workers, so echoes and channel announcements all arrive here
+    let (tx, rx) = mpsc::channel();
+    router_tx
+        .send(OckamCommand::Router(RouterCommand::Register(
+            AddressType::Worker,
+            tx,
+        )))
+        .map_err(|e| format!("failed to register ping worker: {}", e))?;
+
+    let ping_addr = RouterAddress::worker_router_address_from_str(PING_ADDRESS).unwrap();
+    node.channel_tx
+        .send(OckamCommand::Channel(ChannelCommand::Initiate(
+            route,
+            ping_addr.address.clone(),
+            None,
+            None,
+        )))
+        .map_err(|e| format!("failed to initiate channel: {}", e))?;
+
+    let pinger = thread::spawn(move || {
+        let result = ping(&args, router_tx, rx, ping_addr);
+        node::stop();
+        result
+    });
+    node.run();
+
+    pinger
+        .join()
+        .map_err(|_| "ping thread panicked".to_string())?
+}
+
+fn ping(
+    args: &PingArgs,
+    router_tx: Sender<OckamCommand>,
+    rx: Receiver<OckamCommand>,
+    ping_addr: RouterAddress,
+) -> Result<(), String> {
+    let timeout = Duration::from_millis(args.timeout_ms);
+
+    // the channel manager announces the completed key exchange with the remote public key
+    let started = Instant::now();
+    let channel = loop {
+        let remaining = timeout
+            .checked_sub(started.elapsed())
+            .ok_or("timed out waiting for the secure channel")?;
+        match rx.recv_timeout(remaining) {
+            Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)))
+                if matches!(msg.message_type, MessageType::None) =>
+            {
+                let public_key = hex::encode(&msg.message_body);
+                println!(
+                    "Channel established in {:.3} ms, remote public key: {}",
+                    millis(started.elapsed()),
+                    public_key
+                );
+                if let Some(expected) = &args.service_public_key {
+                    if *expected != public_key {
+                        return Err("remote public key doesn't match expected".into());
+                    }
+                }
+                break msg.return_route.addresses[0].clone();
+            }
+            Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)))
+                if matches!(msg.message_type, MessageType::ChannelError) =>
+            {
+                return Err(format!(
+                    "key exchange failed with error {:#010x}",
+                    channel_error_code(&msg).unwrap_or_default()
+                ));
+            }
+            Ok(_) => {}
+            Err(_) => return Err("timed out waiting for the secure channel".into()),
+        }
+    };
+
+    // the echo service returns each body unchanged, correlation ID included
+    let mut requester = Requester::new(router_tx, ping_addr, rx);
+    let echo_route = Route {
+        addresses: vec![
+            channel,
+            RouterAddress::worker_router_address_from_str(ECHO_SERVICE_ADDRESS).unwrap(),
+        ],
+    };
+    let mut rtts = Rtts::default();
+    for seq in 0..args.count {
+        if seq > 0 {
+            thread::sleep(Duration::from_millis(args.interval_ms));
+        }
+
+        let sent = Instant::now();
+        rtts.sent += 1;
+        match requester.send_request(echo_route.clone(), &[], timeout) {
+            Ok(_) => {
+                let rtt = sent.elapsed();
+                println!("seq={} time={:.3} ms", seq, millis(rtt));
+                rtts.record(rtt);
+            }
+            Err(e) => println!("seq={} {}", seq, e),
+        }
+    }
+
+    println!("{}", rtts);
+    if rtts.received() == 0 && args.count > 0 {
+        return Err("no echoes received".into());
+    }
+    Ok(())
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+/// The round-trip times of the echoed payloads.
+#[derive(Default)]
+struct Rtts {
+    sent: u32,
+    times: Vec<Duration>,
+}
+
+impl Rtts {
+    fn record(&mut self, rtt: Duration) {
+        self.times.push(rtt);
+    }
+
+    fn received(&self) -> usize {
+        self.times.len()
+    }
+}
+
+impl std::fmt::Display for Rtts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lost = self.sent as usize - self.received();
+        let loss = if self.sent == 0 {
+            0.0
+        } else {
+            lost as f64 * 100.0 / self.sent as f64
+        };
+        write!(
+            f,
+            "{} sent, {} received, {:.0}% loss",
+            self.sent,
+            self.received(),
+            loss
+        )?;
+        if let (Some(min), Some(max)) = (self.times.iter().min(), self.times.iter().max()) {
+            let avg = self.times.iter().sum::<Duration>() / self.times.len() as u32;
+            write!(
+                f,
+                ", rtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
+                millis(*min),
+                millis(avg),
+                millis(*max)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_ping_rtts() {
+    let mut rtts = Rtts::default();
+    assert_eq!(rtts.to_string(), "0 sent, 0 received, 0% loss");
+
+    rtts.sent = 4;
+    rtts.record(Duration::from_millis(1));
+    rtts.record(Duration::from_millis(2));
+    rtts.record(Duration::from_millis(6));
+    assert_eq!(
+        rtts.to_string(),
+        "4 sent, 3 received, 25% loss, rtt min/avg/max = 1.000/3.000/6.000 ms"
+    );
+}
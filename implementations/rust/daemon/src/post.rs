@@ -0,0 +1,273 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+
+use crate::config::Config;
+use crate::names;
+
+use ockam_message::message::{
+    AddressType, Codec, Message as OckamMessage, MessageType, Route, RouterAddress,
+};
+use ockam_system::commands::{OckamCommand, RouterCommand};
+
+/// Encode `value` as the body of a payload.
+pub fn encode<T: Codec>(value: &T) -> Result<Vec<u8>, String> {
+    let mut body = vec![];
+    value.encode(&mut body)?;
+    Ok(body)
+}
+
+/// Decode the body of a payload as a `T`, which must take up the whole body.
+pub fn decode<T: Codec<Inner = T>>(body: &[u8]) -> Result<T, String> {
+    match T::decode(body)? {
+        (value, []) => Ok(value),
+        (_, rest) => Err(format!("{} bytes left after the payload", rest.len())),
+    }
+}
+
+fn encode_any<T: Codec + 'static>(value: &dyn Any) -> Result<Vec<u8>, String> {
+    encode(
+        value
+            .downcast_ref::<T>()
+            .ok_or("local message has the wrong type")?,
+    )
+}
+
+/// A value sent to a worker by another worker on the same thread, which arrives as it was sent
+/// rather than encoded. See `WorkerHandler::handle_local`.
+pub struct Local {
+    pub from: RouterAddress,
+    pub to: RouterAddress,
+    value: Box<dyn Any + Send>,
+    encode: fn(&dyn Any) -> Result<Vec<u8>, String>,
+}
+
+impl Local {
+    /// The value, if it is a `T`, or else the message back.
+    pub fn downcast<T: 'static>(self) -> Result<T, Local> {
+        if self.value.is::<T>() {
+            Ok(*self.value.downcast().unwrap())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// The payload the value would have arrived as from a remote node.
+    pub fn into_message(self) -> Result<OckamMessage, String> {
+        Ok(OckamMessage {
+            onward_route: Route {
+                addresses: vec![self.to],
+            },
+            return_route: Route {
+                addresses: vec![self.from],
+            },
+            message_type: MessageType::Payload,
+            message_body: (self.encode)(self.value.as_ref())?,
+        })
+    }
+}
+
+// the last registration ID, and the workers registered by address with the ID of each
+type Registrations = (u64, HashMap<String, (u64, Sender<Local>)>);
+
+// the workers on this thread, which is the node's
+thread_local! {
+    static LOCAL_WORKERS: RefCell<Registrations> = RefCell::new((0, HashMap::new()));
+}
+
+// Deliver values sent to `addr` from this thread to `tx`, returning the ID of the registration.
+pub(crate) fn register(addr: &RouterAddress, tx: Sender<Local>) -> u64 {
+    LOCAL_WORKERS.with(|workers| {
+        let (next_id, workers) = &mut *workers.borrow_mut();
+        *next_id += 1;
+        workers.insert(addr.address.as_string(), (*next_id, tx));
+        *next_id
+    })
+}
+
+// Remove the registration `id` for `addr`, unless another worker has registered there since.
+pub(crate) fn unregister(addr: &RouterAddress, id: u64) {
+    LOCAL_WORKERS.with(|workers| {
+        let workers = &mut workers.borrow_mut().1;
+        let name = addr.address.as_string();
+        if workers
+            .get(&name)
+            .map(|(current, _)| *current == id)
+            .unwrap_or(false)
+        {
+            workers.remove(&name);
+        }
+    })
+}
+
+/// Sends typed payloads from a worker address to other workers. A worker on the same thread gets
+/// the value itself, skipping encoding; any other is sent the encoded value through the router.
+#[derive(Clone)]
+pub struct Poster {
+    router_tx: Sender<OckamCommand>,
+    addr: RouterAddress,
+}
+
+impl Poster {
+    pub fn new(router_tx: Sender<OckamCommand>, addr: RouterAddress) -> Self {
+        Poster { router_tx, addr }
+    }
+
+    /// Send `value` to the worker at `to`.
+    pub fn send<T: Codec + Any + Send>(&self, to: &RouterAddress, value: T) -> Result<(), String> {
+        let mut local = Local {
+            from: self.addr.clone(),
+            to: to.clone(),
+            value: Box::new(value),
+            encode: encode_any::<T>,
+        };
+        if let AddressType::Worker = to.a_type {
+            let tx = LOCAL_WORKERS
+                .with(|workers| workers.borrow().1.get(&to.address.as_string()).cloned());
+            if let Some((_, tx)) = tx {
+                // a worker which has gone is sent the value through the router instead
+                match tx.send(local) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => local = e.0,
+                }
+            }
+        }
+        let msg = local.into_message()?;
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(msg)))
+            .map_err(|_| "failed to send message to router".to_string())
+    }
+
+    /// Send `value` to the service called `name` on this node.
+    pub fn send_to_service<T: Codec + Any + Send>(
+        &self,
+        config: &Config,
+        name: &str,
+        value: T,
+    ) -> Result<(), String> {
+        let address = names::resolve(config, name).ok_or(format!("no service called {}", name))?;
+        let to = RouterAddress::worker_router_address_from_str(&address)?;
+        self.send(&to, value)
+    }
+}
+
+#[test]
+fn test_post_local() {
+    use crate::worker::{Worker, WorkerHandler};
+    use ockam_message::error::MessageError;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    struct Reading(u16);
+    impl Codec for Reading {
+        type Inner = Reading;
+        fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
+            self.0.encode(v)
+        }
+        fn decode(s: &[u8]) -> Result<(Reading, &[u8]), MessageError> {
+            u16::decode(s).map(|(n, rest)| (Reading(n), rest))
+        }
+    }
+
+    // records each reading, and whether it arrived as itself or encoded
+    struct Sink(Arc<Mutex<Vec<(&'static str, u16)>>>, bool);
+    impl WorkerHandler for Sink {
+        fn handle_message(&mut self, _: &Config, msg: OckamMessage) -> Result<(), String> {
+            let reading: Reading = decode(&msg.message_body)?;
+            self.0.lock().unwrap().push(("encoded", reading.0));
+            Ok(())
+        }
+        fn handle_local(&mut self, config: &Config, local: Local) -> Result<(), String> {
+            if !self.1 {
+                return self.handle_message(config, local.into_message()?);
+            }
+            match local.downcast::<Reading>() {
+                Ok(reading) => self.0.lock().unwrap().push(("local", reading.0)),
+                Err(_) => return Err("not a reading".into()),
+            }
+            Ok(())
+        }
+    }
+
+    let config: Config = crate::cli::Args::load(
+        vec![
+            "ockamd",
+            "--role",
+            "responder",
+            "--service",
+            "sink=0a0b0c0d",
+        ]
+        .into_iter()
+        .map(std::ffi::OsString::from),
+    )
+    .unwrap()
+    .into();
+    let (fake_router_tx, fake_router_rx) = mpsc::channel();
+    let readings = Arc::new(Mutex::new(vec![]));
+    let sink = |address: &str, typed: bool| {
+        let readings = readings.clone();
+        Worker::new(
+            RouterAddress::worker_router_address_from_str(address).unwrap(),
+            fake_router_tx.clone(),
+            config.clone(),
+            Box::new(
+                move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                    Ok(Box::new(Sink(readings.clone(), typed)))
+                },
+            ),
+        )
+        .unwrap()
+    };
+    let mut typed = sink("0a0b0c0d", true);
+    let mut untyped = sink("01020304", false);
+    fake_router_rx.try_iter().count();
+
+    // workers on this thread get the value, or its encoding if they don't take values
+    let from = RouterAddress::worker_router_address_from_str("00000001").unwrap();
+    let poster = Poster::new(fake_router_tx, from.clone());
+    poster
+        .send_to_service(&config, "sink", Reading(300))
+        .unwrap();
+    poster
+        .send(
+            &RouterAddress::worker_router_address_from_str("01020304").unwrap(),
+            Reading(7),
+        )
+        .unwrap();
+    assert!(typed.poll());
+    assert!(untyped.poll());
+    assert_eq!(
+        *readings.lock().unwrap(),
+        vec![("local", 300), ("encoded", 7)]
+    );
+    assert_eq!(typed.processed(), 1);
+    assert!(fake_router_rx.try_recv().is_err());
+    assert!(poster
+        .send_to_service(&config, "missing", Reading(1))
+        .is_err());
+
+    // a worker elsewhere is sent the encoded value through the router
+    drop(typed);
+    let to = RouterAddress::worker_router_address_from_str("0a0b0c0d").unwrap();
+    poster.send(&to, Reading(300)).unwrap();
+    match fake_router_rx.try_recv().unwrap() {
+        OckamCommand::Router(RouterCommand::SendMessage(msg)) => {
+            assert_eq!(msg.onward_route.addresses, vec![to]);
+            assert_eq!(msg.return_route.addresses, vec![from]);
+            assert_eq!(decode::<Reading>(&msg.message_body).unwrap().0, 300);
+        }
+        _ => panic!("expected a message to the router"),
+    }
+    assert!(decode::<Reading>(&[1, 2]).is_err());
+
+    // values sent to a stopped worker are dropped, as messages are
+    untyped.stop().unwrap();
+    poster
+        .send(
+            &RouterAddress::worker_router_address_from_str("01020304").unwrap(),
+            Reading(8),
+        )
+        .unwrap();
+    assert!(untyped.poll());
+    assert_eq!(untyped.dropped(), 1);
+}
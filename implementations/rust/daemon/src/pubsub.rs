@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::worker::{Worker, WorkerHandler};
+
+use ockam_message::message::{Message as OckamMessage, MessageType, Route, RouterAddress};
+use ockam_system::commands::{OckamCommand, RouterCommand};
+
+/// The worker address of the pub/sub broker, "pubs" in ASCII.
+pub const BROKER_SERVICE_ADDRESS: &str = "70756273";
+
+// the kinds of message the broker handles, the first byte of each payload
+const SUBSCRIBE: u8 = 1;
+const UNSUBSCRIBE: u8 = 2;
+const PUBLISH: u8 = 3;
+
+// subscriptions are dropped once their subscriber misses this many renewals
+const EXPIRY_INTERVALS: u32 = 3;
+
+// how often subscribers renew when channels have no keepalive to follow
+const DEFAULT_RENEW_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A subscription to `topic`, sent to the broker over a channel of the subscriber's own; the
+/// publications are sent back along the route it arrived by. A subscription must be renewed at
+/// each keepalive interval, or it expires once three are missed, e.g. when the subscriber's
+/// channel has gone.
+pub fn subscribe(topic: &str) -> Result<Vec<u8>, String> {
+    encode(SUBSCRIBE, topic, &[])
+}
+
+/// An end to the subscription to `topic` made over the same route.
+pub fn unsubscribe(topic: &str) -> Result<Vec<u8>, String> {
+    encode(UNSUBSCRIBE, topic, &[])
+}
+
+/// A publication of `data` to the subscribers of `topic`.
+pub fn publish(topic: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    encode(PUBLISH, topic, data)
+}
+
+/// The topic and data of a publication, as the broker delivers it to a subscriber.
+pub fn publication(payload: &[u8]) -> Result<(String, Vec<u8>), String> {
+    match decode(payload)? {
+        (PUBLISH, topic, data) => Ok((topic, data.to_vec())),
+        _ => Err("not a publication".into()),
+    }
+}
+
+fn encode(kind: u8, topic: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    if topic.is_empty() || topic.len() > u8::MAX as usize {
+        return Err(format!("bad topic name: {:?}", topic));
+    }
+    let mut v = vec![kind, topic.len() as u8];
+    v.extend_from_slice(topic.as_bytes());
+    v.extend_from_slice(data);
+    Ok(v)
+}
+
+fn decode(payload: &[u8]) -> Result<(u8, String, &[u8]), String> {
+    let (&kind, u) = payload.split_first().ok_or("empty pub/sub message")?;
+    let (&len, u) = u.split_first().ok_or("pub/sub message has no topic")?;
+    let topic = u
+        .get(..len as usize)
+        .ok_or("pub/sub message is truncated")?;
+    let topic = String::from_utf8(topic.to_vec()).map_err(|_| "topic is not UTF-8")?;
+    Ok((kind, topic, &u[len as usize..]))
+}
+
+/// The pub/sub broker, hosted with `--broker`: each publication to a topic is sent on to every
+/// route subscribed to it, each over the subscriber's own channel, with the broker's address as
+/// its return route. Publishers and subscribers only need a route to the broker, not to each
+/// other.
+pub fn broker_worker(config: &Config, router_tx: Sender<OckamCommand>) -> Option<Worker> {
+    if !config.broker() {
+        return None;
+    }
+    let worker_addr =
+        RouterAddress::worker_router_address_from_str(BROKER_SERVICE_ADDRESS).unwrap();
+    let addr = worker_addr.clone();
+    let tx = router_tx.clone();
+    let make_handler = Box::new(
+        move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            Ok(Box::new(Broker {
+                router_tx: tx.clone(),
+                addr: addr.clone(),
+                topics: HashMap::new(),
+            }))
+        },
+    );
+    let worker = Worker::new(worker_addr, router_tx, config.clone(), make_handler)
+        .expect("failed to start broker worker");
+    Some(worker)
+}
+
+// a route subscribed to a topic, and when it last subscribed
+struct Subscriber {
+    route: Route,
+    renewed: Instant,
+}
+
+struct Broker {
+    router_tx: Sender<OckamCommand>,
+    addr: RouterAddress,
+    // the subscribers to each topic, by name
+    topics: HashMap<String, Vec<Subscriber>>,
+}
+
+impl Broker {
+    fn fan_out(&self, topic: &str, data: &[u8]) -> Result<(), String> {
+        let subscribers = match self.topics.get(topic) {
+            Some(subscribers) => subscribers,
+            None => return Ok(()),
+        };
+        let body = publish(topic, data)?;
+        for subscriber in subscribers {
+            let msg = OckamMessage {
+                onward_route: subscriber.route.clone(),
+                return_route: Route {
+                    addresses: vec![self.addr.clone()],
+                },
+                message_type: MessageType::Payload,
+                message_body: body.clone(),
+            };
+            self.router_tx
+                .send(OckamCommand::Router(RouterCommand::SendMessage(msg)))
+                .map_err(|_| "failed to send publication to router".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl WorkerHandler for Broker {
+    fn handle_message(&mut self, _config: &Config, msg: OckamMessage) -> Result<(), String> {
+        let (kind, topic, data) = match decode(&msg.message_body) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                eprintln!("broker: refused a message: {}", e);
+                return Ok(());
+            }
+        };
+        match kind {
+            SUBSCRIBE | UNSUBSCRIBE if msg.return_route.addresses.is_empty() => {
+                eprintln!("broker: refused a subscription to {} with no route", topic);
+                Ok(())
+            }
+            SUBSCRIBE => {
+                let subscribers = self.topics.entry(topic).or_default();
+                let route = msg.return_route;
+                match subscribers
+                    .iter_mut()
+                    .find(|s| s.route.addresses == route.addresses)
+                {
+                    Some(subscriber) => subscriber.renewed = Instant::now(),
+                    None => subscribers.push(Subscriber {
+                        route,
+                        renewed: Instant::now(),
+                    }),
+                }
+                Ok(())
+            }
+            UNSUBSCRIBE => {
+                if let Some(subscribers) = self.topics.get_mut(&topic) {
+                    subscribers.retain(|s| s.route.addresses != msg.return_route.addresses);
+                    if subscribers.is_empty() {
+                        self.topics.remove(&topic);
+                    }
+                }
+                Ok(())
+            }
+            PUBLISH => self.fan_out(&topic, data),
+            kind => {
+                eprintln!("broker: refused a message of unknown kind {}", kind);
+                Ok(())
+            }
+        }
+    }
+
+    fn tick_interval(&self, config: &Config) -> Option<Duration> {
+        Some(config.keepalive().unwrap_or(DEFAULT_RENEW_INTERVAL))
+    }
+
+    fn on_tick(&mut self, config: &Config) -> Result<(), String> {
+        let expiry = config.keepalive().unwrap_or(DEFAULT_RENEW_INTERVAL) * EXPIRY_INTERVALS;
+        for subscribers in self.topics.values_mut() {
+            subscribers.retain(|s| s.renewed.elapsed() < expiry);
+        }
+        self.topics.retain(|_, subscribers| !subscribers.is_empty());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pubsub_broker() {
+    let (router_tx, router_rx) = std::sync::mpsc::channel();
+    let mut broker = Broker {
+        router_tx,
+        addr: RouterAddress::worker_router_address_from_str(BROKER_SERVICE_ADDRESS).unwrap(),
+        topics: HashMap::new(),
+    };
+    let config = Config::default();
+    let subscriber = |channel: &str| Route {
+        addresses: vec![
+            RouterAddress::channel_router_address_from_str(channel).unwrap(),
+            RouterAddress::worker_router_address_from_str("0a0b0c0d").unwrap(),
+        ],
+    };
+    let from = |route: &Route, body: Vec<u8>| OckamMessage {
+        return_route: route.clone(),
+        message_type: MessageType::Payload,
+        message_body: body,
+        ..Default::default()
+    };
+    let (a, b) = (subscriber("00000001"), subscriber("00000002"));
+    let publisher = subscriber("00000003");
+    let delivered = || -> Vec<Route> {
+        router_rx
+            .try_iter()
+            .filter_map(|cmd| match cmd {
+                OckamCommand::Router(RouterCommand::SendMessage(msg)) => {
+                    let (topic, data) = publication(&msg.message_body).unwrap();
+                    assert_eq!((topic.as_str(), data.as_slice()), ("temps", &b"21.5"[..]));
+                    Some(msg.onward_route)
+                }
+                _ => None,
+            })
+            .collect()
+    };
+
+    // subscribing twice over the same route is one subscription
+    for route in &[&a, &b, &a] {
+        broker
+            .handle_message(&config, from(route, subscribe("temps").unwrap()))
+            .unwrap();
+    }
+    broker
+        .handle_message(
+            &config,
+            from(&publisher, publish("temps", b"21.5").unwrap()),
+        )
+        .unwrap();
+    let routes = delivered();
+    assert_eq!(routes.len(), 2);
+    assert_eq!(routes[0].addresses, a.addresses);
+    assert_eq!(routes[1].addresses, b.addresses);
+
+    broker
+        .handle_message(&config, from(&a, unsubscribe("temps").unwrap()))
+        .unwrap();
+    broker
+        .handle_message(
+            &config,
+            from(&publisher, publish("temps", b"21.5").unwrap()),
+        )
+        .unwrap();
+    let routes = delivered();
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].addresses, b.addresses);
+
+    // nothing is sent for a topic without subscribers, or for a malformed message
+    broker
+        .handle_message(
+            &config,
+            from(&publisher, publish("other", b"21.5").unwrap()),
+        )
+        .unwrap();
+    broker
+        .handle_message(&config, from(&publisher, vec![PUBLISH, 9]))
+        .unwrap();
+    assert!(delivered().is_empty());
+    assert!(subscribe("").is_err());
+    assert!(publication(&subscribe("temps").unwrap()).is_err());
+}
@@ -0,0 +1,39 @@
+use crate::config::Config;
+use crate::control;
+use crate::discovery;
+use crate::hub::Hub;
+use crate::node::Node;
+use crate::reload;
+use crate::worker;
+
+use std::sync::mpsc;
+
+/// Run a node that terminates no channels, and only forwards messages whose onward route
+/// continues to another hop, e.g. `--route udp://relay:4050,udp://responder:4051` on an
+/// initiator sends its key exchange and payloads through a relay at `relay:4050`. The relay's
+/// hub also forwards to the aliases of the nodes registered with it, see `Hub`, and its discovery
+/// service answers queries for the routes to the services its neighbors advertise.
+pub fn run(config: Config) {
+    let (mut node, router_tx) = Node::new(&config);
+
+    println!("Relaying messages on {}", config.local_host());
+    let discovery = discovery::discovery_worker(&config, router_tx.clone());
+    let reload_tx = reload::watch(config.clone(), vec![discovery.config_sender()], vec![]);
+    if let Some(path) = config.control_socket() {
+        node.add_control(
+            control::listen(&path, reload_tx, node.waker()).expect("failed to open control socket"),
+        );
+    }
+
+    // messages for the discovery service are picked out, and all others go to the hub
+    let hub = Hub::detached(&router_tx);
+    let routes = vec![(discovery.address(), discovery.sender())];
+    let (_, added) = mpsc::channel();
+    node.add_thread(
+        "worker dispatch",
+        worker::dispatch(&router_tx, routes, hub.sender(), added),
+    );
+    node.add_worker(discovery);
+    node.add_thread("hub", hub.spawn());
+    node.run();
+}
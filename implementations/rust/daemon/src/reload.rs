@@ -0,0 +1,80 @@
+use std::sync::mpsc::{self, Sender};
+
+use crate::cli::Args;
+use crate::config::{Config, ConfigUpdate};
+use crate::wake::Waker;
+
+/// A request to reload the configuration, with an optional channel on which to report how
+/// many updates were applied.
+pub type ReloadRequest = Option<Sender<Result<usize, String>>>;
+
+/// Re-read the command line and `--config` file whenever `ockamd` receives SIGHUP, or a reload
+/// is requested through the returned sender, and send the resulting changes to the given
+/// components so established channels stay up, waking the threads which poll them.
+pub fn watch(
+    config: Config,
+    subscribers: Vec<Sender<ConfigUpdate>>,
+    wakers: Vec<Waker>,
+) -> Sender<ReloadRequest> {
+    let (reload_tx, reload_rx) = mpsc::channel::<ReloadRequest>();
+
+    #[cfg(unix)]
+    forward_sighup(reload_tx.clone());
+
+    std::thread::spawn(move || {
+        let mut current = config;
+        for reply in reload_rx.iter() {
+            let result = reload(&mut current, &subscribers);
+            for waker in &wakers {
+                waker.wake();
+            }
+            if let Err(e) = &result {
+                eprintln!("{}", e);
+            }
+            if let Some(reply) = reply {
+                let _ = reply.send(result);
+            }
+        }
+    });
+
+    reload_tx
+}
+
+fn reload(current: &mut Config, subscribers: &[Sender<ConfigUpdate>]) -> Result<usize, String> {
+    let new: Config = Args::load(std::env::args_os())
+        .map_err(|e| format!("failed to reload configuration: {}", e))?
+        .into();
+
+    let updates = current.changes(&new);
+    for update in &updates {
+        println!("applying configuration update: {:?}", update);
+        for s in subscribers {
+            if s.send(update.clone()).is_err() {
+                eprintln!("failed to deliver configuration update");
+            }
+        }
+        current.apply(update);
+    }
+    Ok(updates.len())
+}
+
+#[cfg(unix)]
+fn forward_sighup(reload_tx: Sender<ReloadRequest>) {
+    use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+    let mut signals = match Signals::new([SIGHUP].iter()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            if reload_tx.send(None).is_err() {
+                break;
+            }
+        }
+    });
+}
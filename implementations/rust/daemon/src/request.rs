@@ -0,0 +1,162 @@
+use std::convert::TryInto;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use ockam_message::message::{Message as OckamMessage, MessageType, Route, RouterAddress};
+use ockam_system::commands::{OckamCommand, RouterCommand, WorkerCommand};
+
+/// Bytes of the correlation ID at the start of each request and response body.
+pub const CORRELATION_ID_LEN: usize = 8;
+
+/// Prefix `payload` with the correlation ID of a request, or of the request it answers.
+pub fn encode(id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut body = id.to_be_bytes().to_vec();
+    body.extend_from_slice(payload);
+    body
+}
+
+/// Split a request or response body into its correlation ID and payload.
+pub fn decode(body: &[u8]) -> Option<(u64, &[u8])> {
+    if body.len() < CORRELATION_ID_LEN {
+        return None;
+    }
+    let (id, payload) = body.split_at(CORRELATION_ID_LEN);
+    Some((u64::from_be_bytes(id.try_into().unwrap()), payload))
+}
+
+/// Sends requests from a worker address and waits for their responses, which services send
+/// with `Replier::respond`. The requester owns the inbox of its address, so it runs apart from
+/// the node's poll loop, e.g. on its own thread, with its sender registered with the router.
+pub struct Requester {
+    router_tx: Sender<OckamCommand>,
+    addr: RouterAddress,
+    rx: Receiver<OckamCommand>,
+    next_id: u64,
+}
+
+impl Requester {
+    pub fn new(
+        router_tx: Sender<OckamCommand>,
+        addr: RouterAddress,
+        rx: Receiver<OckamCommand>,
+    ) -> Self {
+        Requester {
+            router_tx,
+            addr,
+            rx,
+            next_id: 0,
+        }
+    }
+
+    /// The correlation ID of the next request.
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Send `payload` along `route` and return the payload of the response, or an error once
+    /// `timeout` has passed. Late responses to earlier requests, and anything else arriving
+    /// meanwhile, are discarded.
+    pub fn send_request(
+        &mut self,
+        route: Route,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = OckamMessage {
+            onward_route: route,
+            return_route: Route {
+                addresses: vec![self.addr.clone()],
+            },
+            message_type: MessageType::Payload,
+            message_body: encode(id, payload),
+        };
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(request)))
+            .map_err(|_| "failed to send request to router".to_string())?;
+
+        let sent = Instant::now();
+        loop {
+            let remaining = timeout
+                .checked_sub(sent.elapsed())
+                .ok_or("timed out waiting for a response")?;
+            match self.rx.recv_timeout(remaining) {
+                Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg))) => {
+                    if let MessageType::Payload = msg.message_type {
+                        if let Some((response_id, payload)) = decode(&msg.message_body) {
+                            if response_id == id {
+                                return Ok(payload.to_vec());
+                            }
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err("timed out waiting for a response".into())
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err("the node has stopped".into()),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_request_response() {
+    use crate::worker::Replier;
+    use std::sync::mpsc;
+
+    let addr = RouterAddress::worker_router_address_from_str("72657121").unwrap();
+    let service = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (router_tx, router_rx) = mpsc::channel();
+    let (tx, rx) = mpsc::channel();
+    let mut requester = Requester::new(router_tx.clone(), addr.clone(), rx);
+
+    // stand in for the router and a service which upper-cases each request, answering the
+    // first one twice so that the second request sees a stale response first
+    let replier = Replier::new(router_tx, service.clone());
+    std::thread::spawn(move || {
+        for cmd in router_rx.iter() {
+            let msg = match cmd {
+                OckamCommand::Router(RouterCommand::SendMessage(msg)) => msg,
+                _ => continue,
+            };
+            if msg.onward_route.addresses[0] == addr {
+                tx.send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)))
+                    .unwrap();
+                continue;
+            }
+            let (id, payload) = decode(&msg.message_body).unwrap();
+            if payload == b"slow" {
+                continue;
+            }
+            let response = payload.to_ascii_uppercase();
+            if id == 0 {
+                replier.respond(&msg, &response).unwrap();
+            }
+            replier.respond(&msg, &response).unwrap();
+        }
+    });
+
+    let route = Route {
+        addresses: vec![service],
+    };
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        requester
+            .send_request(route.clone(), b"one", timeout)
+            .unwrap(),
+        b"ONE"
+    );
+    assert_eq!(
+        requester
+            .send_request(route.clone(), b"two", timeout)
+            .unwrap(),
+        b"TWO"
+    );
+    assert!(requester
+        .send_request(route, b"slow", Duration::from_millis(10))
+        .is_err());
+    assert_eq!(requester.next_id(), 3);
+}
@@ -1,47 +1,238 @@
 use std::io::Write;
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
 
-use crate::config::{AddonKind, Config};
+use crate::addon::{Addon, AddonRegistry, AddonSpec};
+use crate::config::{Config, ServiceSpec, ECHO_SERVICE_ADDRESS};
+use crate::control;
+use crate::discovery;
+use crate::hub;
+use crate::mailbox;
+use crate::mdns;
+use crate::names;
 use crate::node::Node;
-use crate::worker::Worker;
+use crate::output;
+use crate::pubsub;
+use crate::reload;
+use crate::stats;
+use crate::transfer;
+use crate::worker::{self, Factory, MakeHandler, Replier, Spawner, Worker, WorkerHandler};
 
-use ockam_message::message::RouterAddress;
+use ockam_channel::CHANNEL_ZERO;
+use ockam_message::message::{Message as OckamMessage, RouterAddress};
+use ockam_system::commands::OckamCommand;
 
-use attohttpc::post;
-
-pub fn run(config: Config) {
+pub fn run(config: Config, addons: AddonRegistry) {
     let (mut node, router_tx) = Node::new(&config);
+    let (route_tx, route_rx) = mpsc::channel();
+    let spawner = Spawner::new(node.worker_sender(), route_tx);
 
-    let worker_addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
-    let worker = Worker::new(worker_addr, router_tx, config.clone(), |w, msg| {
-        match w.config().addon() {
-            Some(AddonKind::InfluxDb(url, db)) => {
-                let payload = String::from_utf8(msg.message_body);
-                if payload.is_err() {
-                    eprintln!("invalid message body for influx");
-                    return;
-                }
+    let mut workers = output_workers(&config, &addons, &router_tx, &spawner);
+    if let Some(worker) = hub_registrant(&config, &node, &router_tx) {
+        workers.push(worker);
+    }
+    workers.extend(transfer::transfer_worker(&config, &node, router_tx.clone()));
+    if config.mdns() {
+        mdns::spawn(&config, &node, None).expect("failed to start mDNS");
+    }
+    let reload_tx = reload::watch(
+        config.clone(),
+        workers.iter().map(Worker::config_sender).collect(),
+        vec![node.waker()],
+    );
+    if let Some(path) = config.control_socket() {
+        node.add_control(
+            control::listen(&path, reload_tx, node.waker()).expect("failed to open control socket"),
+        );
+    }
+
+    // the services, their sessions and the name service share the router's single worker
+    // handler; the announcements of new channels go to all of them, and messages for unknown
+    // services to the first
+    let announcements = RouterAddress::worker_router_address_from_str(CHANNEL_ZERO).unwrap();
+    let mut routes: Vec<_> = workers.iter().map(|w| (w.address(), w.sender())).collect();
+    routes.extend(workers.iter().map(|w| (announcements.clone(), w.sender())));
+    let first = workers[0].sender();
+
+    // add the workers and run the node to poll its various internal components
+    for worker in workers {
+        node.add_worker(worker);
+    }
+    node.add_thread(
+        "worker dispatch",
+        worker::dispatch(&router_tx, routes, first, route_rx),
+    );
+    node.run();
+}
+
+/// Create a worker for each configured service, which passes each payload received over a
+/// secure channel to the service's addon, or writes it to stdout. The worker of a session
+/// service passes each sender's payloads to a worker of its own instead, started by `spawner`.
+///
+/// Workers are also created for:
+/// - the echo service, if enabled
+/// - the name service
+/// - the discovery service, given `--neighbor`
+/// - the broker, given `--broker`
+/// - the mailbox, given `--mailbox-dir`
+pub fn output_workers(
+    config: &Config,
+    addons: &AddonRegistry,
+    router_tx: &Sender<OckamCommand>,
+    spawner: &Spawner,
+) -> Vec<Worker> {
+    let services = config.services();
+    for name in config.session_services() {
+        if !services.iter().any(|s| s.name == name) {
+            eprintln!("no service named {} to give sessions", name);
+            std::process::exit(1);
+        }
+    }
 
-                match post(format!("{}write?db={}", url.into_string(), db))
-                    .text(payload.unwrap())
-                    .send()
-                {
-                    Ok(resp) => {
-                        if let Err(e) = resp.error_for_status() {
-                            eprintln!("bad influx HTTP response: {}", e);
-                        }
+    let mut workers: Vec<Worker> = services
+        .into_iter()
+        .map(|service| output_worker(config, service, addons.clone(), router_tx.clone(), spawner))
+        .collect();
+    if config.echo() {
+        workers.push(echo_worker(config, router_tx.clone()));
+    }
+    workers.push(names::name_worker(config, router_tx.clone()));
+    if !config.neighbors().is_empty() {
+        workers.push(discovery::discovery_worker(config, router_tx.clone()));
+    }
+    workers.extend(pubsub::broker_worker(config, router_tx.clone()));
+    workers.extend(mailbox::mailbox_worker(config, router_tx.clone()));
+    workers
+}
+
+/// With `--hub`, a worker registering the node with the relay's hub under its identifier.
+pub fn hub_registrant(
+    config: &Config,
+    node: &Node,
+    router_tx: &Sender<OckamCommand>,
+) -> Option<Worker> {
+    config.hub_route()?;
+    let name = node
+        .identity()
+        .map_or_else(|| config.identity_name(), |i| i.id().to_string());
+    Some(hub::registrant_worker(config, router_tx.clone(), name))
+}
+
+// Return each payload to its sender, through the channel it arrived on.
+fn echo_worker(config: &Config, router_tx: Sender<OckamCommand>) -> Worker {
+    let worker_addr = RouterAddress::worker_router_address_from_str(ECHO_SERVICE_ADDRESS).unwrap();
+    let replier = Replier::new(router_tx.clone(), worker_addr.clone());
+    let make_handler = Box::new(
+        move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            let replier = replier.clone();
+            Ok(Box::new(move |_: &Config, msg: OckamMessage| {
+                replier.reply(&msg, msg.message_body.clone())
+            }))
+        },
+    );
+    Worker::new(worker_addr, router_tx, config.clone(), make_handler)
+        .expect("failed to start echo worker")
+}
+
+fn output_worker(
+    config: &Config,
+    service: ServiceSpec,
+    addons: AddonRegistry,
+    router_tx: Sender<OckamCommand>,
+    spawner: &Spawner,
+) -> Worker {
+    let worker_addr = RouterAddress::worker_router_address_from_str(&service.address).unwrap();
+    let make_handler = if config.session_services().contains(&service.name) {
+        let factory = Factory::new(
+            worker_addr.clone(),
+            router_tx.clone(),
+            spawner.clone(),
+            move || service_handler(service.clone(), addons.clone()),
+        );
+        factory.make_handler()
+    } else {
+        service_handler(service, addons)
+    };
+
+    // a bad addon configuration is reported at startup
+    match Worker::new(worker_addr, router_tx, config.clone(), make_handler) {
+        Ok(worker) => worker,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// The addon is created afresh each time the worker starts, with the current configuration.
+fn service_handler(service: ServiceSpec, addons: AddonRegistry) -> MakeHandler {
+    Box::new(
+        move |config: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            let spec = config.service(&service.name).and_then(|s| s.addon);
+            let addon = spec.as_ref().map(|s| addons.create(s)).transpose()?;
+            Ok(Box::new(ServiceHandler {
+                name: service.name.clone(),
+                addons: addons.clone(),
+                spec,
+                addon,
+            }))
+        },
+    )
+}
+
+/// Passes the payloads sent to a service to its addon, or writes them to stdout.
+struct ServiceHandler {
+    name: String,
+    addons: AddonRegistry,
+    spec: Option<AddonSpec>,
+    addon: Option<Box<dyn Addon>>,
+}
+
+impl WorkerHandler for ServiceHandler {
+    fn handle_message(&mut self, config: &Config, msg: OckamMessage) -> Result<(), String> {
+        stats::record_message(msg.message_body.len());
+
+        // the addon setting may have been changed by a reload since the last message
+        let current = config.service(&self.name).and_then(|s| s.addon);
+        if current != self.spec {
+            match current.as_ref().map(|s| self.addons.create(s)).transpose() {
+                Ok(new_addon) => {
+                    if let Err(e) = self.shutdown() {
+                        eprintln!("{}", e);
                     }
-                    Err(e) => println!("failed to send to influxdb: {}", e),
+                    self.addon = new_addon;
                 }
+                Err(e) => eprintln!("keeping the current addon: {}", e),
             }
+            self.spec = current;
+        }
+
+        match self.addon.as_mut() {
+            Some(addon) => addon.handle(&msg),
             None => {
                 let mut out = std::io::stdout();
-                out.write_all(msg.message_body.as_ref())
-                    .expect("failed to write message to stdout");
-                out.flush().expect("failed to flush stdout");
+                out.write_all(&output::encode(config.output_encoding(), &msg))
+                    .and_then(|_| out.flush())
+                    .map_err(|e| format!("failed to write message to stdout: {}", e))
             }
         }
-    });
-    // add the worker and run the node to poll its various internal components
-    node.add_worker(worker);
-    node.run();
+    }
+
+    fn tick_interval(&self, _config: &Config) -> Option<Duration> {
+        self.addon.as_ref().and_then(|addon| addon.tick_interval())
+    }
+
+    fn on_tick(&mut self, _config: &Config) -> Result<(), String> {
+        match self.addon.as_mut() {
+            Some(addon) => addon.tick(),
+            None => Ok(()),
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), String> {
+        match self.addon.take() {
+            Some(mut addon) => addon.shutdown(),
+            None => Ok(()),
+        }
+    }
 }
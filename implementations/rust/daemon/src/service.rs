@@ -0,0 +1,260 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cli::Args;
+use crate::node;
+use crate::Builder;
+
+use winapi::shared::minwindef::{DWORD, LPVOID};
+use winapi::shared::winerror::{ERROR_BAD_ARGUMENTS, ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR};
+use winapi::um::winnt::{
+    DELETE, LPWSTR, SERVICE_AUTO_START, SERVICE_ERROR_NORMAL, SERVICE_WIN32_OWN_PROCESS,
+};
+use winapi::um::winsvc::{
+    CloseServiceHandle, ControlService, CreateServiceW, DeleteService, OpenSCManagerW,
+    OpenServiceW, RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+    SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP,
+    SERVICE_ALL_ACCESS, SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN,
+    SERVICE_CONTROL_STOP, SERVICE_QUERY_STATUS, SERVICE_RUNNING, SERVICE_STATUS,
+    SERVICE_STATUS_HANDLE, SERVICE_STOP, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+    SERVICE_TABLE_ENTRYW,
+};
+
+const SERVICE_NAME: &str = "ockamd";
+const DISPLAY_NAME: &str = "Ockam daemon";
+
+// set once by the service main function, and read by the control handler
+static STATUS_HANDLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Handle `ockamd service install [OPTIONS]`, `ockamd service uninstall` and
+/// `ockamd service run [OPTIONS]`, which the service manager uses to start the installed service
+/// with the options given at install time.
+pub fn main(args: &[OsString]) -> Result<(), String> {
+    match args.get(2).and_then(|a| a.to_str()) {
+        Some("install") => install(&args[3..]),
+        Some("uninstall") => uninstall(),
+        Some("run") => run(),
+        _ => Err("usage: ockamd service install [OPTIONS] | uninstall | run [OPTIONS]".into()),
+    }
+}
+
+fn install(options: &[OsString]) -> Result<(), String> {
+    // check the options now, rather than when the service first starts
+    let mut cli = vec![OsString::from(SERVICE_NAME)];
+    cli.extend(options.iter().cloned());
+    Args::load(cli)?;
+
+    let exe = env::current_exe().map_err(|e| e.to_string())?;
+    let mut command = quote(exe.as_os_str());
+    command.push(" service run");
+    for option in options {
+        command.push(" ");
+        command.push(quote(option));
+    }
+
+    let name = wide(OsStr::new(SERVICE_NAME));
+    let display_name = wide(OsStr::new(DISPLAY_NAME));
+    let command = wide(&command);
+    unsafe {
+        let manager = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CREATE_SERVICE);
+        if manager.is_null() {
+            return Err(last_error("failed to open the service manager"));
+        }
+
+        let service = CreateServiceW(
+            manager,
+            name.as_ptr(),
+            display_name.as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            command.as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+        );
+        let result = if service.is_null() {
+            Err(last_error("failed to create the service"))
+        } else {
+            CloseServiceHandle(service);
+            println!("Installed the {} service", SERVICE_NAME);
+            Ok(())
+        };
+        CloseServiceHandle(manager);
+        result
+    }
+}
+
+fn uninstall() -> Result<(), String> {
+    let name = wide(OsStr::new(SERVICE_NAME));
+    unsafe {
+        let manager = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CONNECT);
+        if manager.is_null() {
+            return Err(last_error("failed to open the service manager"));
+        }
+
+        let service = OpenServiceW(
+            manager,
+            name.as_ptr(),
+            SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE,
+        );
+        let result = if service.is_null() {
+            Err(last_error("failed to open the service"))
+        } else {
+            // a service that isn't running fails to stop, which is fine
+            let mut status = status(SERVICE_STOPPED, NO_ERROR);
+            ControlService(service, SERVICE_CONTROL_STOP, &mut status);
+
+            let deleted = DeleteService(service) != 0;
+            let result = if deleted {
+                println!("Uninstalled the {} service", SERVICE_NAME);
+                Ok(())
+            } else {
+                Err(last_error("failed to delete the service"))
+            };
+            CloseServiceHandle(service);
+            result
+        };
+        CloseServiceHandle(manager);
+        result
+    }
+}
+
+fn run() -> Result<(), String> {
+    let name = wide(OsStr::new(SERVICE_NAME));
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: ptr::null(),
+            lpServiceProc: None,
+        },
+    ];
+
+    // blocks until the service has stopped
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(last_error(
+            "failed to connect to the service manager; `service run` is used by the service manager",
+        ));
+    }
+    Ok(())
+}
+
+unsafe extern "system" fn service_main(_argc: DWORD, _argv: *mut LPWSTR) {
+    let name = wide(OsStr::new(SERVICE_NAME));
+    let handle =
+        RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), ptr::null_mut());
+    if handle.is_null() {
+        return;
+    }
+    STATUS_HANDLE.store(handle as usize, Ordering::SeqCst);
+
+    // the options given at install time follow `service run` on the command line
+    let mut cli: Vec<OsString> = env::args_os().take(1).collect();
+    cli.extend(env::args_os().skip(3));
+    match Args::load(cli) {
+        Ok(args) => {
+            set_status(SERVICE_RUNNING, NO_ERROR);
+            Builder::new(args.into()).run();
+            set_status(SERVICE_STOPPED, NO_ERROR);
+        }
+        Err(_) => set_status(SERVICE_STOPPED, ERROR_BAD_ARGUMENTS),
+    }
+}
+
+unsafe extern "system" fn control_handler(
+    control: DWORD,
+    _event_type: DWORD,
+    _event_data: LPVOID,
+    _context: LPVOID,
+) -> DWORD {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, NO_ERROR);
+            node::stop();
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+fn status(state: DWORD, exit_code: DWORD) -> SERVICE_STATUS {
+    SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: match state {
+            SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+            _ => 0,
+        },
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        dwWaitHint: match state {
+            SERVICE_STOP_PENDING => 5000,
+            _ => 0,
+        },
+    }
+}
+
+fn set_status(state: DWORD, exit_code: DWORD) {
+    let handle = STATUS_HANDLE.load(Ordering::SeqCst) as SERVICE_STATUS_HANDLE;
+    let mut status = status(state, exit_code);
+    unsafe {
+        SetServiceStatus(handle, &mut status);
+    }
+}
+
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(Some(0)).collect()
+}
+
+fn last_error(context: &str) -> String {
+    format!("{}: {}", context, std::io::Error::last_os_error())
+}
+
+/// Quote an argument for a Windows command line, following the rules used by
+/// `CommandLineToArgvW`: backslashes are only special when they precede a quote.
+fn quote(arg: &OsStr) -> OsString {
+    let arg = arg.to_string_lossy();
+    if !arg.is_empty() && !arg.contains(|c| c == ' ' || c == '\t' || c == '"') {
+        return arg.as_ref().into();
+    }
+
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => {
+                backslashes += 1;
+                continue;
+            }
+            '"' => quoted.push_str(&"\\".repeat(backslashes * 2 + 1)),
+            _ => quoted.push_str(&"\\".repeat(backslashes)),
+        }
+        backslashes = 0;
+        quoted.push(c);
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted.into()
+}
+
+#[test]
+fn test_service_quote() {
+    assert_eq!(quote(OsStr::new("--role")), "--role");
+    assert_eq!(
+        quote(OsStr::new(r"C:\Program Files\ockamd\")),
+        r#""C:\Program Files\ockamd\\""#
+    );
+    assert_eq!(quote(OsStr::new(r#"say "hi""#)), r#""say \"hi\"""#);
+    assert_eq!(quote(OsStr::new("")), r#""""#);
+}
@@ -0,0 +1,19 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// counts of the messages passed through the daemon, reported by the control socket
+static MESSAGES: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Count a message read from the input or delivered to the responder's output.
+pub fn record_message(len: usize) {
+    MESSAGES.fetch_add(1, Ordering::Relaxed);
+    BYTES.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+pub fn messages() -> u64 {
+    MESSAGES.load(Ordering::Relaxed)
+}
+
+pub fn bytes() -> u64 {
+    BYTES.load(Ordering::Relaxed)
+}
@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use ockam_channel::state::ChannelState;
+
+/// Write the state of the channels suspended as the node stops to `path`, one after another.
+pub fn save(path: &Path, states: &[ChannelState]) -> Result<(), String> {
+    let mut bytes = vec![];
+    for state in states {
+        state
+            .encode(&mut bytes)
+            .map_err(|e| format!("failed to save channel {}: {:?}", state.address(), e))?;
+    }
+    std::fs::write(path, bytes).map_err(|e| format!("failed to write channel state file: {}", e))
+}
+
+/// Read the channels saved to `path` by the node's last run, removing the file so that none of
+/// them is resumed twice, which would reuse its nonces. Without the file there are none.
+pub fn load(path: &Path) -> Result<Vec<ChannelState>, String> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("failed to read channel state file: {}", e)),
+    };
+    std::fs::remove_file(path)
+        .map_err(|e| format!("failed to remove channel state file: {}", e))?;
+
+    let mut states = vec![];
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        let (state, next) =
+            ChannelState::decode(rest).map_err(|e| format!("bad channel state file: {:?}", e))?;
+        states.push(state);
+        rest = next;
+    }
+    Ok(states)
+}
+
+#[test]
+fn test_suspend_load() {
+    let path = std::env::temp_dir().join("ockamd_test_suspend_load");
+    let _ = std::fs::remove_file(&path);
+    assert!(load(&path).unwrap().is_empty());
+
+    save(&path, &[]).unwrap();
+    assert!(load(&path).unwrap().is_empty());
+    assert!(!path.exists());
+
+    std::fs::write(&path, [1, 2, 3]).unwrap();
+    assert!(load(&path).is_err());
+    assert!(!path.exists());
+}
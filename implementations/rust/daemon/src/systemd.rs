@@ -0,0 +1,137 @@
+use std::ffi::OsString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+
+/// Reports service state to systemd over `$NOTIFY_SOCKET`, and pets the watchdog when
+/// `$WATCHDOG_USEC` is set. Does nothing when `ockamd` wasn't started by systemd.
+pub struct Notifier {
+    socket: Option<(UnixDatagram, OsString)>,
+    watchdog: Option<Duration>,
+    last_pet: Instant,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let socket = std::env::var_os("NOTIFY_SOCKET").and_then(|path| {
+            UnixDatagram::unbound()
+                .map_err(|e| eprintln!("failed to create systemd notify socket: {}", e))
+                .ok()
+                .map(|socket| (socket, path))
+        });
+
+        // the watchdog only applies to the main process, which is given as WATCHDOG_PID if set
+        let for_us = std::env::var("WATCHDOG_PID")
+            .map(|pid| pid == std::process::id().to_string())
+            .unwrap_or(true);
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| for_us)
+            .map(Duration::from_micros);
+
+        Notifier {
+            socket,
+            watchdog,
+            last_pet: Instant::now(),
+        }
+    }
+
+    /// Tell systemd that startup is complete.
+    pub fn ready(&mut self) {
+        self.notify("READY=1");
+        self.last_pet = Instant::now();
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// When the watchdog is next due a pet, if enabled.
+    pub fn next_watchdog(&self) -> Option<Instant> {
+        self.watchdog.map(|timeout| self.last_pet + timeout / 2)
+    }
+
+    /// Pet the watchdog, if enabled, at twice the rate systemd requires.
+    pub fn watchdog(&mut self) {
+        if let Some(timeout) = self.watchdog {
+            if self.last_pet.elapsed() >= timeout / 2 {
+                self.notify("WATCHDOG=1");
+                self.last_pet = Instant::now();
+            }
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some((socket, path)) = &self.socket {
+            if let Err(e) = send_to(socket, path, state.as_bytes()) {
+                eprintln!("failed to notify systemd: {}", e);
+            }
+        }
+    }
+}
+
+// Send a datagram to a socket path, which may name an abstract socket when it starts with `@`.
+// `UnixDatagram::send_to` only accepts filesystem paths, so the address is built by hand.
+fn send_to(socket: &UnixDatagram, path: &OsString, data: &[u8]) -> io::Result<()> {
+    let path = path.as_bytes();
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bad NOTIFY_SOCKET path",
+        ));
+    }
+
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            data.as_ptr() as *const libc::c_void,
+            data.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_systemd_notify() {
+    let path = std::env::temp_dir().join("ockamd_test_systemd_notify.sock");
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixDatagram::bind(&path).unwrap();
+
+    let mut notifier = Notifier {
+        socket: Some((
+            UnixDatagram::unbound().unwrap(),
+            path.clone().into_os_string(),
+        )),
+        watchdog: Some(Duration::from_millis(0)),
+        last_pet: Instant::now(),
+    };
+    notifier.ready();
+    notifier.watchdog();
+
+    let mut buf = [0; 64];
+    let n = listener.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"READY=1");
+    let n = listener.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"WATCHDOG=1");
+    std::fs::remove_file(&path).unwrap();
+}
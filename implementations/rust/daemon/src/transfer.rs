@@ -0,0 +1,674 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cli::{Args, OutputKind, DEFAULT_VAULT_PATH, FILENAME_KEY_DEFAULT};
+use crate::config::Config;
+use crate::node::{self, Node};
+use crate::request::Requester;
+use crate::worker::{Replier, Worker, WorkerHandler};
+
+use ockam_channel::protocol::channel_error_code;
+use ockam_message::message::{
+    AddressType, Message as OckamMessage, MessageType, Route, RouterAddress,
+};
+use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand, WorkerCommand};
+use ockam_vault::DynVault;
+use structopt::StructOpt;
+
+/// The worker address of the file-transfer service, "file" in ASCII.
+pub const TRANSFER_SERVICE_ADDRESS: &str = "66696c65";
+
+/// The worker address `ockamd send` receives the service's answers at, "send" in ASCII.
+const SEND_ADDRESS: &str = "73656e64";
+
+// the requests the service answers, the first byte of each payload
+const OFFER: u8 = 1;
+const CHUNK: u8 = 2;
+
+// the first byte of each answer: the offset to send from next, the whole file received, or a
+// refusal with its reason
+const CONTINUE: u8 = 0;
+const DONE: u8 = 1;
+const REFUSED: u8 = 2;
+
+const DIGEST_LEN: usize = 32;
+
+// the suffix of a file still being received, named by its digest
+const PARTIAL_SUFFIX: &str = ".part";
+
+type SharedVault = Arc<Mutex<dyn DynVault + Send>>;
+
+fn sha256(vault: &SharedVault, data: &[u8]) -> Result<[u8; DIGEST_LEN], String> {
+    vault
+        .lock()
+        .unwrap()
+        .sha256(data)
+        .map_err(|e| format!("failed to hash: {:?}", e))
+}
+
+// The digest of a file is the SHA-256 of the SHA-256 of each of its chunks, so that it can be
+// checked as the chunks arrive and recomputed from a partial file on resuming.
+fn file_digest(vault: &SharedVault, hashes: &[[u8; DIGEST_LEN]]) -> Result<[u8; 32], String> {
+    sha256(vault, &hashes.concat())
+}
+
+// The hashes of the whole chunks of `file` up to `limit` bytes, and the length they cover.
+fn chunk_hashes(
+    vault: &SharedVault,
+    file: &mut File,
+    chunk_size: usize,
+    limit: u64,
+) -> Result<(Vec<[u8; DIGEST_LEN]>, u64), String> {
+    file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    let mut hashes = vec![];
+    let mut covered = 0;
+    let mut chunk = vec![0; chunk_size];
+    while covered < limit {
+        let want = chunk_size.min((limit - covered) as usize);
+        let read = read_full(file, &mut chunk[..want])?;
+        if read < want {
+            break;
+        }
+        hashes.push(sha256(vault, &chunk[..read])?);
+        covered += read as u64;
+    }
+    Ok((hashes, covered))
+}
+
+fn read_full(file: &mut File, buf: &mut [u8]) -> Result<usize, String> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]).map_err(|e| e.to_string())? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn offer(digest: &[u8; DIGEST_LEN], size: u64, chunk_size: u32, name: &str) -> Vec<u8> {
+    let mut v = vec![OFFER];
+    v.extend_from_slice(digest);
+    v.extend_from_slice(&size.to_be_bytes());
+    v.extend_from_slice(&chunk_size.to_be_bytes());
+    v.extend_from_slice(name.as_bytes());
+    v
+}
+
+fn chunk(digest: &[u8; DIGEST_LEN], offset: u64, hash: &[u8; DIGEST_LEN], data: &[u8]) -> Vec<u8> {
+    let mut v = vec![CHUNK];
+    v.extend_from_slice(digest);
+    v.extend_from_slice(&offset.to_be_bytes());
+    v.extend_from_slice(hash);
+    v.extend_from_slice(data);
+    v
+}
+
+fn answer(status: u8, offset: u64) -> Vec<u8> {
+    let mut v = vec![status];
+    v.extend_from_slice(&offset.to_be_bytes());
+    v
+}
+
+fn refusal(reason: &str) -> Vec<u8> {
+    let mut v = vec![REFUSED];
+    v.extend_from_slice(reason.as_bytes());
+    v
+}
+
+// The status and offset of an answer, or the reason given for a refusal.
+fn decode_answer(payload: &[u8]) -> Result<(u8, u64), String> {
+    match payload.split_first() {
+        Some((&REFUSED, reason)) => Err(format!(
+            "the receiver refused the transfer: {}",
+            String::from_utf8_lossy(reason)
+        )),
+        Some((&status, offset)) if offset.len() == 8 => {
+            Ok((status, u64::from_be_bytes(offset.try_into().unwrap())))
+        }
+        _ => Err("malformed answer from the receiver".into()),
+    }
+}
+
+fn take<'a>(body: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if body.len() < len {
+        return Err("truncated transfer request".into());
+    }
+    let (head, rest) = body.split_at(len);
+    *body = rest;
+    Ok(head)
+}
+
+fn take_u64(body: &mut &[u8]) -> Result<u64, String> {
+    Ok(u64::from_be_bytes(take(body, 8)?.try_into().unwrap()))
+}
+
+/// The file-transfer service, hosted with `--transfer-dir`: files are offered to it and sent in
+/// chunks, each checked against its hash as it arrives, and the whole file against its digest
+/// before it is moved into the directory under the name it was offered with. A partial file is
+/// kept, so that a transfer offered again after an interruption, even of the receiver, resumes
+/// from the last whole chunk received. Hashes are computed by the node's vault.
+pub fn transfer_worker(
+    config: &Config,
+    node: &Node,
+    router_tx: Sender<OckamCommand>,
+) -> Option<Worker> {
+    let dir = config.transfer_dir()?;
+    let vault = node.vault()?;
+    let worker_addr =
+        RouterAddress::worker_router_address_from_str(TRANSFER_SERVICE_ADDRESS).unwrap();
+    let replier = Replier::new(router_tx.clone(), worker_addr.clone());
+    let make_handler = Box::new(
+        move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            fs::create_dir_all(&dir)
+                .map_err(|e| format!("failed to create transfer directory: {}", e))?;
+            Ok(Box::new(Receiver {
+                dir: dir.clone(),
+                vault: vault.clone(),
+                replier: replier.clone(),
+                incoming: HashMap::new(),
+            }))
+        },
+    );
+    let worker = Worker::new(worker_addr, router_tx, config.clone(), make_handler)
+        .expect("failed to start transfer worker");
+    Some(worker)
+}
+
+// a file being received, by its digest
+struct Incoming {
+    name: String,
+    size: u64,
+    chunk_size: usize,
+    file: File,
+    hashes: Vec<[u8; DIGEST_LEN]>,
+    received: u64,
+}
+
+struct Receiver {
+    dir: PathBuf,
+    vault: SharedVault,
+    replier: Replier,
+    incoming: HashMap<[u8; DIGEST_LEN], Incoming>,
+}
+
+impl Receiver {
+    fn partial_path(&self, digest: &[u8; DIGEST_LEN]) -> PathBuf {
+        self.dir
+            .join(format!("{}{}", hex::encode(digest), PARTIAL_SUFFIX))
+    }
+
+    fn offered(&mut self, mut body: &[u8]) -> Result<Vec<u8>, String> {
+        let digest: [u8; DIGEST_LEN] = take(&mut body, DIGEST_LEN)?.try_into().unwrap();
+        let size = take_u64(&mut body)?;
+        let chunk_size = u32::from_be_bytes(take(&mut body, 4)?.try_into().unwrap()) as usize;
+        let name = String::from_utf8(body.to_vec()).map_err(|_| "file name is not UTF-8")?;
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\', '\0']) {
+            return Err(format!("bad file name: {:?}", name));
+        }
+        if chunk_size == 0 {
+            return Err("chunk size is zero".into());
+        }
+        if let Some(incoming) = self.incoming.get(&digest) {
+            if incoming.size == size && incoming.chunk_size == chunk_size {
+                return Ok(answer(CONTINUE, incoming.received));
+            }
+        }
+
+        // resume from the whole chunks of a partial file, left by an interrupted transfer
+        let path = self.partial_path(&digest);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+        let (hashes, received) = chunk_hashes(&self.vault, &mut file, chunk_size, size)?;
+        file.set_len(received).map_err(|e| e.to_string())?;
+        file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+        if received > 0 {
+            println!("Resuming {} at {} of {} bytes", name, received, size);
+        } else {
+            println!("Receiving {} ({} bytes)", name, size);
+        }
+        self.incoming.insert(
+            digest,
+            Incoming {
+                name,
+                size,
+                chunk_size,
+                file,
+                hashes,
+                received,
+            },
+        );
+        self.received(&digest)
+    }
+
+    fn chunk(&mut self, mut body: &[u8]) -> Result<Vec<u8>, String> {
+        let digest: [u8; DIGEST_LEN] = take(&mut body, DIGEST_LEN)?.try_into().unwrap();
+        let offset = take_u64(&mut body)?;
+        let hash = take(&mut body, DIGEST_LEN)?;
+        let data = body;
+        let computed = sha256(&self.vault, data)?;
+        let incoming = match self.incoming.get_mut(&digest) {
+            Some(incoming) => incoming,
+            None => return Err("no such transfer was offered".into()),
+        };
+
+        // a chunk sent again, out of turn, or damaged is dropped, and the sender told where to
+        // send from
+        let whole = data.len() == incoming.chunk_size
+            || incoming.received + data.len() as u64 == incoming.size;
+        if offset != incoming.received || computed != hash || !whole {
+            return Ok(answer(CONTINUE, incoming.received));
+        }
+        if incoming.received + data.len() as u64 > incoming.size {
+            return Err("chunk runs past the end of the file".into());
+        }
+        incoming
+            .file
+            .write_all(data)
+            .map_err(|e| format!("failed to write {}: {}", incoming.name, e))?;
+        incoming.hashes.push(computed);
+        incoming.received += data.len() as u64;
+        self.received(&digest)
+    }
+
+    // The answer to send for a transfer which may have completed with the last chunk.
+    fn received(&mut self, digest: &[u8; DIGEST_LEN]) -> Result<Vec<u8>, String> {
+        let incoming = &self.incoming[digest];
+        if incoming.received < incoming.size {
+            return Ok(answer(CONTINUE, incoming.received));
+        }
+        let incoming = self.incoming.remove(digest).unwrap();
+        let path = self.partial_path(digest);
+        if file_digest(&self.vault, &incoming.hashes)? != *digest {
+            let _ = fs::remove_file(&path);
+            return Err(format!("{} doesn't match its digest", incoming.name));
+        }
+        incoming
+            .file
+            .sync_all()
+            .map_err(|e| format!("failed to write {}: {}", incoming.name, e))?;
+        fs::rename(&path, self.dir.join(&incoming.name))
+            .map_err(|e| format!("failed to move {} into place: {}", incoming.name, e))?;
+        println!("Received {} ({} bytes)", incoming.name, incoming.size);
+        Ok(answer(DONE, incoming.size))
+    }
+}
+
+impl WorkerHandler for Receiver {
+    fn handle_message(&mut self, _config: &Config, msg: OckamMessage) -> Result<(), String> {
+        let payload = match crate::request::decode(&msg.message_body) {
+            Some((_, payload)) => payload,
+            None => {
+                eprintln!("transfer: refused a message with no correlation ID");
+                return Ok(());
+            }
+        };
+        let result = match payload.split_first() {
+            Some((&OFFER, body)) => self.offered(body),
+            Some((&CHUNK, body)) => self.chunk(body),
+            _ => Err("unknown transfer request".into()),
+        };
+        let answer = result.unwrap_or_else(|e| {
+            eprintln!("transfer: {}", e);
+            refusal(&e)
+        });
+        self.replier.respond(&msg, &answer)
+    }
+}
+
+/// Sends a file to a file-transfer service, in chunks of `chunk_size` bytes, retrying each
+/// request `retries` times before giving up. Sending the same file again resumes where the
+/// service's partial copy ends.
+pub struct Upload {
+    pub route: Route,
+    pub chunk_size: usize,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl Upload {
+    /// Send the file at `path` as `name`, hashed by `vault`, calling `progress` with the bytes
+    /// the service holds and the size of the file after each answer.
+    pub fn send(
+        &self,
+        requester: &mut Requester,
+        vault: &SharedVault,
+        path: &Path,
+        name: &str,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<(), String> {
+        let mut file =
+            File::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+        let size = file.metadata().map_err(|e| e.to_string())?.len();
+        let (hashes, _) = chunk_hashes(vault, &mut file, self.chunk_size, size)?;
+        let digest = file_digest(vault, &hashes)?;
+
+        let mut request = offer(&digest, size, self.chunk_size as u32, name);
+        let mut data = vec![0; self.chunk_size];
+        loop {
+            let (status, offset) = decode_answer(&self.request(requester, &request)?)?;
+            progress(offset.min(size), size);
+            if status == DONE {
+                return Ok(());
+            }
+            if offset >= size {
+                return Err("the receiver holds the whole file but didn't accept it".into());
+            }
+            let index = (offset / self.chunk_size as u64) as usize;
+            let len = self.chunk_size.min((size - offset) as usize);
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|e| e.to_string())?;
+            if read_full(&mut file, &mut data[..len])? < len {
+                return Err(format!("{} changed while sending it", path.display()));
+            }
+            let hash = hashes.get(index).ok_or("bad offset from the receiver")?;
+            request = chunk(&digest, offset, hash, &data[..len]);
+        }
+    }
+
+    fn request(&self, requester: &mut Requester, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let mut attempts = 0;
+        loop {
+            match requester.send_request(self.route.clone(), payload, self.timeout) {
+                Ok(answer) => return Ok(answer),
+                Err(e) if attempts >= self.retries => return Err(e),
+                Err(_) => attempts += 1,
+            }
+        }
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "ockamd send",
+    about = "Send a file, e.g. a firmware image, over a secure channel to a responder running with `--transfer-dir`; an interrupted transfer resumes when sent again."
+)]
+pub struct SendArgs {
+    #[structopt(
+        help = "Route to the responder, e.g. udp://127.0.0.1:4050 or several comma-separated hops"
+    )]
+    route: OutputKind,
+
+    #[structopt(parse(from_os_str), help = "File to send")]
+    file: PathBuf,
+
+    #[structopt(long, help = "Name to store the file under, by default its own")]
+    name: Option<String>,
+
+    #[structopt(
+        long,
+        default_value = "8192",
+        help = "Bytes of the file sent in each message; with the headers of the chunk, the channel and the route, each must fit the transport's largest message"
+    )]
+    chunk_size: usize,
+
+    #[structopt(
+        long,
+        default_value = "5000",
+        help = "Milliseconds to wait for the channel, or for each chunk to be acknowledged"
+    )]
+    timeout_ms: u64,
+
+    #[structopt(
+        long,
+        default_value = "5",
+        help = "Times each chunk is sent again before giving up"
+    )]
+    retries: u32,
+
+    #[structopt(long, help = "Expected public key of the responder's identity")]
+    service_public_key: Option<String>,
+
+    #[structopt(
+        long,
+        default_value = "0.0.0.0:0",
+        help = "Local node address and port to bind"
+    )]
+    local_socket: SocketAddr,
+
+    #[structopt(
+        parse(from_os_str),
+        long,
+        default_value = DEFAULT_VAULT_PATH,
+        help = "Filepath on disk of the filesystem vault, for an existing identity key"
+    )]
+    vault_path: PathBuf,
+
+    #[structopt(
+        long,
+        default_value = FILENAME_KEY_DEFAULT,
+        help = "Name of the identity key in the vault"
+    )]
+    identity_name: String,
+}
+
+/// Handle `ockamd send ROUTE FILE [OPTIONS]`.
+pub fn main(args: &[OsString]) -> Result<(), String> {
+    // parse as if `send` were the program name, so that usage reads `ockamd send ...`
+    let args = SendArgs::from_iter(&args[1..]);
+    let route = match args.route.clone() {
+        OutputKind::Channel(route) => route,
+        OutputKind::Stdout => return Err("send needs a route to a responder".into()),
+    };
+    let name = match &args.name {
+        Some(name) => name.clone(),
+        None => args
+            .file
+            .file_name()
+            .ok_or("the file has no name")?
+            .to_string_lossy()
+            .into_owned(),
+    };
+    if args.chunk_size == 0 {
+        return Err("the chunk size must be at least one byte".into());
+    }
+
+    let config: Config = Args::initiator(
+        route.clone(),
+        TRANSFER_SERVICE_ADDRESS,
+        args.service_public_key.clone(),
+        args.local_socket,
+        args.vault_path.clone(),
+        args.identity_name.clone(),
+    )
+    .into();
+    let (node, router_tx) = Node::new(&config);
+    let vault = node.vault().ok_or("the node has no vault")?;
+
+    // this node has no other workers, so answers and channel announcements all arrive here
+    let (tx, rx) = mpsc::channel();
+    router_tx
+        .send(OckamCommand::Router(RouterCommand::Register(
+            AddressType::Worker,
+            tx,
+        )))
+        .map_err(|e| format!("failed to register send worker: {}", e))?;
+
+    let send_addr = RouterAddress::worker_router_address_from_str(SEND_ADDRESS).unwrap();
+    node.channel_tx
+        .send(OckamCommand::Channel(ChannelCommand::Initiate(
+            route,
+            send_addr.address.clone(),
+            None,
+            None,
+        )))
+        .map_err(|e| format!("failed to initiate channel: {}", e))?;
+
+    let sent_name = name.clone();
+    let sender = thread::spawn(move || {
+        let timeout = Duration::from_millis(args.timeout_ms);
+        let result = (|| {
+            // the channel manager announces the completed key exchange with the remote key
+            let channel = loop {
+                match rx.recv_timeout(timeout) {
+                    Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)))
+                        if matches!(msg.message_type, MessageType::None) =>
+                    {
+                        let public_key = hex::encode(&msg.message_body);
+                        if args
+                            .service_public_key
+                            .as_ref()
+                            .map(|expected| *expected != public_key)
+                            .unwrap_or(false)
+                        {
+                            return Err("remote public key doesn't match expected".to_string());
+                        }
+                        break msg.return_route.addresses[0].clone();
+                    }
+                    Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)))
+                        if matches!(msg.message_type, MessageType::ChannelError) =>
+                    {
+                        return Err(format!(
+                            "key exchange failed with error {:#010x}",
+                            channel_error_code(&msg).unwrap_or_default()
+                        ));
+                    }
+                    Ok(_) => {}
+                    Err(_) => return Err("timed out waiting for the secure channel".into()),
+                }
+            };
+            let upload = Upload {
+                route: Route {
+                    addresses: vec![
+                        channel,
+                        RouterAddress::worker_router_address_from_str(TRANSFER_SERVICE_ADDRESS)
+                            .unwrap(),
+                    ],
+                },
+                chunk_size: args.chunk_size,
+                timeout,
+                retries: args.retries,
+            };
+            let mut requester = Requester::new(router_tx, send_addr, rx);
+            let mut reported = None;
+            upload.send(
+                &mut requester,
+                &vault,
+                &args.file,
+                &sent_name,
+                &mut |sent, size| {
+                    // report each tenth of the file once
+                    let tenth = (sent * 10).checked_div(size).unwrap_or(10);
+                    if reported.map(|r| tenth > r).unwrap_or(true) {
+                        reported = Some(tenth);
+                        println!("{} of {} bytes sent ({}%)", sent, size, tenth * 10);
+                    }
+                },
+            )
+        })();
+        node::stop();
+        result
+    });
+    node.run();
+
+    sender
+        .join()
+        .map_err(|_| "send thread panicked".to_string())??;
+    println!("Sent {}", name);
+    Ok(())
+}
+
+#[test]
+fn test_transfer_resume() {
+    use ockam_vault::software::DefaultVault;
+
+    let dir = std::env::temp_dir().join("ockamd_test_transfer_resume");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let vault: SharedVault = Arc::new(Mutex::new(DefaultVault::default()));
+    let (router_tx, router_rx) = mpsc::channel();
+    let addr = RouterAddress::worker_router_address_from_str(TRANSFER_SERVICE_ADDRESS).unwrap();
+    let receiver = || Receiver {
+        dir: dir.clone(),
+        vault: vault.clone(),
+        replier: Replier::new(router_tx.clone(), addr.clone()),
+        incoming: HashMap::new(),
+    };
+    let config = Config::default();
+    let request = |receiver: &mut Receiver, payload: Vec<u8>| -> Vec<u8> {
+        let msg = OckamMessage {
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::worker_router_address_from_str(SEND_ADDRESS).unwrap()
+                ],
+            },
+            message_type: MessageType::Payload,
+            message_body: crate::request::encode(7, &payload),
+            ..Default::default()
+        };
+        receiver.handle_message(&config, msg).unwrap();
+        match router_rx.try_recv() {
+            Ok(OckamCommand::Router(RouterCommand::SendMessage(msg))) => {
+                crate::request::decode(&msg.message_body)
+                    .unwrap()
+                    .1
+                    .to_vec()
+            }
+            _ => panic!("no answer"),
+        }
+    };
+
+    // a file of two whole chunks and a short one
+    let data: Vec<u8> = (0..10u8).collect();
+    let chunks: Vec<&[u8]> = data.chunks(4).collect();
+    let hashes: Vec<_> = chunks.iter().map(|c| sha256(&vault, c).unwrap()).collect();
+    let digest = file_digest(&vault, &hashes).unwrap();
+    let offered = offer(&digest, 10, 4, "firmware.bin");
+
+    let mut first = receiver();
+    assert_eq!(request(&mut first, offered.clone()), answer(CONTINUE, 0));
+    assert_eq!(
+        request(&mut first, chunk(&digest, 0, &hashes[0], chunks[0])),
+        answer(CONTINUE, 4)
+    );
+    // a damaged chunk, or one out of turn, is dropped
+    assert_eq!(
+        request(&mut first, chunk(&digest, 4, &hashes[1], b"xxxx")),
+        answer(CONTINUE, 4)
+    );
+    assert_eq!(
+        request(&mut first, chunk(&digest, 8, &hashes[2], chunks[2])),
+        answer(CONTINUE, 4)
+    );
+
+    // a receiver started afresh resumes from the partial file
+    let mut second = receiver();
+    assert_eq!(request(&mut second, offered), answer(CONTINUE, 4));
+    assert_eq!(
+        request(&mut second, chunk(&digest, 4, &hashes[1], chunks[1])),
+        answer(CONTINUE, 8)
+    );
+    assert_eq!(
+        request(&mut second, chunk(&digest, 8, &hashes[2], chunks[2])),
+        answer(DONE, 10)
+    );
+    assert_eq!(fs::read(dir.join("firmware.bin")).unwrap(), data);
+    assert!(!second.partial_path(&digest).exists());
+
+    // a file which doesn't match its digest is discarded, and names can't leave the directory
+    let offered = offer(&[0; DIGEST_LEN], 4, 4, "bad.bin");
+    assert_eq!(request(&mut second, offered), answer(CONTINUE, 0));
+    let refused = request(
+        &mut second,
+        chunk(&[0; DIGEST_LEN], 0, &hashes[0], chunks[0]),
+    );
+    assert!(decode_answer(&refused).is_err());
+    assert!(!dir.join("bad.bin").exists());
+    let refused = request(&mut second, offer(&digest, 10, 4, "../escape"));
+    assert!(decode_answer(&refused).is_err());
+    let _ = fs::remove_dir_all(&dir);
+}
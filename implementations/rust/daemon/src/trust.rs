@@ -0,0 +1,151 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use ockam_channel::protocol::Role;
+use ockam_channel::TrustPolicy;
+
+/// The static public keys of the initiators a responder completes channels with.
+pub struct AllowList {
+    keys: BTreeSet<Vec<u8>>,
+}
+
+impl AllowList {
+    /// Combine hex-encoded keys given as options with those listed in `file`, one per line.
+    /// Returns `None` when neither is given, in which case every initiator is accepted.
+    pub fn load(keys: &[String], file: Option<&Path>) -> Result<Option<AllowList>, String> {
+        let mut lines = keys.to_vec();
+        if let Some(path) = file {
+            let contents = std::fs::read_to_string(path).map_err(|e| {
+                format!(
+                    "failed to read allowed initiators file {}: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+            lines.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(String::from),
+            );
+        } else if keys.is_empty() {
+            return Ok(None);
+        }
+
+        let keys = lines
+            .iter()
+            .map(|k| hex::decode(k).map_err(|_| format!("bad initiator public key: {}", k)))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(AllowList { keys }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.keys.contains(key)
+    }
+}
+
+/// Which remote parties a node completes channels with: the responder holding the service
+/// public key, when one is given, and the initiators on the allow list, when there is one.
+#[derive(Default)]
+pub struct Policy {
+    responder: Option<Vec<u8>>,
+    initiators: Option<AllowList>,
+}
+
+impl Policy {
+    /// Expect the responders of initiated channels to hold the hex-encoded `key`.
+    pub fn with_responder_key(mut self, key: &str) -> Result<Self, String> {
+        let key = hex::decode(key).map_err(|_| format!("bad service public key: {}", key))?;
+        self.responder = Some(key);
+        Ok(self)
+    }
+
+    /// Only complete channels with the initiators on `allow_list`.
+    pub fn with_initiators(mut self, allow_list: AllowList) -> Self {
+        self.initiators = Some(allow_list);
+        self
+    }
+
+    /// Whether the policy trusts every party, so needn't be consulted.
+    pub fn is_open(&self) -> bool {
+        self.responder.is_none() && self.initiators.is_none()
+    }
+}
+
+impl TrustPolicy for Policy {
+    fn is_trusted(&self, role: Role, remote_static_public_key: &[u8]) -> bool {
+        let refusal = match role {
+            Role::Initiator => match &self.responder {
+                Some(key) if key.as_slice() != remote_static_public_key => {
+                    "not the service public key, possible spoofing"
+                }
+                _ => return true,
+            },
+            Role::Responder => match &self.initiators {
+                Some(allow_list) if !allow_list.contains(remote_static_public_key) => {
+                    "not an allowed initiator"
+                }
+                _ => return true,
+            },
+        };
+        let peer = match role {
+            Role::Initiator => "responder",
+            Role::Responder => "initiator",
+        };
+        eprintln!(
+            "refused channel with {} {}: {}",
+            peer,
+            hex::encode(remote_static_public_key),
+            refusal
+        );
+        false
+    }
+}
+
+#[test]
+fn test_trust_allow_list() {
+    assert!(AllowList::load(&[], None).unwrap().is_none());
+    assert!(AllowList::load(&["zz".into()], None).is_err());
+
+    let path = std::env::temp_dir().join("ockamd_test_trust_allow_list");
+    std::fs::write(&path, "# enrolled devices\n\n  0a0b \nFFFF\n").unwrap();
+    let allow_list = AllowList::load(&["0102".into()], Some(&path))
+        .unwrap()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(allow_list.len(), 3);
+    assert!(allow_list.contains(&[1, 2]));
+    assert!(allow_list.contains(&[0x0a, 0x0b]));
+    assert!(allow_list.contains(&[0xff, 0xff]));
+    assert!(!allow_list.contains(&[1, 3]));
+}
+
+#[test]
+fn test_trust_policy() {
+    assert!(Policy::default().is_open());
+    assert!(Policy::default().is_trusted(Role::Initiator, &[1, 2]));
+    assert!(Policy::default().with_responder_key("zz").is_err());
+
+    let allow_list = AllowList::load(&["0102".into()], None).unwrap().unwrap();
+    let policy = Policy::default()
+        .with_responder_key("0a0b")
+        .unwrap()
+        .with_initiators(allow_list);
+    assert!(!policy.is_open());
+
+    // each end of a channel is checked against its own keys
+    assert!(policy.is_trusted(Role::Initiator, &[0x0a, 0x0b]));
+    assert!(!policy.is_trusted(Role::Initiator, &[1, 2]));
+    assert!(policy.is_trusted(Role::Responder, &[1, 2]));
+    assert!(!policy.is_trusted(Role::Responder, &[0x0a, 0x0b]));
+}
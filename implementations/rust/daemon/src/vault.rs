@@ -0,0 +1,142 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{Config, Role, VaultBackend};
+use crate::identity;
+
+use ockam_identity::Identity;
+use ockam_vault::{file::FilesystemVault, software::DefaultVault, DynVault};
+use zeroize::Zeroize;
+
+/// Open the vault backend selected by `--vault`, which holds the node's identity key and the
+/// keys of its channels.
+pub fn open(config: &Config) -> Result<Arc<Mutex<dyn DynVault + Send>>, String> {
+    match config.vault() {
+        VaultBackend::Filesystem => {
+            // switch to a rotated identity key once its overlap window has passed
+            if let Err(e) = identity::promote_staged(&config.vault_path(), &config.identity_name())
+            {
+                eprintln!("{}", e);
+            }
+
+            let vault = open_filesystem(
+                &config.vault_path(),
+                config.vault_passphrase_file().as_deref(),
+            )?;
+            Ok(Arc::new(Mutex::new(vault)))
+        }
+        VaultBackend::Memory => Ok(Arc::new(Mutex::new(DefaultVault::default()))),
+    }
+}
+
+/// Open the filesystem vault at `path`, sealed with the passphrase in `passphrase_file` if one is
+/// given. A trailing newline in the file is not part of the passphrase.
+pub fn open_filesystem(
+    path: &Path,
+    passphrase_file: Option<&Path>,
+) -> Result<FilesystemVault, String> {
+    let vault = match passphrase_file {
+        Some(file) => {
+            let mut passphrase = std::fs::read(file).map_err(|e| {
+                format!("failed to read vault passphrase {}: {}", file.display(), e)
+            })?;
+            while passphrase.ends_with(b"\n") || passphrase.ends_with(b"\r") {
+                passphrase.pop();
+            }
+            if passphrase.is_empty() {
+                return Err(format!("empty vault passphrase in {}", file.display()));
+            }
+            let vault = FilesystemVault::with_passphrase(path.to_path_buf(), &passphrase);
+            passphrase.zeroize();
+            vault
+        }
+        None => FilesystemVault::new(path.to_path_buf()),
+    };
+    vault.map_err(|e| format!("failed to open vault {}: {}", path.display(), e))
+}
+
+/// Load the identity the node's role calls for. The responder's identity must survive
+/// restarts so that initiators can pin its public key, which only the filesystem vault allows;
+/// an initiator only uses an identity key that is already in the vault.
+pub fn identity(
+    config: &Config,
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+) -> Result<Option<Identity>, String> {
+    let name = config.identity_name();
+    let path = config.vault_path();
+    match (config.role(), config.vault()) {
+        (Role::Responder, VaultBackend::Filesystem) | (Role::Both, VaultBackend::Filesystem) => {
+            identity::load_or_generate(&mut *vault.lock().unwrap(), &path, &name)?;
+            identity::load(vault, &path, &name).map(Some)
+        }
+        (Role::Responder, VaultBackend::Memory) | (Role::Both, VaultBackend::Memory) => {
+            let ctx = vault
+                .lock()
+                .unwrap()
+                .secret_generate(identity::identity_attributes())
+                .map_err(|e| format!("failed to generate identity key: {}", e))?;
+            println!("Generated an identity key in memory; it is lost when ockamd stops");
+            Identity::from_key(vault, ctx)
+                .map(Some)
+                .map_err(|e| format!("failed to create identity: {}", e))
+        }
+        (Role::Initiator, _) if identity::contains_key(&mut *vault.lock().unwrap(), &name) => {
+            identity::load(vault, &path, &name).map(Some)
+        }
+        (Role::Initiator, _) | (Role::Relay, _) => Ok(None),
+    }
+}
+
+#[test]
+fn test_vault_memory_initiator() {
+    let args = crate::cli::Args::load(
+        vec![
+            "ockamd",
+            "--vault",
+            "MEMORY",
+            "--vault-path",
+            "/nonexistent",
+            "--service-public-key",
+            "00",
+            "--service-address",
+            "01242020",
+        ]
+        .into_iter()
+        .map(std::ffi::OsString::from),
+    )
+    .unwrap();
+    let config = Config::from(args);
+    assert_eq!(config.vault(), VaultBackend::Memory);
+
+    // the memory vault starts empty, so an initiator has no identity key and uses an ephemeral
+    // one for each channel, and nothing is created at the vault path
+    let vault = open(&config).unwrap();
+    let identity = identity(&config, vault).unwrap();
+    assert!(identity.is_none());
+    assert!(!config.vault_path().exists());
+}
+
+#[test]
+fn test_sealed_filesystem_vault() {
+    let path = std::env::temp_dir().join("ockamd_test_sealed_vault");
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    let passphrase = path.join("passphrase");
+    std::fs::write(&passphrase, "correct horse\n").unwrap();
+
+    let vault = open_filesystem(&path.join("vault"), Some(&passphrase)).unwrap();
+    assert!(vault.is_sealed());
+    assert!(open_filesystem(&path.join("vault"), None).is_err());
+
+    // the newline isn't part of the passphrase
+    std::fs::write(&passphrase, "correct horse").unwrap();
+    assert!(open_filesystem(&path.join("vault"), Some(&passphrase)).is_ok());
+    std::fs::write(&passphrase, "battery staple\n").unwrap();
+    let wrong = open_filesystem(&path.join("vault"), Some(&passphrase)).unwrap_err();
+    assert!(wrong.contains("wrong passphrase"), "{}", wrong);
+
+    std::fs::write(&passphrase, "\n").unwrap();
+    assert!(open_filesystem(&path.join("vault"), Some(&passphrase)).is_err());
+    assert!(open_filesystem(&path.join("vault"), Some(&path.join("missing"))).is_err());
+    std::fs::remove_dir_all(&path).unwrap();
+}
@@ -0,0 +1,34 @@
+use std::sync::mpsc::Sender;
+
+use ockam_system::commands::{OckamCommand, RouterCommand, WorkerCommand};
+
+/// Ends the wait of a thread blocked until it has work, for a thread which hands it work other
+/// than through the channel it blocks on, e.g. a configuration update or an input record.
+#[derive(Clone)]
+pub struct Waker {
+    tx: Sender<OckamCommand>,
+    wake: fn() -> OckamCommand,
+}
+
+impl Waker {
+    /// Wakes the node polling the router `router_tx` sends to.
+    pub fn router(router_tx: Sender<OckamCommand>) -> Self {
+        Waker {
+            tx: router_tx,
+            wake: || OckamCommand::Router(RouterCommand::Wake),
+        }
+    }
+
+    /// Wakes the thread of a worker blocked on the inbox `tx` sends to.
+    pub fn worker(tx: Sender<OckamCommand>) -> Self {
+        Waker {
+            tx,
+            wake: || OckamCommand::Worker(WorkerCommand::Wake),
+        }
+    }
+
+    pub fn wake(&self) {
+        // a thread which has gone has nothing left to do
+        let _ = self.tx.send((self.wake)());
+    }
+}
@@ -1,96 +1,1857 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
 use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
+use crate::config::{Config, ConfigUpdate, Overflow};
+use crate::post::{self, Local, Poster};
+use crate::request;
+use crate::wake::Waker;
 
-use ockam_message::message::{AddressType, Message as OckamMessage, MessageType, RouterAddress};
-use ockam_system::commands::{OckamCommand, RouterCommand, WorkerCommand};
+use futures::executor::block_on;
+use ockam_channel::protocol::channel_error_code;
+use ockam_message::message::{
+    AddressType, Message as OckamMessage, MessageType, Route, RouterAddress,
+};
+use ockam_system::commands::{
+    Component, OckamCommand, ReplyTo, RequestId, Response, RouterCommand, WorkerCommand,
+};
 
-type WorkFn = fn(self_worker: &Worker, msg: OckamMessage);
+/// The messages and events a worker handles, given its current configuration. The handler
+/// owns whatever state it needs, e.g. connections, buffers and counters. An error, like a panic,
+/// fails the worker, which is then restarted by its supervisor.
+pub trait WorkerHandler {
+    /// Handle a payload sent to the worker's address.
+    fn handle_message(&mut self, config: &Config, msg: OckamMessage) -> Result<(), String>;
+
+    /// Called when a remote node completes a secure channel to this one, with the channel's
+    /// address and the remote node's static public key.
+    fn on_channel_established(
+        &mut self,
+        _config: &Config,
+        _channel: &RouterAddress,
+        _remote_public_key: &[u8],
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called when a secure channel announced to this worker closes, at either end, with the
+    /// channel's address.
+    fn on_channel_closed(
+        &mut self,
+        _config: &Config,
+        _channel: &RouterAddress,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called when the key exchange of a secure channel this worker initiated fails, with the
+    /// channel's address and the code of the channel error, e.g. to initiate it again.
+    fn on_channel_failed(
+        &mut self,
+        _config: &Config,
+        _channel: &RouterAddress,
+        _code: u32,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Handle a value sent by a worker on the node's thread with a `Poster`, which arrives
+    /// without having been encoded. By default it is encoded and handled as a payload.
+    fn handle_local(&mut self, config: &Config, local: Local) -> Result<(), String> {
+        let msg = local.into_message()?;
+        self.handle_message(config, msg)
+    }
+
+    /// How often `on_tick` is called while the handler runs, if at all. Asked again after each
+    /// tick, and after each message while the handler has no ticks, so the interval may follow
+    /// the configuration.
+    fn tick_interval(&self, _config: &Config) -> Option<Duration> {
+        None
+    }
+
+    /// Called every `tick_interval`, in turn with the worker's messages, e.g. to flush a batch
+    /// or poll an external resource. An error fails the worker, as for a message.
+    fn on_tick(&mut self, _config: &Config) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called with the answer to a request the worker made of another component, e.g. the
+    /// router, giving `Worker::reply_to` for the request's ID. An error fails the worker, as for
+    /// a message.
+    fn on_response(
+        &mut self,
+        _config: &Config,
+        _id: RequestId,
+        _result: Result<Response, String>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called before the handler is dropped, when the worker is stopped or restarted, or the
+    /// node stops.
+    fn shutdown(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A closure handles payloads only.
+impl<F: FnMut(&Config, OckamMessage) -> Result<(), String>> WorkerHandler for F {
+    fn handle_message(&mut self, config: &Config, msg: OckamMessage) -> Result<(), String> {
+        self(config, msg)
+    }
+}
+
+/// The async variant of `WorkerHandler`, for handlers which wait on network I/O, e.g. HTTP
+/// requests or database writes. Wrapped in an `Async`, the handler runs on a thread of its own,
+/// so waiting holds up only its own worker's messages rather than the node's poll loop.
+// the futures are only run on the handler's own thread, so they needn't be Send
+#[allow(async_fn_in_trait)]
+pub trait AsyncWorkerHandler: Send + 'static {
+    /// Handle a payload sent to the worker's address.
+    async fn handle_message(&mut self, config: &Config, msg: OckamMessage) -> Result<(), String>;
+
+    /// Called once the messages already taken have been handled, before the handler is dropped.
+    async fn shutdown(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// how often an `Async` checks for the results of messages in flight
+const ASYNC_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs an `AsyncWorkerHandler` on its own thread, as the `WorkerHandler` of a worker. Each
+/// message is passed on without waiting for it to be handled; an error handling it fails the
+/// worker once seen, at the worker's next message or tick, as it would a synchronous handler.
+pub struct Async {
+    jobs: Option<Sender<(Config, OckamMessage)>>,
+    results: Receiver<Result<(), String>>,
+    // messages passed on whose results haven't been seen yet
+    in_flight: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Async {
+    pub fn new<H: AsyncWorkerHandler>(mut handler: H) -> Self {
+        let (jobs, job_rx) = mpsc::channel::<(Config, OckamMessage)>();
+        let (result_tx, results) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for (config, msg) in job_rx.iter() {
+                let _ = result_tx.send(block_on(handler.handle_message(&config, msg)));
+            }
+            let _ = result_tx.send(block_on(handler.shutdown()));
+        });
+        Async {
+            jobs: Some(jobs),
+            results,
+            in_flight: 0,
+            thread: Some(thread),
+        }
+    }
+
+    // Take the results of the messages handled so far, returning the first error.
+    fn collect(&mut self) -> Result<(), String> {
+        let mut result = Ok(());
+        loop {
+            match self.results.try_recv() {
+                Ok(r) => {
+                    self.in_flight = self.in_flight.saturating_sub(1);
+                    result = result.and(r);
+                }
+                Err(TryRecvError::Empty) => return result,
+                Err(TryRecvError::Disconnected) => {
+                    return result.and(Err("async handler panicked".into()))
+                }
+            }
+        }
+    }
+}
+
+impl WorkerHandler for Async {
+    fn handle_message(&mut self, config: &Config, msg: OckamMessage) -> Result<(), String> {
+        self.collect()?;
+        let jobs = self.jobs.as_ref().ok_or("async handler has shut down")?;
+        jobs.send((config.clone(), msg))
+            .map_err(|_| "async handler panicked".to_string())?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    fn tick_interval(&self, _config: &Config) -> Option<Duration> {
+        if self.in_flight > 0 {
+            Some(ASYNC_POLL_INTERVAL)
+        } else {
+            None
+        }
+    }
+
+    fn on_tick(&mut self, _config: &Config) -> Result<(), String> {
+        self.collect()
+    }
+
+    fn shutdown(&mut self) -> Result<(), String> {
+        // waits for the messages in flight, then the handler's own shutdown
+        self.jobs = None;
+        let panicked = match self.thread.take() {
+            Some(thread) => thread.join().is_err(),
+            None => false,
+        };
+        let mut result = Ok(());
+        while let Ok(r) = self.results.try_recv() {
+            result = result.and(r);
+        }
+        self.in_flight = 0;
+        if panicked {
+            return result.and(Err("async handler panicked".into()));
+        }
+        result
+    }
+}
+
+/// Creates a worker's handler when it starts, and again each time it is restarted, so that a
+/// failed worker begins again with fresh state, e.g. a new addon connection.
+pub type MakeHandler = Box<dyn FnMut(&Config) -> Result<Box<dyn WorkerHandler>, String>>;
+
+/// Sends replies from a worker address; handlers capture one to answer requests.
+#[derive(Clone)]
+pub struct Replier {
+    router_tx: Sender<OckamCommand>,
+    addr: RouterAddress,
+}
+
+impl Replier {
+    pub fn new(router_tx: Sender<OckamCommand>, addr: RouterAddress) -> Self {
+        Replier { router_tx, addr }
+    }
+
+    /// Send `payload` back to the sender of `original`. Each hop a message passes through adds
+    /// itself to the front of the return route, so the return route is the route back as is,
+    /// and the reply's own return route is this worker.
+    pub fn reply(&self, original: &OckamMessage, payload: Vec<u8>) -> Result<(), String> {
+        if original.return_route.addresses.is_empty() {
+            return Err("can't reply to a message without a return route".into());
+        }
+        let reply = OckamMessage {
+            onward_route: original.return_route.clone(),
+            return_route: Route {
+                addresses: vec![self.addr.clone()],
+            },
+            message_type: MessageType::Payload,
+            message_body: payload,
+        };
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(reply)))
+            .map_err(|_| "failed to send reply to router".to_string())
+    }
+
+    /// Answer a request sent with `Requester::send_request`, with the same correlation ID.
+    pub fn respond(&self, request: &OckamMessage, payload: &[u8]) -> Result<(), String> {
+        let (id, _) =
+            request::decode(&request.message_body).ok_or("request has no correlation ID")?;
+        self.reply(request, request::encode(id, payload))
+    }
+}
+
+/// A message a worker rejected, with the reason; commands other than messages are rejected
+/// without one.
+pub struct DeadLetter {
+    pub worker: String,
+    pub reason: String,
+    pub message: Option<OckamMessage>,
+}
+
+// an entry in a worker's mailbox
+enum Mail {
+    Command(OckamCommand),
+    Local(Local),
+    // at most one tick waits in the mailbox at a time
+    Tick,
+}
+
+// the wait before the first restart of a failed worker, doubled for each further failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+enum State {
+    Running(Box<dyn WorkerHandler>),
+    Stopped,
+    // restarted at the given time, or left failed once the restarts are used up
+    Failed(Option<Instant>),
+}
 
 #[allow(dead_code)]
 pub struct Worker {
     router_tx: Sender<OckamCommand>,
     rx: Receiver<OckamCommand>,
     tx: Sender<OckamCommand>,
+    local_rx: Receiver<Local>,
+    // kept so that `local_rx` never disconnects
+    local_tx: Sender<Local>,
+    // the ID of the worker's registration for values from `Poster`s
+    local_id: u64,
     addr: RouterAddress,
-    work_fn: WorkFn,
+    // the messages waiting to be handled, at most `--worker-mailbox-size` of them
+    mailbox: VecDeque<Mail>,
+    // when the running handler is next due a tick, if it asked for them
+    next_tick: Option<Instant>,
+    make_handler: MakeHandler,
+    state: State,
+    failures: u32,
+    backoff: Duration,
+    dropped: u64,
+    rejected: u64,
+    processed: u64,
+    last_error: Option<String>,
+    dead_letters: Option<Sender<DeadLetter>>,
     config: Config,
+    update_rx: Receiver<ConfigUpdate>,
+    update_tx: Sender<ConfigUpdate>,
 }
 
 impl Worker {
+    /// Create and start a worker, failing if its first handler can't be created.
     pub fn new(
         addr: RouterAddress,
         router_tx: Sender<OckamCommand>,
         config: Config,
-        work_fn: WorkFn,
-    ) -> Self {
-        debug_assert!(matches!(addr.a_type, AddressType::Worker));
-
-        let (tx, rx) = mpsc::channel();
+        make_handler: MakeHandler,
+    ) -> Result<Self, String> {
+        let worker = Self::detached(addr, router_tx, config, make_handler)?;
 
         // register the worker with the router
-        let cmd = OckamCommand::Router(RouterCommand::Register(AddressType::Worker, tx.clone()));
-        router_tx.send(cmd).expect("failed to register worker");
+        let cmd = OckamCommand::Router(RouterCommand::Register(
+            AddressType::Worker,
+            worker.sender(),
+        ));
+        worker
+            .router_tx
+            .send(cmd)
+            .expect("failed to register worker");
 
-        println!("Service address: {}", addr.address.as_string());
+        println!("Service address: {}", worker.name());
+        Ok(worker)
+    }
+
+    /// Create and start a worker without registering it with the router, for a worker which
+    /// receives its messages through `dispatch`, e.g. a session spawned by a `Factory`.
+    pub fn detached(
+        addr: RouterAddress,
+        router_tx: Sender<OckamCommand>,
+        config: Config,
+        mut make_handler: MakeHandler,
+    ) -> Result<Self, String> {
+        debug_assert!(matches!(addr.a_type, AddressType::Worker));
 
-        Worker {
+        let handler = supervised(|| make_handler(&config))?;
+        let (tx, rx) = mpsc::channel();
+        let (local_tx, local_rx) = mpsc::channel();
+        let (update_tx, update_rx) = mpsc::channel();
+        let local_id = post::register(&addr, local_tx.clone());
+
+        let mut worker = Worker {
             router_tx,
             rx,
             tx,
+            local_rx,
+            local_tx,
+            local_id,
             addr,
+            mailbox: VecDeque::new(),
+            next_tick: None,
+            make_handler,
+            state: State::Running(handler),
+            failures: 0,
+            backoff: INITIAL_BACKOFF,
+            dropped: 0,
+            rejected: 0,
+            processed: 0,
+            last_error: None,
+            dead_letters: None,
             config,
-            work_fn,
-        }
+            update_rx,
+            update_tx,
+        };
+        worker.schedule_tick();
+        Ok(worker)
     }
 
     pub fn sender(&self) -> Sender<OckamCommand> {
         self.tx.clone()
     }
 
+    pub fn address(&self) -> RouterAddress {
+        self.addr.clone()
+    }
+
     pub fn config(&self) -> Config {
         self.config.clone()
     }
 
-    pub fn poll(&self) -> bool {
-        match self.rx.try_recv() {
-            Ok(cmd) => match cmd {
-                OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)) => {
-                    match msg.message_type {
-                        MessageType::Payload => {
-                            // Confirm address
-                            if self.addr != msg.onward_route.addresses[0] {
-                                println!("Received bad worker address");
-                                return true;
-                            }
-                            (self.work_fn)(&self, msg);
-                            true
-                        }
-                        MessageType::None => true,
-                        _ => unimplemented!(),
+    /// Send `payload` back to the sender of `original`, from this worker's address.
+    pub fn reply(&self, original: &OckamMessage, payload: Vec<u8>) -> Result<(), String> {
+        self.replier().reply(original, payload)
+    }
+
+    pub fn replier(&self) -> Replier {
+        Replier::new(self.router_tx.clone(), self.addr.clone())
+    }
+
+    /// Where the answer to the request `id` goes, for a request made of another component, e.g.
+    /// `RouterCommand::GetInfo`. The answer is passed to the handler's `on_response`.
+    pub fn reply_to(&self, id: RequestId) -> ReplyTo {
+        ReplyTo::new(id, Component::Worker, self.sender())
+    }
+
+    /// A `Poster` sending typed payloads from this worker's address.
+    pub fn poster(&self) -> Poster {
+        Poster::new(self.router_tx.clone(), self.addr.clone())
+    }
+
+    /// Sender used to deliver configuration changes to this worker while it runs.
+    pub fn config_sender(&self) -> Sender<ConfigUpdate> {
+        self.update_tx.clone()
+    }
+
+    /// "running", "stopped", "restarting" while waiting to restart after a failure, or "failed"
+    /// once the restarts allowed by `--worker-max-restarts` are used up.
+    pub fn status(&self) -> &'static str {
+        match self.state {
+            State::Running(_) => "running",
+            State::Stopped => "stopped",
+            State::Failed(Some(_)) => "restarting",
+            State::Failed(None) => "failed",
+        }
+    }
+
+    /// Whether the worker has been told to stop, after which the node drops it.
+    pub fn stopped(&self) -> bool {
+        matches!(self.state, State::Stopped)
+    }
+
+    /// Consecutive failures since the worker last handled a message.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Messages dropped because the worker wasn't running, or its mailbox was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Messages waiting in the worker's mailbox.
+    pub fn depth(&self) -> usize {
+        self.mailbox.len()
+    }
+
+    /// Whether the worker's mailbox is full and set to block, in which case the node stops
+    /// reading from the network until the worker catches up.
+    pub fn blocking(&self) -> bool {
+        self.config.worker_overflow() == Overflow::Block && self.mailbox_full()
+    }
+
+    fn mailbox_full(&self) -> bool {
+        self.mailbox.len() >= self.config.worker_mailbox_size().max(1)
+    }
+
+    // Move the commands the router has sent into the mailbox, applying `--worker-overflow`
+    // once it is full. A blocking mailbox leaves them in the channel instead, which stops
+    // growing once the node stops reading from the network.
+    fn fill(&mut self) -> Result<(), TryRecvError> {
+        loop {
+            if self.blocking() {
+                return Ok(());
+            }
+            let mail = match self.rx.try_recv() {
+                Ok(cmd) => Mail::Command(cmd),
+                Err(TryRecvError::Empty) => match self.local_rx.try_recv() {
+                    Ok(local) => Mail::Local(local),
+                    Err(_) => return Ok(()),
+                },
+                Err(e) => return Err(e),
+            };
+            if self.mailbox_full() {
+                self.dropped += 1;
+                match self.config.worker_overflow() {
+                    Overflow::DropNewest => continue,
+                    _ => {
+                        self.mailbox.pop_front();
                     }
                 }
-                _ => {
-                    eprintln!("unrecognized worker command: {:?}", cmd);
-                    false
+            }
+            self.mailbox.push_back(mail);
+        }
+    }
+
+    /// When the worker next has something to do without being sent anything: now if messages
+    /// are waiting, or else its next tick or restart. A node waiting for work polls it by then.
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        if self.fill().is_err() || !self.mailbox.is_empty() {
+            return Some(Instant::now());
+        }
+        match self.state {
+            State::Failed(Some(at)) => Some(at),
+            _ => self.next_tick,
+        }
+    }
+
+    // When the running handler is next due a tick, counting from now.
+    fn schedule_tick(&mut self) {
+        self.next_tick = match &self.state {
+            State::Running(handler) => handler
+                .tick_interval(&self.config)
+                .map(|interval| Instant::now() + interval),
+            _ => None,
+        };
+    }
+
+    /// Start a stopped or failed worker with a new handler.
+    pub fn start(&mut self) -> Result<(), String> {
+        if let State::Running(_) = self.state {
+            return Err(format!("worker {} is already running", self.name()));
+        }
+        self.failures = 0;
+        self.backoff = INITIAL_BACKOFF;
+        let config = &self.config;
+        let make_handler = &mut self.make_handler;
+        let handler = supervised(|| make_handler(config))?;
+        self.state = State::Running(handler);
+        self.schedule_tick();
+        Ok(())
+    }
+
+    /// Stop the worker, which drops the messages it receives until it is started again.
+    pub fn stop(&mut self) -> Result<(), String> {
+        if let State::Stopped = self.state {
+            return Err(format!("worker {} is already stopped", self.name()));
+        }
+        self.shutdown()
+    }
+
+    pub fn restart(&mut self) -> Result<(), String> {
+        if let Err(e) = self.shutdown() {
+            eprintln!("{}", e);
+        }
+        self.start()
+    }
+
+    /// Shut down the running handler, leaving the worker stopped.
+    pub fn shutdown(&mut self) -> Result<(), String> {
+        self.next_tick = None;
+        if let State::Running(mut handler) = std::mem::replace(&mut self.state, State::Stopped) {
+            supervised(|| handler.shutdown())
+                .map_err(|e| format!("worker {} failed to shut down: {}", self.name(), e))?;
+        }
+        Ok(())
+    }
+
+    pub fn poll(&mut self) -> bool {
+        while let Ok(update) = self.update_rx.try_recv() {
+            self.config.apply(&update);
+        }
+
+        if let State::Failed(Some(at)) = self.state {
+            if Instant::now() >= at {
+                self.recover();
+            }
+        }
+
+        if let Err(e) = self.fill() {
+            eprintln!("failed to recv worker rx: {:?}", e);
+            return false;
+        }
+
+        // the next tick is scheduled once this one is handled
+        if let Some(at) = self.next_tick {
+            if Instant::now() >= at {
+                self.next_tick = None;
+                self.mailbox.push_back(Mail::Tick);
+            }
+        }
+
+        match self.mailbox.pop_front() {
+            Some(Mail::Tick) => self.tick(),
+            Some(Mail::Local(local)) => self.handle_local(local),
+            // a message another worker on this node sent is delivered as if it had arrived
+            Some(Mail::Command(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg))))
+            | Some(Mail::Command(OckamCommand::Worker(WorkerCommand::SendMessage(msg)))) => {
+                match msg.message_type {
+                    MessageType::Payload
+                        if msg.onward_route.addresses.first() != Some(&self.addr) =>
+                    {
+                        self.reject("addressed to another worker".into(), Some(msg))
+                    }
+                    MessageType::Payload => self.handle(msg),
+                    // a remote node completed a channel to this one, or a channel closed or failed
+                    MessageType::None | MessageType::Close | MessageType::ChannelError => {
+                        self.handle(msg)
+                    }
+                    message_type => self.reject(
+                        format!("unexpected message type {:?}", message_type),
+                        Some(msg),
+                    ),
                 }
+            }
+            Some(Mail::Command(OckamCommand::Worker(WorkerCommand::Stop))) => {
+                if let Err(e) = self.shutdown() {
+                    eprintln!("{}", e);
+                }
+            }
+            Some(Mail::Command(OckamCommand::Worker(WorkerCommand::Wake))) => {}
+            Some(Mail::Command(OckamCommand::Worker(WorkerCommand::Response(id, response)))) => {
+                self.answered(id, Ok(response))
+            }
+            Some(Mail::Command(OckamCommand::Worker(WorkerCommand::Error(id, e)))) => {
+                self.answered(id, Err(e))
+            }
+            Some(Mail::Command(cmd)) => {
+                self.reject(format!("unrecognized command {:?}", cmd), None)
+            }
+            None => {}
+        }
+        true
+    }
+
+    /// Messages passed to the worker's handler, whether or not it handled them successfully.
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    /// The error or panic which last failed the worker, if it has ever failed.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Messages the worker couldn't handle, e.g. of an unexpected type.
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Send the messages the worker rejects to `tx`, as well as logging and counting them.
+    pub fn set_dead_letters(&mut self, tx: Sender<DeadLetter>) {
+        self.dead_letters = Some(tx);
+    }
+
+    fn reject(&mut self, reason: String, message: Option<OckamMessage>) {
+        self.rejected += 1;
+        eprintln!("worker {} rejected a message: {}", self.name(), reason);
+        if let Some(tx) = &self.dead_letters {
+            let letter = DeadLetter {
+                worker: self.name(),
+                reason,
+                message,
+            };
+            if tx.send(letter).is_err() {
+                self.dead_letters = None;
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        self.addr.address.as_string()
+    }
+
+    fn handle(&mut self, msg: OckamMessage) {
+        let handler = match &mut self.state {
+            State::Running(handler) => handler,
+            _ => {
+                self.dropped += 1;
+                return;
+            }
+        };
+        self.processed += 1;
+        let config = &self.config;
+        let result = supervised(|| match msg.message_type {
+            MessageType::None => match msg.return_route.addresses.first() {
+                Some(channel) => handler.on_channel_established(config, channel, &msg.message_body),
+                None => Ok(()),
+            },
+            MessageType::Close => match msg.return_route.addresses.first() {
+                Some(channel) => handler.on_channel_closed(config, channel),
+                None => Ok(()),
             },
-            Err(e) => match e {
-                TryRecvError::Empty => true,
-                _ => {
-                    eprintln!("failed to recv worker rx: {:?}", e);
-                    false
+            MessageType::ChannelError => match msg.return_route.addresses.first() {
+                Some(channel) => {
+                    let code = channel_error_code(&msg).unwrap_or_default();
+                    handler.on_channel_failed(config, channel, code)
                 }
+                None => Ok(()),
             },
+            _ => handler.handle_message(config, msg),
+        });
+        self.handled(result);
+    }
+
+    fn handle_local(&mut self, local: Local) {
+        let handler = match &mut self.state {
+            State::Running(handler) => handler,
+            _ => {
+                self.dropped += 1;
+                return;
+            }
+        };
+        self.processed += 1;
+        let config = &self.config;
+        let result = supervised(|| handler.handle_local(config, local));
+        self.handled(result);
+    }
+
+    fn handled(&mut self, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                self.backoff = INITIAL_BACKOFF;
+                let tick_pending = self.mailbox.iter().any(|m| matches!(m, Mail::Tick));
+                if self.next_tick.is_none() && !tick_pending {
+                    self.schedule_tick();
+                }
+            }
+            Err(e) => self.fail(&e),
+        }
+    }
+
+    fn answered(&mut self, id: RequestId, result: Result<Response, String>) {
+        let handler = match &mut self.state {
+            State::Running(handler) => handler,
+            _ => {
+                self.dropped += 1;
+                return;
+            }
+        };
+        let config = &self.config;
+        if let Err(e) = supervised(|| handler.on_response(config, id, result)) {
+            self.fail(&e);
+        }
+    }
+
+    fn tick(&mut self) {
+        let handler = match &mut self.state {
+            State::Running(handler) => handler,
+            _ => return,
+        };
+        let config = &self.config;
+        match supervised(|| handler.on_tick(config)) {
+            Ok(()) => self.schedule_tick(),
+            Err(e) => self.fail(&e),
+        }
+    }
+
+    // Drop the failed handler, and schedule a restart unless the restarts are used up.
+    fn fail(&mut self, e: &str) {
+        self.last_error = Some(e.to_string());
+        if let Err(e) = self.shutdown() {
+            eprintln!("{}", e);
+        }
+        let max_restarts = self.config.worker_max_restarts();
+        if self.failures < max_restarts {
+            self.failures += 1;
+            let wait = self.backoff.min(self.config.max_backoff());
+            eprintln!(
+                "worker {} failed: {}; restarting in {}s ({} of {})",
+                self.name(),
+                e,
+                wait.as_secs(),
+                self.failures,
+                max_restarts
+            );
+            self.state = State::Failed(Some(Instant::now() + wait));
+            self.backoff = (self.backoff * 2).min(self.config.max_backoff());
+        } else {
+            eprintln!(
+                "worker {} failed: {}; giving up after {} restarts",
+                self.name(),
+                e,
+                max_restarts
+            );
+            self.state = State::Failed(None);
+        }
+    }
+
+    fn recover(&mut self) {
+        let config = &self.config;
+        let make_handler = &mut self.make_handler;
+        match supervised(|| make_handler(config)) {
+            Ok(handler) => {
+                println!("worker {} restarted", self.name());
+                self.state = State::Running(handler);
+                self.schedule_tick();
+            }
+            Err(e) => self.fail(&e),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        post::unregister(&self.addr, self.local_id);
+    }
+}
+
+// Run `f`, turning a panic into an error.
+fn supervised<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| Err("panicked".into()))
+}
+
+/// Adds the workers a `Factory` spawns to the running node, and routes their messages to them.
+#[derive(Clone)]
+pub struct Spawner {
+    worker_tx: Sender<Worker>,
+    route_tx: Sender<(RouterAddress, Sender<OckamCommand>)>,
+}
+
+impl Spawner {
+    /// `worker_tx` is the node's, from `Node::worker_sender`, and the routes go to `dispatch`.
+    pub fn new(
+        worker_tx: Sender<Worker>,
+        route_tx: Sender<(RouterAddress, Sender<OckamCommand>)>,
+    ) -> Self {
+        Spawner {
+            worker_tx,
+            route_tx,
         }
     }
+
+    pub fn spawn(&self, worker: Worker) -> Result<(), String> {
+        self.route_tx
+            .send((worker.address(), worker.sender()))
+            .map_err(|_| "failed to add worker to dispatch".to_string())?;
+        self.worker_tx
+            .send(worker)
+            .map_err(|_| "failed to add worker to node".to_string())
+    }
+}
+
+// the most sessions a factory keeps, beyond which the one idle longest is stopped for a new
+// sender, and how long a session may go without a message before it is stopped
+const MAX_SESSIONS: usize = 256;
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// a worker spawned by a factory: its address and sender, and when it was last sent a message
+#[derive(Clone)]
+struct Session {
+    addr: RouterAddress,
+    tx: Sender<OckamCommand>,
+    active: Instant,
+}
+
+// the sessions of a `Factory`, by the return route of their sender
+type Sessions = HashMap<Vec<(u8, String)>, Session>;
+
+/// Spawns a dedicated worker for each sender of the messages to a service, e.g. to keep the
+/// state of each device apart. A sender is known by its return route; its session's address is
+/// the service's address followed by a counter, and replies from the session come from there,
+/// so the sender may address the session directly afterwards. Sessions outlive a restart of the
+/// factory's own worker, and are stopped once the channel they were started for closes, or once
+/// idle for a while.
+#[derive(Clone)]
+pub struct Factory {
+    addr: RouterAddress,
+    router_tx: Sender<OckamCommand>,
+    spawner: Spawner,
+    make_session: Rc<RefCell<dyn FnMut() -> MakeHandler>>,
+    sessions: Rc<RefCell<Sessions>>,
+    next: Rc<Cell<u32>>,
+    max_sessions: usize,
+    idle_timeout: Duration,
+}
+
+impl Factory {
+    pub fn new(
+        addr: RouterAddress,
+        router_tx: Sender<OckamCommand>,
+        spawner: Spawner,
+        make_session: impl FnMut() -> MakeHandler + 'static,
+    ) -> Self {
+        Factory {
+            addr,
+            router_tx,
+            spawner,
+            make_session: Rc::new(RefCell::new(make_session)),
+            sessions: Rc::new(RefCell::new(HashMap::new())),
+            next: Rc::new(Cell::new(0)),
+            max_sessions: MAX_SESSIONS,
+            idle_timeout: SESSION_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Keep at most `max_sessions`, stopping each once it has gone `idle_timeout` without a
+    /// message.
+    pub fn with_limits(mut self, max_sessions: usize, idle_timeout: Duration) -> Self {
+        self.max_sessions = max_sessions.max(1);
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// The handler of the factory's worker, at the service's address.
+    pub fn make_handler(self) -> MakeHandler {
+        Box::new(
+            move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                Ok(Box::new(self.clone()))
+            },
+        )
+    }
+
+    // Start a worker for a new sender, with the factory's current configuration.
+    fn spawn_session(&self, config: &Config) -> Result<Session, String> {
+        let mut address = self.addr.address.as_string();
+        address.push_str(&hex::encode(self.next.get().to_be_bytes()));
+        self.next.set(self.next.get() + 1);
+
+        let addr = RouterAddress::worker_router_address_from_str(&address)?;
+        let make_handler = (*self.make_session.borrow_mut())();
+        let worker = Worker::detached(addr, self.router_tx.clone(), config.clone(), make_handler)?;
+        let session = Session {
+            addr: worker.address(),
+            tx: worker.sender(),
+            active: Instant::now(),
+        };
+        self.spawner.spawn(worker)?;
+        println!("Started session {}", address);
+        Ok(session)
+    }
+
+    // Stop the sessions of the senders `matching`; the node drops their workers once stopped.
+    fn stop_sessions(&self, matching: impl Fn(&[(u8, String)], &Session) -> bool) {
+        let mut sessions = self.sessions.borrow_mut();
+        let stopped: Vec<_> = sessions
+            .iter()
+            .filter(|(sender, session)| matching(sender, session))
+            .map(|(sender, _)| sender.clone())
+            .collect();
+        for sender in stopped {
+            if let Some(session) = sessions.remove(&sender) {
+                let _ = session.tx.send(OckamCommand::Worker(WorkerCommand::Stop));
+                println!("Stopped session {}", session.addr.address.as_string());
+            }
+        }
+    }
+
+    // Make room for a new sender's session by stopping the one idle longest.
+    fn make_room(&self) {
+        let oldest = match self.sessions.borrow().values().map(|s| s.active).min() {
+            Some(oldest) => oldest,
+            None => return,
+        };
+        if self.sessions.borrow().len() >= self.max_sessions {
+            self.stop_sessions(|_, session| session.active == oldest);
+        }
+    }
+}
+
+impl WorkerHandler for Factory {
+    fn handle_message(&mut self, config: &Config, mut msg: OckamMessage) -> Result<(), String> {
+        let sender: Vec<(u8, String)> = msg
+            .return_route
+            .addresses
+            .iter()
+            .map(|a| (a.a_type as u8, a.address.as_string()))
+            .collect();
+        let existing = self.sessions.borrow_mut().get_mut(&sender).map(|session| {
+            session.active = Instant::now();
+            session.clone()
+        });
+        let session = match existing {
+            Some(session) => session,
+            None => {
+                self.make_room();
+                let session = self.spawn_session(config)?;
+                self.sessions.borrow_mut().insert(sender, session.clone());
+                session
+            }
+        };
+
+        // the session's worker only accepts messages for its own address
+        msg.onward_route.addresses[0] = session.addr;
+        session
+            .tx
+            .send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)))
+            .map_err(|_| "failed to pass message to session".to_string())
+    }
+
+    // a sender's return route starts with the channel its messages arrived on
+    fn on_channel_closed(
+        &mut self,
+        _config: &Config,
+        channel: &RouterAddress,
+    ) -> Result<(), String> {
+        let channel = (channel.a_type as u8, channel.address.as_string());
+        self.stop_sessions(|sender, _| sender.first() == Some(&channel));
+        Ok(())
+    }
+
+    fn tick_interval(&self, _config: &Config) -> Option<Duration> {
+        Some(self.idle_timeout)
+    }
+
+    fn on_tick(&mut self, _config: &Config) -> Result<(), String> {
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout;
+        self.stop_sessions(|_, session| now.duration_since(session.active) >= idle_timeout);
+        Ok(())
+    }
+}
+
+/// Share the router's single worker handler between several workers: each message goes to the
+/// workers registered for its onward address, and any other message to `default`. Workers added
+/// later, e.g. by a `Spawner`, arrive on `added`. Registers with the router, so it must be
+/// called after the workers have registered themselves. Returns the dispatching thread, which
+/// passes a stop from the router on to every worker and ends.
+pub fn dispatch(
+    router_tx: &Sender<OckamCommand>,
+    workers: Vec<(RouterAddress, Sender<OckamCommand>)>,
+    default: Sender<OckamCommand>,
+    added: Receiver<(RouterAddress, Sender<OckamCommand>)>,
+) -> JoinHandle<()> {
+    Dispatch::new(router_tx, workers, default, added).spawn(router_tx)
+}
+
+/// The router's single worker handler shared between several workers, as `dispatch` starts it
+/// on a thread of its own, for a node to poll instead on its own thread.
+pub struct Dispatch {
+    rx: Receiver<OckamCommand>,
+    workers: Vec<(RouterAddress, Sender<OckamCommand>)>,
+    default: Sender<OckamCommand>,
+    added: Receiver<(RouterAddress, Sender<OckamCommand>)>,
+}
+
+impl Dispatch {
+    /// Register with the router, after the workers have registered themselves.
+    pub fn new(
+        router_tx: &Sender<OckamCommand>,
+        workers: Vec<(RouterAddress, Sender<OckamCommand>)>,
+        default: Sender<OckamCommand>,
+        added: Receiver<(RouterAddress, Sender<OckamCommand>)>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<OckamCommand>();
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Worker,
+                tx,
+            )))
+            .expect("failed to register worker dispatch");
+        Dispatch {
+            rx,
+            workers,
+            default,
+            added,
+        }
+    }
+
+    /// Pass on the commands the router has sent so far, returning false once told to stop.
+    pub fn poll(&mut self) -> bool {
+        while let Ok(cmd) = self.rx.try_recv() {
+            if !self.pass_on(cmd) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Pass the commands on as they arrive, on a thread of its own.
+    fn spawn(mut self, router_tx: &Sender<OckamCommand>) -> JoinHandle<()> {
+        // the workers are polled by the node, which is woken once a message has been passed on
+        let waker = Waker::router(router_tx.clone());
+        std::thread::spawn(move || {
+            while let Ok(cmd) = self.rx.recv() {
+                if !self.pass_on(cmd) {
+                    break;
+                }
+                waker.wake();
+            }
+        })
+    }
+
+    fn pass_on(&mut self, cmd: OckamCommand) -> bool {
+        self.workers.extend(self.added.try_iter());
+        // the node is stopping, so every worker is told to stop too
+        if let OckamCommand::Worker(WorkerCommand::Stop) = cmd {
+            for tx in self
+                .workers
+                .iter()
+                .map(|(_, tx)| tx)
+                .chain(Some(&self.default))
+            {
+                let _ = tx.send(OckamCommand::Worker(WorkerCommand::Stop));
+            }
+            return false;
+        }
+        let sent = match &cmd {
+            OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg))
+            | OckamCommand::Worker(WorkerCommand::SendMessage(msg)) => {
+                // a worker which has gone, e.g. a stopped session, is forgotten, and a message
+                // none of its workers took goes to the default
+                let mut delivered = false;
+                self.workers.retain(|(addr, tx)| {
+                    if msg.onward_route.addresses.first() != Some(addr) {
+                        return true;
+                    }
+                    let sent = tx
+                        .send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(
+                            msg.clone(),
+                        )))
+                        .is_ok();
+                    delivered |= sent;
+                    sent
+                });
+                if delivered {
+                    Some(true)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let sent = sent.unwrap_or_else(|| self.default.send(cmd).is_ok());
+        if !sent {
+            eprintln!("failed to dispatch worker command");
+        }
+        true
+    }
 }
 
 #[test]
 fn test_ockamd_worker() {
     let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
     let (fake_router_tx, fake_router_rx) = mpsc::channel();
-    Worker::new(addr, fake_router_tx, Default::default(), |_, _| {});
+    Worker::new(
+        addr,
+        fake_router_tx,
+        Default::default(),
+        Box::new(|_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            Ok(Box::new(|_: &Config, _: OckamMessage| Ok(())))
+        }),
+    )
+    .unwrap();
 
     assert!(fake_router_rx.recv().is_ok());
 }
+
+#[test]
+fn test_worker_dispatch() {
+    use ockam_message::message::Route;
+
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, fake_router_rx) = mpsc::channel();
+    let (worker_tx, worker_rx) = mpsc::channel();
+    let (default_tx, default_rx) = mpsc::channel();
+    let (added_tx, added_rx) = mpsc::channel();
+    dispatch(
+        &fake_router_tx,
+        vec![(addr.clone(), worker_tx)],
+        default_tx,
+        added_rx,
+    );
+
+    let dispatch_tx = match fake_router_rx.recv().unwrap() {
+        OckamCommand::Router(RouterCommand::Register(AddressType::Worker, tx)) => tx,
+        _ => panic!("expected the dispatcher to register with the router"),
+    };
+    let msg = |to: &str| {
+        OckamCommand::Worker(WorkerCommand::ReceiveMessage(OckamMessage {
+            onward_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str(to).unwrap()],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: to.as_bytes().to_vec(),
+        }))
+    };
+    dispatch_tx.send(msg("00000001")).unwrap();
+    dispatch_tx.send(msg("01242020")).unwrap();
+
+    // a worker added while the dispatcher runs gets the messages sent after it was added
+    let (session_tx, session_rx) = mpsc::channel();
+    let session = RouterAddress::worker_router_address_from_str("0124202000000000").unwrap();
+    added_tx.send((session, session_tx)).unwrap();
+    dispatch_tx.send(msg("0124202000000000")).unwrap();
+
+    let body = |cmd: OckamCommand| match cmd {
+        OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)) => msg.message_body,
+        _ => vec![],
+    };
+    assert_eq!(body(worker_rx.recv().unwrap()), b"01242020");
+    assert_eq!(body(default_rx.recv().unwrap()), b"00000001");
+    assert_eq!(body(session_rx.recv().unwrap()), b"0124202000000000");
+}
+
+#[test]
+fn test_worker_supervision() {
+    use ockam_message::message::Route;
+    use std::sync::{Arc, Mutex};
+
+    let config = crate::cli::Args::load(
+        vec![
+            "ockamd",
+            "--role",
+            "responder",
+            "--max-backoff-secs",
+            "0",
+            "--worker-max-restarts",
+            "2",
+        ]
+        .into_iter()
+        .map(std::ffi::OsString::from),
+    )
+    .unwrap()
+    .into();
+
+    // every message fails the worker, which counts its starts
+    let starts = Arc::new(Mutex::new(0));
+    let counter = starts.clone();
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, _fake_router_rx) = mpsc::channel();
+    let mut worker = Worker::new(
+        addr.clone(),
+        fake_router_tx,
+        config,
+        Box::new(
+            move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                *counter.lock().unwrap() += 1;
+                Ok(Box::new(|_: &Config, msg: OckamMessage| {
+                    match msg.message_body[0] {
+                        0 => Err("bad message".into()),
+                        _ => panic!("worse message"),
+                    }
+                }))
+            },
+        ),
+    )
+    .unwrap();
+    let msg = |body: u8| {
+        OckamCommand::Worker(WorkerCommand::ReceiveMessage(OckamMessage {
+            onward_route: Route {
+                addresses: vec![addr.clone()],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: vec![body],
+        }))
+    };
+
+    // an error and a panic are each followed by a restart, then the worker gives up
+    let tx = worker.sender();
+    for body in &[0, 1, 0] {
+        tx.send(msg(*body)).unwrap();
+        assert!(worker.poll());
+        assert!(worker.poll());
+    }
+    assert_eq!(worker.status(), "failed");
+    assert_eq!(*starts.lock().unwrap(), 3);
+    assert_eq!(worker.processed(), 3);
+    assert_eq!(worker.last_error(), Some("bad message"));
+
+    tx.send(msg(0)).unwrap();
+    assert!(worker.poll());
+    assert_eq!(worker.dropped(), 1);
+    assert_eq!(worker.processed(), 3);
+
+    worker.start().unwrap();
+    assert_eq!(worker.status(), "running");
+    assert_eq!(worker.failures(), 0);
+    worker.stop().unwrap();
+    assert!(worker.stop().is_err());
+    worker.restart().unwrap();
+    assert_eq!(*starts.lock().unwrap(), 5);
+}
+
+#[test]
+fn test_worker_handler() {
+    use ockam_message::message::Route;
+    use std::sync::{Arc, Mutex};
+
+    // counts the messages, channels, shutdowns and closed channels it sees, and the error of
+    // the last channel to fail
+    type Counts = (usize, Vec<u8>, usize, usize, u32);
+    struct Counter(Arc<Mutex<Counts>>);
+    impl WorkerHandler for Counter {
+        fn handle_message(&mut self, _: &Config, _: OckamMessage) -> Result<(), String> {
+            self.0.lock().unwrap().0 += 1;
+            Ok(())
+        }
+        fn on_channel_established(
+            &mut self,
+            _: &Config,
+            _: &RouterAddress,
+            remote_public_key: &[u8],
+        ) -> Result<(), String> {
+            self.0.lock().unwrap().1 = remote_public_key.to_vec();
+            Ok(())
+        }
+        fn on_channel_closed(&mut self, _: &Config, _: &RouterAddress) -> Result<(), String> {
+            self.0.lock().unwrap().3 += 1;
+            Ok(())
+        }
+        fn on_channel_failed(
+            &mut self,
+            _: &Config,
+            _: &RouterAddress,
+            code: u32,
+        ) -> Result<(), String> {
+            self.0.lock().unwrap().4 = code;
+            Ok(())
+        }
+        fn shutdown(&mut self) -> Result<(), String> {
+            self.0.lock().unwrap().2 += 1;
+            Ok(())
+        }
+    }
+
+    let counts = Arc::new(Mutex::new((0, vec![], 0, 0, 0)));
+    let handler_counts = counts.clone();
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, _fake_router_rx) = mpsc::channel();
+    let mut worker = Worker::new(
+        addr.clone(),
+        fake_router_tx,
+        Default::default(),
+        Box::new(
+            move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                Ok(Box::new(Counter(handler_counts.clone())))
+            },
+        ),
+    )
+    .unwrap();
+
+    let channel = RouterAddress::channel_router_address_from_str("0a0b0c0d").unwrap();
+    let tx = worker.sender();
+    let messages = vec![
+        (MessageType::None, vec![7, 7]),
+        (MessageType::Payload, vec![]),
+        (MessageType::Close, vec![]),
+        (MessageType::ChannelError, vec![13, 0, 0, 8]),
+    ];
+    for (message_type, body) in messages {
+        tx.send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(
+            OckamMessage {
+                onward_route: Route {
+                    addresses: vec![addr.clone()],
+                },
+                return_route: Route {
+                    addresses: vec![channel.clone()],
+                },
+                message_type,
+                message_body: body,
+            },
+        )))
+        .unwrap();
+        assert!(worker.poll());
+    }
+    worker.restart().unwrap();
+    worker.shutdown().unwrap();
+
+    assert_eq!(*counts.lock().unwrap(), (1, vec![7, 7], 2, 1, 0x0800_000d));
+}
+
+#[test]
+fn test_worker_reply() {
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, fake_router_rx) = mpsc::channel();
+    let replier = Replier::new(fake_router_tx, addr.clone());
+
+    let channel = RouterAddress::channel_router_address_from_str("0a0b0c0d").unwrap();
+    let sender = RouterAddress::worker_router_address_from_str("70696e67").unwrap();
+    let request = OckamMessage {
+        onward_route: Route {
+            addresses: vec![addr.clone()],
+        },
+        return_route: Route {
+            addresses: vec![channel.clone(), sender.clone()],
+        },
+        message_type: MessageType::Payload,
+        message_body: b"ping".to_vec(),
+    };
+    replier.reply(&request, b"pong".to_vec()).unwrap();
+
+    match fake_router_rx.try_recv() {
+        Ok(OckamCommand::Router(RouterCommand::SendMessage(reply))) => {
+            assert_eq!(reply.onward_route.addresses, vec![channel, sender]);
+            assert_eq!(reply.return_route.addresses, vec![addr]);
+            assert_eq!(reply.message_body, b"pong");
+        }
+        _ => panic!("expected the reply to be sent to the router"),
+    }
+
+    let anonymous = OckamMessage {
+        return_route: Route { addresses: vec![] },
+        ..request
+    };
+    assert!(replier.reply(&anonymous, vec![]).is_err());
+}
+
+#[test]
+fn test_worker_mailbox() {
+    use ockam_message::message::Route;
+    use std::sync::{Arc, Mutex};
+
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let msg = |body: u8| {
+        OckamCommand::Worker(WorkerCommand::ReceiveMessage(OckamMessage {
+            onward_route: Route {
+                addresses: vec![addr.clone()],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: vec![body],
+        }))
+    };
+
+    // four messages arrive at once for a mailbox of two, and the worker then handles each
+    // message left in it
+    let cases = vec![
+        ("drop-oldest", vec![2, 3], 2),
+        ("drop-newest", vec![0, 1], 2),
+        ("block", vec![0, 1, 2, 3], 0),
+    ];
+    for (overflow, handled, dropped) in cases {
+        let config = crate::cli::Args::load(
+            vec![
+                "ockamd",
+                "--role",
+                "responder",
+                "--worker-mailbox-size",
+                "2",
+                "--worker-overflow",
+                overflow,
+            ]
+            .into_iter()
+            .map(std::ffi::OsString::from),
+        )
+        .unwrap()
+        .into();
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let handler_bodies = bodies.clone();
+        let (fake_router_tx, _fake_router_rx) = mpsc::channel();
+        let mut worker = Worker::new(
+            addr.clone(),
+            fake_router_tx,
+            config,
+            Box::new(
+                move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                    let bodies = handler_bodies.clone();
+                    Ok(Box::new(move |_: &Config, msg: OckamMessage| {
+                        bodies.lock().unwrap().push(msg.message_body[0]);
+                        Ok(())
+                    }))
+                },
+            ),
+        )
+        .unwrap();
+
+        let tx = worker.sender();
+        for body in 0..4 {
+            tx.send(msg(body)).unwrap();
+        }
+        worker.fill().unwrap();
+        assert_eq!(worker.depth(), 2);
+        assert_eq!(worker.blocking(), overflow == "block");
+        while worker.depth() > 0 || worker.blocking() {
+            assert!(worker.poll());
+        }
+        assert!(worker.poll());
+        assert_eq!(*bodies.lock().unwrap(), handled, "{}", overflow);
+        assert_eq!(worker.dropped(), dropped, "{}", overflow);
+    }
+}
+
+#[test]
+fn test_worker_factory() {
+    use std::sync::{Arc, Mutex};
+
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, _fake_router_rx) = mpsc::channel();
+    let (worker_tx, worker_rx) = mpsc::channel();
+    let (route_tx, route_rx) = mpsc::channel();
+
+    // each session records the payloads it handles under the number of its handler
+    let handled = Arc::new(Mutex::new(vec![]));
+    let session_handled = handled.clone();
+    let mut sessions = 0;
+    let factory = Factory::new(
+        addr.clone(),
+        fake_router_tx.clone(),
+        Spawner::new(worker_tx, route_tx),
+        move || -> MakeHandler {
+            let handled = session_handled.clone();
+            let session = sessions;
+            sessions += 1;
+            Box::new(
+                move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                    let handled = handled.clone();
+                    Ok(Box::new(move |_: &Config, msg: OckamMessage| {
+                        handled.lock().unwrap().push((session, msg.message_body));
+                        Ok(())
+                    }))
+                },
+            )
+        },
+    );
+    let mut worker = Worker::detached(
+        addr.clone(),
+        fake_router_tx,
+        Default::default(),
+        factory.make_handler(),
+    )
+    .unwrap();
+
+    let tx = worker.sender();
+    let send = |from: &str, body: u8| {
+        tx.send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(
+            OckamMessage {
+                onward_route: Route {
+                    addresses: vec![addr.clone()],
+                },
+                return_route: Route {
+                    addresses: vec![RouterAddress::channel_router_address_from_str(from).unwrap()],
+                },
+                message_type: MessageType::Payload,
+                message_body: vec![body],
+            },
+        )))
+        .unwrap();
+    };
+
+    // two senders get a session each, which a restart of the factory's worker doesn't change
+    send("0a0a0a0a", 1);
+    send("0b0b0b0b", 2);
+    assert!(worker.poll());
+    assert!(worker.poll());
+    worker.restart().unwrap();
+    send("0a0a0a0a", 3);
+    assert!(worker.poll());
+
+    let mut spawned: Vec<Worker> = worker_rx.try_iter().collect();
+    let addresses: Vec<String> = route_rx
+        .try_iter()
+        .map(|(a, _)| a.address.as_string())
+        .collect();
+    assert_eq!(addresses, vec!["0124202000000000", "0124202000000001"]);
+    assert_eq!(spawned.len(), 2);
+    for session in spawned.iter_mut() {
+        while session.poll() && session.depth() > 0 {}
+        assert!(session.poll());
+    }
+    assert_eq!(
+        *handled.lock().unwrap(),
+        vec![(0, vec![1]), (0, vec![3]), (1, vec![2])]
+    );
+}
+
+#[test]
+fn test_worker_factory_stops_sessions() {
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, _fake_router_rx) = mpsc::channel();
+    let (worker_tx, worker_rx) = mpsc::channel();
+    let (route_tx, _route_rx) = mpsc::channel();
+    let mut factory = Factory::new(
+        addr.clone(),
+        fake_router_tx,
+        Spawner::new(worker_tx, route_tx),
+        || -> MakeHandler {
+            Box::new(|_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                Ok(Box::new(|_: &Config, _: OckamMessage| Ok(())))
+            })
+        },
+    )
+    .with_limits(2, Duration::from_secs(60));
+    let config = Config::default();
+    let channel = |a: &str| RouterAddress::channel_router_address_from_str(a).unwrap();
+    let mut send = |from: &str| {
+        let msg = OckamMessage {
+            onward_route: Route {
+                addresses: vec![addr.clone()],
+            },
+            return_route: Route {
+                addresses: vec![channel(from)],
+            },
+            message_type: MessageType::Payload,
+            message_body: vec![],
+        };
+        factory.handle_message(&config, msg).unwrap();
+        thread::sleep(Duration::from_millis(2));
+    };
+
+    // a third sender stops the session idle longest, which isn't the one just sent to again
+    send("0a0a0a0a");
+    send("0b0b0b0b");
+    send("0a0a0a0a");
+    send("0c0c0c0c");
+    let mut spawned: Vec<Worker> = worker_rx.try_iter().collect();
+    let mut stopped = || -> Vec<bool> {
+        spawned
+            .iter_mut()
+            .map(|session| {
+                while session.poll() && session.depth() > 0 {}
+                session.poll();
+                session.stopped()
+            })
+            .collect()
+    };
+    assert_eq!(stopped(), vec![false, true, false]);
+
+    // a session stops once its sender's channel closes, or once idle
+    factory
+        .on_channel_closed(&config, &channel("0a0a0a0a"))
+        .unwrap();
+    assert_eq!(stopped(), vec![true, true, false]);
+    factory = factory.with_limits(2, Duration::from_secs(0));
+    factory.on_tick(&config).unwrap();
+    assert_eq!(stopped(), vec![true, true, true]);
+}
+
+#[test]
+fn test_worker_reject() {
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, _fake_router_rx) = mpsc::channel();
+    let mut worker = Worker::new(
+        addr.clone(),
+        fake_router_tx,
+        Default::default(),
+        Box::new(|_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+            Ok(Box::new(|_: &Config, _: OckamMessage| Ok(())))
+        }),
+    )
+    .unwrap();
+    let (dead_letter_tx, dead_letter_rx) = mpsc::channel();
+    worker.set_dead_letters(dead_letter_tx);
+
+    // neither an unexpected message type, a message for another address, nor a command other
+    // than a message stops the worker
+    let msg = |to: &RouterAddress, message_type| OckamMessage {
+        onward_route: Route {
+            addresses: vec![to.clone()],
+        },
+        return_route: Route { addresses: vec![] },
+        message_type,
+        message_body: vec![1, 2, 3],
+    };
+    let other = RouterAddress::worker_router_address_from_str("0a0b0c0d").unwrap();
+    let tx = worker.sender();
+    let cmds = vec![
+        WorkerCommand::ReceiveMessage(msg(&addr, MessageType::KeyAgreementM1)),
+        WorkerCommand::ReceiveMessage(msg(&other, MessageType::Payload)),
+        WorkerCommand::Test,
+        WorkerCommand::ReceiveMessage(msg(&addr, MessageType::Payload)),
+    ];
+    for cmd in cmds {
+        tx.send(OckamCommand::Worker(cmd)).unwrap();
+        assert!(worker.poll());
+    }
+    assert_eq!(worker.rejected(), 3);
+    assert_eq!(worker.status(), "running");
+
+    let letters: Vec<DeadLetter> = dead_letter_rx.try_iter().collect();
+    assert_eq!(
+        letters
+            .iter()
+            .map(|l| l.reason.as_str())
+            .collect::<Vec<_>>(),
+        vec![
+            "unexpected message type KeyAgreementM1",
+            "addressed to another worker",
+            "unrecognized command Worker(Test)"
+        ]
+    );
+    assert!(letters.iter().all(|l| l.worker == "01242020"));
+    assert_eq!(
+        letters[1].message.as_ref().unwrap().message_body,
+        vec![1, 2, 3]
+    );
+    assert!(letters[2].message.is_none());
+}
+
+#[test]
+fn test_worker_tick() {
+    use std::sync::{Arc, Mutex};
+
+    // ticks every 10ms, counting them, until it has had 3
+    struct Ticker(Arc<Mutex<u32>>);
+    impl WorkerHandler for Ticker {
+        fn handle_message(&mut self, _: &Config, _: OckamMessage) -> Result<(), String> {
+            Ok(())
+        }
+        fn tick_interval(&self, _: &Config) -> Option<Duration> {
+            match *self.0.lock().unwrap() {
+                n if n < 3 => Some(Duration::from_millis(10)),
+                _ => None,
+            }
+        }
+        fn on_tick(&mut self, _: &Config) -> Result<(), String> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    let ticks = Arc::new(Mutex::new(0));
+    let handler_ticks = ticks.clone();
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, _fake_router_rx) = mpsc::channel();
+    let mut worker = Worker::new(
+        addr,
+        fake_router_tx,
+        Default::default(),
+        Box::new(
+            move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                Ok(Box::new(Ticker(handler_ticks.clone())))
+            },
+        ),
+    )
+    .unwrap();
+
+    // a tick isn't delivered before it is due
+    assert!(worker.poll());
+    assert_eq!(*ticks.lock().unwrap(), 0);
+
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(200) {
+        assert!(worker.poll());
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(*ticks.lock().unwrap(), 3);
+    assert_eq!(worker.depth(), 0);
+
+    // a stopped worker gets no ticks
+    worker.restart().unwrap();
+    *ticks.lock().unwrap() = 0;
+    worker.stop().unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(worker.poll());
+    assert_eq!(*ticks.lock().unwrap(), 0);
+}
+
+#[test]
+fn test_worker_async() {
+    use std::sync::{Arc, Mutex};
+
+    // records each message once a slow "write" completes, failing on an empty one
+    struct Slow(Arc<Mutex<Vec<u8>>>);
+    impl AsyncWorkerHandler for Slow {
+        async fn handle_message(&mut self, _: &Config, msg: OckamMessage) -> Result<(), String> {
+            std::thread::sleep(Duration::from_millis(20));
+            let body = msg.message_body.first().ok_or("empty message")?;
+            self.0.lock().unwrap().push(*body);
+            Ok(())
+        }
+        async fn shutdown(&mut self) -> Result<(), String> {
+            self.0.lock().unwrap().push(0);
+            Ok(())
+        }
+    }
+
+    let handled = Arc::new(Mutex::new(vec![]));
+    let handler_handled = handled.clone();
+    let config = crate::cli::Args::load(
+        vec!["ockamd", "--role", "responder", "--max-backoff-secs", "0"]
+            .into_iter()
+            .map(std::ffi::OsString::from),
+    )
+    .unwrap()
+    .into();
+    let addr = RouterAddress::worker_router_address_from_str("01242020").unwrap();
+    let (fake_router_tx, _fake_router_rx) = mpsc::channel();
+    let mut worker = Worker::new(
+        addr.clone(),
+        fake_router_tx,
+        config,
+        Box::new(
+            move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                Ok(Box::new(Async::new(Slow(handler_handled.clone()))))
+            },
+        ),
+    )
+    .unwrap();
+    let msg = |body: Vec<u8>| {
+        OckamCommand::Worker(WorkerCommand::ReceiveMessage(OckamMessage {
+            onward_route: Route {
+                addresses: vec![addr.clone()],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: body,
+        }))
+    };
+
+    // the poll doesn't wait for the messages to be handled
+    let tx = worker.sender();
+    tx.send(msg(vec![1])).unwrap();
+    tx.send(msg(vec![2])).unwrap();
+    let started = Instant::now();
+    assert!(worker.poll());
+    assert!(worker.poll());
+    assert!(started.elapsed() < Duration::from_millis(20));
+    assert_eq!(worker.depth(), 0);
+
+    // stopping waits for them, then shuts the handler down
+    worker.stop().unwrap();
+    assert_eq!(*handled.lock().unwrap(), vec![1, 2, 0]);
+
+    // an error fails the worker once its result is seen, at a tick
+    handled.lock().unwrap().clear();
+    worker.start().unwrap();
+    tx.send(msg(vec![])).unwrap();
+    let started = Instant::now();
+    while worker.status() == "running" && started.elapsed() < Duration::from_secs(1) {
+        assert!(worker.poll());
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(worker.failures(), 1);
+    assert_eq!(*handled.lock().unwrap(), vec![0]);
+}
+
+#[test]
+fn test_worker_response() {
+    use ockam_router::router::Router;
+    use std::sync::{Arc, Mutex};
+
+    type Answers = Arc<Mutex<Vec<(RequestId, Result<Response, String>)>>>;
+    struct Requester(Answers);
+    impl WorkerHandler for Requester {
+        fn handle_message(&mut self, _: &Config, _: OckamMessage) -> Result<(), String> {
+            Ok(())
+        }
+        fn on_response(
+            &mut self,
+            _: &Config,
+            id: RequestId,
+            result: Result<Response, String>,
+        ) -> Result<(), String> {
+            self.0.lock().unwrap().push((id, result));
+            Ok(())
+        }
+    }
+
+    let (router_tx, router_rx) = mpsc::channel();
+    let mut router = Router::new(router_rx);
+    let answers: Answers = Arc::new(Mutex::new(vec![]));
+    let handler_answers = answers.clone();
+    let mut worker = Worker::new(
+        RouterAddress::worker_router_address_from_str("01242020").unwrap(),
+        router_tx.clone(),
+        Default::default(),
+        Box::new(
+            move |_: &Config| -> Result<Box<dyn WorkerHandler>, String> {
+                Ok(Box::new(Requester(handler_answers.clone())))
+            },
+        ),
+    )
+    .unwrap();
+
+    // the router answers with what it has handlers for, and errors are passed on too
+    router_tx
+        .send(OckamCommand::Router(RouterCommand::GetInfo(
+            worker.reply_to(7),
+        )))
+        .unwrap();
+    assert!(router.poll());
+    assert!(worker.poll());
+    worker.reply_to(8).answer(Err("refused".into())).unwrap();
+    assert!(worker.poll());
+    assert_eq!(
+        *answers.lock().unwrap(),
+        vec![
+            (7, Ok(Response::Info(vec![AddressType::Worker]))),
+            (8, Err("refused".to_string()))
+        ]
+    );
+    assert_eq!(worker.processed(), 0);
+}
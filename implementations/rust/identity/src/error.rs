@@ -0,0 +1,44 @@
+use failure::Fail;
+use ockam_common::error::{ErrorKind, ERROR_INTERFACE_IDENTITY};
+use ockam_vault::error::{VaultFailError, VaultFailErrorKind};
+
+/// Represents the failures that can occur
+/// creating, loading or rotating an identity
+#[derive(Clone, Copy, Fail, Debug)]
+#[non_exhaustive]
+pub enum IdentityErrorKind {
+    /// A key history or identifier couldn't be decoded
+    #[fail(display = "The identity is malformed")]
+    Malformed,
+    /// A change in the key history isn't signed by the key it replaces
+    #[fail(display = "The key history doesn't verify")]
+    History,
+    /// The key isn't the current key of the identity's history
+    #[fail(display = "The key doesn't match the identity")]
+    KeyMismatch,
+    /// An error occurred in the vault
+    #[fail(display = "An error occurred in the vault: {}", 0)]
+    Vault(VaultFailErrorKind),
+}
+
+impl ErrorKind for IdentityErrorKind {
+    const ERROR_INTERFACE: usize = ERROR_INTERFACE_IDENTITY;
+
+    fn to_usize(&self) -> usize {
+        match *self {
+            IdentityErrorKind::Malformed => Self::ERROR_INTERFACE | 1,
+            IdentityErrorKind::History => Self::ERROR_INTERFACE | 2,
+            IdentityErrorKind::KeyMismatch => Self::ERROR_INTERFACE | 3,
+            IdentityErrorKind::Vault(_) => Self::ERROR_INTERFACE | 4,
+        }
+    }
+}
+
+error_impl!(IdentityError, IdentityErrorKind);
+
+impl From<VaultFailError> for IdentityError {
+    fn from(err: VaultFailError) -> Self {
+        let kind = *err.kind();
+        Self::from_source(IdentityErrorKind::Vault(kind), err)
+    }
+}
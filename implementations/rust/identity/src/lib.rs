@@ -0,0 +1,406 @@
+//! Identities: a long-term key held in a vault, named by a stable identifier, the SHA-256 hash
+//! of the identity's first public key. When the key is rotated, the new public key is signed by
+//! the key it replaces and appended to the identity's key history, so that a peer holding the
+//! history can follow the identity from the identifier to its current key.
+
+#[macro_use]
+extern crate ockam_common;
+
+/// Represents the errors that occur creating, loading and rotating identities
+pub mod error;
+
+use crate::error::{IdentityError, IdentityErrorKind};
+use ockam_vault::types::{
+    PublicKey, SecretKeyAttributes, SecretKeyContext, SecretKeyType, SecretPersistenceType,
+    SecretPurposeType,
+};
+use ockam_vault::DynVault;
+use std::sync::{Arc, Mutex};
+
+// the version of the key history encoding, its first byte
+const HISTORY_VERSION: u8 = 1;
+const SIGNATURE_LEN: usize = 64;
+
+/// The stable identifier of an identity, which doesn't change when its key is rotated
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IdentityId([u8; 32]);
+
+impl IdentityId {
+    /// The identifier of the identity whose first public key is `public_key`
+    pub fn of(vault: &dyn DynVault, public_key: &[u8]) -> Result<IdentityId, IdentityError> {
+        Ok(IdentityId(vault.sha256(public_key)?))
+    }
+}
+
+impl AsRef<[u8]> for IdentityId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for IdentityId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::str::FromStr for IdentityId {
+    type Err = IdentityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| IdentityErrorKind::Malformed)?;
+        if bytes.len() != 32 {
+            return Err(IdentityErrorKind::Malformed.into());
+        }
+        let mut id = [0; 32];
+        id.copy_from_slice(&bytes);
+        Ok(IdentityId(id))
+    }
+}
+
+/// One key of an identity, signed by the key it replaced, or by itself for the first
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyChange {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// The keys an identity has had, the current one last
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct History {
+    changes: Vec<KeyChange>,
+}
+
+impl History {
+    pub fn changes(&self) -> &[KeyChange] {
+        &self.changes
+    }
+
+    /// The identity's public key, after every change
+    pub fn current_public_key(&self) -> Option<&[u8]> {
+        self.changes.last().map(|c| c.public_key.as_slice())
+    }
+
+    /// Append a change, as staged by `Identity::change_to`. The change is verified when the
+    /// history is next loaded.
+    pub fn push(&mut self, change: KeyChange) {
+        self.changes.push(change);
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, IdentityError> {
+        if self.changes.len() > u16::MAX as usize {
+            return Err(IdentityErrorKind::Malformed.into());
+        }
+        let mut v = vec![HISTORY_VERSION];
+        v.extend_from_slice(&(self.changes.len() as u16).to_be_bytes());
+        for change in &self.changes {
+            if change.public_key.len() > u8::MAX as usize || change.signature.len() != SIGNATURE_LEN
+            {
+                return Err(IdentityErrorKind::Malformed.into());
+            }
+            v.push(change.public_key.len() as u8);
+            v.extend_from_slice(&change.public_key);
+            v.extend_from_slice(&change.signature);
+        }
+        Ok(v)
+    }
+
+    pub fn decode(u: &[u8]) -> Result<History, IdentityError> {
+        if u.len() < 3 || u[0] != HISTORY_VERSION {
+            return Err(IdentityErrorKind::Malformed.into());
+        }
+        let count = u16::from_be_bytes([u[1], u[2]]);
+        let mut u = &u[3..];
+        let mut changes = vec![];
+        for _ in 0..count {
+            let len = *u.first().ok_or(IdentityErrorKind::Malformed)? as usize;
+            if u.len() < 1 + len + SIGNATURE_LEN {
+                return Err(IdentityErrorKind::Malformed.into());
+            }
+            changes.push(KeyChange {
+                public_key: u[1..1 + len].to_vec(),
+                signature: u[1 + len..1 + len + SIGNATURE_LEN].to_vec(),
+            });
+            u = &u[1 + len + SIGNATURE_LEN..];
+        }
+        if !u.is_empty() {
+            return Err(IdentityErrorKind::Malformed.into());
+        }
+        Ok(History { changes })
+    }
+
+    /// Check that each key was signed by the one before it, returning the identifier of the
+    /// identity the history is of
+    pub fn verify(&self, vault: &mut dyn DynVault) -> Result<IdentityId, IdentityError> {
+        let first = self.changes.first().ok_or(IdentityErrorKind::History)?;
+        for (index, change) in self.changes.iter().enumerate() {
+            let previous = match index {
+                0 => &[][..],
+                _ => self.changes[index - 1].public_key.as_slice(),
+            };
+            let signer = match index {
+                0 => change.public_key.as_slice(),
+                _ => previous,
+            };
+            if change.signature.len() != SIGNATURE_LEN {
+                return Err(IdentityErrorKind::Malformed.into());
+            }
+            let mut signature = [0; SIGNATURE_LEN];
+            signature.copy_from_slice(&change.signature);
+            vault
+                .verify(
+                    signature,
+                    public_key(signer)?,
+                    &change_data(index, previous, &change.public_key),
+                )
+                .map_err(|e| IdentityError::from_source(IdentityErrorKind::History, e))?;
+        }
+        IdentityId::of(vault, &first.public_key)
+    }
+}
+
+/// What the key before a change signs: the change's place in the history, the key it replaces,
+/// and the new key
+fn change_data(index: usize, previous: &[u8], public_key: &[u8]) -> Vec<u8> {
+    let mut v = (index as u32).to_be_bytes().to_vec();
+    v.push(previous.len() as u8);
+    v.extend_from_slice(previous);
+    v.extend_from_slice(public_key);
+    v
+}
+
+/// The vault's public key for `key`, a Curve25519 key or an uncompressed P-256 key
+pub fn public_key(key: &[u8]) -> Result<PublicKey, IdentityError> {
+    match key.len() {
+        32 => {
+            let mut k = [0; 32];
+            k.copy_from_slice(key);
+            Ok(PublicKey::Curve25519(k))
+        }
+        65 => {
+            let mut k = [0; 65];
+            k.copy_from_slice(key);
+            Ok(PublicKey::P256(k))
+        }
+        _ => Err(IdentityErrorKind::Malformed.into()),
+    }
+}
+
+/// A long-term key in a vault, with the identity's identifier and key history. Channels use
+/// the current key as their static key, and it signs whatever the identity attests.
+#[derive(Clone)]
+pub struct Identity {
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+    id: IdentityId,
+    key: SecretKeyContext,
+    history: History,
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Identity {{ id: {}, key: {:?}, keys: {}, vault }}",
+            self.id,
+            self.key,
+            self.history.changes.len()
+        )
+    }
+}
+
+impl Identity {
+    /// A new identity with an ephemeral Curve25519 key, which is lost with the vault unless
+    /// the vault persists it
+    pub fn create(vault: Arc<Mutex<dyn DynVault + Send>>) -> Result<Identity, IdentityError> {
+        let key = vault.lock().unwrap().secret_generate(SecretKeyAttributes {
+            xtype: SecretKeyType::Curve25519,
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        })?;
+        Identity::from_key(vault, key)
+    }
+
+    /// A new identity whose first key is `key`, already in the vault
+    pub fn from_key(
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        key: SecretKeyContext,
+    ) -> Result<Identity, IdentityError> {
+        let (id, change) = {
+            let mut v = vault.lock().unwrap();
+            let public_key = v.secret_public_key_get(key)?.as_ref().to_vec();
+            let signature = v.sign(key, &change_data(0, &[], &public_key))?;
+            (
+                IdentityId::of(&*v, &public_key)?,
+                KeyChange {
+                    public_key,
+                    signature: signature.to_vec(),
+                },
+            )
+        };
+        Ok(Identity {
+            vault,
+            id,
+            key,
+            history: History {
+                changes: vec![change],
+            },
+        })
+    }
+
+    /// The identity with key `history`, whose current key is `key`
+    pub fn load(
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        key: SecretKeyContext,
+        history: History,
+    ) -> Result<Identity, IdentityError> {
+        let id = {
+            let mut v = vault.lock().unwrap();
+            let id = history.verify(&mut *v)?;
+            let public_key = v.secret_public_key_get(key)?;
+            if history.current_public_key() != Some(public_key.as_ref()) {
+                return Err(IdentityErrorKind::KeyMismatch.into());
+            }
+            id
+        };
+        Ok(Identity {
+            vault,
+            id,
+            key,
+            history,
+        })
+    }
+
+    pub fn id(&self) -> IdentityId {
+        self.id
+    }
+
+    /// The vault's handle on the current key
+    pub fn key(&self) -> SecretKeyContext {
+        self.key
+    }
+
+    /// The current public key
+    pub fn public_key(&self) -> &[u8] {
+        self.history.current_public_key().unwrap_or_default()
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Sign `data` with the current key
+    pub fn sign(&self, data: &[u8]) -> Result<[u8; 64], IdentityError> {
+        Ok(self.vault.lock().unwrap().sign(self.key, data)?)
+    }
+
+    /// The change replacing the current key with `public_key`, signed by the current key. The
+    /// new key takes over once the change is applied, or pushed to a history which is loaded.
+    pub fn change_to(&self, public_key: &[u8]) -> Result<KeyChange, IdentityError> {
+        let signature = self.sign(&change_data(
+            self.history.changes.len(),
+            self.public_key(),
+            public_key,
+        ))?;
+        Ok(KeyChange {
+            public_key: public_key.to_vec(),
+            signature: signature.to_vec(),
+        })
+    }
+
+    /// Replace the current key with `key`, already in the vault, as signed by `change`
+    pub fn apply(&mut self, key: SecretKeyContext, change: KeyChange) -> Result<(), IdentityError> {
+        let mut history = self.history.clone();
+        history.push(change);
+        *self = Identity::load(self.vault.clone(), key, history)?;
+        Ok(())
+    }
+
+    /// Replace the current key with a new one of the same type, returning the old key, which
+    /// channels established with it may still be using
+    pub fn rotate(&mut self) -> Result<SecretKeyContext, IdentityError> {
+        let key = {
+            let mut v = self.vault.lock().unwrap();
+            let attributes = v.secret_attributes_get(self.key)?;
+            v.secret_generate(attributes)?
+        };
+        let public_key = self
+            .vault
+            .lock()
+            .unwrap()
+            .secret_public_key_get(key)?
+            .as_ref()
+            .to_vec();
+        let change = self.change_to(&public_key)?;
+        let old = self.key;
+        self.apply(key, change)?;
+        Ok(old)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::software::DefaultVault;
+
+    fn history(keys: &[u8]) -> History {
+        History {
+            changes: keys
+                .iter()
+                .map(|k| KeyChange {
+                    public_key: vec![*k; 32],
+                    signature: vec![*k; 64],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn history_encoding() {
+        let h = history(&[1, 2, 3]);
+        let encoded = h.encode().unwrap();
+        assert_eq!(History::decode(&encoded).unwrap(), h);
+        assert_eq!(
+            History::decode(&encoded).unwrap().current_public_key(),
+            Some(&[3; 32][..])
+        );
+
+        assert!(History::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(History::decode(&[encoded.as_slice(), &[0]].concat()).is_err());
+        assert!(History::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn identity_id_text() {
+        let id: IdentityId = "01".repeat(32).parse().unwrap();
+        assert_eq!(id.to_string(), "01".repeat(32));
+        assert!("01".parse::<IdentityId>().is_err());
+        assert!("zz".repeat(32).parse::<IdentityId>().is_err());
+    }
+
+    // needs the software vault's crypto
+    #[test]
+    fn rotation() {
+        let vault: Arc<Mutex<dyn DynVault + Send>> = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut identity = Identity::create(vault.clone()).unwrap();
+        let id = identity.id();
+        let first = identity.public_key().to_vec();
+
+        identity.rotate().unwrap();
+        assert_eq!(identity.id(), id);
+        assert_ne!(identity.public_key(), first.as_slice());
+        assert_eq!(identity.history().changes().len(), 2);
+
+        let loaded = Identity::load(
+            vault.clone(),
+            identity.key(),
+            History::decode(&identity.history().encode().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(loaded.id(), id);
+
+        // a change not signed by the key it replaces is refused
+        let mut forged = identity.history().clone();
+        forged.changes[1].signature = vec![0; 64];
+        assert!(Identity::load(vault, identity.key(), forged).is_err());
+    }
+}
@@ -1,10 +1,11 @@
-use failure::{Context, Fail};
-use ockam_common::error::ErrorKind;
+use failure::Fail;
+use ockam_common::error::{ErrorKind, ERROR_INTERFACE_KEX};
 use ockam_vault::error::{VaultFailError, VaultFailErrorKind};
 
 /// Represents the failures that can occur in
 /// an Ockam Key Exchange
 #[derive(Clone, Fail, Debug)]
+#[non_exhaustive]
 pub enum KeyExchangeFailErrorKind {
     /// An invalid number of bytes was received in an exchange
     #[fail(
@@ -40,10 +41,13 @@ pub enum KeyExchangeFailErrorKind {
         /// The message that describes the error
         msg: String,
     },
+    /// An error occurred in the vault
+    #[fail(display = "An error occurred in the vault: {}", 0)]
+    Vault(VaultFailErrorKind),
 }
 
 impl ErrorKind for KeyExchangeFailErrorKind {
-    const ERROR_INTERFACE: usize = 5 << 24;
+    const ERROR_INTERFACE: usize = ERROR_INTERFACE_KEX;
 
     fn to_usize(&self) -> usize {
         match *self {
@@ -52,83 +56,39 @@ impl ErrorKind for KeyExchangeFailErrorKind {
             KeyExchangeFailErrorKind::MethodCalledOutOfSequence { .. } => Self::ERROR_INTERFACE | 4,
             KeyExchangeFailErrorKind::InvalidHash { .. } => Self::ERROR_INTERFACE | 5,
             KeyExchangeFailErrorKind::GeneralError { .. } => Self::ERROR_INTERFACE | 6,
+            KeyExchangeFailErrorKind::Vault(..) => Self::ERROR_INTERFACE | 7,
         }
     }
 }
 
-impl From<KeyExchangeFailErrorKind> for KexExchangeFailError {
-    fn from(err: KeyExchangeFailErrorKind) -> Self {
-        Self {
-            inner: Context::new("").context(err),
-        }
-    }
-}
-
-impl From<KexExchangeFailError> for KeyExchangeFailErrorKind {
-    fn from(err: KexExchangeFailError) -> Self {
-        err.inner.get_context().clone()
-    }
-}
+error_impl!(KexExchangeFailError, KeyExchangeFailErrorKind);
 
 impl From<VaultFailError> for KexExchangeFailError {
     fn from(err: VaultFailError) -> Self {
-        let err: VaultFailErrorKind = err.into();
-        match err {
-            VaultFailErrorKind::InvalidParam(p) => KeyExchangeFailErrorKind::InvalidParam(p).into(),
-            _ => KeyExchangeFailErrorKind::MethodCalledOutOfSequence {
-                actual: "",
-                expected: "",
-            }
-            .into(),
-        }
+        let kind = *err.kind();
+        Self::from_source(KeyExchangeFailErrorKind::Vault(kind), err)
     }
 }
 
 impl From<KexExchangeFailError> for VaultFailError {
     fn from(err: KexExchangeFailError) -> Self {
-        let err = err.inner.get_context();
-        match err {
-            KeyExchangeFailErrorKind::InvalidParam(p) => {
-                VaultFailErrorKind::InvalidParam(*p).into()
-            }
-            KeyExchangeFailErrorKind::InvalidByteCount(_, _) => {
-                VaultFailErrorKind::InvalidSize.into()
-            }
+        let kind = match err.kind() {
+            KeyExchangeFailErrorKind::InvalidParam(p) => VaultFailErrorKind::InvalidParam(*p),
+            KeyExchangeFailErrorKind::InvalidByteCount(_, _) => VaultFailErrorKind::InvalidSize,
             KeyExchangeFailErrorKind::MethodCalledOutOfSequence { .. } => {
-                VaultFailErrorKind::InvalidContext.into()
+                VaultFailErrorKind::InvalidContext
             }
-            KeyExchangeFailErrorKind::InvalidHash { .. } => VaultFailErrorKind::Ecdh.into(),
-            KeyExchangeFailErrorKind::GeneralError { .. } => VaultFailErrorKind::IOError.into(),
-        }
+            KeyExchangeFailErrorKind::InvalidHash { .. } => VaultFailErrorKind::Ecdh,
+            KeyExchangeFailErrorKind::GeneralError { .. } => VaultFailErrorKind::IOError,
+            KeyExchangeFailErrorKind::Vault(kind) => *kind,
+        };
+        Self::from_source(kind, err)
     }
 }
 
 #[cfg(feature = "ffi")]
 impl From<KeyExchangeFailErrorKind> for ffi_support::ExternError {
     fn from(err: KeyExchangeFailErrorKind) -> ffi_support::ExternError {
-        ffi_support::ExternError::new_error(ffi_support::ErrorCode::new(err.to_usize() as i32), "")
-    }
-}
-
-/// Wraps an error kind with context and backtrace logic
-#[derive(Debug)]
-pub struct KexExchangeFailError {
-    inner: Context<KeyExchangeFailErrorKind>,
-}
-
-impl KexExchangeFailError {
-    /// Convert from an error kind and a static string
-    pub fn from_msg<D>(kind: KeyExchangeFailErrorKind, msg: D) -> Self
-    where
-        D: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
-    {
-        Self {
-            inner: Context::new(msg).context(kind),
-        }
-    }
-
-    /// Convert to an integer, reused in From trait implementations
-    pub fn to_usize(&self) -> usize {
-        self.inner.get_context().to_usize()
+        ffi_support::ExternError::new_error(ffi_support::ErrorCode::new(err.code() as i32), "")
     }
 }
@@ -23,6 +23,8 @@ use ockam_vault::{
     types::{PublicKey, SecretKeyContext},
 };
 
+#[macro_use]
+extern crate ockam_common;
 #[macro_use]
 extern crate ockam_vault;
 
@@ -34,7 +36,7 @@ pub const SHA256_SIZE: usize = 32;
 pub const AES128_KEYSIZE: usize = 16;
 /// The number of bytes in AES256 key
 pub const AES256_KEYSIZE: usize = 32;
-/// The number of bytes in AES-GCM tag
+/// The number of bytes in AES-GCM tag, and in a ChaCha20-Poly1305 one
 pub const AES_GCM_TAGSIZE: usize = 16;
 
 /// A KeyExchange implements these methods
@@ -65,11 +67,12 @@ trait KeyExchange {
     ) -> Result<Vec<u8>, VaultFailError>;
     /// Split step in Noise protocol
     fn split(&mut self) -> Result<(SecretKeyContext, SecretKeyContext), VaultFailError>;
-    /// Finish the key exchange and return computed data
+    /// Finish the key exchange and return computed data, for its initiator if `initiator`
     fn finalize(
         &mut self,
         encrypt_key: SecretKeyContext,
         decrypt_key: SecretKeyContext,
+        initiator: bool,
     ) -> Result<CompletedKeyExchange, VaultFailError>;
 }
 
@@ -90,14 +93,104 @@ pub enum CipherSuite {
     Curve25519AesGcmSha256,
     /// P256 Aes128-GCM Sha256
     P256Aes128GcmSha256,
+    /// Curve25519 ChaCha20-Poly1305 Sha256, for devices without AES instructions
+    Curve25519ChaChaPolySha256,
+}
+
+impl CipherSuite {
+    /// The id of the XX key exchange with this suite, as returned by
+    /// `NewKeyExchanger::cipher_suite_id`
+    pub fn id(&self) -> u8 {
+        match self {
+            CipherSuite::Curve25519AesGcmSha256 => 1,
+            CipherSuite::P256Aes128GcmSha256 => 2,
+            CipherSuite::Curve25519ChaChaPolySha256 => 3,
+        }
+    }
+
+    /// The AEAD that the keys agreed with this suite are for
+    pub fn aead(&self) -> Aead {
+        match self {
+            CipherSuite::Curve25519AesGcmSha256 | CipherSuite::P256Aes128GcmSha256 => Aead::AesGcm,
+            CipherSuite::Curve25519ChaChaPolySha256 => Aead::ChaCha20Poly1305,
+        }
+    }
+}
+
+/// The AEAD algorithms that a key exchange can agree keys for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Aead {
+    /// AES-GCM, with 128 or 256 bit keys
+    AesGcm,
+    /// ChaCha20-Poly1305
+    ChaCha20Poly1305,
+}
+
+impl Aead {
+    /// The 96 bit nonce for the counter `n`, encoded as Noise does for this AEAD: 32 zero bits
+    /// followed by `n` big-endian for AES-GCM, little-endian for ChaCha20-Poly1305
+    pub fn nonce(&self, n: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        match self {
+            Aead::AesGcm => nonce[4..].copy_from_slice(&n.to_be_bytes()),
+            Aead::ChaCha20Poly1305 => nonce[4..].copy_from_slice(&n.to_le_bytes()),
+        }
+        nonce
+    }
+
+    /// The 96 bit nonce for the counter `n` of a channel's payloads: the nonce Noise gives `n`,
+    /// with `salt` in place of its leading 32 zero bits
+    pub fn salted_nonce(&self, n: u64, salt: [u8; 4]) -> [u8; 12] {
+        let mut nonce = self.nonce(n);
+        nonce[..4].copy_from_slice(&salt);
+        nonce
+    }
+
+    /// Encrypt `plaintext` with the key `context` in `vault`, returning the ciphertext and tag
+    pub fn encrypt(
+        &self,
+        vault: &mut dyn ockam_vault::DynVault,
+        context: SecretKeyContext,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        match self {
+            Aead::AesGcm => vault.aead_aes_gcm_encrypt(context, plaintext, nonce, aad),
+            Aead::ChaCha20Poly1305 => {
+                vault.aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+            }
+        }
+    }
+
+    /// Decrypt and authenticate `cipher_text` with the key `context` in `vault`
+    pub fn decrypt(
+        &self,
+        vault: &mut dyn ockam_vault::DynVault,
+        context: SecretKeyContext,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        match self {
+            Aead::AesGcm => vault.aead_aes_gcm_decrypt(context, cipher_text, nonce, aad),
+            Aead::ChaCha20Poly1305 => {
+                vault.aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+            }
+        }
+    }
 }
 
 /// Instantiate a stateful key exchange vault instance
-pub trait NewKeyExchanger<E: KeyExchanger = Self, F: KeyExchanger = Self> {
+pub trait NewKeyExchanger {
+    /// The id of the key exchange and cipher suite the key exchangers use, which tells a
+    /// responder how to answer. XX key exchangers use the id of their `CipherSuite`, from 1 up;
+    /// others count down from 255.
+    fn cipher_suite_id(&self) -> u8;
     /// Create a new Key Exchanger with the initiator role
-    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> E;
+    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> Box<dyn KeyExchanger + Send>;
     /// Create a new Key Exchanger with the responder role
-    fn responder(&self, identity_key: Option<SecretKeyContext>) -> F;
+    fn responder(&self, identity_key: Option<SecretKeyContext>) -> Box<dyn KeyExchanger + Send>;
 }
 
 /// A Completed Key Exchange elements
@@ -113,6 +206,28 @@ pub struct CompletedKeyExchange {
     pub local_static_secret: SecretKeyContext,
     /// The long term static public key from remote party
     pub remote_static_public_key: PublicKey,
+    /// The AEAD the encryption and decryption keys are for
+    pub aead: Aead,
+    /// The salt of the nonces of what is encrypted with the encryption key
+    pub encrypt_salt: [u8; 4],
+    /// The salt of the nonces of what is decrypted with the decryption key
+    pub decrypt_salt: [u8; 4],
+}
+
+/// The salts of the nonces of what the end of a key exchange which ended with the state hash
+/// `h` encrypts and decrypts, its initiator if `initiator` and its responder otherwise. Each
+/// direction takes 4 bytes of `h` of its own, so that a counter reused by mistake, with another
+/// key exchange's keys or in the other direction, doesn't reuse a whole nonce.
+pub fn nonce_salts(h: &[u8; 32], initiator: bool) -> ([u8; 4], [u8; 4]) {
+    let mut sent_by_initiator = [0u8; 4];
+    sent_by_initiator.copy_from_slice(&h[..4]);
+    let mut sent_by_responder = [0u8; 4];
+    sent_by_responder.copy_from_slice(&h[4..8]);
+    if initiator {
+        (sent_by_initiator, sent_by_responder)
+    } else {
+        (sent_by_responder, sent_by_initiator)
+    }
 }
 
 /// Errors thrown by Key exchange
@@ -171,5 +286,9 @@ mod tests {
         let s2 = vault_re.secret_export(responder.encrypt_key).unwrap();
 
         assert_eq!(s1, s2);
+
+        assert_eq!(initiator.encrypt_salt, responder.decrypt_salt);
+        assert_eq!(initiator.decrypt_salt, responder.encrypt_salt);
+        assert_ne!(initiator.encrypt_salt, initiator.decrypt_salt);
     }
 }
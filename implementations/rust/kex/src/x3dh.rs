@@ -1,5 +1,5 @@
 use crate::error::{KexExchangeFailError, KeyExchangeFailErrorKind};
-use crate::{CompletedKeyExchange, KeyExchanger, NewKeyExchanger};
+use crate::{nonce_salts, Aead, CompletedKeyExchange, KeyExchanger, NewKeyExchanger};
 use ockam_vault::types::{
     SecretKey, SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
 };
@@ -340,12 +340,16 @@ impl KeyExchanger for X3dhResponder {
                 let signature = *array_ref![plaintext, 32, 64];
                 vault.verify(signature, *eik, &plaintext[..32])?;
 
+                let (encrypt_salt, decrypt_salt) = nonce_salts(&state_hash, false);
                 self.completed_key_exchange = Some(CompletedKeyExchange {
                     h: state_hash,
                     encrypt_key,
                     decrypt_key,
                     local_static_secret,
                     remote_static_public_key: ikb,
+                    aead: Aead::AesGcm,
+                    encrypt_salt,
+                    decrypt_salt,
                 });
                 self.state = ResponderState::Done;
                 Ok(vec![])
@@ -463,12 +467,16 @@ impl KeyExchanger for X3dhInitiator {
                 )?;
                 let mut output = aad[..64].to_vec();
                 output.append(&mut ciphertext_and_tag);
+                let (encrypt_salt, decrypt_salt) = nonce_salts(&state_hash, true);
                 self.completed_key_exchange = Some(CompletedKeyExchange {
                     h: state_hash,
                     encrypt_key,
                     decrypt_key,
                     local_static_secret: skb,
                     remote_static_public_key: prekey_bundle.identity_key,
+                    aead: Aead::AesGcm,
+                    encrypt_salt,
+                    decrypt_salt,
                 });
                 self.state = InitiatorState::Done;
                 Ok(output)
@@ -514,13 +522,26 @@ impl X3dhNewKeyExchanger {
     }
 }
 
-impl NewKeyExchanger<X3dhInitiator, X3dhResponder> for X3dhNewKeyExchanger {
-    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> X3dhInitiator {
-        X3dhInitiator::new(self.vault_initiator.clone(), identity_key)
+/// The id of the X3DH key exchange, as returned by `NewKeyExchanger::cipher_suite_id`
+pub const X3DH_CIPHER_SUITE_ID: u8 = 255;
+
+impl NewKeyExchanger for X3dhNewKeyExchanger {
+    fn cipher_suite_id(&self) -> u8 {
+        X3DH_CIPHER_SUITE_ID
     }
 
-    fn responder(&self, identity_key: Option<SecretKeyContext>) -> X3dhResponder {
-        X3dhResponder::new(self.vault_responder.clone(), identity_key)
+    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> Box<dyn KeyExchanger + Send> {
+        Box::new(X3dhInitiator::new(
+            self.vault_initiator.clone(),
+            identity_key,
+        ))
+    }
+
+    fn responder(&self, identity_key: Option<SecretKeyContext>) -> Box<dyn KeyExchanger + Send> {
+        Box::new(X3dhResponder::new(
+            self.vault_responder.clone(),
+            identity_key,
+        ))
     }
 }
 
@@ -1,6 +1,6 @@
 use super::{CompletedKeyExchange, KeyExchange, KeyExchanger, SHA256_SIZE};
 use crate::error::KexExchangeFailError;
-use crate::{CipherSuite, NewKeyExchanger, AES_GCM_TAGSIZE};
+use crate::{nonce_salts, CipherSuite, NewKeyExchanger, AES_GCM_TAGSIZE};
 use ockam_vault::{
     error::{VaultFailError, VaultFailErrorKind},
     types::{
@@ -55,7 +55,9 @@ impl std::fmt::Debug for SymmetricState {
 impl SymmetricState {
     fn get_secret_key_type(&self) -> SecretKeyType {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => SecretKeyType::Curve25519,
+            CipherSuite::Curve25519AesGcmSha256 | CipherSuite::Curve25519ChaChaPolySha256 => {
+                SecretKeyType::Curve25519
+            }
             CipherSuite::P256Aes128GcmSha256 => SecretKeyType::P256,
         }
     }
@@ -64,12 +66,14 @@ impl SymmetricState {
         match self.cipher_suite {
             CipherSuite::Curve25519AesGcmSha256 => SecretKeyType::Aes256,
             CipherSuite::P256Aes128GcmSha256 => SecretKeyType::Aes128,
+            // the vault has no key type of its own for ChaCha20, whose keys are 256 bits
+            CipherSuite::Curve25519ChaChaPolySha256 => SecretKeyType::Buffer(32),
         }
     }
 
     fn create_public_key(&self, public_key: &[u8]) -> Result<PublicKey, VaultFailError> {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => {
+            CipherSuite::Curve25519AesGcmSha256 | CipherSuite::Curve25519ChaChaPolySha256 => {
                 if public_key.len() != 32 {
                     return Err(VaultFailError::from(VaultFailErrorKind::InvalidSize));
                 }
@@ -86,7 +90,7 @@ impl SymmetricState {
 
     fn get_public_key_size(&self) -> usize {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => 32,
+            CipherSuite::Curve25519AesGcmSha256 | CipherSuite::Curve25519ChaChaPolySha256 => 32,
             CipherSuite::P256Aes128GcmSha256 => 65,
         }
     }
@@ -117,6 +121,7 @@ impl KeyExchange for SymmetricState {
         match self.cipher_suite {
             CipherSuite::Curve25519AesGcmSha256 => b"Noise_XX_25519_AESGCM_SHA256\0\0\0\0",
             CipherSuite::P256Aes128GcmSha256 => b"Noise_XX_P256_AES128GCM_SHA256\0\0",
+            CipherSuite::Curve25519ChaChaPolySha256 => b"Noise_XX_25519_ChaChaPoly_SHA256",
         }
     }
 
@@ -252,11 +257,12 @@ impl KeyExchange for SymmetricState {
             .h
             .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
 
-        let mut nonce = [0u8; 12];
-        nonce[10..].copy_from_slice(&self.nonce.to_be_bytes());
+        let aead = self.cipher_suite.aead();
+        let nonce = aead.nonce(self.nonce as u64);
         let ciphertext_and_tag = {
             let mut vault = self.vault.lock().unwrap();
-            vault.aead_aes_gcm_encrypt(
+            aead.encrypt(
+                &mut *vault,
                 self.key.ok_or(VaultFailErrorKind::AeadAesGcmEncrypt)?,
                 plaintext.as_ref(),
                 nonce.as_ref(),
@@ -277,12 +283,13 @@ impl KeyExchange for SymmetricState {
             .h
             .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
 
-        let mut nonce = [0u8; 12];
-        nonce[10..].copy_from_slice(&self.nonce.to_be_bytes());
+        let aead = self.cipher_suite.aead();
+        let nonce = aead.nonce(self.nonce as u64);
         let ciphertext = ciphertext.as_ref();
         let plaintext = {
             let mut vault = self.vault.lock().unwrap();
-            vault.aead_aes_gcm_decrypt(
+            aead.decrypt(
+                &mut *vault,
                 self.key.ok_or(VaultFailErrorKind::AeadAesGcmDecrypt)?,
                 ciphertext,
                 nonce.as_ref(),
@@ -321,6 +328,7 @@ impl KeyExchange for SymmetricState {
         &mut self,
         encrypt_key: SecretKeyContext,
         decrypt_key: SecretKeyContext,
+        initiator: bool,
     ) -> Result<CompletedKeyExchange, VaultFailError> {
         let h = self
             .h
@@ -335,12 +343,16 @@ impl KeyExchange for SymmetricState {
             .remote_static_public_key
             .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
 
+        let (encrypt_salt, decrypt_salt) = nonce_salts(&h, initiator);
         Ok(CompletedKeyExchange {
             h,
             encrypt_key,
             decrypt_key,
             local_static_secret,
             remote_static_public_key,
+            aead: self.cipher_suite.aead(),
+            encrypt_salt,
+            decrypt_salt,
         })
     }
 }
@@ -438,7 +450,7 @@ impl Initiator {
     /// after encoding message 3
     pub fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
         let keys = self.0.split()?;
-        self.0.finalize(keys.1, keys.0)
+        self.0.finalize(keys.1, keys.0, true)
     }
 }
 
@@ -533,13 +545,13 @@ impl Responder {
     /// after decoding message 3
     pub fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
         let keys = self.0.split()?;
-        self.0.finalize(keys.0, keys.1)
+        self.0.finalize(keys.0, keys.1, false)
     }
 }
 
 /// The states the connection XX pattern initiator completes
-#[derive(Debug)]
-enum InitiatorState {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitiatorState {
     /// Run encode message 1
     EncodeMessage1,
     /// Run decode message 2
@@ -551,8 +563,8 @@ enum InitiatorState {
 }
 
 /// The states the connection XX pattern responder completes
-#[derive(Debug)]
-enum ResponderState {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponderState {
     /// Run decode message 1
     DecodeMessage1,
     /// Run encode message 2
@@ -563,6 +575,30 @@ enum ResponderState {
     Done,
 }
 
+impl InitiatorState {
+    /// The state once this state's step has run. The transitions don't depend on the data
+    /// exchanged, which only the vault sees.
+    pub fn next(self) -> Self {
+        match self {
+            InitiatorState::EncodeMessage1 => InitiatorState::DecodeMessage2,
+            InitiatorState::DecodeMessage2 => InitiatorState::EncodeMessage3,
+            InitiatorState::EncodeMessage3 | InitiatorState::Done => InitiatorState::Done,
+        }
+    }
+}
+
+impl ResponderState {
+    /// The state once this state's step has run. The transitions don't depend on the data
+    /// exchanged, which only the vault sees.
+    pub fn next(self) -> Self {
+        match self {
+            ResponderState::DecodeMessage1 => ResponderState::EncodeMessage2,
+            ResponderState::EncodeMessage2 => ResponderState::DecodeMessage3,
+            ResponderState::DecodeMessage3 | ResponderState::Done => ResponderState::Done,
+        }
+    }
+}
+
 /// Represents an XX initiator
 #[derive(Debug)]
 pub struct XXInitiator {
@@ -599,33 +635,37 @@ impl XXNewKeyExchanger {
     }
 }
 
-impl NewKeyExchanger<XXInitiator, XXResponder> for XXNewKeyExchanger {
+impl NewKeyExchanger for XXNewKeyExchanger {
+    fn cipher_suite_id(&self) -> u8 {
+        self.cipher_suite.id()
+    }
+
     /// Create a new initiator using the provided backing vault
-    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> XXInitiator {
+    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> Box<dyn KeyExchanger + Send> {
         let ss = SymmetricState::new(
             self.cipher_suite,
             self.vault_initiator.clone(),
             identity_key,
         );
-        XXInitiator {
+        Box::new(XXInitiator {
             state: InitiatorState::EncodeMessage1,
             initiator: Initiator(ss),
             run_prologue: true,
-        }
+        })
     }
 
     /// Create a new responder using the provided backing vault
-    fn responder(&self, identity_key: Option<SecretKeyContext>) -> XXResponder {
+    fn responder(&self, identity_key: Option<SecretKeyContext>) -> Box<dyn KeyExchanger + Send> {
         let ss = SymmetricState::new(
             self.cipher_suite,
             self.vault_responder.clone(),
             identity_key,
         );
-        XXResponder {
+        Box::new(XXResponder {
             state: ResponderState::DecodeMessage1,
             responder: Responder(ss),
             run_prologue: true,
-        }
+        })
     }
 }
 
@@ -637,6 +677,20 @@ pub struct XXResponder {
     run_prologue: bool,
 }
 
+impl XXInitiator {
+    /// The step the initiator runs next
+    pub fn state(&self) -> InitiatorState {
+        self.state
+    }
+}
+
+impl XXResponder {
+    /// The step the responder runs next
+    pub fn state(&self) -> ResponderState {
+        self.state
+    }
+}
+
 impl KeyExchanger for XXInitiator {
     fn process(&mut self, data: &[u8]) -> Result<Vec<u8>, KexExchangeFailError> {
         match self.state {
@@ -645,17 +699,17 @@ impl KeyExchanger for XXInitiator {
                     self.initiator.0.prologue()?;
                 }
                 let msg = self.initiator.encode_message_1(data)?;
-                self.state = InitiatorState::DecodeMessage2;
+                self.state = self.state.next();
                 Ok(msg)
             }
             InitiatorState::DecodeMessage2 => {
                 let msg = self.initiator.decode_message_2(data)?;
-                self.state = InitiatorState::EncodeMessage3;
+                self.state = self.state.next();
                 Ok(msg)
             }
             InitiatorState::EncodeMessage3 => {
                 let msg = self.initiator.encode_message_3(data)?;
-                self.state = InitiatorState::Done;
+                self.state = self.state.next();
                 Ok(msg)
             }
             InitiatorState::Done => Ok(vec![]),
@@ -682,17 +736,17 @@ impl KeyExchanger for XXResponder {
                     self.responder.0.prologue()?;
                 }
                 let msg = self.responder.decode_message_1(data)?;
-                self.state = ResponderState::EncodeMessage2;
+                self.state = self.state.next();
                 Ok(msg)
             }
             ResponderState::EncodeMessage2 => {
                 let msg = self.responder.encode_message_2(data)?;
-                self.state = ResponderState::DecodeMessage3;
+                self.state = self.state.next();
                 Ok(msg)
             }
             ResponderState::DecodeMessage3 => {
                 let msg = self.responder.decode_message_3(data)?;
-                self.state = ResponderState::Done;
+                self.state = self.state.next();
                 Ok(msg)
             }
             ResponderState::Done => Ok(vec![]),
@@ -714,6 +768,7 @@ impl KeyExchanger for XXResponder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Aead;
     use ockam_vault::software::DefaultVault;
 
     #[test]
@@ -802,6 +857,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn state_transitions() {
+        let mut initiator = vec![InitiatorState::EncodeMessage1];
+        let mut responder = vec![ResponderState::DecodeMessage1];
+        for _ in 0..4 {
+            initiator.push(initiator.last().unwrap().next());
+            responder.push(responder.last().unwrap().next());
+        }
+        assert_eq!(
+            initiator,
+            vec![
+                InitiatorState::EncodeMessage1,
+                InitiatorState::DecodeMessage2,
+                InitiatorState::EncodeMessage3,
+                InitiatorState::Done,
+                InitiatorState::Done
+            ]
+        );
+        assert_eq!(
+            responder,
+            vec![
+                ResponderState::DecodeMessage1,
+                ResponderState::EncodeMessage2,
+                ResponderState::DecodeMessage3,
+                ResponderState::Done,
+                ResponderState::Done
+            ]
+        );
+    }
+
     #[test]
     fn handshake_main() {
         const INIT_STATIC: &str =
@@ -891,6 +976,64 @@ mod tests {
         assert_eq!(plaintext, b"hello alice");
     }
 
+    #[test]
+    fn chacha_poly_handshake() {
+        let vault_init: Arc<Mutex<dyn DynVault + Send>> =
+            Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_resp: Arc<Mutex<dyn DynVault + Send>> =
+            Arc::new(Mutex::new(DefaultVault::default()));
+        let suite = CipherSuite::Curve25519ChaChaPolySha256;
+        let mut initiator =
+            XXNewKeyExchanger::new(suite, vault_init.clone(), vault_init.clone()).initiator(None);
+        let mut responder =
+            XXNewKeyExchanger::new(suite, vault_resp.clone(), vault_resp.clone()).responder(None);
+
+        let msg1 = initiator.process(&[]).unwrap();
+        responder.process(&msg1).unwrap();
+        let msg2 = responder.process(&[]).unwrap();
+        initiator.process(&msg2).unwrap();
+        let msg3 = initiator.process(&[]).unwrap();
+        responder.process(&msg3).unwrap();
+        let alice = initiator.finalize().unwrap();
+        let bob = responder.finalize().unwrap();
+        assert_eq!(alice.h, bob.h);
+        assert_eq!(alice.aead, Aead::ChaCha20Poly1305);
+
+        let nonce = alice.aead.nonce(1);
+        assert_eq!(nonce, [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        let ciphertext = alice
+            .aead
+            .encrypt(
+                &mut *vault_init.lock().unwrap(),
+                alice.encrypt_key,
+                b"hello bob",
+                &nonce,
+                &alice.h,
+            )
+            .unwrap();
+        let plaintext = bob
+            .aead
+            .decrypt(
+                &mut *vault_resp.lock().unwrap(),
+                bob.decrypt_key,
+                &ciphertext,
+                &nonce,
+                &bob.h,
+            )
+            .unwrap();
+        assert_eq!(plaintext, b"hello bob");
+
+        // the keys are for ChaCha20-Poly1305 only
+        let res = Aead::AesGcm.decrypt(
+            &mut *vault_resp.lock().unwrap(),
+            bob.decrypt_key,
+            &ciphertext,
+            &Aead::AesGcm.nonce(1),
+            &bob.h,
+        );
+        assert!(res.is_err());
+    }
+
     fn mock_handshake(
         init_static: &str,
         init_eph: &str,
@@ -0,0 +1,68 @@
+use failure::Fail;
+use ockam_common::error::{ErrorKind, ERROR_INTERFACE_MESSAGE};
+
+/// Represents the failures that can occur
+/// encoding and decoding Ockam messages
+#[derive(Clone, Copy, Fail, Debug)]
+#[non_exhaustive]
+pub enum MessageErrorKind {
+    /// The input ended before the value it encodes
+    #[fail(display = "The input ended before the value it encodes")]
+    Truncated,
+    /// An unknown message type was decoded
+    #[fail(display = "Unknown message type")]
+    UnknownMessageType,
+    /// An unknown address type was decoded
+    #[fail(display = "Unknown address type")]
+    UnknownAddressType,
+    /// An unknown host address type was decoded
+    #[fail(display = "Unknown host address type")]
+    UnknownHostAddressType,
+    /// An address couldn't be parsed
+    #[fail(display = "An invalid address was supplied")]
+    InvalidAddress,
+    /// A value was too large to encode
+    #[fail(display = "Maximum value exceeded")]
+    ValueTooLarge,
+    /// The address type isn't implemented
+    #[fail(display = "The address type is not implemented")]
+    NotImplemented,
+}
+
+impl ErrorKind for MessageErrorKind {
+    const ERROR_INTERFACE: usize = ERROR_INTERFACE_MESSAGE;
+
+    fn to_usize(&self) -> usize {
+        match *self {
+            MessageErrorKind::Truncated => Self::ERROR_INTERFACE | 1,
+            MessageErrorKind::UnknownMessageType => Self::ERROR_INTERFACE | 2,
+            MessageErrorKind::UnknownAddressType => Self::ERROR_INTERFACE | 3,
+            MessageErrorKind::UnknownHostAddressType => Self::ERROR_INTERFACE | 4,
+            MessageErrorKind::InvalidAddress => Self::ERROR_INTERFACE | 5,
+            MessageErrorKind::ValueTooLarge => Self::ERROR_INTERFACE | 6,
+            MessageErrorKind::NotImplemented => Self::ERROR_INTERFACE | 7,
+        }
+    }
+}
+
+error_impl!(MessageError, MessageErrorKind);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_string() {
+        let err: MessageError = MessageErrorKind::UnknownMessageType.into();
+        assert_eq!(String::from(err), "Unknown message type");
+        let err = MessageError::from_msg(MessageErrorKind::InvalidAddress, "1.2.3:4");
+        assert_eq!(
+            String::from(err),
+            "An invalid address was supplied: 1.2.3:4"
+        );
+        assert_eq!(
+            MessageError::from(MessageErrorKind::Truncated).code(),
+            (9 << 24) | 1
+        );
+    }
+}
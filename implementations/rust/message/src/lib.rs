@@ -1,10 +1,14 @@
 #![allow(unused)]
 
+#[macro_use]
+extern crate ockam_common;
+
 // Definition and implementation of an Ockam message and message components.
 // Each message component, and the message overall, implements the "Codec" trait
 // allowing it to be encoded/decoded for transmission over a transport.
 
 pub mod message {
+    use crate::error::{MessageError, MessageErrorKind};
     use crate::message::Address::ChannelAddress;
     use crate::message::MessageType::Payload;
     use hex::*;
@@ -31,8 +35,8 @@ pub mod message {
     pub trait Codec {
         type Inner;
 
-        fn encode(&self, v: &mut Vec<u8>) -> Result<(), String>;
-        fn decode(s: &[u8]) -> Result<(Self::Inner, &[u8]), String>;
+        fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError>;
+        fn decode(s: &[u8]) -> Result<(Self::Inner, &[u8]), MessageError>;
     }
 
     //    #[repr(C)]
@@ -44,7 +48,7 @@ pub mod message {
         pub message_body: Vec<u8>,
     }
 
-    #[derive(Copy, Clone, Debug)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub enum MessageType {
         Ping = 0,
         Pong = 1,
@@ -52,6 +56,17 @@ pub mod message {
         KeyAgreementM1 = 3,
         KeyAgreementM2 = 4,
         KeyAgreementM3 = 5,
+        // the sender closed its end of a channel; encrypted between the ends, and passed to the
+        // worker owning each end as it closes
+        Close = 6,
+        // a piece of a payload too large for one frame, encrypted between the ends of a channel
+        Fragment = 7,
+        // a channel's key exchange failed; passed to the worker which initiated it, with the
+        // code of the error as the body, a little-endian u32
+        ChannelError = 8,
+        // acknowledges the frames of a stream a reliable channel received, encrypted between
+        // the ends of the channel
+        Ack = 9,
         None = 255,
     }
 
@@ -66,39 +81,45 @@ pub mod message {
         }
     }
 
+    impl Message {
+        /// An ID for following the message in traces: a hash of its type and body, which no
+        /// hop changes, so a payload has the same ID at every node of its route. A channel
+        /// traces the IDs of a payload's plaintext and ciphertext together.
+        pub fn trace_id(&self) -> String {
+            trace_id(self.message_type, &self.message_body)
+        }
+    }
+
+    fn trace_id(message_type: MessageType, body: &[u8]) -> String {
+        // 64-bit FNV-1a
+        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+        for b in std::iter::once(message_type as u8).chain(body.iter().copied()) {
+            h ^= b as u64;
+            h = h.wrapping_mul(0x0100_0000_01b3);
+        }
+        format!("{:016x}", h)
+    }
+
     impl Codec for Message {
         type Inner = Message;
-        fn encode(&self, u: &mut Vec<u8>) -> Result<(), String> {
+        fn encode(&self, u: &mut Vec<u8>) -> Result<(), MessageError> {
             u.push(1);
-            Route::encode(&self.onward_route.clone(), u);
-            Route::encode(&self.return_route.clone(), u);
+            Route::encode(&self.onward_route, u)?;
+            Route::encode(&self.return_route, u)?;
             u.push(self.message_type as u8);
             u.extend(&self.message_body[0..]);
             Ok(())
         }
 
-        fn decode(u: &[u8]) -> Result<(Message, &[u8]), String> {
+        fn decode(u: &[u8]) -> Result<(Message, &[u8]), MessageError> {
             let mut msg = Message::default();
-            let mut w = &u[1..];
-            match Route::decode(w) {
-                Ok((r, u1)) => {
-                    msg.onward_route = r;
-                    w = u1;
-                }
-                Err(s) => {
-                    return Err(s);
-                }
-            }
-            match Route::decode(w) {
-                Ok((r, u1)) => {
-                    msg.return_route = r;
-                    w = u1;
-                }
-                Err(s) => {
-                    return Err(s);
-                }
-            }
-            msg.message_type = MessageType::try_from(w[0])?;
+            let w = take(u, 1)?;
+            let (r, w) = Route::decode(w)?;
+            msg.onward_route = r;
+            let (r, w) = Route::decode(w)?;
+            msg.return_route = r;
+            msg.message_type =
+                MessageType::try_from(*w.first().ok_or(MessageErrorKind::Truncated)?)?;
             let mut w = &w[1..];
             msg.message_body = w.to_vec();
             Ok((msg, w))
@@ -146,16 +167,22 @@ pub mod message {
                 _ => "error".to_string(),
             }
         }
-        pub fn worker_address_from_string(s: &str) -> Result<Address, String> {
+        pub fn worker_address_from_string(s: &str) -> Result<Address, MessageError> {
             match hex::decode(s) {
                 Ok(h) => Ok(Address::WorkerAddress(h)),
-                _ => Err("string must only contain hex digits".into()),
+                _ => Err(MessageError::from_msg(
+                    MessageErrorKind::InvalidAddress,
+                    "string must only contain hex digits",
+                )),
             }
         }
-        pub fn channel_address_from_string(s: &str) -> Result<Address, String> {
+        pub fn channel_address_from_string(s: &str) -> Result<Address, MessageError> {
             match hex::decode(s) {
                 Ok(h) => Ok(Address::ChannelAddress(h)),
-                _ => Err("string must only contain hex digits".into()),
+                _ => Err(MessageError::from_msg(
+                    MessageErrorKind::InvalidAddress,
+                    "string must only contain hex digits",
+                )),
             }
         }
         pub fn size_of(&self) -> u8 {
@@ -218,8 +245,13 @@ pub mod message {
         }
     }
 
+    // The rest of the input once `n` bytes have been read from it.
+    fn take(u: &[u8], n: usize) -> Result<&[u8], MessageError> {
+        u.get(n..).ok_or_else(|| MessageErrorKind::Truncated.into())
+    }
+
     impl TryFrom<u8> for MessageType {
-        type Error = String;
+        type Error = MessageError;
         fn try_from(data: u8) -> Result<Self, Self::Error> {
             match data {
                 0 => Ok(MessageType::Ping),
@@ -228,24 +260,28 @@ pub mod message {
                 3 => Ok(MessageType::KeyAgreementM1),
                 4 => Ok(MessageType::KeyAgreementM2),
                 5 => Ok(MessageType::KeyAgreementM3),
-                _ => Err("Unknown message type".to_string()),
+                6 => Ok(MessageType::Close),
+                7 => Ok(MessageType::Fragment),
+                8 => Ok(MessageType::ChannelError),
+                9 => Ok(MessageType::Ack),
+                _ => Err(MessageErrorKind::UnknownMessageType.into()),
             }
         }
     }
 
     impl TryFrom<u8> for HostAddressType {
-        type Error = String;
+        type Error = MessageError;
         fn try_from(data: u8) -> Result<Self, Self::Error> {
             match data {
                 0 => Ok(HostAddressType::Ipv4),
                 1 => Ok(HostAddressType::Ipv6),
-                _ => Err("Unknown host address type".to_string()),
+                _ => Err(MessageErrorKind::UnknownHostAddressType.into()),
             }
         }
     }
 
     impl TryFrom<u8> for AddressType {
-        type Error = String;
+        type Error = MessageError;
         fn try_from(data: u8) -> Result<AddressType, Self::Error> {
             match data {
                 255 => Ok(AddressType::Undefined),
@@ -253,91 +289,71 @@ pub mod message {
                 2 => Ok(AddressType::Udp),
                 129 => Ok(AddressType::Channel),
                 0 => Ok(AddressType::Worker),
-                _ => Err("Unknown address type".to_string()),
+                _ => Err(MessageErrorKind::UnknownAddressType.into()),
             }
         }
     }
 
     impl Codec for RouterAddress {
         type Inner = RouterAddress;
-        fn encode(&self, v: &mut Vec<u8>) -> Result<(), String> {
+        fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
             v.push(self.a_type as u8);
             v.push(self.length as u8);
 
-            match self.a_type {
-                AddressType::Worker => {
-                    if let Address::WorkerAddress(mut wa) = self.address.clone() {
-                        v.append(&mut wa);
-                    }
-                }
-                AddressType::Udp => {
-                    if let Address::UdpAddress(sock_addr) = self.address.clone() {
-                        SocketAddr::encode(&sock_addr, v);
-                    }
-                }
-                AddressType::Channel => {
-                    if let Address::ChannelAddress(mut ca) = self.address.clone() {
-                        v.append(&mut ca);
-                    }
+            match (self.a_type, &self.address) {
+                (AddressType::Worker, Address::WorkerAddress(wa)) => v.extend_from_slice(wa),
+                (AddressType::Udp, Address::UdpAddress(sock_addr)) => {
+                    SocketAddr::encode(sock_addr, v)?
                 }
+                (AddressType::Channel, Address::ChannelAddress(ca)) => v.extend_from_slice(ca),
                 _ => {}
             }
             Ok(())
         }
-        fn decode(u: &[u8]) -> Result<(RouterAddress, &[u8]), String> {
-            let mut a_type: AddressType;
-            a_type = AddressType::Undefined;
-            match AddressType::try_from(u[0]) {
-                Ok(t) => {
-                    a_type = t;
-                }
-                Err(s) => return Err(s),
+        fn decode(u: &[u8]) -> Result<(RouterAddress, &[u8]), MessageError> {
+            if u.len() < 2 {
+                return Err(MessageErrorKind::Truncated.into());
             }
+            let a_type = AddressType::try_from(u[0])?;
+            let length = u[1] as usize;
+            let value = u.get(2..(length + 2)).ok_or(MessageErrorKind::Truncated)?;
+            let rest = &u[(length + 2)..];
             match a_type {
-                AddressType::Channel => {
-                    let length = u[1] as usize;
-                    let addr: Vec<u8> = u[2..(length + 2)].to_vec();
-                    Ok((
-                        RouterAddress {
-                            a_type: AddressType::Channel,
-                            length: addr.len() as u8,
-                            address: Address::ChannelAddress(addr),
-                        },
-                        &u[(length + 2)..],
-                    ))
-                }
-                AddressType::Worker => {
-                    let length = u[1] as usize;
-                    let addr: Vec<u8> = u[2..(length + 2)].to_vec();
-                    Ok((
-                        RouterAddress {
-                            a_type: AddressType::Worker,
-                            length: addr.len() as u8,
-                            address: Address::WorkerAddress(addr),
-                        },
-                        &u[(length + 2)..],
-                    ))
-                }
+                AddressType::Channel => Ok((
+                    RouterAddress {
+                        a_type: AddressType::Channel,
+                        length: value.len() as u8,
+                        address: Address::ChannelAddress(value.to_vec()),
+                    },
+                    rest,
+                )),
+                AddressType::Worker => Ok((
+                    RouterAddress {
+                        a_type: AddressType::Worker,
+                        length: value.len() as u8,
+                        address: Address::WorkerAddress(value.to_vec()),
+                    },
+                    rest,
+                )),
                 AddressType::Udp => {
-                    let (sock, v) = SocketAddr::decode(&u[2..])?;
-                    let address = Address::UdpAddress(sock);
+                    let (sock, _) = SocketAddr::decode(value)?;
                     Ok((
                         RouterAddress {
                             a_type: AddressType::Udp,
                             length: u[1],
                             address: Address::UdpAddress(sock),
                         },
-                        &u[u[1] as usize + 2..],
+                        rest,
                     ))
                 }
-                _ => Err("unimplemented address type".to_string()),
+                _ => Err(MessageErrorKind::NotImplemented.into()),
             }
         }
     }
 
     impl Codec for IpAddr {
         type Inner = IpAddr;
-        fn encode(&self, v: &mut Vec<u8>) -> Result<(), String> {
+        fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
             match self {
                 std::net::IpAddr::V4(ip4) => {
                     v.push(HostAddressType::Ipv4 as u8);
@@ -350,21 +366,23 @@ pub mod message {
             }
             Ok(())
         }
-        fn decode(u: &[u8]) -> Result<(IpAddr, &[u8]), String> {
-            match (HostAddressType::try_from(u[0])?, &u[1..]) {
-                (HostAddressType::Ipv4, addr) => {
+        fn decode(u: &[u8]) -> Result<(IpAddr, &[u8]), MessageError> {
+            let host_type = *u.first().ok_or(MessageErrorKind::Truncated)?;
+            match HostAddressType::try_from(host_type)? {
+                HostAddressType::Ipv4 => {
+                    let addr = u.get(1..5).ok_or(MessageErrorKind::Truncated)?;
                     let ip4 = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
                     let ip_addr = IpAddr::V4(ip4);
                     Ok((ip_addr, &u[5..]))
                 }
-                _ => Err("".to_string()),
+                _ => Err(MessageErrorKind::NotImplemented.into()),
             }
         }
     }
 
     impl Codec for SocketAddr {
         type Inner = SocketAddr;
-        fn encode(&self, v: &mut Vec<u8>) -> Result<(), String> {
+        fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
             match self {
                 std::net::SocketAddr::V4(sock4) => {
                     v.push(HostAddressType::Ipv4 as u8);
@@ -381,15 +399,17 @@ pub mod message {
             }
             Ok(())
         }
-        fn decode(u: &[u8]) -> Result<(SocketAddr, &[u8]), String> {
-            match (HostAddressType::try_from(u[0])?, &u[1..]) {
-                (HostAddressType::Ipv4, addr) => {
+        fn decode(u: &[u8]) -> Result<(SocketAddr, &[u8]), MessageError> {
+            let host_type = *u.first().ok_or(MessageErrorKind::Truncated)?;
+            match HostAddressType::try_from(host_type)? {
+                HostAddressType::Ipv4 => {
+                    let addr = u.get(1..7).ok_or(MessageErrorKind::Truncated)?;
                     let ip4 = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
                     let port = u16::from_le_bytes([addr[4], addr[5]]);
                     let sock = SocketAddr::new(IpAddr::V4(ip4), port);
-                    Ok((sock, &addr[6..]))
+                    Ok((sock, &u[7..]))
                 }
-                _ => Err("".to_string()),
+                _ => Err(MessageErrorKind::NotImplemented.into()),
             }
         }
     }
@@ -444,34 +464,43 @@ pub mod message {
                 _ => None,
             }
         }
-        pub fn udp_router_address_from_str(s: &str) -> Result<RouterAddress, String> {
+        pub fn udp_router_address_from_str(s: &str) -> Result<RouterAddress, MessageError> {
             match SocketAddr::from_str(s) {
                 Ok(s) => Ok(RouterAddress {
                     a_type: AddressType::Udp,
                     length: 7,
                     address: Address::UdpAddress(s),
                 }),
-                Err(_unused) => Err("failed to parse router address".to_string()),
+                Err(_unused) => Err(MessageError::from_msg(
+                    MessageErrorKind::InvalidAddress,
+                    "failed to parse router address",
+                )),
             }
         }
-        pub fn channel_router_address_from_str(a: &str) -> Result<RouterAddress, String> {
+        pub fn channel_router_address_from_str(a: &str) -> Result<RouterAddress, MessageError> {
             match hex::decode(a) {
                 Ok(h) => Ok(RouterAddress {
                     a_type: AddressType::Channel,
                     length: h.len() as u8,
                     address: Address::ChannelAddress(h),
                 }),
-                Err(_unused) => Err("string contains non-hex chars".to_string()),
+                Err(_unused) => Err(MessageError::from_msg(
+                    MessageErrorKind::InvalidAddress,
+                    "string contains non-hex chars",
+                )),
             }
         }
-        pub fn worker_router_address_from_str(a: &str) -> Result<RouterAddress, String> {
+        pub fn worker_router_address_from_str(a: &str) -> Result<RouterAddress, MessageError> {
             match hex_vec_from_str(a) {
                 Ok(h) => Ok(RouterAddress {
                     a_type: AddressType::Worker,
                     length: h.len() as u8,
                     address: Address::WorkerAddress(h),
                 }),
-                Err(_unused) => Err("invalid hex input".to_string()),
+                Err(_unused) => Err(MessageError::from_msg(
+                    MessageErrorKind::InvalidAddress,
+                    "invalid hex input",
+                )),
             }
         }
     }
@@ -497,35 +526,197 @@ pub mod message {
 
     impl Codec for Route {
         type Inner = Route;
-        fn encode(&self, u: &mut Vec<u8>) -> Result<(), String> {
+        fn encode(&self, u: &mut Vec<u8>) -> Result<(), MessageError> {
             if self.addresses.is_empty() {
                 u.push(0 as u8)
             } else {
                 u.push(self.addresses.len() as u8);
-                for i in 0..self.addresses.len() {
-                    RouterAddress::encode(&self.addresses[i].clone(), u);
+                for a in &self.addresses {
+                    RouterAddress::encode(a, u)?;
                 }
             }
             Ok(())
         }
-        fn decode(encoded: &[u8]) -> Result<(Route, &[u8]), String> {
+        fn decode(encoded: &[u8]) -> Result<(Route, &[u8]), MessageError> {
             let mut route = Route { addresses: vec![] };
+            let count = *encoded.first().ok_or(MessageErrorKind::Truncated)?;
             let mut next_address = &encoded[1..];
-            if 0 < encoded[0] {
-                for i in 0..encoded[0] as usize {
-                    match RouterAddress::decode(next_address) {
-                        Ok((a, x)) => {
-                            route.addresses.push(a);
-                            next_address = x;
-                        }
-                        Err(s) => {}
-                    }
-                }
+            for _ in 0..count {
+                let (a, x) = RouterAddress::decode(next_address)?;
+                route.addresses.push(a);
+                next_address = x;
             }
             Ok((route, next_address))
         }
     }
 
+    /// A router address borrowed from an encoded message
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct AddressRef<'a> {
+        pub a_type: AddressType,
+        /// The address as encoded, after its type and length
+        pub value: &'a [u8],
+    }
+
+    impl<'a> AddressRef<'a> {
+        fn decode(u: &'a [u8]) -> Result<(AddressRef<'a>, &'a [u8]), MessageError> {
+            if u.len() < 2 {
+                return Err(MessageErrorKind::Truncated.into());
+            }
+            let a_type = AddressType::try_from(u[0])?;
+            let length = u[1] as usize;
+            let value = u.get(2..(length + 2)).ok_or(MessageErrorKind::Truncated)?;
+            match a_type {
+                AddressType::Channel | AddressType::Worker => {}
+                AddressType::Udp => {
+                    SocketAddr::decode(value)?;
+                }
+                _ => return Err(MessageErrorKind::NotImplemented.into()),
+            }
+            Ok((AddressRef { a_type, value }, &u[(length + 2)..]))
+        }
+
+        pub fn encode(&self, v: &mut Vec<u8>) {
+            v.push(self.a_type as u8);
+            v.push(self.value.len() as u8);
+            v.extend_from_slice(self.value);
+        }
+
+        /// The socket address of a UDP address
+        pub fn udp_address(&self) -> Option<SocketAddr> {
+            match self.a_type {
+                AddressType::Udp => SocketAddr::decode(self.value).ok().map(|(sock, _)| sock),
+                _ => None,
+            }
+        }
+    }
+
+    /// A route borrowed from an encoded message, its addresses decoded as they are read
+    #[derive(Clone, Copy, Debug)]
+    pub struct RouteRef<'a> {
+        count: u8,
+        encoded: &'a [u8],
+    }
+
+    impl<'a> RouteRef<'a> {
+        fn decode(u: &'a [u8]) -> Result<(RouteRef<'a>, &'a [u8]), MessageError> {
+            let count = *u.first().ok_or(MessageErrorKind::Truncated)?;
+            let mut rest = &u[1..];
+            for _ in 0..count {
+                rest = AddressRef::decode(rest)?.1;
+            }
+            let encoded = &u[1..(u.len() - rest.len())];
+            Ok((RouteRef { count, encoded }, rest))
+        }
+
+        pub fn len(&self) -> usize {
+            self.count as usize
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.count == 0
+        }
+
+        pub fn addresses(&self) -> impl Iterator<Item = AddressRef<'a>> {
+            let mut rest = self.encoded;
+            (0..self.count).map(move |_| {
+                // checked when the route was decoded
+                let (a, r) = AddressRef::decode(rest).unwrap();
+                rest = r;
+                a
+            })
+        }
+
+        pub fn first(&self) -> Option<AddressRef<'a>> {
+            self.addresses().next()
+        }
+    }
+
+    /// A message borrowed from its encoding, for the hops which only forward it, e.g. a relay.
+    /// Forwarding it this way decodes no more than its first onward address, and allocates
+    /// nothing once the hop's buffer has grown to the size of its messages.
+    #[derive(Clone, Copy, Debug)]
+    pub struct MessageRef<'a> {
+        pub onward_route: RouteRef<'a>,
+        pub return_route: RouteRef<'a>,
+        pub message_type: MessageType,
+        pub message_body: &'a [u8],
+    }
+
+    impl<'a> MessageRef<'a> {
+        pub fn decode(u: &'a [u8]) -> Result<MessageRef<'a>, MessageError> {
+            let w = take(u, 1)?;
+            let (onward_route, w) = RouteRef::decode(w)?;
+            let (return_route, w) = RouteRef::decode(w)?;
+            let message_type =
+                MessageType::try_from(*w.first().ok_or(MessageErrorKind::Truncated)?)?;
+            Ok(MessageRef {
+                onward_route,
+                return_route,
+                message_type,
+                message_body: &w[1..],
+            })
+        }
+
+        /// See [`Message::trace_id`]
+        pub fn trace_id(&self) -> String {
+            trace_id(self.message_type, self.message_body)
+        }
+
+        /// Encode the message as forwarded by the hop at `hop`: without the first address of
+        /// its onward route, and with `hop` at the front of its return route. `v` is cleared
+        /// first, so that a hop can reuse one buffer for every message it forwards.
+        pub fn forward(&self, hop: &RouterAddress, v: &mut Vec<u8>) -> Result<(), MessageError> {
+            self.forward_with(hop, None, v)
+        }
+
+        /// As [`forward`](MessageRef::forward), for a message which arrived from `sender`: a UDP
+        /// address at the front of its return route is replaced by `sender`. A node puts the
+        /// address its socket is bound to there, which a node behind NAT can't be reached at,
+        /// while the address its messages arrive from can be, for as long as the NAT keeps the
+        /// mapping.
+        pub fn forward_from(
+            &self,
+            hop: &RouterAddress,
+            sender: &RouterAddress,
+            v: &mut Vec<u8>,
+        ) -> Result<(), MessageError> {
+            self.forward_with(hop, Some(sender), v)
+        }
+
+        fn forward_with(
+            &self,
+            hop: &RouterAddress,
+            sender: Option<&RouterAddress>,
+            v: &mut Vec<u8>,
+        ) -> Result<(), MessageError> {
+            let first = self
+                .onward_route
+                .first()
+                .ok_or(MessageErrorKind::Truncated)?;
+            if self.return_route.count == u8::MAX {
+                return Err(MessageErrorKind::ValueTooLarge.into());
+            }
+            v.clear();
+            v.push(WIRE_PROTOCOL_VERSION);
+            v.push(self.onward_route.count - 1);
+            v.extend_from_slice(&self.onward_route.encoded[(2 + first.value.len())..]);
+            v.push(self.return_route.count + 1);
+            hop.encode(v)?;
+            let mut rest = self.return_route.encoded;
+            if let (Some(sender), Some(claimed)) = (sender, self.return_route.first()) {
+                if claimed.a_type == AddressType::Udp {
+                    sender.encode(v)?;
+                    rest = &rest[(2 + claimed.value.len())..];
+                }
+            }
+            v.extend_from_slice(rest);
+            v.push(self.message_type as u8);
+            v.extend_from_slice(self.message_body);
+            Ok(())
+        }
+    }
+
     // ToDo: Implement PartialEq, Eq, Copy, Clone
 
     // u16's are encoded as variable-length.
@@ -535,9 +726,9 @@ pub mod message {
     //   make room.
     impl Codec for u16 {
         type Inner = u16;
-        fn encode(&self, u: &mut Vec<u8>) -> Result<(), String> {
+        fn encode(&self, u: &mut Vec<u8>) -> Result<(), MessageError> {
             if self >= &0xC000 {
-                return Err("Maximum value exceeded".to_string());
+                return Err(MessageErrorKind::ValueTooLarge.into());
             }
             let mut bytes = self.to_le_bytes();
 
@@ -554,12 +745,15 @@ pub mod message {
             }
             Ok(())
         }
-        fn decode(u: &[u8]) -> Result<(Self::Inner, &[u8]), String> {
+        fn decode(u: &[u8]) -> Result<(Self::Inner, &[u8]), MessageError> {
             let mut bytes = [0, 0];
             let mut i = 1;
 
-            bytes[0] = u[0] & 0x7f;
+            bytes[0] = *u.first().ok_or(MessageErrorKind::Truncated)? & 0x7f;
             if (u[0] & 0x80) == 0x80 as u8 {
+                if u.len() < 2 {
+                    return Err(MessageErrorKind::Truncated.into());
+                }
                 bytes[0] += (u[1] & 0x01) << 7;
                 bytes[1] = u[1] >> 1;
                 i = 2;
@@ -607,10 +801,13 @@ pub mod message {
         }
     }
 
-    pub fn hex_vec_from_str(s: &str) -> Result<Vec<u8>, String> {
+    pub fn hex_vec_from_str(s: &str) -> Result<Vec<u8>, MessageError> {
         let mut hex: Vec<u8> = vec![];
         if s.len() % 2 != 0 {
-            return Err("odd number of input chars".into());
+            return Err(MessageError::from_msg(
+                MessageErrorKind::InvalidAddress,
+                "odd number of input chars",
+            ));
         }
         for i in 0..s.len() {
             if 0 == i % 2 {
@@ -620,7 +817,10 @@ pub mod message {
                         hex.push(val);
                     }
                     _ => {
-                        return Err("non-hex characters found in string".into());
+                        return Err(MessageError::from_msg(
+                            MessageErrorKind::InvalidAddress,
+                            "non-hex characters found in string",
+                        ));
                     }
                 }
             }
@@ -629,6 +829,9 @@ pub mod message {
     }
 }
 
+/// Represents the errors that occur encoding and decoding messages
+pub mod error;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1105,4 +1308,126 @@ mod tests {
             _ => {}
         }
     }
+
+    #[test]
+    fn test_trace_id() {
+        let mut m = Message {
+            message_body: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let id = m.trace_id();
+        assert_eq!(id.len(), 16);
+
+        // hops change the routes, not the ID
+        m.onward_route
+            .addresses
+            .push(RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap());
+        m.return_route
+            .addresses
+            .push(RouterAddress::channel_router_address_from_str("00010203").unwrap());
+        assert_eq!(m.trace_id(), id);
+
+        m.message_body.push(4);
+        assert_ne!(m.trace_id(), id);
+        m.message_body.pop();
+        m.message_type = MessageType::Ping;
+        assert_ne!(m.trace_id(), id);
+    }
+
+    #[test]
+    fn test_message_ref_forward() {
+        let m = Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::udp_router_address_from_str("127.0.0.1:4050").unwrap(),
+                    RouterAddress::udp_router_address_from_str("10.0.1.10:4051").unwrap(),
+                    RouterAddress::channel_router_address_from_str("00010203").unwrap(),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![RouterAddress::channel_router_address_from_str("04050607").unwrap()],
+            },
+            message_type: MessageType::Payload,
+            message_body: vec![1, 2, 3, 4],
+        };
+        let mut encoded = vec![];
+        m.encode(&mut encoded).unwrap();
+        let hop = RouterAddress::udp_router_address_from_str("192.168.1.1:4052").unwrap();
+
+        let r = MessageRef::decode(&encoded).unwrap();
+        assert_eq!(r.trace_id(), m.trace_id());
+        assert_eq!(r.onward_route.len(), 3);
+        assert_eq!(
+            r.onward_route.first().unwrap().udp_address(),
+            Some(SocketAddr::from_str("127.0.0.1:4050").unwrap())
+        );
+        let mut forwarded = vec![0xff; 8];
+        r.forward(&hop, &mut forwarded).unwrap();
+
+        // the same as decoding the message and forwarding it whole
+        let mut expected = m.clone();
+        expected.onward_route.addresses.remove(0);
+        expected.return_route.addresses.insert(0, hop);
+        let mut expected_encoded = vec![];
+        expected.encode(&mut expected_encoded).unwrap();
+        assert_eq!(forwarded, expected_encoded);
+
+        for n in 0..encoded.len() - 4 {
+            assert!(MessageRef::decode(&encoded[..n]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_message_ref_forward_from() {
+        let claimed = RouterAddress::udp_router_address_from_str("192.168.1.20:4051").unwrap();
+        let sender = RouterAddress::udp_router_address_from_str("203.0.113.7:61002").unwrap();
+        let hop = RouterAddress::udp_router_address_from_str("10.0.1.10:4050").unwrap();
+        let mut m = Message {
+            onward_route: Route {
+                addresses: vec![
+                    hop.clone(),
+                    RouterAddress::channel_router_address_from_str("00010203").unwrap(),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![
+                    claimed,
+                    RouterAddress::worker_router_address_from_str("04050607").unwrap(),
+                ],
+            },
+            message_type: MessageType::Payload,
+            message_body: vec![1, 2, 3, 4],
+        };
+        let mut encoded = vec![];
+        m.encode(&mut encoded).unwrap();
+        let mut forwarded = vec![];
+        MessageRef::decode(&encoded)
+            .unwrap()
+            .forward_from(&hop, &sender, &mut forwarded)
+            .unwrap();
+        let (forwarded, _) = Message::decode(&forwarded).unwrap();
+        assert_eq!(
+            forwarded.return_route.addresses,
+            vec![
+                hop.clone(),
+                sender.clone(),
+                m.return_route.addresses[1].clone()
+            ]
+        );
+
+        // only a UDP address is replaced
+        m.return_route.addresses.remove(0);
+        encoded.clear();
+        m.encode(&mut encoded).unwrap();
+        let mut forwarded = vec![];
+        MessageRef::decode(&encoded)
+            .unwrap()
+            .forward_from(&hop, &sender, &mut forwarded)
+            .unwrap();
+        let (forwarded, _) = Message::decode(&forwarded).unwrap();
+        assert_eq!(
+            forwarded.return_route.addresses,
+            vec![hop, m.return_route.addresses[0].clone()]
+        );
+    }
 }
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use ockam_channel::*;
-use ockam_kex::xx::{XXInitiator, XXNewKeyExchanger, XXResponder};
+use ockam_kex::xx::XXNewKeyExchanger;
 use ockam_kex::CipherSuite;
 use ockam_message::message::*;
 use ockam_router::router::*;
@@ -220,7 +220,6 @@ pub fn start_node(
         UdpTransport::new(transport_rx, transport_tx, router_tx.clone(), &sock_str).unwrap();
 
     let _join_thread: thread::JoinHandle<_> = thread::spawn(move || {
-        type XXChannelManager = ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
         let vault = Arc::new(Mutex::new(DefaultVault::default()));
 
         let new_key_exchanger = XXNewKeyExchanger::new(
@@ -231,12 +230,12 @@ pub fn start_node(
 
         // the channel handler cannot, in its current implementation, be passed safely
         // between threads, so it is created in the context of the polling thread
-        let mut channel_handler = XXChannelManager::new(
+        let mut channel_handler = ChannelManager::new(
             channel_rx,
             channel_tx.clone(),
             router_tx.clone(),
             vault,
-            new_key_exchanger,
+            Box::new(new_key_exchanger),
             None,
             None,
         )
@@ -248,13 +247,15 @@ pub fn start_node(
                     network_route,
                     Address::WorkerAddress(hex::decode("00010203").unwrap()),
                     None,
+                    None,
                 )))
                 .unwrap();
         }
 
+        // the transport wakes the router as datagrams arrive
         while transport.poll() && router.poll() && channel_handler.poll().unwrap() && worker.poll()
         {
-            thread::sleep(time::Duration::from_millis(100));
+            router.wait(time::Duration::from_millis(100));
         }
     });
 
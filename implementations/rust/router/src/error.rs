@@ -0,0 +1,36 @@
+use failure::Fail;
+use ockam_common::error::{ErrorKind, ERROR_INTERFACE_ROUTER};
+
+/// Represents the failures that can occur
+/// routing a message
+#[derive(Clone, Copy, Fail, Debug)]
+#[non_exhaustive]
+pub enum RouterErrorKind {
+    /// The message has no onward route
+    #[fail(display = "No route supplied")]
+    NoRoute,
+    /// Nothing is registered for the type of the next address
+    #[fail(display = "No handler is registered for the address type")]
+    NoHandler,
+    /// The address type can't be routed
+    #[fail(display = "The address type is not implemented")]
+    NotImplemented,
+    /// The handler has stopped
+    #[fail(display = "Unable to send to the handler")]
+    CantSend,
+}
+
+impl ErrorKind for RouterErrorKind {
+    const ERROR_INTERFACE: usize = ERROR_INTERFACE_ROUTER;
+
+    fn to_usize(&self) -> usize {
+        match *self {
+            RouterErrorKind::NoRoute => Self::ERROR_INTERFACE | 1,
+            RouterErrorKind::NoHandler => Self::ERROR_INTERFACE | 2,
+            RouterErrorKind::NotImplemented => Self::ERROR_INTERFACE | 3,
+            RouterErrorKind::CantSend => Self::ERROR_INTERFACE | 4,
+        }
+    }
+}
+
+error_impl!(RouterError, RouterErrorKind);
@@ -1,8 +1,16 @@
 #![allow(unused)]
+
+#[macro_use]
+extern crate ockam_common;
+
+/// Represents the errors that occur routing messages
+pub mod error;
+
 pub mod router {
+    use crate::error::{RouterError, RouterErrorKind};
     use ockam_message::message::*;
     use ockam_system::commands::{
-        ChannelCommand, OckamCommand, RouterCommand, TransportCommand, WorkerCommand,
+        ChannelCommand, OckamCommand, Response, RouterCommand, TransportCommand, WorkerCommand,
     };
     use std::convert::TryFrom;
     use std::fs::OpenOptions;
@@ -13,8 +21,11 @@ pub mod router {
     pub struct Router {
         registry: Vec<Option<std::sync::mpsc::Sender<OckamCommand>>>,
         rx: std::sync::mpsc::Receiver<OckamCommand>,
+        // a command received by wait, handled at the next poll
+        pending: Option<OckamCommand>,
     }
 
+    #[derive(Debug)]
     pub enum Direction {
         Outgoing,
         Incoming,
@@ -25,6 +36,7 @@ pub mod router {
             Router {
                 registry: vec![Option::None; 256],
                 rx,
+                pending: None,
             }
         }
 
@@ -32,8 +44,17 @@ pub mod router {
             &mut self,
             address: Address,
             handler: Arc<Mutex<dyn Receiver + 'static + Send>>,
-        ) -> Result<(), String> {
-            Err("not implemented".into())
+        ) -> Result<(), RouterError> {
+            Err(RouterErrorKind::NotImplemented.into())
+        }
+
+        /// Block until the router is sent a command, or until `timeout` has passed, leaving the
+        /// command for the next poll. Other threads send `RouterCommand::Wake` to end the wait
+        /// when they have work for a component polled alongside the router.
+        pub fn wait(&mut self, timeout: time::Duration) {
+            if self.pending.is_none() {
+                self.pending = self.rx.recv_timeout(timeout).ok();
+            }
         }
 
         pub fn poll(&mut self) -> bool {
@@ -41,9 +62,31 @@ pub mod router {
             let mut got = true;
             while got {
                 got = false;
-                match self.rx.try_recv() {
+                let next = match self.pending.take() {
+                    Some(rc) => Ok(rc),
+                    None => self.rx.try_recv(),
+                };
+                match next {
                     Ok(rc) => match rc {
                         OckamCommand::Router(RouterCommand::Stop) => {
+                            // pass the stop on to each registered component
+                            for (a_type, tx) in self.registry.iter().enumerate() {
+                                let stop = match AddressType::try_from(a_type as u8) {
+                                    Ok(AddressType::Worker) => {
+                                        OckamCommand::Worker(WorkerCommand::Stop)
+                                    }
+                                    Ok(AddressType::Channel) => {
+                                        OckamCommand::Channel(ChannelCommand::Stop)
+                                    }
+                                    Ok(AddressType::Tcp) | Ok(AddressType::Udp) => {
+                                        OckamCommand::Transport(TransportCommand::Stop)
+                                    }
+                                    _ => continue,
+                                };
+                                if let Some(tx) = tx {
+                                    tx.send(stop);
+                                }
+                            }
                             println!("quit!");
                             got = true;
                             keep_going = false;
@@ -61,6 +104,26 @@ pub mod router {
                             got = true;
                             self.route(m, Direction::Outgoing);
                         }
+                        OckamCommand::Router(RouterCommand::TransportReconnected(a)) => {
+                            got = true;
+                            // channels are the only component that reacts to connectivity
+                            if let Some(tx) = &self.registry[AddressType::Channel as usize] {
+                                tx.send(OckamCommand::Channel(
+                                    ChannelCommand::TransportReconnected(a),
+                                ));
+                            }
+                        }
+                        OckamCommand::Router(RouterCommand::Wake) => {
+                            got = true;
+                        }
+                        OckamCommand::Router(RouterCommand::GetInfo(reply_to)) => {
+                            got = true;
+                            let registered = (0..self.registry.len())
+                                .filter(|a_type| self.registry[*a_type].is_some())
+                                .filter_map(|a_type| AddressType::try_from(a_type as u8).ok())
+                                .collect();
+                            reply_to.answer(Ok(Response::Info(registered)));
+                        }
                         _ => println!("Router received bad command"),
                     },
                     Err(e) => {}
@@ -69,49 +132,52 @@ pub mod router {
             keep_going
         }
 
-        fn route(&mut self, m: Message, direction: Direction) -> Result<(), String> {
+        fn route(&mut self, m: Message, direction: Direction) -> Result<(), RouterError> {
+            let span = tracing::debug_span!(
+                "route",
+                id = %m.trace_id(),
+                ?direction,
+                next_hop = tracing::field::Empty
+            );
+            let _enter = span.enter();
             if m.onward_route.addresses.is_empty() {
-                return Err("no route supplied".to_string());
+                tracing::debug!("no onward route");
+                return Err(RouterErrorKind::NoRoute.into());
+            }
+            // formatting the address allocates, so only when tracing
+            if !span.is_disabled() {
+                span.record(
+                    "next_hop",
+                    m.onward_route.addresses[0].address.as_string().as_str(),
+                );
             }
 
-            // println!("routing onward/return: ");
-            // m.onward_route.print_route();
-            // m.return_route.print_route();
-            let destination_address = m.onward_route.addresses[0].clone();
-            let address_type = destination_address.a_type;
-            let at = address_type as u8;
-            let att = AddressType::try_from(at).unwrap();
+            let address_type = m.onward_route.addresses[0].a_type;
             let handler_tx = match &self.registry[address_type as usize] {
                 Some(a) => a,
-                None => return Err("no handler".to_string()),
+                None => return Err(RouterErrorKind::NoHandler.into()),
             };
-            match address_type {
-                AddressType::Channel => match direction {
-                    Direction::Incoming => {
-                        handler_tx.send(OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)));
-                        Ok(())
-                    }
-                    Direction::Outgoing => {
-                        handler_tx.send(OckamCommand::Channel(ChannelCommand::SendMessage(m)));
-                        Ok(())
-                    }
-                },
-                AddressType::Worker => match direction {
-                    Direction::Incoming => {
-                        handler_tx.send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(m)));
-                        Ok(())
-                    }
-                    Direction::Outgoing => {
-                        handler_tx.send(OckamCommand::Worker(WorkerCommand::SendMessage(m)));
-                        Ok(())
-                    }
-                },
-                AddressType::Udp => {
-                    handler_tx.send(OckamCommand::Transport(TransportCommand::SendMessage(m)));
-                    Ok(())
+            let command = match (address_type, direction) {
+                (AddressType::Channel, Direction::Incoming) => {
+                    OckamCommand::Channel(ChannelCommand::ReceiveMessage(m))
                 }
-                _ => Err("not implemented".to_string()),
-            }
+                (AddressType::Channel, Direction::Outgoing) => {
+                    OckamCommand::Channel(ChannelCommand::SendMessage(m))
+                }
+                (AddressType::Worker, Direction::Incoming) => {
+                    OckamCommand::Worker(WorkerCommand::ReceiveMessage(m))
+                }
+                (AddressType::Worker, Direction::Outgoing) => {
+                    OckamCommand::Worker(WorkerCommand::SendMessage(m))
+                }
+                (AddressType::Udp, _) => OckamCommand::Transport(TransportCommand::SendMessage(m)),
+                _ => return Err(RouterErrorKind::NotImplemented.into()),
+            };
+            tracing::debug!("routing");
+            handler_tx.send(command).map_err(|_| {
+                tracing::warn!("handler is gone");
+                RouterErrorKind::CantSend.into()
+            })
         }
     }
 }
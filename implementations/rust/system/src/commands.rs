@@ -3,6 +3,65 @@
 use ockam_message::message::*;
 use ockam_vault::types::SecretKeyContext;
 
+// Identifies a request, so that its response or error can be matched with it
+pub type RequestId = u64;
+
+// What a component answers a request with
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Done,                   // carried out, with nothing to report
+    Info(Vec<AddressType>), // the address types a router has handlers for
+}
+
+// The kinds of component which send requests, and are answered in their own commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Component {
+    Transport,
+    Router,
+    Channel,
+    Worker,
+}
+
+// Where the answer to a request goes: the requester's sender, with the ID it gave the request
+#[derive(Debug, Clone)]
+pub struct ReplyTo {
+    pub id: RequestId,
+    pub component: Component,
+    pub tx: std::sync::mpsc::Sender<OckamCommand>,
+}
+
+impl ReplyTo {
+    pub fn new(
+        id: RequestId,
+        component: Component,
+        tx: std::sync::mpsc::Sender<OckamCommand>,
+    ) -> ReplyTo {
+        ReplyTo { id, component, tx }
+    }
+
+    // Answer the request with the response or error variant of the requester's commands
+    pub fn answer(&self, result: Result<Response, String>) -> Result<(), String> {
+        let id = self.id;
+        let cmd = match (self.component, result) {
+            (Component::Transport, Ok(r)) => {
+                OckamCommand::Transport(TransportCommand::Response(id, r))
+            }
+            (Component::Transport, Err(e)) => {
+                OckamCommand::Transport(TransportCommand::Error(id, e))
+            }
+            (Component::Router, Ok(r)) => OckamCommand::Router(RouterCommand::Response(id, r)),
+            (Component::Router, Err(e)) => OckamCommand::Router(RouterCommand::Error(id, e)),
+            (Component::Channel, Ok(r)) => OckamCommand::Channel(ChannelCommand::Response(id, r)),
+            (Component::Channel, Err(e)) => OckamCommand::Channel(ChannelCommand::Error(id, e)),
+            (Component::Worker, Ok(r)) => OckamCommand::Worker(WorkerCommand::Response(id, r)),
+            (Component::Worker, Err(e)) => OckamCommand::Worker(WorkerCommand::Error(id, e)),
+        };
+        self.tx
+            .send(cmd)
+            .map_err(|_| "the requester has gone".to_string())
+    }
+}
+
 #[derive(Debug)]
 pub enum OckamCommand {
     Transport(TransportCommand),
@@ -17,6 +76,8 @@ pub enum OckamCommand {
 pub enum TransportCommand {
     Stop,
     SendMessage(Message),
+    Response(RequestId, Response), // answers a request the transport made
+    Error(RequestId, String),      // a request the transport made failed
 }
 
 // Router commands - these can be sent to the
@@ -27,17 +88,29 @@ pub enum RouterCommand {
     Register(AddressType, std::sync::mpsc::Sender<OckamCommand>),
     SendMessage(Message),
     ReceiveMessage(Message),
+    TransportReconnected(RouterAddress), /* a transport regained connectivity to this
+                                          * peer, forwarded to the channel manager */
+    Wake, // return a router blocked in wait, e.g. once another thread has work for its node
+    GetInfo(ReplyTo), // answered with Response::Info
+    Response(RequestId, Response), // answers a request the router made
+    Error(RequestId, String), // a request the router made failed
 }
 
 // Channel commands - these can be sent to the
 // channel_tx
 #[derive(Debug)]
 pub enum ChannelCommand {
-    Initiate(Route, Address, Option<SecretKeyContext>), /* route to destination, return local
-                                                         * address */
+    // route to destination, return local address, static key, and a payload sent right after
+    // M3, saving the round trip to wait for the channel before sending it
+    Initiate(Route, Address, Option<SecretKeyContext>, Option<Message>),
     SendMessage(Message),
     ReceiveMessage(Message),
+    TransportReconnected(RouterAddress), /* re-run the key exchange of channels routed
+                                          * through this peer */
+    Close(Address), // close the channel with this address, and the other end with it
     Stop,
+    Response(RequestId, Response), // answers a request the channel manager made
+    Error(RequestId, String),      // a request the channel manager made failed
 }
 
 #[derive(Debug)]
@@ -46,5 +119,8 @@ pub enum WorkerCommand {
     Test,
     ReceiveMessage(Message),
     SendMessage(Message),
+    Wake,                          // return a worker thread blocked waiting for its next command
+    Response(RequestId, Response), // answers a request the worker made
+    Error(RequestId, String),      // a request the worker made failed
 }
 //}
@@ -0,0 +1,318 @@
+//! Support for end-to-end tests of Ockam nodes without sockets or threads. A [`Network`]
+//! creates any number of in-process nodes, each with its own router, channel manager, worker
+//! manager and a [`MemoryTransport`] in place of UDP, and [`run_until`] polls them all in turn
+//! on the calling thread, so that a test such as a channel from an initiator through a relay to
+//! a responder runs the same way every time.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+use ockam_channel::ChannelManager;
+use ockam_kex::xx::XXNewKeyExchanger;
+use ockam_kex::CipherSuite;
+use ockam_message::message::{
+    Address, AddressType, Message, MessageType, Receiver, Route, RouterAddress,
+};
+use ockam_router::router::Router;
+use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand};
+use ockam_vault::software::DefaultVault;
+use ockam_worker::worker_manager::WorkerManager;
+
+mod transport;
+
+pub use transport::MemoryTransport;
+
+// the port of the first node's address; the rest follow it
+const FIRST_PORT: u16 = 4000;
+
+/// The nodes of a test, and the in-memory wire between them
+#[derive(Default)]
+pub struct Network {
+    wire: transport::Wire,
+    next_port: u16,
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network::default()
+    }
+
+    /// A new node on the network, at the next free address
+    pub fn node(&mut self) -> Result<TestNode, String> {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), FIRST_PORT + self.next_port);
+        self.next_port += 1;
+        TestNode::new(address, self.wire.clone())
+    }
+
+    /// `n` new nodes on the network
+    pub fn nodes(&mut self, n: usize) -> Result<Vec<TestNode>, String> {
+        (0..n).map(|_| self.node()).collect()
+    }
+}
+
+/// A node with a router, a channel manager using the software vault, a worker manager and an
+/// in-memory transport. Nothing happens until it is polled.
+pub struct TestNode {
+    address: SocketAddr,
+    router: Router,
+    router_tx: Sender<OckamCommand>,
+    transport: MemoryTransport,
+    channels: ChannelManager,
+    channel_tx: Sender<OckamCommand>,
+    workers: WorkerManager,
+}
+
+impl TestNode {
+    fn new(address: SocketAddr, wire: transport::Wire) -> Result<TestNode, String> {
+        let (router_tx, router_rx) = channel();
+        let (transport_tx, transport_rx) = channel();
+        let (channel_tx, channel_rx) = channel();
+        let (worker_tx, worker_rx) = channel();
+
+        let router = Router::new(router_rx);
+        let transport =
+            MemoryTransport::new(address, wire, transport_rx, transport_tx, router_tx.clone())?;
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let new_key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        let channels = ChannelManager::new(
+            channel_rx,
+            channel_tx.clone(),
+            router_tx.clone(),
+            vault,
+            Box::new(new_key_exchanger),
+            None,
+            None,
+        )
+        .map_err(|e| format!("failed to create channel manager: {:?}", e))?;
+        let workers = WorkerManager::new(worker_tx, worker_rx, router_tx.clone());
+
+        Ok(TestNode {
+            address,
+            router,
+            router_tx,
+            transport,
+            channels,
+            channel_tx,
+            workers,
+        })
+    }
+
+    /// The node's address, for the routes of messages to it or through it
+    pub fn address(&self) -> RouterAddress {
+        RouterAddress::from_address(Address::UdpAddress(self.address)).unwrap()
+    }
+
+    /// Deliver the messages addressed to the worker at `address`, given in hex, to `worker`
+    pub fn register_worker(
+        &mut self,
+        address: &str,
+        worker: Arc<Mutex<dyn Receiver + Send>>,
+    ) -> Result<(), String> {
+        let a = Address::worker_address_from_string(address)
+            .map_err(|_| format!("bad worker address {}", address))?;
+        self.workers.register(a, worker)
+    }
+
+    /// A new [`Inbox`] registered as the worker at `address`
+    pub fn inbox(&mut self, address: &str) -> Result<Inbox, String> {
+        let inbox = Inbox::default();
+        self.register_worker(address, Arc::new(Mutex::new(inbox.clone())))?;
+        Ok(inbox)
+    }
+
+    /// Open a channel to the channel manager at the end of `route`, which must end with the
+    /// zero channel address. The worker at `notify` is sent a message of type `None` once the
+    /// channel is up, with the channel's address at the front of its return route.
+    pub fn initiate_channel(&self, route: Route, notify: &str) -> Result<(), String> {
+        let notify = Address::worker_address_from_string(notify)
+            .map_err(|_| format!("bad worker address {}", notify))?;
+        self.channel_tx
+            .send(OckamCommand::Channel(ChannelCommand::Initiate(
+                route, notify, None, None,
+            )))
+            .map_err(|_| "failed to send to channel manager".to_string())
+    }
+
+    /// Send a message from this node
+    pub fn send(&self, m: Message) -> Result<(), String> {
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(m)))
+            .map_err(|_| "failed to send to router".to_string())
+    }
+
+    /// Handle everything that has arrived for the node, passing each command on as far as it
+    /// goes within the node. Returns false once any part of the node has stopped.
+    pub fn poll(&mut self) -> Result<bool, String> {
+        let keep_going = self.transport.poll()
+            && self.router.poll()
+            && self
+                .channels
+                .poll()
+                .map_err(|e| format!("channel manager failed: {:?}", e))?
+            && self.workers.poll()
+            && self.router.poll();
+        Ok(keep_going)
+    }
+}
+
+/// A worker which keeps every message it receives, for a test to look at
+#[derive(Clone, Default)]
+pub struct Inbox {
+    received: Arc<Mutex<Vec<Message>>>,
+}
+
+impl Inbox {
+    /// The messages received so far
+    pub fn messages(&self) -> Vec<Message> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// The bodies of the payloads received so far
+    pub fn payloads(&self) -> Vec<Vec<u8>> {
+        self.messages()
+            .into_iter()
+            .filter(|m| m.message_type == MessageType::Payload)
+            .map(|m| m.message_body)
+            .collect()
+    }
+
+    /// The address of the channel the worker was told is up, if it has been
+    pub fn channel(&self) -> Option<RouterAddress> {
+        self.messages()
+            .into_iter()
+            .filter(|m| m.message_type == MessageType::None)
+            .filter_map(|m| m.return_route.addresses.first().cloned())
+            .find(|a| a.a_type == AddressType::Channel)
+    }
+}
+
+impl Receiver for Inbox {
+    fn recv(&mut self, m: Message) -> Result<Option<Message>, String> {
+        self.received.lock().unwrap().push(m);
+        Ok(None)
+    }
+}
+
+/// A worker which sends every payload it receives back along its return route
+pub struct Echo;
+
+impl Receiver for Echo {
+    fn recv(&mut self, m: Message) -> Result<Option<Message>, String> {
+        if m.message_type != MessageType::Payload {
+            return Ok(None);
+        }
+        Ok(Some(Message {
+            onward_route: m.return_route,
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: m.message_body,
+        }))
+    }
+}
+
+/// Poll every node in turn until `done` returns true, for at most `max_rounds` rounds. Returns
+/// an error if a node stops or fails, or if `done` is still false after the last round.
+pub fn run_until<F>(nodes: &mut [TestNode], max_rounds: usize, mut done: F) -> Result<(), String>
+where
+    F: FnMut() -> bool,
+{
+    for _ in 0..max_rounds {
+        if done() {
+            return Ok(());
+        }
+        for node in nodes.iter_mut() {
+            if !node.poll()? {
+                return Err(format!("node at {} stopped", node.address));
+            }
+        }
+    }
+    if done() {
+        return Ok(());
+    }
+    Err(format!("not done after {} rounds", max_rounds))
+}
+
+/// A route through `hops` to the worker or channel at `to`
+pub fn route(hops: &[&TestNode], to: RouterAddress) -> Route {
+    let mut addresses: Vec<RouterAddress> = hops.iter().map(|n| n.address()).collect();
+    addresses.push(to);
+    Route { addresses }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_channel::CHANNEL_ZERO;
+
+    fn worker(address: &str) -> RouterAddress {
+        RouterAddress::worker_router_address_from_str(address).unwrap()
+    }
+
+    fn payload(onward_route: Route, from: &str, body: &[u8]) -> Message {
+        Message {
+            onward_route,
+            return_route: Route {
+                addresses: vec![worker(from)],
+            },
+            message_type: MessageType::Payload,
+            message_body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn relay_round_trip() {
+        let mut network = Network::new();
+        let mut nodes = network.nodes(3).unwrap();
+        let inbox = nodes[0].inbox("00000001").unwrap();
+        nodes[2]
+            .register_worker("00000002", Arc::new(Mutex::new(Echo)))
+            .unwrap();
+
+        let onward_route = route(&[&nodes[1], &nodes[2]], worker("00000002"));
+        nodes[0]
+            .send(payload(onward_route, "00000001", b"hello"))
+            .unwrap();
+        run_until(&mut nodes, 20, || !inbox.payloads().is_empty()).unwrap();
+
+        assert_eq!(inbox.payloads(), vec![b"hello".to_vec()]);
+        // the reply came back through the relay, which added itself to the return route
+        let reply = &inbox.messages()[0];
+        assert_eq!(reply.onward_route.addresses.len(), 1);
+        assert_eq!(
+            reply.return_route.addresses,
+            vec![nodes[1].address(), nodes[2].address()]
+        );
+    }
+
+    // needs the software vault's crypto
+    #[test]
+    fn channel_round_trip() {
+        let mut network = Network::new();
+        let mut nodes = network.nodes(3).unwrap();
+        let inbox = nodes[0].inbox("00000001").unwrap();
+        nodes[2]
+            .register_worker("00000002", Arc::new(Mutex::new(Echo)))
+            .unwrap();
+
+        let channel_zero = RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap();
+        nodes[0]
+            .initiate_channel(route(&[&nodes[1], &nodes[2]], channel_zero), "00000001")
+            .unwrap();
+        run_until(&mut nodes, 50, || inbox.channel().is_some()).unwrap();
+
+        let onward_route = Route {
+            addresses: vec![inbox.channel().unwrap(), worker("00000002")],
+        };
+        nodes[0]
+            .send(payload(onward_route, "00000001", b"hello"))
+            .unwrap();
+        run_until(&mut nodes, 50, || !inbox.payloads().is_empty()).unwrap();
+
+        assert_eq!(inbox.payloads(), vec![b"hello".to_vec()]);
+    }
+}
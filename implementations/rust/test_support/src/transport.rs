@@ -0,0 +1,124 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use ockam_message::message::{Address, AddressType, Codec, Message, MessageRef, RouterAddress};
+use ockam_system::commands::{OckamCommand, RouterCommand, TransportCommand};
+
+// the encoded messages waiting for each node, by its address
+pub(crate) type Wire = Arc<Mutex<HashMap<SocketAddr, VecDeque<Vec<u8>>>>>;
+
+/// A transport which passes messages between the nodes of a [`Network`](crate::Network) in
+/// memory. It stands in for the UDP transport: it registers with the router for UDP addresses,
+/// and a node's address is a socket address which no socket is bound to. Messages are encoded
+/// and decoded as they would be on the wire, and forwarded the way a UDP relay forwards them.
+pub struct MemoryTransport {
+    address: SocketAddr,
+    hop: RouterAddress,
+    wire: Wire,
+    rx: Receiver<OckamCommand>,
+    router_tx: Sender<OckamCommand>,
+}
+
+impl MemoryTransport {
+    pub(crate) fn new(
+        address: SocketAddr,
+        wire: Wire,
+        rx: Receiver<OckamCommand>,
+        tx: Sender<OckamCommand>,
+        router_tx: Sender<OckamCommand>,
+    ) -> Result<MemoryTransport, String> {
+        wire.lock().unwrap().insert(address, VecDeque::new());
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Udp,
+                tx,
+            )))
+            .map_err(|_| "failed to register with router".to_string())?;
+        Ok(MemoryTransport {
+            address,
+            hop: RouterAddress::from_address(Address::UdpAddress(address)).unwrap(),
+            wire,
+            rx,
+            router_tx,
+        })
+    }
+
+    pub fn send_message(&mut self, mut m: Message) -> Result<(), String> {
+        if m.onward_route.addresses.is_empty() {
+            return Err("send_message: no onward route".to_string());
+        }
+        let peer = match m.onward_route.addresses.remove(0).address {
+            Address::UdpAddress(peer) => peer,
+            _ => return Err("send_message: not a udp address".to_string()),
+        };
+        m.return_route.addresses.insert(0, self.hop.clone());
+        let mut v = vec![];
+        m.encode(&mut v)
+            .map_err(|_| "send_message: encode failed".to_string())?;
+        self.deliver(peer, v)
+    }
+
+    fn deliver(&self, peer: SocketAddr, v: Vec<u8>) -> Result<(), String> {
+        match self.wire.lock().unwrap().get_mut(&peer) {
+            Some(queue) => {
+                queue.push_back(v);
+                Ok(())
+            }
+            None => Err(format!("no node at {}", peer)),
+        }
+    }
+
+    // Pass a message received from another node to the router, or forward it if it is on its
+    // way to another
+    fn dispatch(&mut self, encoded: &[u8]) -> Result<(), String> {
+        let m = MessageRef::decode(encoded).map_err(|_| "decode failed".to_string())?;
+        if let Some(peer) = m.onward_route.first().and_then(|a| a.udp_address()) {
+            let mut v = vec![];
+            m.forward(&self.hop, &mut v)
+                .map_err(|_| "forward failed".to_string())?;
+            return self.deliver(peer, v);
+        }
+        let (m, _) = Message::decode(encoded).map_err(|_| "decode failed".to_string())?;
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::ReceiveMessage(m)))
+            .map_err(|_| "send to router failed".to_string())
+    }
+
+    /// Receive the messages other nodes have sent this one, and send those the router has for
+    /// other nodes. Returns false once the transport is stopped.
+    pub fn poll(&mut self) -> bool {
+        loop {
+            let next = self
+                .wire
+                .lock()
+                .unwrap()
+                .get_mut(&self.address)
+                .and_then(|queue| queue.pop_front());
+            match next {
+                Some(encoded) => {
+                    if let Err(e) = self.dispatch(&encoded) {
+                        println!("{}", e);
+                    }
+                }
+                None => break,
+            }
+        }
+        while let Ok(c) = self.rx.try_recv() {
+            match c {
+                OckamCommand::Transport(TransportCommand::SendMessage(m)) => {
+                    if let Err(e) = self.send_message(m) {
+                        println!("{}", e);
+                    }
+                }
+                OckamCommand::Transport(TransportCommand::Stop) => {
+                    self.wire.lock().unwrap().remove(&self.address);
+                    return false;
+                }
+                _ => println!("unrecognized command"),
+            }
+        }
+        true
+    }
+}
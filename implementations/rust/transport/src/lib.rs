@@ -1,6 +1,12 @@
 #[allow(unused)]
+pub mod profile;
 
+#[cfg(target_arch = "wasm32")]
+pub mod websocket;
+
+#[allow(unused)]
 pub mod transport {
+    use crate::profile::{Fragmenter, Pacer, Reassembler, TransportProfile};
     use ockam_message::message::*;
     use ockam_router::router::Router;
     use ockam_system::commands::RouterCommand::ReceiveMessage;
@@ -11,16 +17,39 @@ pub mod transport {
     use std::net::{SocketAddrV4, UdpSocket};
     use std::str;
     use std::str::FromStr;
-    use std::sync::mpsc::channel;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{channel, Receiver, TryRecvError};
     use std::sync::Arc;
+    use std::time::Instant;
     use std::{io, thread, time};
 
+    // how often the reader thread checks whether its transport has gone while no datagrams arrive
+    const READER_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+    // datagrams with their senders, and the flag telling the reader thread its transport has gone
+    type Reader = (Receiver<(Vec<u8>, SocketAddr)>, Arc<AtomicBool>);
+
     pub struct UdpTransport {
         socket: UdpSocket,
         rx: std::sync::mpsc::Receiver<OckamCommand>,
         tx: std::sync::mpsc::Sender<OckamCommand>,
         router_tx: std::sync::mpsc::Sender<OckamCommand>,
         buffer: [u8; 16384],
+        // this transport's address, put on the return route of each message it sends
+        hop: RouterAddress,
+        // reused to encode each message this transport sends or forwards
+        encoded: Vec<u8>,
+        // datagrams read by the reader thread, which wakes the router for each of them, or None
+        // when the transport reads its socket inline
+        reader: Option<Reader>,
+        // peers whose last send failed; the next successful send to one of these is
+        // reported to the router so channels can recover. A datagram received from one
+        // isn't, as its sender isn't authenticated here.
+        unreachable: hashbrown::HashSet<SocketAddr>,
+        profile: TransportProfile,
+        fragmenter: Fragmenter,
+        reassembler: Reassembler,
+        pacer: Pacer,
     }
 
     impl UdpTransport {
@@ -29,11 +58,63 @@ pub mod transport {
             tx: std::sync::mpsc::Sender<OckamCommand>,
             router_tx: std::sync::mpsc::Sender<OckamCommand>,
             local_address: &str,
+        ) -> Result<UdpTransport, String> {
+            UdpTransport::new_with_profile(
+                rx,
+                tx,
+                router_tx,
+                local_address,
+                TransportProfile::default(),
+            )
+        }
+
+        pub fn new_with_profile(
+            rx: std::sync::mpsc::Receiver<OckamCommand>,
+            tx: std::sync::mpsc::Sender<OckamCommand>,
+            router_tx: std::sync::mpsc::Sender<OckamCommand>,
+            local_address: &str,
+            profile: TransportProfile,
+        ) -> Result<UdpTransport, String> {
+            UdpTransport::bind(rx, tx, router_tx, local_address, profile, true)
+        }
+
+        /// Create a transport which reads its socket from `poll` without blocking, rather than
+        /// on a thread of its own, for a node polled cooperatively on a single thread. Nothing
+        /// wakes a router waiting for such a transport's datagrams.
+        pub fn new_inline(
+            rx: std::sync::mpsc::Receiver<OckamCommand>,
+            tx: std::sync::mpsc::Sender<OckamCommand>,
+            router_tx: std::sync::mpsc::Sender<OckamCommand>,
+            local_address: &str,
+            profile: TransportProfile,
+        ) -> Result<UdpTransport, String> {
+            UdpTransport::bind(rx, tx, router_tx, local_address, profile, false)
+        }
+
+        fn bind(
+            rx: std::sync::mpsc::Receiver<OckamCommand>,
+            tx: std::sync::mpsc::Sender<OckamCommand>,
+            router_tx: std::sync::mpsc::Sender<OckamCommand>,
+            local_address: &str,
+            profile: TransportProfile,
+            threaded: bool,
         ) -> Result<UdpTransport, String> {
             // Try to create socket at given address
             match UdpSocket::bind(local_address) {
                 Ok(socket) => {
-                    socket.set_nonblocking(true);
+                    let hop = socket
+                        .local_addr()
+                        .ok()
+                        .and_then(|la| RouterAddress::from_address(Address::UdpAddress(la)))
+                        .ok_or_else(|| "failed to read socket address".to_string())?;
+                    let reader = if threaded {
+                        Some(UdpTransport::spawn_reader(&socket, &router_tx)?)
+                    } else {
+                        socket
+                            .set_nonblocking(true)
+                            .map_err(|_| "failed to set socket non-blocking".to_string())?;
+                        None
+                    };
                     // Register address type with Router
                     router_tx.send(OckamCommand::Router(RouterCommand::Register(
                         AddressType::Udp,
@@ -46,6 +127,14 @@ pub mod transport {
                         tx,
                         router_tx,
                         buffer: [0; 16384],
+                        hop,
+                        encoded: vec![],
+                        reader,
+                        unreachable: hashbrown::HashSet::new(),
+                        profile,
+                        fragmenter: Fragmenter::default(),
+                        reassembler: Reassembler::default(),
+                        pacer: Pacer::default(),
                     })
                 }
                 Err(_unused) => {
@@ -55,68 +144,236 @@ pub mod transport {
             }
         }
 
-        pub fn send_message(&mut self, mut m: Message) -> Result<(), String> {
-            let remote_address = m.onward_route.addresses.remove(0);
-
-            match self.socket.local_addr() {
-                Ok(la) => match RouterAddress::from_address(Address::UdpAddress(la)) {
-                    Some(ra) => {
-                        m.return_route.addresses.insert(0, ra);
-                        let mut v = vec![];
-                        // println!("sending onward, return:");
-                        // m.onward_route.print_route();
-                        // m.return_route.print_route();
-                        // println!("message type: {:?}", m.message_type);
-                        Message::encode(&m, &mut v);
-                        match self
-                            .socket
-                            .send_to(v.as_slice(), remote_address.address.as_string())
-                        {
-                            Ok(n) => Ok(()),
-                            Err(s) => {
-                                println!("send_message failed {}", s.to_string());
-                                Err("send_message error".to_string())
+        // Read datagrams on a thread of their own, which blocks until one arrives, so that the
+        // node polling the transport can block too until the reader wakes its router.
+        fn spawn_reader(
+            socket: &UdpSocket,
+            router_tx: &std::sync::mpsc::Sender<OckamCommand>,
+        ) -> Result<Reader, String> {
+            let reader = socket
+                .try_clone()
+                .map_err(|_| "failed to clone socket".to_string())?;
+            reader
+                .set_read_timeout(Some(READER_TIMEOUT))
+                .map_err(|_| "failed to set socket timeout".to_string())?;
+            let (tx, rx) = channel();
+            let closed = Arc::new(AtomicBool::new(false));
+            let reader_closed = closed.clone();
+            let router_tx = router_tx.clone();
+            thread::spawn(move || {
+                let mut buff = [0; 16348];
+                while !reader_closed.load(Ordering::SeqCst) {
+                    match reader.recv_from(&mut buff) {
+                        Ok((s, a)) => {
+                            if tx.send((buff[0..s].to_vec(), a)).is_err()
+                                || router_tx
+                                    .send(OckamCommand::Router(RouterCommand::Wake))
+                                    .is_err()
+                            {
+                                break;
                             }
                         }
+                        Err(e) => match e.kind() {
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {}
+                            _ => println!("socket receive failed {}", e),
+                        },
                     }
-                    None => Err("send_message error".to_string()),
-                },
-                Err(_unused) => Err("send_message".to_string()),
+                }
+            });
+            Ok((rx, closed))
+        }
+
+        /// When the transport next has frames to send, held back by its duty cycle. A node
+        /// blocked waiting for work should poll the transport again by then.
+        pub fn next_deadline(&self) -> Option<Instant> {
+            self.pacer.next_send()
+        }
+
+        /// The address the transport's socket is bound to
+        pub fn local_address(&self) -> SocketAddr {
+            match self.hop.address {
+                Address::UdpAddress(la) => la,
+                _ => unreachable!(),
+            }
+        }
+
+        pub fn send_message(&mut self, mut m: Message) -> Result<(), String> {
+            let span = tracing::debug_span!(
+                "transport_send",
+                id = %m.trace_id(),
+                peer = tracing::field::Empty
+            );
+            let _enter = span.enter();
+            let remote_address = m.onward_route.addresses.remove(0);
+            let peer = match remote_address.address {
+                Address::UdpAddress(peer) => peer,
+                _ => return Err("send_message: not a udp address".to_string()),
+            };
+            span.record("peer", tracing::field::display(peer));
+
+            m.return_route.addresses.insert(0, self.hop.clone());
+            let mut v = std::mem::take(&mut self.encoded);
+            v.clear();
+            let sent = match Message::encode(&m, &mut v) {
+                Ok(()) => self.send_encoded(peer, &v),
+                Err(_unused) => Err("send_message: encode failed".to_string()),
+            };
+            self.encoded = v;
+            sent
+        }
+
+        // Send an encoded message to `peer`, in frames if the profile fragments messages
+        fn send_encoded(&mut self, peer: SocketAddr, v: &[u8]) -> Result<(), String> {
+            if v.len() > self.profile.max_message_size {
+                return Err("message exceeds transport profile limit".to_string());
+            }
+            tracing::debug!(size = v.len(), "sending");
+            if !self.profile.fragmentation && self.profile.duty_cycle.is_none() {
+                return self.send_frame(peer, v);
+            }
+            let frames = if self.profile.fragmentation {
+                self.fragmenter.fragment(v, self.profile.max_frame_size)?
+            } else {
+                vec![v.to_vec()]
+            };
+            if self.profile.duty_cycle.is_some() {
+                self.pacer.push(peer, frames)?;
+                self.send_paced();
+                return Ok(());
+            }
+            for f in frames {
+                self.send_frame(peer, &f)?;
+            }
+            Ok(())
+        }
+
+        fn send_frame(&mut self, peer: SocketAddr, frame: &[u8]) -> Result<(), String> {
+            match self.socket.send_to(frame, peer) {
+                Ok(_n) => {
+                    self.mark_reachable(peer);
+                    Ok(())
+                }
+                Err(s) => {
+                    tracing::warn!(%peer, "send failed: {}", s);
+                    println!("send_message failed {}", s.to_string());
+                    self.unreachable.insert(peer);
+                    Err("send_message error".to_string())
+                }
+            }
+        }
+
+        // Drain queued frames for as long as the duty cycle allows
+        fn send_paced(&mut self) {
+            while let Some((peer, frame)) = self.pacer.pop_ready() {
+                self.send_frame(peer, &frame);
+                self.pacer.sent(frame.len(), &self.profile);
             }
         }
 
         pub fn receive_message(&mut self) -> Result<bool, String> {
-            let mut buff = [0; 16348];
-            match self.socket.recv_from(&mut buff) {
-                Ok((s, a)) => match Message::decode(&buff[0..s]) {
-                    Ok((mut m, _unused)) => {
-                        // println!("receiving onward, return:");
-                        // m.onward_route.print_route();
-                        // m.return_route.print_route();
-                        // println!("message type: {:?}", m.message_type);
-                        if !m.onward_route.addresses.is_empty()
-                            && m.onward_route.addresses[0].a_type == AddressType::Udp
-                        {
-                            match self.send_message(m) {
-                                Err(s) => Err(s),
-                                Ok(()) => Ok(true),
-                            }
-                        } else {
-                            match self.router_tx.send(OckamCommand::Router(ReceiveMessage(m))) {
-                                Ok(_unused) => Ok(true),
-                                Err(s) => Err("send to router failed".to_string()),
-                            }
-                        }
-                    }
-                    _ => Err("decode failed".to_string()),
+            let (buff, a) = match self.next_datagram()? {
+                Some(datagram) => datagram,
+                None => return Ok(false),
+            };
+            if !self.profile.fragmentation {
+                return self.dispatch(&buff, a);
+            }
+            // anyone can send a fragment, so one which is bad is dropped rather than failing
+            // the transport
+            match self.reassembler.accept(a, &buff, &self.profile) {
+                Ok(Some(encoded)) => self.dispatch(&encoded, a),
+                Ok(None) => Ok(true),
+                Err(e) => {
+                    tracing::debug!(peer = %a, "dropping fragment: {}", e);
+                    Ok(true)
+                }
+            }
+        }
+
+        // The next datagram from the reader thread, or from the socket when reading inline.
+        fn next_datagram(&mut self) -> Result<Option<(Vec<u8>, SocketAddr)>, String> {
+            match &self.reader {
+                Some((datagrams, _)) => match datagrams.try_recv() {
+                    Ok(datagram) => Ok(Some(datagram)),
+                    Err(TryRecvError::Empty) => Ok(None),
+                    Err(TryRecvError::Disconnected) => Err("socket receive failed".to_string()),
                 },
-                Err(e) => match e.kind() {
-                    io::ErrorKind::WouldBlock => Ok(false),
-                    _ => Err("socket receive failed".to_string()),
+                None => match self.socket.recv_from(&mut self.buffer) {
+                    Ok((s, a)) => Ok(Some((self.buffer[0..s].to_vec(), a))),
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::WouldBlock => Ok(None),
+                        _ => Err("socket receive failed".to_string()),
+                    },
                 },
             }
         }
 
+        fn dispatch(&mut self, encoded: &[u8], from: SocketAddr) -> Result<bool, String> {
+            if encoded.len() > self.profile.max_message_size {
+                return Err("message exceeds transport profile limit".to_string());
+            }
+            let m = match MessageRef::decode(encoded) {
+                Ok(m) => m,
+                Err(_unused) => {
+                    tracing::debug!(%from, "message failed to decode");
+                    return Err("decode failed".to_string());
+                }
+            };
+            let span = tracing::debug_span!("transport_recv", id = %m.trace_id(), %from);
+            let _enter = span.enter();
+            tracing::debug!(size = encoded.len(), "received");
+
+            // replies go to where the message came from rather than to the address the sender
+            // claims, which is the one its socket is bound to and unreachable behind NAT
+            let sender = RouterAddress::from_address(Address::UdpAddress(from))
+                .ok_or_else(|| "bad sender address".to_string())?;
+
+            // a hop on the way to another node: forward the message without decoding it
+            if let Some(peer) = m.onward_route.first().and_then(|a| a.udp_address()) {
+                let span = tracing::debug_span!("transport_send", id = %m.trace_id(), %peer);
+                let _enter = span.enter();
+                let mut v = std::mem::take(&mut self.encoded);
+                let sent = match m.forward_from(&self.hop, &sender, &mut v) {
+                    Ok(()) => self.send_encoded(peer, &v),
+                    Err(_unused) => Err("forward failed".to_string()),
+                };
+                self.encoded = v;
+                // one which can't be sent on, e.g. as the transmit queue is full, is dropped
+                if let Err(e) = sent {
+                    tracing::debug!("dropping forwarded message: {}", e);
+                }
+                return Ok(true);
+            }
+
+            match Message::decode(encoded) {
+                Ok((mut m, _unused)) => {
+                    if let Some(claimed) = m.return_route.addresses.first_mut() {
+                        if claimed.a_type == AddressType::Udp {
+                            *claimed = sender;
+                        }
+                    }
+                    match self.router_tx.send(OckamCommand::Router(ReceiveMessage(m))) {
+                        Ok(_unused) => Ok(true),
+                        Err(s) => Err("send to router failed".to_string()),
+                    }
+                }
+                _ => Err("decode failed".to_string()),
+            }
+        }
+
+        // Tell the router when a peer that previously failed is reachable again, so
+        // that channels routed through it can re-run their key exchange.
+        fn mark_reachable(&mut self, peer: SocketAddr) {
+            if self.unreachable.remove(&peer) {
+                if let Some(ra) = RouterAddress::from_address(Address::UdpAddress(peer)) {
+                    self.router_tx
+                        .send(OckamCommand::Router(RouterCommand::TransportReconnected(
+                            ra,
+                        )));
+                }
+            }
+        }
+
         pub fn poll(&mut self) -> bool {
             let mut got: bool = true;
             let mut keep_going = true;
@@ -132,6 +389,8 @@ pub mod transport {
                 }
             }
 
+            self.send_paced();
+
             got = true;
             while got && keep_going {
                 got = false;
@@ -153,4 +412,138 @@ pub mod transport {
             keep_going
         }
     }
+
+    impl Drop for UdpTransport {
+        fn drop(&mut self) {
+            // the reader thread ends at its next datagram or timeout
+            if let Some((_, closed)) = &self.reader {
+                closed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profile::{Fragmenter, TransportProfile};
+    use crate::transport::UdpTransport;
+    use ockam_message::message::*;
+    use ockam_system::commands::{OckamCommand, RouterCommand};
+    use std::net::UdpSocket;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn relay_forwards() {
+        let (_tx, rx) = channel();
+        let (router_tx, _router_rx) = channel();
+        let (tx, _) = channel();
+        let mut relay = UdpTransport::new_inline(
+            rx,
+            tx,
+            router_tx,
+            "127.0.0.1:0",
+            TransportProfile::default(),
+        )
+        .unwrap();
+        let relay_address = relay.local_address();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+        target.set_nonblocking(true).unwrap();
+        let m = Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(Address::UdpAddress(target.local_addr().unwrap()))
+                        .unwrap(),
+                    RouterAddress::channel_router_address_from_str("00010203").unwrap(),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![RouterAddress::from_address(Address::UdpAddress(
+                    sender.local_addr().unwrap(),
+                ))
+                .unwrap()],
+            },
+            message_type: MessageType::Payload,
+            message_body: vec![1, 2, 3],
+        };
+        let mut v = vec![];
+        m.encode(&mut v).unwrap();
+        sender.send_to(&v, relay_address).unwrap();
+
+        let mut buffer = [0; 1024];
+        for _ in 0..50 {
+            relay.poll();
+            if let Ok((n, _)) = target.recv_from(&mut buffer) {
+                let (forwarded, _) = Message::decode(&buffer[..n]).unwrap();
+                assert_eq!(forwarded.onward_route.addresses.len(), 1);
+                assert_eq!(
+                    forwarded.onward_route.addresses[0].a_type,
+                    AddressType::Channel
+                );
+                assert_eq!(forwarded.return_route.addresses.len(), 2);
+                assert_eq!(
+                    forwarded.return_route.addresses[0].address,
+                    Address::UdpAddress(relay_address)
+                );
+                assert_eq!(
+                    forwarded.return_route.addresses[1].address,
+                    Address::UdpAddress(sender.local_addr().unwrap())
+                );
+                assert_eq!(forwarded.message_body, vec![1, 2, 3]);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("message was not forwarded");
+    }
+
+    #[test]
+    fn bad_fragments_dropped() {
+        let (_tx, rx) = channel();
+        let (router_tx, router_rx) = channel();
+        let (tx, _) = channel();
+        let mut transport = UdpTransport::new_inline(
+            rx,
+            tx,
+            router_tx,
+            "127.0.0.1:0",
+            TransportProfile::lorawan(),
+        )
+        .unwrap();
+        let address = transport.local_address();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // too short, a bad header and more fragments than the profile allows
+        for datagram in &[vec![1], vec![0, 2, 2, 0], vec![0, 0, 0x7F, 0xFF, 0]] {
+            sender.send_to(datagram, address).unwrap();
+        }
+        let m = Message {
+            onward_route: Route {
+                addresses: vec![RouterAddress::channel_router_address_from_str("00010203").unwrap()],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: vec![1, 2, 3],
+        };
+        let mut v = vec![];
+        m.encode(&mut v).unwrap();
+        let frames = Fragmenter::default().fragment(&v, 51).unwrap();
+        for f in &frames {
+            sender.send_to(f, address).unwrap();
+        }
+
+        for _ in 0..50 {
+            assert!(transport.poll());
+            if let Ok(OckamCommand::Router(RouterCommand::ReceiveMessage(received))) =
+                router_rx.try_recv()
+            {
+                assert_eq!(received.message_body, vec![1, 2, 3]);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("message was not received");
+    }
 }
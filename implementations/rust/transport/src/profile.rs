@@ -0,0 +1,395 @@
+// Link profiles let a transport adapt its framing and send rate to the medium
+// underneath it. The default profile writes each encoded message as a single
+// datagram, as the UDP transport always has. Constrained radio links such as
+// LoRaWAN need tiny frames, so their profile splits every message into
+// fragments and paces transmissions to respect the regulatory duty cycle.
+
+use ockam_message::message::Codec;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Tuning for the link a transport sends over.
+#[derive(Clone, Debug)]
+pub struct TransportProfile {
+    /// Largest datagram written to the link, fragment header included
+    pub max_frame_size: usize,
+    /// Split every encoded message into frames of at most `max_frame_size`
+    pub fragmentation: bool,
+    /// Largest encoded message accepted for sending or reassembly
+    pub max_message_size: usize,
+    /// Fraction of time the transmitter may be active, e.g. 0.01 for 1%
+    pub duty_cycle: Option<f32>,
+    /// Link bit rate, used to estimate time-on-air when pacing sends
+    pub bit_rate: u32,
+    /// How long a partially received message is kept before it is dropped
+    pub reassembly_timeout: Duration,
+}
+
+impl Default for TransportProfile {
+    fn default() -> Self {
+        TransportProfile {
+            max_frame_size: 16384,
+            fragmentation: false,
+            max_message_size: 16384,
+            duty_cycle: None,
+            bit_rate: 0,
+            reassembly_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl TransportProfile {
+    /// A profile for LoRaWAN-class links: 51 byte frames (the smallest payload
+    /// allowed at EU868 DR0-DR2), a 512 byte message limit and a 1% duty cycle
+    /// at the SF7/125kHz data rate.
+    pub fn lorawan() -> Self {
+        TransportProfile {
+            max_frame_size: 51,
+            fragmentation: true,
+            max_message_size: 512,
+            duty_cycle: Some(0.01),
+            bit_rate: 5470,
+            reassembly_timeout: Duration::from_secs(120),
+        }
+    }
+
+    /// The most fragments a message within `max_message_size` is split into.
+    pub fn max_fragments(&self) -> usize {
+        match self.max_frame_size.checked_sub(MAX_FRAGMENT_HEADER) {
+            Some(chunk_size) if chunk_size > 0 => {
+                self.max_message_size.saturating_sub(1) / chunk_size + 1
+            }
+            _ => 0,
+        }
+    }
+
+    /// How long the transmitter must stay silent after sending `frame_len` bytes.
+    pub fn pause_after(&self, frame_len: usize) -> Duration {
+        match self.duty_cycle {
+            Some(dc) if dc > 0.0 && self.bit_rate > 0 => {
+                let time_on_air = (frame_len * 8) as f32 / self.bit_rate as f32;
+                Duration::from_secs_f32(time_on_air * (1.0 / dc - 1.0))
+            }
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+// Each fragment starts with the message id, the fragment index and the fragment
+// count, all written with the compact u16 codec: at most 2 bytes apiece. The codec only
+// round-trips values up to 0x7FFF, so ids wrap there.
+const MAX_FRAGMENT_HEADER: usize = 6;
+const MAX_FRAGMENT_ID: u16 = 0x7FFF;
+
+// Messages partly received from one sender, and from all of them, beyond which the oldest is
+// dropped for a new one
+const MAX_PARTIALS_PER_PEER: usize = 4;
+const MAX_PARTIALS: usize = 64;
+
+// Frames waiting for the duty cycle, beyond which a message is refused rather than queued
+const MAX_PACED_FRAMES: usize = 128;
+
+/// Splits encoded messages into numbered frames.
+#[derive(Debug, Default)]
+pub struct Fragmenter {
+    next_id: u16,
+}
+
+impl Fragmenter {
+    pub fn fragment(
+        &mut self,
+        encoded: &[u8],
+        max_frame_size: usize,
+    ) -> Result<Vec<Vec<u8>>, String> {
+        if max_frame_size <= MAX_FRAGMENT_HEADER {
+            return Err("frame size too small for fragment header".into());
+        }
+        let chunk_size = max_frame_size - MAX_FRAGMENT_HEADER;
+        let chunks: Vec<&[u8]> = encoded.chunks(chunk_size).collect();
+        if chunks.len() > MAX_FRAGMENT_ID as usize {
+            return Err("message needs too many fragments".into());
+        }
+
+        let id = self.next_id;
+        self.next_id = if id == MAX_FRAGMENT_ID { 0 } else { id + 1 };
+
+        let count = chunks.len() as u16;
+        let mut frames = vec![];
+        for (index, chunk) in chunks.iter().enumerate() {
+            let mut frame = vec![];
+            u16::encode(&id, &mut frame)?;
+            u16::encode(&(index as u16), &mut frame)?;
+            u16::encode(&count, &mut frame)?;
+            frame.extend_from_slice(chunk);
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+}
+
+struct Partial {
+    frames: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Collects fragments per sender until a message is complete.
+#[derive(Default)]
+pub struct Reassembler {
+    partials: hashbrown::HashMap<(SocketAddr, u16), Partial>,
+}
+
+impl Reassembler {
+    /// Returns the encoded message once its last missing fragment arrives.
+    pub fn accept(
+        &mut self,
+        peer: SocketAddr,
+        frame: &[u8],
+        profile: &TransportProfile,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.expire(profile.reassembly_timeout);
+
+        if frame.len() < 3 {
+            return Err("fragment too short".into());
+        }
+        let (id, rest) = u16::decode(frame)?;
+        let (index, rest) = u16::decode(rest)?;
+        let (count, chunk) = u16::decode(rest)?;
+        if count == 0 || index >= count {
+            return Err("bad fragment header".into());
+        }
+        if count as usize > profile.max_fragments() {
+            return Err("fragment count exceeds profile limit".into());
+        }
+
+        if !self.partials.contains_key(&(peer, id)) {
+            self.make_room(peer);
+        }
+        let partial = self.partials.entry((peer, id)).or_insert_with(|| Partial {
+            frames: vec![None; count as usize],
+            received: 0,
+            size: 0,
+            started: Instant::now(),
+        });
+        if partial.frames.len() != count as usize {
+            self.partials.remove(&(peer, id));
+            return Err("fragment count changed mid-message".into());
+        }
+        if partial.frames[index as usize].is_none() {
+            partial.size += chunk.len();
+            if partial.size > profile.max_message_size {
+                self.partials.remove(&(peer, id));
+                return Err("reassembled message exceeds profile limit".into());
+            }
+            partial.frames[index as usize] = Some(chunk.to_vec());
+            partial.received += 1;
+        }
+
+        if partial.received < partial.frames.len() {
+            return Ok(None);
+        }
+        let partial = self.partials.remove(&(peer, id)).unwrap();
+        let mut encoded = Vec::with_capacity(partial.size);
+        for f in partial.frames.into_iter().flatten() {
+            encoded.extend(f);
+        }
+        Ok(Some(encoded))
+    }
+
+    fn expire(&mut self, timeout: Duration) {
+        self.partials.retain(|_, p| p.started.elapsed() < timeout);
+    }
+
+    // Drop the oldest message partly received from `peer`, or from anyone, if there is no room
+    // to start another
+    fn make_room(&mut self, peer: SocketAddr) {
+        let from_peer = self.partials.keys().filter(|(p, _)| *p == peer).count();
+        let oldest = if from_peer >= MAX_PARTIALS_PER_PEER {
+            self.oldest(|(p, _)| *p == peer)
+        } else if self.partials.len() >= MAX_PARTIALS {
+            self.oldest(|_| true)
+        } else {
+            None
+        };
+        if let Some(key) = oldest {
+            self.partials.remove(&key);
+        }
+    }
+
+    fn oldest<F: Fn(&(SocketAddr, u16)) -> bool>(&self, matches: F) -> Option<(SocketAddr, u16)> {
+        self.partials
+            .iter()
+            .filter(|(key, _)| matches(key))
+            .min_by_key(|(_, p)| p.started)
+            .map(|(key, _)| *key)
+    }
+}
+
+/// Frames waiting for the duty cycle to allow the next transmission.
+#[derive(Default)]
+pub struct Pacer {
+    queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    next_send: Option<Instant>,
+}
+
+impl Pacer {
+    /// Queue the frames of a message to `peer`, refusing them all if the queue has no room.
+    pub fn push(&mut self, peer: SocketAddr, frames: Vec<Vec<u8>>) -> Result<(), String> {
+        if self.queue.len() + frames.len() > MAX_PACED_FRAMES {
+            return Err("transmit queue full".into());
+        }
+        self.queue
+            .extend(frames.into_iter().map(|frame| (peer, frame)));
+        Ok(())
+    }
+
+    /// The next frame, if the transmitter is allowed to send now.
+    pub fn pop_ready(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        if let Some(t) = self.next_send {
+            if Instant::now() < t {
+                return None;
+            }
+        }
+        self.queue.pop_front()
+    }
+
+    /// When the next queued frame may be sent, if any are queued.
+    pub fn next_send(&self) -> Option<Instant> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        Some(self.next_send.unwrap_or_else(Instant::now))
+    }
+
+    pub fn sent(&mut self, frame_len: usize, profile: &TransportProfile) {
+        self.next_send = Some(Instant::now() + profile.pause_after(frame_len));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn fragment_round_trip() {
+        let profile = TransportProfile::lorawan();
+        let peer = SocketAddr::from_str("127.0.0.1:4050").unwrap();
+        let message: Vec<u8> = (0..300).map(|i| i as u8).collect();
+
+        let mut fragmenter = Fragmenter::default();
+        let frames = fragmenter
+            .fragment(&message, profile.max_frame_size)
+            .unwrap();
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|f| f.len() <= profile.max_frame_size));
+
+        let mut reassembler = Reassembler::default();
+        let mut out = None;
+        for f in frames.iter().rev() {
+            out = reassembler.accept(peer, f, &profile).unwrap();
+        }
+        assert_eq!(out.unwrap(), message);
+    }
+
+    #[test]
+    fn fragment_ids_wrap_within_codec() {
+        let profile = TransportProfile::lorawan();
+        let peer = SocketAddr::from_str("127.0.0.1:4050").unwrap();
+        let message: Vec<u8> = (0..300).map(|i| i as u8).collect();
+
+        let mut fragmenter = Fragmenter {
+            next_id: MAX_FRAGMENT_ID,
+        };
+        let mut reassembler = Reassembler::default();
+        for id in &[MAX_FRAGMENT_ID, 0] {
+            let frames = fragmenter
+                .fragment(&message, profile.max_frame_size)
+                .unwrap();
+            assert_eq!(u16::decode(&frames[0]).unwrap().0, *id);
+            let mut out = None;
+            for f in &frames {
+                out = reassembler.accept(peer, f, &profile).unwrap();
+            }
+            assert_eq!(out.unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn reassembly_bounded() {
+        let profile = TransportProfile::lorawan();
+        let peer = SocketAddr::from_str("127.0.0.1:4050").unwrap();
+        let other = SocketAddr::from_str("127.0.0.1:4051").unwrap();
+        let frame = |id: u16, count: u16| {
+            let mut frame = vec![];
+            u16::encode(&id, &mut frame).unwrap();
+            u16::encode(&0, &mut frame).unwrap();
+            u16::encode(&count, &mut frame).unwrap();
+            frame.push(0);
+            frame
+        };
+        let mut reassembler = Reassembler::default();
+        let most = profile.max_fragments() as u16;
+        assert!(reassembler
+            .accept(peer, &frame(0, most + 1), &profile)
+            .is_err());
+        assert!(reassembler.partials.is_empty());
+
+        // a sender starting more messages than it may drops the oldest it started
+        for id in 0..=MAX_PARTIALS_PER_PEER as u16 {
+            assert_eq!(
+                reassembler.accept(peer, &frame(id, most), &profile),
+                Ok(None)
+            );
+        }
+        assert_eq!(reassembler.partials.len(), MAX_PARTIALS_PER_PEER);
+        assert!(!reassembler.partials.contains_key(&(peer, 0)));
+
+        // as do all of them together
+        for id in 0..MAX_PARTIALS as u16 {
+            let sender = SocketAddr::new(other.ip(), 5000 + id);
+            reassembler.accept(sender, &frame(id, 2), &profile).unwrap();
+        }
+        assert_eq!(reassembler.partials.len(), MAX_PARTIALS);
+        assert!(reassembler.partials.keys().all(|(p, _)| *p != peer));
+    }
+
+    #[test]
+    fn pacer_bounded() {
+        let peer = SocketAddr::from_str("127.0.0.1:4050").unwrap();
+        let mut pacer = Pacer::default();
+        pacer
+            .push(peer, vec![vec![0]; MAX_PACED_FRAMES - 1])
+            .unwrap();
+        assert!(pacer.push(peer, vec![vec![0], vec![0]]).is_err());
+        assert_eq!(pacer.queue.len(), MAX_PACED_FRAMES - 1);
+        pacer.push(peer, vec![vec![0]]).unwrap();
+    }
+
+    #[test]
+    fn pacer_next_send() {
+        let profile = TransportProfile::lorawan();
+        let peer = SocketAddr::from_str("127.0.0.1:4050").unwrap();
+        let mut pacer = Pacer::default();
+        assert!(pacer.next_send().is_none());
+
+        pacer.push(peer, vec![vec![0; 51], vec![0; 51]]).unwrap();
+        assert!(pacer.next_send().unwrap() <= Instant::now());
+        let (_, frame) = pacer.pop_ready().unwrap();
+        pacer.sent(frame.len(), &profile);
+        assert!(pacer.next_send().unwrap() > Instant::now() + Duration::from_secs(5));
+        assert!(pacer.pop_ready().is_none());
+    }
+
+    #[test]
+    fn lorawan_paces_sends() {
+        let profile = TransportProfile::lorawan();
+        assert!(profile.pause_after(51) > Duration::from_secs(5));
+        assert_eq!(
+            TransportProfile::default().pause_after(51),
+            Duration::from_secs(0)
+        );
+    }
+}
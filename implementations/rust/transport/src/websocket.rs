@@ -0,0 +1,134 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, Sender};
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use ockam_message::message::{AddressType, Codec, Message};
+use ockam_system::commands::{OckamCommand, RouterCommand, TransportCommand};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+/// A transport for a node in a browser, where there are no UDP sockets: it takes the messages
+/// the router has for UDP addresses and sends each of them, whole, over a WebSocket to a server
+/// which relays it on to the hop at the front of its onward route, and passes back the messages
+/// the server relays to it. Like the rest of the node it is polled on the browser's one thread,
+/// which runs the socket's message handler between polls.
+pub struct WebSocketTransport {
+    socket: WebSocket,
+    rx: Receiver<OckamCommand>,
+    router_tx: Sender<OckamCommand>,
+    // messages received by the socket's handler since the last poll
+    received: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    // messages held until the socket has opened
+    unsent: VecDeque<Vec<u8>>,
+    // kept for as long as the socket may call it
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl WebSocketTransport {
+    /// Connect to the server at `url`, e.g. "wss://gateway.example:4443", registering with the
+    /// router for UDP addresses.
+    pub fn connect(
+        rx: Receiver<OckamCommand>,
+        tx: Sender<OckamCommand>,
+        router_tx: Sender<OckamCommand>,
+        url: &str,
+    ) -> Result<WebSocketTransport, String> {
+        let socket = WebSocket::new(url).map_err(|_| format!("failed to connect to {}", url))?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let received = Rc::new(RefCell::new(VecDeque::new()));
+        let queue = received.clone();
+        let on_message = Closure::wrap(Box::new(move |e: MessageEvent| {
+            // text frames aren't messages
+            if let Ok(buffer) = e.data().dyn_into::<ArrayBuffer>() {
+                queue
+                    .borrow_mut()
+                    .push_back(Uint8Array::new(&buffer).to_vec());
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Udp,
+                tx,
+            )))
+            .map_err(|_| "failed to register with router".to_string())?;
+
+        Ok(WebSocketTransport {
+            socket,
+            rx,
+            router_tx,
+            received,
+            unsent: VecDeque::new(),
+            _on_message: on_message,
+        })
+    }
+
+    pub fn send_message(&mut self, m: Message) -> Result<(), String> {
+        let mut v = vec![];
+        Message::encode(&m, &mut v)?;
+        self.unsent.push_back(v);
+        self.flush()
+    }
+
+    // Send the messages held while the socket was connecting.
+    fn flush(&mut self) -> Result<(), String> {
+        match self.socket.ready_state() {
+            WebSocket::CONNECTING => return Ok(()),
+            WebSocket::OPEN => {}
+            _ => return Err("websocket is closed".to_string()),
+        }
+        while let Some(v) = self.unsent.pop_front() {
+            self.socket
+                .send_with_u8_array(&v)
+                .map_err(|_| "websocket send failed".to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Pass the messages received since the last poll to the router, and send those the router
+    /// has for the transport, returning false once it is stopped.
+    pub fn poll(&mut self) -> bool {
+        let received: Vec<_> = self.received.borrow_mut().drain(..).collect();
+        for v in received {
+            match Message::decode(&v) {
+                Ok((m, _unused)) => {
+                    let _ = self
+                        .router_tx
+                        .send(OckamCommand::Router(RouterCommand::ReceiveMessage(m)));
+                }
+                Err(_unused) => println!("websocket message failed to decode"),
+            }
+        }
+
+        while let Ok(tc) = self.rx.try_recv() {
+            match tc {
+                OckamCommand::Transport(TransportCommand::SendMessage(m)) => {
+                    if let Err(e) = self.send_message(m) {
+                        println!("{}", e);
+                    }
+                }
+                OckamCommand::Transport(TransportCommand::Stop) => {
+                    let _ = self.socket.close();
+                    return false;
+                }
+                _ => println!("unrecognized command"),
+            }
+        }
+        if let Err(e) = self.flush() {
+            println!("{}", e);
+        }
+        true
+    }
+}
+
+impl Drop for WebSocketTransport {
+    fn drop(&mut self) {
+        self.socket.set_onmessage(None);
+        let _ = self.socket.close();
+    }
+}
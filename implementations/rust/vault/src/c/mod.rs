@@ -1,20 +1,459 @@
-use crate::error::VaultFailError;
+use crate::error::{VaultFailError, VaultFailErrorKind};
+use crate::hardware::{self, HardwareSecrets, Held};
 use crate::types::{PublicKey, SecretKey, SecretKeyAttributes, SecretKeyContext};
+use crate::Vault;
 use c_bindings::*;
-use std::cell::Cell;
+use std::ptr;
 use zeroize::Zeroize;
 
-// TODO: Should be thread-safe?
-/// Represents a single instance of an Ockam vault context
-#[derive(Debug)]
+const SHARED_SECRET_LENGTH: usize = OCKAM_VAULT_SHARED_SECRET_LENGTH as usize;
+const P256_PUBLICKEY_LENGTH: usize = OCKAM_VAULT_P256_PUBLICKEY_LENGTH as usize;
+const P256_PRIVATEKEY_LENGTH: usize = OCKAM_VAULT_P256_PRIVATEKEY_LENGTH as usize;
+
+/// Where to find an ATECC608A on the I2C bus, and the key which protects what is sent to it
+#[derive(Clone, Copy)]
+pub struct Atecc608aConfig {
+    /// The I2C bus the device is on
+    pub bus: u8,
+    /// The device's 8-bit I2C address, 0xC0 unless it was changed
+    pub address: u8,
+    /// The I2C bus speed in Hz
+    pub baud: u32,
+    /// The IO protection key the device was provisioned with, which encrypts secrets written to
+    /// it and shared secrets read from it
+    pub io_protection_key: [u8; 32],
+    /// The slot the IO protection key is in
+    pub io_protection_slot: u8,
+}
+
+impl Default for Atecc608aConfig {
+    fn default() -> Self {
+        Self {
+            bus: 1,
+            address: 0xC0,
+            baud: 100_000,
+            io_protection_key: [0u8; 32],
+            io_protection_slot: 6,
+        }
+    }
+}
+
+impl std::fmt::Debug for Atecc608aConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Atecc608aConfig {{ bus: {}, address: {:#x}, baud: {}, io_protection_slot: {} }}",
+            self.bus, self.address, self.baud, self.io_protection_slot
+        )
+    }
+}
+
+// A secret held by the vault: a key in a slot of the device, or one in memory
+type CSecret = Held<ockam_vault_secret_t>;
+
+/// A Vault backed by a C vault implementation, the Microchip ATECC608A. Persistent P-256 keys
+/// are generated in, or imported into, the device's slots and never leave it: the device computes
+/// their public keys, ECDH with them and ECDSA signatures. Other secrets are kept in memory.
 pub struct CVault {
-    context: Cell<ockam_vault_t>,
+    context: ockam_vault_t,
+    // the C vault refers to the interface configuration for as long as it is open
+    _iface: Box<ATCAIfaceCfg>,
+    secrets: HardwareSecrets<ockam_vault_secret_t>,
+}
+
+// The C vault is only ever used through `&mut self`, or `&self` for SHA-256, which keeps no state
+unsafe impl Send for CVault {}
+unsafe impl Sync for CVault {}
+
+impl std::fmt::Debug for CVault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "CVault {{ ephemeral_vault: {:?}, secrets: {} }}",
+            self.secrets.software,
+            self.secrets.count()
+        )
+    }
 }
 
 impl CVault {
-    fn new(vault: ockam_vault_t) -> Self {
-        CVault {
-            context: Cell::new(vault),
+    /// Open the ATECC608A described by `config`
+    pub fn atecc608a(config: &Atecc608aConfig) -> Result<Self, VaultFailError> {
+        let mut iface = Box::new(ATCAIfaceCfg {
+            iface_type: ATCAIfaceType::ATCA_I2C_IFACE,
+            devtype: ATCADeviceType::ATECC608A,
+            __bindgen_anon_1: ATCAIfaceCfg__bindgen_ty_1 {
+                atcai2c: ATCAIfaceCfg__bindgen_ty_1__bindgen_ty_1 {
+                    slave_address: config.address,
+                    bus: config.bus,
+                    baud: config.baud,
+                },
+            },
+            wake_delay: 1500,
+            rx_retries: 20,
+            cfg_data: ptr::null_mut(),
+        });
+        let mut io_protection = ockam_vault_atecc608a_io_protection_t {
+            key: config.io_protection_key,
+            key_size: config.io_protection_key.len() as u8,
+            slot: config.io_protection_slot,
+        };
+        let mut attributes = ockam_vault_atecc608a_attributes_t {
+            memory: c_rust_memory::ockam_memory(),
+            mutex: ptr::null_mut(),
+            atca_iface_cfg: &mut *iface,
+            io_protection: &mut io_protection,
+        };
+
+        let mut context = ockam_vault_t {
+            dispatch: ptr::null_mut(),
+            default_context: ptr::null_mut(),
+            impl_context: ptr::null_mut(),
+        };
+        let error = unsafe { ockam_vault_atecc608a_init(&mut context, &mut attributes) };
+        io_protection.key.zeroize();
+        check(error, VaultFailErrorKind::Init)?;
+
+        Ok(Self {
+            context,
+            _iface: iface,
+            secrets: HardwareSecrets::new("the ATECC608A"),
+        })
+    }
+
+    fn device_attributes() -> ockam_vault_secret_attributes_t {
+        ockam_vault_secret_attributes_t {
+            length: P256_PRIVATEKEY_LENGTH as u16,
+            type_: ockam_vault_secret_type_t::OCKAM_VAULT_SECRET_TYPE_P256_PRIVATEKEY,
+            purpose: ockam_vault_secret_purpose_t::OCKAM_VAULT_SECRET_PURPOSE_KEY_AGREEMENT,
+            persistence: ockam_vault_secret_persistence_t::OCKAM_VAULT_SECRET_PERSISTENT,
+        }
+    }
+
+    fn empty_secret() -> ockam_vault_secret_t {
+        ockam_vault_secret_t {
+            attributes: Self::device_attributes(),
+            context: ptr::null_mut(),
+        }
+    }
+
+    // The shared secret of ECDH with the key `context`, in memory
+    fn shared_secret(
+        &mut self,
+        context: SecretKeyContext,
+        peer_public_key: PublicKey,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let mut secret = match self.secrets.get(context, VaultFailErrorKind::Ecdh)? {
+            CSecret::Memory(context) => {
+                return self
+                    .secrets
+                    .software
+                    .ec_diffie_hellman(context, peer_public_key)
+            }
+            CSecret::Hardware(secret) => secret,
+        };
+        let peer_public_key = hardware::p256_public_key(peer_public_key)?;
+
+        let mut dh = [0u8; SHARED_SECRET_LENGTH];
+        let error = unsafe {
+            ockam_vault_atecc608a_ecdh_output(
+                &mut self.context,
+                &mut secret,
+                peer_public_key.as_ptr(),
+                peer_public_key.len(),
+                dh.as_mut_ptr(),
+                dh.len(),
+            )
+        };
+        check(error, VaultFailErrorKind::Ecdh)?;
+        self.secrets.shared_secret_import(&mut dh)
+    }
+}
+
+fn check(error: ockam_error_t, kind: VaultFailErrorKind) -> Result<(), VaultFailError> {
+    if ockam_error_is_none(&error) {
+        Ok(())
+    } else {
+        Err(VaultFailError::from_msg(
+            kind,
+            format!("ATECC608A error {}", error.code),
+        ))
+    }
+}
+
+impl Zeroize for CVault {
+    fn zeroize(&mut self) {
+        self.secrets.zeroize();
+    }
+}
+
+impl Drop for CVault {
+    fn drop(&mut self) {
+        self.zeroize();
+        unsafe {
+            ockam_vault_deinit(&mut self.context);
+        }
+    }
+}
+
+impl Vault for CVault {
+    fn random(&mut self, data: &mut [u8]) -> Result<(), VaultFailError> {
+        let error = unsafe {
+            ockam_vault_random_bytes_generate(&mut self.context, data.as_mut_ptr(), data.len())
+        };
+        check(error, VaultFailErrorKind::Random)
+    }
+
+    fn sha256<B: AsRef<[u8]>>(&self, data: B) -> Result<[u8; 32], VaultFailError> {
+        let data = data.as_ref();
+        let mut digest = [0u8; 32];
+        let mut length = 0;
+        // the device keeps no state for SHA-256 between calls
+        let context: *const ockam_vault_t = &self.context;
+        let context = context as *mut ockam_vault_t;
+        let error = unsafe {
+            ockam_vault_sha256(
+                context,
+                data.as_ptr(),
+                data.len(),
+                digest.as_mut_ptr(),
+                digest.len(),
+                &mut length,
+            )
+        };
+        check(error, VaultFailErrorKind::Sha256)?;
+        Ok(digest)
+    }
+
+    fn secret_generate(
+        &mut self,
+        attributes: SecretKeyAttributes,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        if !hardware::in_hardware(&attributes) {
+            return self.secrets.secret_generate(attributes);
+        }
+        let mut secret = Self::empty_secret();
+        let error = unsafe {
+            ockam_vault_secret_generate(&mut self.context, &mut secret, &Self::device_attributes())
+        };
+        check(error, VaultFailErrorKind::SecretGenerate)?;
+        Ok(self.secrets.insert(CSecret::Hardware(secret)))
+    }
+
+    fn secret_import(
+        &mut self,
+        secret: &SecretKey,
+        attributes: SecretKeyAttributes,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        if !hardware::in_hardware(&attributes) {
+            return self.secrets.secret_import(secret, attributes);
+        }
+        let key = secret.as_ref();
+        if key.len() != P256_PRIVATEKEY_LENGTH {
+            return Err(VaultFailErrorKind::InvalidSecret.into());
+        }
+        let mut imported = Self::empty_secret();
+        let error = unsafe {
+            ockam_vault_secret_import(
+                &mut self.context,
+                &mut imported,
+                &Self::device_attributes(),
+                key.as_ptr(),
+                key.len(),
+            )
+        };
+        check(error, VaultFailErrorKind::Import)?;
+        Ok(self.secrets.insert(CSecret::Hardware(imported)))
+    }
+
+    fn secret_export(&mut self, context: SecretKeyContext) -> Result<SecretKey, VaultFailError> {
+        self.secrets.secret_export(context)
+    }
+
+    fn secret_attributes_get(
+        &mut self,
+        context: SecretKeyContext,
+    ) -> Result<SecretKeyAttributes, VaultFailError> {
+        match self
+            .secrets
+            .get(context, VaultFailErrorKind::GetAttributes)?
+        {
+            CSecret::Hardware(_) => Ok(hardware::hardware_attributes()),
+            CSecret::Memory(context) => self.secrets.software.secret_attributes_get(context),
+        }
+    }
+
+    fn secret_public_key_get(
+        &mut self,
+        context: SecretKeyContext,
+    ) -> Result<PublicKey, VaultFailError> {
+        match self.secrets.get(context, VaultFailErrorKind::PublicKey)? {
+            CSecret::Hardware(mut secret) => {
+                let mut public_key = [0u8; P256_PUBLICKEY_LENGTH];
+                let mut length = 0;
+                let error = unsafe {
+                    ockam_vault_secret_publickey_get(
+                        &mut self.context,
+                        &mut secret,
+                        public_key.as_mut_ptr(),
+                        public_key.len(),
+                        &mut length,
+                    )
+                };
+                check(error, VaultFailErrorKind::PublicKey)?;
+                Ok(PublicKey::P256(public_key))
+            }
+            CSecret::Memory(context) => self.secrets.software.secret_public_key_get(context),
         }
     }
+
+    fn secret_destroy(&mut self, context: SecretKeyContext) -> Result<(), VaultFailError> {
+        match self
+            .secrets
+            .get(context, VaultFailErrorKind::InvalidContext)?
+        {
+            CSecret::Hardware(mut secret) => {
+                let error = unsafe { ockam_vault_secret_destroy(&mut self.context, &mut secret) };
+                check(error, VaultFailErrorKind::InvalidContext)?;
+            }
+            CSecret::Memory(context) => self.secrets.software.secret_destroy(context)?,
+        }
+        self.secrets.remove(context);
+        Ok(())
+    }
+
+    fn ec_diffie_hellman(
+        &mut self,
+        context: SecretKeyContext,
+        peer_public_key: PublicKey,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let shared_secret = self.shared_secret(context, peer_public_key)?;
+        Ok(self.secrets.ec_diffie_hellman(shared_secret))
+    }
+
+    fn ec_diffie_hellman_hkdf_sha256(
+        &mut self,
+        context: SecretKeyContext,
+        peer_public_key: PublicKey,
+        salt: SecretKeyContext,
+        info: &[u8],
+        output_attributes: Vec<SecretKeyAttributes>,
+    ) -> Result<Vec<SecretKeyContext>, VaultFailError> {
+        let salt = self.secrets.memory(salt, VaultFailErrorKind::HkdfSha256)?;
+        let shared_secret = self.shared_secret(context, peer_public_key)?;
+        self.secrets
+            .ec_diffie_hellman_hkdf_sha256(salt, info, shared_secret, output_attributes)
+    }
+
+    fn hkdf_sha256(
+        &mut self,
+        salt: SecretKeyContext,
+        info: &[u8],
+        ikm: Option<SecretKeyContext>,
+        output_attributes: Vec<SecretKeyAttributes>,
+    ) -> Result<Vec<SecretKeyContext>, VaultFailError> {
+        self.secrets.hkdf_sha256(salt, info, ikm, output_attributes)
+    }
+
+    fn aead_aes_gcm_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.secrets
+            .aead_aes_gcm_encrypt(context, plaintext, nonce, aad)
+    }
+
+    fn aead_aes_gcm_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.secrets
+            .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.secrets
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.secrets
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+    }
+
+    fn deinit(&mut self) {
+        self.secrets.software.deinit();
+    }
+
+    /// Sign the SHA-256 digest of `data` with ECDSA, for a P-256 key in the device
+    fn sign<B: AsRef<[u8]>>(
+        &mut self,
+        secret_key: SecretKeyContext,
+        data: B,
+    ) -> Result<[u8; 64], VaultFailError> {
+        let mut secret = match self.secrets.get(secret_key, VaultFailErrorKind::Sign)? {
+            CSecret::Hardware(secret) => secret,
+            CSecret::Memory(context) => return self.secrets.software.sign(context, data),
+        };
+        let digest = self.sha256(data)?;
+        let mut signature = [0u8; 64];
+        let error = unsafe {
+            ockam_vault_atecc608a_sign(
+                &mut self.context,
+                &mut secret,
+                digest.as_ptr(),
+                digest.len(),
+                signature.as_mut_ptr(),
+                signature.len(),
+            )
+        };
+        check(error, VaultFailErrorKind::Sign)?;
+        Ok(signature)
+    }
+
+    /// Verify an ECDSA signature of the SHA-256 digest of `data` for a P-256 public key
+    fn verify<B: AsRef<[u8]>>(
+        &mut self,
+        signature: [u8; 64],
+        public_key: PublicKey,
+        data: B,
+    ) -> Result<(), VaultFailError> {
+        let public_key = match public_key {
+            PublicKey::P256(k) => k,
+            PublicKey::Curve25519(_) => {
+                return self.secrets.software.verify(signature, public_key, data)
+            }
+        };
+        let digest = self.sha256(data)?;
+        let error = unsafe {
+            ockam_vault_atecc608a_verify(
+                &mut self.context,
+                public_key.as_ptr(),
+                public_key.len(),
+                digest.as_ptr(),
+                digest.len(),
+                signature.as_ptr(),
+                signature.len(),
+            )
+        };
+        check(error, VaultFailErrorKind::Verify)
+    }
 }
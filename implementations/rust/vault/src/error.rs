@@ -1,9 +1,11 @@
-use failure::{Backtrace, Context, Fail};
-use std::{fmt, io};
+use failure::Fail;
+use ockam_common::error::{ErrorKind, ERROR_INTERFACE_VAULT};
+use std::io;
 
 /// Represents the failures that can occur in
 /// an Ockam Vault
 #[derive(Clone, Copy, Fail, Debug)]
+#[non_exhaustive]
 pub enum VaultFailErrorKind {
     /// Failed to initialize the vault
     #[fail(display = "Failed to initialize the vault")]
@@ -44,6 +46,18 @@ pub enum VaultFailErrorKind {
     /// Could not use the AES-GCM cipher scheme
     #[fail(display = "Could not use the AES-GCM cipher scheme")]
     AeadAesGcm,
+    /// Failed to encrypt data with ChaCha20-Poly1305
+    #[fail(display = "Failed to encrypt data with ChaCha20-Poly1305")]
+    AeadChaChaPolyEncrypt,
+    /// Failed to decrypt data with ChaCha20-Poly1305
+    #[fail(display = "Failed to decrypt data with ChaCha20-Poly1305")]
+    AeadChaChaPolyDecrypt,
+    /// Failed to sign data
+    #[fail(display = "Failed to sign data")]
+    Sign,
+    /// Failed to verify a signature
+    #[fail(display = "Failed to verify a signature")]
+    Verify,
     /// An invalid parameter was supplied: {}
     #[fail(display = "An invalid parameter was supplied: {}", 0)]
     InvalidParam(usize),
@@ -94,80 +108,49 @@ pub enum VaultFailErrorKind {
     AccessDenied,
 }
 
-impl VaultFailErrorKind {
-    pub(crate) const ERROR_INTERFACE_VAULT: usize = 3 << 24;
-    /// Convert to an integer
-    pub fn to_usize(&self) -> usize {
-        match *self {
-            VaultFailErrorKind::Init => Self::ERROR_INTERFACE_VAULT | 1,
-            VaultFailErrorKind::Random => Self::ERROR_INTERFACE_VAULT | 2,
-            VaultFailErrorKind::Sha256 => Self::ERROR_INTERFACE_VAULT | 3,
-            VaultFailErrorKind::SecretGenerate => Self::ERROR_INTERFACE_VAULT | 4,
-            VaultFailErrorKind::Import => Self::ERROR_INTERFACE_VAULT | 5,
-            VaultFailErrorKind::Export => Self::ERROR_INTERFACE_VAULT | 6,
-            VaultFailErrorKind::GetAttributes => Self::ERROR_INTERFACE_VAULT | 7,
-            VaultFailErrorKind::PublicKey => Self::ERROR_INTERFACE_VAULT | 8,
-            VaultFailErrorKind::Ecdh => Self::ERROR_INTERFACE_VAULT | 9,
-            VaultFailErrorKind::HkdfSha256 => Self::ERROR_INTERFACE_VAULT | 10,
-            VaultFailErrorKind::AeadAesGcmEncrypt => Self::ERROR_INTERFACE_VAULT | 11,
-            VaultFailErrorKind::AeadAesGcmDecrypt => Self::ERROR_INTERFACE_VAULT | 12,
-            VaultFailErrorKind::AeadAesGcm => Self::ERROR_INTERFACE_VAULT | 13,
-            VaultFailErrorKind::InvalidParam(..) => Self::ERROR_INTERFACE_VAULT | 20,
-            VaultFailErrorKind::InvalidAttributes => Self::ERROR_INTERFACE_VAULT | 21,
-            VaultFailErrorKind::InvalidContext => Self::ERROR_INTERFACE_VAULT | 22,
-            VaultFailErrorKind::InvalidBuffer => Self::ERROR_INTERFACE_VAULT | 23,
-            VaultFailErrorKind::InvalidSize => Self::ERROR_INTERFACE_VAULT | 24,
-            VaultFailErrorKind::InvalidRegenerate => Self::ERROR_INTERFACE_VAULT | 25,
-            VaultFailErrorKind::InvalidSecret => Self::ERROR_INTERFACE_VAULT | 26,
-            VaultFailErrorKind::InvalidSecretAttributes => Self::ERROR_INTERFACE_VAULT | 27,
-            VaultFailErrorKind::InvalidSecretType => Self::ERROR_INTERFACE_VAULT | 28,
-            VaultFailErrorKind::InvalidTag => Self::ERROR_INTERFACE_VAULT | 29,
-            VaultFailErrorKind::BufferTooSmall => Self::ERROR_INTERFACE_VAULT | 30,
-            VaultFailErrorKind::DefaultRandomRequired => Self::ERROR_INTERFACE_VAULT | 31,
-            VaultFailErrorKind::MemoryRequired => Self::ERROR_INTERFACE_VAULT | 32,
-            VaultFailErrorKind::SecretSizeMismatch => Self::ERROR_INTERFACE_VAULT | 33,
-            VaultFailErrorKind::IOError => Self::ERROR_INTERFACE_VAULT | 40,
-            VaultFailErrorKind::AccessDenied => Self::ERROR_INTERFACE_VAULT | 50,
-        }
-    }
-}
-
-/// Wraps an error kind with context and backtrace logic
-#[derive(Debug)]
-pub struct VaultFailError {
-    inner: Context<VaultFailErrorKind>,
-}
+impl ErrorKind for VaultFailErrorKind {
+    const ERROR_INTERFACE: usize = ERROR_INTERFACE_VAULT;
 
-impl VaultFailError {
-    /// Convert from an error kind and a static string
-    pub fn from_msg<D>(kind: VaultFailErrorKind, msg: D) -> Self
-    where
-        D: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
-    {
-        Self {
-            inner: Context::new(msg).context(kind),
-        }
-    }
-
-    /// Convert to an integer, reused in From trait implementations
     fn to_usize(&self) -> usize {
-        self.inner.get_context().to_usize()
-    }
-}
-
-impl From<VaultFailErrorKind> for VaultFailError {
-    fn from(kind: VaultFailErrorKind) -> Self {
-        Self {
-            inner: Context::new("").context(kind),
+        match *self {
+            VaultFailErrorKind::Init => Self::ERROR_INTERFACE | 1,
+            VaultFailErrorKind::Random => Self::ERROR_INTERFACE | 2,
+            VaultFailErrorKind::Sha256 => Self::ERROR_INTERFACE | 3,
+            VaultFailErrorKind::SecretGenerate => Self::ERROR_INTERFACE | 4,
+            VaultFailErrorKind::Import => Self::ERROR_INTERFACE | 5,
+            VaultFailErrorKind::Export => Self::ERROR_INTERFACE | 6,
+            VaultFailErrorKind::GetAttributes => Self::ERROR_INTERFACE | 7,
+            VaultFailErrorKind::PublicKey => Self::ERROR_INTERFACE | 8,
+            VaultFailErrorKind::Ecdh => Self::ERROR_INTERFACE | 9,
+            VaultFailErrorKind::HkdfSha256 => Self::ERROR_INTERFACE | 10,
+            VaultFailErrorKind::AeadAesGcmEncrypt => Self::ERROR_INTERFACE | 11,
+            VaultFailErrorKind::AeadAesGcmDecrypt => Self::ERROR_INTERFACE | 12,
+            VaultFailErrorKind::AeadAesGcm => Self::ERROR_INTERFACE | 13,
+            VaultFailErrorKind::AeadChaChaPolyEncrypt => Self::ERROR_INTERFACE | 14,
+            VaultFailErrorKind::AeadChaChaPolyDecrypt => Self::ERROR_INTERFACE | 15,
+            VaultFailErrorKind::Sign => Self::ERROR_INTERFACE | 16,
+            VaultFailErrorKind::Verify => Self::ERROR_INTERFACE | 17,
+            VaultFailErrorKind::InvalidParam(..) => Self::ERROR_INTERFACE | 20,
+            VaultFailErrorKind::InvalidAttributes => Self::ERROR_INTERFACE | 21,
+            VaultFailErrorKind::InvalidContext => Self::ERROR_INTERFACE | 22,
+            VaultFailErrorKind::InvalidBuffer => Self::ERROR_INTERFACE | 23,
+            VaultFailErrorKind::InvalidSize => Self::ERROR_INTERFACE | 24,
+            VaultFailErrorKind::InvalidRegenerate => Self::ERROR_INTERFACE | 25,
+            VaultFailErrorKind::InvalidSecret => Self::ERROR_INTERFACE | 26,
+            VaultFailErrorKind::InvalidSecretAttributes => Self::ERROR_INTERFACE | 27,
+            VaultFailErrorKind::InvalidSecretType => Self::ERROR_INTERFACE | 28,
+            VaultFailErrorKind::InvalidTag => Self::ERROR_INTERFACE | 29,
+            VaultFailErrorKind::BufferTooSmall => Self::ERROR_INTERFACE | 30,
+            VaultFailErrorKind::DefaultRandomRequired => Self::ERROR_INTERFACE | 31,
+            VaultFailErrorKind::MemoryRequired => Self::ERROR_INTERFACE | 32,
+            VaultFailErrorKind::SecretSizeMismatch => Self::ERROR_INTERFACE | 33,
+            VaultFailErrorKind::IOError => Self::ERROR_INTERFACE | 40,
+            VaultFailErrorKind::AccessDenied => Self::ERROR_INTERFACE | 50,
         }
     }
 }
 
-impl From<VaultFailError> for VaultFailErrorKind {
-    fn from(err: VaultFailError) -> Self {
-        *err.inner.get_context()
-    }
-}
+error_impl!(VaultFailError, VaultFailErrorKind);
 
 impl From<hkdf::InvalidLength> for VaultFailErrorKind {
     fn from(_: hkdf::InvalidLength) -> Self {
@@ -190,7 +173,7 @@ impl From<aes_gcm::Error> for VaultFailErrorKind {
 #[cfg(feature = "ffi")]
 impl From<VaultFailErrorKind> for ffi_support::ExternError {
     fn from(err: VaultFailErrorKind) -> ffi_support::ExternError {
-        ffi_support::ExternError::new_error(ffi_support::ErrorCode::new(err.to_usize() as i32), "")
+        ffi_support::ExternError::new_error(ffi_support::ErrorCode::new(err.code() as i32), "")
     }
 }
 
@@ -206,12 +189,6 @@ impl From<io::Error> for VaultFailError {
     }
 }
 
-impl From<Context<VaultFailErrorKind>> for VaultFailError {
-    fn from(inner: Context<VaultFailErrorKind>) -> Self {
-        Self { inner }
-    }
-}
-
 impl From<hkdf::InvalidLength> for VaultFailError {
     fn from(_: hkdf::InvalidLength) -> Self {
         VaultFailError::from(VaultFailErrorKind::HkdfSha256)
@@ -295,8 +272,10 @@ impl From<keychain_services::Error> for VaultFailError {
 #[cfg(feature = "ffi")]
 impl From<VaultFailError> for ffi_support::ExternError {
     fn from(err: VaultFailError) -> ffi_support::ExternError {
-        let err: VaultFailErrorKind = err.into();
-        err.into()
+        ffi_support::ExternError::new_error(
+            ffi_support::ErrorCode::new(err.code() as i32),
+            err.to_string(),
+        )
     }
 }
 
@@ -306,39 +285,6 @@ impl From<VaultFailError> for ffi_support::ExternError {
 //     }
 // }
 
-from_int_impl!(VaultFailError, u32);
-from_int_impl!(VaultFailError, u64);
-from_int_impl!(VaultFailError, u128);
-from_int_impl!(VaultFailErrorKind, u32);
-from_int_impl!(VaultFailErrorKind, u64);
-from_int_impl!(VaultFailErrorKind, u128);
-
-impl Fail for VaultFailError {
-    fn cause(&self) -> Option<&dyn Fail> {
-        self.inner.cause()
-    }
-
-    fn backtrace(&self) -> Option<&Backtrace> {
-        self.inner.backtrace()
-    }
-}
-
-impl fmt::Display for VaultFailError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut first = true;
-
-        for cause in Fail::iter_chain(&self.inner) {
-            if first {
-                first = false;
-                writeln!(f, "Error: {}", cause)?;
-            } else {
-                writeln!(f, "Caused by: {}", cause)?;
-            }
-        }
-        Ok(())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -348,115 +294,131 @@ mod tests {
         let errors = vec![
             (
                 VaultFailErrorKind::Init,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 1,
+                VaultFailErrorKind::ERROR_INTERFACE | 1,
             ),
             (
                 VaultFailErrorKind::Random,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 2,
+                VaultFailErrorKind::ERROR_INTERFACE | 2,
             ),
             (
                 VaultFailErrorKind::Sha256,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 3,
+                VaultFailErrorKind::ERROR_INTERFACE | 3,
             ),
             (
                 VaultFailErrorKind::SecretGenerate,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 4,
+                VaultFailErrorKind::ERROR_INTERFACE | 4,
             ),
             (
                 VaultFailErrorKind::Import,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 5,
+                VaultFailErrorKind::ERROR_INTERFACE | 5,
             ),
             (
                 VaultFailErrorKind::Export,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 6,
+                VaultFailErrorKind::ERROR_INTERFACE | 6,
             ),
             (
                 VaultFailErrorKind::GetAttributes,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 7,
+                VaultFailErrorKind::ERROR_INTERFACE | 7,
             ),
             (
                 VaultFailErrorKind::PublicKey,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 8,
+                VaultFailErrorKind::ERROR_INTERFACE | 8,
             ),
             (
                 VaultFailErrorKind::Ecdh,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 9,
+                VaultFailErrorKind::ERROR_INTERFACE | 9,
             ),
             (
                 VaultFailErrorKind::HkdfSha256,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 10,
+                VaultFailErrorKind::ERROR_INTERFACE | 10,
             ),
             (
                 VaultFailErrorKind::AeadAesGcmEncrypt,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 11,
+                VaultFailErrorKind::ERROR_INTERFACE | 11,
             ),
             (
                 VaultFailErrorKind::AeadAesGcmDecrypt,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 12,
+                VaultFailErrorKind::ERROR_INTERFACE | 12,
             ),
             (
                 VaultFailErrorKind::AeadAesGcm,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 13,
+                VaultFailErrorKind::ERROR_INTERFACE | 13,
+            ),
+            (
+                VaultFailErrorKind::AeadChaChaPolyEncrypt,
+                VaultFailErrorKind::ERROR_INTERFACE | 14,
+            ),
+            (
+                VaultFailErrorKind::AeadChaChaPolyDecrypt,
+                VaultFailErrorKind::ERROR_INTERFACE | 15,
+            ),
+            (
+                VaultFailErrorKind::Sign,
+                VaultFailErrorKind::ERROR_INTERFACE | 16,
+            ),
+            (
+                VaultFailErrorKind::Verify,
+                VaultFailErrorKind::ERROR_INTERFACE | 17,
             ),
             (
                 VaultFailErrorKind::InvalidParam(0),
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 20,
+                VaultFailErrorKind::ERROR_INTERFACE | 20,
             ),
             (
                 VaultFailErrorKind::InvalidAttributes,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 21,
+                VaultFailErrorKind::ERROR_INTERFACE | 21,
             ),
             (
                 VaultFailErrorKind::InvalidContext,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 22,
+                VaultFailErrorKind::ERROR_INTERFACE | 22,
             ),
             (
                 VaultFailErrorKind::InvalidBuffer,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 23,
+                VaultFailErrorKind::ERROR_INTERFACE | 23,
             ),
             (
                 VaultFailErrorKind::InvalidSize,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 24,
+                VaultFailErrorKind::ERROR_INTERFACE | 24,
             ),
             (
                 VaultFailErrorKind::InvalidRegenerate,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 25,
+                VaultFailErrorKind::ERROR_INTERFACE | 25,
             ),
             (
                 VaultFailErrorKind::InvalidSecret,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 26,
+                VaultFailErrorKind::ERROR_INTERFACE | 26,
             ),
             (
                 VaultFailErrorKind::InvalidSecretAttributes,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 27,
+                VaultFailErrorKind::ERROR_INTERFACE | 27,
             ),
             (
                 VaultFailErrorKind::InvalidSecretType,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 28,
+                VaultFailErrorKind::ERROR_INTERFACE | 28,
             ),
             (
                 VaultFailErrorKind::InvalidTag,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 29,
+                VaultFailErrorKind::ERROR_INTERFACE | 29,
             ),
             (
                 VaultFailErrorKind::BufferTooSmall,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 30,
+                VaultFailErrorKind::ERROR_INTERFACE | 30,
             ),
             (
                 VaultFailErrorKind::DefaultRandomRequired,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 31,
+                VaultFailErrorKind::ERROR_INTERFACE | 31,
             ),
             (
                 VaultFailErrorKind::MemoryRequired,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 32,
+                VaultFailErrorKind::ERROR_INTERFACE | 32,
             ),
             (
                 VaultFailErrorKind::SecretSizeMismatch,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 33,
+                VaultFailErrorKind::ERROR_INTERFACE | 33,
             ),
             (
                 VaultFailErrorKind::IOError,
-                VaultFailErrorKind::ERROR_INTERFACE_VAULT | 40,
+                VaultFailErrorKind::ERROR_INTERFACE | 40,
             ),
         ];
 
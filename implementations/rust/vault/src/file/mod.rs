@@ -1,26 +1,72 @@
 use std::convert::TryFrom;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 
 use crate::{error::*, software::DefaultVault, types::*, DynVault};
 
+use chacha20poly1305::aead::{generic_array::GenericArray, Aead, NewAead, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hmac::{Hmac, Mac, NewMac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
 use zeroize::Zeroize;
 
 const ATTRS_BYTE_LENGTH: usize = 6;
 
+/// The file in a sealed vault's directory which holds the salt its key is derived from
+pub const SEAL_FILE_NAME: &str = "vault.seal";
+
+// Sealed files start with this. Plain key files start with the big-endian key type, whose first
+// byte is always 0, so the two can't be mistaken for each other.
+const SEALED_MAGIC: &[u8; 4] = b"OKVS";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+// PBKDF2-HMAC-SHA256 rounds for new vaults; a vault records the number it was sealed with
+const KDF_ITERATIONS: u32 = 100_000;
+
 /// A FilesystemVault is an implementation of an Ockam Vault that wraps the software vault and uses
 /// the disk as a persistent store.
+///
+/// Opened with a passphrase, the vault is sealed: every key file is encrypted with
+/// ChaCha20-Poly1305 under a key derived from the passphrase, so that the secrets are never
+/// written to disk in the clear. Plain key files found in a sealed vault, e.g. those of a vault
+/// created without a passphrase, are sealed when it is opened.
 #[derive(Debug)]
 pub struct FilesystemVault {
     v: DefaultVault,
     path: PathBuf,
+    seal: Option<SealKey>,
 }
 
 impl FilesystemVault {
     /// Creates a new FilesystemVault using the provided path on disk to store secrets.
-    pub fn new(path: PathBuf) -> std::io::Result<Self> {
-        let create_path = path.clone();
-        fs::create_dir_all(create_path)?;
+    pub fn new(path: PathBuf) -> io::Result<Self> {
+        Self::open(path, None)
+    }
+
+    /// Creates a new FilesystemVault using the provided path on disk to store secrets, encrypted
+    /// with a key derived from `passphrase`. Fails with `InvalidData` if the vault was sealed
+    /// with a different passphrase.
+    pub fn with_passphrase(path: PathBuf, passphrase: &[u8]) -> io::Result<Self> {
+        Self::open(path, Some(passphrase))
+    }
+
+    fn open(path: PathBuf, passphrase: Option<&[u8]>) -> io::Result<Self> {
+        fs::create_dir_all(&path)?;
+
+        let seal_path = path.join(SEAL_FILE_NAME);
+        let seal = match passphrase {
+            Some(passphrase) => Some(SealKey::open(&seal_path, passphrase)?),
+            None if seal_path.exists() => {
+                return Err(invalid_data(
+                    "the vault is sealed, a passphrase is needed to open it",
+                ))
+            }
+            None => None,
+        };
 
         let mut vault = DefaultVault::default();
         let to_secret = |data: &[u8]| -> Result<(SecretKey, SecretKeyAttributes), VaultFailError> {
@@ -37,62 +83,201 @@ impl FilesystemVault {
                 attributes,
             ))
         };
-        let fs_path = path.clone();
-
-        path.read_dir()?
-            .filter(|r| {
-                // ignore directories within vault path
-                if let Ok(e) = r {
-                    match fs::metadata(e.path()) {
-                        Ok(md) => md.is_file(),
-                        Err(_) => false,
-                    }
-                } else {
-                    false
+
+        for entry in path.read_dir()? {
+            let entry = entry?;
+            // ignore directories within vault path
+            let is_file = fs::metadata(entry.path())
+                .map(|md| md.is_file())
+                .unwrap_or(false);
+            if !is_file || entry.file_name() == SEAL_FILE_NAME {
+                continue;
+            }
+
+            let data = match fs::read(entry.path()) {
+                Ok(data) => data,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
                 }
-            })
-            .for_each(|entry| {
-                if let Ok(entry) = entry {
-                    match fs::read(entry.path()) {
-                        Ok(data) => {
-                            let (secret, attrs) =
-                                &to_secret(data.as_slice()).unwrap_or_else(|_| {
-                                    panic!("failed to get secret {:?} from file", entry.file_name())
-                                });
-                            // Files are read in any order
-                            let fname = entry.file_name();
-                            let t: &std::path::Path = fname.as_os_str().as_ref();
-                            let mut valid_id = false;
-                            if let Some(stem) = t.file_stem() {
-                                if let Some(str) = stem.to_str() {
-                                    if let Ok(id) = str.parse::<usize>() {
-                                        // Set the next id to match the file name
-                                        vault.next_id = id - 1;
-                                        valid_id = true;
-                                    }
-                                }
-                            }
-                            if !valid_id {
-                                eprintln!("invalid key file name: {:?}", entry);
-                            } else {
-                                if let Err(e) = vault.secret_import(secret, *attrs) {
-                                    eprintln!("{}", e);
-                                }
-                            }
-                        }
-                        Err(e) => eprintln!("{}", e),
+            };
+            let was_sealed = data.starts_with(SEALED_MAGIC);
+            let mut data = match (&seal, was_sealed) {
+                (Some(seal), true) => seal.unseal(&data)?,
+                (None, true) => {
+                    return Err(invalid_data(format!(
+                        "key file {:?} is sealed, a passphrase is needed to open it",
+                        entry.file_name()
+                    )))
+                }
+                (_, false) => data,
+            };
+            let (secret, attrs) = to_secret(data.as_slice()).map_err(|_| {
+                invalid_data(format!(
+                    "failed to get secret {:?} from file",
+                    entry.file_name()
+                ))
+            })?;
+            // a plain key file in a sealed vault is sealed in place
+            if let (Some(seal), false) = (&seal, was_sealed) {
+                fs::write(entry.path(), seal.seal(&data)?)?;
+            }
+            data.zeroize();
+
+            // Files are read in any order
+            let fname = entry.file_name();
+            let t: &Path = fname.as_os_str().as_ref();
+            let mut valid_id = false;
+            if let Some(stem) = t.file_stem() {
+                if let Some(str) = stem.to_str() {
+                    // Set the next id to match the file name; ids start at 1
+                    if let Some(previous) =
+                        str.parse::<usize>().ok().and_then(|id| id.checked_sub(1))
+                    {
+                        vault.next_id = previous;
+                        valid_id = true;
                     }
                 }
-            });
+            }
+            if !valid_id {
+                eprintln!("invalid key file name: {:?}", entry);
+            } else if let Err(e) = vault.secret_import(&secret, attrs) {
+                eprintln!("{}", e);
+            }
+        }
         if let Some(id) = vault.get_ids().iter().max() {
             vault.next_id = *id;
         }
 
         Ok(Self {
             v: vault,
-            path: fs_path,
+            path,
+            seal,
         })
     }
+
+    /// Whether the vault's key files are encrypted
+    pub fn is_sealed(&self) -> bool {
+        self.seal.is_some()
+    }
+}
+
+// The key a sealed vault's files are encrypted with
+#[derive(Zeroize)]
+#[zeroize(drop)]
+struct SealKey([u8; 32]);
+
+impl fmt::Debug for SealKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SealKey")
+    }
+}
+
+impl SealKey {
+    // The key derived from `passphrase` with the salt in the seal file at `path`, checked against
+    // the tag stored along with it. Without a seal file, the vault is sealed from now on with a
+    // new salt.
+    fn open(path: &Path, passphrase: &[u8]) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => {
+                let header = SEALED_MAGIC.len() + 4 + SALT_LENGTH;
+                if data.len() != header + SEALED_MAGIC.len() + NONCE_LENGTH + TAG_LENGTH
+                    || !data.starts_with(SEALED_MAGIC)
+                {
+                    return Err(invalid_data("bad seal file"));
+                }
+                let mut iterations = [0u8; 4];
+                iterations.copy_from_slice(&data[SEALED_MAGIC.len()..SEALED_MAGIC.len() + 4]);
+                let iterations = u32::from_be_bytes(iterations);
+                let key = Self::derive(
+                    passphrase,
+                    &data[SEALED_MAGIC.len() + 4..header],
+                    iterations,
+                );
+                key.unseal(&data[header..])
+                    .map_err(|_| invalid_data("wrong passphrase for the vault"))?;
+                Ok(key)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Self::create(path, passphrase, KDF_ITERATIONS)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn create(path: &Path, passphrase: &[u8], iterations: u32) -> io::Result<Self> {
+        let mut salt = [0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        let key = Self::derive(passphrase, &salt, iterations);
+
+        // the tag of nothing sealed, which only the right passphrase opens
+        let mut data = SEALED_MAGIC.to_vec();
+        data.extend_from_slice(&iterations.to_be_bytes());
+        data.extend_from_slice(&salt);
+        data.extend_from_slice(&key.seal(&[])?);
+        fs::write(path, data)?;
+        Ok(key)
+    }
+
+    // PBKDF2-HMAC-SHA256 of `passphrase`, as in RFC 8018; one block is all a key needs
+    fn derive(passphrase: &[u8], salt: &[u8], iterations: u32) -> Self {
+        let prf = Hmac::<Sha256>::new_varkey(passphrase).expect("HMAC takes keys of any length");
+        let mut mac = prf.clone();
+        mac.update(salt);
+        mac.update(&1u32.to_be_bytes());
+        let mut u = mac.finalize().into_bytes();
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&u);
+        for _ in 1..iterations {
+            let mut mac = prf.clone();
+            mac.update(&u);
+            u = mac.finalize().into_bytes();
+            key.iter_mut().zip(u.iter()).for_each(|(k, u)| *k ^= u);
+        }
+        u.as_mut_slice().zeroize();
+        SealKey(key)
+    }
+
+    // `plaintext` encrypted under a random nonce, which starts the sealed data after the magic
+    fn seal(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&self.0));
+        let payload = Payload {
+            msg: plaintext,
+            aad: SEALED_MAGIC,
+        };
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(&nonce), payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to seal file"))?;
+
+        let mut sealed = SEALED_MAGIC.to_vec();
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if !sealed.starts_with(SEALED_MAGIC)
+            || sealed.len() < SEALED_MAGIC.len() + NONCE_LENGTH + TAG_LENGTH
+        {
+            return Err(invalid_data("bad sealed file"));
+        }
+        let sealed = &sealed[SEALED_MAGIC.len()..];
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&self.0));
+        let payload = Payload {
+            msg: ciphertext,
+            aad: SEALED_MAGIC,
+        };
+        cipher
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|_| invalid_data("failed to unseal file, wrong passphrase or corrupt file"))
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
 }
 
 fn id_to_path(id: usize) -> PathBuf {
@@ -101,6 +286,7 @@ fn id_to_path(id: usize) -> PathBuf {
 
 fn fs_write_secret(
     path: PathBuf,
+    seal: Option<&SealKey>,
     ctx: SecretKeyContext,
     key: SecretKey,
     attrs: SecretKeyAttributes,
@@ -110,13 +296,18 @@ fn fs_write_secret(
             SecretKeyContext::Memory(id) => {
                 let mut bytes = attrs.to_bytes().to_vec();
                 bytes.extend_from_slice(key.as_ref());
+                if let Some(seal) = seal {
+                    let sealed = seal.seal(&bytes);
+                    bytes.zeroize();
+                    bytes = sealed?;
+                }
 
                 Ok(fs::write(path.join(id_to_path(id)), bytes)?)
             }
             _ => Err(VaultFailErrorKind::InvalidContext.into()),
         };
     }
-    return Ok(());
+    Ok(())
 }
 
 impl DynVault for FilesystemVault {
@@ -138,7 +329,13 @@ impl DynVault for FilesystemVault {
         // write the secret to disk using the context id
         let ctx = self.v.secret_generate(attributes)?;
         let secret = self.v.secret_export(ctx)?;
-        fs_write_secret(self.path.clone(), ctx, secret, attributes)?;
+        fs_write_secret(
+            self.path.clone(),
+            self.seal.as_ref(),
+            ctx,
+            secret,
+            attributes,
+        )?;
 
         Ok(ctx)
     }
@@ -151,7 +348,13 @@ impl DynVault for FilesystemVault {
     ) -> Result<SecretKeyContext, VaultFailError> {
         // write the secret to disk using the context id
         let ctx = self.v.secret_import(secret, attributes)?;
-        fs_write_secret(self.path.clone(), ctx, secret.clone(), attributes)?;
+        fs_write_secret(
+            self.path.clone(),
+            self.seal.as_ref(),
+            ctx,
+            secret.clone(),
+            attributes,
+        )?;
 
         Ok(ctx)
     }
@@ -263,6 +466,30 @@ impl DynVault for FilesystemVault {
             .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
     }
 
+    /// Encrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.v
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    /// Decrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.v
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+    }
+
     /// Close and release all resources in use by the vault
     fn deinit(&mut self) {
         self.v.deinit()
@@ -327,4 +554,105 @@ mod tests {
         assert_eq!(sk_data2, sk2_data_2);
         assert_eq!(sk_data3, sk2_data_3);
     }
+
+    #[test]
+    fn zero_id_key_file_test() {
+        let path = PathBuf::from("__zero_id_key_file_test");
+        if path.exists() {
+            fs::remove_dir_all(path.clone()).unwrap();
+        }
+        let mut vault = FilesystemVault::new(path.clone()).unwrap();
+        let atts = SecretKeyAttributes {
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Persistent,
+            xtype: SecretKeyType::Curve25519,
+        };
+        vault.secret_generate(atts).unwrap();
+        vault.deinit();
+
+        // no key has id 0, so the file is passed over rather than loaded
+        fs::rename(path.join("1.key"), path.join("0.key")).unwrap();
+        let vault2 = FilesystemVault::new(path.clone()).unwrap();
+        assert!(vault2.v.get_ids().is_empty());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn sealed_persistence_test() {
+        let path = PathBuf::from("__sealed_persistence_test");
+        if path.exists() {
+            fs::remove_dir_all(path.clone()).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        SealKey::create(&path.join(SEAL_FILE_NAME), b"passphrase", 16).unwrap();
+        let mut vault = FilesystemVault::with_passphrase(path.clone(), b"passphrase").unwrap();
+        let atts = SecretKeyAttributes {
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Persistent,
+            xtype: SecretKeyType::Curve25519,
+        };
+        let sk1 = vault.secret_generate(atts).unwrap();
+        let sk_data1 = vault.secret_export(sk1).unwrap();
+        vault.deinit();
+
+        let data = fs::read(path.join("1.key")).unwrap();
+        assert!(data.starts_with(SEALED_MAGIC));
+        assert!(!data
+            .windows(sk_data1.as_ref().len())
+            .any(|w| w == sk_data1.as_ref()));
+
+        let mut vault2 = FilesystemVault::with_passphrase(path.clone(), b"passphrase").unwrap();
+        assert_eq!(vault2.secret_export(sk1).unwrap(), sk_data1);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn seal_test() {
+        let path = PathBuf::from("__seal_test");
+        if path.exists() {
+            fs::remove_dir_all(path.clone()).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        let key = SealKey::create(&path.join(SEAL_FILE_NAME), b"passphrase", 16).unwrap();
+
+        let sealed = key.seal(b"secret").unwrap();
+        assert_eq!(
+            sealed.len(),
+            SEALED_MAGIC.len() + NONCE_LENGTH + 6 + TAG_LENGTH
+        );
+        assert_eq!(key.unseal(&sealed).unwrap(), b"secret");
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.unseal(&tampered).is_err());
+
+        assert!(
+            FilesystemVault::with_passphrase(path.clone(), b"passphrase")
+                .unwrap()
+                .is_sealed()
+        );
+        let wrong = FilesystemVault::with_passphrase(path.clone(), b"wrong").unwrap_err();
+        assert_eq!(wrong.kind(), io::ErrorKind::InvalidData);
+        assert!(FilesystemVault::new(path.clone()).is_err());
+
+        // a sealed key file can't be read without the passphrase
+        fs::remove_file(path.join(SEAL_FILE_NAME)).unwrap();
+        fs::write(path.join("1.key"), sealed).unwrap();
+        assert!(FilesystemVault::new(path.clone()).is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn pbkdf2_test() {
+        // RFC 7914, section 11
+        let key = SealKey::derive(b"passwd", b"salt", 1);
+        assert_eq!(
+            hex::encode(&key.0),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        let key = SealKey::derive(b"password", b"salt", 4096);
+        assert_eq!(
+            hex::encode(&key.0),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"
+        );
+    }
 }
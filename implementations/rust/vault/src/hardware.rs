@@ -0,0 +1,287 @@
+use crate::error::{VaultFailError, VaultFailErrorKind};
+use crate::software::DefaultVault;
+use crate::types::{
+    PublicKey, SecretKey, SecretKeyAttributes, SecretKeyContext, SecretKeyType,
+    SecretPersistenceType, SecretPurposeType,
+};
+use crate::Vault;
+use std::collections::BTreeMap;
+use zeroize::Zeroize;
+
+pub(crate) const P256_PUBLICKEY_LENGTH: usize = 65;
+
+/// A secret held by a hardware backed vault: the key `K` in the hardware, or a secret in memory
+#[derive(Clone, Copy)]
+pub(crate) enum Held<K> {
+    Hardware(K),
+    Memory(SecretKeyContext),
+}
+
+/// The secrets of a vault which keeps persistent P-256 keys in hardware, e.g. a secure element or
+/// an HSM, and does everything else in software: ephemeral keys, the shared secrets returned by
+/// ECDH, the keys HKDF derives from them and the AEAD run with those.
+///
+/// Both kinds of secret are known by the id of a `SecretKeyContext::Memory`. The operations only
+/// done in software fail with a message naming the hardware when given a key in it.
+pub(crate) struct HardwareSecrets<K> {
+    pub(crate) software: DefaultVault,
+    secrets: BTreeMap<usize, Held<K>>,
+    next_id: usize,
+    hardware: &'static str,
+}
+
+impl<K: Copy> HardwareSecrets<K> {
+    /// No secrets yet, for the hardware named `hardware`, e.g. "the ATECC608A"
+    pub(crate) fn new(hardware: &'static str) -> Self {
+        Self {
+            software: DefaultVault::default(),
+            secrets: BTreeMap::new(),
+            next_id: 0,
+            hardware,
+        }
+    }
+
+    /// The number of secrets held
+    pub(crate) fn count(&self) -> usize {
+        self.secrets.len()
+    }
+
+    /// Add `secret` under the next unused id
+    pub(crate) fn insert(&mut self, secret: Held<K>) -> SecretKeyContext {
+        self.next_id += 1;
+        while self.secrets.contains_key(&self.next_id) {
+            self.next_id += 1;
+        }
+        self.secrets.insert(self.next_id, secret);
+        SecretKeyContext::Memory(self.next_id)
+    }
+
+    /// Add the key `key` in the hardware under an id chosen for it, see `is_free`
+    pub(crate) fn insert_at(&mut self, id: usize, key: K) -> SecretKeyContext {
+        self.secrets.insert(id, Held::Hardware(key));
+        SecretKeyContext::Memory(id)
+    }
+
+    /// Whether `id` is neither taken nor one `insert` may give out later
+    pub(crate) fn is_free(&self, id: usize) -> bool {
+        id > self.next_id && !self.secrets.contains_key(&id)
+    }
+
+    /// The secret `context`, if it is known
+    pub(crate) fn find(
+        &self,
+        context: SecretKeyContext,
+    ) -> Result<Option<Held<K>>, VaultFailError> {
+        match context {
+            SecretKeyContext::Memory(id) => Ok(self.secrets.get(&id).copied()),
+            _ => Err(VaultFailErrorKind::InvalidContext.into()),
+        }
+    }
+
+    /// The secret `context`, failing with `error` if it isn't known
+    pub(crate) fn get(
+        &self,
+        context: SecretKeyContext,
+        error: VaultFailErrorKind,
+    ) -> Result<Held<K>, VaultFailError> {
+        self.find(context)?.ok_or_else(|| error.into())
+    }
+
+    /// The context in the software vault of the in-memory secret `context`, failing with `error`
+    /// for a key in the hardware
+    pub(crate) fn memory(
+        &self,
+        context: SecretKeyContext,
+        error: VaultFailErrorKind,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        match self.get(context, error)? {
+            Held::Memory(context) => Ok(context),
+            Held::Hardware(_) => Err(VaultFailError::from_msg(
+                error,
+                format!("not possible with a key in {}", self.hardware),
+            )),
+        }
+    }
+
+    /// Forget the secret `context`, once it has been destroyed
+    pub(crate) fn remove(&mut self, context: SecretKeyContext) {
+        if let SecretKeyContext::Memory(id) = context {
+            self.secrets.remove(&id);
+        }
+    }
+
+    /// Generate a secret with `attributes` in memory
+    pub(crate) fn secret_generate(
+        &mut self,
+        attributes: SecretKeyAttributes,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let context = self.software.secret_generate(attributes)?;
+        Ok(self.insert(Held::Memory(context)))
+    }
+
+    /// Import `secret` with `attributes` into memory
+    pub(crate) fn secret_import(
+        &mut self,
+        secret: &SecretKey,
+        attributes: SecretKeyAttributes,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let context = self.software.secret_import(secret, attributes)?;
+        Ok(self.insert(Held::Memory(context)))
+    }
+
+    pub(crate) fn secret_export(
+        &mut self,
+        context: SecretKeyContext,
+    ) -> Result<SecretKey, VaultFailError> {
+        let context = self.memory(context, VaultFailErrorKind::Export)?;
+        self.software.secret_export(context)
+    }
+
+    /// Import the shared secret `dh` the hardware computed into the software vault, zeroizing it
+    pub(crate) fn shared_secret_import(
+        &mut self,
+        dh: &mut [u8],
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let shared_secret = self.software.secret_import(
+            &SecretKey::Buffer(dh.to_vec()),
+            SecretKeyAttributes {
+                xtype: SecretKeyType::Buffer(dh.len()),
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Ephemeral,
+            },
+        );
+        dh.zeroize();
+        shared_secret
+    }
+
+    /// Hold the shared secret `shared_secret`, in the software vault, as a secret of its own
+    pub(crate) fn ec_diffie_hellman(
+        &mut self,
+        shared_secret: SecretKeyContext,
+    ) -> SecretKeyContext {
+        self.insert(Held::Memory(shared_secret))
+    }
+
+    /// HKDF with the salt `salt` and the shared secret `shared_secret`, in the software vault,
+    /// which is destroyed afterwards
+    pub(crate) fn ec_diffie_hellman_hkdf_sha256(
+        &mut self,
+        salt: SecretKeyContext,
+        info: &[u8],
+        shared_secret: SecretKeyContext,
+        output_attributes: Vec<SecretKeyAttributes>,
+    ) -> Result<Vec<SecretKeyContext>, VaultFailError> {
+        let outputs = self
+            .software
+            .hkdf_sha256(salt, info, Some(shared_secret), output_attributes);
+        self.software.secret_destroy(shared_secret)?;
+        Ok(outputs?
+            .into_iter()
+            .map(|c| self.insert(Held::Memory(c)))
+            .collect())
+    }
+
+    pub(crate) fn hkdf_sha256(
+        &mut self,
+        salt: SecretKeyContext,
+        info: &[u8],
+        ikm: Option<SecretKeyContext>,
+        output_attributes: Vec<SecretKeyAttributes>,
+    ) -> Result<Vec<SecretKeyContext>, VaultFailError> {
+        let salt = self.memory(salt, VaultFailErrorKind::HkdfSha256)?;
+        let ikm = match ikm {
+            Some(ikm) => Some(self.memory(ikm, VaultFailErrorKind::HkdfSha256)?),
+            None => None,
+        };
+        let outputs = self
+            .software
+            .hkdf_sha256(salt, info, ikm, output_attributes)?;
+        Ok(outputs
+            .into_iter()
+            .map(|c| self.insert(Held::Memory(c)))
+            .collect())
+    }
+
+    pub(crate) fn aead_aes_gcm_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let context = self.memory(context, VaultFailErrorKind::AeadAesGcmEncrypt)?;
+        self.software
+            .aead_aes_gcm_encrypt(context, plaintext, nonce, aad)
+    }
+
+    pub(crate) fn aead_aes_gcm_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let context = self.memory(context, VaultFailErrorKind::AeadAesGcmDecrypt)?;
+        self.software
+            .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
+    }
+
+    pub(crate) fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let context = self.memory(context, VaultFailErrorKind::AeadChaChaPolyEncrypt)?;
+        self.software
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    pub(crate) fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let context = self.memory(context, VaultFailErrorKind::AeadChaChaPolyDecrypt)?;
+        self.software
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+    }
+}
+
+impl<K> Zeroize for HardwareSecrets<K> {
+    fn zeroize(&mut self) {
+        self.software.zeroize();
+        self.secrets.clear();
+    }
+}
+
+/// The attributes of every key in the hardware
+pub(crate) fn hardware_attributes() -> SecretKeyAttributes {
+    SecretKeyAttributes {
+        xtype: SecretKeyType::P256,
+        purpose: SecretPurposeType::KeyAgreement,
+        persistence: SecretPersistenceType::Persistent,
+    }
+}
+
+/// Whether a key with `attributes` belongs in the hardware, rather than in memory
+pub(crate) fn in_hardware(attributes: &SecretKeyAttributes) -> bool {
+    matches!(attributes.xtype, SecretKeyType::P256)
+        && matches!(attributes.persistence, SecretPersistenceType::Persistent)
+}
+
+/// The P-256 point of the peer public key `public_key` in ECDH, failing for another curve's
+pub(crate) fn p256_public_key(
+    public_key: PublicKey,
+) -> Result<[u8; P256_PUBLICKEY_LENGTH], VaultFailError> {
+    match public_key {
+        PublicKey::P256(k) => Ok(k),
+        PublicKey::Curve25519(_) => Err(VaultFailError::from_msg(
+            VaultFailErrorKind::Ecdh,
+            "Unknown key type",
+        )),
+    }
+}
@@ -50,9 +50,15 @@ pub mod ffi;
 /// Software vault where keys are persisted to the filesystem
 /// if permanent
 pub mod file;
+#[cfg(any(feature = "atecc608a", feature = "pkcs11"))]
+/// Secrets split between hardware and software, for the hardware backed vaults
+mod hardware;
 /// Vault backed by the OSX Keychain and Secure-Enclave Processor
 #[cfg(all(target_os = "macos", feature = "os"))]
 pub mod osx;
+/// Vault backed by a token reached through PKCS#11, such as an HSM
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
 /// Software implementation of Vault. No persistence
 /// all keys are stored, operations happen in memory
 pub mod software;
@@ -135,6 +141,22 @@ pub trait Vault: Zeroize {
         nonce: C,
         aad: D,
     ) -> Result<Vec<u8>, VaultFailError>;
+    /// Encrypt a payload using ChaCha20-Poly1305, with a 32 byte `SecretKeyType::Buffer` key
+    fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError>;
+    /// Decrypt a payload using ChaCha20-Poly1305, with a 32 byte `SecretKeyType::Buffer` key
+    fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError>;
     /// Close and release all resources in use by the vault
     fn deinit(&mut self);
     /// Generate a signature
@@ -227,6 +249,22 @@ pub trait DynVault {
         nonce: &[u8],
         aad: &[u8],
     ) -> Result<Vec<u8>, VaultFailError>;
+    /// Encrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError>;
+    /// Decrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError>;
     /// Close and release all resources in use by the vault
     fn deinit(&mut self);
     /// Generate a signature
@@ -346,6 +384,26 @@ impl<D: Vault + Send + Sync + 'static> DynVault for D {
         Vault::aead_aes_gcm_decrypt(self, context, cipher_text, nonce, aad)
     }
 
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        Vault::aead_chacha20_poly1305_encrypt(self, context, plaintext, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        Vault::aead_chacha20_poly1305_decrypt(self, context, cipher_text, nonce, aad)
+    }
+
     fn deinit(&mut self) {
         Vault::deinit(self)
     }
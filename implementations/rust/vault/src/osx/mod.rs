@@ -9,21 +9,26 @@ use crate::{
     Vault,
 };
 use keychain_services as enclave;
-use p256::arithmetic::{AffinePoint, ProjectivePoint, Scalar};
 use rand::prelude::*;
 use security_framework::os::macos::keychain;
-use sha2::Sha256;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use subtle::ConstantTimeEq;
 use zeroize::Zeroize;
 
 const OCKAM_SERVICE_NAME: &str = "OckamOsxVault";
 
 /// A Vault that interacts with the Keychain
 /// and Secure Enclave Processor
+///
+/// Persistent P-256 keys are generated in the Secure Enclave and never leave it,
+/// they sign there with ECDSA over SHA-256. Other persistent secrets are kept in the
+/// Keychain and loaded into memory the first time they are used. Ephemeral secrets
+/// stay in memory.
 pub struct OsxVault {
     ephemeral_vault: DefaultVault,
     keychain: keychain::SecKeychain,
+    // The in memory contexts of the keychain secrets loaded so far, by keychain id
+    loaded: BTreeMap<usize, SecretKeyContext>,
 }
 
 impl OsxVault {
@@ -31,6 +36,165 @@ impl OsxVault {
         self.keychain.unlock(None)?;
         Ok(())
     }
+
+    // Store `secret` with `attributes` in the keychain
+    fn keychain_insert(
+        &mut self,
+        attributes: SecretKeyAttributes,
+        secret: &[u8],
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let id = rand::rngs::OsRng {}.gen::<usize>();
+        let mut bytes = attributes.to_bytes().to_vec();
+        bytes.extend_from_slice(secret);
+        self.unlock()?;
+        let stored = self.keychain.set_generic_password(
+            OCKAM_SERVICE_NAME,
+            id.to_string().as_str(),
+            bytes.as_slice(),
+        );
+        bytes.zeroize();
+        stored?;
+        Ok(SecretKeyContext::KeyRing {
+            id,
+            os_type: OsKeyRing::Osx(OsxContext::Keychain),
+        })
+    }
+
+    // The attributes and secret stored in the keychain as `id`
+    fn keychain_get(
+        &mut self,
+        id: usize,
+    ) -> Result<(SecretKeyAttributes, SecretKey), VaultFailError> {
+        self.unlock()?;
+        let (item, _) = self
+            .keychain
+            .find_generic_password(OCKAM_SERVICE_NAME, id.to_string().as_str())?;
+        let mut bytes = item.to_owned();
+        if bytes.len() < 6 {
+            bytes.zeroize();
+            fail!(VaultFailErrorKind::InvalidSecret);
+        }
+        let secret = SecretKeyAttributes::try_from(*array_ref![bytes, 0, 6])
+            .map(|a| (a, SecretKey::new(&bytes[6..], a.xtype)));
+        bytes.zeroize();
+        secret
+    }
+
+    // The context in `ephemeral_vault` of the secret `context`, loading it from the keychain
+    // if need be, failing with `error` for a key in the Secure Enclave
+    fn memory(
+        &mut self,
+        context: SecretKeyContext,
+        error: VaultFailErrorKind,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        match osx_context(context)? {
+            (id, OsxContext::Memory) => Ok(SecretKeyContext::Memory(id)),
+            (id, OsxContext::Keychain) => {
+                if let Some(loaded) = self.loaded.get(&id) {
+                    return Ok(*loaded);
+                }
+                let (mut attributes, secret) = self.keychain_get(id)?;
+                attributes.persistence = SecretPersistenceType::Ephemeral;
+                let loaded = self.ephemeral_vault.secret_import(&secret, attributes)?;
+                self.loaded.insert(id, loaded);
+                Ok(loaded)
+            }
+            (_, OsxContext::Enclave) => Err(VaultFailError::from_msg(
+                error,
+                "not possible with a key in the Secure Enclave",
+            )),
+        }
+    }
+
+    fn enclave_generate(&mut self) -> Result<SecretKeyContext, VaultFailError> {
+        let id = rand::rngs::OsRng {}.gen::<usize>();
+        let label = enclave_label(id);
+        let access_control = enclave::AccessControl::create_with_flags(
+            enclave::AttrAccessible::WhenUnlockedThisDeviceOnly,
+            Default::default(),
+        )?;
+        let params =
+            enclave::KeyPairGenerateParams::new(enclave::AttrKeyType::EcSecPrimeRandom, 256)
+                .access_control(&access_control)
+                .token_id(enclave::AttrTokenId::SecureEnclave)
+                .label(label.as_str())
+                .permanent(true);
+        enclave::KeyPair::generate(params)?;
+        Ok(SecretKeyContext::KeyRing {
+            id,
+            os_type: OsKeyRing::Osx(OsxContext::Enclave),
+        })
+    }
+}
+
+// The label of the Secure Enclave key `id` in the keychain
+fn enclave_label(id: usize) -> String {
+    format!("{}-{}", OCKAM_SERVICE_NAME, id)
+}
+
+fn enclave_key(id: usize) -> Result<enclave::key::Key, VaultFailError> {
+    let label = enclave_label(id);
+    let query = enclave::item::Query::new().label(label.as_str());
+    Ok(enclave::key::Key::find(query)?)
+}
+
+fn osx_context(context: SecretKeyContext) -> Result<(usize, OsxContext), VaultFailError> {
+    match context {
+        SecretKeyContext::KeyRing {
+            id,
+            os_type: OsKeyRing::Osx(ctx),
+        } => Ok((id, ctx)),
+        _ => Err(VaultFailErrorKind::InvalidContext.into()),
+    }
+}
+
+// The context handed out for the in memory secret `context`
+fn memory_context(context: SecretKeyContext) -> SecretKeyContext {
+    if let SecretKeyContext::Memory(id) = context {
+        SecretKeyContext::KeyRing {
+            id,
+            os_type: OsKeyRing::Osx(OsxContext::Memory),
+        }
+    } else {
+        context
+    }
+}
+
+// The 64 byte r || s of the DER encoded ECDSA signature `der`
+fn signature_from_der(der: &[u8]) -> Result<[u8; 64], VaultFailError> {
+    fn integer(der: &[u8]) -> Option<(&[u8], &[u8])> {
+        match der {
+            [0x02, len, rest @ ..] if *len as usize <= rest.len() => {
+                Some(rest.split_at(*len as usize))
+            }
+            _ => None,
+        }
+    }
+    let invalid = || {
+        VaultFailError::from_msg(
+            VaultFailErrorKind::Ecdh,
+            "invalid signature from the Secure Enclave",
+        )
+    };
+    let body = match der {
+        [0x30, len, rest @ ..] if *len as usize == rest.len() => rest,
+        _ => return Err(invalid()),
+    };
+    let (r, rest) = integer(body).ok_or_else(invalid)?;
+    let (s, rest) = integer(rest).ok_or_else(invalid)?;
+    if !rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut signature = [0u8; 64];
+    for (half, int) in signature.chunks_mut(32).zip(&[r, s]) {
+        let start = int.iter().position(|b| *b != 0).unwrap_or(int.len());
+        let int = &int[start..];
+        if int.len() > 32 {
+            return Err(invalid());
+        }
+        half[32 - int.len()..].copy_from_slice(int);
+    }
+    Ok(signature)
 }
 
 impl std::fmt::Debug for OsxVault {
@@ -48,6 +212,7 @@ impl Default for OsxVault {
         Self {
             ephemeral_vault: DefaultVault::default(),
             keychain: keychain::SecKeychain::default().unwrap(),
+            loaded: BTreeMap::new(),
         }
     }
 }
@@ -55,6 +220,7 @@ impl Default for OsxVault {
 impl Zeroize for OsxVault {
     fn zeroize(&mut self) {
         self.ephemeral_vault.zeroize();
+        self.loaded.clear();
     }
 }
 
@@ -73,66 +239,21 @@ impl Vault for OsxVault {
         &mut self,
         attributes: SecretKeyAttributes,
     ) -> Result<SecretKeyContext, VaultFailError> {
-        let mut swkey_insert = |atts: SecretKeyAttributes,
-                                buffer: &[u8]|
-         -> Result<SecretKeyContext, VaultFailError> {
-            let mut r = rand::rngs::OsRng {};
-            let id = r.gen::<usize>();
-            let mut bytes = atts.to_bytes().to_vec();
-            bytes.extend_from_slice(buffer);
-            self.unlock()?;
-            self.keychain.set_generic_password(
-                OCKAM_SERVICE_NAME,
-                id.to_string().as_str(),
-                bytes.as_slice(),
-            )?;
-
-            Ok(SecretKeyContext::KeyRing {
-                id,
-                os_type: OsKeyRing::Osx(OsxContext::Keychain),
-            })
-        };
-        let mut rng = rand::rngs::OsRng {};
-        match attributes.persistence {
-            SecretPersistenceType::Ephemeral => {
-                self.ephemeral_vault.secret_generate(attributes).map(|c| {
-                    if let SecretKeyContext::Memory(id) = c {
-                        SecretKeyContext::KeyRing {
-                            id,
-                            os_type: OsKeyRing::Osx(OsxContext::Memory),
-                        }
-                    } else {
-                        c
-                    }
-                })
+        match (attributes.persistence, attributes.xtype) {
+            (SecretPersistenceType::Ephemeral, _) => self
+                .ephemeral_vault
+                .secret_generate(attributes)
+                .map(memory_context),
+            (SecretPersistenceType::Persistent, SecretKeyType::P256) => self.enclave_generate(),
+            (SecretPersistenceType::Persistent, _) => {
+                let mut generated = attributes;
+                generated.persistence = SecretPersistenceType::Ephemeral;
+                let context = self.ephemeral_vault.secret_generate(generated)?;
+                let secret = self.ephemeral_vault.secret_export(context);
+                self.ephemeral_vault.secret_destroy(context)?;
+                let secret = secret?;
+                self.keychain_insert(attributes, secret.as_ref())
             }
-            SecretPersistenceType::Persistent => match attributes.xtype {
-                SecretKeyType::Curve25519 => {
-                    let sk = x25519_dalek::StaticSecret::new(&mut rng);
-                    swkey_insert(attributes, sk.to_bytes().as_ref())
-                }
-                SecretKeyType::P256 => {
-                    // SEP doesn't support ECDH directly
-                    // only for ECIES, for now just use the keychain
-                    let key = p256::SecretKey::generate();
-                    swkey_insert(attributes, key.secret_scalar().as_ref())
-                }
-                SecretKeyType::Aes256 => {
-                    let mut key = [0u8; 32];
-                    rng.fill_bytes(&mut key);
-                    swkey_insert(attributes, key.as_ref())
-                }
-                SecretKeyType::Aes128 => {
-                    let mut key = [0u8; 16];
-                    rng.fill_bytes(&mut key);
-                    swkey_insert(attributes, key.as_ref())
-                }
-                SecretKeyType::Buffer(size) => {
-                    let mut key = vec![0u8; size];
-                    rng.fill_bytes(key.as_mut_slice());
-                    swkey_insert(attributes, key.as_slice())
-                }
-            },
         }
     }
 
@@ -141,77 +262,23 @@ impl Vault for OsxVault {
         secret: &SecretKey,
         attributes: SecretKeyAttributes,
     ) -> Result<SecretKeyContext, VaultFailError> {
-        let mut swkey_insert = |atts: SecretKeyAttributes,
-                                buffer: &[u8]|
-         -> Result<SecretKeyContext, VaultFailError> {
-            let mut r = rand::rngs::OsRng {};
-            let id = r.gen::<usize>();
-            let mut bytes = atts.to_bytes().to_vec();
-            bytes.extend_from_slice(buffer);
-            self.unlock()?;
-            self.keychain.set_generic_password(
-                OCKAM_SERVICE_NAME,
-                id.to_string().as_str(),
-                bytes.as_slice(),
-            )?;
-
-            Ok(SecretKeyContext::KeyRing {
-                id,
-                os_type: OsKeyRing::Osx(OsxContext::Keychain),
-            })
-        };
         match attributes.persistence {
             SecretPersistenceType::Ephemeral => self
                 .ephemeral_vault
                 .secret_import(secret, attributes)
-                .map(|c| {
-                    if let SecretKeyContext::Memory(id) = c {
-                        SecretKeyContext::KeyRing {
-                            id,
-                            os_type: OsKeyRing::Osx(OsxContext::Memory),
-                        }
-                    } else {
-                        c
-                    }
-                }),
-            SecretPersistenceType::Persistent => swkey_insert(attributes, secret.as_ref()),
+                .map(memory_context),
+            // Keys can't be imported into the SEP, so imported P-256 keys go in the keychain
+            SecretPersistenceType::Persistent => self.keychain_insert(attributes, secret.as_ref()),
         }
     }
 
     fn secret_export(&mut self, context: SecretKeyContext) -> Result<SecretKey, VaultFailError> {
-        if let SecretKeyContext::KeyRing { id, os_type } = context {
-            if let OsKeyRing::Osx(ctx) = os_type {
-                return match ctx {
-                    OsxContext::Memory => {
-                        let cid = SecretKeyContext::Memory(id);
-                        self.ephemeral_vault.secret_export(cid)
-                    }
-                    OsxContext::Keychain => {
-                        self.unlock()?;
-                        let (key, _) = self
-                            .keychain
-                            .find_generic_password(OCKAM_SERVICE_NAME, id.to_string().as_str())?;
-                        let bytes = key.to_owned();
-                        let mut atts = [0u8; 6];
-                        atts.copy_from_slice(&bytes[0..6]);
-                        let attributes = SecretKeyAttributes::try_from(atts)?;
-                        Ok(match attributes.xtype {
-                            SecretKeyType::Buffer(_) => SecretKey::Buffer(bytes[6..].to_vec()),
-                            SecretKeyType::P256 => SecretKey::P256(*array_ref![bytes, 6, 32]),
-                            SecretKeyType::Curve25519 => {
-                                SecretKey::Curve25519(*array_ref![bytes, 6, 32])
-                            }
-                            SecretKeyType::Aes128 => SecretKey::Aes128(*array_ref![bytes, 6, 16]),
-                            SecretKeyType::Aes256 => SecretKey::Aes256(*array_ref![bytes, 6, 32]),
-                        })
-                    }
-                    OsxContext::Enclave => Err(VaultFailErrorKind::AccessDenied.into()),
-                };
-            } else {
-                Err(VaultFailErrorKind::InvalidContext.into())
-            }
-        } else {
-            Err(VaultFailErrorKind::InvalidContext.into())
+        match osx_context(context)? {
+            (id, OsxContext::Memory) => self
+                .ephemeral_vault
+                .secret_export(SecretKeyContext::Memory(id)),
+            (id, OsxContext::Keychain) => Ok(self.keychain_get(id)?.1),
+            (_, OsxContext::Enclave) => Err(VaultFailErrorKind::AccessDenied.into()),
         }
     }
 
@@ -219,35 +286,16 @@ impl Vault for OsxVault {
         &mut self,
         context: SecretKeyContext,
     ) -> Result<SecretKeyAttributes, VaultFailError> {
-        if let SecretKeyContext::KeyRing { id, os_type } = context {
-            if let OsKeyRing::Osx(ctx) = os_type {
-                return match ctx {
-                    OsxContext::Memory => {
-                        let cid = SecretKeyContext::Memory(id);
-                        self.ephemeral_vault.secret_attributes_get(cid)
-                    }
-                    OsxContext::Enclave => Ok(SecretKeyAttributes {
-                        xtype: SecretKeyType::P256,
-                        persistence: SecretPersistenceType::Persistent,
-                        purpose: SecretPurposeType::KeyAgreement,
-                    }),
-                    OsxContext::Keychain => {
-                        self.unlock()?;
-                        let (key, _) = self
-                            .keychain
-                            .find_generic_password(OCKAM_SERVICE_NAME, id.to_string().as_str())?;
-                        let bytes = key.to_owned();
-                        let mut atts = [0u8; 6];
-                        atts.copy_from_slice(&bytes[..6]);
-                        let attributes = SecretKeyAttributes::try_from(atts)?;
-                        Ok(attributes)
-                    }
-                };
-            } else {
-                Err(VaultFailErrorKind::InvalidContext.into())
-            }
-        } else {
-            Err(VaultFailErrorKind::InvalidContext.into())
+        match osx_context(context)? {
+            (id, OsxContext::Memory) => self
+                .ephemeral_vault
+                .secret_attributes_get(SecretKeyContext::Memory(id)),
+            (id, OsxContext::Keychain) => Ok(self.keychain_get(id)?.0),
+            (_, OsxContext::Enclave) => Ok(SecretKeyAttributes {
+                xtype: SecretKeyType::P256,
+                persistence: SecretPersistenceType::Persistent,
+                purpose: SecretPurposeType::KeyAgreement,
+            }),
         }
     }
 
@@ -255,79 +303,38 @@ impl Vault for OsxVault {
         &mut self,
         context: SecretKeyContext,
     ) -> Result<PublicKey, VaultFailError> {
-        if let SecretKeyContext::KeyRing { id, os_type } = context {
-            if let OsKeyRing::Osx(ctx) = os_type {
-                match ctx {
-                    OsxContext::Memory => {
-                        let cid = SecretKeyContext::Memory(id);
-                        self.ephemeral_vault.secret_public_key_get(cid)
-                    }
-                    OsxContext::Keychain => {
-                        self.unlock()?;
-                        let (key, _) = self
-                            .keychain
-                            .find_generic_password(OCKAM_SERVICE_NAME, id.to_string().as_str())?;
-                        let bytes = key.to_owned();
-                        let mut atts = [0u8; 6];
-                        atts.copy_from_slice(&bytes[..6]);
-                        let attributes = SecretKeyAttributes::try_from(atts)?;
-                        let key = *array_ref![bytes, 6, 32];
-                        match attributes.xtype {
-                            SecretKeyType::Curve25519 => {
-                                let sk = x25519_dalek::StaticSecret::from(key);
-                                let pk = x25519_dalek::PublicKey::from(&sk);
-                                Ok(PublicKey::Curve25519(*pk.as_bytes()))
-                            }
-                            SecretKeyType::P256 => {
-                                let sk = Scalar::from_bytes(key).unwrap();
-                                let pp = ProjectivePoint::generator() * &sk;
-                                let pk = p256::elliptic_curve::weierstrass::PublicKey::from(
-                                    pp.to_affine().unwrap().to_uncompressed_pubkey(),
-                                );
-                                Ok(PublicKey::P256(*array_ref![pk.as_bytes(), 0, 65]))
-                            }
-                            _ => Err(VaultFailErrorKind::PublicKey.into()),
-                        }
-                    }
-                    _ => Err(VaultFailErrorKind::PublicKey.into()),
-                }
-            } else {
-                Err(VaultFailErrorKind::InvalidContext.into())
-            }
-        } else {
-            Err(VaultFailErrorKind::InvalidContext.into())
+        if let (id, OsxContext::Enclave) = osx_context(context)? {
+            // The X9.63 uncompressed point, as for a P-256 key in memory
+            let public_key = enclave_key(id)?.public_key()?;
+            return match public_key.to_external_representation() {
+                Some(k) if k.len() == 65 => Ok(PublicKey::P256(*array_ref![k, 0, 65])),
+                _ => Err(VaultFailErrorKind::PublicKey.into()),
+            };
         }
+        let context = self.memory(context, VaultFailErrorKind::PublicKey)?;
+        self.ephemeral_vault.secret_public_key_get(context)
     }
 
     fn secret_destroy(&mut self, context: SecretKeyContext) -> Result<(), VaultFailError> {
-        if let SecretKeyContext::KeyRing { id, os_type } = context {
-            if let OsKeyRing::Osx(ctx) = os_type {
-                match ctx {
-                    OsxContext::Memory => {
-                        let memctx = SecretKeyContext::Memory(id);
-                        self.ephemeral_vault.secret_destroy(memctx)
-                    }
-                    OsxContext::Keychain => {
-                        self.unlock()?;
-                        let (_, item) = self
-                            .keychain
-                            .find_generic_password(OCKAM_SERVICE_NAME, id.to_string().as_str())?;
-                        item.delete();
-                        Ok(())
-                    }
-                    OsxContext::Enclave => {
-                        let label = format!("{}-{}", OCKAM_SERVICE_NAME, id);
-                        let query = enclave::item::Query::new().label(label.as_str());
-                        let key = enclave::key::Key::find(query)?;
-                        key.delete()?;
-                        Ok(())
-                    }
+        match osx_context(context)? {
+            (id, OsxContext::Memory) => self
+                .ephemeral_vault
+                .secret_destroy(SecretKeyContext::Memory(id)),
+            (id, OsxContext::Keychain) => {
+                if let Some(loaded) = self.loaded.remove(&id) {
+                    self.ephemeral_vault.secret_destroy(loaded)?;
                 }
-            } else {
-                Err(VaultFailErrorKind::InvalidParam(0).into())
+                self.unlock()?;
+                let (_, item) = self
+                    .keychain
+                    .find_generic_password(OCKAM_SERVICE_NAME, id.to_string().as_str())?;
+                item.delete();
+                Ok(())
+            }
+            (id, OsxContext::Enclave) => {
+                enclave_key(id)?.delete()?;
+                Ok(())
             }
-        } else {
-            Err(VaultFailErrorKind::InvalidParam(0).into())
         }
     }
 
@@ -336,126 +343,128 @@ impl Vault for OsxVault {
         context: SecretKeyContext,
         peer_public_key: PublicKey,
     ) -> Result<SecretKeyContext, VaultFailError> {
-        if let SecretKeyContext::KeyRing { id, os_type } = context {
-            if let OsKeyRing::Osx(ctx) = os_type {
-                match ctx {
-                    OsxContext::Memory => {
-                        let cid = SecretKeyContext::Memory(id);
-                        self.ephemeral_vault.ec_diffie_hellman(cid, peer_public_key)
-                    }
-                    OsxContext::Keychain => {
-                        self.unlock()?;
-                        let (key, _) = self
-                            .keychain
-                            .find_generic_password(OCKAM_SERVICE_NAME, id.to_string().as_str())?;
-                        let bytes = key.to_owned();
-                        let mut atts = [0u8; 6];
-                        atts.copy_from_slice(&bytes[..6]);
-                        let attributes = SecretKeyAttributes::try_from(atts)?;
-                        let key = *array_ref![bytes, 6, 32];
-                        match (attributes.xtype, peer_public_key) {
-                            (SecretKeyType::Curve25519, PublicKey::Curve25519(b)) => {
-                                let sk = x25519_dalek::StaticSecret::from(key);
-                                let pk_t = x25519_dalek::PublicKey::from(b);
-                                let secret = sk.diffie_hellman(&pk_t);
-                                let buffer = SecretKey::Buffer(secret.as_bytes().to_vec());
-                                let attributes = SecretKeyAttributes {
-                                    xtype: SecretKeyType::Buffer(32),
-                                    purpose: SecretPurposeType::KeyAgreement,
-                                    persistence: SecretPersistenceType::Ephemeral,
-                                };
-                                self.ephemeral_vault.secret_import(&buffer, attributes)
-                            }
-                            (SecretKeyType::P256, PublicKey::P256(b)) => {
-                                let o_pk_t: Option<
-                                    p256::elliptic_curve::weierstrass::PublicKey<p256::NistP256>,
-                                > = p256::elliptic_curve::weierstrass::PublicKey::from_bytes(
-                                    b.as_ref(),
-                                );
-                                if o_pk_t.is_none() {
-                                    fail!(VaultFailErrorKind::Ecdh);
-                                }
-                                let pk_t = o_pk_t.unwrap();
-                                let o_p_t = AffinePoint::from_pubkey(&pk_t);
-                                if o_p_t.is_none().unwrap_u8() == 1 {
-                                    fail!(VaultFailErrorKind::Ecdh);
-                                }
-                                let sk = Scalar::from_bytes(key).unwrap();
-                                let pk_t = ProjectivePoint::from(o_p_t.unwrap());
-                                let secret = &pk_t * &sk;
-                                if secret.ct_eq(&ProjectivePoint::identity()).unwrap_u8() == 1 {
-                                    fail!(VaultFailErrorKind::Ecdh);
-                                }
-                                let result = secret.to_affine().unwrap().to_compressed_pubkey();
-                                // Throw away the compressed indicator byte
-                                let buffer = SecretKey::Buffer(result.as_ref()[1..].to_vec());
-                                let attributes = SecretKeyAttributes {
-                                    xtype: SecretKeyType::Buffer(32),
-                                    purpose: SecretPurposeType::KeyAgreement,
-                                    persistence: SecretPersistenceType::Ephemeral,
-                                };
-                                self.ephemeral_vault.secret_import(&buffer, attributes)
-                            }
-                            _ => Err(VaultFailErrorKind::PublicKey.into()),
-                        }
-                    }
-                    _ => Err(VaultFailErrorKind::PublicKey.into()),
-                }
-            } else {
-                Err(VaultFailErrorKind::InvalidContext.into())
-            }
-        } else {
-            Err(VaultFailErrorKind::InvalidContext.into())
-        }
+        let context = self.memory(context, VaultFailErrorKind::Ecdh)?;
+        self.ephemeral_vault
+            .ec_diffie_hellman(context, peer_public_key)
+            .map(memory_context)
     }
 
-    fn ec_diffie_hellman_hkdf_sha256<B: AsRef<[u8]>>(
+    fn ec_diffie_hellman_hkdf_sha256(
         &mut self,
         context: SecretKeyContext,
         peer_public_key: PublicKey,
-        salt: B,
-        okm_len: usize,
-    ) -> Result<Vec<u8>, VaultFailError> {
-        let ctx = self.ec_diffie_hellman(context, peer_public_key)?;
-        let shared_secret = self.secret_export(ctx)?;
-        let mut okm = vec![0u8; okm_len];
-        let prk = hkdf::Hkdf::<Sha256>::new(Some(salt.as_ref()), &shared_secret.as_ref());
-        prk.expand(b"", okm.as_mut_slice())?;
-        Ok(okm)
+        salt: SecretKeyContext,
+        info: &[u8],
+        output_attributes: Vec<SecretKeyAttributes>,
+    ) -> Result<Vec<SecretKeyContext>, VaultFailError> {
+        let context = self.memory(context, VaultFailErrorKind::Ecdh)?;
+        let salt = self.memory(salt, VaultFailErrorKind::HkdfSha256)?;
+        let outputs = self.ephemeral_vault.ec_diffie_hellman_hkdf_sha256(
+            context,
+            peer_public_key,
+            salt,
+            info,
+            output_attributes,
+        )?;
+        Ok(outputs.into_iter().map(memory_context).collect())
     }
 
-    fn hkdf_sha256<B: AsRef<[u8]>, C: AsRef<[u8]>>(
+    fn hkdf_sha256(
         &mut self,
-        salt: B,
-        ikm: C,
-        okm_len: usize,
-    ) -> Result<Vec<u8>, VaultFailError> {
-        self.ephemeral_vault.hkdf_sha256(salt, ikm, okm_len)
+        salt: SecretKeyContext,
+        info: &[u8],
+        ikm: Option<SecretKeyContext>,
+        output_attributes: Vec<SecretKeyAttributes>,
+    ) -> Result<Vec<SecretKeyContext>, VaultFailError> {
+        let salt = self.memory(salt, VaultFailErrorKind::HkdfSha256)?;
+        let ikm = match ikm {
+            Some(ikm) => Some(self.memory(ikm, VaultFailErrorKind::HkdfSha256)?),
+            None => None,
+        };
+        let outputs = self
+            .ephemeral_vault
+            .hkdf_sha256(salt, info, ikm, output_attributes)?;
+        Ok(outputs.into_iter().map(memory_context).collect())
     }
 
     fn aead_aes_gcm_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
         &mut self,
-        _context: SecretKeyContext,
-        _plaintext: B,
-        _nonce: C,
-        _aad: D,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
     ) -> Result<Vec<u8>, VaultFailError> {
-        unimplemented!()
+        let context = self.memory(context, VaultFailErrorKind::AeadAesGcmEncrypt)?;
+        self.ephemeral_vault
+            .aead_aes_gcm_encrypt(context, plaintext, nonce, aad)
     }
 
     fn aead_aes_gcm_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
         &mut self,
-        _context: SecretKeyContext,
-        _cipher_text: B,
-        _nonce: C,
-        _aad: D,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
     ) -> Result<Vec<u8>, VaultFailError> {
-        unimplemented!()
+        let context = self.memory(context, VaultFailErrorKind::AeadAesGcmDecrypt)?;
+        self.ephemeral_vault
+            .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let context = self.memory(context, VaultFailErrorKind::AeadChaChaPolyEncrypt)?;
+        self.ephemeral_vault
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let context = self.memory(context, VaultFailErrorKind::AeadChaChaPolyDecrypt)?;
+        self.ephemeral_vault
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
     }
 
     fn deinit(&mut self) {
         self.zeroize();
     }
+
+    /// Sign with ECDSA over SHA-256 in the Secure Enclave for a key there,
+    /// as the software vault does otherwise
+    fn sign<B: AsRef<[u8]>>(
+        &mut self,
+        secret_key: SecretKeyContext,
+        data: B,
+    ) -> Result<[u8; 64], VaultFailError> {
+        if let (id, OsxContext::Enclave) = osx_context(secret_key)? {
+            let signature = enclave_key(id)?.sign(
+                enclave::KeyAlgorithm::ECDSASignatureMessageX962SHA256,
+                data.as_ref(),
+            )?;
+            return signature_from_der(signature.as_bytes());
+        }
+        let context = self.memory(secret_key, VaultFailErrorKind::Ecdh)?;
+        self.ephemeral_vault.sign(context, data)
+    }
+
+    fn verify<B: AsRef<[u8]>>(
+        &mut self,
+        signature: [u8; 64],
+        public_key: PublicKey,
+        data: B,
+    ) -> Result<(), VaultFailError> {
+        self.ephemeral_vault.verify(signature, public_key, data)
+    }
 }
 
 #[cfg(test)]
@@ -494,6 +503,64 @@ mod tests {
         }
     }
 
+    #[test]
+    fn keychain_secrets() {
+        let mut vault = OsxVault::default();
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Curve25519,
+            persistence: SecretPersistenceType::Persistent,
+            purpose: SecretPurposeType::KeyAgreement,
+        };
+        let static_key = vault.secret_generate(attributes).unwrap();
+        let public_key = vault.secret_public_key_get(static_key).unwrap();
+        let signature = vault.sign(static_key, b"hello world!").unwrap();
+        assert!(vault.verify(signature, public_key, b"hello world!").is_ok());
+
+        let key = vault
+            .secret_import(
+                &SecretKey::Buffer(vec![7u8; 32]),
+                SecretKeyAttributes {
+                    xtype: SecretKeyType::Buffer(32),
+                    persistence: SecretPersistenceType::Persistent,
+                    purpose: SecretPurposeType::KeyAgreement,
+                },
+            )
+            .unwrap();
+        let nonce = [0u8; 12];
+        let cipher_text = vault
+            .aead_chacha20_poly1305_encrypt(key, b"hello world!", &nonce, b"")
+            .unwrap();
+        // a fresh vault loads the key from the keychain
+        let mut vault = OsxVault::default();
+        let plaintext = vault
+            .aead_chacha20_poly1305_decrypt(key, &cipher_text, &nonce, b"")
+            .unwrap();
+        assert_eq!(plaintext, b"hello world!");
+        assert!(vault.secret_destroy(key).is_ok());
+        assert!(vault.secret_destroy(static_key).is_ok());
+    }
+
+    #[test]
+    fn der_signatures() {
+        let mut der = vec![0x30, 0x45, 0x02, 0x21, 0x00];
+        der.extend_from_slice(&[0x80; 32]);
+        der.extend_from_slice(&[0x02, 0x20]);
+        der.extend_from_slice(&[0x01; 32]);
+        let signature = signature_from_der(&der).unwrap();
+        assert_eq!(&signature[..32], &[0x80; 32]);
+        assert_eq!(&signature[32..], &[0x01; 32]);
+
+        // short integers are padded
+        let signature =
+            signature_from_der(&[0x30, 0x06, 0x02, 0x01, 0x05, 0x02, 0x01, 0x06]).unwrap();
+        assert_eq!(signature[31], 5);
+        assert_eq!(signature[63], 6);
+        assert!(signature[..31].iter().all(|b| *b == 0));
+
+        assert!(signature_from_der(&der[..40]).is_err());
+        assert!(signature_from_der(&[0x30, 0x03, 0x02, 0x01, 0x05]).is_err());
+    }
+
     #[ignore]
     #[test]
     fn new_enclave_keys() {
@@ -506,6 +573,10 @@ mod tests {
         let res = vault.secret_generate(attributes);
         assert!(res.is_ok());
         let ctx = res.unwrap();
+        let public_key = vault.secret_public_key_get(ctx).unwrap();
+        assert!(matches!(public_key, PublicKey::P256(k) if k[0] == 4));
+        assert!(vault.sign(ctx, b"hello world!").is_ok());
+        assert!(vault.secret_export(ctx).is_err());
         let res = vault.secret_destroy(ctx);
         assert!(res.is_ok());
     }
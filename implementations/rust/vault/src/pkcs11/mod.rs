@@ -0,0 +1,890 @@
+use crate::error::{VaultFailError, VaultFailErrorKind};
+use crate::hardware::{self, HardwareSecrets, Held, P256_PUBLICKEY_LENGTH};
+use crate::types::{
+    PublicKey, SecretKey, SecretKeyAttributes, SecretKeyContext, SecretPersistenceType,
+};
+use crate::Vault;
+use std::convert::TryFrom;
+use std::mem::{size_of, size_of_val};
+use std::os::raw::{c_ulong, c_void};
+use std::path::PathBuf;
+use std::ptr;
+use zeroize::Zeroize;
+
+mod sys;
+use sys::*;
+
+// The DER encoded object identifier of the P-256 curve, prime256v1
+const P256_PARAMS: [u8; 10] = [0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const P256_PRIVATEKEY_LENGTH: usize = 32;
+const SHARED_SECRET_LENGTH: usize = 32;
+
+/// The PKCS#11 module of a provider, the token to use and the PIN to log into it with
+#[derive(Clone)]
+pub struct Pkcs11Config {
+    /// The path to the provider's PKCS#11 module, e.g. /usr/lib/softhsm/libsofthsm2.so
+    pub module: PathBuf,
+    /// The slot the token is in, the first slot with a token in it if `None`
+    pub slot: Option<u64>,
+    /// The token's user PIN
+    pub pin: String,
+}
+
+impl std::fmt::Debug for Pkcs11Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Pkcs11Config {{ module: {:?}, slot: {:?} }}",
+            self.module, self.slot
+        )
+    }
+}
+
+impl Drop for Pkcs11Config {
+    fn drop(&mut self) {
+        self.pin.zeroize();
+    }
+}
+
+// A key pair in the token
+#[derive(Clone, Copy)]
+struct TokenKey {
+    private_key: ObjectHandle,
+    public_key: ObjectHandle,
+}
+
+// A secret held by the vault: a key pair in the token, or a secret in memory
+type Pkcs11Secret = Held<TokenKey>;
+
+/// A Vault backed by a token reached through PKCS#11, e.g. SoftHSM, a YubiHSM or a cloud HSM.
+/// Persistent P-256 keys are generated in, or imported into, the token as sensitive objects which
+/// can't be extracted: the token computes ECDH and ECDSA signatures with them. Each is given the
+/// id of its context as its CKA_ID, so a vault opened later finds it again from the context.
+/// Ephemeral keys, and the secrets derived from the token's keys, stay in memory.
+pub struct Pkcs11Vault {
+    functions: *const FunctionList,
+    session: SessionHandle,
+    // whether this vault initialized the module, and so finalizes it
+    initialized: bool,
+    secrets: HardwareSecrets<TokenKey>,
+    // the function list is in the module, which stays loaded until the vault is dropped
+    _module: libloading::Library,
+}
+
+// The session is only ever used through `&mut self`
+unsafe impl Send for Pkcs11Vault {}
+unsafe impl Sync for Pkcs11Vault {}
+
+impl std::fmt::Debug for Pkcs11Vault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Pkcs11Vault {{ session: {}, ephemeral_vault: {:?}, secrets: {} }}",
+            self.session,
+            self.secrets.software,
+            self.secrets.count()
+        )
+    }
+}
+
+impl Pkcs11Vault {
+    /// Load the PKCS#11 module of `config`, and open a session with its token logged in as the
+    /// user
+    pub fn open(config: &Pkcs11Config) -> Result<Self, VaultFailError> {
+        let init_error = |e: libloading::Error| {
+            VaultFailError::from_msg(VaultFailErrorKind::Init, format!("{}", e))
+        };
+        let module = libloading::Library::new(&config.module).map_err(init_error)?;
+        let mut functions = ptr::null_mut();
+        unsafe {
+            let get_function_list = module
+                .get::<GetFunctionList>(b"C_GetFunctionList\0")
+                .map_err(init_error)?;
+            check(get_function_list(&mut functions), VaultFailErrorKind::Init)?;
+        }
+        if functions.is_null() {
+            fail!(VaultFailErrorKind::Init);
+        }
+        let f = unsafe { &*functions };
+
+        let rv = unsafe { (f.initialize)(ptr::null_mut()) };
+        let initialized = rv != CKR_CRYPTOKI_ALREADY_INITIALIZED;
+        if initialized {
+            check(rv, VaultFailErrorKind::Init)?;
+        }
+        let session = match login(f, config) {
+            Ok(session) => session,
+            Err(e) => {
+                if initialized {
+                    unsafe { (f.finalize)(ptr::null_mut()) };
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            functions,
+            session,
+            initialized,
+            secrets: HardwareSecrets::new("the PKCS#11 token"),
+            _module: module,
+        })
+    }
+
+    fn functions(&self) -> &FunctionList {
+        unsafe { &*self.functions }
+    }
+
+    // The secret `context`, looked for in the token if it isn't known yet
+    fn get(
+        &mut self,
+        context: SecretKeyContext,
+        error: VaultFailErrorKind,
+    ) -> Result<Pkcs11Secret, VaultFailError> {
+        if let Some(secret) = self.secrets.find(context)? {
+            return Ok(secret);
+        }
+        let id = match context {
+            SecretKeyContext::Memory(id) => id,
+            _ => return Err(VaultFailErrorKind::InvalidContext.into()),
+        };
+        let key_id = key_id(id);
+        let private_key = self.find(CKO_PRIVATE_KEY, &key_id)?;
+        let public_key = self.find(CKO_PUBLIC_KEY, &key_id)?;
+        match (private_key, public_key) {
+            (Some(private_key), Some(public_key)) => {
+                let key = TokenKey {
+                    private_key,
+                    public_key,
+                };
+                self.secrets.insert_at(id, key);
+                Ok(Pkcs11Secret::Hardware(key))
+            }
+            _ => Err(error.into()),
+        }
+    }
+
+    // The context of the in-memory secret `context`, found in the token first if it is a key
+    // there, failing with `error` for a key in the token
+    fn memory(
+        &mut self,
+        context: SecretKeyContext,
+        error: VaultFailErrorKind,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        self.get(context, error)?;
+        self.secrets.memory(context, error)
+    }
+
+    // An id for a new key in the token, random so that it doesn't collide with those of the keys
+    // other vaults put in it
+    fn token_id(&mut self) -> Result<usize, VaultFailError> {
+        loop {
+            let mut id = [0u8; size_of::<usize>()];
+            self.random(&mut id)?;
+            let id = usize::from_be_bytes(id);
+            if self.secrets.is_free(id)
+                && self
+                    .get(
+                        SecretKeyContext::Memory(id),
+                        VaultFailErrorKind::InvalidContext,
+                    )
+                    .is_err()
+            {
+                return Ok(id);
+            }
+        }
+    }
+
+    // The object of `class` in the token whose CKA_ID is `key_id`
+    fn find(&self, class: c_ulong, key_id: &[u8]) -> Result<Option<ObjectHandle>, VaultFailError> {
+        let f = self.functions();
+        let mut template = [attribute(CKA_CLASS, &class), attribute(CKA_ID, key_id)];
+        let mut object = 0;
+        let mut count = 0;
+        unsafe {
+            check(
+                (f.find_objects_init)(
+                    self.session,
+                    template.as_mut_ptr(),
+                    template.len() as c_ulong,
+                ),
+                VaultFailErrorKind::InvalidContext,
+            )?;
+            let rv = (f.find_objects)(self.session, &mut object, 1, &mut count);
+            let final_rv = (f.find_objects_final)(self.session);
+            check(rv, VaultFailErrorKind::InvalidContext)?;
+            check(final_rv, VaultFailErrorKind::InvalidContext)?;
+        }
+        Ok(if count == 1 { Some(object) } else { None })
+    }
+
+    // Read the attribute `kind` of `object` into `value`, returning its length
+    fn attribute_get(
+        &self,
+        object: ObjectHandle,
+        kind: c_ulong,
+        value: &mut [u8],
+        error: VaultFailErrorKind,
+    ) -> Result<usize, VaultFailError> {
+        let mut template = [Attribute {
+            kind,
+            value: value.as_mut_ptr().cast(),
+            value_len: value.len() as c_ulong,
+        }];
+        check(
+            unsafe {
+                (self.functions().get_attribute_value)(
+                    self.session,
+                    object,
+                    template.as_mut_ptr(),
+                    template.len() as c_ulong,
+                )
+            },
+            error,
+        )?;
+        let length = template[0].value_len as usize;
+        if length > value.len() {
+            fail!(error);
+        }
+        Ok(length)
+    }
+
+    // Create an EC public key object for `public_key`, kept in the token with `key_id` as its
+    // CKA_ID if there is one, for the session only otherwise
+    fn public_key_create(
+        &mut self,
+        public_key: &[u8; P256_PUBLICKEY_LENGTH],
+        key_id: Option<&[u8]>,
+        error: VaultFailErrorKind,
+    ) -> Result<ObjectHandle, VaultFailError> {
+        let point = ec_point_der(public_key);
+        let token = if key_id.is_some() { CK_TRUE } else { CK_FALSE };
+        let mut template = vec![
+            attribute(CKA_CLASS, &CKO_PUBLIC_KEY),
+            attribute(CKA_KEY_TYPE, &CKK_EC),
+            attribute(CKA_TOKEN, &token),
+            attribute(CKA_VERIFY, &CK_TRUE),
+            attribute(CKA_EC_PARAMS, &P256_PARAMS[..]),
+            attribute(CKA_EC_POINT, &point[..]),
+        ];
+        if let Some(key_id) = key_id {
+            template.push(attribute(CKA_ID, key_id));
+        }
+        let mut object = 0;
+        check(
+            unsafe {
+                (self.functions().create_object)(
+                    self.session,
+                    template.as_mut_ptr(),
+                    template.len() as c_ulong,
+                    &mut object,
+                )
+            },
+            error,
+        )?;
+        Ok(object)
+    }
+
+    // The shared secret of ECDH with the key `context`, in memory
+    fn shared_secret(
+        &mut self,
+        context: SecretKeyContext,
+        peer_public_key: PublicKey,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let private_key = match self.get(context, VaultFailErrorKind::Ecdh)? {
+            Pkcs11Secret::Memory(context) => {
+                return self
+                    .secrets
+                    .software
+                    .ec_diffie_hellman(context, peer_public_key)
+            }
+            Pkcs11Secret::Hardware(key) => key.private_key,
+        };
+        let mut peer_public_key = hardware::p256_public_key(peer_public_key)?;
+
+        let f = self.functions();
+        let mut params = Ecdh1DeriveParams {
+            kdf: CKD_NULL,
+            shared_data_len: 0,
+            shared_data: ptr::null_mut(),
+            public_data_len: peer_public_key.len() as c_ulong,
+            public_data: peer_public_key.as_mut_ptr(),
+        };
+        let parameter: *mut Ecdh1DeriveParams = &mut params;
+        let mut mechanism = Mechanism {
+            mechanism: CKM_ECDH1_DERIVE,
+            parameter: parameter as *mut c_void,
+            parameter_len: size_of::<Ecdh1DeriveParams>() as c_ulong,
+        };
+        let value_len = SHARED_SECRET_LENGTH as c_ulong;
+        // a session object, which goes as soon as its value has been read
+        let mut template = [
+            attribute(CKA_CLASS, &CKO_SECRET_KEY),
+            attribute(CKA_KEY_TYPE, &CKK_GENERIC_SECRET),
+            attribute(CKA_TOKEN, &CK_FALSE),
+            attribute(CKA_SENSITIVE, &CK_FALSE),
+            attribute(CKA_EXTRACTABLE, &CK_TRUE),
+            attribute(CKA_VALUE_LEN, &value_len),
+        ];
+        let mut derived = 0;
+        check(
+            unsafe {
+                (f.derive_key)(
+                    self.session,
+                    &mut mechanism,
+                    private_key,
+                    template.as_mut_ptr(),
+                    template.len() as c_ulong,
+                    &mut derived,
+                )
+            },
+            VaultFailErrorKind::Ecdh,
+        )?;
+        let mut dh = [0u8; SHARED_SECRET_LENGTH];
+        let length = self.attribute_get(derived, CKA_VALUE, &mut dh, VaultFailErrorKind::Ecdh);
+        unsafe { (f.destroy_object)(self.session, derived) };
+        if length? != SHARED_SECRET_LENGTH {
+            dh.zeroize();
+            fail!(VaultFailErrorKind::Ecdh);
+        }
+        self.secrets.shared_secret_import(&mut dh)
+    }
+}
+
+// Open a session with the token of `config` and log into it as the user
+fn login(f: &FunctionList, config: &Pkcs11Config) -> Result<SessionHandle, VaultFailError> {
+    let slot = match config.slot {
+        Some(slot) => SlotId::try_from(slot)
+            .map_err(|_| VaultFailError::from(VaultFailErrorKind::InvalidParam(0)))?,
+        None => {
+            let mut count = 0;
+            check(
+                unsafe { (f.get_slot_list)(CK_TRUE, ptr::null_mut(), &mut count) },
+                VaultFailErrorKind::Init,
+            )?;
+            let mut slots = vec![0; count as usize];
+            check(
+                unsafe { (f.get_slot_list)(CK_TRUE, slots.as_mut_ptr(), &mut count) },
+                VaultFailErrorKind::Init,
+            )?;
+            match slots.first() {
+                Some(slot) if count > 0 => *slot,
+                _ => {
+                    return Err(VaultFailError::from_msg(
+                        VaultFailErrorKind::Init,
+                        "no PKCS#11 token found",
+                    ))
+                }
+            }
+        }
+    };
+
+    let mut session = 0;
+    check(
+        unsafe {
+            (f.open_session)(
+                slot,
+                CKF_SERIAL_SESSION | CKF_RW_SESSION,
+                ptr::null_mut(),
+                None,
+                &mut session,
+            )
+        },
+        VaultFailErrorKind::Init,
+    )?;
+    let mut pin = config.pin.clone().into_bytes();
+    let rv = unsafe { (f.login)(session, CKU_USER, pin.as_mut_ptr(), pin.len() as c_ulong) };
+    pin.zeroize();
+    if rv != CKR_USER_ALREADY_LOGGED_IN {
+        if let Err(e) = check(rv, VaultFailErrorKind::AccessDenied) {
+            unsafe { (f.close_session)(session) };
+            return Err(e);
+        }
+    }
+    Ok(session)
+}
+
+fn check(rv: Rv, kind: VaultFailErrorKind) -> Result<(), VaultFailError> {
+    if rv == CKR_OK {
+        Ok(())
+    } else {
+        Err(VaultFailError::from_msg(
+            kind,
+            format!("PKCS#11 error {:#x}", rv),
+        ))
+    }
+}
+
+// The attribute `kind` whose value is `value`, which must outlive the attribute's use
+fn attribute<T: ?Sized>(kind: c_ulong, value: &T) -> Attribute {
+    let pointer: *const T = value;
+    Attribute {
+        kind,
+        value: pointer as *const c_void as *mut c_void,
+        value_len: size_of_val(value) as c_ulong,
+    }
+}
+
+// The CKA_ID of the key in the token whose context has the id `id`
+fn key_id(id: usize) -> [u8; size_of::<usize>()] {
+    id.to_be_bytes()
+}
+
+// A CKA_EC_POINT is the uncompressed point DER encoded as an OCTET STRING
+fn ec_point_der(point: &[u8; P256_PUBLICKEY_LENGTH]) -> Vec<u8> {
+    let mut der = vec![0x04, P256_PUBLICKEY_LENGTH as u8];
+    der.extend_from_slice(point);
+    der
+}
+
+// The uncompressed point of a CKA_EC_POINT, also taken as it is since some tokens don't encode it
+fn ec_point(value: &[u8]) -> Option<[u8; P256_PUBLICKEY_LENGTH]> {
+    match value {
+        [0x04, 0x41, point @ ..] if point.len() == P256_PUBLICKEY_LENGTH => {
+            Some(*array_ref![point, 0, P256_PUBLICKEY_LENGTH])
+        }
+        [0x04, ..] if value.len() == P256_PUBLICKEY_LENGTH => {
+            Some(*array_ref![value, 0, P256_PUBLICKEY_LENGTH])
+        }
+        _ => None,
+    }
+}
+
+impl Zeroize for Pkcs11Vault {
+    fn zeroize(&mut self) {
+        self.secrets.zeroize();
+    }
+}
+
+impl Drop for Pkcs11Vault {
+    fn drop(&mut self) {
+        self.zeroize();
+        let f = self.functions();
+        unsafe {
+            (f.logout)(self.session);
+            (f.close_session)(self.session);
+            if self.initialized {
+                (f.finalize)(ptr::null_mut());
+            }
+        }
+    }
+}
+
+impl Vault for Pkcs11Vault {
+    fn random(&mut self, data: &mut [u8]) -> Result<(), VaultFailError> {
+        check(
+            unsafe {
+                (self.functions().generate_random)(
+                    self.session,
+                    data.as_mut_ptr(),
+                    data.len() as c_ulong,
+                )
+            },
+            VaultFailErrorKind::Random,
+        )
+    }
+
+    fn sha256<B: AsRef<[u8]>>(&self, data: B) -> Result<[u8; 32], VaultFailError> {
+        self.secrets.software.sha256(data)
+    }
+
+    fn secret_generate(
+        &mut self,
+        attributes: SecretKeyAttributes,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        if !hardware::in_hardware(&attributes) {
+            return self.secrets.secret_generate(attributes);
+        }
+        let id = self.token_id()?;
+        let key_id = key_id(id);
+        let mut public_template = [
+            attribute(CKA_TOKEN, &CK_TRUE),
+            attribute(CKA_VERIFY, &CK_TRUE),
+            attribute(CKA_EC_PARAMS, &P256_PARAMS[..]),
+            attribute(CKA_ID, &key_id[..]),
+        ];
+        let mut private_template = [
+            attribute(CKA_TOKEN, &CK_TRUE),
+            attribute(CKA_PRIVATE, &CK_TRUE),
+            attribute(CKA_SENSITIVE, &CK_TRUE),
+            attribute(CKA_EXTRACTABLE, &CK_FALSE),
+            attribute(CKA_SIGN, &CK_TRUE),
+            attribute(CKA_DERIVE, &CK_TRUE),
+            attribute(CKA_ID, &key_id[..]),
+        ];
+        let mut mechanism = Mechanism {
+            mechanism: CKM_EC_KEY_PAIR_GEN,
+            parameter: ptr::null_mut(),
+            parameter_len: 0,
+        };
+        let mut public_key = 0;
+        let mut private_key = 0;
+        check(
+            unsafe {
+                (self.functions().generate_key_pair)(
+                    self.session,
+                    &mut mechanism,
+                    public_template.as_mut_ptr(),
+                    public_template.len() as c_ulong,
+                    private_template.as_mut_ptr(),
+                    private_template.len() as c_ulong,
+                    &mut public_key,
+                    &mut private_key,
+                )
+            },
+            VaultFailErrorKind::SecretGenerate,
+        )?;
+        Ok(self.secrets.insert_at(
+            id,
+            TokenKey {
+                private_key,
+                public_key,
+            },
+        ))
+    }
+
+    fn secret_import(
+        &mut self,
+        secret: &SecretKey,
+        attributes: SecretKeyAttributes,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        if !hardware::in_hardware(&attributes) {
+            return self.secrets.secret_import(secret, attributes);
+        }
+        let key = secret.as_ref();
+        if key.len() != P256_PRIVATEKEY_LENGTH {
+            return Err(VaultFailErrorKind::InvalidSecret.into());
+        }
+        // the token is given the public key too, so that it can be read back like a generated one
+        let mut ephemeral = attributes;
+        ephemeral.persistence = SecretPersistenceType::Ephemeral;
+        let software = &mut self.secrets.software;
+        let context = software.secret_import(secret, ephemeral)?;
+        let public_key = software.secret_public_key_get(context);
+        software.secret_destroy(context)?;
+        let public_key = match public_key? {
+            PublicKey::P256(k) => k,
+            PublicKey::Curve25519(_) => return Err(VaultFailErrorKind::InvalidSecret.into()),
+        };
+
+        let id = self.token_id()?;
+        let key_id = key_id(id);
+        let mut template = [
+            attribute(CKA_CLASS, &CKO_PRIVATE_KEY),
+            attribute(CKA_KEY_TYPE, &CKK_EC),
+            attribute(CKA_TOKEN, &CK_TRUE),
+            attribute(CKA_PRIVATE, &CK_TRUE),
+            attribute(CKA_SENSITIVE, &CK_TRUE),
+            attribute(CKA_EXTRACTABLE, &CK_FALSE),
+            attribute(CKA_SIGN, &CK_TRUE),
+            attribute(CKA_DERIVE, &CK_TRUE),
+            attribute(CKA_EC_PARAMS, &P256_PARAMS[..]),
+            attribute(CKA_VALUE, key),
+            attribute(CKA_ID, &key_id[..]),
+        ];
+        let mut private_key = 0;
+        check(
+            unsafe {
+                (self.functions().create_object)(
+                    self.session,
+                    template.as_mut_ptr(),
+                    template.len() as c_ulong,
+                    &mut private_key,
+                )
+            },
+            VaultFailErrorKind::Import,
+        )?;
+        let public_key =
+            match self.public_key_create(&public_key, Some(&key_id), VaultFailErrorKind::Import) {
+                Ok(public_key) => public_key,
+                Err(e) => {
+                    unsafe { (self.functions().destroy_object)(self.session, private_key) };
+                    return Err(e);
+                }
+            };
+        Ok(self.secrets.insert_at(
+            id,
+            TokenKey {
+                private_key,
+                public_key,
+            },
+        ))
+    }
+
+    fn secret_export(&mut self, context: SecretKeyContext) -> Result<SecretKey, VaultFailError> {
+        self.secrets.secret_export(context)
+    }
+
+    fn secret_attributes_get(
+        &mut self,
+        context: SecretKeyContext,
+    ) -> Result<SecretKeyAttributes, VaultFailError> {
+        match self.get(context, VaultFailErrorKind::GetAttributes)? {
+            Pkcs11Secret::Hardware(_) => Ok(hardware::hardware_attributes()),
+            Pkcs11Secret::Memory(context) => self.secrets.software.secret_attributes_get(context),
+        }
+    }
+
+    fn secret_public_key_get(
+        &mut self,
+        context: SecretKeyContext,
+    ) -> Result<PublicKey, VaultFailError> {
+        match self.get(context, VaultFailErrorKind::PublicKey)? {
+            Pkcs11Secret::Hardware(key) => {
+                let mut value = [0u8; P256_PUBLICKEY_LENGTH + 2];
+                let length = self.attribute_get(
+                    key.public_key,
+                    CKA_EC_POINT,
+                    &mut value,
+                    VaultFailErrorKind::PublicKey,
+                )?;
+                ec_point(&value[..length])
+                    .map(PublicKey::P256)
+                    .ok_or_else(|| VaultFailErrorKind::PublicKey.into())
+            }
+            Pkcs11Secret::Memory(context) => self.secrets.software.secret_public_key_get(context),
+        }
+    }
+
+    fn secret_destroy(&mut self, context: SecretKeyContext) -> Result<(), VaultFailError> {
+        match self.get(context, VaultFailErrorKind::InvalidContext)? {
+            Pkcs11Secret::Hardware(key) => {
+                let f = self.functions();
+                let rv = unsafe { (f.destroy_object)(self.session, key.private_key) };
+                check(rv, VaultFailErrorKind::InvalidContext)?;
+                let rv = unsafe { (f.destroy_object)(self.session, key.public_key) };
+                check(rv, VaultFailErrorKind::InvalidContext)?;
+            }
+            Pkcs11Secret::Memory(context) => self.secrets.software.secret_destroy(context)?,
+        }
+        self.secrets.remove(context);
+        Ok(())
+    }
+
+    fn ec_diffie_hellman(
+        &mut self,
+        context: SecretKeyContext,
+        peer_public_key: PublicKey,
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let shared_secret = self.shared_secret(context, peer_public_key)?;
+        Ok(self.secrets.ec_diffie_hellman(shared_secret))
+    }
+
+    fn ec_diffie_hellman_hkdf_sha256(
+        &mut self,
+        context: SecretKeyContext,
+        peer_public_key: PublicKey,
+        salt: SecretKeyContext,
+        info: &[u8],
+        output_attributes: Vec<SecretKeyAttributes>,
+    ) -> Result<Vec<SecretKeyContext>, VaultFailError> {
+        let salt = self.memory(salt, VaultFailErrorKind::HkdfSha256)?;
+        let shared_secret = self.shared_secret(context, peer_public_key)?;
+        self.secrets
+            .ec_diffie_hellman_hkdf_sha256(salt, info, shared_secret, output_attributes)
+    }
+
+    fn hkdf_sha256(
+        &mut self,
+        salt: SecretKeyContext,
+        info: &[u8],
+        ikm: Option<SecretKeyContext>,
+        output_attributes: Vec<SecretKeyAttributes>,
+    ) -> Result<Vec<SecretKeyContext>, VaultFailError> {
+        self.secrets.hkdf_sha256(salt, info, ikm, output_attributes)
+    }
+
+    fn aead_aes_gcm_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.secrets
+            .aead_aes_gcm_encrypt(context, plaintext, nonce, aad)
+    }
+
+    fn aead_aes_gcm_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.secrets
+            .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.secrets
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.secrets
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+    }
+
+    fn deinit(&mut self) {
+        self.zeroize();
+    }
+
+    /// Sign the SHA-256 digest of `data` with ECDSA, for a P-256 key in the token
+    fn sign<B: AsRef<[u8]>>(
+        &mut self,
+        secret_key: SecretKeyContext,
+        data: B,
+    ) -> Result<[u8; 64], VaultFailError> {
+        let private_key = match self.get(secret_key, VaultFailErrorKind::Sign)? {
+            Pkcs11Secret::Hardware(key) => key.private_key,
+            Pkcs11Secret::Memory(context) => return self.secrets.software.sign(context, data),
+        };
+        let mut digest = self.sha256(data)?;
+        let mut mechanism = Mechanism {
+            mechanism: CKM_ECDSA,
+            parameter: ptr::null_mut(),
+            parameter_len: 0,
+        };
+        let mut signature = [0u8; 64];
+        let mut length = signature.len() as c_ulong;
+        let f = self.functions();
+        unsafe {
+            check(
+                (f.sign_init)(self.session, &mut mechanism, private_key),
+                VaultFailErrorKind::Sign,
+            )?;
+            check(
+                (f.sign)(
+                    self.session,
+                    digest.as_mut_ptr(),
+                    digest.len() as c_ulong,
+                    signature.as_mut_ptr(),
+                    &mut length,
+                ),
+                VaultFailErrorKind::Sign,
+            )?;
+        }
+        if length as usize != signature.len() {
+            fail!(VaultFailErrorKind::Sign);
+        }
+        Ok(signature)
+    }
+
+    /// Verify an ECDSA signature of the SHA-256 digest of `data` in the token, for a P-256
+    /// public key
+    fn verify<B: AsRef<[u8]>>(
+        &mut self,
+        mut signature: [u8; 64],
+        public_key: PublicKey,
+        data: B,
+    ) -> Result<(), VaultFailError> {
+        let public_key = match public_key {
+            PublicKey::P256(k) => k,
+            PublicKey::Curve25519(_) => {
+                return self.secrets.software.verify(signature, public_key, data)
+            }
+        };
+        let mut digest = self.sha256(data)?;
+        let object = self.public_key_create(&public_key, None, VaultFailErrorKind::Verify)?;
+        let mut mechanism = Mechanism {
+            mechanism: CKM_ECDSA,
+            parameter: ptr::null_mut(),
+            parameter_len: 0,
+        };
+        let f = self.functions();
+        let rv = unsafe {
+            match (f.verify_init)(self.session, &mut mechanism, object) {
+                CKR_OK => (f.verify)(
+                    self.session,
+                    digest.as_mut_ptr(),
+                    digest.len() as c_ulong,
+                    signature.as_mut_ptr(),
+                    signature.len() as c_ulong,
+                ),
+                rv => rv,
+            }
+        };
+        unsafe { (f.destroy_object)(self.session, object) };
+        check(rv, VaultFailErrorKind::Verify)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{SecretKeyType, SecretPurposeType};
+
+    #[test]
+    fn ec_points() {
+        let mut point = [7u8; P256_PUBLICKEY_LENGTH];
+        point[0] = 0x04;
+        assert_eq!(ec_point(&ec_point_der(&point)).unwrap()[..], point[..]);
+        assert_eq!(ec_point(&point).unwrap()[..], point[..]);
+        assert!(ec_point(&point[1..]).is_none());
+        assert!(ec_point(&ec_point_der(&point)[1..]).is_none());
+        assert_eq!(key_id(0x0102)[size_of::<usize>() - 2..], [1, 2]);
+    }
+
+    // Run against SoftHSM with, e.g.
+    // softhsm2-util --init-token --free --label ockam --pin 1234 --so-pin 5678
+    // OCKAM_PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so OCKAM_PKCS11_PIN=1234 \
+    //     cargo test --features pkcs11 -- --ignored
+    #[ignore]
+    #[test]
+    fn token_keys() {
+        let config = Pkcs11Config {
+            module: std::env::var("OCKAM_PKCS11_MODULE").unwrap().into(),
+            slot: None,
+            pin: std::env::var("OCKAM_PKCS11_PIN").unwrap(),
+        };
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::P256,
+            persistence: SecretPersistenceType::Persistent,
+            purpose: SecretPurposeType::KeyAgreement,
+        };
+        let mut vault = Pkcs11Vault::open(&config).unwrap();
+        let alice = vault.secret_generate(attributes).unwrap();
+        let alice_public_key = vault.secret_public_key_get(alice).unwrap();
+        assert!(vault.secret_export(alice).is_err());
+        let signature = vault.sign(alice, b"hello world!").unwrap();
+        assert!(vault
+            .verify(signature, alice_public_key, b"hello world!")
+            .is_ok());
+        assert!(vault
+            .verify(signature, alice_public_key, b"hello world?")
+            .is_err());
+
+        let bob = vault.secret_generate(attributes).unwrap();
+        let bob_public_key = vault.secret_public_key_get(bob).unwrap();
+        let alice_secret = vault.ec_diffie_hellman(alice, bob_public_key).unwrap();
+        let bob_secret = vault.ec_diffie_hellman(bob, alice_public_key).unwrap();
+        assert_eq!(
+            vault.secret_export(alice_secret).unwrap().as_ref(),
+            vault.secret_export(bob_secret).unwrap().as_ref()
+        );
+        drop(vault);
+
+        // the keys are found again in the token
+        let mut vault = Pkcs11Vault::open(&config).unwrap();
+        assert_eq!(
+            vault.secret_public_key_get(alice).unwrap().as_ref(),
+            alice_public_key.as_ref()
+        );
+        vault.secret_destroy(alice).unwrap();
+        vault.secret_destroy(bob).unwrap();
+        assert!(vault.secret_public_key_get(alice).is_err());
+    }
+}
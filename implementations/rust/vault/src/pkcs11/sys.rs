@@ -0,0 +1,229 @@
+//! The part of the PKCS#11 v2.40 C interface the vault uses. Structures are packed on Windows, as
+//! pkcs11.h has them there.
+
+use std::os::raw::{c_uchar, c_ulong, c_void};
+
+pub type Rv = c_ulong;
+pub type SlotId = c_ulong;
+pub type SessionHandle = c_ulong;
+pub type ObjectHandle = c_ulong;
+
+pub const CK_TRUE: c_uchar = 1;
+pub const CK_FALSE: c_uchar = 0;
+
+pub const CKR_OK: Rv = 0;
+pub const CKR_USER_ALREADY_LOGGED_IN: Rv = 0x100;
+pub const CKR_CRYPTOKI_ALREADY_INITIALIZED: Rv = 0x191;
+
+pub const CKF_RW_SESSION: c_ulong = 0x2;
+pub const CKF_SERIAL_SESSION: c_ulong = 0x4;
+pub const CKU_USER: c_ulong = 1;
+
+pub const CKO_PUBLIC_KEY: c_ulong = 2;
+pub const CKO_PRIVATE_KEY: c_ulong = 3;
+pub const CKO_SECRET_KEY: c_ulong = 4;
+pub const CKK_EC: c_ulong = 0x3;
+pub const CKK_GENERIC_SECRET: c_ulong = 0x10;
+
+pub const CKA_CLASS: c_ulong = 0x0;
+pub const CKA_TOKEN: c_ulong = 0x1;
+pub const CKA_PRIVATE: c_ulong = 0x2;
+pub const CKA_VALUE: c_ulong = 0x11;
+pub const CKA_KEY_TYPE: c_ulong = 0x100;
+pub const CKA_ID: c_ulong = 0x102;
+pub const CKA_SENSITIVE: c_ulong = 0x103;
+pub const CKA_SIGN: c_ulong = 0x108;
+pub const CKA_VERIFY: c_ulong = 0x10A;
+pub const CKA_DERIVE: c_ulong = 0x10C;
+pub const CKA_VALUE_LEN: c_ulong = 0x161;
+pub const CKA_EXTRACTABLE: c_ulong = 0x162;
+pub const CKA_EC_PARAMS: c_ulong = 0x180;
+pub const CKA_EC_POINT: c_ulong = 0x181;
+
+pub const CKM_EC_KEY_PAIR_GEN: c_ulong = 0x1040;
+pub const CKM_ECDSA: c_ulong = 0x1041;
+pub const CKM_ECDH1_DERIVE: c_ulong = 0x1050;
+pub const CKD_NULL: c_ulong = 0x1;
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct Attribute {
+    pub kind: c_ulong,
+    pub value: *mut c_void,
+    pub value_len: c_ulong,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct Mechanism {
+    pub mechanism: c_ulong,
+    pub parameter: *mut c_void,
+    pub parameter_len: c_ulong,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct Ecdh1DeriveParams {
+    pub kdf: c_ulong,
+    pub shared_data_len: c_ulong,
+    pub shared_data: *mut c_uchar,
+    pub public_data_len: c_ulong,
+    pub public_data: *mut c_uchar,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct Version {
+    pub major: c_uchar,
+    pub minor: c_uchar,
+}
+
+type Unused = Option<unsafe extern "C" fn()>;
+
+pub type GetFunctionList = unsafe extern "C" fn(list: *mut *mut FunctionList) -> Rv;
+
+/// CK_FUNCTION_LIST, whose order is fixed by the standard
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct FunctionList {
+    pub version: Version,
+    pub initialize: unsafe extern "C" fn(init_args: *mut c_void) -> Rv,
+    pub finalize: unsafe extern "C" fn(reserved: *mut c_void) -> Rv,
+    pub get_info: Unused,
+    pub get_function_list: Unused,
+    pub get_slot_list: unsafe extern "C" fn(
+        token_present: c_uchar,
+        slot_list: *mut SlotId,
+        count: *mut c_ulong,
+    ) -> Rv,
+    pub get_slot_info: Unused,
+    pub get_token_info: Unused,
+    pub get_mechanism_list: Unused,
+    pub get_mechanism_info: Unused,
+    pub init_token: Unused,
+    pub init_pin: Unused,
+    pub set_pin: Unused,
+    pub open_session: unsafe extern "C" fn(
+        slot: SlotId,
+        flags: c_ulong,
+        application: *mut c_void,
+        notify: Unused,
+        session: *mut SessionHandle,
+    ) -> Rv,
+    pub close_session: unsafe extern "C" fn(session: SessionHandle) -> Rv,
+    pub close_all_sessions: Unused,
+    pub get_session_info: Unused,
+    pub get_operation_state: Unused,
+    pub set_operation_state: Unused,
+    pub login: unsafe extern "C" fn(
+        session: SessionHandle,
+        user_type: c_ulong,
+        pin: *mut c_uchar,
+        pin_len: c_ulong,
+    ) -> Rv,
+    pub logout: unsafe extern "C" fn(session: SessionHandle) -> Rv,
+    pub create_object: unsafe extern "C" fn(
+        session: SessionHandle,
+        template: *mut Attribute,
+        count: c_ulong,
+        object: *mut ObjectHandle,
+    ) -> Rv,
+    pub copy_object: Unused,
+    pub destroy_object: unsafe extern "C" fn(session: SessionHandle, object: ObjectHandle) -> Rv,
+    pub get_object_size: Unused,
+    pub get_attribute_value: unsafe extern "C" fn(
+        session: SessionHandle,
+        object: ObjectHandle,
+        template: *mut Attribute,
+        count: c_ulong,
+    ) -> Rv,
+    pub set_attribute_value: Unused,
+    pub find_objects_init: unsafe extern "C" fn(
+        session: SessionHandle,
+        template: *mut Attribute,
+        count: c_ulong,
+    ) -> Rv,
+    pub find_objects: unsafe extern "C" fn(
+        session: SessionHandle,
+        objects: *mut ObjectHandle,
+        max_count: c_ulong,
+        count: *mut c_ulong,
+    ) -> Rv,
+    pub find_objects_final: unsafe extern "C" fn(session: SessionHandle) -> Rv,
+    pub encrypt_init: Unused,
+    pub encrypt: Unused,
+    pub encrypt_update: Unused,
+    pub encrypt_final: Unused,
+    pub decrypt_init: Unused,
+    pub decrypt: Unused,
+    pub decrypt_update: Unused,
+    pub decrypt_final: Unused,
+    pub digest_init: Unused,
+    pub digest: Unused,
+    pub digest_update: Unused,
+    pub digest_key: Unused,
+    pub digest_final: Unused,
+    pub sign_init: unsafe extern "C" fn(
+        session: SessionHandle,
+        mechanism: *mut Mechanism,
+        key: ObjectHandle,
+    ) -> Rv,
+    pub sign: unsafe extern "C" fn(
+        session: SessionHandle,
+        data: *mut c_uchar,
+        data_len: c_ulong,
+        signature: *mut c_uchar,
+        signature_len: *mut c_ulong,
+    ) -> Rv,
+    pub sign_update: Unused,
+    pub sign_final: Unused,
+    pub sign_recover_init: Unused,
+    pub sign_recover: Unused,
+    pub verify_init: unsafe extern "C" fn(
+        session: SessionHandle,
+        mechanism: *mut Mechanism,
+        key: ObjectHandle,
+    ) -> Rv,
+    pub verify: unsafe extern "C" fn(
+        session: SessionHandle,
+        data: *mut c_uchar,
+        data_len: c_ulong,
+        signature: *mut c_uchar,
+        signature_len: c_ulong,
+    ) -> Rv,
+    pub verify_update: Unused,
+    pub verify_final: Unused,
+    pub verify_recover_init: Unused,
+    pub verify_recover: Unused,
+    pub digest_encrypt_update: Unused,
+    pub decrypt_digest_update: Unused,
+    pub sign_encrypt_update: Unused,
+    pub decrypt_verify_update: Unused,
+    pub generate_key: Unused,
+    pub generate_key_pair: unsafe extern "C" fn(
+        session: SessionHandle,
+        mechanism: *mut Mechanism,
+        public_template: *mut Attribute,
+        public_count: c_ulong,
+        private_template: *mut Attribute,
+        private_count: c_ulong,
+        public_key: *mut ObjectHandle,
+        private_key: *mut ObjectHandle,
+    ) -> Rv,
+    pub wrap_key: Unused,
+    pub unwrap_key: Unused,
+    pub derive_key: unsafe extern "C" fn(
+        session: SessionHandle,
+        mechanism: *mut Mechanism,
+        base_key: ObjectHandle,
+        template: *mut Attribute,
+        count: c_ulong,
+        key: *mut ObjectHandle,
+    ) -> Rv,
+    pub seed_random: Unused,
+    pub generate_random:
+        unsafe extern "C" fn(session: SessionHandle, random: *mut c_uchar, len: c_ulong) -> Rv,
+    pub get_function_status: Unused,
+    pub cancel_function: Unused,
+    pub wait_for_slot_event: Unused,
+}
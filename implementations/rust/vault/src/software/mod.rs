@@ -5,6 +5,7 @@ use crate::{
 };
 use aead::{generic_array::GenericArray, Aead, NewAead, Payload};
 use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use chacha20poly1305::ChaCha20Poly1305;
 use p256::{
     elliptic_curve::{sec1::FromEncodedPoint, Group},
     AffinePoint, ProjectivePoint, Scalar,
@@ -173,6 +174,25 @@ macro_rules! encrypt_impl {
     }};
 }
 
+macro_rules! chacha_impl {
+    ($entry:expr, $aad:expr, $nonce: expr, $text:expr, $op:ident, $err:expr) => {{
+        match &$entry.key {
+            SecretKey::Buffer(k) if k.len() == 32 => {
+                let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(k));
+                let nonce = GenericArray::from_slice($nonce.as_ref());
+                let payload = Payload {
+                    aad: $aad.as_ref(),
+                    msg: $text.as_ref(),
+                };
+                cipher
+                    .$op(nonce, payload)
+                    .map_err(|_| VaultFailError::from($err))
+            }
+            _ => Err($err.into()),
+        }
+    }};
+}
+
 impl Vault for DefaultVault {
     fn random(&mut self, data: &mut [u8]) -> Result<(), VaultFailError> {
         let mut rng = OsRng {};
@@ -456,6 +476,42 @@ impl Vault for DefaultVault {
         )
     }
 
+    fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let entry = self.get_entry(context, VaultFailErrorKind::AeadChaChaPolyEncrypt)?;
+        chacha_impl!(
+            entry,
+            aad,
+            nonce,
+            plaintext,
+            encrypt,
+            VaultFailErrorKind::AeadChaChaPolyEncrypt
+        )
+    }
+
+    fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let entry = self.get_entry(context, VaultFailErrorKind::AeadChaChaPolyDecrypt)?;
+        chacha_impl!(
+            entry,
+            aad,
+            nonce,
+            cipher_text,
+            decrypt,
+            VaultFailErrorKind::AeadChaChaPolyDecrypt
+        )
+    }
+
     fn deinit(&mut self) {
         self.zeroize();
     }
@@ -885,6 +941,52 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn chacha20_poly1305_encryption() {
+        let mut vault = DefaultVault::default();
+        let message = b"Ockam Test Message";
+        let nonce = b"TestingNonce";
+        let aad = b"Extra payload data";
+        let mut attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Buffer(32),
+            persistence: SecretPersistenceType::Ephemeral,
+            purpose: SecretPurposeType::KeyAgreement,
+        };
+
+        let ctx = vault.secret_generate(attributes).unwrap();
+        let mut ciphertext = vault
+            .aead_chacha20_poly1305_encrypt(ctx, message.as_ref(), nonce.as_ref(), aad.as_ref())
+            .unwrap();
+        let plaintext = vault
+            .aead_chacha20_poly1305_decrypt(
+                ctx,
+                ciphertext.as_slice(),
+                nonce.as_ref(),
+                aad.as_ref(),
+            )
+            .unwrap();
+        assert_eq!(plaintext, message.to_vec());
+        ciphertext[0] ^= ciphertext[1];
+        let res = vault.aead_chacha20_poly1305_decrypt(
+            ctx,
+            ciphertext.as_slice(),
+            nonce.as_ref(),
+            aad.as_ref(),
+        );
+        assert!(res.is_err());
+
+        // only a 32 byte key will do
+        attributes.xtype = SecretKeyType::Aes128;
+        let ctx = vault.secret_generate(attributes).unwrap();
+        let res = vault.aead_chacha20_poly1305_encrypt(
+            ctx,
+            message.as_ref(),
+            nonce.as_ref(),
+            aad.as_ref(),
+        );
+        assert!(res.is_err());
+    }
+
     #[test]
     fn sign() {
         let mut vault = DefaultVault::default();
@@ -2,9 +2,11 @@
 #[allow(unused_variables)]
 #[allow(dead_code)]
 use ockam_message::message::{Address, AddressType, Message, MessageType, Receiver, Route, Sender};
-use ockam_system::commands::{OckamCommand, RouterCommand};
+use ockam_system::commands::{OckamCommand, RouterCommand, WorkerCommand};
 use std::sync::{Arc, Mutex};
 
+/// Shares the router's single worker registration between any number of workers, passing each
+/// message to the worker registered at its first onward address.
 pub struct WorkerManager {
     tx: std::sync::mpsc::Sender<OckamCommand>,
     rx: std::sync::mpsc::Receiver<OckamCommand>,
@@ -13,8 +15,10 @@ pub struct WorkerManager {
 }
 
 impl Sender for WorkerManager {
-    fn send(&mut self, _m: Message) -> bool {
-        unimplemented!()
+    fn send(&mut self, m: Message) -> bool {
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(m)))
+            .is_ok()
     }
 }
 
@@ -38,44 +42,169 @@ impl WorkerManager {
         }
     }
 
+    /// The sender for commands to the manager, e.g. WorkerCommand::Stop.
+    pub fn sender(&self) -> std::sync::mpsc::Sender<OckamCommand> {
+        self.tx.clone()
+    }
+
+    /// Deliver the messages addressed to `a` to the worker `r`.
     pub fn register(
         &mut self,
         a: Address,
         r: Arc<Mutex<dyn Receiver + 'static + Send>>,
     ) -> Result<(), String> {
+        if self.workers.contains_key(&a.as_string()) {
+            return Err(format!("worker {} is already registered", a.as_string()));
+        }
         self.workers.insert(a.as_string(), r);
         Ok(())
     }
 
+    /// Stop delivering messages to the worker at `a`.
+    pub fn unregister(&mut self, a: &Address) -> Result<(), String> {
+        match self.workers.remove(&a.as_string()) {
+            Some(_) => Ok(()),
+            None => Err(format!("no worker registered at {}", a.as_string())),
+        }
+    }
+
+    // Pass the message to the worker at its first onward address, and send any reply.
+    fn deliver(&mut self, m: Message) -> Result<(), String> {
+        let address = match m.onward_route.addresses.first() {
+            Some(a) => a.address.as_string(),
+            None => return Err("worker message has no onward address".into()),
+        };
+        let worker = match self.workers.get(&address) {
+            Some(w) => w.clone(),
+            None => return Err(format!("no worker registered at {}", address)),
+        };
+        let reply = worker.lock().unwrap().recv(m)?;
+        if let Some(reply) = reply {
+            if !self.send(reply) {
+                return Err("failed to send worker reply to router".into());
+            }
+        }
+        Ok(())
+    }
+
     pub fn poll(&mut self) -> bool {
-        true
-        //    pub fn poll(wm: Arc<Mutex<WorkerManager>>) -> bool {
-        //        let keep_going = true;
-        // let mut got = true;
-        // while got {
-        //     got = false;
-        //     if let Ok(c) = self.rx.try_recv() {
-        //         got = true;
-        //         match c {
-        //             // OckamCommand::worker(WorkerCommand::SendMessage(m)) => {
-        //             //     self.handle_send(m).unwrap();
-        //             // }
-        //             // OckamCommand::worker(WorkerCommand::ReceiveMessage(m)) => {
-        //             //     if let MessageType::Payload = m.message_type {
-        //             //         println!(
-        //             //             "worker received: {}",
-        //             //             str::from_utf8(&m.message_body).unwrap()
-        //             //         );
-        //             //     }
-        //             //     self.handle_receive(m).unwrap();
-        //             // }
-        //             // OckamCommand::worker(WorkerCommand::Stop) => {
-        //             //     keep_going = false;
-        //             // }
-        //             _ => println!("worker got bad message"),
-        //         }
-        //     }
-        // }
-        //        keep_going
+        let mut keep_going = true;
+        while let Ok(c) = self.rx.try_recv() {
+            let result = match c {
+                OckamCommand::Worker(WorkerCommand::ReceiveMessage(m)) => self.deliver(m),
+                OckamCommand::Worker(WorkerCommand::SendMessage(m)) => {
+                    // a message sent from this node to one of its own workers is delivered
+                    // directly; anything else goes back out through the router
+                    let local = m
+                        .onward_route
+                        .addresses
+                        .first()
+                        .map(|a| a.a_type == AddressType::Worker)
+                        .unwrap_or(false);
+                    if local {
+                        self.deliver(m)
+                    } else if self.send(m) {
+                        Ok(())
+                    } else {
+                        Err("failed to send worker message to router".into())
+                    }
+                }
+                OckamCommand::Worker(WorkerCommand::Stop) => {
+                    self.workers.clear();
+                    keep_going = false;
+                    Ok(())
+                }
+                OckamCommand::Worker(WorkerCommand::Test) => Ok(()),
+                _ => Err("worker manager got bad command".into()),
+            };
+            if let Err(s) = result {
+                println!("{}", s);
+            }
+            if !keep_going {
+                break;
+            }
+        }
+        keep_going
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_message::message::RouterAddress;
+    use std::sync::mpsc;
+
+    struct EchoWorker {
+        received: Vec<Vec<u8>>,
+    }
+
+    impl Receiver for EchoWorker {
+        fn recv(&mut self, m: Message) -> Result<Option<Message>, String> {
+            self.received.push(m.message_body.clone());
+            Ok(Some(Message {
+                onward_route: m.return_route,
+                return_route: Route { addresses: vec![] },
+                message_type: MessageType::Payload,
+                message_body: m.message_body,
+            }))
+        }
+    }
+
+    fn message(to: &str) -> OckamCommand {
+        OckamCommand::Worker(WorkerCommand::ReceiveMessage(Message {
+            onward_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str(to).unwrap()],
+            },
+            return_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str("00000000").unwrap()],
+            },
+            message_type: MessageType::Payload,
+            message_body: hex::decode(to).unwrap(),
+        }))
+    }
+
+    #[test]
+    fn test_worker_manager_dispatch() {
+        let (tx, rx) = mpsc::channel();
+        let (router_tx, router_rx) = mpsc::channel();
+        let mut wm = WorkerManager::new(tx.clone(), rx, router_tx);
+        assert!(matches!(
+            router_rx.try_recv(),
+            Ok(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Worker,
+                _
+            )))
+        ));
+
+        let first = Arc::new(Mutex::new(EchoWorker { received: vec![] }));
+        let second = Arc::new(Mutex::new(EchoWorker { received: vec![] }));
+        let first_address = Address::worker_address_from_string("00000001").unwrap();
+        wm.register(first_address.clone(), first.clone()).unwrap();
+        wm.register(
+            Address::worker_address_from_string("00000002").unwrap(),
+            second.clone(),
+        )
+        .unwrap();
+        assert!(wm.register(first_address.clone(), second.clone()).is_err());
+
+        tx.send(message("00000001")).unwrap();
+        tx.send(message("00000002")).unwrap();
+        tx.send(message("00000003")).unwrap();
+        assert!(wm.poll());
+        assert_eq!(first.lock().unwrap().received, vec![vec![0, 0, 0, 1]]);
+        assert_eq!(second.lock().unwrap().received, vec![vec![0, 0, 0, 2]]);
+
+        // each reply goes out through the router
+        let replies: Vec<OckamCommand> = router_rx.try_iter().collect();
+        assert_eq!(replies.len(), 2);
+
+        wm.unregister(&first_address).unwrap();
+        assert!(wm.unregister(&first_address).is_err());
+        tx.send(message("00000001")).unwrap();
+        assert!(wm.poll());
+        assert_eq!(first.lock().unwrap().received.len(), 1);
+
+        tx.send(OckamCommand::Worker(WorkerCommand::Stop)).unwrap();
+        assert!(!wm.poll());
     }
 }